@@ -0,0 +1,149 @@
+//! Build-time values the kernel reports about itself
+//!
+//! UNIVERSEK_BUILD_DATE is the build day as YYYY-MM-DD (UTC), and
+//! UNIVERSEK_BUILD_TIME the build moment as YYYY-MM-DD HH:MM:SS, both taken
+//! from SOURCE_DATE_EPOCH when set so builds can be reproduced.
+//!
+//! UNIVERSEK_GIT_HASH is the short hash of the checked-out commit, with
+//! "-dirty" if the tree has changes, and UNIVERSEK_RUSTC_VERSION what
+//! `rustc --version` says. Either is "unknown" when the tool isn't there or
+//! fails, as git does outside a checkout. src/version.rs reads them all.
+//!
+//! ksyms.bin in OUT_DIR is the symbol table src/ksyms.rs embeds, made from
+//! the `nm` output UNIVERSEK_KSYMS names; without it the table is empty.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Demangled name of the function whose address says a table fits this
+/// build
+const ANCHOR: &str = "kernel::ksyms::anchor";
+
+fn main() {
+    // Naming what to watch stops Cargo rerunning this on any change in
+    // the package, so the sources are named too, for the build date
+    for watched in ["build.rs", "src"] {
+        println!("cargo:rerun-if-changed={}", watched);
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=UNIVERSEK_KSYMS");
+
+    let seconds = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()));
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let time_of_day = seconds % 86_400;
+    println!("cargo:rustc-env=UNIVERSEK_BUILD_DATE={:04}-{:02}-{:02}", year, month, day);
+    println!("cargo:rustc-env=UNIVERSEK_BUILD_TIME={:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day,
+        time_of_day / 3600, time_of_day / 60 % 60, time_of_day % 60);
+
+    // Rebuild when the checked-out commit moves, if there's a checkout
+    if let Some(git_dir) = command_output("git", &["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/index", git_dir);
+    }
+    let git_hash = command_output("git", &["rev-parse", "--short=12", "HEAD"]).map(|hash| {
+        let dirty = command_output("git", &["status", "--porcelain", "--untracked-files=no"]).is_some();
+        if dirty { format!("{}-dirty", hash) } else { hash }
+    });
+    println!("cargo:rustc-env=UNIVERSEK_GIT_HASH={}", git_hash.as_deref().unwrap_or("unknown"));
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = command_output(&rustc, &["--version"]);
+    println!("cargo:rustc-env=UNIVERSEK_RUSTC_VERSION={}", rustc_version.as_deref().unwrap_or("unknown"));
+
+    let table = match std::env::var("UNIVERSEK_KSYMS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            let listing = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Can't read UNIVERSEK_KSYMS file {}: {}", path, e));
+            symbol_table(&listing)
+        }
+        Err(_) => Vec::new(),
+    };
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").expect("OUT_DIR not set")).join("ksyms.bin");
+    std::fs::write(&out, table).unwrap_or_else(|e| panic!("Can't write {}: {}", out.display(), e));
+}
+
+/// First line of what `program args` prints, if it runs, succeeds and
+/// prints something
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    let line = text.lines().next()?.trim();
+    (!line.is_empty()).then(|| String::from(line))
+}
+
+/// The table src/ksyms.rs reads, from `nm -C -S --defined-only` output
+/// (sizes optional): functions only, by address. Empty, with a warning,
+/// if the listing has no `ANCHOR`.
+fn symbol_table(listing: &str) -> Vec<u8> {
+    let mut symbols: Vec<(u64, u32, &str)> = listing.lines().filter_map(parse_nm_line).collect();
+    symbols.sort_by_key(|(address, _, _)| *address);
+    symbols.dedup_by_key(|(address, _, _)| *address);
+    let Some(anchor) = symbols.iter().find(|(_, _, name)| *name == ANCHOR).map(|(address, _, _)| *address) else {
+        println!("cargo:warning=UNIVERSEK_KSYMS has no {}; building without kernel symbols", ANCHOR);
+        return Vec::new();
+    };
+
+    let mut table = Vec::new();
+    table.extend_from_slice(b"KSYM");
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&anchor.to_le_bytes());
+    let mut names = Vec::new();
+    for (address, size, name) in &symbols {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    table
+}
+
+/// (address, size, name) from a line of nm output, if it's a function
+fn parse_nm_line(line: &str) -> Option<(u64, u32, &str)> {
+    let mut fields = line.splitn(3, ' ');
+    let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let second = fields.next()?;
+    let rest = fields.next()?;
+    // A one-letter second field is the type, so there's no size
+    let (size, kind, name) = if second.len() == 1 {
+        (0, second, rest)
+    } else {
+        let (kind, name) = rest.split_once(' ')?;
+        (u32::from_str_radix(second, 16).ok()?, kind, name)
+    };
+    if !matches!(kind, "T" | "t" | "W" | "w") {
+        return None;
+    }
+    Some((address, size, strip_hash(name)))
+}
+
+/// `name` without the `::h0123456789abcdef` hash older demanglers leave
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => path,
+        _ => name,
+    }
+}
+
+/// Year, month and day of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Counted from 0000-03-01, so leap days fall at the end of a year
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -1,16 +1,135 @@
 // kernel/src/allocator.rs
-use linked_list_allocator::LockedHeap;
+use heap::LockedHeap;
 use x86_64::{
     structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
     VirtAddr,
 };
+use crate::errors::KernelError;
+use crate::faultinject;
 use crate::serial_println;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB for the initial kernel heap
 
+#[cfg(not(feature = "heap_debug"))]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: InterruptChecked<LockedHeap> = InterruptChecked(LockedHeap::empty());
+
+#[cfg(feature = "heap_debug")]
+#[global_allocator]
+static ALLOCATOR: InterruptChecked<debug::CheckedHeap> = InterruptChecked(debug::CheckedHeap::empty());
+
+/// Set while `with_idle_heap` runs code that has checked the heap is free
+static IDLE_HEAP_VOUCHED: AtomicBool = AtomicBool::new(false);
+
+/// Set by `catch_interrupt_allocation` to have the next allocation from
+/// interrupt context recorded instead of panicking
+#[cfg(feature = "diagnostics")]
+static CATCHING: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "diagnostics")]
+static CAUGHT: AtomicBool = AtomicBool::new(false);
+
+/// A heap behind a check that interrupt handlers don't use it. One that
+/// does deadlocks if the code it interrupted holds the heap lock, so with
+/// the `diagnostics` feature any allocation or free from interrupt context
+/// panics at once, saying so; without it the check compiles to nothing.
+/// It's also where the `alloc` fault point makes allocations fail.
+struct InterruptChecked<H>(H);
+
+// Lets the rest of this file use the heap as before
+impl<H> Deref for InterruptChecked<H> {
+    type Target = H;
+
+    fn deref(&self) -> &H {
+        &self.0
+    }
+}
+
+unsafe impl<H: GlobalAlloc> GlobalAlloc for InterruptChecked<H> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        check_context("allocation", layout);
+        if faultinject::ALLOC.check().is_err() {
+            return core::ptr::null_mut();
+        }
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        check_context("free", layout);
+        self.0.dealloc(block, layout)
+    }
+
+    unsafe fn realloc(&self, block: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        check_context("resize", layout);
+        if new_size > layout.size() && faultinject::ALLOC.check().is_err() {
+            return core::ptr::null_mut();
+        }
+        self.0.realloc(block, layout, new_size)
+    }
+}
+
+#[inline(always)]
+fn check_context(what: &str, layout: Layout) {
+    #[cfg(feature = "diagnostics")]
+    if crate::interrupts::in_handler() && !IDLE_HEAP_VOUCHED.load(Ordering::SeqCst) {
+        if CATCHING.swap(false, Ordering::SeqCst) {
+            CAUGHT.store(true, Ordering::SeqCst);
+            return;
+        }
+        panic!("Heap {} of {} bytes from an interrupt handler, which deadlocks if the interrupted code holds \
+            the heap lock; use fixed-size buffers and isr_println! there", what, layout.size());
+    }
+    #[cfg(not(feature = "diagnostics"))]
+    let _ = (what, layout);
+}
+
+/// Run `f`, which may allocate, only if the heap is free; for interrupt
+/// handlers that can do without what `f` does, like logging. Returns None
+/// without running it otherwise.
+pub fn with_idle_heap<R>(f: impl FnOnce() -> R) -> Option<R> {
+    if !heap_idle() {
+        return None;
+    }
+    let vouched = IDLE_HEAP_VOUCHED.swap(true, Ordering::SeqCst);
+    let result = f();
+    IDLE_HEAP_VOUCHED.store(vouched, Ordering::SeqCst);
+    Some(result)
+}
+
+/// Run `f` and report whether it allocated from interrupt context, with the
+/// first such allocation let through instead of panicking. Only
+/// `diagnostics` builds check, so elsewhere this is always false.
+pub fn catch_interrupt_allocation(f: impl FnOnce()) -> bool {
+    #[cfg(feature = "diagnostics")]
+    {
+        CAUGHT.store(false, Ordering::SeqCst);
+        CATCHING.store(true, Ordering::SeqCst);
+        f();
+        CATCHING.store(false, Ordering::SeqCst);
+        CAUGHT.swap(false, Ordering::SeqCst)
+    }
+    #[cfg(not(feature = "diagnostics"))]
+    {
+        f();
+        false
+    }
+}
+
+/// Timer ticks between periodic heap sweeps in `heap_debug` builds
+#[cfg(feature = "heap_debug")]
+const CHECK_INTERVAL_TICKS: u64 = 1000;
+
+#[cfg(feature = "heap_debug")]
+lazy_static::lazy_static! {
+    /// Deferred work that sweeps the heap, once registered
+    static ref CHECK_WORK: spin::Mutex<Option<crate::task::deferred::WorkId>> = spin::Mutex::new(None);
+}
 
 /// Flag to track heap initialization state
 static mut HEAP_INITIALIZED: bool = false;
@@ -82,4 +201,779 @@ pub fn init_heap(
     set_heap_initialized();
 
     Ok(())
-} 
\ No newline at end of file
+} 
+/// Kernel heap usage in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Current heap usage; all zero before the heap is initialized
+pub fn heap_stats() -> HeapStats {
+    if !is_heap_initialized() {
+        return HeapStats { size: 0, used: 0, free: 0 };
+    }
+    let heap = ALLOCATOR.lock();
+    HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+    }
+}
+
+/// Whether an allocation would go ahead without waiting for the heap lock.
+/// Only reliable with interrupts disabled, as in an interrupt handler, where
+/// nothing can take the lock between this check and the allocation.
+pub fn heap_idle() -> bool {
+    if !is_heap_initialized() {
+        return false;
+    }
+    #[cfg(feature = "heap_debug")]
+    {
+        ALLOCATOR.is_idle()
+    }
+    #[cfg(not(feature = "heap_debug"))]
+    {
+        ALLOCATOR.try_lock().is_some()
+    }
+}
+
+/// Live heap bytes allocated under one tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagUsage {
+    pub tag: &'static str,
+    pub bytes: usize,
+    pub blocks: usize,
+}
+
+/// Live bytes per allocation tag, most first. Empty unless built with the
+/// `heap_debug` feature, which is what records them.
+pub fn tag_usage() -> Vec<TagUsage> {
+    #[cfg(feature = "heap_debug")]
+    {
+        let mut usage: Vec<TagUsage> = ALLOCATOR.usage().into_iter().filter(|usage| usage.blocks > 0).collect();
+        usage.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        usage
+    }
+    #[cfg(not(feature = "heap_debug"))]
+    {
+        Vec::new()
+    }
+}
+
+/// What a walk of the heap's free list found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fragmentation {
+    /// Free bytes by the heap's own count
+    pub free: usize,
+    /// Free bytes in the blocks walked; equal to `free` unless the list is damaged
+    pub listed: usize,
+    pub free_blocks: usize,
+    pub largest_free: usize,
+}
+
+impl Fragmentation {
+    /// Share of the free space outside the largest free block, in percent:
+    /// 0 when it's one block, near 100 when it's in crumbs. An allocation
+    /// bigger than `largest_free` fails however much is free.
+    pub fn percent(&self) -> usize {
+        if self.listed == 0 {
+            0
+        } else {
+            (self.listed - self.largest_free) * 100 / self.listed
+        }
+    }
+}
+
+/// Walk the heap's free list with the heap locked. Takes time in
+/// proportion to the number of free blocks, so it's for reports, not hot
+/// paths. All zero before the heap is initialized.
+pub fn fragmentation_report() -> Fragmentation {
+    if !is_heap_initialized() {
+        return Fragmentation::default();
+    }
+    ALLOCATOR.lock().fragmentation()
+}
+
+/// Text of /proc/meminfo: heap totals and fragmentation, then bytes per
+/// allocation tag
+pub fn meminfo_text() -> String {
+    let heap = heap_stats();
+    let fragmentation = fragmentation_report();
+    let mut text = format!("HeapTotal: {:>10} B\nHeapUsed:  {:>10} B\nHeapFree:  {:>10} B\n",
+        heap.size, heap.used, heap.free);
+    text.push_str(&format!("HeapFreeBlocks:  {:>5}\nHeapLargestFree: {:>5} B\nHeapFragmented:  {:>5} %\n",
+        fragmentation.free_blocks, fragmentation.largest_free, fragmentation.percent()));
+    for usage in tag_usage() {
+        text.push_str(&format!("Tag {:<16} {:>8} B in {} blocks\n", usage.tag, usage.bytes, usage.blocks));
+    }
+    text
+}
+
+/// Restores the previous allocation tag when dropped
+pub struct TagGuard {
+    #[cfg(feature = "heap_debug")]
+    previous: &'static str,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "heap_debug")]
+        ALLOCATOR.set_tag(self.previous);
+    }
+}
+
+/// Record `name` with the blocks allocated until the guard drops, so a
+/// corruption report can say roughly who owned the block and
+/// `tag_usage` can count their bytes. A no-op unless built with the
+/// `heap_debug` feature.
+pub fn tag(name: &'static str) -> TagGuard {
+    #[cfg(feature = "heap_debug")]
+    {
+        TagGuard { previous: ALLOCATOR.set_tag(name) }
+    }
+    #[cfg(not(feature = "heap_debug"))]
+    {
+        let _ = name;
+        TagGuard {}
+    }
+}
+
+/// Check the canaries of every live block, panicking on the first damaged
+/// one. A no-op unless built with the `heap_debug` feature.
+pub fn check_heap() {
+    #[cfg(feature = "heap_debug")]
+    if let Some(corruption) = ALLOCATOR.scan() {
+        debug::report(corruption);
+    }
+}
+
+/// Sweep the heap from deferred work every `CHECK_INTERVAL_TICKS` ticks
+#[cfg(feature = "heap_debug")]
+pub fn start_periodic_check() -> Result<(), KernelError> {
+    let work = crate::task::deferred::register(check_heap)?;
+    *CHECK_WORK.lock() = Some(work);
+    Ok(())
+}
+
+/// Called from the timer interrupt with the new tick count
+#[cfg(feature = "heap_debug")]
+pub fn timer_tick(ticks: u64) {
+    if ticks % CHECK_INTERVAL_TICKS != 0 {
+        return;
+    }
+    // Skip this round rather than spin in an interrupt if the slot is busy
+    if let Some(work) = CHECK_WORK.try_lock().and_then(|work| *work) {
+        crate::task::deferred::raise(work);
+    }
+}
+
+/// The kernel heap: first fit over a list of free blocks kept in address
+/// order, each block's header stored in the block itself. A freed block
+/// merges with free neighbours, so the list holds the free space as
+/// maximal runs and a walk of it says how broken up that space is.
+mod heap {
+    use super::Fragmentation;
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::mem::{align_of, size_of};
+    use core::ops::Deref;
+    use core::ptr::{self, NonNull};
+    use spin::Mutex;
+
+    /// Header at the start of every free block
+    struct Hole {
+        size: usize,
+        next: *mut Hole,
+    }
+
+    /// Smallest block handed out or kept free: room for a header
+    const MIN_BLOCK: usize = size_of::<Hole>();
+    /// Every block starts and ends on a multiple of this
+    const BLOCK_ALIGN: usize = align_of::<Hole>();
+
+    pub struct Heap {
+        size: usize,
+        used: usize,
+        /// Lowest free block; null when there is none
+        first: *mut Hole,
+    }
+
+    // Safety: the list is only reached through the heap, behind its lock
+    unsafe impl Send for Heap {}
+
+    fn align_up(value: usize, align: usize) -> Option<usize> {
+        Some(value.checked_add(align - 1)? & !(align - 1))
+    }
+
+    /// Bytes a block for `layout` takes from the heap
+    fn block_size(layout: &Layout) -> Option<usize> {
+        align_up(layout.size().max(MIN_BLOCK), BLOCK_ALIGN)
+    }
+
+    /// Where a block of `size` aligned to `align` goes in the free run
+    /// `start..end`, and how many bytes stay free in front of it. What's
+    /// left on either side must be nothing or big enough to stay free.
+    fn fit(start: usize, end: usize, size: usize, align: usize) -> Option<(usize, usize)> {
+        let mut block = align_up(start, align)?;
+        if block != start && block - start < MIN_BLOCK {
+            block = align_up(start + MIN_BLOCK, align)?;
+        }
+        let back = end.checked_sub(block.checked_add(size)?)?;
+        (back == 0 || back >= MIN_BLOCK).then_some((block, block - start))
+    }
+
+    impl Heap {
+        pub const fn empty() -> Self {
+            Self { size: 0, used: 0, first: ptr::null_mut() }
+        }
+
+        /// Hand the heap `size` bytes at `bottom`, which must be mapped,
+        /// unused and aligned to `BLOCK_ALIGN`
+        pub unsafe fn init(&mut self, bottom: usize, size: usize) {
+            self.size = size - size % BLOCK_ALIGN;
+            self.used = 0;
+            self.first = ptr::null_mut();
+            if self.size >= MIN_BLOCK {
+                let hole = bottom as *mut Hole;
+                hole.write(Hole { size: self.size, next: ptr::null_mut() });
+                self.first = hole;
+            }
+        }
+
+        pub fn size(&self) -> usize {
+            self.size
+        }
+
+        pub fn used(&self) -> usize {
+            self.used
+        }
+
+        pub fn free(&self) -> usize {
+            self.size - self.used
+        }
+
+        /// Take a block for `layout` from the lowest free run it fits in
+        pub fn allocate_first_fit(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+            let size = block_size(&layout)?;
+            let align = layout.align().max(BLOCK_ALIGN);
+            let mut link: *mut *mut Hole = ptr::addr_of_mut!(self.first);
+            unsafe {
+                while !(*link).is_null() {
+                    let hole = *link;
+                    let (start, end) = (hole as usize, hole as usize + (*hole).size);
+                    if let Some((block, front)) = fit(start, end, size, align) {
+                        let mut next = (*hole).next;
+                        let back = end - (block + size);
+                        if back > 0 {
+                            let rest = (block + size) as *mut Hole;
+                            rest.write(Hole { size: back, next });
+                            next = rest;
+                        }
+                        if front > 0 {
+                            (*hole).size = front;
+                            (*hole).next = next;
+                        } else {
+                            *link = next;
+                        }
+                        self.used += size;
+                        return NonNull::new(block as *mut u8);
+                    }
+                    link = ptr::addr_of_mut!((*hole).next);
+                }
+            }
+            None
+        }
+
+        /// Give back a block `allocate_first_fit` returned for `layout`,
+        /// merging it with the free runs either side
+        pub unsafe fn deallocate(&mut self, block: NonNull<u8>, layout: Layout) {
+            let Some(size) = block_size(&layout) else {
+                return;
+            };
+            let start = block.as_ptr() as usize;
+            self.used -= size;
+
+            let mut prev: *mut Hole = ptr::null_mut();
+            let mut next = self.first;
+            while !next.is_null() && (next as usize) < start {
+                prev = next;
+                next = (*next).next;
+            }
+
+            let hole = start as *mut Hole;
+            hole.write(Hole { size, next });
+            if !next.is_null() && start + size == next as usize {
+                (*hole).size += (*next).size;
+                (*hole).next = (*next).next;
+            }
+            if prev.is_null() {
+                self.first = hole;
+            } else if prev as usize + (*prev).size == start {
+                (*prev).size += (*hole).size;
+                (*prev).next = (*hole).next;
+            } else {
+                (*prev).next = hole;
+            }
+        }
+
+        /// Walk the free list. It can't hold more blocks than the heap has
+        /// room for, so the walk stops there even if the list is damaged.
+        pub fn fragmentation(&self) -> Fragmentation {
+            let mut report = Fragmentation { free: self.free(), ..Fragmentation::default() };
+            let mut hole = self.first;
+            for _ in 0..self.size / MIN_BLOCK {
+                if hole.is_null() {
+                    break;
+                }
+                let size = unsafe { (*hole).size };
+                report.free_blocks += 1;
+                report.listed += size;
+                report.largest_free = report.largest_free.max(size);
+                hole = unsafe { (*hole).next };
+            }
+            report
+        }
+    }
+
+    /// `Heap` behind a spinlock, to serve as the global allocator
+    pub struct LockedHeap(Mutex<Heap>);
+
+    impl LockedHeap {
+        pub const fn empty() -> Self {
+            Self(Mutex::new(Heap::empty()))
+        }
+    }
+
+    // Lets callers lock the heap for init and statistics
+    impl Deref for LockedHeap {
+        type Target = Mutex<Heap>;
+
+        fn deref(&self) -> &Mutex<Heap> {
+            &self.0
+        }
+    }
+
+    unsafe impl GlobalAlloc for LockedHeap {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.0.lock().allocate_first_fit(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+        }
+
+        unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+            if let Some(block) = NonNull::new(block) {
+                self.0.lock().deallocate(block, layout);
+            }
+        }
+    }
+}
+
+/// Debug heap: each block is wrapped in canary words and freed memory is
+/// poisoned, so an overrun shows up when the block is freed or the heap is
+/// swept rather than as a strange failure somewhere else later.
+#[cfg(feature = "heap_debug")]
+mod debug {
+    use super::TagUsage;
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::mem::{align_of, size_of};
+    use core::ops::Deref;
+    use core::ptr;
+    use super::heap::LockedHeap;
+    use spin::Mutex;
+
+    /// Written just before and just after every block
+    const CANARY: u64 = 0xC0DE_CAFE_F00D_D00D;
+    /// Tags counted separately; blocks of any further tags are counted
+    /// under the last slot
+    const MAX_TAGS: usize = 16;
+    const OTHER_TAGS: &str = "(other)";
+    /// Fills freed blocks
+    pub const POISON: u8 = 0xDE;
+
+    /// Bookkeeping kept just before each block, canary last so that an
+    /// underrun hits it first
+    #[repr(C)]
+    struct Header {
+        prev: *mut Header,
+        next: *mut Header,
+        /// Bytes the caller asked for
+        size: usize,
+        tag: &'static str,
+        canary: u64,
+    }
+
+    /// Live blocks, newest first, for sweeps
+    struct LiveList {
+        head: *mut Header,
+    }
+
+    // Safety: the list is only used with its lock held
+    unsafe impl Send for LiveList {}
+
+    /// A block whose canary has been overwritten
+    #[derive(Debug, Clone, Copy)]
+    pub struct Corruption {
+        pub address: usize,
+        pub size: usize,
+        pub tag: &'static str,
+        /// "front" or "back"
+        pub side: &'static str,
+    }
+
+    pub struct CheckedHeap {
+        heap: LockedHeap,
+        live: Mutex<LiveList>,
+        /// Recorded with each new block
+        tag: Mutex<&'static str>,
+        /// Live bytes and blocks per tag
+        usage: Mutex<[TagUsage; MAX_TAGS]>,
+    }
+
+    // Lets init_heap and heap_stats lock the underlying heap as before
+    impl Deref for CheckedHeap {
+        type Target = LockedHeap;
+
+        fn deref(&self) -> &LockedHeap {
+            &self.heap
+        }
+    }
+
+    impl CheckedHeap {
+        pub const fn empty() -> Self {
+            Self {
+                heap: LockedHeap::empty(),
+                live: Mutex::new(LiveList { head: ptr::null_mut() }),
+                tag: Mutex::new("untagged"),
+                usage: Mutex::new([TagUsage { tag: "", bytes: 0, blocks: 0 }; MAX_TAGS]),
+            }
+        }
+
+        /// Whether none of the locks an allocation takes are held
+        pub fn is_idle(&self) -> bool {
+            self.heap.try_lock().is_some() && self.live.try_lock().is_some() && self.tag.try_lock().is_some()
+                && self.usage.try_lock().is_some()
+        }
+
+        /// Live bytes and blocks per tag, in the order tags were first seen
+        pub fn usage(&self) -> [TagUsage; MAX_TAGS] {
+            *self.usage.lock()
+        }
+
+        /// Count a block of `size` bytes under `tag` coming (`live`) or going
+        fn account(&self, tag: &'static str, size: usize, live: bool) {
+            let mut usage = self.usage.lock();
+            let slot = usage.iter().position(|slot| slot.tag == tag || slot.tag.is_empty()).unwrap_or(MAX_TAGS - 1);
+            let slot = &mut usage[slot];
+            if slot.tag.is_empty() {
+                slot.tag = tag;
+            } else if slot.tag != tag && live {
+                slot.tag = OTHER_TAGS;
+            }
+            if live {
+                slot.bytes += size;
+                slot.blocks += 1;
+            } else {
+                slot.bytes = slot.bytes.saturating_sub(size);
+                slot.blocks = slot.blocks.saturating_sub(1);
+            }
+        }
+
+        /// Allocate a block for `layout` recorded under `tag`
+        unsafe fn alloc_tagged(&self, layout: Layout, tag: &'static str) -> *mut u8 {
+            let (outer, front) = match outer_layout(layout) {
+                Some(outer) => outer,
+                None => return ptr::null_mut(),
+            };
+            let base = self.heap.alloc(outer);
+            if base.is_null() {
+                return base;
+            }
+
+            let block = base.add(front);
+            let header = block.sub(size_of::<Header>()) as *mut Header;
+            ptr::write_unaligned(block.add(layout.size()) as *mut u64, CANARY);
+
+            let mut live = self.live.lock();
+            header.write(Header { prev: ptr::null_mut(), next: live.head, size: layout.size(), tag, canary: CANARY });
+            if !live.head.is_null() {
+                (*live.head).prev = header;
+            }
+            live.head = header;
+            drop(live);
+            self.account(tag, layout.size(), true);
+            block
+        }
+
+        /// Set the tag for new blocks, returning the old one
+        pub fn set_tag(&self, tag: &'static str) -> &'static str {
+            core::mem::replace(&mut *self.tag.lock(), tag)
+        }
+
+        /// First live block with a damaged canary
+        pub fn scan(&self) -> Option<Corruption> {
+            let live = self.live.lock();
+            let mut header = live.head;
+            while !header.is_null() {
+                unsafe {
+                    if let Some(corruption) = verify(header) {
+                        return Some(corruption);
+                    }
+                    header = (*header).next;
+                }
+            }
+            None
+        }
+    }
+
+    /// The layout actually allocated for `layout`, and how far into it the
+    /// caller's block starts
+    fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+        let align = layout.align().max(align_of::<Header>());
+        let front = size_of::<Header>().next_multiple_of(align);
+        let size = front.checked_add(layout.size())?.checked_add(size_of::<u64>())?;
+        Layout::from_size_align(size, align).ok().map(|outer| (outer, front))
+    }
+
+    /// Check one block's canaries
+    unsafe fn verify(header: *mut Header) -> Option<Corruption> {
+        let block = (header as *mut u8).add(size_of::<Header>());
+        let size = (*header).size;
+        let (side, tag) = if (*header).canary != CANARY {
+            // An underrun long enough may have reached the tag too
+            ("front", "(unknown, header overwritten)")
+        } else if ptr::read_unaligned(block.add(size) as *const u64) != CANARY {
+            ("back", (*header).tag)
+        } else {
+            return None;
+        };
+        Some(Corruption { address: block as usize, size, tag, side })
+    }
+
+    /// Stop the kernel over a damaged block
+    pub fn report(corruption: Corruption) -> ! {
+        panic!("Heap corruption: {} canary of the {}-byte block at {:#x} (tag '{}') overwritten",
+            corruption.side, corruption.size, corruption.address, corruption.tag)
+    }
+
+    unsafe impl GlobalAlloc for CheckedHeap {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let tag = *self.tag.lock();
+            self.alloc_tagged(layout, tag)
+        }
+
+        /// A resized block keeps the tag it was allocated under, whatever
+        /// the current one is
+        unsafe fn realloc(&self, block: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+                return ptr::null_mut();
+            };
+            let header = block.sub(size_of::<Header>()) as *mut Header;
+            let new_block = self.alloc_tagged(new_layout, (*header).tag);
+            if !new_block.is_null() {
+                ptr::copy_nonoverlapping(block, new_block, layout.size().min(new_size));
+                self.dealloc(block, layout);
+            }
+            new_block
+        }
+
+        unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+            let header = block.sub(size_of::<Header>()) as *mut Header;
+            let mut live = self.live.lock();
+            if let Some(corruption) = verify(header) {
+                drop(live);
+                report(corruption);
+            }
+            let (prev, next) = ((*header).prev, (*header).next);
+            if prev.is_null() {
+                live.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            drop(live);
+            self.account((*header).tag, (*header).size, false);
+
+            // Poison the header too, so a stale pointer reads nothing useful
+            if let Some((outer, front)) = outer_layout(layout) {
+                let base = block.sub(front);
+                ptr::write_bytes(base, POISON, outer.size());
+                self.heap.dealloc(base, outer);
+            }
+        }
+    }
+}
+
+/// Blocks the fragmentation stress test allocates in all
+const STRESS_ROUNDS: usize = 240;
+/// Start of the stress test's size sequence; any value will do, fixed so
+/// every run allocates the same sizes
+const STRESS_SEED: u32 = 0x2545_F491;
+
+/// Check a walk of the free list agrees with the heap's own count
+fn check_fragmentation(report: Fragmentation) -> Result<(), KernelError> {
+    if report.listed != report.free || report.largest_free > report.listed
+        || (report.free_blocks == 0) != (report.listed == 0)
+        || report.largest_free * report.free_blocks < report.listed {
+        serial_println!("HEAP: Free list walk found {:?}", report);
+        return Err(KernelError::ValidationError("Heap free list disagrees with the free total"));
+    }
+    Ok(())
+}
+
+/// Check free runs split and merge as expected on a private heap, then
+/// allocate mixed sizes and alignments from the real one, freeing some as
+/// it goes, and check every walk of its free list adds up
+fn fragmentation_self_test() -> Result<(), KernelError> {
+    let mut arena = alloc::vec![0u64; 512];
+    let arena_size = arena.len() * core::mem::size_of::<u64>();
+    let mut private = heap::Heap::empty();
+    unsafe { private.init(arena.as_mut_ptr() as usize, arena_size) };
+
+    let block = Layout::from_size_align(64, 8).map_err(|_| KernelError::InvalidParameter)?;
+    let blocks: Vec<_> = (0..4).map(|_| private.allocate_first_fit(block)).collect::<Option<_>>()
+        .ok_or(KernelError::OutOfMemory)?;
+    unsafe {
+        private.deallocate(blocks[0], block);
+        private.deallocate(blocks[2], block);
+    }
+    let split = private.fragmentation();
+    unsafe { private.deallocate(blocks[1], block) };
+    let merged = private.fragmentation();
+    unsafe { private.deallocate(blocks[3], block) };
+    let whole = private.fragmentation();
+    let one_block = Fragmentation { free: arena_size, listed: arena_size, free_blocks: 1, largest_free: arena_size };
+    if split.free_blocks != 3 || split.largest_free != arena_size - 4 * 64 || split.percent() == 0
+        || merged.free_blocks != 2 || whole != one_block || whole.percent() != 0 {
+        serial_println!("HEAP: Private heap walked {:?}, then {:?}, then {:?}", split, merged, whole);
+        return Err(KernelError::ValidationError("Freed blocks split or merged wrongly"));
+    }
+
+    let small = Layout::from_size_align(24, 8).map_err(|_| KernelError::InvalidParameter)?;
+    let aligned = Layout::from_size_align(40, 256).map_err(|_| KernelError::InvalidParameter)?;
+    let first = private.allocate_first_fit(small).ok_or(KernelError::OutOfMemory)?;
+    let second = private.allocate_first_fit(aligned).ok_or(KernelError::OutOfMemory)?;
+    let padded = private.fragmentation();
+    unsafe {
+        private.deallocate(second, aligned);
+        private.deallocate(first, small);
+    }
+    if second.as_ptr() as usize % 256 != 0 || private.fragmentation() != one_block {
+        return Err(KernelError::ValidationError("Aligned block misplaced or its padding lost"));
+    }
+    check_fragmentation(padded)?;
+    drop(arena);
+
+    let mut live: Vec<(*mut u8, Layout)> = Vec::with_capacity(STRESS_ROUNDS);
+    let mut seed = STRESS_SEED;
+    let mut result = Ok(());
+    for round in 0..STRESS_ROUNDS {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let size = 1 + (seed >> 16) as usize % 1024;
+        let align = 8 << ((seed >> 8) % 4);
+        let Ok(layout) = Layout::from_size_align(size, align) else {
+            result = Err(KernelError::InvalidParameter);
+            break;
+        };
+        let block = unsafe { alloc::alloc::alloc(layout) };
+        if block.is_null() {
+            result = Err(KernelError::OutOfMemory);
+            break;
+        }
+        live.push((block, layout));
+        if round % 3 == 2 {
+            let (block, layout) = live.swap_remove((seed >> 4) as usize % live.len());
+            unsafe { alloc::alloc::dealloc(block, layout) };
+        }
+        if round % 20 == 0 {
+            result = check_fragmentation(fragmentation_report());
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+    let during = fragmentation_report();
+    for (block, layout) in live.drain(..) {
+        unsafe { alloc::alloc::dealloc(block, layout) };
+    }
+    result?;
+    check_fragmentation(during)?;
+    check_fragmentation(fragmentation_report())
+}
+
+/// Check the free list adds up under a mixed load. In `heap_debug` builds
+/// also make a deliberate off-by-one write past a block and check a sweep
+/// finds it, check a freed block is poisoned, and check a tag's byte count
+/// follows a block through a resize and a free.
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("HEAP: Running self-test");
+
+    fragmentation_self_test()?;
+
+    #[cfg(feature = "heap_debug")]
+    {
+        if ALLOCATOR.scan().is_some() {
+            return Err(KernelError::ValidationError("Heap already damaged before the self-test"));
+        }
+
+        let guard = tag("heap-selftest");
+        let mut block: Vec<u8> = Vec::with_capacity(16);
+        drop(guard);
+
+        // One byte past the end, put back before the block is freed
+        let found = unsafe {
+            let past_end = block.as_mut_ptr().add(16);
+            let saved = past_end.read();
+            past_end.write(!saved);
+            let found = ALLOCATOR.scan();
+            past_end.write(saved);
+            found
+        };
+        match found {
+            Some(corruption) if corruption.address == block.as_ptr() as usize
+                && corruption.size == 16 && corruption.side == "back" && corruption.tag == "heap-selftest" => {},
+            _ => return Err(KernelError::ValidationError("Off-by-one write went unnoticed")),
+        }
+        drop(block);
+
+        let layout = core::alloc::Layout::from_size_align(64, 8).map_err(|_| KernelError::InvalidParameter)?;
+        let poisoned = unsafe {
+            let raw = alloc::alloc::alloc(layout);
+            if raw.is_null() {
+                return Err(KernelError::OutOfMemory);
+            }
+            raw.write_bytes(0x55, 64);
+            alloc::alloc::dealloc(raw, layout);
+            // The allocator keeps its free list at the start of a free
+            // region, so look further in
+            raw.add(32).read_volatile()
+        };
+        if poisoned != debug::POISON {
+            return Err(KernelError::ValidationError("Freed block not poisoned"));
+        }
+
+        let counted = |tag: &str| ALLOCATOR.usage().iter()
+            .find(|usage| usage.tag == tag)
+            .map_or((0, 0), |usage| (usage.bytes, usage.blocks));
+        let guard = tag("heap-selftest-usage");
+        let mut grown: Vec<u8> = Vec::with_capacity(32);
+        drop(guard);
+        let allocated = counted("heap-selftest-usage");
+        // Grown outside the tag's scope, so only a resize keeping the tag
+        // leaves it counted there
+        grown.reserve_exact(96);
+        let resized = counted("heap-selftest-usage");
+        let capacity = grown.capacity();
+        drop(grown);
+        let freed = counted("heap-selftest-usage");
+        if allocated != (32, 1) || resized != (capacity, 1) || freed != (0, 0) {
+            serial_println!("HEAP: Tag counted {:?}, then {:?} for {} bytes, then {:?}",
+                allocated, resized, capacity, freed);
+            return Err(KernelError::ValidationError("Tagged bytes counted wrongly"));
+        }
+    }
+
+    serial_println!("HEAP: Self-test passed");
+    Ok(())
+}
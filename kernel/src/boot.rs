@@ -0,0 +1,322 @@
+//! Boot progress splash
+//!
+//! init() reports the start and end of each phase here, along with anything
+//! that went wrong in it. The splash lists the phases with their status and
+//! a progress bar, so tolerated failures show on screen rather than being
+//! buried in the serial log. Detail lines go to the logger at Debug level.
+//!
+//! With `boot.verbose` set the splash is dropped and detail goes to serial as
+//! well. The setting can only be read once the configuration is loaded, so
+//! the splash shows until then.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::drivers::cp437;
+use crate::drivers::vga_enhanced::{self, Color};
+use crate::logger::{self, LogLevel};
+use crate::{println, serial_println};
+
+/// Most phases the splash has room for
+const MAX_PHASES: usize = 12;
+/// How long the splash stays up after a failure, unless a key is pressed
+const FAILURE_PAUSE_MS: u64 = 5000;
+
+// Splash layout, in text cells
+const TITLE_ROW: usize = 1;
+const BOX_TOP: usize = 3;
+const BOX_LEFT: usize = 14;
+const BOX_WIDTH: usize = 52;
+/// Last row for problem messages; the logger writes to the one below
+const LAST_PROBLEM_ROW: usize = 23;
+const SCREEN_WIDTH: usize = 80;
+
+/// Where a phase has got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseStatus {
+    Pending,
+    Running,
+    Done,
+    Warning,
+    Failed,
+}
+
+impl PhaseStatus {
+    fn marker(self) -> (char, Color) {
+        match self {
+            PhaseStatus::Pending => ('·', Color::DarkGray),
+            PhaseStatus::Running => ('►', Color::Yellow),
+            PhaseStatus::Done => ('✓', Color::LightGreen),
+            PhaseStatus::Warning => ('!', Color::Yellow),
+            PhaseStatus::Failed => ('✗', Color::LightRed),
+        }
+    }
+}
+
+/// A warning or failure, shown under the phase list
+struct Problem {
+    phase: usize,
+    failed: bool,
+    message: String,
+}
+
+/// Fixed-size so phases can be tracked before the heap is up
+struct BootProgress {
+    names: [&'static str; MAX_PHASES],
+    count: usize,
+    statuses: [PhaseStatus; MAX_PHASES],
+    current: Option<usize>,
+    problems: Vec<Problem>,
+    splash: bool,
+    verbose: bool,
+}
+
+lazy_static! {
+    static ref PROGRESS: Mutex<BootProgress> = Mutex::new(BootProgress {
+        names: [""; MAX_PHASES],
+        count: 0,
+        statuses: [PhaseStatus::Pending; MAX_PHASES],
+        current: None,
+        problems: Vec::new(),
+        splash: false,
+        verbose: false,
+    });
+}
+
+/// Write `text` at (row, column) cell by cell, padded or cut to `width`.
+/// Unlike write_at this never wraps or scrolls.
+fn put(row: usize, column: usize, width: usize, text: &str, fg: Color) {
+    let mut chars = text.chars();
+    for offset in 0..width.min(SCREEN_WIDTH.saturating_sub(column)) {
+        let byte = chars.next().map_or(b' ', cp437::encode);
+        vga_enhanced::write_cell(row, column + offset, byte, fg, Color::Black);
+    }
+}
+
+impl BootProgress {
+    fn phase_row(index: usize) -> usize {
+        BOX_TOP + 1 + index
+    }
+
+    fn bar_row(&self) -> usize {
+        BOX_TOP + self.count + 2
+    }
+
+    fn draw(&self) {
+        vga_enhanced::clear_screen();
+        let title = "UniverseK OS is starting";
+        put(TITLE_ROW, (SCREEN_WIDTH - title.len()) / 2, title.len(), title, Color::White);
+        vga_enhanced::draw_box(BOX_LEFT, BOX_TOP, BOX_WIDTH, self.count + 4);
+        for index in 0..self.count {
+            self.draw_phase(index);
+        }
+        self.draw_bar();
+        self.draw_problems();
+    }
+
+    fn draw_phase(&self, index: usize) {
+        let row = Self::phase_row(index);
+        let status = self.statuses[index];
+        let (marker, color) = status.marker();
+        vga_enhanced::write_cell(row, BOX_LEFT + 2, cp437::encode(marker), color, Color::Black);
+        let name_color = if status == PhaseStatus::Pending { Color::DarkGray } else { Color::LightGray };
+        put(row, BOX_LEFT + 4, 30, self.names[index], name_color);
+
+        // The count is only needed, and only allocated, once something went wrong
+        let count = self.problems.iter().filter(|problem| problem.phase == index).count();
+        let note = match status {
+            PhaseStatus::Failed => String::from("failed"),
+            PhaseStatus::Warning if count == 1 => String::from("1 warning"),
+            PhaseStatus::Warning => format!("{} warnings", count),
+            _ => String::new(),
+        };
+        put(row, BOX_LEFT + 35, BOX_WIDTH - 37, &note, color);
+    }
+
+    fn draw_bar(&self) {
+        let total = self.count.max(1);
+        let finished = self.statuses[..self.count].iter()
+            .filter(|status| !matches!(status, PhaseStatus::Pending | PhaseStatus::Running))
+            .count();
+        let width = BOX_WIDTH - 10;
+        let filled = width * finished / total;
+        let row = self.bar_row();
+        for offset in 0..width {
+            let (glyph, color) = if offset < filled { ('█', Color::LightGreen) } else { ('░', Color::DarkGray) };
+            vga_enhanced::write_cell(row, BOX_LEFT + 2 + offset, cp437::encode(glyph), color, Color::Black);
+        }
+
+        // Written out by hand as this runs before the heap exists
+        let percent = 100 * finished / total;
+        let digits = [(percent >= 100, percent / 100), (percent >= 10, percent / 10 % 10), (true, percent % 10)];
+        let column = BOX_LEFT + 3 + width;
+        for (offset, (shown, digit)) in digits.into_iter().enumerate() {
+            let byte = if shown { b'0' + digit as u8 } else { b' ' };
+            vga_enhanced::write_cell(row, column + offset, byte, Color::White, Color::Black);
+        }
+        vga_enhanced::write_cell(row, column + digits.len(), b'%', Color::White, Color::Black);
+    }
+
+    fn draw_problems(&self) {
+        let first_row = BOX_TOP + self.count + 5;
+        let rows = (LAST_PROBLEM_ROW + 1).saturating_sub(first_row);
+        if rows == 0 {
+            return;
+        }
+        let shown = if self.problems.len() > rows { rows - 1 } else { self.problems.len() };
+        for (offset, problem) in self.problems.iter().take(shown).enumerate() {
+            let (marker, color) = if problem.failed {
+                PhaseStatus::Failed.marker()
+            } else {
+                PhaseStatus::Warning.marker()
+            };
+            let line = format!("{} {}: {}", marker, self.names[problem.phase], problem.message);
+            put(first_row + offset, 1, SCREEN_WIDTH - 2, &line, color);
+        }
+        if shown < self.problems.len() {
+            let line = format!("  ...and {} more; see the log", self.problems.len() - shown);
+            put(first_row + shown, 1, SCREEN_WIDTH - 2, &line, Color::LightGray);
+        }
+    }
+
+    fn set_status(&mut self, index: usize, status: PhaseStatus) {
+        self.statuses[index] = status;
+        if self.splash {
+            self.draw_phase(index);
+            self.draw_bar();
+        }
+    }
+
+    fn report(&mut self, failed: bool, message: &str) {
+        let Some(index) = self.current else {
+            return;
+        };
+        self.problems.push(Problem { phase: index, failed, message: String::from(message) });
+        let status = if failed || self.statuses[index] == PhaseStatus::Failed {
+            PhaseStatus::Failed
+        } else {
+            PhaseStatus::Warning
+        };
+        self.set_status(index, status);
+        if self.splash {
+            self.draw_problems();
+        }
+    }
+}
+
+/// Show the splash with `phases`, in the order they'll run, all pending.
+/// Safe before the heap is up.
+pub fn begin(phases: &[&'static str]) {
+    let mut progress = PROGRESS.lock();
+    let count = phases.len().min(MAX_PHASES);
+    progress.count = count;
+    progress.names[..count].copy_from_slice(&phases[..count]);
+    progress.statuses = [PhaseStatus::Pending; MAX_PHASES];
+    progress.current = None;
+    progress.splash = true;
+    progress.draw();
+}
+
+/// Read `boot.verbose` once the configuration is loaded, and drop the
+/// splash for the chatty output if it's set
+pub fn configure() {
+    let verbose = crate::config::get("boot.verbose")
+        .and_then(|value| value.try_as_boolean())
+        .unwrap_or(false);
+    let mut progress = PROGRESS.lock();
+    progress.verbose = verbose;
+    if verbose && progress.splash {
+        progress.splash = false;
+        vga_enhanced::clear_screen();
+        println!("Starting kernel initialization (boot.verbose is set)...");
+    }
+}
+
+/// Mark phase `index` as running
+pub fn start_phase(index: usize) {
+    let mut progress = PROGRESS.lock();
+    if index >= progress.count {
+        return;
+    }
+    if progress.verbose {
+        serial_println!("DEBUG: [INIT Phase {}] Starting", progress.names[index]);
+    }
+    progress.current = Some(index);
+    progress.set_status(index, PhaseStatus::Running);
+}
+
+/// Mark the running phase as done, keeping any warning or failure
+pub fn end_phase() {
+    let mut progress = PROGRESS.lock();
+    let Some(index) = progress.current.take() else {
+        return;
+    };
+    if progress.verbose {
+        serial_println!("DEBUG: [INIT Phase {}] Complete", progress.names[index]);
+    }
+    if progress.statuses[index] == PhaseStatus::Running {
+        progress.statuses[index] = PhaseStatus::Done;
+    }
+    // Self-tests may have drawn over the splash
+    if progress.splash {
+        progress.draw();
+    }
+}
+
+/// A detail line: kept in the log, and printed to serial when verbose
+pub fn detail(message: &str) {
+    if PROGRESS.lock().verbose {
+        serial_println!("DEBUG: {}", message);
+    }
+    logger::record(LogLevel::Debug, "boot", message);
+}
+
+/// Something went wrong but the phase carried on
+pub fn warn(message: &str) {
+    serial_println!("DEBUG: Warning: {}", message);
+    logger::record(LogLevel::Warning, "boot", message);
+    PROGRESS.lock().report(false, message);
+}
+
+/// The phase failed at what it was for, and boot goes on without it
+pub fn fail(message: &str) {
+    serial_println!("ERROR: {}", message);
+    logger::record(LogLevel::Error, "boot", message);
+    PROGRESS.lock().report(true, message);
+}
+
+/// Whether any phase failed outright
+pub fn any_failed() -> bool {
+    PROGRESS.lock().problems.iter().any(|problem| problem.failed)
+}
+
+/// Finish the splash. After a failure it stays up for a few seconds, or
+/// until a key is pressed, so it can be read before the desktop covers it.
+pub fn finish() {
+    let (splash, problems) = {
+        let mut progress = PROGRESS.lock();
+        progress.current = None;
+        if progress.verbose {
+            println!("Kernel initialization complete!");
+        }
+        (progress.splash, progress.problems.len())
+    };
+    serial_println!("DEBUG: Kernel initialization complete with {} problem(s)", problems);
+    if !splash || !any_failed() {
+        return;
+    }
+
+    let prompt = "Some parts of the system failed to start. Press a key to continue.";
+    put(LAST_PROBLEM_ROW + 1, (SCREEN_WIDTH - prompt.len()) / 2, prompt.len(), prompt, Color::White);
+    let step_ms = 10;
+    for _ in 0..FAILURE_PAUSE_MS / step_ms {
+        let pressed = core::iter::from_fn(crate::drivers::ps2_keyboard::get_event)
+            .any(|event| event.state == crate::drivers::ps2_keyboard::KeyState::Pressed);
+        if pressed {
+            break;
+        }
+        crate::drivers::pit::busy_sleep_us(step_ms * 1000);
+    }
+}
@@ -0,0 +1,178 @@
+//! The boot command line: words like `safe` or `key=value` that change
+//! how one boot goes, without touching the saved settings. A config key
+//! such as `log.level=debug` overrides that setting for the boot.
+//!
+//! The bootloader doesn't pass a command line, so it's read from QEMU's
+//! firmware configuration device as the file `opt/universek/cmdline`:
+//!
+//! ```text
+//! qemu-system-x86_64 ... -fw_cfg name=opt/universek/cmdline,string=safe
+//! ```
+//!
+//! Anywhere else there's no such device, the command line is empty and
+//! every flag is off.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// Name of the firmware configuration file holding the command line
+pub const FILE_NAME: &str = "opt/universek/cmdline";
+
+/// Firmware configuration ports: write an item's key to the selector,
+/// then read the item a byte at a time from the data port
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+/// Items with fixed keys: "QEMU" when the device is there, and the list
+/// of named files
+const SIGNATURE_KEY: u16 = 0x0000;
+const FILE_DIR_KEY: u16 = 0x0019;
+
+/// Size of one file list entry, and of the name at its end
+const FILE_ENTRY_SIZE: usize = 64;
+const FILE_NAME_SIZE: usize = 56;
+
+/// Longest command line read
+const MAX_LENGTH: usize = 1024;
+
+lazy_static! {
+    static ref WORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+/// Select firmware configuration item `key` and read the start of it
+fn read_item(key: u16, buffer: &mut [u8]) {
+    unsafe { Port::<u16>::new(SELECTOR_PORT).write(key) };
+    read_more(buffer);
+}
+
+/// Read on from where the last read stopped
+fn read_more(buffer: &mut [u8]) {
+    let mut data = Port::<u8>::new(DATA_PORT);
+    for byte in buffer.iter_mut() {
+        *byte = unsafe { data.read() };
+    }
+}
+
+/// The command line file from QEMU, if there's a firmware configuration
+/// device and it has one
+fn read_from_firmware() -> Option<String> {
+    let mut signature = [0u8; 4];
+    read_item(SIGNATURE_KEY, &mut signature);
+    if &signature != b"QEMU" {
+        return None;
+    }
+
+    let mut count = [0u8; 4];
+    read_item(FILE_DIR_KEY, &mut count);
+    let mut found = None;
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut entry = [0u8; FILE_ENTRY_SIZE];
+        read_more(&mut entry);
+        let name = &entry[FILE_ENTRY_SIZE - FILE_NAME_SIZE..];
+        let name = &name[..name.iter().position(|byte| *byte == 0).unwrap_or(FILE_NAME_SIZE)];
+        if name == FILE_NAME.as_bytes() {
+            let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+            found = Some((u16::from_be_bytes([entry[4], entry[5]]), size.min(MAX_LENGTH)));
+            break;
+        }
+    }
+
+    let (key, size) = found?;
+    let mut data = alloc::vec![0u8; size];
+    read_item(key, &mut data);
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Split a command line into its words
+pub fn parse(text: &str) -> Vec<String> {
+    text.split(|c: char| c.is_whitespace() || c == '\0')
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Whether `words` has `name` on its own
+fn has_flag(words: &[String], name: &str) -> bool {
+    words.iter().any(|word| word == name)
+}
+
+/// What the last `key=value` in `words` sets `key` to
+fn find_value<'a>(words: &'a [String], key: &str) -> Option<&'a str> {
+    words.iter().rev()
+        .find_map(|word| word.strip_prefix(key).and_then(|rest| rest.strip_prefix('=')))
+}
+
+/// The `key=value` words of `words` whose key is a config key, with a dot
+fn find_settings(words: &[String]) -> Vec<(String, String)> {
+    words.iter()
+        .filter_map(|word| word.split_once('='))
+        .filter(|(key, _)| key.contains('.'))
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
+
+/// Read this boot's command line. Needs the heap.
+pub fn init() {
+    let words = parse(&read_from_firmware().unwrap_or_default());
+    if !words.is_empty() {
+        serial_println!("CMDLINE: {}", words.join(" "));
+    }
+    *WORDS.lock() = words;
+}
+
+/// Whether the command line has `name` on its own, like `safe`
+pub fn flag(name: &str) -> bool {
+    has_flag(&WORDS.lock(), name)
+}
+
+/// What the command line sets `key` to with `key=value`; the last one
+/// wins
+pub fn value(key: &str) -> Option<String> {
+    find_value(&WORDS.lock(), key).map(String::from)
+}
+
+/// Config settings the command line overrides, in order
+pub fn settings() -> Vec<(String, String)> {
+    find_settings(&WORDS.lock())
+}
+
+/// The command line, for /proc/cmdline
+pub fn text() -> String {
+    format!("{}\n", WORDS.lock().join(" "))
+}
+
+/// Check how command lines are split and searched
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("CMDLINE: Running self-test");
+
+    let words = parse("  safe\tconsole=serial\n profile=debug profile=demo\0");
+    if words != ["safe", "console=serial", "profile=debug", "profile=demo"] {
+        serial_println!("CMDLINE: Split into {:?}", words);
+        return Err(KernelError::ValidationError("Command line split wrongly"));
+    }
+    if !has_flag(&words, "safe") || has_flag(&words, "saf") || has_flag(&words, "console") {
+        return Err(KernelError::ValidationError("Command line flag found wrongly"));
+    }
+    if find_value(&words, "console") != Some("serial") || find_value(&words, "profile") != Some("demo")
+        || find_value(&words, "safe").is_some() || find_value(&words, "prof").is_some() {
+        return Err(KernelError::ValidationError("Command line value found wrongly"));
+    }
+    let settings = find_settings(&parse("log.level=debug safe profile=demo ui.wallpaper=a=b"));
+    let expected = [("log.level", "debug"), ("ui.wallpaper", "a=b")];
+    if settings.len() != expected.len()
+        || settings.iter().zip(expected).any(|((key, value), (want_key, want_value))| key != want_key || value != want_value) {
+        return Err(KernelError::ValidationError("Command line settings found wrongly"));
+    }
+    if !parse("").is_empty() {
+        return Err(KernelError::ValidationError("Empty command line has words"));
+    }
+
+    serial_println!("CMDLINE: Self-test passed");
+    Ok(())
+}
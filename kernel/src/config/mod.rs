@@ -3,6 +3,7 @@
 
 use alloc::string::{String, ToString};
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use alloc::format;
 use spin::Mutex;
 use lazy_static::lazy_static;
@@ -11,7 +12,7 @@ use crate::fs;
 use crate::errors::KernelError;
 
 /// A single configuration value
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ConfigValue {
     /// String value
     String(String),
@@ -71,179 +72,390 @@ impl ConfigValue {
     }
 }
 
+/// Where a setting comes from. `get` looks through the layers from the
+/// last to the first, so the active profile beats the system's value, a
+/// user's preference beats both and a boot override beats everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    /// /System/Library/config.ini, shared by every user
+    System,
+    /// The active profile's section of the system file, `[profile.<name>]`;
+    /// values set here are kept in that section
+    Profile,
+    /// The logged-in user's ~/Library/Preferences/config.ini
+    User,
+    /// This boot only: `key=value` settings from the command line and
+    /// anything set here at run time; never saved
+    Runtime,
+}
+
+impl Layer {
+    /// Every layer, lowest precedence first
+    pub const ALL: [Layer; 4] = [Layer::System, Layer::Profile, Layer::User, Layer::Runtime];
+    
+    pub fn name(self) -> &'static str {
+        match self {
+            Layer::System => "system",
+            Layer::Profile => "profile",
+            Layer::User => "user",
+            Layer::Runtime => "runtime",
+        }
+    }
+    
+    /// The layer called `name`
+    pub fn from_name(name: &str) -> Option<Layer> {
+        Layer::ALL.into_iter().find(|layer| layer.name() == name)
+    }
+}
+
+/// The user layer's file, inside the user's home directory
+pub const USER_CONFIG_FILE: &str = "Library/Preferences/config.ini";
+
+/// Config key giving how many backups a save keeps of the file it replaces
+pub const BACKUPS_KEY: &str = "system.config_backups";
+/// Backups kept when `system.config_backups` isn't set
+const DEFAULT_BACKUPS: i64 = 3;
+/// Most backups kept, and looked through for one that loads
+pub const MAX_BACKUPS: usize = 9;
+/// Largest config file read
+const MAX_FILE_SIZE: usize = 16384;
+
+/// Config key naming the profile to use when the boot command line
+/// doesn't give one with `profile=<name>`; "" for none
+pub const ACTIVE_PROFILE_KEY: &str = "system.active_profile";
+/// Profile keys are kept in the system layer as `profile.<name>.<key>`,
+/// and in the file as `<key>` lines under a `[profile.<name>]` header
+const PROFILE_PREFIX: &str = "profile.";
+
+/// The profile name and the key a profile key `profile.<name>.<key>`
+/// sets, or None for any other key
+fn split_profile_key(key: &str) -> Option<(&str, &str)> {
+    let (name, key) = key.strip_prefix(PROFILE_PREFIX)?.split_once('.')?;
+    (!name.is_empty() && !key.is_empty()).then_some((name, key))
+}
+
+/// Whether `name` can name a profile: letters, digits, `-` and `_`
+pub fn valid_profile_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 /// Configuration manager
 pub struct ConfigManager {
-    /// Configuration values
-    values: BTreeMap<String, ConfigValue>,
+    /// Each layer's own values, in `Layer::ALL` order
+    layers: [BTreeMap<String, ConfigValue>; 4],
+    /// The profile the profile layer holds, if any
+    profile: Option<String>,
+    /// The profile the boot command line picked, beating the config key
+    boot_profile: Option<String>,
     /// Whether configuration has been modified
     modified: bool,
     /// Path to the config file
     config_file: String,
+    /// The user layer's file, while someone is logged in
+    user_file: Option<String>,
 }
 
 impl ConfigManager {
     /// Create a new configuration manager
     pub fn new() -> Self {
         Self {
-            values: BTreeMap::new(),
+            layers: Default::default(),
+            profile: None,
+            boot_profile: None,
             modified: false,
             config_file: "/System/Library/config.ini".to_string(),
+            user_file: None,
         }
     }
     
-    /// Get a configuration value
+    /// Get a configuration value, from the highest layer that has it
     pub fn get(&self, key: &str) -> Option<&ConfigValue> {
-        self.values.get(key)
+        self.layers.iter().rev().find_map(|values| values.get(key))
+    }
+    
+    /// Get a value from `layer` alone
+    pub fn get_in(&self, layer: Layer, key: &str) -> Option<&ConfigValue> {
+        self.layers[layer as usize].get(key)
+    }
+    
+    /// The layer `get` finds `key` in
+    pub fn layer_of(&self, key: &str) -> Option<Layer> {
+        Layer::ALL.into_iter().rev().find(|layer| self.layers[*layer as usize].contains_key(key))
+    }
+    
+    /// Where `set` puts values: the user layer while someone is logged
+    /// in, else the system layer
+    pub fn default_layer(&self) -> Layer {
+        if self.user_file.is_some() { Layer::User } else { Layer::System }
     }
     
-    /// Set a configuration value
+    /// Set a configuration value in the default layer
     pub fn set(&mut self, key: &str, value: ConfigValue) {
-        self.values.insert(key.to_string(), value);
+        self.set_in(self.default_layer(), key, value);
+    }
+    
+    /// Set a configuration value in `layer`. One for the profile layer
+    /// goes in the active profile's section; `sync_profile` brings the
+    /// profile layer up to date with it.
+    pub fn set_in(&mut self, layer: Layer, key: &str, value: ConfigValue) {
+        let (layer, key) = self.stored_as(layer, key);
+        self.layers[layer as usize].insert(key, value);
         self.modified = true;
     }
     
-    /// Remove a configuration value
-    pub fn remove(&mut self, key: &str) -> Option<ConfigValue> {
-        let value = self.values.remove(key);
+    /// Remove a configuration value from `layer`, uncovering any lower
+    /// layer's value
+    pub fn remove_from(&mut self, layer: Layer, key: &str) -> Option<ConfigValue> {
+        let (layer, key) = self.stored_as(layer, key);
+        let value = self.layers[layer as usize].remove(&key);
         if value.is_some() {
             self.modified = true;
         }
         value
     }
     
+    /// Where a value for `key` in `layer` is kept
+    fn stored_as(&self, layer: Layer, key: &str) -> (Layer, String) {
+        match (layer, &self.profile) {
+            (Layer::Profile, Some(name)) => (Layer::System, format!("{}{}.{}", PROFILE_PREFIX, name, key)),
+            _ => (layer, key.to_string()),
+        }
+    }
+    
+    /// The profiles the system layer has sections for, with how many
+    /// keys each sets, sorted by name
+    pub fn profiles(&self) -> Vec<(String, usize)> {
+        let mut profiles: Vec<(String, usize)> = Vec::new();
+        for (name, _) in self.layers[Layer::System as usize].keys().filter_map(|key| split_profile_key(key)) {
+            match profiles.last_mut() {
+                Some((last, count)) if last == name => *count += 1,
+                _ => profiles.push((name.to_string(), 1)),
+            }
+        }
+        profiles.sort();
+        profiles
+    }
+    
+    /// The profile the profile layer holds
+    pub fn active_profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+    
+    /// Fill the profile layer from the section of the profile in use: the
+    /// boot command line's, else `system.active_profile` from the runtime
+    /// or system layer. Returns the keys whose value as `get` finds it
+    /// changed.
+    pub fn sync_profile(&mut self) -> Vec<String> {
+        let name = self.boot_profile.clone()
+            .or_else(|| [Layer::Runtime, Layer::System].into_iter()
+                .find_map(|layer| self.get_in(layer, ACTIVE_PROFILE_KEY))
+                .map(ConfigValue::as_string))
+            .filter(|name| !name.is_empty());
+        let entries = match &name {
+            Some(name) => self.layers[Layer::System as usize].iter()
+                .filter_map(|(key, value)| match split_profile_key(key) {
+                    Some((profile, key)) if profile == name => Some((key.to_string(), value.clone())),
+                    _ => None,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        self.profile = name;
+        self.replace_layer(Layer::Profile, entries)
+    }
+    
+    /// Every key any layer has, with the value `get` finds, sorted by key
+    pub fn values(&self) -> BTreeMap<&String, &ConfigValue> {
+        let mut values = BTreeMap::new();
+        for layer in &self.layers {
+            values.extend(layer.iter());
+        }
+        values
+    }
+    
+    /// Replace `layer` with `entries`; returns the keys whose value as
+    /// `get` finds it changed
+    fn replace_layer(&mut self, layer: Layer, entries: Vec<(String, ConfigValue)>) -> Vec<String> {
+        let mut keys: Vec<String> = self.layers[layer as usize].keys().cloned().collect();
+        keys.extend(entries.iter().map(|(key, _)| key.clone()));
+        keys.sort();
+        keys.dedup();
+        let before: Vec<Option<ConfigValue>> = keys.iter().map(|key| self.get(key).cloned()).collect();
+        self.layers[layer as usize] = entries.into_iter().collect();
+        keys.into_iter().zip(before)
+            .filter(|(key, before)| self.get(key) != before.as_ref())
+            .map(|(key, _)| key)
+            .collect()
+    }
+    
     /// Load configuration from the default file
     pub fn load(&mut self) -> Result<(), KernelError> {
         let config_file = self.config_file.clone();
         self.load_from_file(&config_file)
     }
     
-    /// Load configuration from a file
+    /// Load the system layer from a file, or from its newest good backup
+    /// if the file is corrupt
     pub fn load_from_file(&mut self, path: &str) -> Result<(), KernelError> {
-        // Check if file exists
-        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
-        
-        // Try to get metadata (to check if file exists)
-        if let Err(KernelError::NotFound) = vfs.metadata(path) {
-            // File doesn't exist, use defaults
-            serial_println!("Config file not found, using defaults");
-            self.set_defaults();
-            return Ok(());
-        }
-        
-        // Read the file
-        let mut buffer = [0u8; 1024]; // Limit config file size to 1KB
-        let bytes_read = fs::direct_read_file(path, &mut buffer)?;
-        
-        if bytes_read == 0 {
-            // Empty file, use defaults
-            serial_println!("Config file is empty, using defaults");
+        let Some(entries) = read_entries_or_backup(path)? else {
+            serial_println!("Config file missing or empty, using defaults");
             self.set_defaults();
             return Ok(());
-        }
-        
-        // Parse the file content
-        let content = core::str::from_utf8(&buffer[0..bytes_read])
-            .map_err(|_| KernelError::InvalidData)?;
-        
-        // Clear existing configuration
-        self.values.clear();
-        
-        // Parse lines
-        for line in content.lines() {
-            let line = line.trim();
-            
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-            
-            // Parse key=value
-            if let Some(pos) = line.find('=') {
-                let key = line[..pos].trim();
-                let value = line[pos+1..].trim();
-                
-                if key.is_empty() {
-                    continue;
-                }
-                
-                // Parse value
-                if value.eq_ignore_ascii_case("true") {
-                    self.set(key, ConfigValue::boolean(true));
-                } else if value.eq_ignore_ascii_case("false") {
-                    self.set(key, ConfigValue::boolean(false));
-                } else if let Ok(int_value) = value.parse::<i64>() {
-                    self.set(key, ConfigValue::integer(int_value));
-                } else {
-                    self.set(key, ConfigValue::string(value));
-                }
-            }
-        }
+        };
         
+        // Replace the existing configuration
+        self.replace_layer(Layer::System, entries);
         self.modified = false;
         Ok(())
     }
     
-    /// Save configuration to the default file
+    /// Save the system layer, and the user layer while someone is logged
+    /// in, each to its own file
     pub fn save(&mut self) -> Result<(), KernelError> {
         let config_file = self.config_file.clone();
-        self.save_to_file(&config_file)
+        self.save_to_file(Layer::System, &config_file)?;
+        if let Some(user_file) = self.user_file.clone() {
+            self.save_to_file(Layer::User, &user_file)?;
+        }
+        self.modified = false;
+        Ok(())
     }
     
-    /// Save configuration to a file
-    pub fn save_to_file(&mut self, path: &str) -> Result<(), KernelError> {
+    /// Save the values of `layer` alone to a file, first keeping the file
+    /// it replaces as a backup
+    pub fn save_to_file(&mut self, layer: Layer, path: &str) -> Result<(), KernelError> {
         let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
         
-        // Try to create the file (or truncate if exists)
-        if let Err(KernelError::AlreadyExists) = vfs.create_file(path) {
-            // File already exists, remove and recreate
-            vfs.remove(path)?;
-            vfs.create_file(path)?;
-        }
-        
-        // Build the file content
-        let mut content = String::new();
-        content.push_str("# UniverseK OS Configuration\n");
-        content.push_str("# Auto-generated - do not edit manually\n\n");
-        
-        // Add all values
-        for (key, value) in &self.values {
-            match value {
-                ConfigValue::String(s) => content.push_str(&format!("{}={}\n", key, s)),
-                ConfigValue::Integer(i) => content.push_str(&format!("{}={}\n", key, i)),
-                ConfigValue::Boolean(b) => content.push_str(&format!("{}={}\n", key, b)),
-            }
+        let depth = self.get(BACKUPS_KEY).and_then(|value| value.try_as_integer()).unwrap_or(DEFAULT_BACKUPS);
+        if let Err(e) = rotate_backups(path, (depth.max(0) as usize).min(MAX_BACKUPS)) {
+            serial_println!("Warning: Not backing up {}: {:?}", path, e);
         }
         
-        // Write to file
-        let bytes = content.as_bytes();
-        fs::direct_write_file(path, bytes)?;
-        
-        self.modified = false;
+        // Replace the file whole, so a failed save keeps the old settings
+        vfs.write_file_atomic(path, self.layer_text(layer).as_bytes())?;
         Ok(())
     }
     
+    /// The file contents `layer` saves as
+    fn layer_text(&self, layer: Layer) -> String {
+        let header = match layer {
+            Layer::User => "# UniverseK OS user preferences\n# Settings here override /System/Library/config.ini\n",
+            _ => "# UniverseK OS Configuration\n# Auto-generated - do not edit manually\n",
+        };
+        format_entries(header, self.layers[layer as usize].iter().map(|(key, value)| (key.as_str(), value)))
+    }
+    
     /// Set default configuration values
     pub fn set_defaults(&mut self) {
         // System settings
-        self.set("system.name", ConfigValue::string("UniverseK OS"));
-        self.set("system.version", ConfigValue::string("0.1.0"));
-        self.set("system.safe_mode", ConfigValue::boolean(true));
+        self.set_in(Layer::System, "system.name", ConfigValue::string(crate::version::NAME));
+        self.set_in(Layer::System, "system.version", ConfigValue::string(crate::version::VERSION));
+        self.set_in(Layer::System, crate::i18n::CONFIG_KEY, ConfigValue::string(crate::i18n::DEFAULT_LOCALE));
+        self.set_in(Layer::System, BACKUPS_KEY, ConfigValue::integer(DEFAULT_BACKUPS));
+        // Boot to the shell with the mouse, disks and GUI left out (see
+        // safe_mode); the "safe" boot flag does the same for one boot
+        self.set_in(Layer::System, "system.safe_mode", ConfigValue::boolean(false));
+        // Profile whose [profile.<name>] section overrides these values;
+        // the "profile=<name>" boot parameter picks one for a boot
+        self.set_in(Layer::System, ACTIVE_PROFILE_KEY, ConfigValue::string(""));
+        
+        // Boot settings: verbose prints every init step instead of the splash
+        self.set_in(Layer::System, "boot.verbose", ConfigValue::boolean(false));
+        // Give a failed init step that boot can do without a second try
+        self.set_in(Layer::System, "boot.retry_failed_steps", ConfigValue::boolean(false));
+        // Start in the GUI rather than the full-screen shell, if there is one
+        self.set_in(Layer::System, "boot.start_gui", ConfigValue::boolean(true));
         
         // UI settings
-        self.set("ui.theme", ConfigValue::string("default"));
-        self.set("ui.color_scheme", ConfigValue::string("blue"));
+        self.set_in(Layer::System, "ui.theme", ConfigValue::string("default"));
+        self.set_in(Layer::System, "ui.color_scheme", ConfigValue::string("blue"));
+        self.set_in(Layer::System, "ui.wallpaper", ConfigValue::string("blue"));
+        self.set_in(Layer::System, "ui.wallpaper_mode", ConfigValue::string("tile"));
+        // The bell flashes the window's taskbar button instead of beeping
+        self.set_in(Layer::System, "ui.visual_bell", ConfigValue::boolean(false));
+        // Start the screensaver after this many seconds without input (0
+        // for never); "blank" or a moving "text"
+        self.set_in(Layer::System, "ui.screensaver_timeout", ConfigValue::integer(600));
+        self.set_in(Layer::System, "ui.screensaver", ConfigValue::string("text"));
+        // Reopen the windows of the last GUI session at boot
+        self.set_in(Layer::System, "gui.restore_session", ConfigValue::boolean(true));
+        // Record GUI input to this file from start to exit ("" for none);
+        // `guirec play` replays it
+        self.set_in(Layer::System, "gui.record_file", ConfigValue::string(""));
+        
+        // Key repeat: wait before the first repeat (250-1000ms) and repeats
+        // a second (2-30), for the keyboard and the software fallback
+        self.set_in(Layer::System, "input.repeat_delay_ms", ConfigValue::integer(500));
+        self.set_in(Layer::System, "input.repeat_rate_cps", ConfigValue::integer(20));
+        // Pointer speed as a percentage of the mouse's (10-1000), and how
+        // much faster quick movements go (0-100, 0 for none)
+        self.set_in(Layer::System, "input.mouse_sensitivity", ConfigValue::integer(100));
+        self.set_in(Layer::System, "input.mouse_accel", ConfigValue::integer(0));
+        
+        // Shell settings
+        self.set_in(Layer::System, "shell.paste_executes", ConfigValue::boolean(false));
+        self.set_in(Layer::System, "shell.history_size", ConfigValue::integer(100));
+        self.set_in(Layer::System, "shell.dir_stack_size", ConfigValue::integer(10));
         
         // Filesystem settings
-        self.set("fs.root_device", ConfigValue::string("ramdisk"));
-        self.set("fs.automount", ConfigValue::boolean(true));
+        self.set_in(Layer::System, "fs.root_device", ConfigValue::string("ramdisk"));
+        self.set_in(Layer::System, "fs.automount", ConfigValue::boolean(true));
+        self.set_in(Layer::System, "fs.tempfs_capacity", ConfigValue::integer(10 * 1024 * 1024));
+        self.set_in(Layer::System, "fs.check_on_mount", ConfigValue::boolean(false));
+        // Descriptors open at once; more fail with "Too many open files"
+        self.set_in(Layer::System, "fs.max_open_files", ConfigValue::integer(64));
+        // Without a disk the root is TempFS, or FAT on a RamDisk with "fat"
+        self.set_in(Layer::System, "fs.ram_fs", ConfigValue::string("tempfs"));
+        self.set_in(Layer::System, "fs.ramdisk_size_kb", ConfigValue::integer(4096));
+        // Blocks read ahead on sequential disk reads; 0 turns readahead off
+        self.set_in(Layer::System, "fs.readahead_blocks", ConfigValue::integer(16));
+        // Removing things in a home under /Users moves them to its .Trash
+        self.set_in(Layer::System, "fs.use_trash", ConfigValue::boolean(false));
+        
+        // Watchdog settings; the action is "log" or "reboot"
+        self.set_in(Layer::System, "watchdog.timeout_secs", ConfigValue::integer(10));
+        self.set_in(Layer::System, "watchdog.action", ConfigValue::string("log"));
+        
+        // Idle loop health line: seconds between lines (0 for none) and
+        // "quiet", "normal" or "verbose"
+        self.set_in(Layer::System, "idle.heartbeat_secs", ConfigValue::integer(60));
+        self.set_in(Layer::System, "idle.verbosity", ConfigValue::string("normal"));
+        
+        // Logging: lowest level printed ("debug", "info", "warning", "error" or
+        // "critical"), serial messages a second per module (0 for no limit),
+        // and whether repeats of one message are collapsed
+        self.set_in(Layer::System, "log.level", ConfigValue::string("info"));
+        self.set_in(Layer::System, "log.serial_rate", ConfigValue::integer(200));
+        self.set_in(Layer::System, "log.serial_dedup", ConfigValue::boolean(true));
         
-        // Network settings (for future use)
-        self.set("network.enabled", ConfigValue::boolean(false));
-        self.set("network.dhcp", ConfigValue::boolean(true));
+        // Debugging: panic on a lock held too long instead of warning
+        self.set_in(Layer::System, "debug.strict_locks", ConfigValue::boolean(false));
+        
+        // Debugging: allow sendkeys and other synthetic keyboard and mouse input
+        self.set_in(Layer::System, "debug.input_injection", ConfigValue::boolean(false));
+        
+        // Network settings (static addressing; defaults suit QEMU user networking)
+        self.set_in(Layer::System, "network.enabled", ConfigValue::boolean(true));
+        self.set_in(Layer::System, "network.dhcp", ConfigValue::boolean(false));
+        self.set_in(Layer::System, "network.ip", ConfigValue::string("10.0.2.15"));
+        self.set_in(Layer::System, "network.netmask", ConfigValue::string("255.255.255.0"));
+        self.set_in(Layer::System, "network.gateway", ConfigValue::string("10.0.2.2"));
         
         // User settings
-        self.set("user.auto_login", ConfigValue::boolean(false));
-        self.set("user.default", ConfigValue::string("user"));
+        self.set_in(Layer::System, "user.auto_login", ConfigValue::boolean(false));
+        self.set_in(Layer::System, "user.default", ConfigValue::string("user"));
+        // Ask for the password to leave the screensaver, if there is one
+        self.set_in(Layer::System, "user.lock_on_idle", ConfigValue::boolean(false));
+        
+        // Profiles: "debug" boots verbosely to the shell in safe mode,
+        // "demo" boots quietly to the desktop
+        self.set_in(Layer::System, "profile.debug.boot.verbose", ConfigValue::boolean(true));
+        self.set_in(Layer::System, "profile.debug.boot.start_gui", ConfigValue::boolean(false));
+        self.set_in(Layer::System, "profile.debug.log.level", ConfigValue::string("debug"));
+        self.set_in(Layer::System, "profile.debug.system.safe_mode", ConfigValue::boolean(true));
+        self.set_in(Layer::System, "profile.demo.boot.verbose", ConfigValue::boolean(false));
+        self.set_in(Layer::System, "profile.demo.boot.start_gui", ConfigValue::boolean(true));
+        self.set_in(Layer::System, "profile.demo.log.level", ConfigValue::string("warning"));
         
         self.modified = true;
     }
@@ -260,13 +472,314 @@ impl ConfigManager {
     /// Set a boot option
     pub fn set_boot_option(&mut self, option: &str, enabled: bool) {
         let key = format!("boot.{}", option);
-        self.set(&key, ConfigValue::boolean(enabled));
+        self.set_in(Layer::System, &key, ConfigValue::boolean(enabled));
+    }
+}
+
+/// The text of the config file at `path`, or None if it is missing or
+/// empty. A file too big for a config file, or not text, is InvalidData.
+fn read_text(path: &str) -> Result<Option<String>, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let size = match vfs.metadata(path) {
+        Ok(metadata) => metadata.size as usize,
+        Err(KernelError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if size > MAX_FILE_SIZE {
+        return Err(KernelError::InvalidData);
+    }
+    
+    let mut buffer = alloc::vec![0u8; size];
+    let bytes_read = fs::direct_read_file(path, &mut buffer)?;
+    if bytes_read == 0 {
+        return Ok(None);
+    }
+    buffer.truncate(bytes_read);
+    String::from_utf8(buffer).map(Some).map_err(|_| KernelError::InvalidData)
+}
+
+/// The entries of the config file at `path`, or None if it is missing or
+/// empty. A file with a line `parse_entries` would skip is InvalidData.
+fn read_entries(path: &str) -> Result<Option<Vec<(String, ConfigValue)>>, KernelError> {
+    match read_text(path)? {
+        Some(text) => parse_file(&text).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// `parse_entries`, failing on a line that isn't blank, a comment, a
+/// `[section]` header or `key=value`; a file the kernel wrote never has
+/// one, so a file that does was damaged
+fn parse_file(content: &str) -> Result<Vec<(String, ConfigValue)>, KernelError> {
+    let malformed = content.lines().map(str::trim).any(|line| {
+        !line.is_empty() && !line.starts_with('#') && section_name(line).is_none()
+            && !line.split_once('=').is_some_and(|(key, _)| !key.trim().is_empty())
+    });
+    if malformed {
+        return Err(KernelError::InvalidData);
+    }
+    Ok(parse_entries(content))
+}
+
+/// Path of backup `n` of the config file at `path`, counting from 1 for
+/// the newest: `.bak`, then `.bak.1`, `.bak.2` and so on
+pub fn backup_path(path: &str, n: usize) -> String {
+    if n <= 1 {
+        format!("{}.bak", path)
+    } else {
+        format!("{}.bak.{}", path, n - 1)
+    }
+}
+
+/// `read_entries`, but if the file is corrupt, the entries of its newest
+/// backup that isn't. Logs which file they came from.
+fn read_entries_or_backup(path: &str) -> Result<Option<Vec<(String, ConfigValue)>>, KernelError> {
+    let error = match read_entries(path) {
+        Ok(Some(entries)) => {
+            serial_println!("Loaded configuration from {}", path);
+            return Ok(Some(entries));
+        }
+        Ok(None) => return Ok(None),
+        Err(KernelError::InvalidData) => KernelError::InvalidData,
+        Err(e) => return Err(e),
+    };
+    
+    serial_println!("Warning: {} is corrupt; trying its backups", path);
+    for n in 1..=MAX_BACKUPS {
+        let backup = backup_path(path, n);
+        match read_entries(&backup) {
+            Ok(Some(entries)) => {
+                serial_println!("Loaded configuration from {}", backup);
+                return Ok(Some(entries));
+            }
+            // Backups may be missing, from a lower depth or a fresh system
+            Ok(None) => {}
+            Err(e) => serial_println!("Warning: Backup {} is unusable too: {:?}", backup, e),
+        }
+    }
+    serial_println!("Warning: No usable backup of {}", path);
+    Err(error)
+}
+
+/// Move each backup of `path` one older, dropping the one past `depth`,
+/// and copy `path` in as the newest. A missing or corrupt `path` is left
+/// out, so it can't push a good backup further down.
+fn rotate_backups(path: &str, depth: usize) -> Result<(), KernelError> {
+    if depth == 0 {
+        return Ok(());
+    }
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let current = match read_text(path) {
+        Ok(Some(text)) if parse_file(&text).is_ok() => text,
+        Ok(_) | Err(KernelError::InvalidData) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    
+    match vfs.remove_permanently(&backup_path(path, depth)) {
+        Ok(()) | Err(KernelError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    for n in (1..depth).rev() {
+        match vfs.rename(&backup_path(path, n), &backup_path(path, n + 1)) {
+            Ok(()) | Err(KernelError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    vfs.write_file_atomic(&backup_path(path, 1), current.as_bytes())
+}
+
+/// Read a value the way config files spell them: true/false, a whole
+/// number, or else a string
+pub fn parse_value(value: &str) -> ConfigValue {
+    if value.eq_ignore_ascii_case("true") {
+        ConfigValue::boolean(true)
+    } else if value.eq_ignore_ascii_case("false") {
+        ConfigValue::boolean(false)
+    } else if let Ok(int_value) = value.parse::<i64>() {
+        ConfigValue::integer(int_value)
+    } else {
+        ConfigValue::string(value)
+    }
+}
+
+/// The name in a `[name]` section header line
+fn section_name(line: &str) -> Option<&str> {
+    let name = line.strip_prefix('[')?.strip_suffix(']')?.trim();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Parse `key=value` lines, skipping blank lines, `#` comments and lines
+/// without a key. Keys after a `[section]` header are read as
+/// `section.key`.
+pub fn parse_entries(content: &str) -> Vec<(String, ConfigValue)> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = section_name(line) {
+            section = format!("{}.", name);
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim();
+            if !key.is_empty() {
+                entries.push((format!("{}{}", section, key), parse_value(value.trim())));
+            }
+        }
+    }
+    entries
+}
+
+/// Write entries as `key=value` lines after `header`, which should be
+/// `#` comment lines, with profile keys last under their profile's
+/// `[profile.<name>]` header; `parse_entries` reads the result back
+pub fn format_entries<'a>(header: &str, entries: impl IntoIterator<Item = (&'a str, &'a ConfigValue)>) -> String {
+    let mut content = String::from(header);
+    content.push('\n');
+    let mut profiles = Vec::new();
+    for (key, value) in entries {
+        match split_profile_key(key) {
+            Some((name, key)) => profiles.push((name, key, value)),
+            None => content.push_str(&format!("{}={}\n", key, value.as_string())),
+        }
+    }
+    profiles.sort_by_key(|(name, _, _)| *name);
+    let mut section = None;
+    for (name, key, value) in profiles {
+        if section != Some(name) {
+            content.push_str(&format!("\n[{}{}]\n", PROFILE_PREFIX, name));
+            section = Some(name);
+        }
+        content.push_str(&format!("{}={}\n", key, value.as_string()));
     }
+    content
+}
+
+/// What a known key may be set to
+#[derive(Debug, Clone, Copy)]
+pub enum Rule {
+    Boolean,
+    /// A whole number from `min` to `max`
+    Integer { min: i64, max: i64 },
+    /// One of these strings
+    Choice(&'static [&'static str]),
+    /// Any string
+    Text,
+}
+
+impl Rule {
+    /// Whether `value` is of the right type and in range
+    pub fn allows(&self, value: &ConfigValue) -> bool {
+        match (self, value) {
+            (Rule::Boolean, ConfigValue::Boolean(_)) => true,
+            (Rule::Integer { min, max }, ConfigValue::Integer(i)) => (*min..=*max).contains(i),
+            (Rule::Choice(choices), ConfigValue::String(s)) => choices.contains(&s.as_str()),
+            (Rule::Text, ConfigValue::String(_)) => true,
+            _ => false,
+        }
+    }
+    
+    /// What the rule allows, to finish "must be ..."
+    pub fn describe(&self) -> String {
+        match self {
+            Rule::Boolean => String::from("true or false"),
+            Rule::Integer { min, max } => format!("a number from {} to {}", min, max),
+            Rule::Choice(choices) => format!("one of: {}", choices.join(", ")),
+            Rule::Text => String::from("text"),
+        }
+    }
+}
+
+/// Keys the kernel reads and what each may hold; others can be set but
+/// nothing checks them
+const RULES: &[(&str, Rule)] = &[
+    ("system.name", Rule::Text),
+    ("system.version", Rule::Text),
+    ("system.locale", Rule::Choice(crate::i18n::LOCALE_CODES)),
+    ("system.safe_mode", Rule::Boolean),
+    ("system.active_profile", Rule::Text),
+    ("system.config_backups", Rule::Integer { min: 0, max: MAX_BACKUPS as i64 }),
+    ("boot.verbose", Rule::Boolean),
+    ("boot.retry_failed_steps", Rule::Boolean),
+    ("boot.start_gui", Rule::Boolean),
+    ("ui.theme", Rule::Choice(&["default"])),
+    ("ui.color_scheme", Rule::Text),
+    ("ui.wallpaper", Rule::Text),
+    ("ui.wallpaper_mode", Rule::Choice(&["tile", "stretch"])),
+    ("ui.visual_bell", Rule::Boolean),
+    ("ui.screensaver_timeout", Rule::Integer { min: 0, max: 86400 }),
+    ("ui.screensaver", Rule::Choice(&["blank", "text"])),
+    ("gui.restore_session", Rule::Boolean),
+    ("gui.record_file", Rule::Text),
+    ("input.repeat_delay_ms", Rule::Integer { min: 250, max: 1000 }),
+    ("input.repeat_rate_cps", Rule::Integer { min: 2, max: 30 }),
+    ("input.mouse_sensitivity", Rule::Integer { min: 10, max: 1000 }),
+    ("input.mouse_accel", Rule::Integer { min: 0, max: 100 }),
+    ("shell.paste_executes", Rule::Boolean),
+    ("shell.history_size", Rule::Integer { min: 1, max: 1000 }),
+    ("shell.dir_stack_size", Rule::Integer { min: 1, max: 100 }),
+    ("fs.root_device", Rule::Text),
+    ("fs.automount", Rule::Boolean),
+    ("fs.tempfs_capacity", Rule::Integer { min: 1, max: i64::MAX }),
+    ("fs.check_on_mount", Rule::Boolean),
+    ("fs.max_open_files", Rule::Integer { min: 1, max: 4096 }),
+    ("fs.ram_fs", Rule::Choice(&["tempfs", "fat"])),
+    ("fs.ramdisk_size_kb", Rule::Integer { min: 1, max: i64::MAX }),
+    ("fs.readahead_blocks", Rule::Integer { min: 0, max: 127 }),
+    ("fs.use_trash", Rule::Boolean),
+    ("watchdog.timeout_secs", Rule::Integer { min: 1, max: 3600 }),
+    ("watchdog.action", Rule::Choice(&["log", "reboot"])),
+    ("idle.heartbeat_secs", Rule::Integer { min: 0, max: 86400 }),
+    ("idle.verbosity", Rule::Choice(&["quiet", "normal", "verbose"])),
+    ("log.level", Rule::Choice(&["debug", "info", "warning", "error", "critical"])),
+    ("log.serial_rate", Rule::Integer { min: 0, max: u32::MAX as i64 }),
+    ("log.serial_dedup", Rule::Boolean),
+    ("debug.strict_locks", Rule::Boolean),
+    ("debug.input_injection", Rule::Boolean),
+    ("network.enabled", Rule::Boolean),
+    ("network.dhcp", Rule::Boolean),
+    ("network.ip", Rule::Text),
+    ("network.netmask", Rule::Text),
+    ("network.gateway", Rule::Text),
+    ("user.auto_login", Rule::Boolean),
+    ("user.default", Rule::Text),
+    ("user.lock_on_idle", Rule::Boolean),
+];
+
+/// The rule for `key`, if it's one the kernel knows; a profile key has
+/// the rule of the key it sets
+pub fn rule(key: &str) -> Option<Rule> {
+    let key = split_profile_key(key).map_or(key, |(_, key)| key);
+    RULES.iter().find(|(known, _)| *known == key).map(|(_, rule)| *rule)
 }
 
+/// Callback run after a watched key changes; receives the key
+pub type ConfigListener = fn(&str);
+
 // Global configuration manager
 lazy_static! {
     static ref CONFIG: Mutex<ConfigManager> = Mutex::new(ConfigManager::new());
+    static ref LISTENERS: Mutex<Vec<(String, ConfigListener)>> = Mutex::new(Vec::new());
+}
+
+/// Call `listener` whenever a key starting with `prefix` is set
+pub fn subscribe(prefix: &str, listener: ConfigListener) {
+    LISTENERS.lock().push((prefix.to_string(), listener));
+}
+
+/// Run the listeners watching `key`, without holding any config lock so they
+/// can read the new value
+fn notify(key: &str) {
+    let listeners: Vec<ConfigListener> = LISTENERS.lock().iter()
+        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .map(|(_, listener)| *listener)
+        .collect();
+    for listener in listeners {
+        listener(key);
+    }
 }
 
 /// Initialize the configuration system
@@ -285,18 +798,196 @@ pub fn init() -> Result<(), KernelError> {
         serial_println!("Configuration loaded successfully from file.");
     }
     
+    // Settings on the command line hold for this boot only
+    for (key, value) in crate::cmdline::settings() {
+        serial_println!("Boot setting {}={}", key, value);
+        config.set_in(Layer::Runtime, &key, parse_value(&value));
+    }
+    
+    config.boot_profile = crate::cmdline::value("profile");
+    config.sync_profile();
+    if let Some(profile) = config.active_profile() {
+        serial_println!("Using configuration profile {}", profile);
+    }
+    
     serial_println!("Configuration system initialized.");
     Ok(())
 }
 
+/// Replace `layer` with `entries`, bring the profile layer up to date, and
+/// tell the listeners about the keys whose value changed
+fn replace_layer(layer: Layer, entries: Vec<(String, ConfigValue)>) -> usize {
+    let changed = {
+        let mut config = CONFIG.lock();
+        let mut changed = config.replace_layer(layer, entries);
+        changed.extend(config.sync_profile());
+        changed.sort();
+        changed.dedup();
+        changed
+    };
+    for key in &changed {
+        notify(key);
+    }
+    changed.len()
+}
+
+/// Load the saved settings over the current ones once the root file
+/// system is mounted, which `init` is too early for. Keys the file
+/// doesn't have keep their values; listeners hear about the ones that
+/// change.
+pub fn load_saved() -> Result<(), KernelError> {
+    let path = CONFIG.lock().config_file.clone();
+    let Some(entries) = read_entries_or_backup(&path)? else {
+        return Ok(());
+    };
+    let mut system = CONFIG.lock().layers[Layer::System as usize].clone();
+    system.extend(entries);
+    let changed = replace_layer(Layer::System, system.into_iter().collect());
+    serial_println!("Loaded saved configuration: {} settings changed", changed);
+    Ok(())
+}
+
+/// Switch the user layer to the preferences in `home`, or drop it with
+/// None when nobody is logged in. Listeners hear about every setting the
+/// switch changed, so the desktop follows the new user's theme.
+pub fn load_user(home: Option<&str>) -> Result<(), KernelError> {
+    let path = home.map(|home| format!("{}/{}", home, USER_CONFIG_FILE));
+    let entries = match &path {
+        Some(path) => read_entries_or_backup(path).unwrap_or_else(|e| {
+            serial_println!("Warning: Can't read {}: {:?}; no preferences loaded", path, e);
+            None
+        }),
+        None => None,
+    };
+    CONFIG.lock().user_file = path;
+    let changed = replace_layer(Layer::User, entries.unwrap_or_default());
+    serial_println!("Loaded user preferences: {} settings changed", changed);
+    Ok(())
+}
+
+/// Restore the system settings from backup `n` (1 is the newest) and load
+/// them over the defaults, telling listeners about every change. The file
+/// replaced becomes the newest backup, so a rollback can be undone by
+/// another. Returns the backup used and how many settings changed.
+pub fn rollback(n: usize) -> Result<(String, usize), KernelError> {
+    let path = CONFIG.lock().config_file.clone();
+    let backup = backup_path(&path, n);
+    let text = read_text(&backup)?.ok_or(KernelError::NotFound)?;
+    let entries = parse_file(&text)?;
+    
+    let depth = get(BACKUPS_KEY).and_then(|value| value.try_as_integer()).unwrap_or(DEFAULT_BACKUPS);
+    if let Err(e) = rotate_backups(&path, (depth.max(0) as usize).min(MAX_BACKUPS)) {
+        serial_println!("Warning: Not backing up {}: {:?}", path, e);
+    }
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    vfs.write_file_atomic(&path, text.as_bytes())?;
+    
+    let mut defaults = ConfigManager::new();
+    defaults.set_defaults();
+    let mut system = core::mem::take(&mut defaults.layers[Layer::System as usize]);
+    system.extend(entries);
+    let changed = replace_layer(Layer::System, system.into_iter().collect());
+    serial_println!("Rolled configuration back to {}: {} settings changed", backup, changed);
+    Ok((backup, changed))
+}
+
 /// Get a configuration value
 pub fn get(key: &str) -> Option<ConfigValue> {
     CONFIG.lock().get(key).cloned()
 }
 
-/// Set a configuration value
+/// The layer `key`'s value comes from
+pub fn layer_of(key: &str) -> Option<Layer> {
+    CONFIG.lock().layer_of(key)
+}
+
+/// Where `set` puts values
+pub fn default_layer() -> Layer {
+    CONFIG.lock().default_layer()
+}
+
+/// Tell the listeners about `key` and the keys a profile change moved
+fn notify_all(key: &str, changed: Vec<String>) {
+    notify(key);
+    for changed in changed.iter().filter(|changed| *changed != key) {
+        notify(changed);
+    }
+}
+
+/// Set a configuration value in the user layer while someone is logged
+/// in, else in the system layer
 pub fn set(key: &str, value: ConfigValue) {
-    CONFIG.lock().set(key, value);
+    let changed = {
+        let mut config = CONFIG.lock();
+        config.set(key, value);
+        config.sync_profile()
+    };
+    notify_all(key, changed);
+}
+
+/// Set a configuration value in `layer`. The user layer needs someone
+/// logged in, and the profile layer an active profile.
+pub fn set_in(layer: Layer, key: &str, value: ConfigValue) -> Result<(), KernelError> {
+    let changed = {
+        let mut config = CONFIG.lock();
+        if (layer == Layer::User && config.user_file.is_none()) || (layer == Layer::Profile && config.profile.is_none()) {
+            return Err(KernelError::NotInitialized);
+        }
+        config.set_in(layer, key, value);
+        config.sync_profile()
+    };
+    notify_all(key, changed);
+    Ok(())
+}
+
+/// Remove `key` from `layer`; it takes the value of the next layer down,
+/// if any has it
+pub fn unset_in(layer: Layer, key: &str) -> Result<(), KernelError> {
+    let changed = {
+        let mut config = CONFIG.lock();
+        config.remove_from(layer, key).ok_or(KernelError::NotFound)?;
+        config.sync_profile()
+    };
+    notify_all(key, changed);
+    Ok(())
+}
+
+/// The profiles the system settings define, with how many keys each sets
+pub fn profiles() -> Vec<(String, usize)> {
+    CONFIG.lock().profiles()
+}
+
+/// The profile in use
+pub fn active_profile() -> Option<String> {
+    CONFIG.lock().active_profile().map(String::from)
+}
+
+/// Make `name` the profile in use, or none with "". It's applied at once,
+/// so settings with listeners follow it now and the rest at the next boot,
+/// unless the boot command line picked a profile for this boot. Returns
+/// how many settings changed; NotFound if there's no such profile.
+pub fn switch_profile(name: &str) -> Result<usize, KernelError> {
+    let changed = {
+        let mut config = CONFIG.lock();
+        if !name.is_empty() && !config.profiles().iter().any(|(known, _)| known == name) {
+            return Err(KernelError::NotFound);
+        }
+        config.set_in(Layer::System, ACTIVE_PROFILE_KEY, ConfigValue::string(name));
+        config.sync_profile()
+    };
+    let count = changed.len();
+    notify_all(ACTIVE_PROFILE_KEY, changed);
+    Ok(count)
+}
+
+/// Keys starting with `prefix`, their values and the layer each value
+/// comes from, sorted by key
+pub fn entries(prefix: &str) -> Vec<(String, ConfigValue, Layer)> {
+    let config = CONFIG.lock();
+    config.values().into_iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .filter_map(|(key, value)| Some((key.clone(), value.clone(), config.layer_of(key)?)))
+        .collect()
 }
 
 /// Save configuration changes
@@ -312,4 +1003,216 @@ pub fn is_boot_option_enabled(option: &str) -> bool {
 /// Set a boot option
 pub fn set_boot_option(option: &str, enabled: bool) {
     CONFIG.lock().set_boot_option(option, enabled);
-} 
\ No newline at end of file
+}
+
+/// Check that the defaults keep to the rules and that values parse and
+/// are checked as `config set` does
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("CONFIG: Running self-test");
+    
+    let mut defaults = ConfigManager::new();
+    defaults.set_defaults();
+    for (key, value) in defaults.values() {
+        if rule(key).is_some_and(|rule| !rule.allows(value)) {
+            serial_println!("CONFIG: Default {}={} breaks its rule", key, value.as_string());
+            return Err(KernelError::ValidationError("A default config value breaks its rule"));
+        }
+    }
+    if let Some((key, _)) = RULES.iter().find(|(key, _)| defaults.get(key).is_none()) {
+        serial_println!("CONFIG: {} has a rule but no default", key);
+        return Err(KernelError::ValidationError("A config rule has no default"));
+    }
+    
+    let theme = rule("ui.theme").ok_or(KernelError::ValidationError("ui.theme has no rule"))?;
+    let delay = rule("input.repeat_delay_ms").ok_or(KernelError::ValidationError("Key delay has no rule"))?;
+    if theme.allows(&parse_value("nonexistent")) || !delay.allows(&parse_value("250"))
+        || delay.allows(&parse_value("100")) || delay.allows(&parse_value("fast"))
+        || !rule("fs.automount").is_some_and(|rule| rule.allows(&parse_value("FALSE")))
+        || rule("no.such.key").is_some() || theme.describe() != "one of: default" {
+        return Err(KernelError::ValidationError("Config values checked wrongly"));
+    }
+    
+    layer_self_test()?;
+    profile_self_test()?;
+    
+    serial_println!("CONFIG: Self-test passed");
+    Ok(())
+}
+
+/// Check a key set in several layers resolves to the highest one, and that
+/// each layer saves only its own keys
+fn layer_self_test() -> Result<(), KernelError> {
+    let mut config = ConfigManager::new();
+    config.set_defaults();
+    if config.default_layer() != Layer::System {
+        return Err(KernelError::ValidationError("Config set goes to the user layer with nobody logged in"));
+    }
+    config.user_file = Some(format!("/root/{}", USER_CONFIG_FILE));
+    if config.default_layer() != Layer::User {
+        return Err(KernelError::ValidationError("Config set skips the user layer"));
+    }
+    
+    let key = "ui.wallpaper";
+    let layered = |config: &ConfigManager| (config.get(key).map(ConfigValue::as_string), config.layer_of(key));
+    let system = (Some(String::from("blue")), Some(Layer::System));
+    let user = (Some(String::from("green")), Some(Layer::User));
+    let runtime = (Some(String::from("red")), Some(Layer::Runtime));
+    config.set_in(Layer::Runtime, key, ConfigValue::string("red"));
+    config.set(key, ConfigValue::string("green"));
+    if layered(&config) != runtime {
+        return Err(KernelError::ValidationError("Boot override lost to a lower layer"));
+    }
+    config.remove_from(Layer::Runtime, key);
+    if layered(&config) != user {
+        return Err(KernelError::ValidationError("User preference lost to the system value"));
+    }
+    config.remove_from(Layer::User, key);
+    if layered(&config) != system || config.get_in(Layer::User, key).is_some() {
+        return Err(KernelError::ValidationError("System value not uncovered"));
+    }
+    
+    // Changes report only keys whose resolved value moved
+    config.set_in(Layer::Runtime, "ui.theme", ConfigValue::string("default"));
+    let changed = config.replace_layer(Layer::User, alloc::vec![
+        (String::from(key), ConfigValue::string("green")),
+        (String::from("ui.theme"), ConfigValue::string("dark")),
+    ]);
+    if changed != [key] {
+        return Err(KernelError::ValidationError("Layer switch reported the wrong changes"));
+    }
+    
+    let user_text = config.layer_text(Layer::User);
+    let saved: Vec<String> = parse_entries(&user_text).into_iter().map(|(key, _)| key).collect();
+    if saved != ["ui.theme", "ui.wallpaper"] {
+        return Err(KernelError::ValidationError("User layer saves more than its own keys"));
+    }
+    if Layer::from_name("user") != Some(Layer::User) || Layer::from_name("nope").is_some() {
+        return Err(KernelError::ValidationError("Layer names parsed wrongly"));
+    }
+    Ok(())
+}
+
+/// Check a key set in the system layer and a profile resolves to the
+/// profile's value only while that profile is active, under the user and
+/// boot layers, and that profiles survive a save and load
+fn profile_self_test() -> Result<(), KernelError> {
+    let mut config = ConfigManager::new();
+    config.set_defaults();
+    config.sync_profile();
+    if config.active_profile().is_some() || !config.profiles().iter().any(|(name, _)| name == "debug") {
+        return Err(KernelError::ValidationError("Default profiles set up wrongly"));
+    }
+    
+    let key = "ui.wallpaper";
+    let layered = |config: &ConfigManager| (config.get(key).map(ConfigValue::as_string), config.layer_of(key));
+    let base = (Some(String::from("blue")), Some(Layer::System));
+    let profiled = (Some(String::from("green")), Some(Layer::Profile));
+    config.set_in(Layer::System, "profile.test.ui.wallpaper", ConfigValue::string("green"));
+    config.set_in(Layer::System, "profile.other.ui.theme", ConfigValue::string("default"));
+    config.sync_profile();
+    if layered(&config) != base {
+        return Err(KernelError::ValidationError("Inactive profile's value used"));
+    }
+    config.set_in(Layer::System, ACTIVE_PROFILE_KEY, ConfigValue::string("other"));
+    config.sync_profile();
+    if layered(&config) != base || config.active_profile() != Some("other") {
+        return Err(KernelError::ValidationError("Another profile's value used"));
+    }
+    config.set_in(Layer::System, ACTIVE_PROFILE_KEY, ConfigValue::string("test"));
+    let changed = config.sync_profile();
+    if layered(&config) != profiled || !changed.iter().any(|changed| changed == key) {
+        return Err(KernelError::ValidationError("Active profile's value not used"));
+    }
+    
+    // The user's preference and a boot override still win
+    config.user_file = Some(format!("/root/{}", USER_CONFIG_FILE));
+    config.set(key, ConfigValue::string("red"));
+    if layered(&config).1 != Some(Layer::User) {
+        return Err(KernelError::ValidationError("Profile beat the user's preference"));
+    }
+    config.remove_from(Layer::User, key);
+    config.boot_profile = Some(String::from("other"));
+    config.sync_profile();
+    if layered(&config) != base {
+        return Err(KernelError::ValidationError("Boot parameter didn't pick the profile"));
+    }
+    config.boot_profile = None;
+    config.sync_profile();
+    
+    // Set in the profile layer, kept in the profile's section
+    config.set_in(Layer::Profile, key, ConfigValue::string("black"));
+    config.sync_profile();
+    if config.get(key).map(ConfigValue::as_string).as_deref() != Some("black")
+        || config.get_in(Layer::System, "profile.test.ui.wallpaper").is_none() {
+        return Err(KernelError::ValidationError("Profile layer value not kept in its section"));
+    }
+    
+    let text = config.layer_text(Layer::System);
+    let saved: BTreeMap<String, ConfigValue> = parse_file(&text)?.into_iter().collect();
+    if !text.contains("\n[profile.test]\nui.wallpaper=black\n") || saved != config.layers[Layer::System as usize]
+        || rule("profile.test.ui.theme").is_none() {
+        return Err(KernelError::ValidationError("Profiles saved or read back wrongly"));
+    }
+    Ok(())
+}
+
+/// Check saves keep and rotate backups, and that a corrupt file is passed
+/// over for its newest good backup with the rest of the settings intact
+pub fn backup_self_test() -> Result<(), KernelError> {
+    serial_println!("CONFIG: Running backup self-test");
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let path = "/tmp/config-selftest.ini";
+    
+    let wallpaper_in = |path: &str| read_entries(path).ok().flatten()
+        .and_then(|entries| entries.into_iter().find(|(key, _)| key == "ui.wallpaper"))
+        .map(|(_, value)| value.as_string());
+    let result = (|| {
+        let mut config = ConfigManager::new();
+        config.config_file = path.to_string();
+        config.set_defaults();
+        config.set_in(Layer::System, BACKUPS_KEY, ConfigValue::integer(2));
+        for wallpaper in ["first", "second", "third"] {
+            config.set_in(Layer::System, "ui.wallpaper", ConfigValue::string(wallpaper));
+            config.save()?;
+        }
+        // Two kept: the second save's file, then the first's
+        if wallpaper_in(&backup_path(path, 1)).as_deref() != Some("second")
+            || wallpaper_in(&backup_path(path, 2)).as_deref() != Some("first")
+            || vfs.metadata(&backup_path(path, 3)).is_ok() {
+            return Err(KernelError::ValidationError("Config backups rotated wrongly"));
+        }
+        
+        // A save whose write fails keeps the file it was replacing, and
+        // leaves no temporary file behind
+        if crate::faultinject::enabled() {
+            config.set_in(Layer::System, BACKUPS_KEY, ConfigValue::integer(0));
+            config.set_in(Layer::System, "ui.wallpaper", ConfigValue::string("fourth"));
+            crate::faultinject::arm(crate::faultinject::TEMPFS_WRITE.name(), Default::default())?;
+            let saved = config.save();
+            crate::faultinject::TEMPFS_WRITE.disarm();
+            if saved.is_ok() || wallpaper_in(path).as_deref() != Some("third")
+                || vfs.metadata(&format!("{}.tmp", path)).is_ok() {
+                return Err(KernelError::ValidationError("Failed config save didn't roll back"));
+            }
+        }
+        
+        // A torn write leaves junk behind the last good line
+        vfs.write_file_atomic(path, b"ui.wallpaper=third\n\0\0\0\0")?;
+        let mut loaded = ConfigManager::new();
+        loaded.config_file = path.to_string();
+        loaded.load()?;
+        if loaded.get("ui.wallpaper").map(ConfigValue::as_string).as_deref() != Some("second")
+            || loaded.get("system.name").is_none() {
+            return Err(KernelError::ValidationError("Corrupt config file not replaced by its backup"));
+        }
+        Ok(())
+    })();
+    for n in 0..=3 {
+        let file = if n == 0 { path.to_string() } else { backup_path(path, n) };
+        let _ = vfs.remove_permanently(&file);
+    }
+    result?;
+    
+    serial_println!("CONFIG: Backup self-test passed");
+    Ok(())
+}
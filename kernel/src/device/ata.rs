@@ -4,7 +4,9 @@ use alloc::format;
 use alloc::string::ToString;
 use crate::errors::{KernelError, DeviceError};
 use crate::device::{Device, DeviceType, DeviceStatus};
-use core::sync::atomic::{AtomicU64, Ordering};
+use crate::device::iostats::{self, IoLayer, IoStats};
+use crate::faultinject;
+use alloc::sync::Arc;
 use x86_64::instructions::port::{Port, PortWriteOnly, PortReadOnly};
 
 /// PIO-based ATA driver for IDE disks
@@ -33,6 +35,9 @@ pub struct AtaDevice {
     
     // Flags for driver state
     initialized: bool,
+    
+    /// Transfer counters (see `device::io_stats`)
+    stats: Arc<IoStats>,
 }
 
 // ATA controller port addresses
@@ -50,6 +55,7 @@ const ATA_PRIMARY_CONTROL: u16 = 0x3F6;
 const ATA_CMD_READ_SECTORS: u8 = 0x20;
 const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
 const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_CMD_FLUSH_CACHE: u8 = 0xE7;
 
 // ATA status register bits
 const ATA_SR_BSY: u8 = 0x80; // Busy
@@ -77,8 +83,7 @@ const DEFAULT_SECTOR_SIZE: usize = 512;
 impl AtaDevice {
     /// Create a new ATA device for the primary channel, master drive
     pub fn new() -> Self {
-        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
-        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let id = crate::device::generate_device_id();
         
         AtaDevice {
             id,
@@ -99,6 +104,7 @@ impl AtaDevice {
             sector_size: DEFAULT_SECTOR_SIZE,
             sector_count: 0,
             initialized: false,
+            stats: iostats::register(id, "ata0-master", IoLayer::Device, DEFAULT_SECTOR_SIZE),
         }
     }
     
@@ -165,7 +171,8 @@ impl AtaDevice {
         // Wait for the BSY flag to clear
         self.wait_not_busy()?;
         
-        // Check if disk is ATA
+        // Check if disk is ATA. Packet devices (CD-ROMs) are left to the
+        // ATAPI driver.
         let lba_mid = unsafe { self.lba_mid_port.read() };
         let lba_high = unsafe { self.lba_high_port.read() };
         if lba_mid != 0 || lba_high != 0 {
@@ -228,6 +235,14 @@ impl AtaDevice {
     
     /// Read sectors from the disk using LBA28 addressing
     pub fn read_sectors(&mut self, lba: u32, count: u8, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let stats = self.stats.clone();
+        stats.read(u64::from(count), || {
+            faultinject::ATA_READ.check()?;
+            self.pio_read_sectors(lba, count, buffer)
+        })
+    }
+    
+    fn pio_read_sectors(&mut self, lba: u32, count: u8, buffer: &mut [u8]) -> Result<(), KernelError> {
         if !self.initialized {
             return Err(KernelError::DeviceNotInitialized);
         }
@@ -282,6 +297,14 @@ impl AtaDevice {
     
     /// Write sectors to the disk using LBA28 addressing
     pub fn write_sectors(&mut self, lba: u32, count: u8, buffer: &[u8]) -> Result<(), KernelError> {
+        let stats = self.stats.clone();
+        stats.write(u64::from(count), || {
+            faultinject::ATA_WRITE.check()?;
+            self.pio_write_sectors(lba, count, buffer)
+        })
+    }
+    
+    fn pio_write_sectors(&mut self, lba: u32, count: u8, buffer: &[u8]) -> Result<(), KernelError> {
         if !self.initialized {
             return Err(KernelError::DeviceNotInitialized);
         }
@@ -372,6 +395,10 @@ impl Device for AtaDevice {
         self.status
     }
     
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.status = status;
+    }
+    
     fn initialize(&mut self) -> Result<(), KernelError> {
         // Check if the device is present
         if !self.is_present() {
@@ -418,7 +445,8 @@ impl Device for AtaDevice {
     }
     
     fn suspend(&mut self) -> Result<(), KernelError> {
-        // Nothing to do for now
+        // Get the drive's write cache onto the platters first
+        crate::device::BlockDevice::flush(self)?;
         self.status = DeviceStatus::Suspended;
         Ok(())
     }
@@ -482,6 +510,7 @@ impl crate::device::BlockDevice for AtaDevice {
             sector_size: self.sector_size,
             sector_count: self.sector_count,
             initialized: self.initialized,
+            stats: self.stats.clone(),
         };
         
         mutable_self.read_sectors(block_id as u32, 1, buffer)
@@ -492,7 +521,20 @@ impl crate::device::BlockDevice for AtaDevice {
     }
     
     fn flush(&mut self) -> Result<(), KernelError> {
-        // ATA flush cache command could be implemented here
+        if !self.initialized {
+            return Ok(());
+        }
+        self.select_drive();
+        self.wait_not_busy()?;
+        unsafe {
+            self.command_port.write(ATA_CMD_FLUSH_CACHE);
+        }
+        self.wait_not_busy()?;
+        
+        let status = unsafe { self.command_port.read() };
+        if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+            return Err(KernelError::WriteError);
+        }
         Ok(())
     }
 }
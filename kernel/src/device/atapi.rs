@@ -0,0 +1,421 @@
+// kernel/src/device/atapi.rs
+//! PIO driver for ATAPI CD-ROM drives, such as QEMU's `-cdrom`, which it
+//! attaches to the secondary channel.
+//!
+//! ATAPI drives answer ATA IDENTIFY with the signature 0x14/0xEB in the LBA
+//! mid/high registers and take SCSI commands through PACKET instead of
+//! ATA's read and write commands. Only READ CAPACITY and READ(12) are used.
+//! Sectors are 2048 bytes, and the drive is registered as a read-only
+//! block device.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::errors::{DeviceError, KernelError};
+use crate::device::{Device, DeviceStatus, DeviceType};
+use crate::device::iostats::{self, IoLayer, IoStats};
+use crate::serial_println;
+use x86_64::instructions::port::Port;
+
+// Register offsets from a channel's I/O base
+const REG_DATA: u16 = 0;
+const REG_FEATURES: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DEVICE: u16 = 6;
+const REG_COMMAND: u16 = 7;
+
+/// (I/O base, control port) of the primary and secondary channels
+pub const CHANNELS: [(u16, u16); 2] = [(0x1F0, 0x3F6), (0x170, 0x376)];
+
+// Commands
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_CMD_PACKET: u8 = 0xA0;
+const ATA_CMD_IDENTIFY_PACKET: u8 = 0xA1;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xA8;
+
+// Status register bits
+const STATUS_BSY: u8 = 0x80;
+const STATUS_DF: u8 = 0x20;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_ERR: u8 = 0x01;
+
+/// LBA mid/high after IDENTIFY on a packet device
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+
+/// CD-ROM sector size
+pub const SECTOR_SIZE: usize = 2048;
+
+/// Status polls before giving up; drives spinning up are slow to answer
+const POLL_LIMIT: usize = 1_000_000;
+
+/// An ATAPI drive on one of the two legacy IDE channels
+pub struct AtapiDevice {
+    id: u64,
+    name: String,
+    status: DeviceStatus,
+    base: u16,
+    control: u16,
+    slave: bool,
+    /// Sectors on the disc in the drive; 0 when there is none
+    sector_count: u64,
+    initialized: bool,
+    /// Transfer counters (see `device::io_stats`)
+    stats: Arc<IoStats>,
+}
+
+impl AtapiDevice {
+    /// A drive at `base`/`control`, master or slave
+    pub fn new(base: u16, control: u16, slave: bool) -> Self {
+        let channel = if base == CHANNELS[0].0 { 0 } else { 1 };
+        let id = crate::device::generate_device_id();
+        let name = format!("atapi{}-{}", channel, if slave { "slave" } else { "master" });
+        AtapiDevice {
+            id,
+            stats: iostats::register(id, &name, IoLayer::Device, SECTOR_SIZE),
+            name,
+            status: DeviceStatus::Uninitialized,
+            base,
+            control,
+            slave,
+            sector_count: 0,
+            initialized: false,
+        }
+    }
+
+    fn inb(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + register).read() }
+    }
+
+    fn outb(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + register).write(value) }
+    }
+
+    fn inw(&self) -> u16 {
+        unsafe { Port::<u16>::new(self.base + REG_DATA).read() }
+    }
+
+    fn outw(&self, value: u16) {
+        unsafe { Port::<u16>::new(self.base + REG_DATA).write(value) }
+    }
+
+    /// The alternate status register, which reads without side effects
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    /// Select the drive and give it the 400ns it needs to respond
+    fn select(&self) {
+        self.outb(REG_DEVICE, 0xA0 | if self.slave { 0x10 } else { 0 });
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, KernelError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(KernelError::DeviceTimeout)
+    }
+
+    fn wait_drq(&self) -> Result<(), KernelError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(KernelError::DeviceError(DeviceError::InvalidOperation));
+            }
+            if status & STATUS_BSY == 0 && status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(KernelError::DeviceTimeout)
+    }
+
+    /// Whether a packet device answers at this position. ATA disks found
+    /// here have their IDENTIFY data drained and are left alone.
+    pub fn is_present(&self) -> bool {
+        self.select();
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            self.outb(register, 0);
+        }
+        self.outb(REG_COMMAND, ATA_CMD_IDENTIFY);
+        let status = self.alt_status();
+        if status == 0 || status == 0xFF || self.wait_not_busy().is_err() {
+            return false;
+        }
+
+        let signature = (self.inb(REG_LBA_MID), self.inb(REG_LBA_HIGH));
+        if signature == ATAPI_SIGNATURE {
+            return true;
+        }
+        if signature == (0, 0) && self.alt_status() & STATUS_DRQ != 0 {
+            for _ in 0..256 {
+                self.inw();
+            }
+        }
+        false
+    }
+
+    /// Read the drive's IDENTIFY PACKET DEVICE data
+    fn identify(&self) -> Result<Vec<u16>, KernelError> {
+        self.select();
+        self.outb(REG_COMMAND, ATA_CMD_IDENTIFY_PACKET);
+        self.wait_drq()?;
+        Ok((0..256).map(|_| self.inw()).collect())
+    }
+
+    /// Send a 12-byte SCSI command and read whatever data it returns into
+    /// `buffer`, returning the number of bytes transferred. Data past the
+    /// end of `buffer` is read and dropped.
+    fn packet(&self, command: &[u8; 12], buffer: &mut [u8]) -> Result<usize, KernelError> {
+        self.select();
+        self.wait_not_busy()?;
+        // PIO, with at most one sector per data request
+        self.outb(REG_FEATURES, 0);
+        self.outb(REG_LBA_MID, (SECTOR_SIZE & 0xFF) as u8);
+        self.outb(REG_LBA_HIGH, (SECTOR_SIZE >> 8) as u8);
+        self.outb(REG_COMMAND, ATA_CMD_PACKET);
+        self.wait_drq()?;
+        for pair in command.chunks(2) {
+            self.outw(u16::from(pair[0]) | u16::from(pair[1]) << 8);
+        }
+
+        let mut transferred = 0;
+        loop {
+            // The drive sets BSY while it works on the command
+            for _ in 0..4 {
+                self.alt_status();
+            }
+            let status = self.wait_not_busy()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                // The sense key is the top nibble of the error register
+                serial_println!("DEBUG: {}: command {:#04x} failed, sense key {:#x}",
+                    self.name, command[0], self.inb(REG_FEATURES) >> 4);
+                return Err(KernelError::ReadError);
+            }
+            if status & STATUS_DRQ == 0 {
+                return Ok(transferred);
+            }
+            let length = usize::from(self.inb(REG_LBA_MID)) | usize::from(self.inb(REG_LBA_HIGH)) << 8;
+            for _ in 0..length.div_ceil(2) {
+                let word = self.inw().to_le_bytes();
+                for byte in word {
+                    if transferred < buffer.len() {
+                        buffer[transferred] = byte;
+                    }
+                    transferred += 1;
+                }
+            }
+        }
+    }
+
+    /// Ask the drive for the size of the disc: (sectors, sector size)
+    fn read_capacity(&self) -> Result<(u64, usize), KernelError> {
+        let mut command = [0u8; 12];
+        command[0] = SCSI_READ_CAPACITY;
+        let mut reply = [0u8; 8];
+        // The first command after a disc change fails with UNIT ATTENTION
+        let mut result = self.packet(&command, &mut reply);
+        if result.is_err() {
+            result = self.packet(&command, &mut reply);
+        }
+        if result? < reply.len() {
+            return Err(KernelError::InvalidData);
+        }
+        let last_lba = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+        let block_size = u32::from_be_bytes([reply[4], reply[5], reply[6], reply[7]]);
+        Ok((u64::from(last_lba) + 1, block_size as usize))
+    }
+
+    /// Read `count` 2048-byte sectors starting at `lba`
+    pub fn read_sectors(&self, lba: u32, count: u16, buffer: &mut [u8]) -> Result<(), KernelError> {
+        if !self.initialized {
+            return Err(KernelError::DeviceNotInitialized);
+        }
+        if u64::from(lba) + u64::from(count) > self.sector_count {
+            return Err(KernelError::InvalidParameter);
+        }
+        let length = usize::from(count) * SECTOR_SIZE;
+        if buffer.len() < length {
+            return Err(KernelError::BufferTooSmall);
+        }
+
+        let mut command = [0u8; 12];
+        command[0] = SCSI_READ_12;
+        command[2..6].copy_from_slice(&lba.to_be_bytes());
+        command[6..10].copy_from_slice(&u32::from(count).to_be_bytes());
+        self.stats.read(u64::from(count), || {
+            if self.packet(&command, &mut buffer[..length])? < length {
+                return Err(KernelError::ReadError);
+            }
+            Ok(())
+        })
+    }
+
+    /// Look up the size of the disc again, e.g. after it was changed
+    pub fn refresh_capacity(&mut self) -> Result<(), KernelError> {
+        match self.read_capacity() {
+            Ok((sectors, SECTOR_SIZE)) => {
+                self.sector_count = sectors;
+                Ok(())
+            }
+            Ok((_, size)) => {
+                serial_println!("DEBUG: {}: unsupported sector size {}", self.name, size);
+                self.sector_count = 0;
+                Err(KernelError::UnsupportedFeature)
+            }
+            Err(e) => {
+                // Most likely no disc in the drive
+                self.sector_count = 0;
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether there is a readable disc in the drive
+    pub fn has_media(&self) -> bool {
+        self.sector_count > 0
+    }
+}
+
+/// Look for packet devices at every IDE position. The primary master is
+/// only tried if `skip_primary_master` is false, so a disk there is left to
+/// the ATA driver.
+pub fn probe(skip_primary_master: bool) -> Vec<AtapiDevice> {
+    let mut found = Vec::new();
+    for (base, control) in CHANNELS {
+        for slave in [false, true] {
+            if skip_primary_master && base == CHANNELS[0].0 && !slave {
+                continue;
+            }
+            let mut device = AtapiDevice::new(base, control, slave);
+            if !device.is_present() {
+                continue;
+            }
+            match device.initialize() {
+                Ok(()) => serial_println!("DEBUG: {}", device.debug_info()),
+                Err(e) => serial_println!("DEBUG: Failed to initialize {}: {:?}", device.name, e),
+            }
+            found.push(device);
+        }
+    }
+    found
+}
+
+impl Device for AtapiDevice {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.status = status;
+    }
+
+    fn initialize(&mut self) -> Result<(), KernelError> {
+        let data = match self.identify() {
+            Ok(data) => data,
+            Err(e) => {
+                self.status = DeviceStatus::Error;
+                return Err(e);
+            }
+        };
+
+        // Model string, words 27-46, bytes swapped within each word
+        let model: String = data[27..47].iter()
+            .flat_map(|word| [(word >> 8) as u8 as char, (word & 0xFF) as u8 as char])
+            .collect();
+        self.name = format!("{} ({})", self.name, model.trim());
+        self.initialized = true;
+        self.status = DeviceStatus::Initialized;
+
+        // An empty drive is still a drive
+        if let Err(e) = self.refresh_capacity() {
+            serial_println!("DEBUG: {}: no readable disc ({:?})", self.name, e);
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), KernelError> {
+        self.initialize()
+    }
+
+    fn suspend(&mut self) -> Result<(), KernelError> {
+        // Nothing is ever written, so there is nothing to flush
+        self.status = DeviceStatus::Suspended;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), KernelError> {
+        // The disc may have been changed meanwhile
+        let _ = self.refresh_capacity();
+        self.status = DeviceStatus::Initialized;
+        Ok(())
+    }
+
+    fn debug_info(&self) -> String {
+        format!(
+            "ATAPI Drive: {} (ID: {})\n\
+             Status: {:?}\n\
+             Sector Size: {} bytes\n\
+             Sector Count: {}\n\
+             Capacity: {} MB",
+            self.name, self.id, self.status, SECTOR_SIZE, self.sector_count,
+            (self.sector_count * SECTOR_SIZE as u64) / (1024 * 1024)
+        )
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn core::any::Any {
+        self
+    }
+}
+
+impl crate::device::BlockDevice for AtapiDevice {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> usize {
+        self.sector_count as usize
+    }
+
+    fn read_block(&self, block_id: usize, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let lba = u32::try_from(block_id).map_err(|_| KernelError::InvalidParameter)?;
+        self.read_sectors(lba, 1, buffer)
+    }
+
+    fn write_block(&mut self, _block_id: usize, _buffer: &[u8]) -> Result<(), KernelError> {
+        Err(KernelError::UnsupportedFeature)
+    }
+
+    fn flush(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,226 @@
+//! Per-device I/O counters
+//!
+//! Every block device keeps an `IoStats` and runs its transfers through
+//! it. The counters are plain atomics, so counting costs no lock, and the
+//! registry only holds weak references: a device that goes away drops out
+//! of `all()` by itself. The cache in front of a disk registers a second
+//! entry of its own (`IoLayer::Cache`) counting the requests made of it,
+//! so comparing the two shows how much the cache saved.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::fs::block_device::BlockDevice;
+use crate::fs::ramdisk::RamDisk;
+use crate::serial_println;
+
+/// What a registered set of counters sits in front of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoLayer {
+    /// The device itself: every operation is a real transfer
+    Device,
+    /// A block cache: operations are requests, many answered from memory
+    Cache,
+}
+
+/// Counters of one device, updated as it transfers
+#[derive(Debug, Default)]
+pub struct IoStats {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    blocks_read: AtomicU64,
+    blocks_written: AtomicU64,
+    errors: AtomicU64,
+    busy_ns: AtomicU64,
+}
+
+/// A snapshot of an `IoStats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoCounters {
+    pub reads: u64,
+    pub writes: u64,
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    /// Operations that failed; their blocks aren't counted
+    pub errors: u64,
+    /// Time spent in operations, in nanoseconds
+    pub busy_ns: u64,
+}
+
+impl IoCounters {
+    /// What changed since `earlier`
+    pub fn since(&self, earlier: &IoCounters) -> IoCounters {
+        IoCounters {
+            reads: self.reads.saturating_sub(earlier.reads),
+            writes: self.writes.saturating_sub(earlier.writes),
+            blocks_read: self.blocks_read.saturating_sub(earlier.blocks_read),
+            blocks_written: self.blocks_written.saturating_sub(earlier.blocks_written),
+            errors: self.errors.saturating_sub(earlier.errors),
+            busy_ns: self.busy_ns.saturating_sub(earlier.busy_ns),
+        }
+    }
+
+    /// Add another device's counters to these
+    pub fn add(&mut self, other: &IoCounters) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.blocks_read += other.blocks_read;
+        self.blocks_written += other.blocks_written;
+        self.errors += other.errors;
+        self.busy_ns += other.busy_ns;
+    }
+}
+
+impl IoStats {
+    /// Run `op`, a read of `blocks` blocks, and count it
+    pub fn read<T, E>(&self, blocks: u64, op: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        self.count(&self.reads, &self.blocks_read, blocks, op)
+    }
+
+    /// Run `op`, a write of `blocks` blocks, and count it
+    pub fn write<T, E>(&self, blocks: u64, op: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        self.count(&self.writes, &self.blocks_written, blocks, op)
+    }
+
+    fn count<T, E>(&self, ops: &AtomicU64, done: &AtomicU64, blocks: u64,
+                   op: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let start = crate::time::monotonic_ns();
+        let result = op();
+        self.busy_ns.fetch_add(crate::time::monotonic_ns().saturating_sub(start), Ordering::Relaxed);
+        ops.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(_) => done.fetch_add(blocks, Ordering::Relaxed),
+            Err(_) => self.errors.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    pub fn counters(&self) -> IoCounters {
+        IoCounters {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            blocks_read: self.blocks_read.load(Ordering::Relaxed),
+            blocks_written: self.blocks_written.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            busy_ns: self.busy_ns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A registered device's counters, as listed by `all`
+#[derive(Debug, Clone)]
+pub struct DeviceIo {
+    pub id: u64,
+    pub name: String,
+    pub layer: IoLayer,
+    pub block_size: usize,
+    pub counters: IoCounters,
+}
+
+struct Entry {
+    id: u64,
+    name: String,
+    layer: IoLayer,
+    block_size: usize,
+    stats: Weak<IoStats>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+}
+
+/// Give device `id` a fresh set of counters, listed for as long as the
+/// returned `Arc` is kept
+pub fn register(id: u64, name: &str, layer: IoLayer, block_size: usize) -> Arc<IoStats> {
+    let stats = Arc::new(IoStats::default());
+    let mut registry = REGISTRY.lock();
+    registry.retain(|entry| entry.stats.strong_count() > 0);
+    registry.push(Entry { id, name: String::from(name), layer, block_size, stats: Arc::downgrade(&stats) });
+    stats
+}
+
+/// Every live device's counters, in the order they were registered
+pub fn all() -> Vec<DeviceIo> {
+    REGISTRY.lock().iter().filter_map(|entry| {
+        entry.stats.upgrade().map(|stats| DeviceIo {
+            id: entry.id,
+            name: entry.name.clone(),
+            layer: entry.layer,
+            block_size: entry.block_size,
+            counters: stats.counters(),
+        })
+    }).collect()
+}
+
+/// Counters of device `id`, if it has any
+pub fn get(id: u64) -> Option<IoCounters> {
+    REGISTRY.lock().iter()
+        .find(|entry| entry.id == id)
+        .and_then(|entry| entry.stats.upgrade())
+        .map(|stats| stats.counters())
+}
+
+/// Summed counters of every real device, leaving out caches
+pub fn device_totals() -> IoCounters {
+    let mut total = IoCounters::default();
+    for device in all().iter().filter(|device| device.layer == IoLayer::Device) {
+        total.add(&device.counters);
+    }
+    total
+}
+
+/// /proc/diskstats: one line per device, `id name layer reads blocks_read
+/// writes blocks_written errors busy_ms`
+pub fn diskstats_text() -> String {
+    let mut text = String::new();
+    for device in all() {
+        let counters = device.counters;
+        text.push_str(&format!("{:>3} {:<24} {:<6} {} {} {} {} {} {}\n",
+            device.id, device.name,
+            match device.layer { IoLayer::Device => "device", IoLayer::Cache => "cache" },
+            counters.reads, counters.blocks_read, counters.writes, counters.blocks_written,
+            counters.errors, counters.busy_ns / 1_000_000));
+    }
+    text
+}
+
+/// Check a RamDisk's transfers and failures are counted and that its
+/// counters leave the registry with it
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("IOSTATS: Running self-test");
+
+    let mut disk = RamDisk::with_capacity(8, 512).map_err(KernelError::GenericError)?;
+    let id = disk.id();
+    let mut buffer = [0u8; 512];
+    for block in 0..3 {
+        disk.write_block(block, &[0x5Au8; 512]).map_err(KernelError::GenericError)?;
+    }
+    disk.read_block(1, &mut buffer).map_err(KernelError::GenericError)?;
+    let _ = disk.read_block(8, &mut buffer);
+
+    let counters = get(id).ok_or(KernelError::ValidationError("RamDisk has no I/O counters"))?;
+    let expected = IoCounters { reads: 2, writes: 3, blocks_read: 1, blocks_written: 3, errors: 1, ..counters };
+    if counters != expected {
+        serial_println!("IOSTATS: Counted {:?}", counters);
+        return Err(KernelError::ValidationError("RamDisk transfers were miscounted"));
+    }
+    if counters.since(&counters) != (IoCounters::default()) {
+        return Err(KernelError::ValidationError("Counters differ from themselves"));
+    }
+    if !diskstats_text().lines().any(|line| line.split_whitespace().next() == Some(format!("{}", id).as_str())) {
+        return Err(KernelError::ValidationError("RamDisk missing from /proc/diskstats"));
+    }
+
+    drop(disk);
+    if get(id).is_some() {
+        return Err(KernelError::ValidationError("Counters outlived their device"));
+    }
+
+    serial_println!("IOSTATS: Self-test passed");
+    Ok(())
+}
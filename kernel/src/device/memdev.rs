@@ -0,0 +1,244 @@
+//! Memory character devices: /dev/null, /dev/zero, /dev/random and /dev/full
+//!
+//! None of them touch hardware. null swallows writes and reads as empty,
+//! zero reads as endless zeros, full reads as zeros but refuses every write
+//! with NoSpace, for testing what happens when a disk fills up. random reads
+//! from an xorshift64* generator seeded from the RTC and TSC jitter; it is
+//! fine for tests and scripts, not for keys.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+use crate::device::{generate_device_id, CharacterDevice, Device, DeviceStatus, DeviceType};
+use crate::errors::KernelError;
+use crate::fs::devfs;
+use crate::serial_println;
+
+/// Which device it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemKind {
+    Null,
+    Zero,
+    Random,
+    Full,
+}
+
+impl MemKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemKind::Null => "null",
+            MemKind::Zero => "zero",
+            MemKind::Random => "random",
+            MemKind::Full => "full",
+        }
+    }
+}
+
+/// xorshift64*: quick and evenly spread, but anyone who sees a few outputs
+/// can predict the rest
+struct Xorshift64Star(u64);
+
+impl Xorshift64Star {
+    /// The state must never be zero, or it stays zero
+    const FALLBACK_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
+    fn new(seed: u64) -> Self {
+        Self(if seed == 0 { Self::FALLBACK_SEED } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    /// Stir written bytes into the state
+    fn mix(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).rotate_left(8).wrapping_mul(0x0100_0000_01B3);
+        }
+        if self.0 == 0 {
+            self.0 = Self::FALLBACK_SEED;
+        }
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// A seed from the RTC's time and how long reading it takes, which wobbles
+/// by a few cycles from read to read
+fn seed() -> u64 {
+    let mut seed = crate::drivers::rtc::get_datetime().to_unix_seconds();
+    for _ in 0..16 {
+        let start = rdtsc();
+        crate::drivers::rtc::get_datetime();
+        let elapsed = rdtsc().wrapping_sub(start);
+        seed = (seed ^ elapsed).rotate_left(7).wrapping_mul(0x0100_0000_01B3);
+    }
+    seed ^ rdtsc()
+}
+
+pub struct MemDevice {
+    id: u64,
+    kind: MemKind,
+    status: DeviceStatus,
+    /// Only used by random
+    generator: Mutex<Xorshift64Star>,
+}
+
+impl MemDevice {
+    pub fn new(kind: MemKind) -> Self {
+        let seed = if kind == MemKind::Random { seed() } else { 0 };
+        Self {
+            id: generate_device_id(),
+            kind,
+            status: DeviceStatus::Running,
+            generator: Mutex::new(Xorshift64Star::new(seed)),
+        }
+    }
+}
+
+impl Device for MemDevice {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Character
+    }
+
+    fn name(&self) -> &str {
+        self.kind.name()
+    }
+
+    fn status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.status = status;
+    }
+
+    fn initialize(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), KernelError> {
+        if self.kind == MemKind::Random {
+            *self.generator.lock() = Xorshift64Star::new(seed());
+        }
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn debug_info(&self) -> String {
+        format!("Memory device: /dev/{} (ID: {})\nStatus: {:?}", self.kind.name(), self.id, self.status)
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn core::any::Any {
+        self
+    }
+}
+
+impl CharacterDevice for MemDevice {
+    fn read_byte(&self) -> Result<u8, KernelError> {
+        let mut byte = [0];
+        match self.read(&mut byte)? {
+            // null has no bytes at all
+            0 => Err(KernelError::InvalidOperation),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), KernelError> {
+        self.write(&[byte]).map(|_| ())
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        match self.kind {
+            MemKind::Null => return Ok(0),
+            MemKind::Zero | MemKind::Full => buffer.fill(0),
+            MemKind::Random => self.generator.lock().fill(buffer),
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, KernelError> {
+        match self.kind {
+            MemKind::Full if !buffer.is_empty() => Err(KernelError::NoSpace),
+            MemKind::Random => {
+                self.generator.lock().mix(buffer);
+                Ok(buffer.len())
+            }
+            _ => Ok(buffer.len()),
+        }
+    }
+}
+
+/// Register all four with the device registry and as /dev entries
+pub fn register_all() -> Result<(), KernelError> {
+    for kind in [MemKind::Null, MemKind::Zero, MemKind::Random, MemKind::Full] {
+        let device = Arc::new(Mutex::new(MemDevice::new(kind)));
+        crate::device::register_device(device.clone())?;
+        devfs::register(kind.name(), device)?;
+    }
+    Ok(())
+}
+
+/// Check each device's reads and writes, and that the generator neither
+/// repeats itself nor gets stuck at zero
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("MEMDEV: Running self-test");
+
+    let mut buffer = [0xAAu8; 16];
+    let mut null = MemDevice::new(MemKind::Null);
+    if null.read(&mut buffer)? != 0 || null.write(&buffer)? != buffer.len() || null.read_byte().is_ok() {
+        return Err(KernelError::ValidationError("/dev/null behaves wrongly"));
+    }
+    let mut full = MemDevice::new(MemKind::Full);
+    if full.read(&mut buffer)? != buffer.len() || buffer != [0; 16]
+        || !matches!(full.write(b"x"), Err(KernelError::NoSpace)) || full.write(&[])? != 0 {
+        return Err(KernelError::ValidationError("/dev/full behaves wrongly"));
+    }
+
+    let mut generator = Xorshift64Star::new(0);
+    let (first, second) = (generator.next(), generator.next());
+    generator.mix(&[0; 64]);
+    if first == second || generator.0 == 0 {
+        return Err(KernelError::ValidationError("Random generator repeats or stalls"));
+    }
+    let random = MemDevice::new(MemKind::Random);
+    let mut other = [0u8; 16];
+    random.read(&mut buffer)?;
+    random.read(&mut other)?;
+    if buffer == other || buffer == [0; 16] {
+        return Err(KernelError::ValidationError("/dev/random gave the same bytes twice"));
+    }
+
+    serial_println!("MEMDEV: Self-test passed");
+    Ok(())
+}
@@ -3,13 +3,19 @@
 //! This module defines the interface for all hardware device drivers.
 
 pub mod ata; // ATA/IDE disk driver
+pub mod atapi; // ATAPI CD-ROM driver
+pub mod iostats; // Per-device transfer counters
+pub mod memdev; // null, zero, random and full
+pub mod ps2; // PS/2 keyboard and mouse registry entries
 
 use core::any::Any;
 use core::fmt;
 use core::sync::atomic::{AtomicU64, Ordering};
-use alloc::string::String;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::sync::Arc;
+use lazy_static::lazy_static;
 use spin::Mutex;
 use crate::errors::KernelError;
 use crate::serial_println;
@@ -18,10 +24,16 @@ use crate::serial_println;
 static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
 
 /// Generates a unique device ID
-fn generate_device_id() -> u64 {
+pub(crate) fn generate_device_id() -> u64 {
     NEXT_DEVICE_ID.fetch_add(1, Ordering::SeqCst)
 }
 
+/// Transfer counters of block device `id`, or of the cache in front of
+/// one, if it has any
+pub fn io_stats(id: u64) -> Option<iostats::IoCounters> {
+    iostats::get(id)
+}
+
 /// DeviceType categorizes different classes of devices
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceType {
@@ -59,6 +71,10 @@ pub trait Device: Send + Sync + Any {
     /// Returns the current status of the device
     fn status(&self) -> DeviceStatus;
     
+    /// Records a status change made from outside the driver, such as by
+    /// suspend_all
+    fn set_status(&mut self, status: DeviceStatus);
+    
     /// Initializes the device. This must be called before the device can be used.
     fn initialize(&mut self) -> Result<(), KernelError>;
     
@@ -107,6 +123,11 @@ pub trait BlockDevice: Device {
     
     /// Flushes any cached data to the underlying device
     fn flush(&mut self) -> Result<(), KernelError>;
+    
+    /// Whether the device refuses writes, as CD-ROM drives do
+    fn read_only(&self) -> bool {
+        false
+    }
 }
 
 /// CharacterDevice extends the Device trait for byte-stream oriented devices
@@ -144,6 +165,14 @@ pub fn init() -> Result<(), KernelError> {
     // Initialize and register storage devices
     probe_storage_devices()?;
     
+    // The PS/2 drivers keep buffered input that a resume has to clear
+    register_device(Arc::new(Mutex::new(ps2::Ps2Device::keyboard())))?;
+    if !crate::safe_mode::is_active() {
+        register_device(Arc::new(Mutex::new(ps2::Ps2Device::mouse())))?;
+    }
+    
+    memdev::register_all()?;
+    
     Ok(())
 }
 
@@ -171,23 +200,30 @@ fn probe_storage_devices() -> Result<(), KernelError> {
     let ata_device = Arc::new(Mutex::new(ata::AtaDevice::new()));
     
     // Try to initialize it
-    {
+    let primary_master_is_atapi = {
         let mut device_guard = ata_device.lock();
         match device_guard.initialize() {
             Ok(_) => {
                 serial_println!("DEBUG: ATA device initialized successfully");
                 serial_println!("DEBUG: {}", device_guard.debug_info());
+                false
             }
             Err(e) => {
                 serial_println!("DEBUG: Failed to initialize ATA device: {:?}", e);
                 // We'll still register it, just in an uninitialized state
+                matches!(e, KernelError::UnsupportedFeature)
             }
         }
-    }
+    };
     
     // Register the device
     register_device(ata_device)?;
     
+    // CD-ROM drives, usually on the secondary channel
+    for drive in atapi::probe(!primary_master_is_atapi) {
+        register_device(Arc::new(Mutex::new(drive)))?;
+    }
+    
     Ok(())
 }
 
@@ -229,4 +265,215 @@ pub fn get_device_by_id(id: u64) -> Option<Arc<Mutex<dyn Device>>> {
 /// Get all block devices
 pub fn get_block_devices() -> Vec<Arc<Mutex<dyn Device>>> {
     get_devices_by_type(DeviceType::Block)
-} 
\ No newline at end of file
+}
+
+/// The block device named `wanted`: its ID, or the first word of its name
+pub fn find_block_device(wanted: &str) -> Option<Arc<Mutex<dyn Device>>> {
+    get_block_devices().into_iter().find(|device| {
+        let device = device.lock();
+        device.id().to_string() == wanted || device.name().split_whitespace().next() == Some(wanted)
+    })
+}
+
+lazy_static! {
+    /// What each suspended device was doing before, to return it to on resume
+    static ref STATUS_BEFORE_SUSPEND: Mutex<BTreeMap<u64, DeviceStatus>> = Mutex::new(BTreeMap::new());
+}
+
+/// Outcome of suspending or resuming a set of devices
+#[derive(Debug, Default)]
+pub struct PowerReport {
+    /// Devices whose call succeeded, in call order
+    pub done: Vec<String>,
+    /// Devices whose call failed, and why
+    pub failed: Vec<(String, KernelError)>,
+    /// Devices resumed again because a later one wouldn't suspend
+    pub rolled_back: Vec<String>,
+}
+
+impl PowerReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+// Devices that are up and so have something to suspend
+fn is_active(status: DeviceStatus) -> bool {
+    matches!(status, DeviceStatus::Initialized | DeviceStatus::Running)
+}
+
+// Resume one device, putting back the status it had before suspending
+fn resume_device(device: &Arc<Mutex<dyn Device>>, report: &mut PowerReport) -> bool {
+    let mut guard = device.lock();
+    let name = guard.name().to_string();
+    match guard.resume() {
+        Ok(()) => {
+            let before = STATUS_BEFORE_SUSPEND.lock().remove(&guard.id());
+            guard.set_status(before.unwrap_or(DeviceStatus::Initialized));
+            report.done.push(name);
+            true
+        }
+        Err(e) => {
+            guard.set_status(DeviceStatus::Error);
+            report.failed.push((name, e));
+            false
+        }
+    }
+}
+
+/// Suspend `devices` last to first, so nothing is suspended before the
+/// devices registered after it that may depend on it. If one fails, the
+/// ones already suspended are resumed again and the rest left alone.
+pub fn suspend_devices(devices: &[Arc<Mutex<dyn Device>>]) -> PowerReport {
+    let mut report = PowerReport::default();
+    let mut suspended = Vec::new();
+    for device in devices.iter().rev() {
+        let mut guard = device.lock();
+        let status = guard.status();
+        if !is_active(status) {
+            continue;
+        }
+        let name = guard.name().to_string();
+        match guard.suspend() {
+            Ok(()) => {
+                STATUS_BEFORE_SUSPEND.lock().insert(guard.id(), status);
+                guard.set_status(DeviceStatus::Suspended);
+                report.done.push(name);
+                suspended.push(device.clone());
+            }
+            Err(e) => {
+                serial_println!("DEBUG: Device {} failed to suspend: {:?}", name, e);
+                // Still running, as far as anyone can tell
+                guard.set_status(status);
+                report.failed.push((name, e));
+                drop(guard);
+                
+                let mut rollback = PowerReport::default();
+                for device in suspended.iter().rev() {
+                    resume_device(device, &mut rollback);
+                }
+                report.rolled_back = rollback.done;
+                report.failed.extend(rollback.failed);
+                break;
+            }
+        }
+    }
+    report
+}
+
+/// Resume the suspended ones among `devices`, first to last. A failure
+/// marks that device Error and carries on with the rest.
+pub fn resume_devices(devices: &[Arc<Mutex<dyn Device>>]) -> PowerReport {
+    let mut report = PowerReport::default();
+    for device in devices {
+        if device.lock().status() == DeviceStatus::Suspended {
+            resume_device(device, &mut report);
+        }
+    }
+    report
+}
+
+fn registered_devices() -> Vec<Arc<Mutex<dyn Device>>> {
+    unsafe { DEVICE_REGISTRY.as_ref() }.cloned().unwrap_or_default()
+}
+
+/// Suspend every registered device, in reverse registration order, after
+/// writing back the block caches above them
+pub fn suspend_all() -> PowerReport {
+    crate::fs::block_adapter::flush_all();
+    suspend_devices(&registered_devices())
+}
+
+/// Resume every suspended device, in registration order
+pub fn resume_all() -> PowerReport {
+    resume_devices(&registered_devices())
+}
+
+/// Check suspend order, rollback after a failure and status transitions
+/// with devices that record the calls made on them
+pub fn self_test() -> Result<(), KernelError> {
+    use alloc::format;
+    
+    serial_println!("DEVICE: Running self-test");
+    
+    struct MockDevice {
+        id: u64,
+        name: String,
+        status: DeviceStatus,
+        fail_suspend: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+    
+    impl Device for MockDevice {
+        fn id(&self) -> u64 { self.id }
+        fn device_type(&self) -> DeviceType { DeviceType::Other }
+        fn name(&self) -> &str { &self.name }
+        fn status(&self) -> DeviceStatus { self.status }
+        fn set_status(&mut self, status: DeviceStatus) { self.status = status; }
+        fn initialize(&mut self) -> Result<(), KernelError> { Ok(()) }
+        fn reset(&mut self) -> Result<(), KernelError> { Ok(()) }
+        fn suspend(&mut self) -> Result<(), KernelError> {
+            self.log.lock().push(format!("suspend {}", self.name));
+            if self.fail_suspend { Err(KernelError::DeviceTimeout) } else { Ok(()) }
+        }
+        fn resume(&mut self) -> Result<(), KernelError> {
+            self.log.lock().push(format!("resume {}", self.name));
+            Ok(())
+        }
+        fn debug_info(&self) -> String { self.name.clone() }
+        fn as_any(&self) -> &dyn Any { self }
+        fn as_any_mut(&mut self) -> &mut dyn Any { self }
+    }
+    
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mock = |name: &str, status: DeviceStatus, fail_suspend: bool| -> Arc<Mutex<dyn Device>> {
+        Arc::new(Mutex::new(MockDevice {
+            id: generate_device_id(),
+            name: name.to_string(),
+            status,
+            fail_suspend,
+            log: log.clone(),
+        }))
+    };
+    let statuses = |devices: &[Arc<Mutex<dyn Device>>]| -> Vec<DeviceStatus> {
+        devices.iter().map(|device| device.lock().status()).collect()
+    };
+    
+    // "b" was never brought up, so it is skipped both ways
+    let devices = [
+        mock("a", DeviceStatus::Running, false),
+        mock("b", DeviceStatus::NotResponding, false),
+        mock("c", DeviceStatus::Initialized, false),
+    ];
+    let report = suspend_devices(&devices);
+    if !report.is_ok() || statuses(&devices) != [DeviceStatus::Suspended, DeviceStatus::NotResponding, DeviceStatus::Suspended] {
+        return Err(KernelError::ValidationError("Suspend left the wrong device statuses"));
+    }
+    let report = resume_devices(&devices);
+    if !report.is_ok() || statuses(&devices) != [DeviceStatus::Running, DeviceStatus::NotResponding, DeviceStatus::Initialized] {
+        return Err(KernelError::ValidationError("Resume didn't restore the earlier statuses"));
+    }
+    if *log.lock() != ["suspend c", "suspend a", "resume a", "resume c"] {
+        return Err(KernelError::ValidationError("Devices suspended or resumed in the wrong order"));
+    }
+    
+    // "e" refuses, so "f" and "g" are resumed again, last suspended first
+    log.lock().clear();
+    let devices = [
+        mock("d", DeviceStatus::Running, false),
+        mock("e", DeviceStatus::Running, true),
+        mock("f", DeviceStatus::Running, false),
+        mock("g", DeviceStatus::Running, false),
+    ];
+    let report = suspend_devices(&devices);
+    if report.is_ok() || report.failed.len() != 1 || report.rolled_back != ["f", "g"] {
+        return Err(KernelError::ValidationError("Failed suspend wasn't reported and rolled back"));
+    }
+    if *log.lock() != ["suspend g", "suspend f", "suspend e", "resume f", "resume g"]
+        || statuses(&devices).iter().any(|&status| status != DeviceStatus::Running) {
+        return Err(KernelError::ValidationError("Rollback left devices suspended"));
+    }
+    
+    serial_println!("DEVICE: Self-test passed");
+    Ok(())
+}
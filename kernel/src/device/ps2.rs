@@ -0,0 +1,84 @@
+//! Registry entries for the PS/2 keyboard and mouse.
+//! The drivers themselves live in crate::drivers and keep their state in
+//! globals; these give suspend/resume a handle on that state.
+
+use alloc::format;
+use alloc::string::String;
+use crate::device::{generate_device_id, Device, DeviceStatus, DeviceType};
+use crate::errors::KernelError;
+
+/// A PS/2 port's device, whose resume clears what the driver had buffered
+pub struct Ps2Device {
+    id: u64,
+    name: &'static str,
+    status: DeviceStatus,
+    reset_state: fn(),
+}
+
+impl Ps2Device {
+    pub fn keyboard() -> Self {
+        Self::new("ps2-keyboard", crate::drivers::ps2_keyboard::reset_state)
+    }
+
+    pub fn mouse() -> Self {
+        Self::new("ps2-mouse", crate::drivers::ps2_mouse::reset_state)
+    }
+
+    fn new(name: &'static str, reset_state: fn()) -> Self {
+        Self { id: generate_device_id(), name, status: DeviceStatus::Running, reset_state }
+    }
+}
+
+impl Device for Ps2Device {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Input
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.status = status;
+    }
+
+    fn initialize(&mut self) -> Result<(), KernelError> {
+        // The driver set the port up during drivers::init
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), KernelError> {
+        (self.reset_state)();
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), KernelError> {
+        // Whatever was queued or half-received before the suspend is stale
+        (self.reset_state)();
+        Ok(())
+    }
+
+    fn debug_info(&self) -> String {
+        format!("PS/2 device: {} (ID: {})\nStatus: {:?}", self.name, self.id, self.status)
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn core::any::Any {
+        self
+    }
+}
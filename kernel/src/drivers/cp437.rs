@@ -0,0 +1,68 @@
+//! Code page 437 encoding for VGA text mode
+//! The VGA character generator draws CP437, so text has to be converted one
+//! char at a time rather than copied as UTF-8 bytes.
+
+/// Stand-in for characters CP437 can't show
+pub const REPLACEMENT: u8 = b'?';
+
+/// Unicode characters outside ASCII that CP437 can draw, with their codes
+const MAPPINGS: &[(char, u8)] = &[
+    // Single-line box drawing
+    ('─', 0xC4), ('│', 0xB3), ('┌', 0xDA), ('┐', 0xBF), ('└', 0xC0), ('┘', 0xD9),
+    ('├', 0xC3), ('┤', 0xB4), ('┬', 0xC2), ('┴', 0xC1), ('┼', 0xC5),
+    // Double-line box drawing
+    ('═', 0xCD), ('║', 0xBA), ('╔', 0xC9), ('╗', 0xBB), ('╚', 0xC8), ('╝', 0xBC),
+    ('╠', 0xCC), ('╣', 0xB9), ('╦', 0xCB), ('╩', 0xCA), ('╬', 0xCE),
+    // Mixed single and double
+    ('╒', 0xD5), ('╓', 0xD6), ('╕', 0xB8), ('╖', 0xB7), ('╘', 0xD4), ('╙', 0xD3),
+    ('╛', 0xBE), ('╜', 0xBD), ('╞', 0xC6), ('╟', 0xC7), ('╡', 0xB5), ('╢', 0xB6),
+    ('╤', 0xD1), ('╥', 0xD2), ('╧', 0xCF), ('╨', 0xD0), ('╪', 0xD8), ('╫', 0xD7),
+    // Shades and blocks
+    ('░', 0xB0), ('▒', 0xB1), ('▓', 0xB2), ('█', 0xDB), ('▄', 0xDC), ('▌', 0xDD),
+    ('▐', 0xDE), ('▀', 0xDF), ('■', 0xFE),
+    // Arrows and pointers
+    ('↑', 0x18), ('↓', 0x19), ('→', 0x1A), ('←', 0x1B), ('↔', 0x1D), ('↕', 0x12),
+    ('▲', 0x1E), ('▼', 0x1F), ('►', 0x10), ('◄', 0x11),
+    // Symbols
+    ('☺', 0x01), ('☻', 0x02), ('♥', 0x03), ('♦', 0x04), ('♣', 0x05), ('♠', 0x06),
+    ('•', 0x07), ('◘', 0x08), ('○', 0x09), ('◙', 0x0A), ('♂', 0x0B), ('♀', 0x0C),
+    ('♪', 0x0D), ('♫', 0x0E), ('☼', 0x0F), ('‼', 0x13), ('¶', 0x14), ('§', 0x15),
+    ('⌂', 0x7F), ('·', 0xFA), ('√', 0xFB), ('°', 0xF8), ('±', 0xF1), ('²', 0xFD),
+    ('≥', 0xF2), ('≤', 0xF3), ('÷', 0xF6), ('≈', 0xF7), ('∞', 0xEC), ('µ', 0xE6),
+    ('π', 0xE3), ('Σ', 0xE4), ('Ω', 0xEA), ('α', 0xE0), ('ß', 0xE1), ('¢', 0x9B),
+    ('£', 0x9C), ('¥', 0x9D), ('¡', 0xAD), ('¿', 0xA8), ('«', 0xAE), ('»', 0xAF),
+    ('½', 0xAB), ('¼', 0xAC),
+    // Check and cross marks, drawn with the nearest glyphs there are
+    ('✓', 0xFB), ('✔', 0xFB), ('✗', b'x'), ('✘', b'x'),
+    // Accented letters
+    ('Ç', 0x80), ('ü', 0x81), ('é', 0x82), ('â', 0x83), ('ä', 0x84), ('à', 0x85),
+    ('å', 0x86), ('ç', 0x87), ('ê', 0x88), ('ë', 0x89), ('è', 0x8A), ('ï', 0x8B),
+    ('î', 0x8C), ('ì', 0x8D), ('Ä', 0x8E), ('Å', 0x8F), ('É', 0x90), ('æ', 0x91),
+    ('Æ', 0x92), ('ô', 0x93), ('ö', 0x94), ('ò', 0x95), ('û', 0x96), ('ù', 0x97),
+    ('ÿ', 0x98), ('Ö', 0x99), ('Ü', 0x9A), ('á', 0xA0), ('í', 0xA1), ('ó', 0xA2),
+    ('ú', 0xA3), ('ñ', 0xA4), ('Ñ', 0xA5),
+];
+
+/// CP437 code for a printable character; ASCII maps to itself and anything
+/// without a glyph becomes REPLACEMENT. Control characters are the
+/// caller's business and are not passed here.
+pub fn encode(c: char) -> u8 {
+    if (' '..='~').contains(&c) {
+        return c as u8;
+    }
+    MAPPINGS.iter()
+        .find(|(unicode, _)| *unicode == c)
+        .map_or(REPLACEMENT, |(_, code)| *code)
+}
+
+/// The character CP437 code `code` draws, for reading the screen back as
+/// text. Codes with no character here, NUL and 0xFF among them, read as a
+/// space.
+pub fn decode(code: u8) -> char {
+    if (b' '..=b'~').contains(&code) {
+        return code as char;
+    }
+    MAPPINGS.iter()
+        .find(|(_, mapped)| *mapped == code)
+        .map_or(' ', |(unicode, _)| *unicode)
+}
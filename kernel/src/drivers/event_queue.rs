@@ -0,0 +1,61 @@
+//! Fixed-size event queue for interrupt handlers
+//!
+//! All the room is part of the queue itself, so pushing from an interrupt
+//! handler never touches the heap, whose lock the interrupted code may
+//! hold. A full queue hands the event back instead of growing.
+
+/// First-in first-out queue of up to `N` events
+pub struct EventQueue<T: Copy, const N: usize> {
+    slots: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        Self { slots: [None; N], head: 0, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Add an event at the back, or hand it back if the queue is full
+    pub fn push_back(&mut self, event: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(event);
+        }
+        self.slots[(self.head + self.len) % N] = Some(event);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take the oldest event
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let event = self.slots[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        event
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+}
+
+impl<T: Copy, const N: usize> Default for EventQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
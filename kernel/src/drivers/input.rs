@@ -0,0 +1,355 @@
+// kernel/src/drivers/input.rs
+//! Synthetic keyboard and mouse input, for scripting the shell and GUI.
+//!
+//! Injected scancodes and mouse packets wait in a queue of their own. The
+//! PS/2 drivers take them from it as their event queues have room, and feed
+//! them through the same decoding as bytes read by the interrupt handlers.
+//! Whoever reads keyboard or mouse events can't tell them from real input.
+//!
+//! Injection is off unless `debug.input_injection` is set.
+//!
+//! The drivers also note here the time of each event read, real or
+//! injected, as stamped when it was queued, so `idle_ms` says how long the
+//! machine has gone without input.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::serial_println;
+use super::ps2_keyboard::{self, KeyCode, KeyEvent, KeyState};
+use super::ps2_mouse::{self, MouseEvent};
+
+/// Most injected bytes waiting, per device
+const MAX_PENDING: usize = 4096;
+
+// Scancode set 1 make codes used for text
+const SCANCODE_LEFT_SHIFT: u8 = 0x2A;
+const SCANCODE_ENTER: u8 = 0x1C;
+const SCANCODE_TAB: u8 = 0x0F;
+const SCANCODE_BACKSPACE: u8 = 0x0E;
+const SCANCODE_ESCAPE: u8 = 0x01;
+const SCANCODE_SPACE: u8 = 0x39;
+/// Added to a make code for the key's release
+const RELEASE: u8 = 0x80;
+
+/// Runs of keys with consecutive make codes: (first code, unshifted
+/// characters, shifted characters), on a US layout
+const KEY_ROWS: [(u8, &str, &str); 4] = [
+    (0x02, "1234567890-=", "!@#$%^&*()_+"),
+    (0x10, "qwertyuiop[]", "QWERTYUIOP{}"),
+    (0x1E, "asdfghjkl;'`", "ASDFGHJKL:\"~"),
+    (0x2B, "\\zxcvbnm,./", "|ZXCVBNM<>?"),
+];
+
+// Mouse packet bits
+const PACKET_ALWAYS_SET: u8 = 0x08;
+const PACKET_X_SIGN: u8 = 0x10;
+const PACKET_Y_SIGN: u8 = 0x20;
+const BUTTON_LEFT: u8 = 0x01;
+/// Largest movement one packet carries
+const MAX_PACKET_MOVE: i16 = 127;
+
+/// Make codes of the keys sent after an 0xE0 prefix
+const EXTENDED_KEYS: [(KeyCode, u8); 11] = [
+    (KeyCode::ArrowUp, 0x48), (KeyCode::ArrowDown, 0x50), (KeyCode::ArrowLeft, 0x4B), (KeyCode::ArrowRight, 0x4D),
+    (KeyCode::Home, 0x47), (KeyCode::End, 0x4F), (KeyCode::PageUp, 0x49), (KeyCode::PageDown, 0x51),
+    (KeyCode::Insert, 0x52), (KeyCode::Delete, 0x53), (KeyCode::PrintScreen, 0x37),
+];
+
+/// Monotonic time (ms) of the latest keyboard or mouse event read
+static LAST_EVENT_MS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Injected bytes, each with the monotonic time (ms) it was queued at,
+    /// which the event decoded from it carries
+    static ref PENDING_KEYS: Mutex<VecDeque<(u8, u64)>> = Mutex::new(VecDeque::new());
+    static ref PENDING_MOUSE: Mutex<VecDeque<(u8, u64)>> = Mutex::new(VecDeque::new());
+}
+
+/// Whether `debug.input_injection` allows injecting input
+pub fn injection_enabled() -> bool {
+    crate::config::get("debug.input_injection")
+        .and_then(|value| value.try_as_boolean())
+        .unwrap_or(false)
+}
+
+fn check_enabled() -> Result<(), KernelError> {
+    if injection_enabled() {
+        Ok(())
+    } else {
+        Err(KernelError::InvalidOperation)
+    }
+}
+
+fn push(queue: &Mutex<VecDeque<(u8, u64)>>, bytes: &[u8]) -> Result<(), KernelError> {
+    let now_ms = crate::time::monotonic_ms();
+    let mut queue = queue.lock();
+    if queue.len() + bytes.len() > MAX_PENDING {
+        return Err(KernelError::NoSpace);
+    }
+    queue.extend(bytes.iter().map(|&byte| (byte, now_ms)));
+    Ok(())
+}
+
+/// Queue scancodes regardless of the setting, for self-tests
+pub(crate) fn queue_keys(scancodes: &[u8]) -> Result<(), KernelError> {
+    push(&PENDING_KEYS, scancodes)
+}
+
+/// Queue whole mouse packets regardless of the setting, for self-tests
+pub(crate) fn queue_mouse(bytes: &[u8]) -> Result<(), KernelError> {
+    if bytes.len() % 3 != 0 {
+        return Err(KernelError::InvalidParameter);
+    }
+    push(&PENDING_MOUSE, bytes)
+}
+
+/// Inject one set 1 scancode byte as if the keyboard had sent it
+pub fn inject_key(scancode: u8) -> Result<(), KernelError> {
+    check_enabled()?;
+    queue_keys(&[scancode])
+}
+
+/// Inject standard 3-byte mouse packets as if the mouse had sent them
+pub fn inject_mouse(bytes: &[u8]) -> Result<(), KernelError> {
+    check_enabled()?;
+    queue_mouse(bytes)
+}
+
+/// Type `text`: each character becomes a press and release, wrapped in
+/// Shift when the character needs it. Nothing is injected if any character
+/// has no key.
+pub fn send_text(text: &str) -> Result<(), KernelError> {
+    check_enabled()?;
+    let scancodes = text_scancodes(text).ok_or(KernelError::InvalidParameter)?;
+    queue_keys(&scancodes)
+}
+
+/// Move the pointer to (x, y) and click the left button there
+pub fn click_at(x: i16, y: i16) -> Result<(), KernelError> {
+    check_enabled()?;
+    queue_mouse(&click_packets(x, y))
+}
+
+/// Press or release the key with make code `scancode`, after an 0xE0
+/// prefix if `extended`
+pub fn inject_scancode(scancode: u8, extended: bool, pressed: bool) -> Result<(), KernelError> {
+    check_enabled()?;
+    let code = if pressed { scancode } else { scancode | RELEASE };
+    if extended {
+        queue_keys(&[0xE0, code])
+    } else {
+        queue_keys(&[code])
+    }
+}
+
+/// Move the pointer to (x, y) with `buttons` (PS/2 packet bits: 1 left,
+/// 2 right, 4 middle) held, or just change the buttons if it's there
+pub fn mouse_to(x: i16, y: i16, buttons: u8) -> Result<(), KernelError> {
+    check_enabled()?;
+    let mut packets = move_packets(x, y, buttons & 0x07);
+    if packets.is_empty() {
+        packets.extend(mouse_packet(0, 0, buttons & 0x07));
+    }
+    queue_mouse(&packets)
+}
+
+/// Whether all injected input has been taken by the drivers
+pub fn is_idle() -> bool {
+    PENDING_KEYS.lock().is_empty() && PENDING_MOUSE.lock().is_empty()
+}
+
+/// Next injected scancode and when it was queued, for the keyboard driver
+pub(crate) fn next_key() -> Option<(u8, u64)> {
+    PENDING_KEYS.lock().pop_front()
+}
+
+/// Next injected packet and when it was queued, for the mouse driver
+pub(crate) fn next_mouse_packet() -> Option<([u8; 3], u64)> {
+    let mut queue = PENDING_MOUSE.lock();
+    if queue.len() < 3 {
+        return None;
+    }
+    let mut bytes = queue.drain(..3);
+    let (first, time_ms) = bytes.next()?;
+    Some(([first, bytes.next()?.0, bytes.next()?.0], time_ms))
+}
+
+/// Record that a keyboard or mouse event stamped `time_ms` was read; the
+/// drivers call this. Keys and mouse are read from separate queues, so an
+/// older event doesn't move the time back.
+pub(crate) fn note_event(time_ms: u64) {
+    LAST_EVENT_MS.fetch_max(time_ms, Ordering::Relaxed);
+}
+
+/// Milliseconds since the latest keyboard or mouse event read happened, or
+/// since boot if none has been
+pub fn idle_ms() -> u64 {
+    crate::time::monotonic_ms().saturating_sub(LAST_EVENT_MS.load(Ordering::Relaxed))
+}
+
+/// Drop input that hasn't been taken yet
+pub fn clear() {
+    PENDING_KEYS.lock().clear();
+    PENDING_MOUSE.lock().clear();
+}
+
+/// The key that types `c`, and whether it needs Shift
+fn char_scancode(c: char) -> Option<(u8, bool)> {
+    match c {
+        '\n' => return Some((SCANCODE_ENTER, false)),
+        '\t' => return Some((SCANCODE_TAB, false)),
+        '\u{8}' => return Some((SCANCODE_BACKSPACE, false)),
+        '\u{1b}' => return Some((SCANCODE_ESCAPE, false)),
+        ' ' => return Some((SCANCODE_SPACE, false)),
+        _ => {}
+    }
+    KEY_ROWS.iter().find_map(|(first, plain, shifted)| {
+        if let Some(index) = plain.chars().position(|key| key == c) {
+            Some((first + index as u8, false))
+        } else {
+            shifted.chars().position(|key| key == c).map(|index| (first + index as u8, true))
+        }
+    })
+}
+
+/// The make code sending `code`, and whether it comes after an 0xE0
+/// prefix; None for Unknown
+pub fn key_scancode(code: KeyCode) -> Option<(u8, bool)> {
+    if let Some((_, scancode)) = EXTENDED_KEYS.iter().find(|(key, _)| *key == code) {
+        return Some((*scancode, true));
+    }
+    // The rest are numbered by their set 1 make codes
+    match code {
+        KeyCode::Unknown => None,
+        _ => Some((code as u8, false)),
+    }
+}
+
+/// Press and release scancodes typing `text`, or None if a character has no key
+pub fn text_scancodes(text: &str) -> Option<Vec<u8>> {
+    let mut scancodes = Vec::with_capacity(text.len() * 2);
+    for c in text.chars() {
+        let (code, shift) = char_scancode(c)?;
+        if shift {
+            scancodes.push(SCANCODE_LEFT_SHIFT);
+        }
+        scancodes.extend([code, code | RELEASE]);
+        if shift {
+            scancodes.push(SCANCODE_LEFT_SHIFT | RELEASE);
+        }
+    }
+    Some(scancodes)
+}
+
+/// One mouse packet moving the pointer (dx, dy) in screen terms, y down
+fn mouse_packet(dx: i16, dy: i16, buttons: u8) -> [u8; 3] {
+    // The mouse counts y upwards
+    let dy = -dy;
+    let mut flags = PACKET_ALWAYS_SET | buttons;
+    if dx < 0 {
+        flags |= PACKET_X_SIGN;
+    }
+    if dy < 0 {
+        flags |= PACKET_Y_SIGN;
+    }
+    [flags, dx as u8, dy as u8]
+}
+
+/// Packets moving the pointer from where it is now to (x, y), with
+/// `buttons` held
+fn move_packets(x: i16, y: i16, buttons: u8) -> Vec<u8> {
+    let start = ps2_mouse::get_state();
+    let (mut dx, mut dy) = (x - start.x, y - start.y);
+    let mut packets = Vec::new();
+    while dx != 0 || dy != 0 {
+        let step_x = dx.clamp(-MAX_PACKET_MOVE, MAX_PACKET_MOVE);
+        let step_y = dy.clamp(-MAX_PACKET_MOVE, MAX_PACKET_MOVE);
+        packets.extend(mouse_packet(step_x, step_y, buttons));
+        dx -= step_x;
+        dy -= step_y;
+    }
+    packets
+}
+
+/// Packets moving the pointer to (x, y), then pressing and releasing the
+/// left button there
+fn click_packets(x: i16, y: i16) -> Vec<u8> {
+    let mut packets = move_packets(x, y, 0);
+    packets.extend(mouse_packet(0, 0, BUTTON_LEFT));
+    packets.extend(mouse_packet(0, 0, 0));
+    packets
+}
+
+fn drain_keys() -> Vec<KeyEvent> {
+    core::iter::from_fn(ps2_keyboard::get_event).collect()
+}
+
+fn drain_mouse() -> Vec<MouseEvent> {
+    core::iter::from_fn(ps2_mouse::get_event).collect()
+}
+
+/// Inject shifted text, an extended key and mouse clicks, and check the
+/// events the drivers decode from them
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("INPUT: Running self-test");
+
+    clear();
+    ps2_keyboard::reset_state();
+    ps2_mouse::reset_state();
+    let result = (|| {
+        // More keys than the keyboard queue holds, so it has to refill
+        let mut scancodes = text_scancodes("Hi! ok").ok_or(KernelError::InvalidData)?;
+        scancodes.extend([0xE0, 0x48, 0xE0, 0xC8]);
+        queue_keys(&scancodes)?;
+        let events = drain_keys();
+        let typed: String = events.iter()
+            .filter(|event| event.state == KeyState::Pressed)
+            .filter_map(KeyEvent::to_char)
+            .collect();
+        let shifts = events.iter().filter(|event| event.code == KeyCode::LeftShift).count();
+        let shifted_bang = events.iter().any(|event| event.code == KeyCode::Key1 && event.shift);
+        let last: Vec<(KeyCode, KeyState)> = events.iter().rev().take(2).map(|event| (event.code, event.state)).collect();
+        if typed != "Hi! ok" || shifts != 4 || !shifted_bang || events.len() != 18
+            || last != [(KeyCode::ArrowUp, KeyState::Released), (KeyCode::ArrowUp, KeyState::Pressed)] {
+            serial_println!("INPUT: Typed '{}' in {} events", typed, events.len());
+            return Err(KernelError::ValidationError("Injected keys decoded wrongly"));
+        }
+        if key_scancode(KeyCode::ArrowUp) != Some((0x48, true)) || key_scancode(KeyCode::Slash) != Some((0x35, false))
+            || key_scancode(KeyCode::Unknown).is_some() {
+            return Err(KernelError::ValidationError("Wrong scancode for a key"));
+        }
+        if text_scancodes("caf\u{e9}").is_some() {
+            return Err(KernelError::ValidationError("Text with no key was accepted"));
+        }
+
+        // Out, then back left and up, covering both signs on both axes
+        let origin = ps2_mouse::get_state();
+        for (x, y, double_click) in [(300, 200, false), (100, 50, true)] {
+            queue_mouse(&click_packets(x, y))?;
+            let events = drain_mouse();
+            let [.., press, release] = events.as_slice() else {
+                return Err(KernelError::ValidationError("Injected click produced too few mouse events"));
+            };
+            if (press.x, press.y, release.x, release.y) != (x, y, x, y)
+                || !press.buttons.left || release.buttons.left || press.double_click != double_click {
+                serial_println!("INPUT: Click at ({}, {}) arrived as {:?}, {:?}", x, y, press, release);
+                return Err(KernelError::ValidationError("Injected click decoded wrongly"));
+            }
+        }
+        queue_mouse(&move_packets(origin.x, origin.y, 0))?;
+        drain_mouse();
+        Ok(())
+    })();
+    clear();
+    ps2_keyboard::reset_state();
+    ps2_mouse::reset_state();
+
+    result?;
+    serial_println!("INPUT: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Repeating a held key, in software when the keyboard doesn't
+//!
+//! A PS/2 keyboard repeats the last key held down by itself (typematic),
+//! at the rate set with 0xF3. Those repeats don't always reach us: the
+//! rate may not have been accepted, and a full queue drops them. So the
+//! driver also reports every decoded event here, and while a key is held
+//! `due` makes up a repeat whenever one is late.
+//!
+//! The set of held keys tells a hardware repeat (a press of a key already
+//! down) from a new press. A hardware repeat pushes the next made-up one
+//! back by half an interval more than the rate, so software only steps in
+//! once the keyboard has missed one, and a hardware repeat arriving just
+//! after a made-up one is dropped. Either way each interval gives one
+//! repeat, never two. Modifiers and lock keys don't repeat.
+
+use crate::errors::KernelError;
+use crate::serial_println;
+use super::ps2_keyboard::{KeyCode, KeyEvent, KeyState};
+
+/// Delays the keyboard can be set to, in milliseconds
+pub const MIN_DELAY_MS: u64 = 250;
+pub const MAX_DELAY_MS: u64 = 1000;
+
+/// Rates the keyboard can be set to, in characters a second
+pub const MIN_RATE_CPS: u64 = 2;
+pub const MAX_RATE_CPS: u64 = 30;
+
+/// Keys tracked as held at once, more than a PS/2 keyboard reports; held
+/// in place so the interrupt handler never allocates
+const MAX_HELD: usize = 8;
+
+/// The key being repeated
+#[derive(Debug, Clone, Copy)]
+struct Repeating {
+    event: KeyEvent,
+    /// When software sends the next repeat
+    next_ms: u64,
+    /// When the key last reached the queue, pressed or repeated
+    last_ms: u64,
+}
+
+pub struct KeyRepeat {
+    /// Keys down now, modifiers included
+    held: [Option<KeyCode>; MAX_HELD],
+    repeating: Option<Repeating>,
+    delay_ms: u64,
+    interval_ms: u64,
+}
+
+impl KeyRepeat {
+    pub const fn new() -> Self {
+        Self { held: [None; MAX_HELD], repeating: None, delay_ms: 500, interval_ms: 50 }
+    }
+
+    /// Wait `delay_ms` before the first repeat, then repeat `rate_cps`
+    /// times a second, each clamped to what the keyboard supports
+    pub fn set_timing(&mut self, delay_ms: u64, rate_cps: u64) {
+        self.delay_ms = delay_ms.clamp(MIN_DELAY_MS, MAX_DELAY_MS);
+        self.interval_ms = 1000 / rate_cps.clamp(MIN_RATE_CPS, MAX_RATE_CPS);
+    }
+
+    /// Note a decoded event, as of when it was queued. Returns false for a
+    /// hardware repeat that comes too soon after a made-up one and should
+    /// be dropped.
+    pub fn observe(&mut self, event: &KeyEvent) -> bool {
+        let now_ms = event.time_ms;
+        if event.state == KeyState::Released {
+            for slot in self.held.iter_mut().filter(|slot| **slot == Some(event.code)) {
+                *slot = None;
+            }
+            if self.repeating.is_some_and(|repeating| repeating.event.code == event.code) {
+                self.repeating = None;
+            }
+            return true;
+        }
+
+        if !self.held.contains(&Some(event.code)) {
+            // Past MAX_HELD keys, every press of another looks new
+            if let Some(slot) = self.held.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(event.code);
+            }
+            if repeats(event.code) {
+                self.repeating = Some(Repeating { event: *event, next_ms: now_ms + self.delay_ms, last_ms: now_ms });
+            }
+            return true;
+        }
+
+        // Held already, so the keyboard is repeating it
+        let interval = self.interval_ms;
+        match self.repeating.as_mut() {
+            Some(repeating) if repeating.event.code == event.code => {
+                repeating.next_ms = now_ms + interval + interval / 2;
+                if now_ms < repeating.last_ms + interval / 2 {
+                    return false;
+                }
+                repeating.last_ms = now_ms;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// A repeat of the held key, stamped `now_ms`, if one is due then. A
+    /// late poll gets one repeat, not a burst to catch up.
+    pub fn due(&mut self, now_ms: u64) -> Option<KeyEvent> {
+        let repeating = self.repeating.as_mut()?;
+        if now_ms < repeating.next_ms {
+            return None;
+        }
+        repeating.next_ms = now_ms + self.interval_ms;
+        repeating.last_ms = now_ms;
+        Some(KeyEvent { time_ms: now_ms, ..repeating.event })
+    }
+
+    /// Forget held keys, as when the keyboard state is reset
+    pub fn clear(&mut self) {
+        self.held = [None; MAX_HELD];
+        self.repeating = None;
+    }
+}
+
+/// Whether holding `code` repeats it
+fn repeats(code: KeyCode) -> bool {
+    !matches!(code, KeyCode::LeftShift | KeyCode::RightShift | KeyCode::LeftControl | KeyCode::LeftAlt
+        | KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock | KeyCode::Unknown)
+}
+
+/// Microseconds between repeats at 0xF3 rate code `code`: (8 + A) * 2^B *
+/// 4.17, with A in bits 0-2 and B in bits 3-4
+fn typematic_interval_us(code: u8) -> u64 {
+    (8 + u64::from(code & 0x07)) * (1u64 << ((code >> 3) & 0x03)) * 4170
+}
+
+/// The byte sent after 0xF3 for the closest supported delay and rate:
+/// delay in bits 5-6 in steps of 250ms, rate code in bits 0-4
+pub fn typematic_byte(delay_ms: u64, rate_cps: u64) -> u8 {
+    let delay = ((delay_ms.clamp(MIN_DELAY_MS, MAX_DELAY_MS) + MIN_DELAY_MS / 2) / MIN_DELAY_MS - 1) as u8;
+    let wanted_us = 1_000_000 / rate_cps.clamp(MIN_RATE_CPS, MAX_RATE_CPS);
+    let rate = (0..=0x1Fu8).min_by_key(|code| typematic_interval_us(*code).abs_diff(wanted_us)).unwrap_or(0);
+    delay << 5 | rate
+}
+
+/// Hold a key through made-up and hardware repeats at set times, and check
+/// what comes out and what's dropped
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("KEY REPEAT: Running self-test");
+
+    let key = |code, state, time_ms| KeyEvent { code, state, shift: false, ctrl: false, alt: false, time_ms };
+    let mut repeat = KeyRepeat::new();
+    repeat.set_timing(500, 20);
+
+    // No hardware repeats: the first after the delay, then every 50ms,
+    // and only one for a poll that's late
+    repeat.observe(&key(KeyCode::Backspace, KeyState::Pressed, 1000));
+    let times = [1499, 1500, 1520, 1550, 1700, 1720];
+    let made = times.map(|now| repeat.due(now).is_some());
+    if made != [false, true, false, true, true, false] {
+        return Err(KernelError::ValidationError("Software repeats at the wrong times"));
+    }
+
+    // A hardware repeat right after a made-up one is dropped, and one on
+    // time holds software back
+    if repeat.observe(&key(KeyCode::Backspace, KeyState::Pressed, 1710))
+        || !repeat.observe(&key(KeyCode::Backspace, KeyState::Pressed, 1760))
+        || repeat.due(1830).is_some()
+        || repeat.due(1835).map(|event| (event.code, event.time_ms)) != Some((KeyCode::Backspace, 1835)) {
+        return Err(KernelError::ValidationError("Hardware and software repeats doubled up"));
+    }
+
+    // Shift held doesn't repeat, and releasing the key stops it
+    repeat.observe(&key(KeyCode::LeftShift, KeyState::Pressed, 1840));
+    repeat.observe(&key(KeyCode::Backspace, KeyState::Released, 1850));
+    if repeat.due(5000).is_some() {
+        return Err(KernelError::ValidationError("Key repeated after release or a modifier repeated"));
+    }
+    repeat.clear();
+
+    // 0xF3 encodings: defaults, fastest and slowest
+    if typematic_byte(500, 11) != 0x2B || typematic_byte(250, 30) != 0x00 || typematic_byte(1000, 2) != 0x7F
+        || typematic_byte(0, 1000) != 0x00 {
+        return Err(KernelError::ValidationError("Typematic byte encoded wrongly"));
+    }
+
+    serial_println!("KEY REPEAT: Self-test passed");
+    Ok(())
+}
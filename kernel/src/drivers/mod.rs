@@ -1,11 +1,16 @@
 //! Hardware device drivers for the kernel
 
 pub mod vga_enhanced;
+pub mod cp437;
+pub mod event_queue;
 pub mod ps2_keyboard;
+pub mod key_repeat;
 pub mod ps2_mouse;
+pub mod input;
 pub mod pit;
 pub mod rtc;
 pub mod pci;
+pub mod rtl8139;
 
 use crate::errors::KernelError;
 use crate::serial_println;
@@ -23,13 +28,17 @@ pub fn init() -> Result<(), KernelError> {
         serial_println!("DEBUG: PS/2 keyboard initialized successfully");
     }
     
-    // Initialize PS/2 mouse
-    serial_println!("DEBUG: Initializing PS/2 mouse");
-    if let Err(e) = ps2_mouse::init() {
-        serial_println!("WARNING: Failed to initialize PS/2 mouse: {:?}", e);
-        // Continue even if mouse init fails
+    // Initialize PS/2 mouse, unless in safe mode
+    if crate::safe_mode::is_active() {
+        crate::safe_mode::skip("PS/2 mouse");
     } else {
-        serial_println!("DEBUG: PS/2 mouse initialized successfully");
+        serial_println!("DEBUG: Initializing PS/2 mouse");
+        if let Err(e) = ps2_mouse::init() {
+            serial_println!("WARNING: Failed to initialize PS/2 mouse: {:?}", e);
+            // Continue even if mouse init fails
+        } else {
+            serial_println!("DEBUG: PS/2 mouse initialized successfully");
+        }
     }
     
     // Initialize PIT for system timer
@@ -41,6 +50,19 @@ pub fn init() -> Result<(), KernelError> {
         serial_println!("DEBUG: PIT timer initialized successfully");
     }
     
+    // Enumerate PCI devices
+    serial_println!("DEBUG: Scanning PCI bus");
+    if let Err(e) = pci::init() {
+        serial_println!("WARNING: Failed to initialize PCI: {:?}", e);
+    }
+    
+    // Network card is optional
+    match rtl8139::init() {
+        Ok(_) => serial_println!("DEBUG: RTL8139 network card initialized"),
+        Err(KernelError::DeviceNotFound) => serial_println!("DEBUG: No RTL8139 network card present"),
+        Err(e) => serial_println!("WARNING: Failed to initialize RTL8139: {:?}", e),
+    }
+    
     serial_println!("DEBUG: Essential drivers initialized");
     Ok(())
 }
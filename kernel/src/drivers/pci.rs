@@ -33,9 +33,90 @@ const PCI_BAR2: u8 = 0x18;
 const PCI_BAR3: u8 = 0x1C;
 const PCI_BAR4: u8 = 0x20;
 const PCI_BAR5: u8 = 0x24;
+const PCI_CAPABILITIES_PTR: u8 = 0x34;
 const PCI_INTERRUPT_LINE: u8 = 0x3C;
 const PCI_INTERRUPT_PIN: u8 = 0x3D;
 
+// PCI command register bits
+const PCI_COMMAND_IO_SPACE: u16 = 0x0001;      // Respond to I/O space accesses
+const PCI_COMMAND_MEMORY_SPACE: u16 = 0x0002;  // Respond to memory space accesses
+const PCI_COMMAND_BUS_MASTER: u16 = 0x0004;    // Allow the device to initiate DMA
+const PCI_COMMAND_INTX_DISABLE: u16 = 0x0400;  // Mask legacy INTx# interrupts
+
+// PCI status register bits
+const PCI_STATUS_CAP_LIST: u16 = 0x0010;       // Capabilities list present
+
+// Capability IDs
+pub const PCI_CAP_ID_PM: u8 = 0x01;       // Power management
+pub const PCI_CAP_ID_MSI: u8 = 0x05;      // Message Signaled Interrupts
+pub const PCI_CAP_ID_VENDOR: u8 = 0x09;   // Vendor specific
+pub const PCI_CAP_ID_PCIE: u8 = 0x10;     // PCI Express
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;     // MSI-X
+
+// Upper bound on list length, guards against malformed (looping) lists
+const PCI_MAX_CAPABILITIES: usize = 48;
+
+// MSI capability layout (offsets from the capability)
+const MSI_CONTROL: u8 = 0x02;
+const MSI_ADDRESS_LOW: u8 = 0x04;
+const MSI_ADDRESS_HIGH: u8 = 0x08;        // Only present on 64-bit capable devices
+const MSI_DATA_32: u8 = 0x08;
+const MSI_DATA_64: u8 = 0x0C;
+const MSI_CONTROL_ENABLE: u16 = 0x0001;
+const MSI_CONTROL_64BIT: u16 = 0x0080;
+const MSI_CONTROL_MME_MASK: u16 = 0x0070;  // Multiple Message Enable
+
+// xAPIC MSI address: 0xFEE in bits 31-20, destination APIC ID in bits 19-12
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+// BAR decoding
+const PCI_BAR_IO: u32 = 0x01;              // Bit 0: I/O space BAR
+const PCI_BAR_MEM_TYPE_MASK: u32 = 0x06;   // Bits 1-2: memory BAR type
+const PCI_BAR_MEM_TYPE_64: u32 = 0x04;     // 64-bit memory BAR
+const PCI_BAR_PREFETCHABLE: u32 = 0x08;    // Bit 3: prefetchable memory
+const PCI_BAR_IO_MASK: u32 = !0x03;
+const PCI_BAR_MEM_MASK: u32 = !0x0F;
+
+// Header types
+const PCI_HEADER_TYPE_MASK: u8 = 0x7F;
+const PCI_HEADER_MULTIFUNCTION: u8 = 0x80;
+const PCI_HEADER_TYPE_NORMAL: u8 = 0x00;
+const PCI_HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// Location of a function in PCI configuration space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self { bus, device, function }
+    }
+}
+
+/// A decoded Base Address Register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// I/O port range
+    Io { port: u16, len: u32 },
+    /// 32-bit memory-mapped range
+    Mem32 { addr: u32, len: u32, prefetch: bool },
+    /// 64-bit memory-mapped range (occupies two BAR slots)
+    Mem64 { addr: u64, len: u64, prefetch: bool },
+}
+
+/// An entry in a device's capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciCapability {
+    /// Capability ID (see the `PCI_CAP_ID_*` constants)
+    pub id: u8,
+    /// Offset of the capability in configuration space
+    pub offset: u8,
+}
+
 // PCI device information
 #[derive(Debug, Clone)]
 pub struct PciDeviceInfo {
@@ -52,6 +133,10 @@ pub struct PciDeviceInfo {
     pub interrupt_line: u8,
     pub interrupt_pin: u8,
     pub bar: [u32; 6],
+    /// Decoded BARs; the upper half of a 64-bit BAR is `None`
+    pub bars: [Option<Bar>; 6],
+    /// Capabilities found by walking the capability list
+    pub capabilities: Vec<PciCapability>,
 }
 
 impl PciDeviceInfo {
@@ -70,9 +155,21 @@ impl PciDeviceInfo {
             interrupt_line: 0,
             interrupt_pin: 0,
             bar: [0; 6],
+            bars: [None; 6],
+            capabilities: Vec::new(),
         }
     }
     
+    /// Configuration space address of this device
+    pub fn address(&self) -> PciAddress {
+        PciAddress::new(self.bus, self.device, self.function)
+    }
+    
+    /// Offset of the first capability with the given ID
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities.iter().find(|cap| cap.id == id).map(|cap| cap.offset)
+    }
+    
     pub fn device_type(&self) -> String {
         match (self.class_code, self.subclass) {
             (0x00, 0x00) => "Non-VGA-Compatible Unclassified Device".to_string(),
@@ -233,13 +330,26 @@ impl PciDriver {
     fn scan_bus(&mut self) {
         serial_println!("Scanning PCI bus for devices...");
         
+        // Start from an empty list so a rescan doesn't duplicate entries
+        self.devices.clear();
+        
         // Scan all buses, devices, and functions
         for bus in 0..256 {
             for device in 0..32 {
                 for function in 0..8 {
-                    if let Some(dev_info) = self.probe_device(bus as u8, device as u8, function as u8) {
-                        serial_println!("Found PCI device: {}", dev_info.description());
-                        self.devices.push(dev_info);
+                    match self.probe_device(bus as u8, device as u8, function as u8) {
+                        Some(dev_info) => {
+                            serial_println!("Found PCI device: {}", dev_info.description());
+                            let multifunction = dev_info.header_type & PCI_HEADER_MULTIFUNCTION != 0;
+                            self.devices.push(dev_info);
+                            
+                            // Single-function devices may mirror function 0 on the others
+                            if function == 0 && !multifunction {
+                                break;
+                            }
+                        }
+                        None if function == 0 => break,
+                        None => {}
                     }
                 }
             }
@@ -277,9 +387,159 @@ impl PciDriver {
         dev_info.bar[4] = self.read_config_u32(bus, device, function, PCI_BAR4);
         dev_info.bar[5] = self.read_config_u32(bus, device, function, PCI_BAR5);
         
+        // Decode and size the BARs this header type actually has
+        let bar_count = match dev_info.header_type & PCI_HEADER_TYPE_MASK {
+            PCI_HEADER_TYPE_NORMAL => 6,
+            PCI_HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        dev_info.bars = self.decode_bars(dev_info.address(), bar_count);
+        dev_info.capabilities = self.read_capabilities(dev_info.address());
+        
         Some(dev_info)
     }
     
+    /// Walk the capability list, if the device has one
+    fn read_capabilities(&mut self, addr: PciAddress) -> Vec<PciCapability> {
+        let mut capabilities = Vec::new();
+        
+        if self.read_u16(addr, PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+            return capabilities;
+        }
+        
+        let mut offset = self.read_u8(addr, PCI_CAPABILITIES_PTR) & 0xFC;
+        while offset != 0 && capabilities.len() < PCI_MAX_CAPABILITIES {
+            let id = self.read_u8(addr, offset);
+            capabilities.push(PciCapability { id, offset });
+            offset = self.read_u8(addr, offset + 1) & 0xFC;
+        }
+        
+        capabilities
+    }
+    
+    /// Program a device's MSI capability to deliver `vector` to the boot CPU
+    fn enable_msi(&mut self, addr: PciAddress, cap: u8, vector: u8) {
+        let mut control = self.read_u16(addr, cap + MSI_CONTROL);
+        
+        // Fixed delivery to this CPU's local APIC, single message
+        let apic_id = unsafe { core::arch::x86_64::__cpuid(1).ebx >> 24 };
+        self.write_u32(addr, cap + MSI_ADDRESS_LOW, MSI_ADDRESS_BASE | (apic_id << 12));
+        if control & MSI_CONTROL_64BIT != 0 {
+            self.write_u32(addr, cap + MSI_ADDRESS_HIGH, 0);
+            self.write_u16(addr, cap + MSI_DATA_64, vector as u16);
+        } else {
+            self.write_u16(addr, cap + MSI_DATA_32, vector as u16);
+        }
+        
+        control &= !MSI_CONTROL_MME_MASK;
+        self.write_u16(addr, cap + MSI_CONTROL, control | MSI_CONTROL_ENABLE);
+        
+        // Legacy INTx is redundant once MSI is on
+        self.set_command_bits(addr, PCI_COMMAND_INTX_DISABLE);
+    }
+    
+    /// Turn MSI off and hand interrupts back to INTx
+    fn disable_msi(&mut self, addr: PciAddress, cap: u8) {
+        let control = self.read_u16(addr, cap + MSI_CONTROL);
+        self.write_u16(addr, cap + MSI_CONTROL, control & !MSI_CONTROL_ENABLE);
+        
+        let command = self.read_u16(addr, PCI_COMMAND);
+        self.write_u16(addr, PCI_COMMAND, command & !PCI_COMMAND_INTX_DISABLE);
+    }
+    
+    /// Decode BARs, probing each one's size by writing all ones
+    fn decode_bars(&mut self, addr: PciAddress, count: usize) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+        
+        // Stop the device decoding while BARs temporarily hold all ones
+        let command = self.read_u16(addr, PCI_COMMAND);
+        self.write_u16(addr, PCI_COMMAND,
+            command & !(PCI_COMMAND_IO_SPACE | PCI_COMMAND_MEMORY_SPACE));
+        
+        let mut index = 0;
+        while index < count {
+            let offset = PCI_BAR0 + (index as u8) * 4;
+            let raw = self.read_u32(addr, offset);
+            let size_mask = self.probe_bar_size(addr, offset, raw);
+            
+            if raw & PCI_BAR_IO != 0 {
+                let mask = size_mask & PCI_BAR_IO_MASK & 0xFFFF;
+                if mask != 0 {
+                    bars[index] = Some(Bar::Io {
+                        port: (raw & PCI_BAR_IO_MASK) as u16,
+                        len: (!mask & 0xFFFF) + 1,
+                    });
+                }
+                index += 1;
+            } else if raw & PCI_BAR_MEM_TYPE_MASK == PCI_BAR_MEM_TYPE_64 && index + 1 < count {
+                let high_offset = offset + 4;
+                let raw_high = self.read_u32(addr, high_offset);
+                let size_high = self.probe_bar_size(addr, high_offset, raw_high);
+                
+                let mask = ((size_high as u64) << 32) | (size_mask & PCI_BAR_MEM_MASK) as u64;
+                if mask != 0 {
+                    bars[index] = Some(Bar::Mem64 {
+                        addr: ((raw_high as u64) << 32) | (raw & PCI_BAR_MEM_MASK) as u64,
+                        len: !mask + 1,
+                        prefetch: raw & PCI_BAR_PREFETCHABLE != 0,
+                    });
+                }
+                index += 2;
+            } else {
+                let mask = size_mask & PCI_BAR_MEM_MASK;
+                if mask != 0 {
+                    bars[index] = Some(Bar::Mem32 {
+                        addr: raw & PCI_BAR_MEM_MASK,
+                        len: !mask + 1,
+                        prefetch: raw & PCI_BAR_PREFETCHABLE != 0,
+                    });
+                }
+                index += 1;
+            }
+        }
+        
+        self.write_u16(addr, PCI_COMMAND, command);
+        bars
+    }
+    
+    /// Write all ones to a BAR, read back the size mask, then restore it
+    fn probe_bar_size(&mut self, addr: PciAddress, offset: u8, original: u32) -> u32 {
+        self.write_u32(addr, offset, 0xFFFF_FFFF);
+        let mask = self.read_u32(addr, offset);
+        self.write_u32(addr, offset, original);
+        mask
+    }
+    
+    fn read_u8(&mut self, addr: PciAddress, offset: u8) -> u8 {
+        self.read_config_u8(addr.bus, addr.device, addr.function, offset)
+    }
+    
+    fn read_u16(&mut self, addr: PciAddress, offset: u8) -> u16 {
+        self.read_config_u16(addr.bus, addr.device, addr.function, offset)
+    }
+    
+    fn read_u32(&mut self, addr: PciAddress, offset: u8) -> u32 {
+        self.read_config_u32(addr.bus, addr.device, addr.function, offset)
+    }
+    
+    fn write_u8(&mut self, addr: PciAddress, offset: u8, value: u8) {
+        self.write_config_u8(addr.bus, addr.device, addr.function, offset, value)
+    }
+    
+    fn write_u16(&mut self, addr: PciAddress, offset: u8, value: u16) {
+        self.write_config_u16(addr.bus, addr.device, addr.function, offset, value)
+    }
+    
+    fn write_u32(&mut self, addr: PciAddress, offset: u8, value: u32) {
+        self.write_config_u32(addr.bus, addr.device, addr.function, offset, value)
+    }
+    
+    /// Set bits in a device's command register
+    fn set_command_bits(&mut self, addr: PciAddress, bits: u16) {
+        let command = self.read_u16(addr, PCI_COMMAND);
+        self.write_u16(addr, PCI_COMMAND, command | bits);
+    }
+    
     fn read_config_u8(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u8 {
         let address = self.get_address(bus, device, function, offset);
         unsafe {
@@ -304,6 +564,26 @@ impl PciDriver {
         }
     }
     
+    // Byte and word writes go to just those bytes of the data port. A
+    // read-modify-write of the whole dword would write back its other
+    // registers too, and writing back Status beside Command clears its
+    // write-1-to-clear error bits.
+    fn write_config_u8(&mut self, bus: u8, device: u8, function: u8, offset: u8, value: u8) {
+        let address = self.get_address(bus, device, function, offset);
+        unsafe {
+            self.config_addr.write(address);
+            Port::<u8>::new(PCI_CONFIG_DATA + u16::from(offset & 3)).write(value);
+        }
+    }
+    
+    fn write_config_u16(&mut self, bus: u8, device: u8, function: u8, offset: u8, value: u16) {
+        let address = self.get_address(bus, device, function, offset);
+        unsafe {
+            self.config_addr.write(address);
+            Port::<u16>::new(PCI_CONFIG_DATA + u16::from(offset & 2)).write(value);
+        }
+    }
+    
     fn write_config_u32(&mut self, bus: u8, device: u8, function: u8, offset: u8, value: u32) {
         let address = self.get_address(bus, device, function, offset);
         unsafe {
@@ -357,6 +637,102 @@ pub fn init() -> Result<(), KernelError> {
     Ok(())
 }
 
+/// Rescan the bus, replacing the current device list
+pub fn rescan() {
+    PCI.lock().scan_bus();
+}
+
+/// Read an 8-bit value from configuration space
+pub fn read_u8(addr: PciAddress, offset: u8) -> u8 {
+    PCI.lock().read_u8(addr, offset)
+}
+
+/// Read a 16-bit value from configuration space
+pub fn read_u16(addr: PciAddress, offset: u8) -> u16 {
+    PCI.lock().read_u16(addr, offset)
+}
+
+/// Read a 32-bit value from configuration space
+pub fn read_u32(addr: PciAddress, offset: u8) -> u32 {
+    PCI.lock().read_u32(addr, offset)
+}
+
+/// Write an 8-bit value to configuration space
+pub fn write_u8(addr: PciAddress, offset: u8, value: u8) {
+    PCI.lock().write_u8(addr, offset, value)
+}
+
+/// Write a 16-bit value to configuration space
+pub fn write_u16(addr: PciAddress, offset: u8, value: u16) {
+    PCI.lock().write_u16(addr, offset, value)
+}
+
+/// Write a 32-bit value to configuration space
+pub fn write_u32(addr: PciAddress, offset: u8, value: u32) {
+    PCI.lock().write_u32(addr, offset, value)
+}
+
+/// Allow the device to perform DMA
+pub fn enable_bus_master(dev: &PciDeviceInfo) {
+    PCI.lock().set_command_bits(dev.address(), PCI_COMMAND_BUS_MASTER);
+}
+
+/// Allow the device to respond to memory-mapped BAR accesses
+pub fn enable_memory_space(dev: &PciDeviceInfo) {
+    PCI.lock().set_command_bits(dev.address(), PCI_COMMAND_MEMORY_SPACE);
+}
+
+/// Allow the device to respond to I/O port BAR accesses
+pub fn enable_io_space(dev: &PciDeviceInfo) {
+    PCI.lock().set_command_bits(dev.address(), PCI_COMMAND_IO_SPACE);
+}
+
+/// Route a device's MSI to an already-allocated IDT vector
+pub fn enable_msi(dev: &PciDeviceInfo, vector: u8) -> Result<(), KernelError> {
+    let cap = dev.find_capability(PCI_CAP_ID_MSI).ok_or(KernelError::UnsupportedFeature)?;
+    
+    // MSI writes land in the local APIC, which must be the one acknowledging them
+    if !crate::interrupts::apic::is_apic_available() {
+        serial_println!("PCI: MSI for {:02x}:{:02x}.{} needs the local APIC, which is disabled",
+            dev.bus, dev.device, dev.function);
+        return Err(KernelError::UnsupportedFeature);
+    }
+    
+    PCI.lock().enable_msi(dev.address(), cap, vector);
+    serial_println!("PCI: MSI enabled for {:02x}:{:02x}.{} on vector {:#x}",
+        dev.bus, dev.device, dev.function, vector);
+    Ok(())
+}
+
+/// Disable a device's MSI
+pub fn disable_msi(dev: &PciDeviceInfo) -> Result<(), KernelError> {
+    let cap = dev.find_capability(PCI_CAP_ID_MSI).ok_or(KernelError::UnsupportedFeature)?;
+    PCI.lock().disable_msi(dev.address(), cap);
+    Ok(())
+}
+
+/// Allocate a vector, install `handler` on it and wire the device's MSI to it
+pub fn request_msi(dev: &PciDeviceInfo, handler: crate::interrupts::IrqHandler) -> Result<u8, KernelError> {
+    if dev.find_capability(PCI_CAP_ID_MSI).is_none() {
+        return Err(KernelError::UnsupportedFeature);
+    }
+    
+    let vector = crate::interrupts::allocate_vector(handler)?;
+    if let Err(e) = enable_msi(dev, vector) {
+        crate::interrupts::free_vector(vector);
+        return Err(e);
+    }
+    
+    Ok(vector)
+}
+
+/// Undo `request_msi`: disable MSI on the device and release its vector
+pub fn release_msi(dev: &PciDeviceInfo, vector: u8) -> Result<(), KernelError> {
+    disable_msi(dev)?;
+    crate::interrupts::free_vector(vector);
+    Ok(())
+}
+
 /// Find a PCI device by class and subclass
 pub fn find_device_by_class(class_code: u8, subclass: u8) -> Option<PciDeviceInfo> {
     PCI.lock().find_device_by_class(class_code, subclass).cloned()
@@ -3,6 +3,7 @@
 
 use spin::Mutex;
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use x86_64::instructions::port::{Port, PortWriteOnly};
 use x86_64::structures::idt::InterruptStackFrame;
 use crate::errors::KernelError;
@@ -13,21 +14,42 @@ const PIT_CHANNEL0: u16 = 0x40;  // Channel 0 data port
 const PIT_CHANNEL1: u16 = 0x41;  // Channel 1 data port
 const PIT_CHANNEL2: u16 = 0x42;  // Channel 2 data port
 const PIT_COMMAND: u16 = 0x43;   // Mode/Command register
+const PIT_CHANNEL2_GATE: u16 = 0x61;  // Channel 2 gate / speaker control
 
 // PIT commands
 const PIT_CMD_CHANNEL0: u8 = 0x00;  // Select channel 0
+const PIT_CMD_CHANNEL2: u8 = 0x80;  // Select channel 2
 const PIT_CMD_LATCH: u8 = 0x00;     // Latch count value command
 const PIT_CMD_ACCESS_BOTH: u8 = 0x30;  // Access mode: low byte then high byte
+const PIT_CMD_MODE0: u8 = 0x00;     // Mode 0: interrupt on terminal count
 const PIT_CMD_MODE2: u8 = 0x04;     // Mode 2: rate generator
 const PIT_CMD_MODE3: u8 = 0x06;     // Mode 3: square wave generator
 
 // PIT parameters
 const PIT_FREQUENCY: u32 = 1193182;  // Base frequency (Hz)
-static mut CURRENT_FREQUENCY: u32 = 0;
-static mut MS_PER_TICK: u32 = 0;
 
-// Tick counter
-static mut TICKS: u64 = 0;
+// Busy-wait calibration
+const CALIBRATION_LOOPS: u64 = 100_000;       // Spin iterations timed against channel 2
+const CALIBRATION_MAX_ATTEMPTS: u32 = 4;      // Retries with fewer loops if channel 2 expires
+const DEFAULT_LOOPS_PER_MS: u64 = 100_000;    // Conservative guess before calibration
+
+/// Currently programmed tick frequency (Hz)
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Tick counter, incremented by the timer interrupt
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Uptime (microseconds) accumulated before the last frequency change
+static BASE_UPTIME_US: AtomicU64 = AtomicU64::new(0);
+
+/// Tick count at the last frequency change
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Spin-loop iterations per millisecond, measured at init
+static LOOPS_PER_MS: AtomicU64 = AtomicU64::new(DEFAULT_LOOPS_PER_MS);
+
+/// Uptime (ms) at which `poll` silences the PC speaker; 0 when it's off
+static SPEAKER_OFF_AT_MS: AtomicU64 = AtomicU64::new(0);
 
 // PIT driver structure
 struct PitDriver {
@@ -50,6 +72,8 @@ impl PitDriver {
             return Err(KernelError::InvalidParameter);
         }
 
+        self.initialized = true;
+
         // Calculate divisor
         let divisor = 1193180 / frequency;
         
@@ -71,18 +95,19 @@ impl PitDriver {
     }
     
     fn set_frequency(&mut self, frequency: u32) -> Result<(), KernelError> {
+        if frequency == 0 {
+            return Err(KernelError::InvalidParameter);
+        }
+
         // Calculate divisor
         let divisor = PIT_FREQUENCY / frequency;
         
-        if divisor > 65535 {
+        if divisor == 0 || divisor > 65535 {
             return Err(KernelError::InvalidParameter);
         }
         
-        // Save the configured frequency and ms per tick
-        unsafe {
-            CURRENT_FREQUENCY = frequency;
-            MS_PER_TICK = 1000 / frequency;
-        }
+        // Fold the elapsed time into the base so uptime doesn't jump
+        rebase(frequency);
         
         // Send the command byte
         let command = PIT_CMD_CHANNEL0 | PIT_CMD_ACCESS_BOTH | PIT_CMD_MODE3;
@@ -97,16 +122,98 @@ impl PitDriver {
         
         Ok(())
     }
+
+    /// Time a fixed spin loop against channel 2 and return loops per millisecond
+    fn calibrate(&mut self) -> Option<u64> {
+        let mut gate: Port<u8> = Port::new(PIT_CHANNEL2_GATE);
+        let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+        let mut loops = CALIBRATION_LOOPS;
+
+        for _ in 0..CALIBRATION_MAX_ATTEMPTS {
+            unsafe {
+                // Gate channel 2 on, keep the speaker disconnected
+                let value = gate.read();
+                gate.write((value & !0x02) | 0x01);
+
+                // One-shot countdown from the maximum count
+                self.command_port.write(PIT_CMD_CHANNEL2 | PIT_CMD_ACCESS_BOTH | PIT_CMD_MODE0);
+                channel2.write(0xFF);
+                channel2.write(0xFF);
+
+                spin(loops);
+
+                // Latch and read the remaining count
+                self.command_port.write(PIT_CMD_CHANNEL2 | PIT_CMD_LATCH);
+                let low = channel2.read() as u32;
+                let high = channel2.read() as u32;
+                let remaining = (high << 8) | low;
+
+                // OUT2 goes high once the count reaches zero; the reading is useless then
+                let expired = gate.read() & 0x20 != 0;
+                gate.write(value);
+
+                let elapsed = 0xFFFF - remaining;
+                if !expired && elapsed > 0 {
+                    let loops_per_ms = loops * PIT_FREQUENCY as u64 / (elapsed as u64 * 1000);
+                    if loops_per_ms > 0 {
+                        return Some(loops_per_ms);
+                    }
+                }
+            }
+
+            loops /= 8;
+        }
+
+        None
+    }
+}
+
+/// Spin for the given number of loop iterations
+#[inline(never)]
+fn spin(loops: u64) {
+    for _ in 0..loops {
+        core::hint::spin_loop();
+    }
+}
+
+/// Record a new tick frequency, carrying the uptime elapsed at the old one
+fn rebase(frequency: u32) {
+    let ticks = TICKS.load(Ordering::SeqCst);
+    let uptime_us = uptime_us_at(ticks);
+    BASE_UPTIME_US.store(uptime_us, Ordering::SeqCst);
+    BASE_TICKS.store(ticks, Ordering::SeqCst);
+    FREQUENCY.store(frequency, Ordering::SeqCst);
+}
+
+/// Uptime in microseconds for the given tick count
+fn uptime_us_at(ticks: u64) -> u64 {
+    let frequency = FREQUENCY.load(Ordering::SeqCst) as u64;
+    let base = BASE_UPTIME_US.load(Ordering::SeqCst);
+    if frequency == 0 {
+        return base;
+    }
+    let elapsed = ticks.saturating_sub(BASE_TICKS.load(Ordering::SeqCst));
+    base + elapsed * 1_000_000 / frequency
 }
 
 lazy_static! {
     static ref PIT: Mutex<PitDriver> = Mutex::new(PitDriver::new());
 }
 
+/// Advance the tick counter; called from the timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    #[cfg(feature = "heap_debug")]
+    crate::allocator::timer_tick(ticks());
+}
+
 /// PIT interrupt handler - called on timer tick
 pub extern "x86-interrupt" fn pit_interrupt_handler(
     _stack_frame: InterruptStackFrame
 ) {
+    let _handler = crate::interrupts::enter_handler();
+    tick();
+    
     // TODO: Implement proper PIC handling
     // For now, we'll skip sending EOI since we're in safe mode
 }
@@ -115,13 +222,21 @@ pub extern "x86-interrupt" fn pit_interrupt_handler(
 pub fn init(frequency: u32) -> Result<(), KernelError> {
     serial_println!("Initializing PIT (Programmable Interval Timer) at {} Hz", frequency);
     
+    let mut pit = PIT.lock();
+    
     // Initialize the PIT with the specified frequency
-    PIT.lock().init(frequency)?;
+    pit.init(frequency)?;
+    rebase(frequency);
     
-    // Set current frequency and timing values
-    unsafe {
-        CURRENT_FREQUENCY = frequency;
-        MS_PER_TICK = 1000 / frequency;
+    // Calibrate busy_sleep_us for contexts where interrupts aren't running yet
+    match pit.calibrate() {
+        Some(loops_per_ms) => {
+            LOOPS_PER_MS.store(loops_per_ms, Ordering::SeqCst);
+            serial_println!("PIT: busy-wait calibrated at {} loops/ms", loops_per_ms);
+        }
+        None => {
+            serial_println!("PIT: busy-wait calibration failed, using {} loops/ms", DEFAULT_LOOPS_PER_MS);
+        }
     }
     
     Ok(())
@@ -132,28 +247,94 @@ pub fn set_frequency(frequency: u32) -> Result<(), KernelError> {
     PIT.lock().set_frequency(frequency)
 }
 
+/// Get the number of timer ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Get the current tick frequency in Hz (0 if the PIT isn't initialized)
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::SeqCst)
+}
+
+/// Get system uptime in milliseconds
+pub fn uptime_ms() -> u64 {
+    uptime_us_at(ticks()) / 1000
+}
+
+/// Get system uptime in microseconds, at tick resolution
+pub fn uptime_us() -> u64 {
+    uptime_us_at(ticks())
+}
+
+/// Busy-wait for the given number of microseconds without relying on interrupts
+pub fn busy_sleep_us(us: u64) {
+    let loops = us * LOOPS_PER_MS.load(Ordering::SeqCst) / 1000;
+    spin(loops.max(1));
+}
+
+/// Sound `frequency` Hz on the PC speaker for `duration_ms`. Returns at
+/// once; `poll` turns the speaker off when the time is up.
+pub fn beep(frequency: u32, duration_ms: u64) {
+    if frequency == 0 || PIT_FREQUENCY / frequency > 0xFFFF {
+        return;
+    }
+    let divisor = PIT_FREQUENCY / frequency;
+    let _pit = PIT.lock();
+    let mut command: PortWriteOnly<u8> = PortWriteOnly::new(PIT_COMMAND);
+    let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+    let mut gate: Port<u8> = Port::new(PIT_CHANNEL2_GATE);
+    unsafe {
+        // Channel 2 makes the square wave; the gate connects it to the speaker
+        command.write(PIT_CMD_CHANNEL2 | PIT_CMD_ACCESS_BOTH | PIT_CMD_MODE3);
+        channel2.write((divisor & 0xFF) as u8);
+        channel2.write(((divisor >> 8) & 0xFF) as u8);
+        let value = gate.read();
+        gate.write(value | 0x03);
+    }
+    SPEAKER_OFF_AT_MS.store(uptime_ms() + duration_ms.max(1), Ordering::SeqCst);
+}
+
+/// Silence the PC speaker if its beep is over; call from the main loop
+pub fn poll() {
+    let off_at = SPEAKER_OFF_AT_MS.load(Ordering::SeqCst);
+    if off_at == 0 || uptime_ms() < off_at {
+        return;
+    }
+    SPEAKER_OFF_AT_MS.store(0, Ordering::SeqCst);
+    let _pit = PIT.lock();
+    let mut gate: Port<u8> = Port::new(PIT_CHANNEL2_GATE);
+    unsafe {
+        let value = gate.read();
+        gate.write(value & !0x03);
+    }
+}
+
 /// Get the current system tick count
 pub fn get_ticks() -> u64 {
-    unsafe { TICKS }
+    ticks()
 }
 
 /// Get system uptime in milliseconds
 pub fn get_uptime_ms() -> u64 {
-    unsafe { TICKS * MS_PER_TICK as u64 }
+    uptime_ms()
 }
 
 /// Sleep for a number of milliseconds
 pub fn sleep_ms(ms: u32) {
-    let start_ticks = get_ticks();
-    let ticks_to_wait = (ms as u64 * unsafe { CURRENT_FREQUENCY as u64 }) / 1000;
+    use crate::task::trace::{self, Reason};
+    let start = uptime_ms();
+    let task = crate::task::scheduler::try_current_task_id().unwrap_or(trace::NO_TASK);
+    trace::record(task, trace::NO_TASK, Reason::Sleep);
     
-    while get_ticks() - start_ticks < ticks_to_wait {
+    while uptime_ms() - start < ms as u64 {
         // Use the CPU's HLT instruction to pause until the next interrupt
         x86_64::instructions::hlt();
     }
+    trace::record(trace::NO_TASK, task, Reason::Wake);
 }
 
 /// Sleep for a number of seconds
 pub fn sleep(seconds: u32) {
     sleep_ms(seconds * 1000);
-}
\ No newline at end of file
+}
@@ -1,14 +1,15 @@
 //! PS/2 keyboard driver
 //! Handles keyboard input via the PS/2 controller
 
-use core::sync::atomic::{AtomicBool, Ordering};
-use alloc::collections::VecDeque;
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::sync::DiagMutex;
 use lazy_static::lazy_static;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 use x86_64::structures::idt::InterruptStackFrame;
 use crate::errors::{KernelError, DeviceError};
-use crate::serial_println;
+use crate::{isr_println, serial_println};
+use super::event_queue::EventQueue;
+use super::key_repeat::{self, KeyRepeat};
 
 // PS/2 controller ports
 const PS2_DATA_PORT: u16 = 0x60;
@@ -27,6 +28,8 @@ const PS2_TEST_FIRST_PORT: u8 = 0xAB;
 const PS2_RESET_DEVICE: u8 = 0xFF;
 const PS2_ENABLE_SCANNING: u8 = 0xF4;
 const PS2_SET_DEFAULTS: u8 = 0xF6;
+const PS2_SET_TYPEMATIC: u8 = 0xF3;
+const PS2_ACK: u8 = 0xFA;
 
 // Keyboard status flags
 const KB_OUTPUT_FULL: u8 = 1 << 0;
@@ -57,7 +60,10 @@ pub enum KeyCode {
     Keypad_7, Keypad_8, Keypad_9, Keypad_Minus,
     Keypad_4, Keypad_5, Keypad_6, Keypad_Plus,
     Keypad_1, Keypad_2, Keypad_3, Keypad_0, Keypad_Decimal,
-    // Extended keys and special keys
+    // Extended keys (sent after an 0xE0 prefix)
+    ArrowUp, ArrowDown, ArrowLeft, ArrowRight,
+    Home, End, PageUp, PageDown, Insert, Delete, PrintScreen,
+    // Special keys
     Unknown = 0xFF
 }
 
@@ -74,22 +80,108 @@ pub struct KeyEvent {
     pub shift: bool,
     pub ctrl: bool,
     pub alt: bool,
+    /// When the event was queued, in milliseconds since boot on the
+    /// monotonic clock; 0 before the timer runs
+    pub time_ms: u64,
+}
+
+impl KeyEvent {
+    /// Character a key produces on a US layout, if it is printable
+    pub fn to_char(&self) -> Option<char> {
+        let key_code = self.code;
+        let shift = self.shift;
+        
+        match key_code {
+            KeyCode::Key1 => Some(if shift { '!' } else { '1' }),
+            KeyCode::Key2 => Some(if shift { '@' } else { '2' }),
+            KeyCode::Key3 => Some(if shift { '#' } else { '3' }),
+            KeyCode::Key4 => Some(if shift { '$' } else { '4' }),
+            KeyCode::Key5 => Some(if shift { '%' } else { '5' }),
+            KeyCode::Key6 => Some(if shift { '^' } else { '6' }),
+            KeyCode::Key7 => Some(if shift { '&' } else { '7' }),
+            KeyCode::Key8 => Some(if shift { '*' } else { '8' }),
+            KeyCode::Key9 => Some(if shift { '(' } else { '9' }),
+            KeyCode::Key0 => Some(if shift { ')' } else { '0' }),
+            KeyCode::A => Some(if shift { 'A' } else { 'a' }),
+            KeyCode::B => Some(if shift { 'B' } else { 'b' }),
+            KeyCode::C => Some(if shift { 'C' } else { 'c' }),
+            KeyCode::D => Some(if shift { 'D' } else { 'd' }),
+            KeyCode::E => Some(if shift { 'E' } else { 'e' }),
+            KeyCode::F => Some(if shift { 'F' } else { 'f' }),
+            KeyCode::G => Some(if shift { 'G' } else { 'g' }),
+            KeyCode::H => Some(if shift { 'H' } else { 'h' }),
+            KeyCode::I => Some(if shift { 'I' } else { 'i' }),
+            KeyCode::J => Some(if shift { 'J' } else { 'j' }),
+            KeyCode::K => Some(if shift { 'K' } else { 'k' }),
+            KeyCode::L => Some(if shift { 'L' } else { 'l' }),
+            KeyCode::M => Some(if shift { 'M' } else { 'm' }),
+            KeyCode::N => Some(if shift { 'N' } else { 'n' }),
+            KeyCode::O => Some(if shift { 'O' } else { 'o' }),
+            KeyCode::P => Some(if shift { 'P' } else { 'p' }),
+            KeyCode::Q => Some(if shift { 'Q' } else { 'q' }),
+            KeyCode::R => Some(if shift { 'R' } else { 'r' }),
+            KeyCode::S => Some(if shift { 'S' } else { 's' }),
+            KeyCode::T => Some(if shift { 'T' } else { 't' }),
+            KeyCode::U => Some(if shift { 'U' } else { 'u' }),
+            KeyCode::V => Some(if shift { 'V' } else { 'v' }),
+            KeyCode::W => Some(if shift { 'W' } else { 'w' }),
+            KeyCode::X => Some(if shift { 'X' } else { 'x' }),
+            KeyCode::Y => Some(if shift { 'Y' } else { 'y' }),
+            KeyCode::Z => Some(if shift { 'Z' } else { 'z' }),
+            KeyCode::Space => Some(' '),
+            KeyCode::Minus => Some(if shift { '_' } else { '-' }),
+            KeyCode::Equals => Some(if shift { '+' } else { '=' }),
+            KeyCode::LeftBracket => Some(if shift { '{' } else { '[' }),
+            KeyCode::RightBracket => Some(if shift { '}' } else { ']' }),
+            KeyCode::Backslash => Some(if shift { '|' } else { '\\' }),
+            KeyCode::Semicolon => Some(if shift { ':' } else { ';' }),
+            KeyCode::Apostrophe => Some(if shift { '"' } else { '\'' }),
+            KeyCode::Backtick => Some(if shift { '~' } else { '`' }),
+            KeyCode::Comma => Some(if shift { '<' } else { ',' }),
+            KeyCode::Period => Some(if shift { '>' } else { '.' }),
+            KeyCode::Slash => Some(if shift { '?' } else { '/' }),
+            KeyCode::Keypad_Multiply => Some('*'),
+            KeyCode::Keypad_Minus => Some('-'),
+            KeyCode::Keypad_Plus => Some('+'),
+            KeyCode::Keypad_Decimal => Some('.'),
+            KeyCode::Keypad_0 => Some('0'),
+            KeyCode::Keypad_1 => Some('1'),
+            KeyCode::Keypad_2 => Some('2'),
+            KeyCode::Keypad_3 => Some('3'),
+            KeyCode::Keypad_4 => Some('4'),
+            KeyCode::Keypad_5 => Some('5'),
+            KeyCode::Keypad_6 => Some('6'),
+            KeyCode::Keypad_7 => Some('7'),
+            KeyCode::Keypad_8 => Some('8'),
+            KeyCode::Keypad_9 => Some('9'),
+            _ => None,
+        }
+    }
 }
 
 // Global keyboard state
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
+    static ref KEYBOARD: DiagMutex<Keyboard> = DiagMutex::new("ps2_keyboard::KEYBOARD", Keyboard::new());
 }
 
 static SHIFT_PRESSED: AtomicBool = AtomicBool::new(false);
 static CTRL_PRESSED: AtomicBool = AtomicBool::new(false);
 static ALT_PRESSED: AtomicBool = AtomicBool::new(false);
 
+/// Key events held for readers
+const QUEUE_CAPACITY: usize = 16;
+
+/// Key events lost because the queue was full
+static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
 pub struct Keyboard {
     data_port: Port<u8>,
     status_port: PortReadOnly<u8>,
     command_port: PortWriteOnly<u8>,
-    event_queue: VecDeque<KeyEvent>,
+    event_queue: EventQueue<KeyEvent, QUEUE_CAPACITY>,
+    // The previous byte was the 0xE0 extended-key prefix
+    extended: bool,
+    repeat: KeyRepeat,
 }
 
 impl Keyboard {
@@ -98,7 +190,9 @@ impl Keyboard {
             data_port: Port::new(PS2_DATA_PORT),
             status_port: PortReadOnly::new(PS2_STATUS_PORT),
             command_port: PortWriteOnly::new(PS2_COMMAND_PORT),
-            event_queue: VecDeque::with_capacity(16),
+            event_queue: EventQueue::new(),
+            extended: false,
+            repeat: KeyRepeat::new(),
         }
     }
 
@@ -160,6 +254,17 @@ impl Keyboard {
         Err(KernelError::DeviceError(DeviceError::Timeout))
     }
 
+    /// Set the keyboard's own repeat delay and rate (0xF3)
+    fn set_typematic(&mut self, value: u8) -> Result<(), KernelError> {
+        for byte in [PS2_SET_TYPEMATIC, value] {
+            self.send_command(byte)?;
+            if self.wait_for_data() != PS2_ACK {
+                return Err(KernelError::DeviceError(DeviceError::NotResponding));
+            }
+        }
+        Ok(())
+    }
+
     fn wait_for_data(&mut self) -> u8 {
         let mut timeout = 10000;
         unsafe {
@@ -173,71 +278,115 @@ impl Keyboard {
         0 // Timeout occurred
     }
 
-    fn handle_scancode(&mut self, scancode: u8) {
+    /// Decode injected scancodes while the queue has room, unless a real
+    /// extended-key sequence is half received
+    fn feed_injected(&mut self) {
+        if self.extended {
+            return;
+        }
+        // An injected 0xE0 leaves `extended` set, so carry on to its key
+        while !self.event_queue.is_full() || self.extended {
+            match super::input::next_key() {
+                Some((scancode, time_ms)) => self.handle_scancode(scancode, time_ms),
+                None => break,
+            }
+        }
+    }
+    
+    /// Decode a scancode that arrived, or was injected, at `time_ms`
+    fn handle_scancode(&mut self, scancode: u8, time_ms: u64) {
+        if scancode == 0xE0 {
+            self.extended = true;
+            return;
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        
         // Determine if this is a key press or release
         let is_release = scancode & 0x80 != 0;
         let key_code = scancode & 0x7F;
         
         // Convert raw scancode to our KeyCode enum
-        let key = match key_code {
-            0x01 => KeyCode::Escape,
-            0x02 => KeyCode::Key1,
-            0x03 => KeyCode::Key2,
-            0x04 => KeyCode::Key3,
-            0x05 => KeyCode::Key4,
-            0x06 => KeyCode::Key5,
-            0x07 => KeyCode::Key6,
-            0x08 => KeyCode::Key7,
-            0x09 => KeyCode::Key8,
-            0x0A => KeyCode::Key9,
-            0x0B => KeyCode::Key0,
-            0x0C => KeyCode::Minus,
-            0x0D => KeyCode::Equals,
-            0x0E => KeyCode::Backspace,
-            0x0F => KeyCode::Tab,
-            0x10 => KeyCode::Q,
-            0x11 => KeyCode::W,
-            0x12 => KeyCode::E,
-            0x13 => KeyCode::R,
-            0x14 => KeyCode::T,
-            0x15 => KeyCode::Y,
-            0x16 => KeyCode::U,
-            0x17 => KeyCode::I,
-            0x18 => KeyCode::O,
-            0x19 => KeyCode::P,
-            0x1A => KeyCode::LeftBracket,
-            0x1B => KeyCode::RightBracket,
-            0x1C => KeyCode::Enter,
-            0x1D => KeyCode::LeftControl,
-            0x1E => KeyCode::A,
-            0x1F => KeyCode::S,
-            0x20 => KeyCode::D,
-            0x21 => KeyCode::F,
-            0x22 => KeyCode::G,
-            0x23 => KeyCode::H,
-            0x24 => KeyCode::J,
-            0x25 => KeyCode::K,
-            0x26 => KeyCode::L,
-            0x27 => KeyCode::Semicolon,
-            0x28 => KeyCode::Apostrophe,
-            0x29 => KeyCode::Backtick,
-            0x2A => KeyCode::LeftShift,
-            0x2B => KeyCode::Backslash,
-            0x2C => KeyCode::Z,
-            0x2D => KeyCode::X,
-            0x2E => KeyCode::C,
-            0x2F => KeyCode::V,
-            0x30 => KeyCode::B,
-            0x31 => KeyCode::N,
-            0x32 => KeyCode::M,
-            0x33 => KeyCode::Comma,
-            0x34 => KeyCode::Period,
-            0x35 => KeyCode::Slash,
-            0x36 => KeyCode::RightShift,
-            0x37 => KeyCode::Keypad_Multiply,
-            0x38 => KeyCode::LeftAlt,
-            0x39 => KeyCode::Space,
-            _ => KeyCode::Unknown,
+        let key = if extended {
+            match key_code {
+                0x1C => KeyCode::Enter, // Keypad Enter
+                0x1D => KeyCode::LeftControl, // Right Control
+                0x38 => KeyCode::LeftAlt, // Right Alt
+                0x35 => KeyCode::Slash, // Keypad /
+                0x37 => KeyCode::PrintScreen,
+                0x47 => KeyCode::Home,
+                0x48 => KeyCode::ArrowUp,
+                0x49 => KeyCode::PageUp,
+                0x4B => KeyCode::ArrowLeft,
+                0x4D => KeyCode::ArrowRight,
+                0x4F => KeyCode::End,
+                0x50 => KeyCode::ArrowDown,
+                0x51 => KeyCode::PageDown,
+                0x52 => KeyCode::Insert,
+                0x53 => KeyCode::Delete,
+                // Includes the fake shifts sent around Print Screen
+                _ => return,
+            }
+        } else {
+            match key_code {
+                0x01 => KeyCode::Escape,
+                0x02 => KeyCode::Key1,
+                0x03 => KeyCode::Key2,
+                0x04 => KeyCode::Key3,
+                0x05 => KeyCode::Key4,
+                0x06 => KeyCode::Key5,
+                0x07 => KeyCode::Key6,
+                0x08 => KeyCode::Key7,
+                0x09 => KeyCode::Key8,
+                0x0A => KeyCode::Key9,
+                0x0B => KeyCode::Key0,
+                0x0C => KeyCode::Minus,
+                0x0D => KeyCode::Equals,
+                0x0E => KeyCode::Backspace,
+                0x0F => KeyCode::Tab,
+                0x10 => KeyCode::Q,
+                0x11 => KeyCode::W,
+                0x12 => KeyCode::E,
+                0x13 => KeyCode::R,
+                0x14 => KeyCode::T,
+                0x15 => KeyCode::Y,
+                0x16 => KeyCode::U,
+                0x17 => KeyCode::I,
+                0x18 => KeyCode::O,
+                0x19 => KeyCode::P,
+                0x1A => KeyCode::LeftBracket,
+                0x1B => KeyCode::RightBracket,
+                0x1C => KeyCode::Enter,
+                0x1D => KeyCode::LeftControl,
+                0x1E => KeyCode::A,
+                0x1F => KeyCode::S,
+                0x20 => KeyCode::D,
+                0x21 => KeyCode::F,
+                0x22 => KeyCode::G,
+                0x23 => KeyCode::H,
+                0x24 => KeyCode::J,
+                0x25 => KeyCode::K,
+                0x26 => KeyCode::L,
+                0x27 => KeyCode::Semicolon,
+                0x28 => KeyCode::Apostrophe,
+                0x29 => KeyCode::Backtick,
+                0x2A => KeyCode::LeftShift,
+                0x2B => KeyCode::Backslash,
+                0x2C => KeyCode::Z,
+                0x2D => KeyCode::X,
+                0x2E => KeyCode::C,
+                0x2F => KeyCode::V,
+                0x30 => KeyCode::B,
+                0x31 => KeyCode::N,
+                0x32 => KeyCode::M,
+                0x33 => KeyCode::Comma,
+                0x34 => KeyCode::Period,
+                0x35 => KeyCode::Slash,
+                0x36 => KeyCode::RightShift,
+                0x37 => KeyCode::Keypad_Multiply,
+                0x38 => KeyCode::LeftAlt,
+                0x39 => KeyCode::Space,
+                _ => KeyCode::Unknown,
+            }
         };
 
         // Update modifier key states
@@ -261,11 +410,17 @@ impl Keyboard {
             shift: SHIFT_PRESSED.load(Ordering::SeqCst),
             ctrl: CTRL_PRESSED.load(Ordering::SeqCst),
             alt: ALT_PRESSED.load(Ordering::SeqCst),
+            time_ms,
         };
 
+        // A hardware repeat that software has already sent
+        if !self.repeat.observe(&event) {
+            return;
+        }
+
         // Add to event queue
-        if self.event_queue.len() < 16 {
-            self.event_queue.push_back(event);
+        if self.event_queue.push_back(event).is_err() {
+            DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
         }
         
         // Print debug info
@@ -311,7 +466,7 @@ impl Keyboard {
                 KeyCode::Space => ' ',
                 _ => '?',
             };
-            serial_println!("Key pressed: {:?} ({})", key, c);
+            isr_println!("Key pressed: {:?} ({})", key, c);
         }
     }
 }
@@ -320,9 +475,10 @@ impl Keyboard {
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame
 ) {
+    let _handler = crate::interrupts::enter_handler();
     unsafe {
         let scancode = Port::<u8>::new(PS2_DATA_PORT).read();
-        KEYBOARD.lock().handle_scancode(scancode);
+        KEYBOARD.lock().handle_scancode(scancode, crate::time::monotonic_ms());
         
         // Send EOI to PIC
         crate::interrupts::pic::PIC_CONTROLLER.lock().notify_end_of_interrupt(
@@ -337,6 +493,8 @@ pub fn init() -> Result<(), KernelError> {
     
     // Initialize keyboard hardware
     KEYBOARD.lock().init()?;
+    // The configuration isn't loaded yet; `configure` applies it later
+    reload_repeat("input.repeat_");
     
     // We don't need to register the interrupt handler here because
     // it's already set up in the IDT initialization in interrupts/mod.rs
@@ -345,16 +503,73 @@ pub fn init() -> Result<(), KernelError> {
     Ok(())
 }
 
+/// Apply `input.repeat_delay_ms` and `input.repeat_rate_cps` now and
+/// whenever they change, once the configuration is loaded
+pub fn configure() {
+    reload_repeat("input.repeat_");
+    crate::config::subscribe("input.repeat_", reload_repeat);
+}
+
+fn reload_repeat(_key: &str) {
+    let setting = |key: &str, default: u64| crate::config::get(key)
+        .and_then(|value| value.try_as_integer())
+        .and_then(|value| u64::try_from(value).ok())
+        .unwrap_or(default);
+    let delay_ms = setting("input.repeat_delay_ms", 500);
+    let rate_cps = setting("input.repeat_rate_cps", 20);
+
+    let mut keyboard = KEYBOARD.lock();
+    keyboard.repeat.set_timing(delay_ms, rate_cps);
+    // The interrupt handler mustn't take the acknowledgements
+    let result = x86_64::instructions::interrupts::without_interrupts(|| {
+        keyboard.set_typematic(key_repeat::typematic_byte(delay_ms, rate_cps))
+    });
+    if let Err(e) = result {
+        serial_println!("DEBUG: Keyboard repeat rate not set, repeating in software only: {:?}", e);
+    }
+}
+
+/// Forget queued keys, modifiers held and any half-received scancode, as
+/// after a resume, when none of them still reflect the keyboard
+pub fn reset_state() {
+    let mut keyboard = KEYBOARD.lock();
+    keyboard.event_queue.clear();
+    keyboard.extended = false;
+    keyboard.repeat.clear();
+    SHIFT_PRESSED.store(false, Ordering::SeqCst);
+    CTRL_PRESSED.store(false, Ordering::SeqCst);
+    ALT_PRESSED.store(false, Ordering::SeqCst);
+}
+
+/// Key events dropped since boot because nobody read the queue
+pub fn dropped_events() -> u64 {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
 /// Get the next keyboard event, if any
 pub fn get_event() -> Option<KeyEvent> {
     // Removed SAFE MODE direct port reading logic.
     // Relies on interrupt handler populating the queue.
     
-    // Try to get an event from the queue
-    let event = KEYBOARD.lock().event_queue.pop_front();
+    // Try to get an event from the queue, topped up with injected input
+    let event = {
+        let mut keyboard = KEYBOARD.lock();
+        keyboard.feed_injected();
+        match keyboard.event_queue.pop_front() {
+            Some(event) => Some(event),
+            // Nothing from the keyboard, so a held key may be due a repeat
+            None => keyboard.repeat.due(crate::time::monotonic_ms()).map(|event| KeyEvent {
+                shift: SHIFT_PRESSED.load(Ordering::SeqCst),
+                ctrl: CTRL_PRESSED.load(Ordering::SeqCst),
+                alt: ALT_PRESSED.load(Ordering::SeqCst),
+                ..event
+            }),
+        }
+    };
     
     // Log if we're returning an event
     if let Some(ref e) = event {
+        super::input::note_event(e.time_ms);
         serial_println!("DEBUG: Keyboard returning event: code={:?}, state={:?}, shift={}, ctrl={}, alt={}", 
             e.code, e.state, e.shift, e.ctrl, e.alt);
     }
@@ -414,7 +629,7 @@ pub fn direct_handle_scancode(scancode: u8) {
     while try_count < 3 {
         if let Some(mut kb) = KEYBOARD.try_lock() {
             // Successfully acquired the lock, process normally
-            kb.handle_scancode(scancode);
+            kb.handle_scancode(scancode, crate::time::monotonic_ms());
             break;
         }
         try_count += 1;
@@ -1,14 +1,21 @@
 //! PS/2 mouse driver
 //! Handles mouse input via the PS/2 controller
+//!
+//! Movement from the mouse is scaled by `input.mouse_sensitivity` (a
+//! percentage) and sped up by `input.mouse_accel` the faster it moves,
+//! in whole-number arithmetic since interrupt handlers can't use the FPU.
+//! Injected movement is taken as it is, so replays and scripted clicks
+//! land where they were aimed. The pointer stays inside the bounds the
+//! GUI sets for its display.
 
-use core::sync::atomic::{AtomicBool, Ordering};
-use alloc::collections::VecDeque;
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use crate::sync::DiagMutex;
 use lazy_static::lazy_static;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 use x86_64::structures::idt::InterruptStackFrame;
 use crate::errors::{KernelError, DeviceError};
-use crate::serial_println;
+use crate::{isr_println, serial_println};
+use super::event_queue::EventQueue;
 
 // PS/2 controller ports
 const PS2_DATA_PORT: u16 = 0x60;
@@ -48,6 +55,30 @@ const MOUSE_Y_SIGN: u8 = 0x20;
 const MOUSE_X_OVERFLOW: u8 = 0x40;
 const MOUSE_Y_OVERFLOW: u8 = 0x80;
 
+// Maximum gap between two left-button presses to count as a double-click
+const DOUBLE_CLICK_MS: u64 = 400;
+
+/// Sensitivity, as a percentage of the mouse's own movement
+pub const DEFAULT_SENSITIVITY: u32 = 100;
+pub const MIN_SENSITIVITY: u32 = 10;
+pub const MAX_SENSITIVITY: u32 = 1000;
+/// Acceleration: the gain added, in percent, for every ACCEL_STEP counts a
+/// report moves beyond ACCEL_THRESHOLD; 0 turns it off
+pub const DEFAULT_ACCEL: u32 = 0;
+pub const MAX_ACCEL: u32 = 100;
+const ACCEL_THRESHOLD: i64 = 4;
+const ACCEL_STEP: i64 = 8;
+/// Most acceleration can multiply movement by, in percent
+const MAX_ACCEL_GAIN: i64 = 400;
+
+/// Pointer bounds until the GUI gives its own: 80x25 cells of 8x16 pixels
+const DEFAULT_BOUNDS: (u16, u16) = (640, 400);
+
+static SENSITIVITY: AtomicU32 = AtomicU32::new(DEFAULT_SENSITIVITY);
+static ACCEL: AtomicU32 = AtomicU32::new(DEFAULT_ACCEL);
+static BOUNDS_WIDTH: AtomicU16 = AtomicU16::new(DEFAULT_BOUNDS.0);
+static BOUNDS_HEIGHT: AtomicU16 = AtomicU16::new(DEFAULT_BOUNDS.1);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MouseState {
     pub x: i16,
@@ -109,23 +140,36 @@ pub struct MouseEvent {
     pub dx: i8,
     pub dy: i8,
     pub buttons: MouseButtons,
+    pub double_click: bool,
+    /// When the event was queued, in milliseconds since boot on the
+    /// monotonic clock; 0 before the timer runs
+    pub time_ms: u64,
 }
 
 // Global mouse state
 lazy_static! {
-    static ref MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+    static ref MOUSE: DiagMutex<Mouse> = DiagMutex::new("ps2_mouse::MOUSE", Mouse::new());
 }
 
 static MOUSE_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
+/// Mouse events held for readers
+const QUEUE_CAPACITY: usize = 16;
+
+/// Mouse events lost because the queue was full
+static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
 pub struct Mouse {
     data_port: Port<u8>,
     status_port: PortReadOnly<u8>,
     command_port: PortWriteOnly<u8>,
-    event_queue: VecDeque<MouseEvent>,
+    event_queue: EventQueue<MouseEvent, QUEUE_CAPACITY>,
     state: MouseState,
     packet: [u8; 3],
     packet_index: usize,
+    last_left_press_ms: Option<u64>,
+    /// Fractions of a pixel not moved yet, in hundredths, per axis
+    remainder: (i32, i32),
 }
 
 impl Mouse {
@@ -134,10 +178,12 @@ impl Mouse {
             data_port: Port::new(PS2_DATA_PORT),
             status_port: PortReadOnly::new(PS2_STATUS_PORT),
             command_port: PortWriteOnly::new(PS2_COMMAND_PORT),
-            event_queue: VecDeque::with_capacity(16),
+            event_queue: EventQueue::new(),
             state: MouseState::new(),
             packet: [0; 3],
             packet_index: 0,
+            last_left_press_ms: None,
+            remainder: (0, 0),
         }
     }
 
@@ -257,50 +303,72 @@ impl Mouse {
         0 // Timeout occurred
     }
     
-    fn handle_packet(&mut self) {
+    /// Decode the packet, arriving at `time_ms`; `accelerate` applies the
+    /// pointer settings, which injected packets skip
+    fn handle_packet(&mut self, accelerate: bool, time_ms: u64) {
         // Extract movement and button information from the packet
         let buttons = self.packet[0] & 0x07;
         
-        // Process X movement
-        let mut dx = self.packet[1] as i8;
-        if self.packet[0] & MOUSE_X_SIGN != 0 {
-            // X sign bit is set (negative movement)
-            if dx > 0 {
-                dx = -128 + (dx as i16 - 128) as i8;
-            }
-        }
+        let dx = decode_movement(self.packet[0], self.packet[1], MOUSE_X_SIGN, MOUSE_X_OVERFLOW);
+        // The mouse counts y upwards, the screen downwards
+        let dy = -decode_movement(self.packet[0], self.packet[2], MOUSE_Y_SIGN, MOUSE_Y_OVERFLOW);
+        let (dx, dy) = if accelerate {
+            let sensitivity = SENSITIVITY.load(Ordering::Relaxed);
+            let accel = ACCEL.load(Ordering::Relaxed);
+            (transform(dx, &mut self.remainder.0, sensitivity, accel),
+             transform(dy, &mut self.remainder.1, sensitivity, accel))
+        } else {
+            (dx, dy)
+        };
         
-        // Process Y movement (inverted for screen coordinates)
-        let mut dy = -(self.packet[2] as i8);
-        if self.packet[0] & MOUSE_Y_SIGN != 0 {
-            // Y sign bit is set (negative movement)
-            if dy < 0 {
-                dy = -(dy as i16 as i8);
-            } else {
-                dy = -dy;
+        // Detect a double-click on the left button's press edge
+        let mut double_click = false;
+        let left_pressed = buttons & MOUSE_LEFT_BUTTON != 0;
+        if left_pressed && !self.state.left_button() {
+            match self.last_left_press_ms {
+                Some(last) if time_ms.saturating_sub(last) <= DOUBLE_CLICK_MS => {
+                    double_click = true;
+                    self.last_left_press_ms = None;
+                }
+                _ => self.last_left_press_ms = Some(time_ms),
             }
         }
         
         // Update mouse state
         self.state.buttons = buttons;
-        self.state.x = (self.state.x + dx as i16).max(0).min(640);
-        self.state.y = (self.state.y + dy as i16).max(0).min(400);
+        self.state.x = clamp_to(self.state.x.saturating_add(dx), &BOUNDS_WIDTH);
+        self.state.y = clamp_to(self.state.y.saturating_add(dy), &BOUNDS_HEIGHT);
         
         // Create a mouse event
         let event = MouseEvent {
             x: self.state.x,
             y: self.state.y,
-            dx,
-            dy,
+            dx: dx.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+            dy: dy.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
             buttons: MouseButtons::from_bits(buttons),
+            double_click,
+            time_ms,
         };
         
         // Add to the event queue if there's space
-        if self.event_queue.len() < 16 {
-            self.event_queue.push_back(event);
+        if self.event_queue.push_back(event).is_err() {
+            DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
         }
         
-        serial_println!("Mouse: x={}, y={}, buttons={:01b}", self.state.x, self.state.y, self.state.buttons);
+        isr_println!("Mouse: x={}, y={}, buttons={:01b}", self.state.x, self.state.y, self.state.buttons);
+    }
+    
+    /// Decode injected packets while the queue has room, between real ones
+    fn feed_injected(&mut self) {
+        while self.packet_index == 0 && !self.event_queue.is_full() {
+            match super::input::next_mouse_packet() {
+                Some((packet, time_ms)) => {
+                    self.packet = packet;
+                    self.handle_packet(false, time_ms);
+                }
+                None => break,
+            }
+        }
     }
     
     fn handle_data(&mut self, data: u8) {
@@ -308,16 +376,54 @@ impl Mouse {
         self.packet_index += 1;
         
         if self.packet_index >= 3 {
-            self.handle_packet();
+            self.handle_packet(true, crate::time::monotonic_ms());
             self.packet_index = 0;
         }
     }
 }
 
+/// A movement from a packet: 9-bit two's complement, with the sign bit in
+/// the flags byte. Overflowed movements are nonsense and dropped.
+fn decode_movement(flags: u8, byte: u8, sign: u8, overflow: u8) -> i16 {
+    if flags & overflow != 0 {
+        0
+    } else if flags & sign != 0 {
+        byte as i16 - 256
+    } else {
+        byte as i16
+    }
+}
+
+/// `delta` counts of movement in pixels, scaled by `sensitivity` percent
+/// and sped up by `accel` (see ACCEL_STEP). Fractions of a pixel carry
+/// over in `remainder`, in hundredths, so slow movement at a low
+/// sensitivity still gets somewhere; turning round drops them.
+pub fn transform(delta: i16, remainder: &mut i32, sensitivity: u32, accel: u32) -> i16 {
+    let delta = i64::from(delta);
+    let excess = delta.abs().saturating_sub(ACCEL_THRESHOLD);
+    let gain = (100 + i64::from(accel) * excess / ACCEL_STEP).min(MAX_ACCEL_GAIN);
+    // Hundredths of a pixel: |delta| <= 256, sensitivity and gain bounded
+    let scaled = delta * i64::from(sensitivity) * gain / 100;
+    if (scaled < 0 && *remainder > 0) || (scaled > 0 && *remainder < 0) {
+        *remainder = 0;
+    }
+    let total = scaled + i64::from(*remainder);
+    *remainder = (total % 100) as i32;
+    (total / 100).clamp(i64::from(i16::MIN), i64::from(i16::MAX)) as i16
+}
+
+/// `position` kept on screen, from 0 to one less than `limit`
+fn clamp_to(position: i16, limit: &AtomicU16) -> i16 {
+    let last = limit.load(Ordering::Relaxed).saturating_sub(1).min(i16::MAX as u16) as i16;
+    position.clamp(0, last)
+}
+
 /// Mouse interrupt handler - called when mouse data is available
 pub extern "x86-interrupt" fn mouse_interrupt_handler(
     _stack_frame: InterruptStackFrame
 ) {
+    let _handler = crate::interrupts::enter_handler();
+    crate::interrupts::record_interrupt(crate::interrupts::pic::InterruptIndex::Mouse.as_u8());
     if MOUSE_INITIALIZED.load(Ordering::SeqCst) {
         unsafe {
             let data = Port::<u8>::new(PS2_DATA_PORT).read();
@@ -346,14 +452,69 @@ pub fn init() -> Result<(), KernelError> {
     Ok(())
 }
 
-/// Get the next mouse event, if any
+/// Keep the pointer within `width` x `height` pixels, the display the GUI
+/// draws on, moving it inside now if it's out
+pub fn set_bounds(width: u16, height: u16) {
+    BOUNDS_WIDTH.store(width.max(1), Ordering::Relaxed);
+    BOUNDS_HEIGHT.store(height.max(1), Ordering::Relaxed);
+    let mut mouse = MOUSE.lock();
+    mouse.state.x = clamp_to(mouse.state.x, &BOUNDS_WIDTH);
+    mouse.state.y = clamp_to(mouse.state.y, &BOUNDS_HEIGHT);
+}
+
+/// The pointer bounds, as (width, height) in pixels
+pub fn bounds() -> (u16, u16) {
+    (BOUNDS_WIDTH.load(Ordering::Relaxed), BOUNDS_HEIGHT.load(Ordering::Relaxed))
+}
+
+/// Apply `input.mouse_sensitivity` and `input.mouse_accel` now and
+/// whenever they change, once the configuration is loaded
+pub fn configure() {
+    reload_pointer("input.mouse_");
+    crate::config::subscribe("input.mouse_", reload_pointer);
+}
+
+fn reload_pointer(_key: &str) {
+    let setting = |key: &str, default: u32, min: u32, max: u32| crate::config::get(key)
+        .and_then(|value| value.try_as_integer())
+        .and_then(|value| u32::try_from(value).ok())
+        .map_or(default, |value| value.clamp(min, max));
+    SENSITIVITY.store(setting("input.mouse_sensitivity", DEFAULT_SENSITIVITY, MIN_SENSITIVITY, MAX_SENSITIVITY),
+        Ordering::Relaxed);
+    ACCEL.store(setting("input.mouse_accel", DEFAULT_ACCEL, 0, MAX_ACCEL), Ordering::Relaxed);
+}
+
+/// The sensitivity and acceleration in use
+pub fn pointer_settings() -> (u32, u32) {
+    (SENSITIVITY.load(Ordering::Relaxed), ACCEL.load(Ordering::Relaxed))
+}
+
+/// Drop queued events and any partial packet, so the next byte is taken
+/// as the start of a packet again
+pub fn reset_state() {
+    let mut mouse = MOUSE.lock();
+    mouse.event_queue.clear();
+    mouse.packet_index = 0;
+    mouse.last_left_press_ms = None;
+    mouse.remainder = (0, 0);
+}
+
+/// Mouse events dropped since boot because nobody read the queue
+pub fn dropped_events() -> u64 {
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Get the next mouse event, if any. Injected input arrives here even
+/// without a mouse.
 pub fn get_event() -> Option<MouseEvent> {
-    if !MOUSE_INITIALIZED.load(Ordering::SeqCst) {
-        return None;
+    // Get an event from the queue, topped up with injected input
+    let mut mouse = MOUSE.lock();
+    mouse.feed_injected();
+    let event = mouse.event_queue.pop_front();
+    if let Some(event) = event {
+        super::input::note_event(event.time_ms);
     }
-    
-    // Get an event from the queue
-    MOUSE.lock().event_queue.pop_front()
+    event
 }
 
 /// Get the current mouse state
@@ -361,20 +522,83 @@ pub fn get_state() -> MouseState {
     MOUSE.lock().state
 }
 
-/// Draw a simple cursor at the current mouse position
-pub fn draw_cursor() {
-    if MOUSE_INITIALIZED.load(Ordering::SeqCst) {
-        let state = get_state();
-        crate::drivers::vga_enhanced::write_at(
-            state.y as usize, 
-            state.x as usize,
-            "X",
-            if state.left_button() {
-                crate::drivers::vga_enhanced::Color::Red
-            } else {
-                crate::drivers::vga_enhanced::Color::White
-            },
-            crate::drivers::vga_enhanced::Color::Black
-        );
+/// Check packets decode at their extremes, the movement transform
+/// scales, accelerates, carries fractions and can't overflow, and clicks
+/// are told apart from double-clicks by when they were queued
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("MOUSE: Running self-test");
+
+    let decoded = [
+        (0x00, 0x7F, 127),
+        (MOUSE_X_SIGN, 0xFF, -1),
+        (MOUSE_X_SIGN, 0x00, -256),
+        (MOUSE_X_OVERFLOW, 0xFF, 0),
+        (MOUSE_X_OVERFLOW | MOUSE_X_SIGN, 0x00, 0),
+        (MOUSE_Y_OVERFLOW | MOUSE_Y_SIGN, 0x00, -256),
+    ];
+    for (flags, byte, expected) in decoded {
+        if decode_movement(flags, byte, MOUSE_X_SIGN, MOUSE_X_OVERFLOW) != expected {
+            serial_println!("MOUSE: Flags {:02x} byte {:02x} decoded as {}", flags, byte,
+                decode_movement(flags, byte, MOUSE_X_SIGN, MOUSE_X_OVERFLOW));
+            return Err(KernelError::ValidationError("Mouse movement decoded wrongly"));
+        }
+    }
+
+    let once = |delta: i16, sensitivity: u32, accel: u32| transform(delta, &mut 0, sensitivity, accel);
+    let cases = [
+        (5, DEFAULT_SENSITIVITY, 0, 5),
+        (-256, DEFAULT_SENSITIVITY, 0, -256),
+        (10, 200, 0, 20),
+        (-3, 50, 0, -1),
+        // 12 counts is one step past the threshold: +50% at accel 50
+        (12, DEFAULT_SENSITIVITY, 50, 18),
+        (3, DEFAULT_SENSITIVITY, MAX_ACCEL, 3),
+        (-256, MAX_SENSITIVITY, MAX_ACCEL, -256 * 10 * 4),
+        (255, MAX_SENSITIVITY, MAX_ACCEL, 255 * 10 * 4),
+        (0, MAX_SENSITIVITY, MAX_ACCEL, 0),
+    ];
+    for (delta, sensitivity, accel, expected) in cases {
+        if once(delta, sensitivity, accel) != expected {
+            serial_println!("MOUSE: {} at {}%/{} moved {}", delta, sensitivity, accel, once(delta, sensitivity, accel));
+            return Err(KernelError::ValidationError("Pointer movement transformed wrongly"));
+        }
+    }
+    if transform(i16::MIN, &mut 0, u32::MAX, u32::MAX) != i16::MIN
+        || transform(i16::MAX, &mut 0, u32::MAX, u32::MAX) != i16::MAX {
+        return Err(KernelError::ValidationError("Pointer movement overflowed"));
+    }
+
+    // A third of a pixel each time adds up, and turning round drops it
+    let mut remainder = 0;
+    let moved: i16 = (0..3).map(|_| transform(1, &mut remainder, 34, 0)).sum();
+    if moved != 1 || transform(-1, &mut remainder, 34, 0) != 0 || remainder != -34 {
+        return Err(KernelError::ValidationError("Fractions of a pixel weren't carried"));
+    }
+
+    let limit = AtomicU16::new(640);
+    if clamp_to(-5, &limit) != 0 || clamp_to(640, &limit) != 639 || clamp_to(i16::MAX, &AtomicU16::new(u16::MAX)) != i16::MAX {
+        return Err(KernelError::ValidationError("Pointer left the screen"));
+    }
+
+    // Presses DOUBLE_CLICK_MS apart make a double-click, a millisecond more
+    // two clicks, however long the events then wait to be read
+    for (gap, expected) in [(DOUBLE_CLICK_MS, true), (DOUBLE_CLICK_MS + 1, false)] {
+        let mut mouse = Mouse::new();
+        for (time_ms, buttons) in [(1000, MOUSE_LEFT_BUTTON), (1050, 0), (1000 + gap, MOUSE_LEFT_BUTTON)] {
+            mouse.packet = [buttons, 0, 0];
+            mouse.handle_packet(false, time_ms);
+        }
+        let events = [mouse.event_queue.pop_front(), mouse.event_queue.pop_front(), mouse.event_queue.pop_front()];
+        match events {
+            [Some(first), Some(_), Some(second)] if !first.double_click && first.time_ms == 1000
+                && second.double_click == expected && second.time_ms == 1000 + gap => {}
+            _ => {
+                serial_println!("MOUSE: Presses {}ms apart decoded as {:?}", gap, events);
+                return Err(KernelError::ValidationError("Double-click classified wrongly at the threshold"));
+            }
+        }
     }
-}
\ No newline at end of file
+
+    serial_println!("MOUSE: Self-test passed");
+    Ok(())
+}
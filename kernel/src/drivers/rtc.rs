@@ -1,10 +1,18 @@
 //! Real-Time Clock (RTC) driver
-//! Provides date and time functionality
+//! Provides date and time functionality, a periodic interrupt that can
+//! drive the monotonic clock instead of the PIT, and a one-shot alarm.
+//!
+//! Both interrupts arrive on IRQ 8. The RTC raises no further interrupt
+//! until register C has been read, so the handler always reads it. Register
+//! accesses happen with interrupts off, so the handler can't land between a
+//! write to the index port and the data access that has to follow it.
 
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 use x86_64::instructions::port::{Port, PortWriteOnly};
 use crate::errors::KernelError;
+use crate::interrupts::pic::{PIC_1_OFFSET, PIC_CONTROLLER};
 use crate::serial_println;
 
 // CMOS/RTC ports
@@ -13,8 +21,11 @@ const CMOS_DATA_PORT: u16 = 0x71;
 
 // RTC registers
 const RTC_SECONDS: u8 = 0x00;
+const RTC_ALARM_SECONDS: u8 = 0x01;
 const RTC_MINUTES: u8 = 0x02;
+const RTC_ALARM_MINUTES: u8 = 0x03;
 const RTC_HOURS: u8 = 0x04;
+const RTC_ALARM_HOURS: u8 = 0x05;
 const RTC_DAY_OF_MONTH: u8 = 0x07;
 const RTC_MONTH: u8 = 0x08;
 const RTC_YEAR: u8 = 0x09;
@@ -28,9 +39,48 @@ const RTC_STATUS_D: u8 = 0x0D;
 
 // Status register bit flags
 const RTC_UIP: u8 = 0x80; // Update in progress flag (Status A)
+const RTC_RATE_MASK: u8 = 0x0F; // Periodic interrupt rate select (Status A)
+const RTC_SET: u8 = 0x80; // Halt updates while setting the time (Status B)
+const RTC_PIE: u8 = 0x40; // Periodic interrupt enable (Status B)
+const RTC_AIE: u8 = 0x20; // Alarm interrupt enable (Status B)
 const RTC_DM: u8 = 0x04;  // Data Mode: 0 = BCD, 1 = Binary (Status B)
 const RTC_24H: u8 = 0x02; // Hour Format: 0 = 12h, 1 = 24h (Status B)
 const RTC_DST: u8 = 0x01; // Daylight Savings Time enable (Status B)
+const RTC_PF: u8 = 0x40;  // Periodic interrupt flag (Status C)
+const RTC_AF: u8 = 0x20;  // Alarm flag (Status C)
+
+/// Set in the index port to keep NMIs off while a register is accessed
+const NMI_DISABLE: u8 = 0x80;
+
+/// IRQ line the RTC interrupts on
+const RTC_IRQ: u8 = 8;
+
+/// Frequency the periodic rate divides down from (Hz)
+pub const BASE_FREQUENCY: u32 = 32768;
+/// Fastest usable periodic rate (8192 Hz); 1 and 2 misbehave on real chips
+pub const MIN_RATE: u8 = 3;
+/// Slowest periodic rate (2 Hz)
+pub const MAX_RATE: u8 = 15;
+
+/// Run from the RTC interrupt handler (or the idle poll) when the alarm goes off
+pub type AlarmCallback = fn();
+
+/// Whether NMIs stay masked between register accesses
+static NMI_MASKED: AtomicBool = AtomicBool::new(false);
+
+/// Periodic interrupt rate, 0 while off
+static PERIODIC_RATE: AtomicU8 = AtomicU8::new(0);
+
+/// Whether the IRQ 8 handler is registered
+static HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether an alarm is set, checked before touching the ports in `poll`
+static ALARM_ARMED: AtomicBool = AtomicBool::new(false);
+
+/// Frequency in Hz of periodic `rate`
+pub fn rate_frequency(rate: u8) -> u32 {
+    BASE_FREQUENCY >> (rate.clamp(1, MAX_RATE) - 1)
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct DateTime {
@@ -60,6 +110,47 @@ impl DateTime {
             self.year, self.month, self.day,
             self.hour, self.minute, self.second)
     }
+
+    /// Seconds since 1970-01-01 00:00:00, taking the RTC to run on UTC
+    pub fn to_unix_seconds(&self) -> u64 {
+        // Days from the civil date, counting years from March so the leap
+        // day falls at the end
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        seconds.max(0) as u64
+    }
+
+    /// The UTC date and time `seconds` after 1970-01-01 00:00:00
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        // The inverse of to_unix_seconds, with years starting in March
+        let days = (seconds / 86_400) as i64 + 719_468;
+        let secs = seconds % 86_400;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            second: (secs % 60) as u8,
+            minute: (secs / 60 % 60) as u8,
+            hour: (secs / 3600) as u8,
+            day: day as u8,
+            month: month as u8,
+            year: year as u16,
+        }
+    }
 }
 
 struct RtcDriver {
@@ -96,18 +187,31 @@ impl RtcDriver {
     fn read_register(&mut self, register: u8) -> u8 {
         unsafe {
             // Disable NMI (high bit set) while reading
-            self.addr_port.write(register | 0x80);
-            self.data_port.read()
+            self.addr_port.write(register | NMI_DISABLE);
+            let value = self.data_port.read();
+            self.release();
+            value
         }
     }
     
     fn write_register(&mut self, register: u8, value: u8) {
         unsafe {
             // Disable NMI (high bit set) while writing
-            self.addr_port.write(register | 0x80);
+            self.addr_port.write(register | NMI_DISABLE);
             self.data_port.write(value);
+            self.release();
         }
     }
+
+    /// Select register D with NMIs back as they were. The NMI bit lives in
+    /// the same port as the index, so it has to be written with a register;
+    /// D is read-only, so a stray data access can't change anything. Each
+    /// index write is followed by a data access, as the CMOS expects.
+    unsafe fn release(&mut self) {
+        let nmi = if NMI_MASKED.load(Ordering::Relaxed) { NMI_DISABLE } else { 0 };
+        self.addr_port.write(RTC_STATUS_D | nmi);
+        self.data_port.read();
+    }
     
     fn bcd_to_binary(&self, value: u8) -> u8 {
         // Convert from BCD to binary
@@ -175,18 +279,266 @@ impl RtcDriver {
     }
 }
 
+/// `value` in the data mode status register B selects
+fn encode_value(status_b: u8, value: u8) -> u8 {
+    if status_b & RTC_DM == 0 {
+        ((value / 10) << 4) | (value % 10)
+    } else {
+        value
+    }
+}
+
+/// `hour` (0-23) as the RTC stores it under status register B: in 12-hour
+/// mode hours run 1-12 with bit 7 marking PM
+fn encode_hour(status_b: u8, hour: u8) -> u8 {
+    if status_b & RTC_24H == 0 {
+        let twelve = match hour % 12 { 0 => 12, hour => hour };
+        encode_value(status_b, twelve) | if hour >= 12 { 0x80 } else { 0 }
+    } else {
+        encode_value(status_b, hour)
+    }
+}
+
+/// The inverse of encode_value
+fn decode_value(status_b: u8, stored: u8) -> u8 {
+    if status_b & RTC_DM == 0 {
+        ((stored >> 4) * 10) + (stored & 0x0F)
+    } else {
+        stored
+    }
+}
+
+/// The inverse of encode_hour
+fn decode_hour(status_b: u8, stored: u8) -> u8 {
+    let value = decode_value(status_b, stored & 0x7F);
+    if status_b & RTC_24H == 0 {
+        value % 12 + if stored & 0x80 != 0 { 12 } else { 0 }
+    } else {
+        value
+    }
+}
+
+impl RtcDriver {
+    /// Write `time` in the format the RTC is configured for, with updates
+    /// held off while the registers are inconsistent
+    fn write_datetime(&mut self, time: &DateTime) -> Result<(), KernelError> {
+        if !(1900..2100).contains(&time.year) {
+            return Err(KernelError::InvalidParameter);
+        }
+        let status_b = self.read_register(RTC_STATUS_B);
+        let encode = |value: u8| encode_value(status_b, value);
+        let values = [
+            (RTC_SECONDS, encode(time.second)),
+            (RTC_MINUTES, encode(time.minute)),
+            (RTC_HOURS, encode_hour(status_b, time.hour)),
+            (RTC_DAY_OF_MONTH, encode(time.day)),
+            (RTC_MONTH, encode(time.month)),
+            (RTC_YEAR, encode((time.year % 100) as u8)),
+            (RTC_CENTURY, encode((time.year / 100) as u8)),
+        ];
+
+        self.write_register(RTC_STATUS_B, status_b | RTC_SET);
+        for (register, value) in values {
+            self.write_register(register, value);
+        }
+        self.write_register(RTC_STATUS_B, status_b & !RTC_SET);
+        Ok(())
+    }
+
+    /// Put periodic `rate` in register A, keeping the divider bits
+    fn set_rate(&mut self, rate: u8) {
+        let status_a = self.read_register(RTC_STATUS_A);
+        self.write_register(RTC_STATUS_A, (status_a & !RTC_RATE_MASK) | rate);
+    }
+
+    /// Turn interrupt enable `bits` in register B on or off
+    fn set_interrupt_enables(&mut self, bits: u8, on: bool) {
+        let status_b = self.read_register(RTC_STATUS_B);
+        let status_b = if on { status_b | bits } else { status_b & !bits };
+        self.write_register(RTC_STATUS_B, status_b);
+    }
+
+    /// Read register C, which acknowledges the pending interrupt, and return
+    /// its flags. Alarms are one-shot, so one going off turns its interrupt off.
+    fn acknowledge(&mut self) -> u8 {
+        let status_c = self.read_register(RTC_STATUS_C);
+        if status_c & RTC_AF != 0 {
+            self.set_interrupt_enables(RTC_AIE, false);
+        }
+        status_c
+    }
+
+    fn write_alarm(&mut self, hour: u8, minute: u8, second: u8) {
+        let status_b = self.read_register(RTC_STATUS_B);
+        self.write_register(RTC_ALARM_SECONDS, encode_value(status_b, second));
+        self.write_register(RTC_ALARM_MINUTES, encode_value(status_b, minute));
+        self.write_register(RTC_ALARM_HOURS, encode_hour(status_b, hour));
+        // A match with the old alarm time may have left the flag set
+        self.read_register(RTC_STATUS_C);
+        self.write_register(RTC_STATUS_B, status_b | RTC_AIE);
+    }
+
+    fn read_alarm(&mut self) -> (u8, u8, u8) {
+        let status_b = self.read_register(RTC_STATUS_B);
+        (
+            decode_hour(status_b, self.read_register(RTC_ALARM_HOURS)),
+            decode_value(status_b, self.read_register(RTC_ALARM_MINUTES)),
+            decode_value(status_b, self.read_register(RTC_ALARM_SECONDS)),
+        )
+    }
+}
+
 lazy_static! {
     static ref RTC: Mutex<RtcDriver> = Mutex::new(RtcDriver::new());
+    /// What to run when the alarm goes off
+    static ref ALARM: Mutex<Option<AlarmCallback>> = Mutex::new(None);
+}
+
+/// Use the RTC with interrupts off, so the IRQ 8 handler can't come in
+/// between selecting a register and accessing it
+fn with_rtc<R>(f: impl FnOnce(&mut RtcDriver) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut RTC.lock()))
 }
 
 /// Initialize the RTC driver
 pub fn init() -> Result<(), KernelError> {
-    RTC.lock().init()
+    with_rtc(|rtc| rtc.init())
 }
 
 /// Get the current date and time from the RTC
 pub fn get_datetime() -> DateTime {
-    RTC.lock().read_datetime()
+    with_rtc(|rtc| rtc.read_datetime())
+}
+
+/// Set the RTC's date and time
+pub fn set_datetime(time: &DateTime) -> Result<(), KernelError> {
+    with_rtc(|rtc| rtc.write_datetime(time))
+}
+
+/// Mask or unmask NMIs through the CMOS index port. Register accesses mask
+/// them for their duration either way, and put this setting back after.
+pub fn set_nmi_enabled(enabled: bool) {
+    NMI_MASKED.store(!enabled, Ordering::Relaxed);
+    with_rtc(|rtc| unsafe { rtc.release() });
+}
+
+/// IRQ 8: acknowledge, then count a periodic tick and fire the alarm as flagged
+fn rtc_irq_handler(_vector: u8) {
+    // Everyone else holds the lock with interrupts off, so on one CPU this
+    // can't fail
+    let Some(status_c) = RTC.try_lock().map(|mut rtc| rtc.acknowledge()) else {
+        return;
+    };
+    if status_c & RTC_PF != 0 {
+        let rate = PERIODIC_RATE.load(Ordering::SeqCst);
+        if rate != 0 {
+            crate::time::rtc_tick(1 << (rate - 1));
+        }
+    }
+    if status_c & RTC_AF != 0 {
+        fire_alarm();
+    }
+}
+
+fn fire_alarm() {
+    if !ALARM_ARMED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let callback = ALARM.try_lock().and_then(|mut alarm| alarm.take());
+    if let Some(callback) = callback {
+        callback();
+    }
+}
+
+/// Register the IRQ 8 handler and unmask the line, once
+fn install_handler() -> Result<(), KernelError> {
+    if HANDLER_INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    if let Err(e) = crate::interrupts::register_irq_handler(PIC_1_OFFSET + RTC_IRQ, rtc_irq_handler) {
+        HANDLER_INSTALLED.store(false, Ordering::SeqCst);
+        return Err(e);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // A flag left over from before would hold the line
+        RTC.lock().read_register(RTC_STATUS_C);
+        PIC_CONTROLLER.lock().unmask_irq(RTC_IRQ);
+    });
+    Ok(())
+}
+
+/// Start periodic interrupts at `rate` (MIN_RATE..=MAX_RATE), which is
+/// `rate_frequency(rate)` Hz. The time module counts each one.
+pub fn enable_periodic(rate: u8) -> Result<(), KernelError> {
+    if !(MIN_RATE..=MAX_RATE).contains(&rate) {
+        return Err(KernelError::InvalidParameter);
+    }
+    install_handler()?;
+    with_rtc(|rtc| {
+        rtc.set_rate(rate);
+        PERIODIC_RATE.store(rate, Ordering::SeqCst);
+        rtc.set_interrupt_enables(RTC_PIE, true);
+    });
+    serial_println!("RTC: Periodic interrupt at {} Hz", rate_frequency(rate));
+    Ok(())
+}
+
+/// Stop periodic interrupts. Fails while they drive the monotonic clock.
+pub fn disable_periodic() -> Result<(), KernelError> {
+    if crate::time::tick_source() == crate::time::TickSource::Rtc {
+        return Err(KernelError::InvalidOperation);
+    }
+    with_rtc(|rtc| {
+        rtc.set_interrupt_enables(RTC_PIE, false);
+        PERIODIC_RATE.store(0, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// Periodic interrupt frequency in Hz, 0 while off
+pub fn periodic_frequency() -> u32 {
+    match PERIODIC_RATE.load(Ordering::SeqCst) {
+        0 => 0,
+        rate => rate_frequency(rate),
+    }
+}
+
+/// Run `callback` when the RTC next reads hour:minute:second (its own
+/// time, UTC). There is one alarm, so this replaces any already set. The
+/// callback runs in interrupt context, so it should do little more than
+/// raise deferred work.
+pub fn set_alarm(hour: u8, minute: u8, second: u8, callback: AlarmCallback) -> Result<(), KernelError> {
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(KernelError::InvalidParameter);
+    }
+    install_handler()?;
+    with_rtc(|rtc| {
+        *ALARM.lock() = Some(callback);
+        ALARM_ARMED.store(true, Ordering::SeqCst);
+        rtc.write_alarm(hour, minute, second);
+    });
+    Ok(())
+}
+
+/// Drop the alarm, if one is set
+pub fn cancel_alarm() {
+    with_rtc(|rtc| {
+        rtc.set_interrupt_enables(RTC_AIE, false);
+        ALARM_ARMED.store(false, Ordering::SeqCst);
+        *ALARM.lock() = None;
+    });
+}
+
+/// Check for the alarm while interrupts are off and IRQ 8 can't report it.
+/// Register C flags a match whether or not its interrupt is enabled.
+pub fn poll() {
+    if !ALARM_ARMED.load(Ordering::SeqCst) || x86_64::instructions::interrupts::are_enabled() {
+        return;
+    }
+    let status_c = with_rtc(|rtc| rtc.acknowledge());
+    if status_c & RTC_AF != 0 {
+        fire_alarm();
+    }
 }
 
 /// Sleep for a given number of seconds using the RTC
@@ -212,4 +564,62 @@ pub fn sleep(seconds: u32) {
         // Use HLT to pause the CPU
         x86_64::instructions::hlt();
     }
-} 
\ No newline at end of file
+}
+
+/// Check the register encodings and rate arithmetic, and that the alarm and
+/// periodic settings reach the chip and come off again
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("RTC: Running self-test");
+
+    // BCD 12-hour, BCD 24-hour and binary 24-hour
+    if encode_hour(0, 0) != 0x12 || encode_hour(0, 12) != 0x92 || encode_hour(0, 13) != 0x81
+        || encode_hour(RTC_24H, 23) != 0x23 || encode_hour(RTC_DM | RTC_24H, 13) != 13 {
+        return Err(KernelError::ValidationError("RTC hour encoded wrongly"));
+    }
+    for status_b in [0, RTC_24H, RTC_DM, RTC_DM | RTC_24H] {
+        if (0..24).any(|hour| decode_hour(status_b, encode_hour(status_b, hour)) != hour) {
+            return Err(KernelError::ValidationError("RTC hour doesn't round-trip"));
+        }
+    }
+    if rate_frequency(MIN_RATE) != 8192 || rate_frequency(6) != 1024 || rate_frequency(MAX_RATE) != 2 {
+        return Err(KernelError::ValidationError("RTC periodic rate gives the wrong frequency"));
+    }
+    if enable_periodic(MIN_RATE - 1).is_ok() || enable_periodic(MAX_RATE + 1).is_ok() {
+        return Err(KernelError::ValidationError("Out-of-range RTC periodic rate accepted"));
+    }
+
+    // Program the chip and read it back, then put it back as it was
+    let (status_a, status_b) = with_rtc(|rtc| (rtc.read_register(RTC_STATUS_A), rtc.read_register(RTC_STATUS_B)));
+    let periodic_rate = PERIODIC_RATE.load(Ordering::SeqCst);
+    let result = (|| {
+        fn ignore() {}
+        set_alarm(13, 45, 30, ignore)?;
+        let alarm = with_rtc(|rtc| rtc.read_alarm());
+        let armed = with_rtc(|rtc| rtc.read_register(RTC_STATUS_B)) & RTC_AIE != 0;
+        cancel_alarm();
+        let disarmed = with_rtc(|rtc| rtc.read_register(RTC_STATUS_B)) & RTC_AIE == 0;
+        if alarm != (13, 45, 30) || !armed || !disarmed {
+            serial_println!("RTC: Alarm read back as {:?}", alarm);
+            return Err(KernelError::ValidationError("RTC alarm not programmed"));
+        }
+
+        enable_periodic(MAX_RATE)?;
+        let (rate, enabled) = with_rtc(|rtc| {
+            (rtc.read_register(RTC_STATUS_A) & RTC_RATE_MASK, rtc.read_register(RTC_STATUS_B) & RTC_PIE != 0)
+        });
+        if rate != MAX_RATE || !enabled || periodic_frequency() != 2 {
+            return Err(KernelError::ValidationError("RTC periodic interrupt not programmed"));
+        }
+        Ok(())
+    })();
+    with_rtc(|rtc| {
+        rtc.write_register(RTC_STATUS_A, status_a);
+        rtc.write_register(RTC_STATUS_B, status_b & !RTC_SET);
+        rtc.read_register(RTC_STATUS_C);
+    });
+    PERIODIC_RATE.store(periodic_rate, Ordering::SeqCst);
+
+    result?;
+    serial_println!("RTC: Self-test passed");
+    Ok(())
+}
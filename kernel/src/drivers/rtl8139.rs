@@ -0,0 +1,384 @@
+//! RTL8139 network card driver
+//! Supports the Realtek 8139 that QEMU emulates with `-nic model=rtl8139`
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::errors::{KernelError, DeviceError};
+use crate::drivers::pci::{self, Bar};
+use crate::interrupts::pic::PIC_1_OFFSET;
+use crate::task::deferred::{self, WorkId};
+use crate::{memory, serial_println};
+
+// PCI identification
+const RTL8139_VENDOR_ID: u16 = 0x10EC;
+const RTL8139_DEVICE_ID: u16 = 0x8139;
+
+// Register offsets from the I/O base
+const REG_MAC: u16 = 0x00;       // MAC address (6 bytes)
+const REG_TSD0: u16 = 0x10;      // Transmit status of descriptor 0 (4 x u32)
+const REG_TSAD0: u16 = 0x20;     // Transmit start address of descriptor 0 (4 x u32)
+const REG_RBSTART: u16 = 0x30;   // Receive buffer start address
+const REG_CR: u16 = 0x37;        // Command register
+const REG_CAPR: u16 = 0x38;      // Current address of packet read
+const REG_IMR: u16 = 0x3C;       // Interrupt mask
+const REG_ISR: u16 = 0x3E;       // Interrupt status
+const REG_RCR: u16 = 0x44;       // Receive configuration
+const REG_CONFIG1: u16 = 0x52;   // Configuration register 1
+
+// Command register bits
+const CR_BUFE: u8 = 0x01;        // Receive buffer empty
+const CR_TE: u8 = 0x04;          // Transmitter enable
+const CR_RE: u8 = 0x08;          // Receiver enable
+const CR_RST: u8 = 0x10;         // Software reset
+
+// Interrupt bits
+const INT_ROK: u16 = 0x0001;     // Receive OK
+const INT_RER: u16 = 0x0002;     // Receive error
+const INT_TOK: u16 = 0x0004;     // Transmit OK
+const INT_TER: u16 = 0x0008;     // Transmit error
+const INT_RXOVW: u16 = 0x0010;   // Receive buffer overflow
+
+// Receive configuration bits
+const RCR_APM: u32 = 0x02;       // Accept physical match
+const RCR_AM: u32 = 0x04;        // Accept multicast
+const RCR_AB: u32 = 0x08;        // Accept broadcast
+const RCR_WRAP: u32 = 0x80;      // Write past the ring end instead of wrapping
+
+// Transmit status bits
+const TSD_OWN: u32 = 0x2000;     // DMA to the FIFO completed
+const TSD_SIZE_MASK: u32 = 0x1FFF;
+
+// Buffer sizes
+const RX_RING_SIZE: usize = 8192;
+const RX_BUFFER_SIZE: usize = RX_RING_SIZE + 16 + 1536; // Ring + header + overflow for RCR_WRAP
+const TX_DESCRIPTORS: usize = 4;
+const TX_BUFFER_SIZE: usize = 2048;
+const MIN_FRAME_SIZE: usize = 60;
+const MAX_FRAME_SIZE: usize = 1514;
+const FRAME_SIZE: usize = 4096;
+
+// Polling limits
+const RESET_TIMEOUT: u32 = 100_000;
+const TX_TIMEOUT: u32 = 100_000;
+
+/// Packet and error counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NicStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+/// RTL8139 device state
+struct Rtl8139 {
+    io_base: u16,
+    irq: u8,
+    mac: [u8; 6],
+    rx_buffer: VirtAddr,
+    rx_offset: usize,
+    tx_buffers: [(VirtAddr, PhysAddr); TX_DESCRIPTORS],
+    tx_current: usize,
+    stats: NicStats,
+}
+
+impl Rtl8139 {
+    fn read8(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base + reg).read() }
+    }
+
+    fn read16(&self, reg: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.io_base + reg).read() }
+    }
+
+    fn read32(&self, reg: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.io_base + reg).read() }
+    }
+
+    fn write8(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base + reg).write(value) }
+    }
+
+    fn write16(&self, reg: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.io_base + reg).write(value) }
+    }
+
+    fn write32(&self, reg: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.io_base + reg).write(value) }
+    }
+
+    /// Power on, reset and program the card
+    fn start(&mut self, rx_phys: PhysAddr) -> Result<(), KernelError> {
+        // Wake the card from low-power mode
+        self.write8(REG_CONFIG1, 0x00);
+
+        // Software reset
+        self.write8(REG_CR, CR_RST);
+        let mut timeout = RESET_TIMEOUT;
+        while self.read8(REG_CR) & CR_RST != 0 {
+            timeout -= 1;
+            if timeout == 0 {
+                return Err(KernelError::DeviceTimeout);
+            }
+        }
+
+        for i in 0..6 {
+            self.mac[i] = self.read8(REG_MAC + i as u16);
+        }
+
+        self.write32(REG_RBSTART, rx_phys.as_u64() as u32);
+        for (i, (_, phys)) in self.tx_buffers.iter().enumerate() {
+            self.write32(REG_TSAD0 + (i as u16) * 4, phys.as_u64() as u32);
+        }
+
+        self.write16(REG_IMR, INT_ROK | INT_RER | INT_TOK | INT_TER | INT_RXOVW);
+        self.write32(REG_RCR, RCR_APM | RCR_AM | RCR_AB | RCR_WRAP);
+        self.write8(REG_CR, CR_RE | CR_TE);
+        self.rx_offset = 0;
+
+        Ok(())
+    }
+
+    /// Read and acknowledge pending interrupt status bits
+    fn take_status(&mut self) -> u16 {
+        let status = self.read16(REG_ISR);
+        if status != 0 {
+            self.write16(REG_ISR, status);
+        }
+        if status & (INT_RER | INT_RXOVW) != 0 {
+            self.stats.rx_errors += 1;
+        }
+        if status & INT_TER != 0 {
+            self.stats.tx_errors += 1;
+        }
+        status
+    }
+
+    /// Copy every complete frame out of the receive ring
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        while self.read8(REG_CR) & CR_BUFE == 0 {
+            let base = self.rx_buffer.as_ptr::<u8>();
+            let (status, length) = unsafe {
+                let header = base.add(self.rx_offset) as *const u16;
+                (
+                    core::ptr::read_volatile(header),
+                    core::ptr::read_volatile(header.add(1)) as usize,
+                )
+            };
+
+            // Bit 0 of the packet status is Receive OK; length includes the CRC
+            if status & 0x01 == 0 || length < 4 || length > MAX_FRAME_SIZE + 4 {
+                self.stats.rx_errors += 1;
+                // The ring is out of sync; drop everything and start over
+                self.write8(REG_CR, CR_TE);
+                self.write8(REG_CR, CR_RE | CR_TE);
+                self.rx_offset = 0;
+                break;
+            }
+
+            let data = unsafe {
+                core::slice::from_raw_parts(base.add(self.rx_offset + 4), length - 4)
+            };
+            frames.push(data.to_vec());
+            self.stats.rx_packets += 1;
+            self.stats.rx_bytes += (length - 4) as u64;
+
+            // Advance past the header and frame, dword aligned
+            self.rx_offset = (self.rx_offset + length + 4 + 3) & !3;
+            self.rx_offset %= RX_RING_SIZE;
+            // CAPR lags the real read pointer by 16 bytes
+            self.write16(REG_CAPR, (self.rx_offset as u16).wrapping_sub(16));
+        }
+
+        frames
+    }
+
+    /// Queue a frame on the next transmit descriptor
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), KernelError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(KernelError::InvalidParameter);
+        }
+
+        let descriptor = self.tx_current;
+        let status_reg = REG_TSD0 + (descriptor as u16) * 4;
+
+        // Wait for the card to finish with the descriptor's previous frame
+        let mut timeout = TX_TIMEOUT;
+        while self.read32(status_reg) & TSD_OWN == 0 {
+            timeout -= 1;
+            if timeout == 0 {
+                self.stats.tx_errors += 1;
+                return Err(KernelError::DeviceTimeout);
+            }
+        }
+
+        let length = frame.len().max(MIN_FRAME_SIZE);
+        let buffer = self.tx_buffers[descriptor].0.as_mut_ptr::<u8>();
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer, frame.len());
+            core::ptr::write_bytes(buffer.add(frame.len()), 0, length - frame.len());
+        }
+
+        // Writing the size with OWN clear starts the transfer
+        self.write32(status_reg, length as u32 & TSD_SIZE_MASK);
+        self.tx_current = (descriptor + 1) % TX_DESCRIPTORS;
+
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += length as u64;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref NIC: Mutex<Option<Rtl8139>> = Mutex::new(None);
+    /// Work raised when frames arrive
+    static ref RX_WORK: Mutex<Option<WorkId>> = Mutex::new(None);
+}
+
+/// Allocate physically contiguous, identity-offset memory below 4 GiB for DMA
+fn allocate_dma(size: usize) -> Result<(VirtAddr, PhysAddr), KernelError> {
+    let frames = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+    let frame = memory::allocate_contiguous_frames(frames)
+        .ok_or(KernelError::OutOfMemory)?;
+    let phys = frame.start_address();
+
+    // The card only takes 32-bit bus addresses
+    if phys.as_u64() + (frames * FRAME_SIZE) as u64 > u32::MAX as u64 {
+        return Err(KernelError::UnsupportedFeature);
+    }
+
+    let virt = memory::phys_to_virt(phys);
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * FRAME_SIZE) };
+    Ok((virt, phys))
+}
+
+/// IRQ handler: acknowledge the card and defer frame processing
+fn irq_handler(_vector: u8) {
+    check_status();
+}
+
+/// Run `f` on the card with interrupts off, so the IRQ handler never finds
+/// the lock held and leaves the interrupt status unacknowledged
+fn with_nic<R>(f: impl FnOnce(&mut Option<Rtl8139>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut NIC.lock()))
+}
+
+/// Read the card's interrupt status and raise the receive work if needed
+fn check_status() {
+    let status = match NIC.try_lock() {
+        Some(mut guard) => match guard.as_mut() {
+            Some(nic) => nic.take_status(),
+            None => return,
+        },
+        None => return,
+    };
+
+    if status & (INT_ROK | INT_RXOVW) != 0 {
+        if let Some(work) = RX_WORK.try_lock().and_then(|w| *w) {
+            deferred::raise(work);
+        }
+    }
+}
+
+/// Find and initialize an RTL8139 on the PCI bus
+pub fn init() -> Result<(), KernelError> {
+    let dev = pci::find_device_by_id(RTL8139_VENDOR_ID, RTL8139_DEVICE_ID)
+        .ok_or(KernelError::DeviceNotFound)?;
+    serial_println!("RTL8139: Found {}", dev.description());
+
+    let io_base = match dev.bars[0] {
+        Some(Bar::Io { port, .. }) => port,
+        _ => return Err(KernelError::DeviceError(DeviceError::InitFailed)),
+    };
+
+    pci::enable_io_space(&dev);
+    pci::enable_bus_master(&dev);
+
+    let (rx_buffer, rx_phys) = allocate_dma(RX_BUFFER_SIZE)?;
+    let (tx_virt, tx_phys) = allocate_dma(TX_DESCRIPTORS * TX_BUFFER_SIZE)?;
+    let mut tx_buffers = [(VirtAddr::zero(), PhysAddr::zero()); TX_DESCRIPTORS];
+    for (i, buffer) in tx_buffers.iter_mut().enumerate() {
+        let offset = (i * TX_BUFFER_SIZE) as u64;
+        *buffer = (tx_virt + offset, tx_phys + offset);
+    }
+
+    let mut nic = Rtl8139 {
+        io_base,
+        irq: dev.interrupt_line,
+        mac: [0; 6],
+        rx_buffer,
+        rx_offset: 0,
+        tx_buffers,
+        tx_current: 0,
+        stats: NicStats::default(),
+    };
+    nic.start(rx_phys)?;
+
+    serial_println!("RTL8139: I/O base {:#x}, IRQ {}, MAC {}",
+        io_base, nic.irq, format_mac(&nic.mac));
+
+    let irq = nic.irq;
+    with_nic(|slot| *slot = Some(nic));
+
+    // Wire up the legacy interrupt line; polling covers the case where interrupts are off
+    if irq < 16 {
+        crate::interrupts::register_irq_handler(PIC_1_OFFSET + irq, irq_handler)?;
+        crate::interrupts::pic::PIC_CONTROLLER.lock().unmask_irq(irq);
+    }
+
+    Ok(())
+}
+
+/// Set the deferred work raised when frames arrive
+pub fn set_receive_work(work: WorkId) {
+    interrupts::without_interrupts(|| *RX_WORK.lock() = Some(work));
+}
+
+/// Poll the card when its interrupt can't be delivered
+pub fn poll() {
+    if !interrupts::are_enabled() {
+        check_status();
+    }
+}
+
+/// Take all frames waiting in the receive ring
+pub fn receive_frames() -> Vec<Vec<u8>> {
+    with_nic(|slot| match slot.as_mut() {
+        Some(nic) => nic.receive(),
+        None => Vec::new(),
+    })
+}
+
+/// Transmit an Ethernet frame
+pub fn send_frame(frame: &[u8]) -> Result<(), KernelError> {
+    with_nic(|slot| slot.as_mut().ok_or(KernelError::DeviceNotInitialized)?.transmit(frame))
+}
+
+/// Check whether a card was found and initialized
+pub fn is_present() -> bool {
+    with_nic(|slot| slot.is_some())
+}
+
+/// Get the card's MAC address
+pub fn mac_address() -> Option<[u8; 6]> {
+    with_nic(|slot| slot.as_ref().map(|nic| nic.mac))
+}
+
+/// Get the card's packet counters
+pub fn stats() -> Option<NicStats> {
+    with_nic(|slot| slot.as_ref().map(|nic| nic.stats))
+}
+
+/// Format a MAC address as aa:bb:cc:dd:ee:ff
+pub fn format_mac(mac: &[u8; 6]) -> alloc::string::String {
+    alloc::format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+}
@@ -1,17 +1,31 @@
 //! Enhanced VGA text mode driver
 //! Extends the basic VGA buffer implementation with more features
+//!
+//! `init` checks there is text memory to draw on. With none, or with
+//! `console=serial` on the boot command line, the machine is headless:
+//! drawing does nothing, the GUI stays down and the shell talks over the
+//! serial port instead.
 
+use alloc::vec::Vec;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
+use crate::sync::DiagMutex;
 use volatile::Volatile;
 use crate::errors::KernelError;
 use crate::serial_println;
+use super::cp437;
 
 // VGA text buffer constants
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 const VGA_BUFFER_ADDR: usize = 0xb8000;
+/// What a read from an address nothing answers gives, byte by byte
+const FLOATING_BUS: u8 = 0xFF;
+
+/// Whether there is a screen to draw on; assumed until `init` probes
+static DISPLAY: AtomicBool = AtomicBool::new(true);
 
 // VGA colors
 #[allow(dead_code)]
@@ -46,9 +60,10 @@ impl ColorCode {
     }
 }
 
+/// One character cell: the character and its colors
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-struct ScreenChar {
+pub struct ScreenChar {
     ascii_character: u8,
     color_code: ColorCode,
 }
@@ -83,8 +98,8 @@ impl Writer {
             b'\r' => self.carriage_return(),
             b'\t' => self.write_tab(),
             b'\x08' => self.backspace(), // Backspace
-            // Handle printable ASCII
-            0x20..=0x7e => {
+            // Printable CP437, including the box-drawing range
+            0x20..=0xff => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
                 }
@@ -159,9 +174,19 @@ impl Writer {
         }
     }
 
+    /// Write one char, converted to CP437. Unknown control characters are
+    /// dropped and characters without a glyph show as '?'.
+    pub fn write_char(&mut self, c: char) {
+        match c {
+            '\n' | '\r' | '\t' | '\x08' => self.write_byte(c as u8),
+            c if c.is_control() => {}
+            c => self.write_byte(cp437::encode(c)),
+        }
+    }
+
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            self.write_byte(byte);
+        for c in s.chars() {
+            self.write_char(c);
         }
     }
 
@@ -300,13 +325,38 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new());
+    pub static ref WRITER: DiagMutex<Writer> = DiagMutex::new("vga_enhanced::WRITER", Writer::new());
 }
 
 // Global interface functions
 
+/// Whether what is drawn can be seen: false once `init` found no text
+/// memory, or was told `console=serial`
+pub fn display_available() -> bool {
+    DISPLAY.load(Ordering::Relaxed)
+}
+
+/// Write two patterns to the last cell and read each back, then put the
+/// cell back. Without text memory the reads float to all ones.
+fn probe() -> bool {
+    let mut writer = WRITER.lock();
+    let cell = &mut writer.buffer.chars[BUFFER_HEIGHT - 1][BUFFER_WIDTH - 1];
+    let saved = cell.read();
+    let answered = [0x5A, 0xA5].iter().all(|&pattern| {
+        let written = ScreenChar { ascii_character: pattern, color_code: ColorCode(!pattern) };
+        cell.write(written);
+        let read = cell.read();
+        read == written && read.ascii_character != FLOATING_BUS
+    });
+    cell.write(saved);
+    answered
+}
+
 /// Clear the screen
 pub fn clear_screen() {
+    if !display_available() {
+        return;
+    }
     serial_println!("DEBUG: vga_enhanced::clear_screen - Clearing screen");
     let mut writer = WRITER.lock();
     writer.clear_screen();
@@ -332,11 +382,17 @@ pub fn set_cursor_position(row: usize, column: usize) {
 
 /// Draw a box on the screen
 pub fn draw_box(x: usize, y: usize, width: usize, height: usize) {
+    if !display_available() {
+        return;
+    }
     WRITER.lock().draw_box(x, y, width, height);
 }
 
 /// Draw a shadowed box
 pub fn draw_shadowed_box(x: usize, y: usize, width: usize, height: usize) {
+    if !display_available() {
+        return;
+    }
     serial_println!("DEBUG: vga_enhanced::draw_shadowed_box - Drawing box at x={}, y={}, width={}, height={}", 
         x, y, width, height);
     
@@ -369,6 +425,9 @@ pub fn draw_shadowed_box(x: usize, y: usize, width: usize, height: usize) {
 
 /// Write a string at a specific position with specific colors
 pub fn write_at(row: usize, column: usize, s: &str, fg: Color, bg: Color) {    
+    if !display_available() {
+        return;
+    }
     // Bounds checking
     if row >= BUFFER_HEIGHT {
         serial_println!("DEBUG: vga_enhanced::write_at - Row {} out of bounds (max {})", row, BUFFER_HEIGHT-1);
@@ -388,6 +447,52 @@ pub fn write_at(row: usize, column: usize, s: &str, fg: Color, bg: Color) {
     writer.set_cursor_position(saved_position.0, saved_position.1);
 }
 
+/// Write a single char at a specific position with specific colors
+pub fn write_at_char(row: usize, column: usize, c: char, fg: Color, bg: Color) {
+    let mut buffer = [0u8; 4];
+    write_at(row, column, c.encode_utf8(&mut buffer), fg, bg);
+}
+
+/// Write one character cell directly, leaving the cursor and colors alone
+pub fn write_cell(row: usize, column: usize, byte: u8, fg: Color, bg: Color) {
+    if row < BUFFER_HEIGHT && column < BUFFER_WIDTH && display_available() {
+        WRITER.lock().buffer.chars[row][column].write(ScreenChar {
+            ascii_character: byte,
+            color_code: ColorCode::new(fg, bg),
+        });
+    }
+}
+
+/// Every cell as its character and color attribute bytes, row by row, all
+/// read under one lock so no write lands in the middle
+pub fn snapshot() -> Vec<[u8; 2]> {
+    let writer = WRITER.lock();
+    let mut cells = Vec::with_capacity(BUFFER_WIDTH * BUFFER_HEIGHT);
+    for row in writer.buffer.chars.iter() {
+        for cell in row.iter() {
+            let cell = cell.read();
+            cells.push([cell.ascii_character, cell.color_code.0]);
+        }
+    }
+    cells
+}
+
+/// Read one character cell, for saving what an overlay covers
+pub fn read_cell(row: usize, column: usize) -> Option<ScreenChar> {
+    if row < BUFFER_HEIGHT && column < BUFFER_WIDTH {
+        Some(WRITER.lock().buffer.chars[row][column].read())
+    } else {
+        None
+    }
+}
+
+/// Put back a cell saved with `read_cell`
+pub fn restore_cell(row: usize, column: usize, cell: ScreenChar) {
+    if row < BUFFER_HEIGHT && column < BUFFER_WIDTH && display_available() {
+        WRITER.lock().buffer.chars[row][column].write(cell);
+    }
+}
+
 /// Create a simple message box with a message
 pub fn message_box(title: &str, message: &str) {
     // Calculate box dimensions
@@ -406,17 +511,19 @@ pub fn message_box(title: &str, message: &str) {
     write_at(y + 2, x + 2, message, Color::White, Color::Black);
 }
 
-/// Initialize the VGA driver
+/// Initialize the VGA driver: find out whether there is a screen, and
+/// whether to use it, leaving what is on it alone. Needs the command line.
 pub fn init() -> Result<(), KernelError> {
     serial_println!("Initializing enhanced VGA text mode driver");
     
-    // Clear the screen
-    clear_screen();
-    
-    // Draw a welcome message
-    set_color(Color::White, Color::Blue);
-    write_at(0, 0, "UniverseK OS - Enhanced VGA Driver", Color::White, Color::Blue);
-    set_color(Color::LightGray, Color::Black);
+    let forced = crate::cmdline::value("console").as_deref() == Some("serial");
+    let present = probe();
+    DISPLAY.store(present && !forced, Ordering::Relaxed);
+    match (present, forced) {
+        (true, false) => serial_println!("VGA: Console mode: screen"),
+        (true, true) => serial_println!("VGA: Console mode: serial (console=serial given)"),
+        (false, _) => serial_println!("VGA: Console mode: serial (no display answered)"),
+    }
     
     Ok(())
 }
@@ -441,8 +548,47 @@ pub fn read_char_at(row: usize, column: usize) -> char {
     }
 }
 
+/// Write box-drawing text through the writer and check the CP437 bytes that
+/// land in the buffer, then put the cells back
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("VGA: Running self-test");
+
+    if !display_available() {
+        serial_println!("VGA: Self-test passed (headless, nothing to draw on)");
+        return Ok(());
+    }
+
+    let row = BUFFER_HEIGHT - 1;
+    let expected = [0xC9, 0xCD, 0xBB, b'a', cp437::REPLACEMENT];
+    let saved: [ScreenChar; 5] = {
+        let writer = WRITER.lock();
+        core::array::from_fn(|i| writer.buffer.chars[row][i].read())
+    };
+
+    write_at(row, 0, "╔═╗a€", Color::White, Color::Black);
+    let written: [u8; 5] = {
+        let writer = WRITER.lock();
+        core::array::from_fn(|i| writer.buffer.chars[row][i].read().ascii_character)
+    };
+
+    for (i, cell) in saved.iter().enumerate() {
+        restore_cell(row, i, *cell);
+    }
+
+    if written != expected {
+        serial_println!("VGA: Wrote {:x?}, expected {:x?}", written, expected);
+        return Err(KernelError::ValidationError("Text was not converted to CP437"));
+    }
+
+    serial_println!("VGA: Self-test passed");
+    Ok(())
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
+    if !display_available() {
+        return;
+    }
     WRITER.lock().write_fmt(args).unwrap();
 } 
\ No newline at end of file
@@ -36,6 +36,14 @@ pub enum KernelError {
     InvalidOperation,
     InitializationFailed,
     OutOfMemory,
+    BrokenPipe,
+    NoSpace,
+    /// A descriptor table is at `fs.max_open_files`
+    TooManyOpenFiles,
+    /// Cancelled before it finished
+    Interrupted,
+    /// A write to a read-only mount or device
+    ReadOnlyFilesystem,
 }
 
 #[derive(Debug)]
@@ -109,6 +117,11 @@ impl KernelError {
             KernelError::InvalidOperation => "Invalid operation",
             KernelError::InitializationFailed => "Initialization failed",
             KernelError::OutOfMemory => "Out of memory",
+            KernelError::BrokenPipe => "Broken pipe",
+            KernelError::NoSpace => "No space left on device",
+            KernelError::TooManyOpenFiles => "Too many open files",
+            KernelError::Interrupted => "Interrupted",
+            KernelError::ReadOnlyFilesystem => "Read-only file system",
         }
     }
 }
@@ -150,6 +163,9 @@ pub fn validate_memory_subsystem() -> Result<(), KernelError> {
         return Err(KernelError::MemoryError(MemoryError::HeapInitFailed));
     }
     
+    // Panics on a damaged block in heap_debug builds; otherwise does nothing
+    crate::allocator::check_heap();
+    
     Ok(())
 }
 
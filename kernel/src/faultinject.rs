@@ -0,0 +1,360 @@
+//! Fault injection: making chosen operations fail on demand
+//!
+//! Disks rarely fail and the heap rarely runs out while someone is
+//! watching, so the code that handles those errors mostly never runs.
+//! Code with such an error path calls `check` on one of the named points
+//! below just before the operation that could fail; `fault arm` makes a
+//! point's Nth hit fail with a chosen error, or stall for a while, once or
+//! every time until it's disarmed.
+//!
+//! Only `fault_injection` builds can arm points. Without the feature
+//! `check` is `Ok(())` and compiles away; with it, a disarmed point costs
+//! one load and a branch. Points are atomics only, with no lock or heap,
+//! because the allocator checks one and a fault may fire in any context.
+//!
+//! Arming `alloc` fails allocations made with `try_reserve` and the like
+//! cleanly, but any other allocation it hits panics as out of memory.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// A failure a point can be armed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    IoError,
+    ReadError,
+    WriteError,
+    Timeout,
+    NoSpace,
+    OutOfMemory,
+    /// Stall this many milliseconds, then carry on
+    Delay(u32),
+}
+
+/// Error names `error=` takes, in the order of their codes from 1
+const ERROR_NAMES: [(&str, Fault); 6] = [
+    ("IoError", Fault::IoError),
+    ("ReadError", Fault::ReadError),
+    ("WriteError", Fault::WriteError),
+    ("Timeout", Fault::Timeout),
+    ("NoSpace", Fault::NoSpace),
+    ("OutOfMemory", Fault::OutOfMemory),
+];
+
+/// Longest `delay=` accepted, in milliseconds
+const MAX_DELAY_MS: u32 = 10_000;
+
+impl Fault {
+    /// `error=<name>` or `delay=<ms>`
+    pub fn parse(text: &str) -> Option<Fault> {
+        if let Some(name) = text.strip_prefix("error=") {
+            return ERROR_NAMES.iter().find(|(known, _)| known.eq_ignore_ascii_case(name)).map(|&(_, fault)| fault);
+        }
+        let ms = text.strip_prefix("delay=")?.parse::<u32>().ok()?;
+        (ms <= MAX_DELAY_MS).then_some(Fault::Delay(ms))
+    }
+
+    /// The error the operation fails with; None for a delay
+    pub fn error(self) -> Option<KernelError> {
+        Some(match self {
+            Fault::IoError => KernelError::IoError,
+            Fault::ReadError => KernelError::ReadError,
+            Fault::WriteError => KernelError::WriteError,
+            Fault::Timeout => KernelError::DeviceTimeout,
+            Fault::NoSpace => KernelError::NoSpace,
+            Fault::OutOfMemory => KernelError::OutOfMemory,
+            Fault::Delay(_) => return None,
+        })
+    }
+
+    /// 0 for a delay, otherwise the error's place in ERROR_NAMES from 1
+    fn code(self) -> u8 {
+        ERROR_NAMES.iter().position(|&(_, fault)| fault == self).map_or(0, |index| index as u8 + 1)
+    }
+
+    fn from_code(code: u8, delay_ms: u32) -> Fault {
+        match code {
+            0 => Fault::Delay(delay_ms),
+            code => ERROR_NAMES.get(code as usize - 1).map_or(Fault::IoError, |&(_, fault)| fault),
+        }
+    }
+}
+
+impl core::fmt::Display for Fault {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Fault::Delay(ms) => write!(f, "delay={}", ms),
+            fault => write!(f, "error={:?}", fault),
+        }
+    }
+}
+
+/// How a point is armed: `[after N] [error=<name> | delay=<ms>] [times=N]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arming {
+    /// Hits let through before the first failure
+    pub after: u32,
+    pub fault: Fault,
+    /// Failures before the point disarms itself; 0 keeps failing
+    pub times: u32,
+}
+
+impl Default for Arming {
+    fn default() -> Self {
+        Arming { after: 0, fault: Fault::IoError, times: 1 }
+    }
+}
+
+impl Arming {
+    /// Read the words after `fault arm <point>`
+    pub fn parse(args: &[&str]) -> Result<Arming, KernelError> {
+        let mut arming = Arming::default();
+        let mut words = args.iter();
+        while let Some(&word) = words.next() {
+            if word == "after" {
+                let count = words.next().ok_or(KernelError::InvalidParameter)?;
+                arming.after = count.parse().map_err(|_| KernelError::InvalidParameter)?;
+            } else if let Some(times) = word.strip_prefix("times=") {
+                arming.times = times.parse().map_err(|_| KernelError::InvalidParameter)?;
+            } else {
+                arming.fault = Fault::parse(word).ok_or(KernelError::InvalidParameter)?;
+            }
+        }
+        Ok(arming)
+    }
+}
+
+/// A place an operation can be made to fail
+pub struct FaultPoint {
+    name: &'static str,
+    armed: AtomicBool,
+    /// Hits still to let through
+    skip: AtomicU32,
+    /// Failures still to come; 0 is no limit
+    left: AtomicU32,
+    fault_code: AtomicU8,
+    delay_ms: AtomicU32,
+    /// Hits while armed
+    hits: AtomicU64,
+    fired: AtomicU64,
+}
+
+impl FaultPoint {
+    pub const fn new(name: &'static str) -> Self {
+        FaultPoint {
+            name,
+            armed: AtomicBool::new(false),
+            skip: AtomicU32::new(0),
+            left: AtomicU32::new(0),
+            fault_code: AtomicU8::new(0),
+            delay_ms: AtomicU32::new(0),
+            hits: AtomicU64::new(0),
+            fired: AtomicU64::new(0),
+        }
+    }
+
+    /// Call just before the operation this point stands for: Err means
+    /// fail it with that error
+    #[inline(always)]
+    pub fn check(&self) -> Result<(), KernelError> {
+        #[cfg(feature = "fault_injection")]
+        if self.armed.load(Ordering::Relaxed) {
+            return self.hit();
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "fault_injection")]
+    #[cold]
+    fn hit(&self) -> Result<(), KernelError> {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if self.skip.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+            return Ok(());
+        }
+        // Taking the last failure disarms; a limit of 0 never runs out
+        if self.left.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1).filter(|_| n > 0)) == Ok(1) {
+            self.armed.store(false, Ordering::SeqCst);
+        }
+        self.fired.fetch_add(1, Ordering::Relaxed);
+        match self.fault() {
+            Fault::Delay(ms) => {
+                crate::drivers::pit::busy_sleep_us(u64::from(ms) * 1000);
+                Ok(())
+            }
+            fault => Err(fault.error().unwrap_or(KernelError::IoError)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed.load(Ordering::SeqCst)
+    }
+
+    /// What the point does when it fires
+    pub fn fault(&self) -> Fault {
+        Fault::from_code(self.fault_code.load(Ordering::SeqCst), self.delay_ms.load(Ordering::SeqCst))
+    }
+
+    /// Hits since it was last armed
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Failures and delays since it was last armed
+    pub fn fired(&self) -> u64 {
+        self.fired.load(Ordering::Relaxed)
+    }
+
+    fn arm(&self, arming: Arming) {
+        self.armed.store(false, Ordering::SeqCst);
+        self.skip.store(arming.after, Ordering::SeqCst);
+        self.left.store(arming.times, Ordering::SeqCst);
+        self.fault_code.store(arming.fault.code(), Ordering::SeqCst);
+        self.delay_ms.store(match arming.fault { Fault::Delay(ms) => ms, _ => 0 }, Ordering::SeqCst);
+        self.hits.store(0, Ordering::SeqCst);
+        self.fired.store(0, Ordering::SeqCst);
+        self.armed.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop failing or delaying; for tests that armed the point themselves
+    pub fn disarm(&self) {
+        self.armed.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Sector reads from the ATA disk
+pub static ATA_READ: FaultPoint = FaultPoint::new("ata.read");
+/// Sector writes to the ATA disk
+pub static ATA_WRITE: FaultPoint = FaultPoint::new("ata.write");
+/// Block cache misses, read from the device
+pub static CACHE_READ: FaultPoint = FaultPoint::new("cache.read");
+/// Dirty blocks written back from the block cache
+pub static CACHE_WRITE: FaultPoint = FaultPoint::new("cache.write");
+/// Writes to a TempFs file
+pub static TEMPFS_WRITE: FaultPoint = FaultPoint::new("tempfs.write_at");
+/// Heap allocations, and reallocations that grow a block
+pub static ALLOC: FaultPoint = FaultPoint::new("alloc");
+
+static POINTS: [&FaultPoint; 6] = [&ATA_READ, &ATA_WRITE, &CACHE_READ, &CACHE_WRITE, &TEMPFS_WRITE, &ALLOC];
+
+/// Whether this build can arm points
+pub const fn enabled() -> bool {
+    cfg!(feature = "fault_injection")
+}
+
+/// Every point, for listings
+pub fn points() -> impl Iterator<Item = &'static FaultPoint> {
+    POINTS.iter().copied()
+}
+
+pub fn find(name: &str) -> Option<&'static FaultPoint> {
+    points().find(|point| point.name == name)
+}
+
+/// Arm point `name`, starting its counts afresh. Allocates nothing, so
+/// arming `alloc` fails the caller's next allocation, not one of its own.
+pub fn arm(name: &str, arming: Arming) -> Result<(), KernelError> {
+    if !enabled() {
+        return Err(KernelError::UnsupportedFeature);
+    }
+    find(name).ok_or(KernelError::NotFound)?.arm(arming);
+    Ok(())
+}
+
+pub fn disarm(name: &str) -> Result<(), KernelError> {
+    find(name).ok_or(KernelError::NotFound)?.disarm();
+    Ok(())
+}
+
+pub fn disarm_all() {
+    points().for_each(FaultPoint::disarm);
+}
+
+/// One line per point for `fault list`
+pub fn list_text() -> Vec<String> {
+    points().map(|point| {
+        let state = if point.is_armed() { format!("armed {}", point.fault()) } else { String::from("off") };
+        format!("{:<16} {:<22} {:>6} hits {:>4} fired", point.name, state, point.hits(), point.fired())
+    }).collect()
+}
+
+/// Check arming is read correctly and, in builds that can arm points,
+/// that a point fires on the hit and as often as asked, and that an armed
+/// `alloc` fails a fallible allocation and task creation
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("FAULT: Running self-test");
+
+    let parsed = [
+        (&[][..], Some(Arming::default())),
+        (&["after", "3", "error=NoSpace"][..], Some(Arming { after: 3, fault: Fault::NoSpace, times: 1 })),
+        (&["delay=50", "times=0"][..], Some(Arming { after: 0, fault: Fault::Delay(50), times: 0 })),
+        (&["error=timeout"][..], Some(Arming { fault: Fault::Timeout, ..Arming::default() })),
+        (&["after"][..], None),
+        (&["error=Bogus"][..], None),
+        (&["delay=99999"][..], None),
+    ];
+    for (args, expected) in parsed {
+        if Arming::parse(args).ok() != expected {
+            serial_println!("FAULT: {:?} parsed as {:?}", args, Arming::parse(args));
+            return Err(KernelError::ValidationError("Fault arming misread"));
+        }
+    }
+    if ERROR_NAMES.iter().any(|&(_, fault)| Fault::from_code(fault.code(), 0) != fault)
+        || Fault::from_code(Fault::Delay(7).code(), 7) != Fault::Delay(7) {
+        return Err(KernelError::ValidationError("Fault didn't survive being stored"));
+    }
+
+    if !enabled() {
+        if arm("tempfs.write_at", Arming::default()).is_ok() {
+            return Err(KernelError::ValidationError("Armed a fault without fault_injection"));
+        }
+        serial_println!("FAULT: Built without fault_injection, skipping the live points");
+        serial_println!("FAULT: Self-test passed");
+        return Ok(());
+    }
+
+    // A point of the test's own, so nothing else hits it meanwhile
+    let point = FaultPoint::new("selftest");
+    point.arm(Arming { after: 2, fault: Fault::WriteError, times: 1 });
+    let outcomes: Vec<bool> = (0..4).map(|_| point.check().is_err()).collect();
+    if outcomes != [false, false, true, false] || point.is_armed() || point.hits() != 3 || point.fired() != 1 {
+        serial_println!("FAULT: One-shot fault went {:?}", outcomes);
+        return Err(KernelError::ValidationError("Fault fired on the wrong hit"));
+    }
+    point.arm(Arming { after: 0, fault: Fault::Delay(0), times: 0 });
+    if (0..3).any(|_| point.check().is_err()) || !point.is_armed() || point.fired() != 3 {
+        return Err(KernelError::ValidationError("Repeating delay failed or stopped"));
+    }
+    point.disarm();
+    if point.check().is_err() || arm("no.such.point", Arming::default()).is_ok() {
+        return Err(KernelError::ValidationError("Disarmed or unknown point misbehaved"));
+    }
+
+    // The allocation right after arming is the one that fails
+    arm(ALLOC.name(), Arming::default())?;
+    let mut reserved: Vec<u8> = Vec::new();
+    let refused = reserved.try_reserve_exact(64).is_err();
+    let task = crate::task::Task::new(|| {});
+    let created = task.is_ok();
+    drop(task);
+    ALLOC.disarm();
+    if !refused || !created || ALLOC.fired() != 1 {
+        return Err(KernelError::ValidationError("Armed alloc didn't fail the next allocation alone"));
+    }
+    arm(ALLOC.name(), Arming::default())?;
+    let task = crate::task::Task::new(|| {});
+    ALLOC.disarm();
+    if task.is_ok() {
+        return Err(KernelError::ValidationError("Task created without its stack"));
+    }
+
+    serial_println!("FAULT: Self-test passed");
+    Ok(())
+}
@@ -1,102 +1,632 @@
+//! Adapter from a device::BlockDevice (an ATA disk or ATAPI CD-ROM) to the
+//! fs::BlockDevice interface the file systems use, with a block cache in
+//! between. Writes to a CD-ROM, or to a disk made read-only with
+//! `set_read_only`, fail at once with ReadOnlyFilesystem.
+//!
+//! PIO transfers cost the same per command whatever their length, so the
+//! cache tries to issue fewer, longer ones:
+//! - Reads that follow on from the previous request pull in up to
+//!   `fs.readahead_blocks` further blocks with the same command. If the last
+//!   three requests were all non-contiguous, the access is taken to be
+//!   random and there is no readahead.
+//! - Writes stay in the cache until a flush. Runs of adjacent dirty blocks
+//!   then go out as single multi-sector writes.
+//!
+//! Dirty blocks are flushed when too many pile up, when the adapter is
+//! dropped, before devices are suspended (`flush_all`), and about once a
+//! second from the idle path.
+//!
+//! Each adapter registers I/O counters of its own, `<device>-cache`, for
+//! the requests made of it; the device's counters show what got past.
+
 use crate::device;
-use crate::device::BlockDevice as DeviceBlockDevice;
+use crate::device::iostats::{self, IoLayer, IoStats};
 use crate::fs::block_device::BlockDevice;
-use alloc::sync::Arc;
-use alloc::string::String;
-use alloc::string::ToString;
-use spin::Mutex;
 use crate::errors::KernelError;
+use crate::faultinject;
+use crate::serial_println;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Readahead window when the configuration doesn't set one
+const DEFAULT_READAHEAD_BLOCKS: usize = 16;
+/// Blocks the cache holds
+const CACHE_BLOCKS: usize = 256;
+/// Dirty blocks allowed before a flush is forced
+const MAX_DIRTY_BLOCKS: usize = 64;
+/// Longest single transfer (ATA sector counts are 8 bits)
+const MAX_TRANSFER_BLOCKS: usize = 128;
+/// How often the idle path writes dirty blocks back
+const IDLE_FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// `fs.readahead_blocks`, cached: reading the configuration can itself go
+/// through this cache
+static READAHEAD_BLOCKS: AtomicUsize = AtomicUsize::new(DEFAULT_READAHEAD_BLOCKS);
+static LAST_IDLE_FLUSH_MS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Every live cache, for `flush_all` and `all_stats`
+    static ref CACHES: Mutex<Vec<Weak<Mutex<BlockCache>>>> = Mutex::new(Vec::new());
+}
+
+/// Block cache counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Block reads answered from the cache
+    pub hits: u64,
+    pub misses: u64,
+    /// Hits on blocks that readahead brought in
+    pub readahead_hits: u64,
+    /// Blocks read ahead of being asked for
+    pub readahead_blocks: u64,
+    /// Read commands issued to the device
+    pub device_reads: u64,
+    /// Write commands issued to the device
+    pub device_writes: u64,
+    /// Blocks written back to the device
+    pub blocks_written: u64,
+}
+
+/// The device under the cache, addressed in whole-block runs
+trait RawBlocks: Send {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    fn read_blocks(&mut self, start: u64, count: usize, buffer: &mut [u8]) -> Result<(), KernelError>;
+    fn write_blocks(&mut self, start: u64, count: usize, buffer: &[u8]) -> Result<(), KernelError>;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// An ATA disk in the device registry
+struct AtaBlocks {
+    device: Arc<Mutex<dyn device::Device>>,
+}
+
+impl AtaBlocks {
+    fn with_ata<R>(&self, f: impl FnOnce(&mut device::ata::AtaDevice) -> R) -> Result<R, KernelError> {
+        let mut device = self.device.lock();
+        let ata = device.as_any_mut().downcast_mut::<device::ata::AtaDevice>()
+            .ok_or(KernelError::UnsupportedFeature)?;
+        Ok(f(ata))
+    }
+}
+
+impl RawBlocks for AtaBlocks {
+    fn block_size(&self) -> usize {
+        // Default size if not a known block device
+        self.with_ata(|ata| ata.block_size()).unwrap_or(512)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.with_ata(|ata| ata.block_count() as u64).unwrap_or(0)
+    }
+
+    fn read_blocks(&mut self, start: u64, count: usize, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let lba = u32::try_from(start).map_err(|_| KernelError::InvalidParameter)?;
+        let count = u8::try_from(count).map_err(|_| KernelError::InvalidParameter)?;
+        self.with_ata(|ata| ata.read_sectors(lba, count, buffer))?
+    }
+
+    fn write_blocks(&mut self, start: u64, count: usize, buffer: &[u8]) -> Result<(), KernelError> {
+        let lba = u32::try_from(start).map_err(|_| KernelError::InvalidParameter)?;
+        let count = u8::try_from(count).map_err(|_| KernelError::InvalidParameter)?;
+        self.with_ata(|ata| ata.write_sectors(lba, count, buffer))?
+    }
+}
+
+/// An ATAPI CD-ROM drive in the device registry
+struct AtapiBlocks {
+    device: Arc<Mutex<dyn device::Device>>,
+}
+
+impl AtapiBlocks {
+    fn with_atapi<R>(&self, f: impl FnOnce(&mut device::atapi::AtapiDevice) -> R) -> Result<R, KernelError> {
+        let mut device = self.device.lock();
+        let atapi = device.as_any_mut().downcast_mut::<device::atapi::AtapiDevice>()
+            .ok_or(KernelError::UnsupportedFeature)?;
+        Ok(f(atapi))
+    }
+}
+
+impl RawBlocks for AtapiBlocks {
+    fn block_size(&self) -> usize {
+        device::atapi::SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.with_atapi(|atapi| device::BlockDevice::block_count(atapi) as u64).unwrap_or(0)
+    }
+
+    fn read_blocks(&mut self, start: u64, count: usize, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let lba = u32::try_from(start).map_err(|_| KernelError::InvalidParameter)?;
+        let count = u16::try_from(count).map_err(|_| KernelError::InvalidParameter)?;
+        self.with_atapi(|atapi| atapi.read_sectors(lba, count, buffer))?
+    }
+
+    fn write_blocks(&mut self, _start: u64, _count: usize, _buffer: &[u8]) -> Result<(), KernelError> {
+        Err(KernelError::UnsupportedFeature)
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    /// Brought in by readahead and not asked for yet
+    prefetched: bool,
+    last_use: u64,
+}
+
+struct BlockCache {
+    raw: Box<dyn RawBlocks>,
+    block_size: usize,
+    block_count: u64,
+    blocks: BTreeMap<u64, CachedBlock>,
+    /// Use counter for least-recently-used eviction
+    clock: u64,
+    /// Block after the previous request
+    next_expected: Option<u64>,
+    /// Whether each of the last three requests followed on from the one before
+    recent_contiguous: [bool; 3],
+    stats: CacheStats,
+    /// Refuse writes: always for a CD-ROM, on request for a disk
+    read_only: bool,
+}
+
+impl BlockCache {
+    fn new(raw: Box<dyn RawBlocks>) -> Self {
+        BlockCache {
+            block_size: raw.block_size(),
+            block_count: raw.block_count(),
+            read_only: raw.read_only(),
+            raw,
+            blocks: BTreeMap::new(),
+            clock: 0,
+            next_expected: None,
+            recent_contiguous: [false; 3],
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Note a request for `block`, returning whether it looks sequential
+    /// enough to read ahead: it follows on from the previous request, and
+    /// the three before weren't all scattered
+    fn track(&mut self, block: u64) -> bool {
+        let contiguous = self.next_expected == Some(block);
+        let sequential = contiguous && self.recent_contiguous.iter().any(|&c| c);
+        self.recent_contiguous.rotate_left(1);
+        self.recent_contiguous[2] = contiguous;
+        self.next_expected = Some(block + 1);
+        sequential
+    }
+
+    fn touch(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), KernelError> {
+        if block >= self.block_count || buffer.len() < self.block_size {
+            return Err(KernelError::InvalidParameter);
+        }
+        let sequential = self.track(block);
+        let now = self.touch();
+
+        if let Some(cached) = self.blocks.get_mut(&block) {
+            cached.last_use = now;
+            if cached.prefetched {
+                cached.prefetched = false;
+                self.stats.readahead_hits += 1;
+            }
+            buffer[..self.block_size].copy_from_slice(&cached.data);
+            self.stats.hits += 1;
+            return Ok(());
+        }
+        self.stats.misses += 1;
+
+        // Read ahead over blocks not cached yet, stopping at the first cached one
+        let window = if sequential { READAHEAD_BLOCKS.load(Ordering::Relaxed) } else { 0 };
+        let mut count = 1;
+        while count <= window && count < MAX_TRANSFER_BLOCKS && block + (count as u64) < self.block_count
+            && !self.blocks.contains_key(&(block + count as u64)) {
+            count += 1;
+        }
+
+        let mut data = vec![0u8; count * self.block_size];
+        faultinject::CACHE_READ.check()?;
+        self.raw.read_blocks(block, count, &mut data)?;
+        self.stats.device_reads += 1;
+        self.stats.readahead_blocks += (count - 1) as u64;
+
+        buffer[..self.block_size].copy_from_slice(&data[..self.block_size]);
+        for (i, chunk) in data.chunks(self.block_size).enumerate() {
+            self.insert(block + i as u64, chunk.to_vec(), false, i > 0, now)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<(), KernelError> {
+        if self.read_only {
+            return Err(KernelError::ReadOnlyFilesystem);
+        }
+        if block >= self.block_count || buffer.len() < self.block_size {
+            return Err(KernelError::InvalidParameter);
+        }
+        let now = self.touch();
+        match self.blocks.get_mut(&block) {
+            Some(cached) => {
+                cached.data.copy_from_slice(&buffer[..self.block_size]);
+                cached.dirty = true;
+                cached.prefetched = false;
+                cached.last_use = now;
+            }
+            None => self.insert(block, buffer[..self.block_size].to_vec(), true, false, now)?,
+        }
+        if self.blocks.values().filter(|cached| cached.dirty).count() > MAX_DIRTY_BLOCKS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, block: u64, data: Vec<u8>, dirty: bool, prefetched: bool, now: u64) -> Result<(), KernelError> {
+        if self.blocks.len() >= CACHE_BLOCKS {
+            self.evict()?;
+        }
+        self.blocks.insert(block, CachedBlock { data, dirty, prefetched, last_use: now });
+        Ok(())
+    }
+
+    /// Drop the least recently used clean block, writing back first if
+    /// every block is dirty
+    fn evict(&mut self) -> Result<(), KernelError> {
+        if self.blocks.values().all(|cached| cached.dirty) {
+            self.flush()?;
+        }
+        let victim = self.blocks.iter()
+            .filter(|(_, cached)| !cached.dirty)
+            .min_by_key(|(_, cached)| cached.last_use)
+            .map(|(&block, _)| block);
+        if let Some(block) = victim {
+            self.blocks.remove(&block);
+        }
+        Ok(())
+    }
+
+    /// Write every dirty block back, one command per run of adjacent blocks
+    fn flush(&mut self) -> Result<(), KernelError> {
+        let dirty: Vec<u64> = self.blocks.iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+
+        let mut index = 0;
+        while index < dirty.len() {
+            let start = dirty[index];
+            let mut count = 1;
+            while index + count < dirty.len() && dirty[index + count] == start + count as u64
+                && count < MAX_TRANSFER_BLOCKS {
+                count += 1;
+            }
+
+            let mut data = Vec::with_capacity(count * self.block_size);
+            for block in start..start + count as u64 {
+                data.extend_from_slice(&self.blocks[&block].data);
+            }
+            faultinject::CACHE_WRITE.check()?;
+            self.raw.write_blocks(start, count, &data)?;
+            self.stats.device_writes += 1;
+            self.stats.blocks_written += count as u64;
+            for block in start..start + count as u64 {
+                if let Some(cached) = self.blocks.get_mut(&block) {
+                    cached.dirty = false;
+                }
+            }
+            index += count;
+        }
+        Ok(())
+    }
+}
 
 /// Adapter to use a device::BlockDevice as a fs::BlockDevice
 pub struct DeviceBlockAdapter {
-    device: Arc<Mutex<dyn device::Device>>,
+    cache: Arc<Mutex<BlockCache>>,
     name: String,
+    /// The device's ID in the device registry
+    device_id: Option<u64>,
+    /// Requests made of the cache
+    stats: Arc<IoStats>,
 }
 
 impl DeviceBlockAdapter {
     /// Create a new adapter for a device
     pub fn new(device: Arc<Mutex<dyn device::Device>>) -> Self {
-        let name = {
+        let (name, id, is_atapi) = {
             let device_guard = device.lock();
-            device_guard.name().to_string()
+            (device_guard.name().to_string(), device_guard.id(), device_guard.as_any().is::<device::atapi::AtapiDevice>())
         };
-        
-        Self { 
-            device,
-            name
-        }
+        let raw: Box<dyn RawBlocks> = if is_atapi {
+            Box::new(AtapiBlocks { device })
+        } else {
+            Box::new(AtaBlocks { device })
+        };
+        let mut adapter = Self::with_blocks(name, raw);
+        adapter.device_id = Some(id);
+        adapter
     }
-    
+
+    fn with_blocks(name: String, raw: Box<dyn RawBlocks>) -> Self {
+        let cache = BlockCache::new(raw);
+        let stats = iostats::register(device::generate_device_id(), &format!("{}-cache", name),
+            IoLayer::Cache, cache.block_size);
+        let cache = Arc::new(Mutex::new(cache));
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(&cache));
+        Self { cache, name, device_id: None, stats }
+    }
+
     /// Create a new adapter for the first available block device
     pub fn new_first_available() -> Result<Self, KernelError> {
         // Get all block devices
         let block_devices = device::get_block_devices();
-        
+
         if block_devices.is_empty() {
             return Err(KernelError::DeviceNotFound);
         }
-        
+
         // Use the first device
         let device = block_devices[0].clone();
-        
+
         Ok(Self::new(device))
     }
-    
+
     /// Get the name of the underlying device
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Cache counters for this device
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().stats
+    }
+
+    /// Refuse writes, or allow them again. A CD-ROM stays read-only.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        let mut cache = self.cache.lock();
+        cache.read_only = read_only || cache.raw.read_only();
+    }
 }
 
 impl BlockDevice for DeviceBlockAdapter {
     fn block_size(&self) -> usize {
-        let device_guard = self.device.lock();
-        
-        // Try to downcast to BlockDevice
-        if let Some(block_device) = device_guard.as_any().downcast_ref::<device::ata::AtaDevice>() {
-            // Need to use the trait method through DeviceBlockDevice trait
-            return block_device.block_size();
-        }
-        
-        // Default size if not a known block device
-        512
+        self.cache.lock().block_size
     }
-    
+
     fn block_count(&self) -> u64 {
-        let device_guard = self.device.lock();
-        
-        // Try to downcast to BlockDevice
-        if let Some(block_device) = device_guard.as_any().downcast_ref::<device::ata::AtaDevice>() {
-            return block_device.block_count() as u64;
-        }
-        
-        // Default size if not a known block device
-        0
+        self.cache.lock().block_count
     }
-    
+
     fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
-        let device_guard = self.device.lock();
-        
-        // Try to downcast to BlockDevice
-        if let Some(block_device) = device_guard.as_any().downcast_ref::<device::ata::AtaDevice>() {
-            return block_device.read_block(block_id as usize, buffer)
-                .map_err(|e| e.to_str());
-        }
-        
-        Err("Device is not a block device")
+        self.stats.read(1, || self.cache.lock().read(block_id, buffer)).map_err(|e| e.to_str())
     }
-    
+
     fn write_block(&mut self, block_id: u64, buffer: &[u8]) -> Result<(), &'static str> {
-        let mut device_guard = self.device.lock();
-        
-        // Try to downcast to BlockDevice
-        if let Some(block_device) = device_guard.as_any_mut().downcast_mut::<device::ata::AtaDevice>() {
-            return block_device.write_block(block_id as usize, buffer)
-                .map_err(|e| e.to_str());
+        self.stats.write(1, || self.cache.lock().write(block_id, buffer)).map_err(|e| e.to_str())
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        self.cache.lock().flush().map_err(|e| e.to_str())
+    }
+
+    fn read_only(&self) -> bool {
+        self.cache.lock().read_only
+    }
+
+    fn device_id(&self) -> Option<u64> {
+        self.device_id
+    }
+}
+
+impl Drop for DeviceBlockAdapter {
+    fn drop(&mut self) {
+        if let Err(e) = self.cache.lock().flush() {
+            serial_println!("DEBUG: Block cache for {} lost writes on drop: {:?}", self.name, e);
+        }
+    }
+}
+
+impl crate::fs::block_device::BlockDeviceMarker for DeviceBlockAdapter {}
+
+/// Read `fs.readahead_blocks`, and again whenever it changes
+pub fn init() {
+    reload("fs.readahead_blocks");
+    crate::config::subscribe("fs.readahead_blocks", reload);
+}
+
+fn reload(_key: &str) {
+    let blocks = crate::config::get("fs.readahead_blocks")
+        .and_then(|value| value.try_as_integer())
+        .filter(|blocks| *blocks >= 0)
+        .map_or(DEFAULT_READAHEAD_BLOCKS, |blocks| (blocks as usize).min(MAX_TRANSFER_BLOCKS - 1));
+    READAHEAD_BLOCKS.store(blocks, Ordering::Relaxed);
+}
+
+fn live_caches() -> Vec<Arc<Mutex<BlockCache>>> {
+    CACHES.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Write back the dirty blocks of every cache, e.g. before the disks are
+/// suspended. Returns how many caches failed.
+pub fn flush_all() -> usize {
+    let mut failed = 0;
+    for cache in live_caches() {
+        if let Err(e) = cache.lock().flush() {
+            serial_println!("DEBUG: Block cache flush failed: {:?}", e);
+            failed += 1;
         }
-        
-        Err("Device is not a block device")
     }
+    failed
 }
 
-impl crate::fs::block_device::BlockDeviceMarker for DeviceBlockAdapter {} 
\ No newline at end of file
+/// Flush from the idle path if the last flush was long enough ago
+pub fn idle_flush() {
+    let now = crate::time::monotonic_ms();
+    if now.saturating_sub(LAST_IDLE_FLUSH_MS.load(Ordering::Relaxed)) >= IDLE_FLUSH_INTERVAL_MS {
+        LAST_IDLE_FLUSH_MS.store(now, Ordering::Relaxed);
+        flush_all();
+    }
+}
+
+/// Counters of every live cache
+pub fn all_stats() -> Vec<CacheStats> {
+    live_caches().iter().map(|cache| cache.lock().stats).collect()
+}
+
+/// Blocks in memory, for the self-test
+struct MemoryBlocks {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl RawBlocks for MemoryBlocks {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / 512) as u64
+    }
+
+    fn read_blocks(&mut self, start: u64, count: usize, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let start = start as usize * 512;
+        buffer[..count * 512].copy_from_slice(&self.data.lock()[start..start + count * 512]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, count: usize, buffer: &[u8]) -> Result<(), KernelError> {
+        let start = start as usize * 512;
+        self.data.lock()[start..start + count * 512].copy_from_slice(&buffer[..count * 512]);
+        Ok(())
+    }
+}
+
+/// Replay scripted access patterns against an in-memory disk and check
+/// readahead and write coalescing cut the number of device commands
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("BLOCKCACHE: Running self-test");
+
+    const BLOCKS: usize = 512;
+    let data: Vec<u8> = (0..BLOCKS * 512).map(|i| (i / 512) as u8 ^ (i % 251) as u8).collect();
+    let disk = Arc::new(Mutex::new(data));
+    let adapter = |disk: &Arc<Mutex<Vec<u8>>>| {
+        DeviceBlockAdapter::with_blocks("selftest".to_string(), Box::new(MemoryBlocks { data: disk.clone() }))
+    };
+    let expected = |disk: &Arc<Mutex<Vec<u8>>>, block: u64| {
+        let start = block as usize * 512;
+        disk.lock()[start..start + 512].to_vec()
+    };
+    let mut buffer = [0u8; 512];
+
+    // Sequential: 64 single-block reads should take a handful of commands
+    let sequential = adapter(&disk);
+    for block in 0..64 {
+        sequential.read_block(block, &mut buffer).map_err(KernelError::GenericError)?;
+        if buffer[..] != expected(&disk, block)[..] {
+            return Err(KernelError::ValidationError("Cached read returned the wrong data"));
+        }
+    }
+    let stats = sequential.stats();
+    let window = READAHEAD_BLOCKS.load(Ordering::Relaxed) as u64;
+    if window > 0 && (stats.device_reads > 64 / 4 || stats.readahead_hits == 0) {
+        return Err(KernelError::ValidationError("Sequential reads weren't read ahead"));
+    }
+    serial_println!("BLOCKCACHE:   sequential: 64 reads -> {} device reads, {} read ahead",
+        stats.device_reads, stats.readahead_blocks);
+
+    // Random: after three non-contiguous requests, nothing is read ahead
+    let random = adapter(&disk);
+    for block in [300, 7, 150, 151, 90, 400, 33] {
+        random.read_block(block, &mut buffer).map_err(KernelError::GenericError)?;
+    }
+    let read_ahead = random.stats().readahead_blocks;
+    random.read_block(210, &mut buffer).map_err(KernelError::GenericError)?;
+    random.read_block(460, &mut buffer).map_err(KernelError::GenericError)?;
+    random.read_block(461, &mut buffer).map_err(KernelError::GenericError)?;
+    if random.stats().readahead_blocks != read_ahead {
+        return Err(KernelError::ValidationError("Random reads triggered readahead"));
+    }
+
+    // Writes: two runs of adjacent blocks become two commands on flush
+    let mut writer = adapter(&disk);
+    let pattern = [0xA5u8; 512];
+    for block in (10..20).chain([40, 41]) {
+        writer.write_block(block, &pattern).map_err(KernelError::GenericError)?;
+    }
+    if expected(&disk, 10)[..] == pattern[..] {
+        return Err(KernelError::ValidationError("Write went to the device before a flush"));
+    }
+    writer.flush().map_err(KernelError::GenericError)?;
+    let stats = writer.stats();
+    if stats.device_writes != 2 || stats.blocks_written != 12 {
+        return Err(KernelError::ValidationError("Adjacent dirty blocks weren't coalesced"));
+    }
+    if (10..20).chain([40, 41]).any(|block| expected(&disk, block)[..] != pattern[..]) {
+        return Err(KernelError::ValidationError("Flushed blocks didn't reach the device"));
+    }
+
+    // Dropping the adapter writes back what is left
+    writer.write_block(99, &pattern).map_err(KernelError::GenericError)?;
+    drop(writer);
+    if expected(&disk, 99)[..] != pattern[..] {
+        return Err(KernelError::ValidationError("Dirty block lost when the adapter was dropped"));
+    }
+
+    // A failed write-back keeps its blocks dirty for the next flush, and a
+    // failed fill caches nothing
+    if faultinject::enabled() {
+        let mut faulty = adapter(&disk);
+        let fresh = [0x5Au8; 512];
+        faulty.write_block(120, &fresh).map_err(KernelError::GenericError)?;
+        faultinject::arm(faultinject::CACHE_WRITE.name(), faultinject::Arming::default())?;
+        let failed = faulty.flush().is_err();
+        faultinject::CACHE_WRITE.disarm();
+        if !failed || expected(&disk, 120)[..] == fresh[..] {
+            return Err(KernelError::ValidationError("Write-back fault didn't fail the flush"));
+        }
+        faulty.flush().map_err(KernelError::GenericError)?;
+        if expected(&disk, 120)[..] != fresh[..] {
+            return Err(KernelError::ValidationError("Blocks lost after a failed write-back"));
+        }
+
+        faultinject::arm(faultinject::CACHE_READ.name(), faultinject::Arming::default())?;
+        let failed = faulty.read_block(250, &mut buffer).is_err();
+        faultinject::CACHE_READ.disarm();
+        faulty.read_block(250, &mut buffer).map_err(KernelError::GenericError)?;
+        if !failed || faulty.stats().hits != 0 || buffer[..] != expected(&disk, 250)[..] {
+            return Err(KernelError::ValidationError("Failed fill left a block cached"));
+        }
+    }
+
+    // A read-only disk still reads, but no write reaches it
+    let mut protected = adapter(&disk);
+    protected.set_read_only(true);
+    if protected.write_block(100, &[0u8; 512]).is_ok() || protected.read_block(100, &mut buffer).is_err()
+        || expected(&disk, 100)[..] == [0u8; 512][..] {
+        return Err(KernelError::ValidationError("Read-only disk took a write"));
+    }
+
+    serial_println!("BLOCKCACHE: Self-test passed");
+    Ok(())
+}
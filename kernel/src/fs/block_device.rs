@@ -1,3 +1,9 @@
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
 /// A marker trait for BlockDevice, ensures Send+Sync for all block devices
 pub trait BlockDeviceMarker: Send + Sync {}
 
@@ -15,9 +21,50 @@ pub trait BlockDevice: BlockDeviceMarker {
     /// Write a block from the provided buffer
     fn write_block(&mut self, block_id: u64, buffer: &[u8]) -> Result<(), &'static str>;
 
+    /// Write back anything the device holds in memory
+    fn flush(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Whether writes are refused, as they are on CD-ROMs
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// ID of the disk underneath, the same for every adapter over it, so
+    /// the VFS can tell whether the disk is in use
+    fn device_id(&self) -> Option<u64> {
+        None
+    }
+
     // It might be useful to have read/write methods that operate on multiple blocks
     // or at byte offsets, but for now, single block operations are sufficient.
 }
 
 // We can also define a helper for block size, e.g., 512 bytes, if it's common.
-pub const DEFAULT_BLOCK_SIZE: usize = 512; 
\ No newline at end of file
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+pub type SharedBlockDevice = Arc<Mutex<dyn BlockDevice>>;
+
+lazy_static! {
+    /// Block devices with no driver in the device registry, by name
+    static ref NAMED: Mutex<Vec<(String, Weak<Mutex<dyn BlockDevice>>)>> = Mutex::new(Vec::new());
+}
+
+/// Let `dd` and the like find a block device the device registry doesn't
+/// know about, such as a RamDisk, by `name`. It's forgotten once dropped.
+pub fn register(name: &str, device: &SharedBlockDevice) {
+    let mut named = NAMED.lock();
+    named.retain(|(existing, device)| existing != name && device.strong_count() > 0);
+    named.push((String::from(name), Arc::downgrade(device)));
+}
+
+/// The registered block device called `name`
+pub fn find(name: &str) -> Option<SharedBlockDevice> {
+    NAMED.lock().iter().find(|(existing, _)| existing == name).and_then(|(_, device)| device.upgrade())
+}
+
+/// Names of the registered block devices still around
+pub fn names() -> Vec<String> {
+    NAMED.lock().iter().filter(|(_, device)| device.strong_count() > 0).map(|(name, _)| name.clone()).collect()
+}
@@ -0,0 +1,446 @@
+//! Raw copies between block devices and files, for `dd`
+//!
+//! Either end is a block device, named as `ram0`, `/dev/ram0` or, for a
+//! disk in the device registry, by its ID or the first word of its name,
+//! or else a file or character device opened through a descriptor. Data
+//! moves in records of `bs` bytes, one buffer at a time, so a whole disk
+//! never has to fit in memory. A record cut short by the end of the input
+//! is copied as it is and ends the copy; one cut short by the end of an
+//! output device is written as far as it fits, then the copy fails with
+//! NoSpace.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::fs::block_adapter::{self, DeviceBlockAdapter};
+use crate::fs::block_device::{self, BlockDevice, SharedBlockDevice};
+use crate::fs::{fd, vfs::file_flags};
+use crate::serial_println;
+
+/// Record size when `bs` isn't given
+pub const DEFAULT_RECORD_SIZE: usize = 512;
+/// Largest `bs`, which is also the most memory a copy holds
+pub const MAX_RECORD_SIZE: usize = 1024 * 1024;
+/// Progress is reported this often, in MB, unless `progress=` says
+pub const DEFAULT_PROGRESS_MB: u64 = 1;
+const MB: u64 = 1_000_000;
+
+/// What a `dd` command line asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub input: String,
+    pub output: String,
+    /// Record size in bytes
+    pub record_size: usize,
+    /// Records to copy, or all of the input
+    pub count: Option<u64>,
+    /// Records of the output to leave alone before the first written
+    pub seek: u64,
+    /// Records of the input to pass over before the first read
+    pub skip: u64,
+    /// MB between progress reports, 0 for none
+    pub progress_mb: u64,
+    /// Write to a mounted device anyway
+    pub force: bool,
+}
+
+impl Request {
+    /// Parse `if=SRC of=DST [bs=N] [count=N] [seek=N] [skip=N] [progress=N]
+    /// [--force]`. Sizes take a K, M or G suffix (powers of 1024).
+    pub fn parse(args: &[&str]) -> Result<Self, KernelError> {
+        let (mut input, mut output) = (None, None);
+        let mut request = Self {
+            input: String::new(),
+            output: String::new(),
+            record_size: DEFAULT_RECORD_SIZE,
+            count: None,
+            seek: 0,
+            skip: 0,
+            progress_mb: DEFAULT_PROGRESS_MB,
+            force: false,
+        };
+        for arg in args {
+            if *arg == "--force" {
+                request.force = true;
+                continue;
+            }
+            let (key, value) = arg.split_once('=').ok_or(KernelError::InvalidParameter)?;
+            match key {
+                "if" if !value.is_empty() => input = Some(value.to_string()),
+                "of" if !value.is_empty() => output = Some(value.to_string()),
+                "bs" => request.record_size = parse_size(value)
+                    .filter(|&size| size > 0 && size <= MAX_RECORD_SIZE as u64)
+                    .ok_or(KernelError::InvalidParameter)? as usize,
+                "count" => request.count = Some(parse_size(value).ok_or(KernelError::InvalidParameter)?),
+                "seek" => request.seek = parse_size(value).ok_or(KernelError::InvalidParameter)?,
+                "skip" => request.skip = parse_size(value).ok_or(KernelError::InvalidParameter)?,
+                "progress" => request.progress_mb = value.parse().map_err(|_| KernelError::InvalidParameter)?,
+                _ => return Err(KernelError::InvalidParameter),
+            }
+        }
+        request.input = input.ok_or(KernelError::InvalidParameter)?;
+        request.output = output.ok_or(KernelError::InvalidParameter)?;
+        Ok(request)
+    }
+}
+
+/// A number with an optional K, M or G suffix
+fn parse_size(text: &str) -> Option<u64> {
+    let (digits, unit) = match text.as_bytes().last()? {
+        b'K' | b'k' => (&text[..text.len() - 1], 1024),
+        b'M' | b'm' => (&text[..text.len() - 1], 1024 * 1024),
+        b'G' | b'g' => (&text[..text.len() - 1], 1024 * 1024 * 1024),
+        _ => (text, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// The block device `spec` names, with its name: a registered one such as
+/// a RamDisk, or a disk in the device registry behind a cache of its own.
+/// `/dev/` in front is allowed.
+pub fn find_device(spec: &str) -> Option<(String, SharedBlockDevice)> {
+    let name = spec.strip_prefix("/dev/").unwrap_or(spec);
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    if let Some(device) = block_device::find(name) {
+        return Some((name.to_string(), device));
+    }
+    let device = crate::device::find_block_device(name)?;
+    // Whatever the mounted file system hasn't written back yet would be
+    // missed by a second cache
+    block_adapter::flush_all();
+    let adapter: SharedBlockDevice = Arc::new(Mutex::new(DeviceBlockAdapter::new(device)));
+    Some((name.to_string(), adapter))
+}
+
+/// Where the file system on `device` is mounted, if it is
+pub fn mounted_at(device: &SharedBlockDevice) -> Option<String> {
+    let id = device.lock().device_id()?;
+    crate::fs::vfs::get_vfs_manager()?.mount_of_device(id)
+}
+
+/// One end of a copy
+pub enum Endpoint {
+    Device { name: String, device: SharedBlockDevice },
+    /// A file or character device, through a descriptor positioned where
+    /// the copy starts
+    File { path: String, fd: u32 },
+}
+
+impl Endpoint {
+    /// Open the file at `path` to read from `offset`
+    pub fn open_input_file(path: &str, offset: u64) -> Result<Self, KernelError> {
+        let fd = fd::open_tagged("dd", path, file_flags::READ)?;
+        Self::positioned(path, fd, offset, false)
+    }
+
+    /// Open or create the file at `path`, cut it at `offset` and write from
+    /// there
+    pub fn open_output_file(path: &str, offset: u64) -> Result<Self, KernelError> {
+        let fd = fd::open_tagged("dd", path, file_flags::WRITE | file_flags::CREATE)?;
+        Self::positioned(path, fd, offset, true)
+    }
+
+    fn positioned(path: &str, fd: u32, offset: u64, truncate: bool) -> Result<Self, KernelError> {
+        let result = if truncate { fd::truncate(fd, offset) } else { Ok(()) }.and_then(|_| fd::seek(fd, offset));
+        if let Err(e) = result {
+            let _ = fd::close(fd);
+            return Err(e);
+        }
+        Ok(Endpoint::File { path: path.to_string(), fd })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Endpoint::Device { name, .. } => name,
+            Endpoint::File { path, .. } => path,
+        }
+    }
+
+    /// Records must be whole blocks of a device; files take any size
+    pub fn block_size(&self) -> usize {
+        match self {
+            Endpoint::Device { device, .. } => device.lock().block_size(),
+            Endpoint::File { .. } => 1,
+        }
+    }
+
+    /// Whether it's a device refusing writes. Read-only files fail when
+    /// opened.
+    pub fn read_only(&self) -> bool {
+        match self {
+            Endpoint::Device { device, .. } => device.lock().read_only(),
+            Endpoint::File { .. } => false,
+        }
+    }
+
+    /// Read up to `buffer.len()` bytes from byte `offset` of a device, or
+    /// the next bytes of a file; fewer only at the end
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        match self {
+            Endpoint::Device { device, .. } => {
+                let device = device.lock();
+                let block_size = device.block_size();
+                let first = offset / block_size as u64;
+                let blocks = (buffer.len() / block_size) as u64;
+                let blocks = blocks.min(device.block_count().saturating_sub(first)) as usize;
+                for (index, block) in buffer.chunks_mut(block_size).take(blocks).enumerate() {
+                    device.read_block(first + index as u64, block).map_err(|_| KernelError::ReadError)?;
+                }
+                Ok(blocks * block_size)
+            }
+            Endpoint::File { fd, .. } => {
+                let mut filled = 0;
+                while filled < buffer.len() {
+                    let read = fd::read(*fd, &mut buffer[filled..])?;
+                    if read == 0 {
+                        break;
+                    }
+                    filled += read;
+                }
+                Ok(filled)
+            }
+        }
+    }
+
+    /// Write `buffer` at byte `offset` of a device, or next in a file;
+    /// returns how much fitted
+    fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
+        match self {
+            Endpoint::Device { device, .. } => {
+                let mut device = device.lock();
+                let block_size = device.block_size();
+                let first = offset / block_size as u64;
+                let blocks = (buffer.len() / block_size) as u64;
+                let blocks = blocks.min(device.block_count().saturating_sub(first)) as usize;
+                for (index, block) in buffer.chunks(block_size).take(blocks).enumerate() {
+                    device.write_block(first + index as u64, block).map_err(|_| KernelError::WriteError)?;
+                }
+                Ok(blocks * block_size)
+            }
+            Endpoint::File { fd, .. } => {
+                let mut written = 0;
+                while written < buffer.len() {
+                    match fd::write(*fd, &buffer[written..])? {
+                        0 => break,
+                        count => written += count,
+                    }
+                }
+                Ok(written)
+            }
+        }
+    }
+
+    /// Write back a device's cache, or close a file
+    pub fn finish(self) -> Result<(), KernelError> {
+        match self {
+            Endpoint::Device { device, .. } => device.lock().flush().map_err(|_| KernelError::WriteError),
+            Endpoint::File { fd, .. } => fd::close(fd),
+        }
+    }
+}
+
+/// How far a copy got
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub bytes: u64,
+    /// Whole and partial records read
+    pub records_in: (u64, u64),
+    /// Whole and partial records written
+    pub records_out: (u64, u64),
+    pub elapsed_ns: u64,
+}
+
+impl Summary {
+    /// Bytes per second, or 0 before any time has passed
+    pub fn rate(&self) -> u64 {
+        if self.elapsed_ns == 0 {
+            return 0;
+        }
+        (self.bytes as u128 * 1_000_000_000 / self.elapsed_ns as u128) as u64
+    }
+
+    /// Short line for progress reports
+    pub fn progress_line(&self) -> String {
+        format!("{} copied, {}", format_mb(self.bytes), format_rate(self.rate()))
+    }
+}
+
+impl core::fmt::Display for Summary {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "{}+{} records in", self.records_in.0, self.records_in.1)?;
+        writeln!(f, "{}+{} records out", self.records_out.0, self.records_out.1)?;
+        let ms = self.elapsed_ns / 1_000_000;
+        write!(f, "{} bytes ({}) copied, {}.{:03} s, {}", self.bytes, format_mb(self.bytes),
+            ms / 1000, ms % 1000, format_rate(self.rate()))
+    }
+}
+
+fn format_mb(bytes: u64) -> String {
+    format!("{}.{} MB", bytes / MB, bytes % MB / (MB / 10))
+}
+
+fn format_rate(bytes_per_second: u64) -> String {
+    format!("{}/s", format_mb(bytes_per_second))
+}
+
+/// Copy as `request` says from `input` to `output`, keeping `summary` up
+/// to date. `on_record` is called after each record, with whether another
+/// `progress_mb` has gone by; an error from it stops the copy. Fails with
+/// ReadOnlyFilesystem for a read-only output device, and InvalidParameter
+/// if records aren't whole blocks of either device.
+pub fn copy(
+    input: &mut Endpoint,
+    output: &mut Endpoint,
+    request: &Request,
+    summary: &mut Summary,
+    on_record: &mut dyn FnMut(&Summary, bool) -> Result<(), KernelError>,
+) -> Result<(), KernelError> {
+    if output.read_only() {
+        return Err(KernelError::ReadOnlyFilesystem);
+    }
+    if request.record_size % input.block_size() != 0 || request.record_size % output.block_size() != 0 {
+        return Err(KernelError::InvalidParameter);
+    }
+    let started = crate::time::monotonic_ns();
+    let size = request.record_size as u64;
+    let mut buffer = vec![0u8; request.record_size];
+    let mut read_at = request.skip * size;
+    let mut write_at = request.seek * size;
+    let report_every = request.progress_mb * MB;
+    let mut next_report = report_every;
+
+    while request.count.map_or(true, |count| summary.records_in.0 + summary.records_in.1 < count) {
+        let read = input.read(read_at, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        if read == buffer.len() { summary.records_in.0 += 1 } else { summary.records_in.1 += 1 }
+        read_at += read as u64;
+
+        let written = output.write(write_at, &buffer[..read])?;
+        if written == buffer.len() { summary.records_out.0 += 1 } else if written > 0 { summary.records_out.1 += 1 }
+        write_at += written as u64;
+        summary.bytes += written as u64;
+        summary.elapsed_ns = crate::time::monotonic_ns().saturating_sub(started);
+        if written < read {
+            return Err(KernelError::NoSpace);
+        }
+
+        let report = report_every > 0 && summary.bytes >= next_report;
+        if report {
+            next_report = (summary.bytes / report_every + 1) * report_every;
+        }
+        on_record(summary, report)?;
+        if read < buffer.len() {
+            break;
+        }
+    }
+    summary.elapsed_ns = crate::time::monotonic_ns().saturating_sub(started);
+    Ok(())
+}
+
+/// Clone one RamDisk onto another and compare them block for block, then
+/// check records cut short at either end, offsets, the read-only flag and
+/// the mount check
+pub fn self_test() -> Result<(), KernelError> {
+    use crate::fs::fat::{self, FatFileSystem};
+    use crate::fs::ramdisk::RamDisk;
+    use crate::fs::vfs::MountFlags;
+    use crate::sync::DiagMutex;
+
+    serial_println!("DD: Running self-test");
+
+    let disk = |blocks: u64| -> Result<(String, SharedBlockDevice), KernelError> {
+        let disk = RamDisk::with_capacity(blocks, block_device::DEFAULT_BLOCK_SIZE)?;
+        let name = disk.name().to_string();
+        let device: SharedBlockDevice = Arc::new(Mutex::new(disk));
+        block_device::register(&name, &device);
+        Ok((name, device))
+    };
+    let endpoint = |(name, device): &(String, SharedBlockDevice)| Endpoint::Device { name: name.clone(), device: device.clone() };
+    let run = |input: &mut Endpoint, output: &mut Endpoint, args: &[&str]| -> (Summary, Result<(), KernelError>) {
+        let mut summary = Summary::default();
+        let result = Request::parse(args).and_then(|request| copy(input, output, &request, &mut summary, &mut |_, _| Ok(())));
+        (summary, result)
+    };
+
+    // 100 blocks of a pattern, then bs=4K: 12 whole records and a half one
+    let source = disk(100)?;
+    let mut block = [0u8; 512];
+    for index in 0..100u64 {
+        block.fill(index as u8 ^ 0x5A);
+        block[..8].copy_from_slice(&index.to_le_bytes());
+        source.1.lock().write_block(index, &block)?;
+    }
+    let target = disk(100)?;
+    if find_device(&format!("/dev/{}", source.0)).is_none() || find_device(&target.0).is_none() {
+        return Err(KernelError::ValidationError("dd couldn't find a registered RamDisk"));
+    }
+    let (summary, result) = run(&mut endpoint(&source), &mut endpoint(&target), &["if=a", "of=b", "bs=4K"]);
+    result?;
+    if summary.bytes != 100 * 512 || summary.records_in != (12, 1) || summary.records_out != (12, 1) {
+        return Err(KernelError::ValidationError("dd counted records wrongly at the end of the device"));
+    }
+    let mut other = [0u8; 512];
+    for index in 0..100 {
+        source.1.lock().read_block(index, &mut block)?;
+        target.1.lock().read_block(index, &mut other)?;
+        if block != other {
+            return Err(KernelError::ValidationError("Cloned RamDisk differs from the original"));
+        }
+    }
+
+    // skip=2 seek=1 count=3 of 1K records: source blocks 4-9 land on 2-7
+    let small = disk(20)?;
+    let (summary, result) = run(&mut endpoint(&source), &mut endpoint(&small), &["if=a", "of=b", "bs=1K", "skip=2", "seek=1", "count=3"]);
+    result?;
+    small.1.lock().read_block(2, &mut block)?;
+    small.1.lock().read_block(1, &mut other)?;
+    if summary.bytes != 3 * 1024 || block[..8] != 4u64.to_le_bytes() || other.iter().any(|&byte| byte != 0) {
+        return Err(KernelError::ValidationError("dd skip, seek or count misplaced the data"));
+    }
+
+    // Only 20 blocks fit, so the rest is refused after a partial record
+    let (summary, result) = run(&mut endpoint(&source), &mut endpoint(&small), &["if=a", "of=b", "bs=3K"]);
+    if !matches!(result, Err(KernelError::NoSpace)) || summary.bytes != 20 * 512 || summary.records_out != (3, 1) {
+        return Err(KernelError::ValidationError("dd past the end of the output wasn't stopped"));
+    }
+
+    // Nothing reaches a read-only disk, and records must be whole blocks
+    let mut locked = RamDisk::with_capacity(20, block_device::DEFAULT_BLOCK_SIZE)?;
+    locked.set_read_only(true);
+    let locked: SharedBlockDevice = Arc::new(Mutex::new(locked));
+    let (_, result) = run(&mut endpoint(&source), &mut endpoint(&(String::from("locked"), locked)), &["if=a", "of=b"]);
+    let (_, odd) = run(&mut endpoint(&source), &mut endpoint(&small), &["if=a", "of=b", "bs=700"]);
+    if !matches!(result, Err(KernelError::ReadOnlyFilesystem)) || !matches!(odd, Err(KernelError::InvalidParameter)) {
+        return Err(KernelError::ValidationError("dd wrote to a read-only disk or in part blocks"));
+    }
+
+    if !matches!(Request::parse(&["if=a"]), Err(KernelError::InvalidParameter))
+        || !matches!(Request::parse(&["if=a", "of=b", "bs=2M"]), Err(KernelError::InvalidParameter))
+        || Request::parse(&["if=a", "of=b", "count=2k", "--force"]).map(|r| (r.count, r.force)).ok() != Some((Some(2048), true)) {
+        return Err(KernelError::ValidationError("dd arguments parsed wrongly"));
+    }
+
+    // A FAT volume mounted from the clone makes it busy
+    if let Some(vfs) = crate::fs::vfs::get_vfs_manager() {
+        let volume = disk(4096 * 2)?;
+        fat::format(&mut *volume.1.lock(), &fat::FormatOptions::default())?;
+        let fs = FatFileSystem::new(volume.1.clone())?;
+        let mount = "/dd-selftest";
+        vfs.mount(mount, Arc::new(DiagMutex::new("fs:dd-selftest", fs)), MountFlags::NONE)?;
+        let busy = mounted_at(&volume.1);
+        vfs.unmount(mount)?;
+        if busy.as_deref() != Some(mount) || mounted_at(&volume.1).is_some() || mounted_at(&source.1).is_some() {
+            return Err(KernelError::ValidationError("dd mount check wrong"));
+        }
+    }
+
+    serial_println!("DD: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! File system of character devices, mounted at /dev
+//!
+//! Each file is a name and a registered `CharacterDevice`. Reads and writes
+//! go straight to the device; offsets mean nothing to a stream, so they are
+//! ignored. Drivers add their devices with `register`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::device::CharacterDevice;
+use crate::errors::KernelError;
+use crate::fs::vfs::{permissions, DirEntry, FileSystem, Metadata, MetadataUpdate, NodeType};
+use crate::serial_println;
+
+type SharedDevice = Arc<Mutex<dyn CharacterDevice>>;
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<(&'static str, SharedDevice)>> = Mutex::new(Vec::new());
+}
+
+/// Add /dev/<name>. Fails with AlreadyExists if the name is taken.
+pub fn register(name: &'static str, device: SharedDevice) -> Result<(), KernelError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|(existing, _)| *existing == name) {
+        return Err(KernelError::AlreadyExists);
+    }
+    devices.push((name, device));
+    Ok(())
+}
+
+fn device(name: &str) -> Option<SharedDevice> {
+    DEVICES.lock().iter().find(|(existing, _)| *existing == name).map(|(_, device)| device.clone())
+}
+
+pub struct DevFs {
+    /// Where it's mounted; the VFS passes whole paths
+    prefix: String,
+}
+
+impl DevFs {
+    pub fn new(prefix: &str) -> Self {
+        Self { prefix: String::from(prefix.trim_end_matches('/')) }
+    }
+
+    /// The device name `path` refers to, or None for the directory itself
+    fn device_name<'a>(&self, path: &'a str) -> Result<Option<&'a str>, KernelError> {
+        let rest = path.strip_prefix(self.prefix.as_str()).ok_or(KernelError::NotFound)?;
+        let name = rest.trim_matches('/');
+        if name.is_empty() {
+            return Ok(None);
+        }
+        if name.contains('/') {
+            return Err(KernelError::NotFound);
+        }
+        Ok(Some(name))
+    }
+
+    fn device(&self, path: &str) -> Result<SharedDevice, KernelError> {
+        let name = self.device_name(path)?.ok_or(KernelError::IsADirectory)?;
+        device(name).ok_or(KernelError::NotFound)
+    }
+
+    fn refuse() -> Result<(), KernelError> {
+        Err(KernelError::UnsupportedFeature)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn mount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn unmount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn create_file(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse()
+    }
+
+    fn create_directory(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse()
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse()
+    }
+
+    fn open(&mut self, path: &str, _write: bool) -> Result<Option<usize>, KernelError> {
+        self.device(path)?;
+        Ok(None)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
+        if self.device_name(path)?.is_none() {
+            let mut metadata = Metadata::new_directory();
+            metadata.permissions = permissions::ALL & !permissions::ALL_WRITE;
+            return Ok(metadata);
+        }
+        self.device(path)?;
+        let mut metadata = Metadata::new_file();
+        metadata.node_type = NodeType::CharacterDevice;
+        metadata.permissions = permissions::READ | permissions::WRITE | permissions::GROUP_READ
+            | permissions::GROUP_WRITE | permissions::OTHERS_READ | permissions::OTHERS_WRITE;
+        Ok(metadata)
+    }
+
+    fn set_metadata(&mut self, _path: &str, _update: MetadataUpdate) -> Result<(), KernelError> {
+        Self::refuse()
+    }
+
+    fn read_dir_from(&self, path: &str, cursor: usize, out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError> {
+        if self.device_name(path)?.is_some() {
+            return Err(KernelError::NotADirectory);
+        }
+        // Devices are only ever added, at the end, so an index stays valid
+        let devices = DEVICES.lock();
+        let remaining = devices.len().saturating_sub(cursor);
+        let filled = remaining.min(out.len());
+        for (slot, (index, (name, _))) in devices.iter().enumerate().skip(cursor).take(filled).enumerate() {
+            out[slot] = Some(DirEntry::new(name, NodeType::CharacterDevice, index + 1));
+        }
+        let next = if cursor + filled < devices.len() { Some(cursor + filled) } else { None };
+        Ok((filled, next))
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), KernelError> {
+        Self::refuse()
+    }
+
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn total_space(&self) -> u64 {
+        0
+    }
+
+    fn available_space(&self) -> u64 {
+        0
+    }
+
+    fn read_at(&self, path: &str, _offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        self.device(path)?.lock().read(buffer)
+    }
+
+    fn write_at(&mut self, path: &str, _offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
+        self.device(path)?.lock().write(buffer)
+    }
+
+    fn truncate(&mut self, path: &str, _length: u64) -> Result<(), KernelError> {
+        // A stream has no length; opening with TRUNCATE just works
+        self.device(path).map(|_| ())
+    }
+}
+
+/// Check the memory devices through descriptors, as programs see them:
+/// /dev/full reads zeros but fails writes with NoSpace, and /dev/null
+/// takes writes and reads as empty
+pub fn self_test() -> Result<(), KernelError> {
+    use crate::fs::{fd, vfs::file_flags};
+    serial_println!("DEVFS: Running self-test");
+
+    let full = fd::open("/dev/full", file_flags::READ | file_flags::WRITE)?;
+    let mut buffer = [0xAAu8; 8];
+    let written = fd::write(full, b"data");
+    let read = fd::read(full, &mut buffer);
+    fd::close(full)?;
+    if !matches!(written, Err(KernelError::NoSpace)) || read? != buffer.len() || buffer != [0; 8] {
+        return Err(KernelError::ValidationError("/dev/full write didn't fail with NoSpace"));
+    }
+
+    let null = fd::open("/dev/null", file_flags::READ | file_flags::WRITE | file_flags::TRUNCATE)?;
+    let written = fd::write(null, b"gone");
+    let read = fd::read(null, &mut buffer);
+    fd::close(null)?;
+    if written? != 4 || read? != 0 {
+        return Err(KernelError::ValidationError("/dev/null read or write wrong"));
+    }
+
+    serial_println!("DEVFS: Self-test passed");
+    Ok(())
+}
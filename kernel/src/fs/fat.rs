@@ -1,8 +1,11 @@
 use crate::errors::KernelError;
-use crate::fs::vfs::{FileSystem, FileHandle, Metadata, DirEntry, NodeType};
+use crate::fs::vfs::{attributes, permissions, CheckReport, FileSystem, Metadata, MetadataUpdate, DirEntry, NodeType};
+use crate::fs::walk;
+use crate::serial_println;
 use crate::fs::block_device::BlockDevice;
 use alloc::vec::Vec;
 use alloc::vec;
+use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
 use spin::Mutex;
@@ -27,6 +30,7 @@ pub enum FatError {
     NotFound,
     AlreadyExists,
     DirectoryFull,
+    VolumeFull,
     NotADirectory,
     NotAFile,
     IoError,
@@ -43,6 +47,7 @@ impl From<FatError> for KernelError {
             FatError::NotFound => KernelError::NotFound,
             FatError::AlreadyExists => KernelError::AlreadyExists,
             FatError::DirectoryFull => KernelError::DirectoryFull,
+            FatError::VolumeFull => KernelError::NoSpace,
             FatError::NotADirectory => KernelError::NotADirectory,
             FatError::NotAFile => KernelError::NotAFile,
             FatError::IoError => KernelError::IoError,
@@ -111,10 +116,35 @@ const ATTR_DIRECTORY: u8 = 0x10;
 const ATTR_ARCHIVE: u8 = 0x20;
 const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
 
+// The VFS attribute flags for an entry's attribute byte
+fn vfs_attributes(attr: u8) -> u8 {
+    [
+        (ATTR_READ_ONLY, attributes::READ_ONLY),
+        (ATTR_HIDDEN, attributes::HIDDEN),
+        (ATTR_SYSTEM, attributes::SYSTEM),
+        (ATTR_ARCHIVE, attributes::ARCHIVE),
+    ].iter().filter(|(fat, _)| attr & fat != 0).fold(0, |bits, (_, vfs)| bits | vfs)
+}
+
 // Special FAT cluster values
 const FAT_EOC: u32 = 0x0FFFFFF8; // End of cluster chain
 const FAT_BAD: u32 = 0x0FFFFFF7; // Bad cluster
 
+// Offset of the extended boot signature in a FAT12/16 boot sector
+const FAT16_SIGNATURE_OFFSET: usize = 0x26;
+
+// Layouts written by format
+const FAT16_RESERVED_SECTORS: u32 = 1;
+const FAT32_RESERVED_SECTORS: u32 = 32;
+const FORMAT_FAT_COUNT: u32 = 2;
+const FAT16_ROOT_ENTRIES: u32 = 512;
+const FAT32_FSINFO_SECTOR: u32 = 1;
+const FAT32_BACKUP_BOOT_SECTOR: u32 = 6;
+// Cluster counts that make a volume FAT16 or FAT32
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+const FAT32_MAX_CLUSTERS: u32 = 0x0FFF_FFF5;
+
 // The FAT file system implementation
 pub struct FatFileSystem {
     // The underlying block device
@@ -135,6 +165,16 @@ pub struct FatFileSystem {
     total_clusters: u32,
     // For FAT32
     root_cluster: u32,
+    // Unused clusters, counted at mount and kept up to date as clusters
+    // are allocated and freed
+    free_clusters: u32,
+    // Where the search for a free cluster starts
+    next_free: u32,
+    // Set when the device refuses writes; every change then fails up front
+    read_only: bool,
+    // One sector, for reads that start or end partway through one; whole
+    // sectors are read straight into the caller's buffer
+    bounce: Mutex<Vec<u8>>,
 }
 
 impl FatFileSystem {
@@ -155,9 +195,15 @@ impl FatFileSystem {
             data_sectors: 0,
             total_clusters: 0,
             root_cluster: 0,
+            free_clusters: 0,
+            next_free: 2,
+            read_only: device.lock().read_only(),
+            bounce: Mutex::new(Vec::new()),
         };
         
         fs.read_boot_sector()?;
+        fs.bounce = Mutex::new(vec![0u8; fs.bytes_per_sector as usize]);
+        fs.free_clusters = fs.count_free_clusters()?;
         Ok(fs)
     }
     
@@ -175,8 +221,14 @@ impl FatFileSystem {
             &*(buffer.as_ptr() as *const FatBootSector)
         };
         
-        // Check the signature
-        if boot_sector.boot_signature != 0x29 {
+        // Check the signature. FAT12/16 keep it at 0x26, where FAT32 has
+        // its own fields; FAT32 has it at 0x42.
+        let signature = if boot_sector.sectors_per_fat_16 != 0 {
+            buffer[FAT16_SIGNATURE_OFFSET]
+        } else {
+            boot_sector.boot_signature
+        };
+        if signature != 0x29 {
             return Err(FatError::InvalidSignature.into());
         }
         
@@ -290,6 +342,125 @@ impl FatFileSystem {
         }
     }
     
+    // Count the unused clusters by scanning the first FAT. Entries are read
+    // through a one-sector cache, since read_fat_entry would read a sector
+    // for every cluster.
+    fn count_free_clusters(&self) -> Result<u32, KernelError> {
+        let sector_size = self.bytes_per_sector as u32;
+        let mut cached: Option<(u32, Vec<u8>)> = None;
+        let mut byte_at = |offset: u32| -> Result<u32, KernelError> {
+            let sector = self.first_fat_sector + offset / sector_size;
+            if cached.as_ref().map(|(number, _)| *number) != Some(sector) {
+                let mut buffer = vec![0u8; sector_size as usize];
+                self.device.lock().read_block(sector as u64, &mut buffer)
+                    .map_err(|_| FatError::ReadError)?;
+                cached = Some((sector, buffer));
+            }
+            Ok(cached.as_ref().map_or(0, |(_, buffer)| buffer[(offset % sector_size) as usize] as u32))
+        };
+        
+        let mut free = 0;
+        for cluster in 2..self.total_clusters + 2 {
+            let value = match self.fat_type {
+                FatType::Fat12 => {
+                    let offset = cluster * 3 / 2;
+                    let raw = byte_at(offset)? | (byte_at(offset + 1)? << 8);
+                    if cluster & 1 == 0 { raw & 0xFFF } else { raw >> 4 }
+                },
+                FatType::Fat16 => {
+                    let offset = cluster * 2;
+                    byte_at(offset)? | (byte_at(offset + 1)? << 8)
+                },
+                FatType::Fat32 => {
+                    let offset = cluster * 4;
+                    let raw = byte_at(offset)? | (byte_at(offset + 1)? << 8) |
+                              (byte_at(offset + 2)? << 16) | (byte_at(offset + 3)? << 24);
+                    raw & 0x0FFFFFFF
+                },
+            };
+            if value == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+    
+    // Whether a FAT value points at another cluster, rather than ending
+    // the chain or being out of range
+    fn is_next_cluster(&self, value: u32) -> bool {
+        value >= 2 && value < self.total_clusters + 2
+    }
+    
+    // Whether a FAT value ends a chain, for this FAT width
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat12 => value >= 0xFF8,
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= FAT_EOC,
+        }
+    }
+    
+    // Follow a chain, marking its clusters in `seen`. Returns the chain's
+    // length and whether it ended cleanly. A cluster already marked belongs
+    // earlier in this chain (a loop) or to another chain (a cross), and
+    // either way the walk stops there.
+    fn check_chain(&self, path: &str, start: u32, seen: &mut [u8],
+                   report: &mut CheckReport) -> Result<(u32, bool), KernelError> {
+        let mut cluster = start;
+        let mut length = 0;
+        while !self.is_end_of_chain(cluster) {
+            if cluster < 2 || cluster >= self.total_clusters + 2 {
+                report.problems.push(format!("{}: chain runs to invalid cluster {:#x}", path, cluster));
+                return Ok((length, false));
+            }
+            let (byte, bit) = ((cluster / 8) as usize, cluster % 8);
+            if seen[byte] & (1 << bit) != 0 {
+                report.problems.push(format!("{}: chain loops or crosses another at cluster {}", path, cluster));
+                return Ok((length, false));
+            }
+            seen[byte] |= 1 << bit;
+            length += 1;
+            cluster = self.read_fat_entry(cluster)?;
+        }
+        Ok((length, true))
+    }
+    
+    // Check the chains of everything under a directory whose own chain has
+    // already been checked
+    fn check_directory(&self, path: &str, depth: usize, seen: &mut [u8],
+                       report: &mut CheckReport) -> Result<(), KernelError> {
+        let mut children = Vec::new();
+        self.scan_directory(path, 0, &mut |_, entry| {
+            if !Self::is_dot_entry(entry) && entry.attr & ATTR_VOLUME_ID == 0 {
+                let name = self.fat_name_to_string(&entry.name, &entry.ext);
+                children.push((name, Self::get_cluster(entry), entry.size, Self::is_directory(entry)));
+            }
+            true
+        })?;
+        
+        let bytes_per_cluster = self.sectors_per_cluster as u64 * self.bytes_per_sector as u64;
+        for (name, start, size, is_directory) in children {
+            let child = walk::join(path, &name);
+            let (length, intact) = if start == 0 {
+                (0, true)
+            } else {
+                self.check_chain(&child, start, seen, report)?
+            };
+            
+            if is_directory {
+                // A broken directory chain can't be read safely
+                if intact && depth < walk::MAX_DEPTH {
+                    if let Err(e) = self.check_directory(&child, depth + 1, seen, report) {
+                        report.problems.push(format!("{}: unreadable ({:?})", child, e));
+                    }
+                }
+            } else if intact && length as u64 != (size as u64).div_ceil(bytes_per_cluster) {
+                report.problems.push(format!("{}: size {} does not fit its {} clusters", child, size, length));
+            }
+        }
+        Ok(())
+    }
+    
     // Read a cluster into a buffer
     fn read_cluster(&self, cluster: u32, buffer: &mut [u8]) -> Result<(), KernelError> {
         let first_sector = self.cluster_to_sector(cluster);
@@ -313,29 +484,49 @@ impl FatFileSystem {
         Ok(())
     }
     
+    // Read `data.len()` bytes from `at` bytes into a cluster. Whole sectors
+    // go from the device straight into `data`; a partial one at either end
+    // goes through the bounce buffer.
+    fn read_in_cluster(&self, cluster: u32, at: usize, data: &mut [u8]) -> Result<(), KernelError> {
+        let sector_size = self.bytes_per_sector as usize;
+        if at + data.len() > self.cluster_size() {
+            return Err(FatError::InvalidParameter.into());
+        }
+        let first_sector = self.cluster_to_sector(cluster) as u64;
+        let device = self.device.lock();
+        let mut done = 0;
+        while done < data.len() {
+            let sector = first_sector + ((at + done) / sector_size) as u64;
+            let within = (at + done) % sector_size;
+            let count = (sector_size - within).min(data.len() - done);
+            if count == sector_size {
+                device.read_block(sector, &mut data[done..done + count])
+                    .map_err(|_| FatError::ReadError)?;
+            } else {
+                let mut bounce = self.bounce.lock();
+                device.read_block(sector, &mut bounce).map_err(|_| FatError::ReadError)?;
+                data[done..done + count].copy_from_slice(&bounce[within..within + count]);
+            }
+            done += count;
+        }
+        Ok(())
+    }
+    
     // Read a file cluster chain into a buffer
     fn read_file(&self, start_cluster: u32, buffer: &mut [u8]) -> Result<usize, KernelError> {
         let mut total_read = 0;
         let bytes_per_cluster = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
         let mut cluster = start_cluster;
         
-        while cluster < FAT_EOC && total_read < buffer.len() {
+        while self.is_next_cluster(cluster) && total_read < buffer.len() {
             let bytes_to_read = core::cmp::min(bytes_per_cluster, buffer.len() - total_read);
-            
-            // Allocate a temporary buffer for the cluster
-            let mut cluster_buffer = vec![0u8; bytes_per_cluster];
-            self.read_cluster(cluster, &mut cluster_buffer)?;
-            
-            // Copy data to the output buffer
-            buffer[total_read..total_read + bytes_to_read]
-                .copy_from_slice(&cluster_buffer[..bytes_to_read]);
-            
+            self.read_in_cluster(cluster, 0, &mut buffer[total_read..total_read + bytes_to_read])?;
             total_read += bytes_to_read;
             
             // Get next cluster in the chain
             if total_read < buffer.len() {
                 let next_cluster = self.read_fat_entry(cluster)?;
-                if next_cluster >= FAT_EOC {
+                if !self.is_next_cluster(next_cluster) {
                     break; // End of file
                 }
                 cluster = next_cluster;
@@ -394,12 +585,80 @@ impl FatFileSystem {
         Ok(entries)
     }
     
+    // Hand the directory's entries to `visit` one at a time with their slot
+    // numbers, starting at slot `start`, until it returns false or the
+    // directory ends. Only one sector or cluster is held at a time.
+    fn scan_directory(&self, path: &str, start: usize,
+                      visit: &mut dyn FnMut(usize, &FatDirEntry) -> bool) -> Result<(), KernelError> {
+        let entry = self.path_to_entry(path)?;
+        if !Self::is_directory(&entry) {
+            return Err(FatError::NotADirectory.into());
+        }
+        
+        // The FAT12/16 root is a run of sectors; other directories are cluster chains
+        let fixed_root = path == "/" && self.fat_type != FatType::Fat32;
+        let block_size = if fixed_root {
+            self.bytes_per_sector as usize
+        } else {
+            self.sectors_per_cluster as usize * self.bytes_per_sector as usize
+        };
+        let slots_per_block = block_size / size_of::<FatDirEntry>();
+        let mut block = start / slots_per_block;
+        let mut first_slot = start % slots_per_block;
+        
+        let mut cluster = Self::get_cluster(&entry);
+        if !fixed_root {
+            for _ in 0..block {
+                cluster = self.read_fat_entry(cluster)?;
+                if !self.is_next_cluster(cluster) {
+                    return Ok(());
+                }
+            }
+        }
+        
+        let mut buffer = vec![0u8; block_size];
+        loop {
+            if fixed_root {
+                if block >= self.root_directory_sectors as usize {
+                    return Ok(());
+                }
+                let sector = self.reserved_sectors as u32 + (self.fat_count as u32 * self.sectors_per_fat) + block as u32;
+                let device = self.device.lock();
+                device.read_block(sector as u64, &mut buffer).map_err(|_| FatError::ReadError)?;
+            } else {
+                self.read_cluster(cluster, &mut buffer)?;
+            }
+            
+            for i in first_slot..slots_per_block {
+                let offset = i * size_of::<FatDirEntry>();
+                let entry_ptr = &buffer[offset] as *const u8 as *const FatDirEntry;
+                let entry = unsafe { *entry_ptr };
+                
+                if entry.name[0] == 0 {
+                    return Ok(()); // End of directory
+                }
+                if entry.name[0] != 0xE5 && !visit(block * slots_per_block + i, &entry) {
+                    return Ok(());
+                }
+            }
+            
+            block += 1;
+            first_slot = 0;
+            if !fixed_root {
+                cluster = self.read_fat_entry(cluster)?;
+                if !self.is_next_cluster(cluster) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    
     // Read a directory (non-root for FAT12/16, any for FAT32)
     fn read_directory(&self, cluster: u32) -> Result<Vec<FatDirEntry>, KernelError> {
         let mut entries = Vec::new();
         let mut current_cluster = cluster;
         
-        while current_cluster < FAT_EOC {
+        while self.is_next_cluster(current_cluster) {
             let bytes_per_cluster = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
             let mut buffer = vec![0u8; bytes_per_cluster];
             
@@ -423,7 +682,7 @@ impl FatFileSystem {
             
             // Go to the next cluster in the chain
             let next_cluster = self.read_fat_entry(current_cluster)?;
-            if next_cluster >= FAT_EOC {
+            if !self.is_next_cluster(next_cluster) {
                 break;
             }
             current_cluster = next_cluster;
@@ -468,6 +727,9 @@ impl FatFileSystem {
         
         // Find the entry
         for entry in dir_entries {
+            if entry.attr & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
             let entry_name = self.fat_name_to_string(&entry.name, &entry.ext);
             if entry_name.to_uppercase() == name_upper {
                 // Clone the entry instead of dereferencing it
@@ -504,6 +766,11 @@ impl FatFileSystem {
         entry.attr & ATTR_DIRECTORY != 0
     }
     
+    // Whether an entry is a directory's "." or ".." link
+    fn is_dot_entry(entry: &FatDirEntry) -> bool {
+        entry.name == *b".       " || entry.name == *b"..      "
+    }
+    
     // Convert a path to a FatDirEntry
     fn path_to_entry(&self, path: &str) -> Result<FatDirEntry, KernelError> {
         // Normalize the path
@@ -570,6 +837,580 @@ impl FatFileSystem {
         // This shouldn't happen
         Err(FatError::NotFound.into())
     }
+    
+    // Bytes in one cluster
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * self.bytes_per_sector as usize
+    }
+    
+    // End-of-chain marker for this FAT width
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+    
+    // Clusters of the chain starting at `start`, stopping early on a loop
+    fn chain(&self, start: u32) -> Result<Vec<u32>, KernelError> {
+        let mut clusters = Vec::new();
+        let mut cluster = start;
+        while self.is_next_cluster(cluster) && clusters.len() < self.total_clusters as usize {
+            clusters.push(cluster);
+            cluster = self.read_fat_entry(cluster)?;
+        }
+        Ok(clusters)
+    }
+    
+    // Write a cluster from a buffer of exactly one cluster
+    fn write_cluster(&self, cluster: u32, data: &[u8]) -> Result<(), KernelError> {
+        let first_sector = self.cluster_to_sector(cluster);
+        let mut device = self.device.lock();
+        for (i, sector) in data.chunks(self.bytes_per_sector as usize).enumerate() {
+            device.write_block((first_sector + i as u32) as u64, sector)
+                .map_err(|_| FatError::WriteError)?;
+        }
+        Ok(())
+    }
+    
+    // Set a FAT entry in every copy of the FAT
+    fn write_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), KernelError> {
+        let fat_offset = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+            FatType::Fat12 => return Err(FatError::UnsupportedFat.into()),
+        };
+        let sector_size = self.bytes_per_sector as u32;
+        let at = (fat_offset % sector_size) as usize;
+        let mut buffer = vec![0u8; sector_size as usize];
+        let mut device = self.device.lock();
+        for fat in 0..self.fat_count as u32 {
+            let sector = (self.first_fat_sector + fat * self.sectors_per_fat + fat_offset / sector_size) as u64;
+            device.read_block(sector, &mut buffer).map_err(|_| FatError::ReadError)?;
+            if self.fat_type == FatType::Fat16 {
+                buffer[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes());
+            } else {
+                // The top four bits are reserved and kept as they are
+                let old = u32::from_le_bytes([buffer[at], buffer[at + 1], buffer[at + 2], buffer[at + 3]]);
+                let value = (old & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                buffer[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            device.write_block(sector, &buffer).map_err(|_| FatError::WriteError)?;
+        }
+        Ok(())
+    }
+    
+    // Take a free cluster, zero it, and link it after `previous`
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, KernelError> {
+        let count = self.total_clusters;
+        let mut found = None;
+        for i in 0..count {
+            let cluster = 2 + (self.next_free - 2 + i) % count;
+            if self.read_fat_entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FatError::VolumeFull)?;
+        
+        self.write_cluster(cluster, &vec![0u8; self.cluster_size()])?;
+        self.write_fat_entry(cluster, self.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.write_fat_entry(previous, cluster)?;
+        }
+        self.free_clusters = self.free_clusters.saturating_sub(1);
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+    
+    // Free every cluster of the chain starting at `start`
+    fn free_chain(&mut self, start: u32) -> Result<(), KernelError> {
+        let mut cluster = start;
+        // A freed cluster reads as 0, so even a looping chain ends
+        while self.is_next_cluster(cluster) {
+            let next = self.read_fat_entry(cluster)?;
+            self.write_fat_entry(cluster, 0)?;
+            self.free_clusters += 1;
+            cluster = next;
+        }
+        Ok(())
+    }
+    
+    // Sectors holding a directory's entries, in order
+    fn directory_sectors(&self, path: &str, entry: &FatDirEntry) -> Result<Vec<u32>, KernelError> {
+        if path == "/" && self.fat_type != FatType::Fat32 {
+            let start = self.reserved_sectors as u32 + self.fat_count as u32 * self.sectors_per_fat;
+            return Ok((start..start + self.root_directory_sectors).collect());
+        }
+        let start = if path == "/" { self.root_cluster } else { Self::get_cluster(entry) };
+        let mut sectors = Vec::new();
+        for cluster in self.chain(start)? {
+            let first = self.cluster_to_sector(cluster);
+            sectors.extend(first..first + self.sectors_per_cluster as u32);
+        }
+        Ok(sectors)
+    }
+    
+    // Look up `name` in the directory at `dir_path`, with where its entry is
+    fn find_slot(&self, dir_path: &str, name: &str) -> Result<Option<(FatDirEntry, EntrySlot)>, KernelError> {
+        let dir = self.path_to_entry(dir_path)?;
+        if !Self::is_directory(&dir) {
+            return Err(FatError::NotADirectory.into());
+        }
+        let mut buffer = vec![0u8; self.bytes_per_sector as usize];
+        for sector in self.directory_sectors(dir_path, &dir)? {
+            self.device.lock().read_block(sector as u64, &mut buffer).map_err(|_| FatError::ReadError)?;
+            for offset in (0..buffer.len()).step_by(size_of::<FatDirEntry>()) {
+                let entry = unsafe { core::ptr::read_unaligned(buffer[offset..].as_ptr() as *const FatDirEntry) };
+                if entry.name[0] == 0 {
+                    return Ok(None);
+                }
+                if entry.name[0] == 0xE5 || entry.attr & ATTR_VOLUME_ID != 0 {
+                    continue;
+                }
+                if self.fat_name_to_string(&entry.name, &entry.ext).eq_ignore_ascii_case(name) {
+                    return Ok(Some((entry, EntrySlot { sector, offset })));
+                }
+            }
+        }
+        Ok(None)
+    }
+    
+    // The entry for `path` and where it is, for changing it
+    // Fail if the volume can't be changed, before anything is touched
+    fn writable(&self) -> Result<(), KernelError> {
+        if self.read_only {
+            return Err(KernelError::ReadOnlyFilesystem);
+        }
+        Ok(())
+    }
+    
+    fn entry_for_update(&self, path: &str) -> Result<(FatDirEntry, EntrySlot), KernelError> {
+        let (parent, name) = split_path(path);
+        self.find_slot(parent, name)?.ok_or_else(|| FatError::NotFound.into())
+    }
+    
+    // An unused slot in a directory, growing it by a cluster when it is full.
+    // The FAT12/16 root can't grow.
+    fn free_slot(&mut self, dir_path: &str) -> Result<EntrySlot, KernelError> {
+        let dir = self.path_to_entry(dir_path)?;
+        let sectors = self.directory_sectors(dir_path, &dir)?;
+        let mut buffer = vec![0u8; self.bytes_per_sector as usize];
+        for &sector in &sectors {
+            self.device.lock().read_block(sector as u64, &mut buffer).map_err(|_| FatError::ReadError)?;
+            for offset in (0..buffer.len()).step_by(size_of::<FatDirEntry>()) {
+                if buffer[offset] == 0 || buffer[offset] == 0xE5 {
+                    return Ok(EntrySlot { sector, offset });
+                }
+            }
+        }
+        
+        if dir_path == "/" && self.fat_type != FatType::Fat32 {
+            return Err(FatError::DirectoryFull.into());
+        }
+        let start = if dir_path == "/" { self.root_cluster } else { Self::get_cluster(&dir) };
+        let last = self.chain(start)?.last().copied();
+        let cluster = self.allocate_cluster(last)?;
+        Ok(EntrySlot { sector: self.cluster_to_sector(cluster), offset: 0 })
+    }
+    
+    // Store a directory entry in its slot
+    fn write_slot(&self, slot: EntrySlot, entry: &FatDirEntry) -> Result<(), KernelError> {
+        let mut buffer = vec![0u8; self.bytes_per_sector as usize];
+        let mut device = self.device.lock();
+        device.read_block(slot.sector as u64, &mut buffer).map_err(|_| FatError::ReadError)?;
+        unsafe { core::ptr::write_unaligned(buffer[slot.offset..].as_mut_ptr() as *mut FatDirEntry, *entry) };
+        device.write_block(slot.sector as u64, &buffer).map_err(|_| FatError::WriteError)?;
+        Ok(())
+    }
+    
+    // Add an entry named after the last part of `path`
+    fn add_entry(&mut self, path: &str, attr: u8, cluster: u32) -> Result<(), KernelError> {
+        let (parent, name) = split_path(path);
+        let (base, ext) = short_name(name)?;
+        if self.find_slot(parent, name)?.is_some() {
+            return Err(FatError::AlreadyExists.into());
+        }
+        let slot = self.free_slot(parent)?;
+        self.write_slot(slot, &FatDirEntry::new(base, ext, attr, cluster))
+    }
+    
+    // Overwrite bytes at `offset` in a chain long enough to hold them
+    fn write_range(&self, start: u32, offset: u64, data: &[u8]) -> Result<(), KernelError> {
+        let cluster_size = self.cluster_size();
+        let mut buffer = vec![0u8; cluster_size];
+        let mut done = 0;
+        let first = (offset / cluster_size as u64) as usize;
+        for cluster in self.chain(start)?.into_iter().skip(first) {
+            if done == data.len() {
+                break;
+            }
+            let at = ((offset + done as u64) % cluster_size as u64) as usize;
+            let count = (cluster_size - at).min(data.len() - done);
+            if count < cluster_size {
+                self.read_cluster(cluster, &mut buffer)?;
+            }
+            buffer[at..at + count].copy_from_slice(&data[done..done + count]);
+            self.write_cluster(cluster, &buffer)?;
+            done += count;
+        }
+        if done < data.len() {
+            return Err(FatError::WriteError.into());
+        }
+        Ok(())
+    }
+    
+    // Read bytes at `offset` from a chain long enough to hold them
+    fn read_range(&self, start: u32, offset: u64, data: &mut [u8]) -> Result<(), KernelError> {
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        let first = (offset / cluster_size as u64) as usize;
+        for cluster in self.chain(start)?.into_iter().skip(first) {
+            if done == data.len() {
+                break;
+            }
+            let at = ((offset + done as u64) % cluster_size as u64) as usize;
+            let count = (cluster_size - at).min(data.len() - done);
+            self.read_in_cluster(cluster, at, &mut data[done..done + count])?;
+            done += count;
+        }
+        if done < data.len() {
+            return Err(FatError::ReadError.into());
+        }
+        Ok(())
+    }
+    
+    // Grow or shrink a file's chain to fit `length` bytes and set its size.
+    // The caller writes the entry back. If clusters run out the file is
+    // left as it was.
+    fn resize(&mut self, entry: &mut FatDirEntry, length: u64) -> Result<(), KernelError> {
+        let size = u32::try_from(length).map_err(|_| FatError::InvalidParameter)?;
+        let cluster_size = self.cluster_size() as u64;
+        let start = Self::get_cluster(entry);
+        let chain = if start == 0 { Vec::new() } else { self.chain(start)? };
+        let needed = length.div_ceil(cluster_size) as usize;
+        
+        if needed < chain.len() {
+            if needed == 0 {
+                entry.set_cluster(0);
+            } else {
+                self.write_fat_entry(chain[needed - 1], self.end_of_chain())?;
+            }
+            self.free_chain(chain[needed])?;
+        } else if needed > chain.len() {
+            let mut added: Vec<u32> = Vec::new();
+            while chain.len() + added.len() < needed {
+                let previous = added.last().or(chain.last()).copied();
+                match self.allocate_cluster(previous) {
+                    Ok(cluster) => added.push(cluster),
+                    Err(e) => {
+                        if let Some(&first) = added.first() {
+                            if let Some(&last) = chain.last() {
+                                self.write_fat_entry(last, self.end_of_chain())?;
+                            }
+                            self.free_chain(first)?;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            if start == 0 {
+                entry.set_cluster(added[0]);
+            }
+        }
+        
+        // New clusters come zeroed, but the old last cluster may still hold
+        // data from before an earlier shrink
+        let old_size = entry.size as u64;
+        let stale_end = (chain.len() as u64 * cluster_size).min(length);
+        if stale_end > old_size {
+            self.write_range(Self::get_cluster(entry), old_size, &vec![0u8; (stale_end - old_size) as usize])?;
+        }
+        entry.size = size;
+        Ok(())
+    }
+}
+
+// Where a directory entry is stored
+#[derive(Debug, Clone, Copy)]
+struct EntrySlot {
+    sector: u32,
+    offset: usize,
+}
+
+impl FatDirEntry {
+    fn new(name: [u8; 8], ext: [u8; 3], attr: u8, cluster: u32) -> Self {
+        let mut entry = Self {
+            name,
+            ext,
+            attr,
+            reserved: 0,
+            create_time_tenth: 0,
+            create_time: 0,
+            create_date: 0,
+            access_date: 0,
+            cluster_high: 0,
+            modify_time: 0,
+            modify_date: 0,
+            cluster_low: 0,
+            size: 0,
+        };
+        entry.set_cluster(cluster);
+        entry
+    }
+    
+    fn set_cluster(&mut self, cluster: u32) {
+        self.cluster_high = (cluster >> 16) as u16;
+        self.cluster_low = cluster as u16;
+    }
+}
+
+// Split a path into its parent directory and last component
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => ("/", path),
+    }
+}
+
+// The 8.3 form of a name, refusing names that don't fit it
+fn short_name(name: &str) -> Result<([u8; 8], [u8; 3]), FatError> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return Err(FatError::InvalidParameter);
+    }
+    let mut short = ([b' '; 8], [b' '; 3]);
+    let slots = short.0.iter_mut().zip(base.bytes()).chain(short.1.iter_mut().zip(ext.bytes()));
+    for (slot, c) in slots {
+        let c = c.to_ascii_uppercase();
+        if !is_short_name_char(c) {
+            return Err(FatError::InvalidParameter);
+        }
+        *slot = c;
+    }
+    Ok(short)
+}
+
+/// How `format` lays out a volume
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FormatOptions<'a> {
+    /// Fat16 or Fat32; None picks FAT16 when the device is small enough
+    pub fat_type: Option<FatType>,
+    /// Up to 11 characters; stored in upper case
+    pub label: Option<&'a str>,
+}
+
+/// Sizes worked out for a new volume
+struct Layout {
+    fat_type: FatType,
+    total_sectors: u32,
+    reserved_sectors: u32,
+    sectors_per_cluster: u32,
+    sectors_per_fat: u32,
+    root_sectors: u32,
+    clusters: u32,
+}
+
+impl Layout {
+    fn new(fat_type: FatType, total_sectors: u32) -> Result<Self, FatError> {
+        let (reserved_sectors, root_sectors, entry_size) = match fat_type {
+            FatType::Fat16 => (FAT16_RESERVED_SECTORS, FAT16_ROOT_ENTRIES * 32 / 512, 2),
+            FatType::Fat32 => (FAT32_RESERVED_SECTORS, 0, 4),
+            FatType::Fat12 => return Err(FatError::UnsupportedFat),
+        };
+        let overhead = reserved_sectors + root_sectors;
+        if total_sectors <= overhead {
+            return Err(FatError::InvalidParameter);
+        }
+
+        // The smallest clusters that keep FAT16's count in range; FAT32
+        // follows the usual size table
+        let mut sectors_per_cluster = match fat_type {
+            FatType::Fat16 => 1,
+            _ => match total_sectors {
+                0..=532_480 => 1,
+                532_481..=16_777_216 => 8,
+                16_777_217..=33_554_432 => 16,
+                33_554_433..=67_108_864 => 32,
+                _ => 64,
+            },
+        };
+        while fat_type == FatType::Fat16 && (total_sectors - overhead) / sectors_per_cluster >= FAT32_MIN_CLUSTERS {
+            if sectors_per_cluster == 64 {
+                return Err(FatError::InvalidParameter);
+            }
+            sectors_per_cluster *= 2;
+        }
+
+        // Sized for every cluster the data area could hold before the FATs
+        // are taken out of it, so it is never too small
+        let entries = (total_sectors - overhead) / sectors_per_cluster + 2;
+        let sectors_per_fat = (entries * entry_size + 511) / 512;
+        let first_data_sector = overhead + FORMAT_FAT_COUNT * sectors_per_fat;
+        let clusters = total_sectors.saturating_sub(first_data_sector) / sectors_per_cluster;
+        let fits = match fat_type {
+            FatType::Fat16 => (FAT16_MIN_CLUSTERS..FAT32_MIN_CLUSTERS).contains(&clusters),
+            _ => (FAT32_MIN_CLUSTERS..=FAT32_MAX_CLUSTERS).contains(&clusters),
+        };
+        if !fits {
+            serial_println!("DEBUG: FAT: {} sectors give {} clusters, out of range for {:?}",
+                total_sectors, clusters, fat_type);
+            return Err(FatError::InvalidParameter);
+        }
+
+        Ok(Self { fat_type, total_sectors, reserved_sectors, sectors_per_cluster, sectors_per_fat, root_sectors, clusters })
+    }
+
+    fn root_start(&self) -> u32 {
+        self.reserved_sectors + FORMAT_FAT_COUNT * self.sectors_per_fat
+    }
+
+    fn boot_sector(&self, label: &[u8; 11]) -> Vec<u8> {
+        let mut boot = vec![0u8; 512];
+        let jump = if self.fat_type == FatType::Fat16 { 0x3C } else { 0x58 };
+        boot[0..3].copy_from_slice(&[0xEB, jump, 0x90]);
+        boot[3..11].copy_from_slice(b"UNIVERSK");
+        boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot[13] = self.sectors_per_cluster as u8;
+        boot[14..16].copy_from_slice(&(self.reserved_sectors as u16).to_le_bytes());
+        boot[16] = FORMAT_FAT_COUNT as u8;
+        boot[21] = 0xF8; // Fixed disk
+        boot[24..26].copy_from_slice(&32u16.to_le_bytes()); // Sectors per track
+        boot[26..28].copy_from_slice(&2u16.to_le_bytes()); // Heads
+        if self.fat_type == FatType::Fat16 && self.total_sectors < 0x10000 {
+            boot[19..21].copy_from_slice(&(self.total_sectors as u16).to_le_bytes());
+        } else {
+            boot[32..36].copy_from_slice(&self.total_sectors.to_le_bytes());
+        }
+
+        // The extended fields sit after the FAT32-only ones on FAT32
+        let extended = if self.fat_type == FatType::Fat16 {
+            boot[17..19].copy_from_slice(&(FAT16_ROOT_ENTRIES as u16).to_le_bytes());
+            boot[22..24].copy_from_slice(&(self.sectors_per_fat as u16).to_le_bytes());
+            FAT16_SIGNATURE_OFFSET - 2
+        } else {
+            boot[36..40].copy_from_slice(&self.sectors_per_fat.to_le_bytes());
+            boot[44..48].copy_from_slice(&2u32.to_le_bytes()); // Root cluster
+            boot[48..50].copy_from_slice(&(FAT32_FSINFO_SECTOR as u16).to_le_bytes());
+            boot[50..52].copy_from_slice(&(FAT32_BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+            0x40
+        };
+        boot[extended] = 0x80; // Drive number
+        boot[extended + 2] = 0x29;
+        boot[extended + 3..extended + 7].copy_from_slice(&(crate::drivers::pit::ticks() as u32).to_le_bytes());
+        boot[extended + 7..extended + 18].copy_from_slice(label);
+        let fs_type: &[u8; 8] = if self.fat_type == FatType::Fat16 { b"FAT16   " } else { b"FAT32   " };
+        boot[extended + 18..extended + 26].copy_from_slice(fs_type);
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+        boot
+    }
+}
+
+/// A volume label padded to 11 bytes, in upper case
+fn label_bytes(label: &str) -> Result<[u8; 11], FatError> {
+    let mut bytes = [b' '; 11];
+    if label.len() > 11 {
+        return Err(FatError::InvalidParameter);
+    }
+    for (slot, c) in bytes.iter_mut().zip(label.bytes()) {
+        let c = c.to_ascii_uppercase();
+        if c != b' ' && !is_short_name_char(c) {
+            return Err(FatError::InvalidParameter);
+        }
+        *slot = c;
+    }
+    Ok(bytes)
+}
+
+/// Characters allowed in 8.3 names, once upper-cased
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// Write an empty FAT16 or FAT32 volume over the whole device: a boot
+/// sector, both FATs with their reserved entries set and an empty root
+/// directory (FAT32 also gets an FSInfo sector and a backup boot sector).
+/// Sectors per cluster are worked out from the size. Everything already
+/// on the device is lost.
+pub fn format(device: &mut dyn BlockDevice, options: &FormatOptions) -> Result<(), KernelError> {
+    if device.read_only() {
+        return Err(KernelError::ReadOnlyFilesystem);
+    }
+    if device.block_size() != 512 {
+        return Err(FatError::UnsupportedFat.into());
+    }
+    let total_sectors = u32::try_from(device.block_count()).map_err(|_| FatError::InvalidParameter)?;
+    let label = label_bytes(options.label.unwrap_or("NO NAME"))?;
+    let layout = match options.fat_type {
+        Some(fat_type) => Layout::new(fat_type, total_sectors)?,
+        None => Layout::new(FatType::Fat16, total_sectors)
+            .or_else(|_| Layout::new(FatType::Fat32, total_sectors))?,
+    };
+    let mut write = |sector: u32, data: &[u8]| -> Result<(), KernelError> {
+        device.write_block(sector as u64, data).map_err(|_| FatError::WriteError.into())
+    };
+
+    let boot = layout.boot_sector(&label);
+    write(0, &boot)?;
+    let zeros = vec![0u8; 512];
+    for sector in 1..layout.reserved_sectors {
+        write(sector, &zeros)?;
+    }
+
+    // Each FAT starts with the media byte and an end-of-chain marker; on
+    // FAT32 the root directory's single cluster follows them
+    let mut first_fat_sector = zeros.clone();
+    match layout.fat_type {
+        FatType::Fat16 => first_fat_sector[0..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]),
+        _ => {
+            first_fat_sector[0..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+            first_fat_sector[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+            first_fat_sector[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        }
+    }
+    for fat in 0..FORMAT_FAT_COUNT {
+        let start = layout.reserved_sectors + fat * layout.sectors_per_fat;
+        write(start, &first_fat_sector)?;
+        for sector in start + 1..start + layout.sectors_per_fat {
+            write(sector, &zeros)?;
+        }
+    }
+
+    // The FAT16 root is a fixed run of sectors; the FAT32 one is cluster 2,
+    // the first in the data area
+    let root_sectors = if layout.fat_type == FatType::Fat16 { layout.root_sectors } else { layout.sectors_per_cluster };
+    let mut root = zeros.clone();
+    if options.label.is_some() {
+        root[0..11].copy_from_slice(&label);
+        root[11] = ATTR_VOLUME_ID;
+    }
+    write(layout.root_start(), &root)?;
+    for sector in layout.root_start() + 1..layout.root_start() + root_sectors {
+        write(sector, &zeros)?;
+    }
+
+    if layout.fat_type == FatType::Fat32 {
+        // Free count and next free cluster are left unknown
+        let mut fs_info = zeros.clone();
+        fs_info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fs_info[488..496].fill(0xFF);
+        fs_info[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+        write(FAT32_FSINFO_SECTOR, &fs_info)?;
+        write(FAT32_BACKUP_BOOT_SECTOR, &boot)?;
+        write(FAT32_BACKUP_BOOT_SECTOR + 1, &fs_info)?;
+    }
+
+    serial_println!("DEBUG: FAT: formatted {} sectors as {:?}, {} clusters of {} sectors",
+        layout.total_sectors, layout.fat_type, layout.clusters, layout.sectors_per_cluster);
+    Ok(())
 }
 
 impl FileSystem for FatFileSystem {
@@ -579,28 +1420,68 @@ impl FileSystem for FatFileSystem {
     }
     
     fn unmount(&mut self) -> Result<(), KernelError> {
-        // Nothing to do
-        Ok(())
+        self.device.lock().flush().map_err(|_| KernelError::WriteError)
     }
     
-    fn create_file(&mut self, _path: &str) -> Result<(), KernelError> {
-        // Not implemented yet
-        Err(KernelError::NotImplemented)
+    fn create_file(&mut self, path: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        // Empty files have no clusters
+        self.add_entry(path, ATTR_ARCHIVE, 0)
     }
     
-    fn create_directory(&mut self, _path: &str) -> Result<(), KernelError> {
-        // Not implemented yet
-        Err(KernelError::NotImplemented)
+    fn create_directory(&mut self, path: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        let (parent, _) = split_path(path);
+        // ".." holds 0 when the parent is the root, even on FAT32
+        let parent_cluster = if parent == "/" { 0 } else { Self::get_cluster(&self.path_to_entry(parent)?) };
+        let cluster = self.allocate_cluster(None)?;
+        
+        let mut buffer = vec![0u8; self.cluster_size()];
+        let dots = [(b".       ", cluster), (b"..      ", parent_cluster)];
+        for (i, (name, target)) in dots.into_iter().enumerate() {
+            let entry = FatDirEntry::new(*name, *b"   ", ATTR_DIRECTORY, target);
+            unsafe { core::ptr::write_unaligned(buffer[i * size_of::<FatDirEntry>()..].as_mut_ptr() as *mut FatDirEntry, entry) };
+        }
+        let result = self.write_cluster(cluster, &buffer)
+            .and_then(|_| self.add_entry(path, ATTR_DIRECTORY, cluster));
+        if result.is_err() {
+            self.free_chain(cluster)?;
+        }
+        result
     }
     
-    fn remove(&mut self, _path: &str) -> Result<(), KernelError> {
-        // Not implemented yet
-        Err(KernelError::NotImplemented)
+    fn remove(&mut self, path: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        let (mut entry, slot) = self.entry_for_update(path)?;
+        if Self::is_directory(&entry) {
+            let mut empty = true;
+            self.scan_directory(path, 0, &mut |_, child| {
+                empty = Self::is_dot_entry(child) || child.attr & ATTR_VOLUME_ID != 0;
+                empty
+            })?;
+            if !empty {
+                return Err(KernelError::DirectoryNotEmpty);
+            }
+        }
+        
+        entry.name[0] = 0xE5;
+        self.write_slot(slot, &entry)?;
+        let cluster = Self::get_cluster(&entry);
+        if cluster != 0 {
+            self.free_chain(cluster)?;
+        }
+        Ok(())
     }
     
-    fn open(&self, _path: &str, _write: bool) -> Result<FileHandle, KernelError> {
-        // Not implemented yet
-        Err(KernelError::NotImplemented)
+    fn open(&mut self, path: &str, write: bool) -> Result<Option<usize>, KernelError> {
+        // Handles work by path
+        if Self::is_directory(&self.path_to_entry(path)?) {
+            return Err(KernelError::NotAFile);
+        }
+        if write {
+            self.writable()?;
+        }
+        Ok(None)
     }
     
     fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
@@ -608,77 +1489,68 @@ impl FileSystem for FatFileSystem {
         let entry = self.path_to_entry(path)?;
         
         // Create metadata from the entry
-        let node_type = if Self::is_directory(&entry) {
-            NodeType::Directory
+        let mut metadata = if Self::is_directory(&entry) {
+            Metadata::new_directory()
         } else {
-            NodeType::File
-        };
-        
-        let mut metadata = Metadata {
-            node_type,
-            size: entry.size as u64,
-            permissions: 0,
-            created_at: 0,
-            modified_at: 0,
-            accessed_at: 0,
+            Metadata::new_file()
         };
+        metadata.size = entry.size as u64;
         
-        // Set permissions based on attributes
+        // FAT has no owners, so everyone gets what the owner would. The
+        // read-only attribute is the only permission it stores.
+        metadata.permissions = permissions::ALL;
         if entry.attr & ATTR_READ_ONLY != 0 {
-            // No write permission
-            metadata.permissions = 0b0001_0001;
-        } else {
-            // Read and write
-            metadata.permissions = 0b0011_0011;
+            metadata.permissions &= !permissions::ALL_WRITE;
         }
+        metadata.attributes = vfs_attributes(entry.attr);
         
         Ok(metadata)
     }
     
-    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, KernelError> {
-        // Find the entry for the path
-        let entry = self.path_to_entry(path)?;
-        
-        // Make sure it's a directory
-        if !Self::is_directory(&entry) {
-            return Err(FatError::NotADirectory.into());
-        }
-        
-        // Read the directory entries
-        let dir_entries = if path == "/" && self.fat_type != FatType::Fat32 {
-            self.read_root_directory()?
-        } else {
-            let cluster = Self::get_cluster(&entry);
-            self.read_directory(cluster)?
+    fn set_metadata(&mut self, path: &str, update: MetadataUpdate) -> Result<(), KernelError> {
+        // Only the owner's write bit maps onto anything, the read-only attribute
+        let MetadataUpdate { permissions: Some(mode), uid: None, gid: None, modified_at: None, accessed_at: None } = update else {
+            return Err(KernelError::UnsupportedFeature);
         };
-        
-        // Convert to VFS directory entries
-        let mut result = Vec::new();
-        for entry in dir_entries {
-            // Skip special entries like . and ..
-            if entry.name[0] == b'.' {
-                continue;
+        self.writable()?;
+        let (mut entry, slot) = self.entry_for_update(path)?;
+        if mode & permissions::WRITE == 0 {
+            entry.attr |= ATTR_READ_ONLY;
+        } else {
+            entry.attr &= !ATTR_READ_ONLY;
+        }
+        self.write_slot(slot, &entry)
+    }
+    
+    fn read_dir_from(&self, path: &str, cursor: usize, out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError> {
+        // The cursor is a slot number in the directory. Slots don't move, so
+        // it stays valid whatever happens to the entries around it.
+        let mut filled = 0;
+        let mut next = None;
+        self.scan_directory(path, cursor, &mut |slot, entry| {
+            // Skip . and .., volume labels and long name parts (whose
+            // attribute includes the volume label bit)
+            if Self::is_dot_entry(entry) || entry.attr & ATTR_VOLUME_ID != 0 {
+                return true;
             }
-            
-            // Skip volume labels
-            if entry.attr & ATTR_VOLUME_ID != 0 {
-                continue;
+            if filled == out.len() {
+                next = Some(slot);
+                return false;
             }
             
-            // Convert to a string
             let name = self.fat_name_to_string(&entry.name, &entry.ext);
-            
-            // Create a directory entry
-            let node_type = if Self::is_directory(&entry) {
+            let node_type = if Self::is_directory(entry) {
                 NodeType::Directory
             } else {
                 NodeType::File
             };
-            
-            result.push(DirEntry::new(&name, node_type, 0));
-        }
+            out[filled] = Some(DirEntry::new(&name, node_type, 0));
+            filled += 1;
+            true
+        })?;
         
-        Ok(result)
+        Ok((filled, next))
     }
     
     fn rename(&mut self, _from: &str, _to: &str) -> Result<(), KernelError> {
@@ -686,6 +1558,89 @@ impl FileSystem for FatFileSystem {
         Err(KernelError::NotImplemented)
     }
     
+    fn read_at(&self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        let entry = self.path_to_entry(path)?;
+        if Self::is_directory(&entry) {
+            return Err(KernelError::IsADirectory);
+        }
+        let size = entry.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((size - offset) as usize);
+        self.read_range(Self::get_cluster(&entry), offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+    
+    fn write_at(&mut self, path: &str, offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
+        self.writable()?;
+        let (mut entry, slot) = self.entry_for_update(path)?;
+        if Self::is_directory(&entry) {
+            return Err(KernelError::IsADirectory);
+        }
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        
+        // Growing zero-fills any gap between the old end and `offset`
+        let end = offset.checked_add(buffer.len() as u64).ok_or(FatError::InvalidParameter)?;
+        if end > entry.size as u64 {
+            self.resize(&mut entry, end)?;
+        }
+        self.write_range(Self::get_cluster(&entry), offset, buffer)?;
+        self.write_slot(slot, &entry)?;
+        Ok(buffer.len())
+    }
+    
+    fn truncate(&mut self, path: &str, length: u64) -> Result<(), KernelError> {
+        self.writable()?;
+        let (mut entry, slot) = self.entry_for_update(path)?;
+        if Self::is_directory(&entry) {
+            return Err(KernelError::IsADirectory);
+        }
+        self.resize(&mut entry, length)?;
+        self.write_slot(slot, &entry)
+    }
+    
+    fn check(&mut self, repair: bool) -> Result<CheckReport, KernelError> {
+        let mut report = CheckReport::default();
+        let mut seen = vec![0u8; (self.total_clusters as usize + 2).div_ceil(8)];
+        
+        // The FAT32 root is a chain too; the FAT12/16 root is a fixed area
+        let root_intact = if self.fat_type == FatType::Fat32 {
+            self.check_chain("/", self.root_cluster, &mut seen, &mut report)?.1
+        } else {
+            true
+        };
+        if root_intact {
+            self.check_directory("/", 0, &mut seen, &mut report)?;
+        }
+        
+        if repair && !report.is_clean() {
+            serial_println!("DEBUG: FAT: {} problems left unrepaired, FAT repairs aren't implemented", report.problems.len());
+        }
+        Ok(report)
+    }
+    
+    fn volume_label(&self) -> Option<String> {
+        // The label is a root directory entry with the volume bit alone of
+        // the four a long name part sets
+        let mut label = None;
+        let _ = self.scan_directory("/", 0, &mut |_, entry| {
+            if entry.attr & ATTR_LONG_NAME != ATTR_VOLUME_ID {
+                return true;
+            }
+            let bytes: Vec<u8> = entry.name.iter().chain(entry.ext.iter()).copied().collect();
+            label = Some(String::from(String::from_utf8_lossy(&bytes).trim_end()));
+            false
+        });
+        label.filter(|label| !label.is_empty())
+    }
+    
+    fn device_id(&self) -> Option<u64> {
+        self.device.lock().device_id()
+    }
+    
     fn name(&self) -> &str {
         match self.fat_type {
             FatType::Fat12 => "FAT12",
@@ -699,8 +1654,97 @@ impl FileSystem for FatFileSystem {
     }
     
     fn available_space(&self) -> u64 {
-        // This would require scanning the FAT to count free clusters
-        // Just return a percentage of total space for now
-        self.total_space() / 2
+        (self.free_clusters as u64) * (self.sectors_per_cluster as u64) * (self.bytes_per_sector as u64)
+    }
+}
+
+/// Format FAT16 and FAT32 RamDisks, fill them through the FileSystem
+/// interface, then mount them again and check everything came back
+pub fn self_test() -> Result<(), KernelError> {
+    use crate::fs::ramdisk::RamDisk;
+    
+    serial_println!("FAT: Running self-test");
+    
+    // A note spanning several clusters, written out of order over old data
+    let mut note: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    note[1000..1007].copy_from_slice(b"patched");
+    
+    // 4 MiB is FAT16-sized; 64 MiB is the smallest FAT32 with 512-byte
+    // clusters, and stays cheap because the RamDisk is sparse
+    for (fat_type, sectors) in [(FatType::Fat16, 4096 * 2), (FatType::Fat32, 64 * 1024 * 2)] {
+        let mut disk = RamDisk::with_capacity(sectors, 512)?;
+        format(&mut disk, &FormatOptions { fat_type: Some(fat_type), label: Some("SelfTest") })?;
+        let device: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(disk));
+        
+        {
+            let mut fs = FatFileSystem::new(device.clone())?;
+            if fs.fat_type != fat_type {
+                return Err(KernelError::ValidationError("Formatted volume mounted as the wrong FAT type"));
+            }
+            let free = fs.available_space();
+            fs.create_directory("/DOCS")?;
+            fs.create_file("/DOCS/NOTE.TXT")?;
+            fs.write_at("/DOCS/NOTE.TXT", 0, &vec![0xEEu8; 2000])?;
+            fs.write_at("/DOCS/NOTE.TXT", 1007, &note[1007..])?;
+            fs.write_at("/DOCS/NOTE.TXT", 0, &note[..1000])?;
+            fs.write_at("/DOCS/NOTE.TXT", 1000, b"patched")?;
+            fs.write_at("/DOCS/NOTE.TXT", 1000, b"patched")?;
+            fs.create_file("/EMPTY")?;
+            if !matches!(fs.create_file("/docs"), Err(KernelError::AlreadyExists))
+                || fs.create_file("/NAME-TOO-LONG.TEXT").is_ok() {
+                return Err(KernelError::ValidationError("FAT accepted a clashing or overlong name"));
+            }
+            if fs.available_space() >= free {
+                return Err(KernelError::ValidationError("FAT free space did not shrink"));
+            }
+        }
+        
+        let mut fs = FatFileSystem::new(device.clone())?;
+        let names: Vec<String> = fs.read_dir("/")?.into_iter().map(|entry| entry.name).collect();
+        if names.len() != 2 || !names.iter().any(|name| name == "DOCS") || !names.iter().any(|name| name == "EMPTY") {
+            serial_println!("FAT: Root after remount: {:?}", names);
+            return Err(KernelError::ValidationError("FAT root listing wrong after remount"));
+        }
+        let mut contents = vec![0u8; note.len() + 10];
+        let read = fs.read_at("/docs/note.txt", 0, &mut contents)?;
+        if read != note.len() || contents[..read] != note[..] || fs.metadata("/EMPTY")?.size != 0 {
+            return Err(KernelError::ValidationError("FAT file contents wrong after remount"));
+        }
+        // Reads starting and ending partway through sectors and clusters
+        for (offset, length) in [(1, 510), (3, 1500), (511, 2), (512, 1024), (1000, note.len() - 1000)] {
+            let read = fs.read_at("/docs/note.txt", offset as u64, &mut contents[..length])?;
+            if read != length || contents[..length] != note[offset..offset + length] {
+                serial_println!("FAT: Read of {} bytes at {} came back wrong", length, offset);
+                return Err(KernelError::ValidationError("FAT read at an offset came back wrong"));
+            }
+        }
+        fs.set_metadata("/EMPTY", MetadataUpdate { permissions: Some(permissions::READ), ..Default::default() })?;
+        let listed: Vec<String> = fs.read_dir("/DOCS")?.into_iter().map(|entry| entry.name).collect();
+        if fs.volume_label().as_deref() != Some("SELFTEST") || listed != ["NOTE.TXT"]
+            || fs.metadata("/EMPTY")?.attributes != attributes::ARCHIVE | attributes::READ_ONLY {
+            return Err(KernelError::ValidationError("FAT label, dot entries or attributes read wrongly"));
+        }
+        fs.set_metadata("/EMPTY", MetadataUpdate { permissions: Some(permissions::ALL), ..Default::default() })?;
+        if !fs.check(false)?.is_clean() {
+            return Err(KernelError::ValidationError("Freshly written FAT volume fails its check"));
+        }
+        
+        // Everything removed gives every cluster back
+        let free = fs.available_space();
+        if !matches!(fs.remove("/DOCS"), Err(KernelError::DirectoryNotEmpty)) {
+            return Err(KernelError::ValidationError("FAT removed a directory that wasn't empty"));
+        }
+        fs.truncate("/DOCS/NOTE.TXT", 10)?;
+        fs.remove("/DOCS/NOTE.TXT")?;
+        fs.remove("/DOCS")?;
+        fs.remove("/EMPTY")?;
+        let freed = fs.available_space() - free;
+        let cluster_size = fs.cluster_size() as u64;
+        if freed != cluster_size * (1 + (note.len() as u64).div_ceil(cluster_size)) || !fs.read_dir("/")?.is_empty() {
+            return Err(KernelError::ValidationError("FAT did not free removed files' clusters"));
+        }
     }
-} 
\ No newline at end of file
+    
+    serial_println!("FAT: Self-test passed");
+    Ok(())
+}
@@ -1,27 +1,51 @@
 use crate::errors::KernelError;
-use crate::fs::vfs::FileHandle;
+use crate::fs::vfs::{file_flags, FileHandle};
+use crate::fs::pipe::PipeIo;
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
-use core::sync::atomic::{AtomicU32, Ordering};
-use spin::Mutex;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::sync::DiagMutex;
+use crate::kdebug;
 use crate::serial_println;
 
 /// Unique file descriptor counter
 static NEXT_FD: AtomicU32 = AtomicU32::new(3); // Start at 3 (after stdin, stdout, stderr)
 
+/// Descriptors a table holds when `fs.max_open_files` isn't set
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// Closes of descriptors that weren't open: double closes, or closing a
+/// number that was never handed out
+static UNKNOWN_CLOSES: AtomicU64 = AtomicU64::new(0);
+
+/// Most descriptors one table may hold, from `fs.max_open_files`
+fn max_open_files() -> usize {
+    crate::config::get("fs.max_open_files")
+        .and_then(|value| value.try_as_integer())
+        .and_then(|value| usize::try_from(value).ok())
+        .unwrap_or(DEFAULT_MAX_OPEN_FILES)
+}
+
 /// A file descriptor is a simple handle to an open file
 pub struct FileDescriptor {
     pub fd: u32,
     pub handle: Box<FileHandle>,
+    /// Task that opened the descriptor; its files are closed when it exits
+    pub owner: Option<u64>,
+    /// Who opened it, to find where a leaked descriptor came from
+    pub tag: &'static str,
+    /// Uptime when it was opened (milliseconds)
+    pub opened_at_ms: u64,
 }
 impl FileDescriptor {
-    fn new(handle: FileHandle) -> Self {
+    fn new(handle: FileHandle, tag: &'static str) -> Self {
         // Avoid printing handle properties directly
-        serial_println!("DEBUG: FileDescriptor::new - Creating new FD");
+        kdebug!("fd", "FileDescriptor::new - Creating new FD");
 
         // Get next available FD number
         let fd = NEXT_FD.fetch_add(1, Ordering::Relaxed);
-        serial_println!("DEBUG: FileDescriptor::new - Got FD: {}", fd);
+        kdebug!("fd", "FileDescriptor::new - Got FD: {}", fd);
 
         // Create a local copy of flags before boxing, to avoid accessing handle after boxing
         let flags = handle.flags;
@@ -30,10 +54,26 @@ impl FileDescriptor {
         return Self {
             fd,
             handle: Box::new(handle),
+            owner: crate::task::scheduler::current_task_id(),
+            tag,
+            opened_at_ms: crate::time::monotonic_ms(),
         };
     }
 }
 
+/// An open descriptor as `lsof` shows it
+#[derive(Debug, Clone)]
+pub struct OpenFile {
+    pub fd: u32,
+    pub path: String,
+    pub flags: u8,
+    pub position: u64,
+    pub owner: Option<u64>,
+    pub tag: &'static str,
+    /// Time since it was opened (milliseconds)
+    pub age_ms: u64,
+}
+
 /// File descriptor table for managing open files
 pub struct FdTable {
     descriptors: Vec<FileDescriptor>,
@@ -46,56 +86,83 @@ impl FdTable {
         }
     }
     
-    /// Open a file and return a file descriptor
-    pub fn open(&mut self, path: &str, flags: u8) -> Result<u32, KernelError> {
-        serial_println!("DEBUG: FdTable::open - Starting for path '{}', flags={}", path, flags);
+    /// Fail with TooManyOpenFiles unless `count` more descriptors fit
+    fn check_room(&self, count: usize) -> Result<(), KernelError> {
+        if self.descriptors.len() + count > max_open_files() {
+            return Err(KernelError::TooManyOpenFiles);
+        }
+        Ok(())
+    }
+    
+    /// Open a file and return a file descriptor, recording `tag` as who
+    /// opened it
+    pub fn open(&mut self, path: &str, flags: u8, tag: &'static str) -> Result<u32, KernelError> {
+        kdebug!("fd", "FdTable::open - Starting for path '{}', flags={}", path, flags);
+        
+        // Before opening, so a refused open doesn't create or truncate
+        self.check_room(1)?;
         
         // Get the VFS manager
         let vfs_manager = match crate::fs::vfs::get_vfs_manager() {
             Some(manager) => {
-                serial_println!("DEBUG: FdTable::open - Got VFS manager");
+                kdebug!("fd", "FdTable::open - Got VFS manager");
                 manager
             },
             None => {
-                serial_println!("DEBUG: FdTable::open - ERROR: VFS manager not initialized");
+                kdebug!("fd", "FdTable::open - ERROR: VFS manager not initialized");
                 return Err(KernelError::NotInitialized);
             }
         };
         
         // Open the file
-        serial_println!("DEBUG: FdTable::open - Calling vfs_manager.open() for path '{}'", path);
+        kdebug!("fd", "FdTable::open - Calling vfs_manager.open() for path '{}'", path);
         let handle = match vfs_manager.open(path, flags) {
             Ok(h) => {
-                serial_println!("DEBUG: FdTable::open - vfs_manager.open() successful for path '{}'", h.path);
+                kdebug!("fd", "FdTable::open - vfs_manager.open() successful for path '{}'", h.path);
                 h
             },
             Err(e) => {
-                serial_println!("DEBUG: FdTable::open - vfs_manager.open() FAILED for path '{}': {:?}", path, e);
+                kdebug!("fd", "FdTable::open - vfs_manager.open() FAILED for path '{}': {:?}", path, e);
                 return Err(e);
             }
         };
         
         // Create a file descriptor
         let fd_entry;
-        serial_println!("DEBUG: FdTable::open - Calling FileDescriptor::new() for path '{}'", handle.path);
-        fd_entry = FileDescriptor::new(handle);
-        serial_println!("DEBUG: FdTable::open - FileDescriptor::new() successful, fd={}", fd_entry.fd);
+        kdebug!("fd", "FdTable::open - Calling FileDescriptor::new() for path '{}'", handle.path);
+        fd_entry = FileDescriptor::new(handle, tag);
+        kdebug!("fd", "FdTable::open - FileDescriptor::new() successful, fd={}", fd_entry.fd);
         let fd = fd_entry.fd;
         
         // Add to the table
-        serial_println!("DEBUG: FdTable::open - Pushing fd_entry (fd={}) to descriptors vector", fd);
+        kdebug!("fd", "FdTable::open - Pushing fd_entry (fd={}) to descriptors vector", fd);
+        let _tag = crate::allocator::tag("fd");
         self.descriptors.push(fd_entry);
-        serial_println!("DEBUG: FdTable::open - Push successful. Current descriptor count: {}", self.descriptors.len());
+        kdebug!("fd", "FdTable::open - Push successful. Current descriptor count: {}", self.descriptors.len());
         
-        serial_println!("DEBUG: FdTable::open - Returning Ok(fd={}) for path '{}'", fd, path);
+        kdebug!("fd", "FdTable::open - Returning Ok(fd={}) for path '{}'", fd, path);
         Ok(fd)
     }
     
-    /// Close a file descriptor
+    /// Add an already open handle to the table, returning its descriptor
+    pub fn insert(&mut self, handle: FileHandle, tag: &'static str) -> u32 {
+        let fd_entry = FileDescriptor::new(handle, tag);
+        let fd = fd_entry.fd;
+        let _tag = crate::allocator::tag("fd");
+        self.descriptors.push(fd_entry);
+        fd
+    }
+    
+    /// Close a file descriptor. Unknown descriptors are counted, as they
+    /// usually mean a double close.
     pub fn close(&mut self, fd: u32) -> Result<(), KernelError> {
-        let index = self.descriptors.iter()
-            .position(|desc| desc.fd == fd)
-            .ok_or(KernelError::InvalidHandle)?;
+        let index = match self.descriptors.iter().position(|desc| desc.fd == fd) {
+            Some(index) => index,
+            None => {
+                UNKNOWN_CLOSES.fetch_add(1, Ordering::Relaxed);
+                return Err(KernelError::InvalidHandle);
+            }
+        };
         
         // Remove the descriptor from the table
         let mut fd_entry = self.descriptors.remove(index);
@@ -104,6 +171,37 @@ impl FdTable {
         fd_entry.handle.close()
     }
     
+    /// Close every descriptor opened by `task_id`, returning how many were closed
+    pub fn close_owned_by(&mut self, task_id: u64) -> usize {
+        let mut closed = 0;
+        let mut index = 0;
+        while index < self.descriptors.len() {
+            if self.descriptors[index].owner == Some(task_id) {
+                let mut fd_entry = self.descriptors.remove(index);
+                if let Err(e) = fd_entry.handle.close() {
+                    kdebug!("fd", "FdTable::close_owned_by - Error closing fd={}: {:?}", fd_entry.fd, e);
+                }
+                closed += 1;
+            } else {
+                index += 1;
+            }
+        }
+        closed
+    }
+    
+    /// Every open descriptor, oldest first
+    pub fn list(&self, now_ms: u64) -> Vec<OpenFile> {
+        self.descriptors.iter().map(|desc| OpenFile {
+            fd: desc.fd,
+            path: desc.handle.path.clone(),
+            flags: desc.handle.flags,
+            position: desc.handle.position,
+            owner: desc.owner,
+            tag: desc.tag,
+            age_ms: now_ms.saturating_sub(desc.opened_at_ms),
+        }).collect()
+    }
+    
     /// Read from a file descriptor
     pub fn read(&mut self, fd: u32, buffer: &mut [u8]) -> Result<usize, KernelError> {
         let fd_entry = self.get_fd_mut(fd)?;
@@ -112,27 +210,26 @@ impl FdTable {
     
     /// Write to a file descriptor
     pub fn write(&mut self, fd: u32, buffer: &[u8]) -> Result<usize, KernelError> {
-        use crate::serial_println;
-        serial_println!("DEBUG: FdTable::write - Starting with fd={}, buffer.len={}", fd, buffer.len());
+        kdebug!("fd", "FdTable::write - Starting with fd={}, buffer.len={}", fd, buffer.len());
         
         // Get the file descriptor entry
         let fd_entry = match self.get_fd_mut(fd) {
             Ok(entry) => {
-                serial_println!("DEBUG: FdTable::write - Found FD entry");
+                kdebug!("fd", "FdTable::write - Found FD entry");
                 entry
             },
             Err(e) => {
-                serial_println!("DEBUG: FdTable::write - FD not found: {:?}", e);
+                kdebug!("fd", "FdTable::write - FD not found: {:?}", e);
                 return Err(e);
             }
         };
         
-        serial_println!("DEBUG: FdTable::write - Calling handle.write()");
+        kdebug!("fd", "FdTable::write - Calling handle.write()");
         let result = fd_entry.handle.write(buffer);
         
         match &result {
-            Ok(bytes) => serial_println!("DEBUG: FdTable::write - Completed, wrote {} bytes", bytes),
-            Err(e) => serial_println!("DEBUG: FdTable::write - Error: {:?}", e),
+            Ok(bytes) => kdebug!("fd", "FdTable::write - Completed, wrote {} bytes", bytes),
+            Err(e) => kdebug!("fd", "FdTable::write - Error: {:?}", e),
         }
         
         result
@@ -144,6 +241,12 @@ impl FdTable {
         fd_entry.handle.seek(position)
     }
     
+    /// Set the length of the file behind a descriptor
+    pub fn truncate(&mut self, fd: u32, length: u64) -> Result<(), KernelError> {
+        let fd_entry = self.get_fd_mut(fd)?;
+        fd_entry.handle.truncate(length)
+    }
+    
     /// Get the current position in a file
     pub fn tell(&self, fd: u32) -> Result<u64, KernelError> {
         let fd_entry = self.get_fd(fd)?;
@@ -166,78 +269,137 @@ impl FdTable {
 }
 
 /// Global file descriptor table - initialized in init()
-static mut FD_TABLE: Option<Mutex<FdTable>> = None;
+static mut FD_TABLE: Option<DiagMutex<FdTable>> = None;
 
 /// Initialize standard file descriptors (stdin, stdout, stderr)
 pub fn init() -> Result<(), KernelError> {
-    serial_println!("DEBUG: fd::init - Initializing file descriptor system");
+    kdebug!("fd", "fd::init - Initializing file descriptor system");
     
     // Initialize the FD table safely
     unsafe {
         if FD_TABLE.is_none() {
-            FD_TABLE = Some(Mutex::new(FdTable::new()));
-            serial_println!("DEBUG: fd::init - Created new FdTable");
+            FD_TABLE = Some(DiagMutex::new("fd::FD_TABLE", FdTable::new()));
+            kdebug!("fd", "fd::init - Created new FdTable");
         }
     }
     
-    serial_println!("DEBUG: fd::init - File descriptor system initialized");
+    kdebug!("fd", "fd::init - File descriptor system initialized");
     // In a real implementation, we would set up stdin, stdout, and stderr
     Ok(())
 }
 
 /// Get the global FD table or initialize it if needed
-fn get_fd_table() -> &'static Mutex<FdTable> {
+fn get_fd_table() -> &'static DiagMutex<FdTable> {
     unsafe {
         if let Some(table) = &FD_TABLE {
             table
         } else {
-            serial_println!("DEBUG: get_fd_table - FD_TABLE not initialized, creating it now");
-            FD_TABLE = Some(Mutex::new(FdTable::new()));
+            kdebug!("fd", "get_fd_table - FD_TABLE not initialized, creating it now");
+            FD_TABLE = Some(DiagMutex::new("fd::FD_TABLE", FdTable::new()));
             FD_TABLE.as_ref().unwrap()
         }
     }
 }
 
-/// Open a file and return a file descriptor
+/// Open a file and return a file descriptor. The calling source file is
+/// recorded as who opened it; `open_tagged` takes a shorter name.
+#[track_caller]
 pub fn open(path: &str, flags: u8) -> Result<u32, KernelError> {
-    serial_println!("DEBUG: fd::open - Opening file '{}'", path);
+    open_tagged(core::panic::Location::caller().file(), path, flags)
+}
+
+/// Open a file and return a file descriptor, with `tag` (such as "cp")
+/// shown by `lsof` as who opened it. Fails with TooManyOpenFiles once the
+/// table holds `fs.max_open_files`.
+pub fn open_tagged(tag: &'static str, path: &str, flags: u8) -> Result<u32, KernelError> {
+    kdebug!("fd", "fd::open - Opening file '{}'", path);
     let table = get_fd_table();
     let mut table_guard = table.lock();
-    let fd = table_guard.open(path, flags)?;
-    serial_println!("DEBUG: fd::open - File opened with fd={}", fd);
+    let fd = table_guard.open(path, flags, tag)?;
+    kdebug!("fd", "fd::open - File opened with fd={}", fd);
     Ok(fd)
 }
 
+/// Create a pipe, returning (read_fd, write_fd) owned by the current task
+pub fn pipe() -> Result<(u32, u32), KernelError> {
+    let table = get_fd_table();
+    let mut table_guard = table.lock();
+    table_guard.check_room(2)?;
+    let (reader, writer) = super::pipe::create_handles();
+    let read_fd = table_guard.insert(reader, "pipe");
+    let write_fd = table_guard.insert(writer, "pipe");
+    kdebug!("fd", "fd::pipe - Created pipe read_fd={} write_fd={}", read_fd, write_fd);
+    Ok((read_fd, write_fd))
+}
+
 /// Close a file descriptor
 pub fn close(fd: u32) -> Result<(), KernelError> {
-    serial_println!("DEBUG: fd::close - Closing fd={}", fd);
+    kdebug!("fd", "fd::close - Closing fd={}", fd);
     let table = get_fd_table();
     let mut table_guard = table.lock();
     table_guard.close(fd)
 }
 
+/// Closes of descriptors that weren't open, since boot
+pub fn unknown_closes() -> u64 {
+    UNKNOWN_CLOSES.load(Ordering::Relaxed)
+}
+
+/// Every open descriptor, for `lsof`
+pub fn list_open() -> Vec<OpenFile> {
+    get_fd_table().lock().list(crate::time::monotonic_ms())
+}
+
+/// Close all descriptors belonging to an exited task
+pub fn close_all_owned_by(task_id: u64) -> usize {
+    let table = get_fd_table();
+    let mut table_guard = table.lock();
+    table_guard.close_owned_by(task_id)
+}
+
+/// Pipe behind a descriptor, if it is one. Pipe I/O may block, so it must
+/// happen after the table lock is released.
+fn pipe_io(table: &mut FdTable, fd: u32, flag: u8) -> Result<Option<PipeIo>, KernelError> {
+    let fd_entry = table.get_fd_mut(fd)?;
+    if fd_entry.handle.flags & flag == 0 {
+        return Ok(None);
+    }
+    match fd_entry.handle.pipe.as_ref() {
+        Some(pipe) => Ok(Some(pipe.io()?)),
+        None => Ok(None),
+    }
+}
+
 /// Read from a file descriptor
 pub fn read(fd: u32, buffer: &mut [u8]) -> Result<usize, KernelError> {
-    serial_println!("DEBUG: fd::read - Reading from fd={}", fd);
+    kdebug!("fd", "fd::read - Reading from fd={}", fd);
     let table = get_fd_table();
     let mut table_guard = table.lock();
+    if let Some(pipe) = pipe_io(&mut table_guard, fd, file_flags::READ)? {
+        drop(table_guard);
+        return pipe.read(buffer);
+    }
     table_guard.read(fd, buffer)
 }
 
 /// Write to a file descriptor
 pub fn write(fd: u32, buffer: &[u8]) -> Result<usize, KernelError> {
-    serial_println!("DEBUG: fd::write - Starting with fd={}, buffer.len={}", fd, buffer.len());
+    kdebug!("fd", "fd::write - Starting with fd={}, buffer.len={}", fd, buffer.len());
     let table = get_fd_table();
     
-    serial_println!("DEBUG: fd::write - Getting lock on fd_table");
+    kdebug!("fd", "fd::write - Getting lock on fd_table");
     let mut table_guard = table.lock();
+    if let Some(pipe) = pipe_io(&mut table_guard, fd, file_flags::WRITE)? {
+        drop(table_guard);
+        return pipe.write(buffer);
+    }
     
-    serial_println!("DEBUG: fd::write - Got lock, calling table.write()");
+    kdebug!("fd", "fd::write - Got lock, calling table.write()");
     let result = table_guard.write(fd, buffer);
     
     match &result {
-        Ok(bytes) => serial_println!("DEBUG: fd::write - Completed, wrote {} bytes", bytes),
-        Err(e) => serial_println!("DEBUG: fd::write - Error: {:?}", e),
+        Ok(bytes) => kdebug!("fd", "fd::write - Completed, wrote {} bytes", bytes),
+        Err(e) => kdebug!("fd", "fd::write - Error: {:?}", e),
     }
     
     result
@@ -245,7 +407,7 @@ pub fn write(fd: u32, buffer: &[u8]) -> Result<usize, KernelError> {
 
 /// Seek to a position in a file
 pub fn seek(fd: u32, position: u64) -> Result<(), KernelError> {
-    serial_println!("DEBUG: fd::seek - Seeking fd={} to position {}", fd, position);
+    kdebug!("fd", "fd::seek - Seeking fd={} to position {}", fd, position);
     let table = get_fd_table();
     let mut table_guard = table.lock();
     table_guard.seek(fd, position)
@@ -253,8 +415,145 @@ pub fn seek(fd: u32, position: u64) -> Result<(), KernelError> {
 
 /// Get the current position in a file
 pub fn tell(fd: u32) -> Result<u64, KernelError> {
-    serial_println!("DEBUG: fd::tell - Getting position for fd={}", fd);
+    kdebug!("fd", "fd::tell - Getting position for fd={}", fd);
     let table = get_fd_table();
     let table_guard = table.lock();
     table_guard.tell(fd)
-} 
\ No newline at end of file
+} 
+
+/// Set the length of the file behind a descriptor, like ftruncate
+pub fn truncate(fd: u32, length: u64) -> Result<(), KernelError> {
+    kdebug!("fd", "fd::truncate - Truncating fd={} to {} bytes", fd, length);
+    let table = get_fd_table();
+    let mut table_guard = table.lock();
+    table_guard.truncate(fd, length)
+}
+
+/// Shrink and grow a scratch file in /tmp, through the VFS and through a
+/// descriptor whose position ends up past the new end, then unlink it and
+/// read it through the descriptor still open on it
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("FD: Running self-test");
+    let vfs = crate::fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let path = "/tmp/truncate-selftest";
+    let _ = vfs.remove(path);
+    vfs.create_file(path)?;
+
+    let mut fd = None;
+    let result = (|| {
+        crate::fs::direct_write_file(path, b"0123456789")?;
+        vfs.truncate(path, 4)?;
+        let mut buffer = [0xAAu8; 16];
+        let count = crate::fs::direct_read_file(path, &mut buffer)?;
+        if buffer[..count] != *b"0123" || vfs.metadata(path)?.size != 4 {
+            return Err(KernelError::ValidationError("Shrinking a file kept the old data"));
+        }
+
+        vfs.truncate(path, 8)?;
+        let count = crate::fs::direct_read_file(path, &mut buffer)?;
+        if buffer[..count] != *b"0123\0\0\0\0" {
+            return Err(KernelError::ValidationError("Growing a file did not zero-fill"));
+        }
+
+        let opened = open(path, file_flags::READ | file_flags::WRITE)?;
+        fd = Some(opened);
+        seek(opened, 6)?;
+        truncate(opened, 2)?;
+        if tell(opened)? != 6 || read(opened, &mut buffer)? != 0 {
+            return Err(KernelError::ValidationError("Read past the truncated end returned data"));
+        }
+        write(opened, b"x")?;
+        let count = crate::fs::direct_read_file(path, &mut buffer)?;
+        if buffer[..count] != *b"01\0\0\0\0x" {
+            return Err(KernelError::ValidationError("Write past the truncated end did not fill the gap"));
+        }
+
+        // Unlinking leaves the data readable until the descriptor closes
+        vfs.remove(path)?;
+        seek(opened, 0)?;
+        let count = read(opened, &mut buffer)?;
+        if buffer[..count] != *b"01\0\0\0\0x" || vfs.metadata(path).is_ok() {
+            return Err(KernelError::ValidationError("Unlinked file unreadable through an open descriptor"));
+        }
+        Ok(())
+    })();
+
+    if let Some(opened) = fd {
+        let _ = close(opened);
+    }
+    let _ = vfs.remove(path);
+
+    result?;
+    leak_test()?;
+    fault_test()?;
+    serial_println!("FD: Self-test passed");
+    Ok(())
+}
+
+/// Open /dev/null over and over without closing, as a leaking caller
+/// would, and check the table stops at its limit; then close them all,
+/// once too often
+fn leak_test() -> Result<(), KernelError> {
+    let limit = max_open_files();
+    let mut leaked = Vec::new();
+    let mut refused = false;
+    for _ in 0..=limit {
+        match open_tagged("fd-leak-test", "/dev/null", file_flags::READ) {
+            Ok(fd) => leaked.push(fd),
+            Err(KernelError::TooManyOpenFiles) => {
+                refused = true;
+                break;
+            }
+            Err(e) => {
+                leaked.iter().for_each(|fd| { let _ = close(*fd); });
+                return Err(e);
+            }
+        }
+    }
+    let listed = list_open().iter().filter(|file| file.tag == "fd-leak-test").count();
+    let unknown = unknown_closes();
+    for fd in &leaked {
+        close(*fd)?;
+    }
+    if !refused || leaked.len() > limit || listed != leaked.len() {
+        return Err(KernelError::ValidationError("Leaked descriptors weren't stopped at the limit"));
+    }
+    let closed_again = match leaked.first() {
+        Some(fd) => close(*fd),
+        None => Err(KernelError::InvalidHandle),
+    };
+    let expected = unknown + u64::from(!leaked.is_empty());
+    if !matches!(closed_again, Err(KernelError::InvalidHandle)) || unknown_closes() != expected {
+        return Err(KernelError::ValidationError("Closing a closed descriptor wasn't counted"));
+    }
+    Ok(())
+}
+
+/// Fail a write through a descriptor with an injected fault and check the
+/// caller gets that error, the offset stays put and closing leaves nothing
+/// open. Only builds with fault injection can run it.
+fn fault_test() -> Result<(), KernelError> {
+    if !crate::faultinject::enabled() {
+        return Ok(());
+    }
+    let vfs = crate::fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let path = "/tmp/fd-fault-selftest";
+    let fd = open_tagged("fd-fault-test", path, file_flags::WRITE | file_flags::CREATE)?;
+    let arming = crate::faultinject::Arming { fault: crate::faultinject::Fault::NoSpace, ..Default::default() };
+    let armed = crate::faultinject::arm(crate::faultinject::TEMPFS_WRITE.name(), arming);
+    let written = write(fd, b"lost");
+    crate::faultinject::TEMPFS_WRITE.disarm();
+    let offset = tell(fd);
+    let closed = close(fd);
+    let _ = vfs.remove(path);
+    armed?;
+    closed?;
+
+    if !matches!(written, Err(KernelError::NoSpace)) || offset? != 0 {
+        return Err(KernelError::ValidationError("Failed write wasn't reported as it failed"));
+    }
+    if list_open().iter().any(|file| file.tag == "fd-fault-test") {
+        return Err(KernelError::ValidationError("Descriptor leaked after a failed write"));
+    }
+    Ok(())
+}
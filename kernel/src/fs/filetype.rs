@@ -0,0 +1,290 @@
+//! Telling what a file holds from its first bytes
+//!
+//! `detect` looks at the start of a file for a magic number: an ELF header,
+//! a boot sector (FAT or not), a tar header or a BMP header. Failing those,
+//! the bytes are text if they decode as UTF-8 with no control characters
+//! besides the usual whitespace, and "data" otherwise. Only `SNIFF_SIZE`
+//! bytes are read, so a text file with binary further in still counts as
+//! text. The shell's `file` and the file manager's labels both use this.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use crate::errors::KernelError;
+use crate::serial_println;
+use super::vfs::{self, file_flags, NodeType, VfsManager};
+
+/// Bytes read from the start of a file to tell its type
+pub const SNIFF_SIZE: usize = 512;
+
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+/// Where a tar header keeps its magic; "ustar\0" for POSIX archives,
+/// "ustar  \0" for GNU ones
+const TAR_MAGIC_OFFSET: usize = 257;
+/// Boot sector signature at the end of the first sector
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+/// File system type field of a FAT12/16 boot sector, and of a FAT32 one
+const FAT16_TYPE_OFFSET: usize = 0x36;
+const FAT32_TYPE_OFFSET: usize = 0x52;
+/// Size of the BMP file header and the smallest info header after it
+const BMP_HEADER_SIZE: usize = 14 + 12;
+
+/// What a file looks like it holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileType {
+    Directory,
+    /// A device, pipe or link: nothing is read from it
+    Special(NodeType),
+    /// Zero bytes long
+    Empty,
+    Elf { bits: u8, little_endian: bool, kind: u16, machine: u16 },
+    /// A FAT volume: "FAT12", "FAT16" or "FAT32", and its label
+    Fat { fat: String, label: String },
+    /// A sector ending in the boot signature that isn't FAT
+    BootSector,
+    Tar { gnu: bool },
+    Bmp { width: u32, height: u32, bits: u16 },
+    /// ASCII text, or UTF-8 with other characters too
+    Text { ascii: bool },
+    Data,
+}
+
+impl FileType {
+    /// One line describing the type, as `file` prints it
+    pub fn description(&self) -> String {
+        match self {
+            FileType::Directory => "directory".to_string(),
+            FileType::Special(node_type) => match node_type {
+                NodeType::BlockDevice => "block device",
+                NodeType::CharacterDevice => "character device",
+                NodeType::SymbolicLink => "symbolic link",
+                NodeType::FIFO => "named pipe",
+                NodeType::Socket => "socket",
+                _ => "special file",
+            }.to_string(),
+            FileType::Empty => "empty".to_string(),
+            FileType::Elf { bits, little_endian, kind, machine } => {
+                let kind = match kind {
+                    1 => "relocatable",
+                    2 => "executable",
+                    3 => "shared object",
+                    4 => "core file",
+                    _ => "unknown type",
+                };
+                let machine = match machine {
+                    0x03 => "Intel 80386".to_string(),
+                    0x28 => "ARM".to_string(),
+                    0x3E => "x86-64".to_string(),
+                    0xB7 => "ARM aarch64".to_string(),
+                    0xF3 => "RISC-V".to_string(),
+                    other => format!("machine {:#x}", other),
+                };
+                format!("ELF {}-bit {} {}, {}", bits, if *little_endian { "LSB" } else { "MSB" }, kind, machine)
+            }
+            FileType::Fat { fat, label } if label.is_empty() => format!("{} file system image", fat),
+            FileType::Fat { fat, label } => format!("{} file system image, label \"{}\"", fat, label),
+            FileType::BootSector => "boot sector".to_string(),
+            FileType::Tar { gnu: false } => "POSIX tar archive".to_string(),
+            FileType::Tar { gnu: true } => "GNU tar archive".to_string(),
+            FileType::Bmp { width, height, bits } => format!("BMP image, {} x {}, {} bits", width, height, bits),
+            FileType::Text { ascii: true } => "ASCII text".to_string(),
+            FileType::Text { ascii: false } => "UTF-8 text".to_string(),
+            FileType::Data => "data".to_string(),
+        }
+    }
+
+    /// A word or two for listings, like the file manager's
+    pub fn label(&self) -> &'static str {
+        match self {
+            FileType::Directory => "dir",
+            FileType::Special(_) => "special",
+            FileType::Empty => "empty",
+            FileType::Elf { .. } => "program",
+            FileType::Fat { .. } => "FAT image",
+            FileType::BootSector => "boot sector",
+            FileType::Tar { .. } => "archive",
+            FileType::Bmp { .. } => "image",
+            FileType::Text { .. } => "text",
+            FileType::Data => "data",
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize, little_endian: bool) -> u16 {
+    let pair = [bytes[offset], bytes[offset + 1]];
+    if little_endian { u16::from_le_bytes(pair) } else { u16::from_be_bytes(pair) }
+}
+
+fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// Name the type of a file starting with `bytes`; pass at least the first
+/// `SNIFF_SIZE` bytes, or the whole file if it's shorter
+pub fn detect(bytes: &[u8]) -> FileType {
+    if bytes.is_empty() {
+        return FileType::Empty;
+    }
+    if bytes.len() >= 20 && bytes.starts_with(ELF_MAGIC) {
+        let little_endian = bytes[5] != 2;
+        return FileType::Elf {
+            bits: if bytes[4] == 1 { 32 } else { 64 },
+            little_endian,
+            kind: u16_at(bytes, 16, little_endian),
+            machine: u16_at(bytes, 18, little_endian),
+        };
+    }
+    if bytes.len() >= SNIFF_SIZE && bytes[BOOT_SIGNATURE_OFFSET..SNIFF_SIZE] == [0x55, 0xAA] {
+        return boot_sector(bytes);
+    }
+    if bytes.len() >= TAR_MAGIC_OFFSET + 8 && &bytes[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5] == b"ustar" {
+        return FileType::Tar { gnu: &bytes[TAR_MAGIC_OFFSET + 5..TAR_MAGIC_OFFSET + 8] == b"  \0" };
+    }
+    if bytes.len() >= BMP_HEADER_SIZE && bytes.starts_with(b"BM") {
+        return FileType::Bmp {
+            width: i32_at(bytes, 18).unsigned_abs(),
+            // Negative heights are images stored top row first
+            height: i32_at(bytes, 22).unsigned_abs(),
+            bits: u16_at(bytes, 28, true),
+        };
+    }
+    if is_text(bytes) {
+        return FileType::Text { ascii: bytes.is_ascii() };
+    }
+    FileType::Data
+}
+
+/// A FAT volume if the boot sector names its file system type, otherwise
+/// some other boot sector (a partition table, say)
+fn boot_sector(bytes: &[u8]) -> FileType {
+    for (type_offset, label_offset) in [(FAT16_TYPE_OFFSET, 0x2B), (FAT32_TYPE_OFFSET, 0x47)] {
+        let fs_type = &bytes[type_offset..type_offset + 8];
+        if fs_type.starts_with(b"FAT") {
+            let text = |field: &[u8]| String::from_utf8_lossy(field).trim_end().to_string();
+            let label = text(&bytes[label_offset..label_offset + 11]);
+            return FileType::Fat {
+                fat: text(fs_type),
+                label: if label == "NO NAME" { String::new() } else { label },
+            };
+        }
+    }
+    FileType::BootSector
+}
+
+/// Whether `bytes` read as text: UTF-8, allowing a character cut off at
+/// the end of the sample, with no control characters but tab, line
+/// breaks, form feed, backspace and escape
+fn is_text(bytes: &[u8]) -> bool {
+    let valid = match core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return false,
+    };
+    valid.chars().all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0C' | '\x08' | '\x1B'))
+}
+
+/// The type and size of the file at `path`, from its first `SNIFF_SIZE`
+/// bytes. Directories and special files aren't read.
+pub fn detect_path(vfs: &VfsManager, path: &str) -> Result<(FileType, u64), KernelError> {
+    let metadata = vfs.metadata(path)?;
+    let file_type = match metadata.node_type {
+        NodeType::File => {
+            // Opening checks the caller may read it
+            vfs.open(path, file_flags::READ)?.close()?;
+            let mut buffer = [0u8; SNIFF_SIZE];
+            let count = vfs.find_fs(path)?.lock().read_at(path, 0, &mut buffer)?;
+            detect(&buffer[..count])
+        }
+        NodeType::Directory => FileType::Directory,
+        other => FileType::Special(other),
+    };
+    Ok((file_type, metadata.size))
+}
+
+/// Check each format against a small sample, and a zero-length file
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("FILETYPE: Running self-test");
+
+    let mut elf = [0u8; 64];
+    elf[..4].copy_from_slice(ELF_MAGIC);
+    elf[4] = 2;
+    elf[5] = 1;
+    elf[16] = 2;
+    elf[18] = 0x3E;
+
+    let mut fat = [0u8; SNIFF_SIZE];
+    fat[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    fat[11..13].copy_from_slice(&512u16.to_le_bytes());
+    fat[0x2B..0x36].copy_from_slice(b"UNIVERSEK  ");
+    fat[FAT16_TYPE_OFFSET..FAT16_TYPE_OFFSET + 8].copy_from_slice(b"FAT16   ");
+    fat[BOOT_SIGNATURE_OFFSET..].copy_from_slice(&[0x55, 0xAA]);
+    let mut fat32 = [0u8; SNIFF_SIZE];
+    fat32[0x47..0x52].copy_from_slice(b"NO NAME    ");
+    fat32[FAT32_TYPE_OFFSET..FAT32_TYPE_OFFSET + 8].copy_from_slice(b"FAT32   ");
+    fat32[BOOT_SIGNATURE_OFFSET..].copy_from_slice(&[0x55, 0xAA]);
+    let mut mbr = [0u8; SNIFF_SIZE];
+    mbr[BOOT_SIGNATURE_OFFSET..].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut tar = [0u8; SNIFF_SIZE];
+    tar[..9].copy_from_slice(b"notes.txt");
+    tar[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 8].copy_from_slice(b"ustar\x0000");
+    let mut gnu_tar = tar;
+    gnu_tar[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 8].copy_from_slice(b"ustar  \0");
+
+    let mut bmp = [0u8; 54];
+    bmp[..2].copy_from_slice(b"BM");
+    bmp[14..18].copy_from_slice(&40u32.to_le_bytes());
+    bmp[18..22].copy_from_slice(&640i32.to_le_bytes());
+    bmp[22..26].copy_from_slice(&(-480i32).to_le_bytes());
+    bmp[28..30].copy_from_slice(&24u16.to_le_bytes());
+
+    let cases: [(&[u8], &str); 13] = [
+        (&elf, "ELF 64-bit LSB executable, x86-64"),
+        (&fat, "FAT16 file system image, label \"UNIVERSEK\""),
+        (&fat32, "FAT32 file system image"),
+        (&mbr, "boot sector"),
+        (&tar, "POSIX tar archive"),
+        (&gnu_tar, "GNU tar archive"),
+        (&bmp, "BMP image, 640 x 480, 24 bits"),
+        (b"hello\n\tworld\r\n", "ASCII text"),
+        ("na\u{ef}ve caf\u{e9}\n".as_bytes(), "UTF-8 text"),
+        // A sample can end partway through a character
+        (&"\u{e9}t\u{e9}".as_bytes()[..4], "UTF-8 text"),
+        (b"text\0with a NUL", "data"),
+        (&[0xFF, 0xFE, 0x00, 0x01], "data"),
+        (b"", "empty"),
+    ];
+    for (sample, expected) in cases {
+        let description = detect(sample).description();
+        if description != expected {
+            serial_println!("FILETYPE: Got '{}', expected '{}'", description, expected);
+            return Err(KernelError::ValidationError("File type detected wrongly"));
+        }
+    }
+    // Too short for its magic to mean anything
+    if detect(b"\x7FELF") != FileType::Data || detect(b"BM") != (FileType::Text { ascii: true }) {
+        return Err(KernelError::ValidationError("Truncated header taken for a format"));
+    }
+
+    let Some(vfs) = vfs::get_vfs_manager() else {
+        serial_println!("FILETYPE: Self-test passed (no file system)");
+        return Ok(());
+    };
+    let path = "/tmp/filetype-selftest";
+    let _ = vfs.remove_permanently(path);
+    let result = (|| {
+        vfs.create_file(path)?;
+        if detect_path(vfs, path)? != (FileType::Empty, 0) {
+            return Err(KernelError::ValidationError("Zero-length file not reported empty"));
+        }
+        vfs.write_at(path, 0, &elf)?;
+        if detect_path(vfs, path)?.0.label() != "program" || detect_path(vfs, "/tmp")?.0 != FileType::Directory {
+            return Err(KernelError::ValidationError("File type read from the VFS wrongly"));
+        }
+        Ok(())
+    })();
+    let _ = vfs.remove_permanently(path);
+    result?;
+
+    serial_println!("FILETYPE: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,579 @@
+// kernel/src/fs/iso9660.rs
+//! Read-only ISO9660 file system, for CD-ROMs and disc images.
+//!
+//! The primary volume descriptor (sector 16) gives the root directory and
+//! the path table. Directories are found through the path table and files
+//! through the directory records of their parent. Names are the plain
+//! ISO9660 ones with the ";1" version dropped. Rock Ridge and Joliet
+//! extensions are ignored, and names are matched without regard to case.
+//! Everything that would change the disc fails with UnsupportedFeature.
+//!
+//! The VFS hands file systems whole paths, so the file system is told where
+//! it is mounted and strips that off.
+
+use crate::drivers::rtc::DateTime;
+use crate::errors::KernelError;
+use crate::fs::block_device::BlockDevice;
+use crate::fs::vfs::{permissions, DirEntry, FileSystem, Metadata, MetadataUpdate, NodeType};
+use crate::serial_println;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Logical sector size; ISO9660 allows others but discs don't use them
+pub const SECTOR_SIZE: usize = 2048;
+
+/// Where the volume descriptors start
+const DESCRIPTOR_START: u32 = 16;
+/// Volume descriptors looked at before giving up on finding the primary one
+const MAX_DESCRIPTORS: u32 = 32;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+
+/// Directory record flag for subdirectories
+const FLAG_DIRECTORY: u8 = 0x02;
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// A directory record, reduced to what the file system uses
+#[derive(Debug, Clone)]
+struct Record {
+    extent: u32,
+    size: u32,
+    is_directory: bool,
+    /// Empty for "." and "\u{1}" for ".."
+    name: String,
+    /// Recording time, in seconds since the Unix epoch
+    recorded_at: u64,
+}
+
+impl Record {
+    /// Parse the record at the start of `bytes`, which holds at least its length
+    fn parse(bytes: &[u8]) -> Result<Self, KernelError> {
+        let length = bytes[0] as usize;
+        if length < 34 || bytes.len() < length {
+            return Err(KernelError::InvalidData);
+        }
+        let name_length = bytes[32] as usize;
+        if 33 + name_length > length {
+            return Err(KernelError::InvalidData);
+        }
+        let raw_name = &bytes[33..33 + name_length];
+        let name = match raw_name {
+            [0] => String::new(),
+            [1] => String::from("\u{1}"),
+            _ => {
+                let name = String::from_utf8_lossy(raw_name);
+                // Drop the version, and the dot of names without an extension
+                let name = name.split(';').next().unwrap_or("");
+                name.strip_suffix('.').unwrap_or(name).to_string()
+            }
+        };
+        Ok(Record {
+            extent: le_u32(bytes, 2),
+            size: le_u32(bytes, 10),
+            is_directory: bytes[25] & FLAG_DIRECTORY != 0,
+            name,
+            recorded_at: recording_time(&bytes[18..25]),
+        })
+    }
+
+    fn is_special(&self) -> bool {
+        self.name.is_empty() || self.name == "\u{1}"
+    }
+
+    fn node_type(&self) -> NodeType {
+        if self.is_directory { NodeType::Directory } else { NodeType::File }
+    }
+}
+
+/// Seconds since the epoch of a 7-byte directory record date: years since
+/// 1900, month, day, hour, minute, second, then the offset from UTC in
+/// quarter hours
+fn recording_time(bytes: &[u8]) -> u64 {
+    if bytes[1] == 0 {
+        return 0;
+    }
+    let local = DateTime {
+        year: 1900 + u16::from(bytes[0]),
+        month: bytes[1],
+        day: bytes[2],
+        hour: bytes[3],
+        minute: bytes[4],
+        second: bytes[5],
+    }.to_unix_seconds() as i64;
+    (local - i64::from(bytes[6] as i8) * 15 * 60).max(0) as u64
+}
+
+/// A path table entry: a directory and the number of its parent
+#[derive(Debug, Clone)]
+struct PathTableEntry {
+    name: String,
+    extent: u32,
+    /// Entries are numbered from 1; the root is its own parent
+    parent: u16,
+}
+
+/// An ISO9660 volume
+pub struct IsoFileSystem {
+    device: Arc<Mutex<dyn BlockDevice>>,
+    /// Where the volume is mounted
+    prefix: String,
+    volume_id: String,
+    volume_sectors: u32,
+    root: Record,
+    path_table: Vec<PathTableEntry>,
+}
+
+impl IsoFileSystem {
+    /// Read the volume on `device`, to be mounted at `prefix`
+    pub fn new(device: Arc<Mutex<dyn BlockDevice>>, prefix: &str) -> Result<Self, KernelError> {
+        let block_size = device.lock().block_size();
+        if block_size == 0 || SECTOR_SIZE % block_size != 0 {
+            return Err(KernelError::UnsupportedFeature);
+        }
+
+        let mut fs = IsoFileSystem {
+            device,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            volume_id: String::new(),
+            volume_sectors: 0,
+            root: Record { extent: 0, size: 0, is_directory: true, name: String::new(), recorded_at: 0 },
+            path_table: Vec::new(),
+        };
+        let descriptor = fs.primary_descriptor()?;
+        if le_u16(&descriptor, 128) as usize != SECTOR_SIZE {
+            return Err(KernelError::UnsupportedFeature);
+        }
+        fs.volume_id = String::from_utf8_lossy(&descriptor[40..72]).trim_end().to_string();
+        fs.volume_sectors = le_u32(&descriptor, 80);
+        fs.root = Record::parse(&descriptor[156..190])?;
+
+        let table_size = le_u32(&descriptor, 132) as usize;
+        let mut table = vec![0u8; table_size];
+        fs.read_extent(le_u32(&descriptor, 140), 0, &mut table)?;
+        fs.path_table = parse_path_table(&table)?;
+
+        serial_println!("DEBUG: ISO9660: volume '{}', {} sectors, {} directories",
+            fs.volume_id, fs.volume_sectors, fs.path_table.len());
+        Ok(fs)
+    }
+
+    /// The volume's label
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    fn read_sector(&self, sector: u32, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let device = self.device.lock();
+        let block_size = device.block_size();
+        let per_sector = (SECTOR_SIZE / block_size) as u64;
+        for (i, chunk) in buffer[..SECTOR_SIZE].chunks_mut(block_size).enumerate() {
+            device.read_block(u64::from(sector) * per_sector + i as u64, chunk)
+                .map_err(|_| KernelError::ReadError)?;
+        }
+        Ok(())
+    }
+
+    /// Read `buffer.len()` bytes from `offset` within the extent starting at
+    /// sector `extent`
+    fn read_extent(&self, extent: u32, offset: u64, buffer: &mut [u8]) -> Result<(), KernelError> {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let index = u32::try_from(position / SECTOR_SIZE as u64).map_err(|_| KernelError::InvalidParameter)?;
+            let within = (position % SECTOR_SIZE as u64) as usize;
+            self.read_sector(extent + index, &mut sector)?;
+            let count = (SECTOR_SIZE - within).min(buffer.len() - done);
+            buffer[done..done + count].copy_from_slice(&sector[within..within + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Find the primary volume descriptor
+    fn primary_descriptor(&self) -> Result<Vec<u8>, KernelError> {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        for index in DESCRIPTOR_START..DESCRIPTOR_START + MAX_DESCRIPTORS {
+            self.read_sector(index, &mut sector)?;
+            if &sector[1..6] != STANDARD_ID {
+                break;
+            }
+            match sector[0] {
+                DESCRIPTOR_PRIMARY => return Ok(sector),
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        Err(KernelError::FilesystemError(crate::errors::FilesystemError::FormatError))
+    }
+
+    /// Visit the records of a directory from byte offset `start`, until the
+    /// visitor returns false. Records don't cross sectors; a zero length
+    /// byte means the rest of the sector is padding.
+    fn scan(&self, directory: &Record, start: usize, visit: &mut dyn FnMut(usize, Record) -> bool)
+        -> Result<(), KernelError> {
+        let size = directory.size as usize;
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        let mut loaded = None;
+        let mut offset = start;
+        while offset < size {
+            let index = (offset / SECTOR_SIZE) as u32;
+            if loaded != Some(index) {
+                self.read_sector(directory.extent + index, &mut sector)?;
+                loaded = Some(index);
+            }
+            let within = offset % SECTOR_SIZE;
+            let length = sector[within] as usize;
+            if length == 0 {
+                offset = (index as usize + 1) * SECTOR_SIZE;
+                continue;
+            }
+            let record = Record::parse(&sector[within..])?;
+            if !visit(offset, record) {
+                return Ok(());
+            }
+            offset += length;
+        }
+        Ok(())
+    }
+
+    /// Path components below the mount point
+    fn components<'p>(&self, path: &'p str) -> Result<Vec<&'p str>, KernelError> {
+        let relative = path.strip_prefix(self.prefix.as_str()).ok_or(KernelError::NotFound)?;
+        if !relative.is_empty() && !relative.starts_with('/') {
+            return Err(KernelError::NotFound);
+        }
+        Ok(relative.split('/').filter(|part| !part.is_empty() && *part != ".").collect())
+    }
+
+    /// The directory record of a directory through the path table
+    fn directory(&self, components: &[&str]) -> Result<Record, KernelError> {
+        let mut number = 1;
+        for component in components {
+            let found = self.path_table.iter().enumerate().skip(1)
+                .find(|(_, entry)| entry.parent as usize == number && entry.name.eq_ignore_ascii_case(component));
+            number = match found {
+                Some((index, _)) => index + 1,
+                None => return Err(KernelError::NotFound),
+            };
+        }
+        if number == 1 {
+            return Ok(self.root.clone());
+        }
+
+        // A directory's own "." record carries its size
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        self.read_sector(self.path_table[number - 1].extent, &mut sector)?;
+        let mut record = Record::parse(&sector)?;
+        record.name = components.last().map_or_else(String::new, |name| name.to_string());
+        Ok(record)
+    }
+
+    /// The record for `path`
+    fn lookup(&self, path: &str) -> Result<Record, KernelError> {
+        let components = self.components(path)?;
+        let Some((last, parents)) = components.split_last() else {
+            return Ok(self.root.clone());
+        };
+        let parent = self.directory(parents)?;
+
+        let mut found = None;
+        self.scan(&parent, 0, &mut |_, record| {
+            if !record.is_special() && record.name.eq_ignore_ascii_case(last) {
+                found = Some(record);
+                return false;
+            }
+            true
+        })?;
+        found.ok_or(KernelError::NotFound)
+    }
+
+    fn refuse_write() -> Result<(), KernelError> {
+        Err(KernelError::UnsupportedFeature)
+    }
+}
+
+fn parse_path_table(table: &[u8]) -> Result<Vec<PathTableEntry>, KernelError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= table.len() {
+        let name_length = table[offset] as usize;
+        if name_length == 0 || offset + 8 + name_length > table.len() {
+            break;
+        }
+        let name = &table[offset + 8..offset + 8 + name_length];
+        entries.push(PathTableEntry {
+            name: if name == [0] { String::new() } else { String::from_utf8_lossy(name).to_string() },
+            extent: le_u32(table, offset + 2),
+            parent: le_u16(table, offset + 6),
+        });
+        // Names of odd length are padded to keep entries word-aligned
+        offset += 8 + name_length + name_length % 2;
+    }
+    if entries.is_empty() {
+        return Err(KernelError::InvalidData);
+    }
+    Ok(entries)
+}
+
+/// Whether `device` holds an ISO9660 volume
+pub fn probe(device: &dyn BlockDevice) -> bool {
+    let block_size = device.block_size();
+    if block_size == 0 || SECTOR_SIZE % block_size != 0 {
+        return false;
+    }
+    // The identifier sits in the first bytes of the first descriptor
+    let first_block = u64::from(DESCRIPTOR_START) * (SECTOR_SIZE / block_size) as u64;
+    let mut block = vec![0u8; block_size];
+    device.read_block(first_block, &mut block).is_ok() && &block[1..6] == STANDARD_ID
+}
+
+impl FileSystem for IsoFileSystem {
+    fn mount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn unmount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn create_file(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn create_directory(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn open(&mut self, path: &str, write: bool) -> Result<Option<usize>, KernelError> {
+        if write {
+            return Err(KernelError::UnsupportedFeature);
+        }
+        if self.lookup(path)?.is_directory {
+            return Err(KernelError::NotAFile);
+        }
+        Ok(None)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
+        let record = self.lookup(path)?;
+        let mut metadata = if record.is_directory {
+            Metadata::new_directory()
+        } else {
+            Metadata::new_file()
+        };
+        metadata.size = u64::from(record.size);
+        // Nothing on a disc can be written
+        metadata.permissions = if record.is_directory {
+            permissions::ALL & !permissions::ALL_WRITE
+        } else {
+            permissions::READ | permissions::GROUP_READ | permissions::OTHERS_READ
+        };
+        metadata.created_at = record.recorded_at;
+        metadata.modified_at = record.recorded_at;
+        metadata.accessed_at = record.recorded_at;
+        Ok(metadata)
+    }
+
+    fn set_metadata(&mut self, _path: &str, _update: MetadataUpdate) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn read_dir_from(&self, path: &str, cursor: usize, out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError> {
+        let directory = self.lookup(path)?;
+        if !directory.is_directory {
+            return Err(KernelError::NotADirectory);
+        }
+
+        // The cursor is a byte offset into the directory, which never changes
+        let mut filled = 0;
+        let mut next = None;
+        self.scan(&directory, cursor, &mut |offset, record| {
+            if record.is_special() {
+                return true;
+            }
+            if filled == out.len() {
+                next = Some(offset);
+                return false;
+            }
+            out[filled] = Some(DirEntry::new(&record.name, record.node_type(), record.extent as usize));
+            filled += 1;
+            true
+        })?;
+        Ok((filled, next))
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn name(&self) -> &str {
+        "ISO9660"
+    }
+
+    fn device_id(&self) -> Option<u64> {
+        self.device.lock().device_id()
+    }
+
+    fn total_space(&self) -> u64 {
+        u64::from(self.volume_sectors) * SECTOR_SIZE as u64
+    }
+
+    fn available_space(&self) -> u64 {
+        0
+    }
+
+    fn read_at(&self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        let record = self.lookup(path)?;
+        if record.is_directory {
+            return Err(KernelError::IsADirectory);
+        }
+        let size = u64::from(record.size);
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((size - offset) as usize);
+        self.read_extent(record.extent, offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&mut self, _path: &str, _offset: u64, _buffer: &[u8]) -> Result<usize, KernelError> {
+        Err(KernelError::UnsupportedFeature)
+    }
+
+    fn truncate(&mut self, _path: &str, _length: u64) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+}
+
+/// Build a small disc image in memory, on 512-byte blocks, and check
+/// listing, lookups, reads across sectors and that writes are refused
+pub fn self_test() -> Result<(), KernelError> {
+    use crate::fs::ramdisk::RamDisk;
+
+    serial_println!("ISO9660: Running self-test");
+
+    // 2024-01-02 03:04:05 at UTC+1
+    const DATE: [u8; 7] = [124, 1, 2, 3, 4, 5, 4];
+    fn record(extent: u32, size: u32, flags: u8, name: &[u8]) -> Vec<u8> {
+        let length = 33 + name.len() + (name.len() + 1) % 2;
+        let mut bytes = vec![0u8; length];
+        bytes[0] = length as u8;
+        bytes[2..6].copy_from_slice(&extent.to_le_bytes());
+        bytes[6..10].copy_from_slice(&extent.to_be_bytes());
+        bytes[10..14].copy_from_slice(&size.to_le_bytes());
+        bytes[14..18].copy_from_slice(&size.to_be_bytes());
+        bytes[18..25].copy_from_slice(&DATE);
+        bytes[25] = flags;
+        bytes[32] = name.len() as u8;
+        bytes[33..33 + name.len()].copy_from_slice(name);
+        bytes
+    }
+    fn put(image: &mut [u8], sector: usize, records: &[Vec<u8>]) {
+        let mut offset = sector * SECTOR_SIZE;
+        for record in records {
+            image[offset..offset + record.len()].copy_from_slice(record);
+            offset += record.len();
+        }
+    }
+
+    // Descriptors at 16 and 17, path table at 18, root at 20, DOCS at 21,
+    // README.TXT over 22-23 and DOCS/NOTE.TXT at 24
+    let readme: Vec<u8> = (0..3000u32).map(|i| (i % 253) as u8).collect();
+    let mut image = vec![0u8; 25 * SECTOR_SIZE];
+    let pvd = 16 * SECTOR_SIZE;
+    image[pvd] = DESCRIPTOR_PRIMARY;
+    image[pvd + 1..pvd + 6].copy_from_slice(STANDARD_ID);
+    image[pvd + 6] = 1;
+    image[pvd + 40..pvd + 72].fill(b' ');
+    image[pvd + 40..pvd + 48].copy_from_slice(b"SELFTEST");
+    image[pvd + 80..pvd + 84].copy_from_slice(&25u32.to_le_bytes());
+    image[pvd + 128..pvd + 130].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    image[pvd + 132..pvd + 136].copy_from_slice(&22u32.to_le_bytes());
+    image[pvd + 140..pvd + 144].copy_from_slice(&18u32.to_le_bytes());
+    image[pvd + 156..pvd + 190].copy_from_slice(&record(20, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0]));
+    image[17 * SECTOR_SIZE] = DESCRIPTOR_TERMINATOR;
+    image[17 * SECTOR_SIZE + 1..17 * SECTOR_SIZE + 6].copy_from_slice(STANDARD_ID);
+    let table = [&[1u8, 0, 20, 0, 0, 0, 1, 0, 0, 0][..], &[4, 0, 21, 0, 0, 0, 1, 0, b'D', b'O', b'C', b'S']].concat();
+    image[18 * SECTOR_SIZE..18 * SECTOR_SIZE + table.len()].copy_from_slice(&table);
+    put(&mut image, 20, &[
+        record(20, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0]),
+        record(20, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[1]),
+        record(21, SECTOR_SIZE as u32, FLAG_DIRECTORY, b"DOCS"),
+        record(22, readme.len() as u32, 0, b"README.TXT;1"),
+    ]);
+    put(&mut image, 21, &[
+        record(21, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0]),
+        record(20, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[1]),
+        record(24, 5, 0, b"NOTE.;1"),
+    ]);
+    image[22 * SECTOR_SIZE..22 * SECTOR_SIZE + readme.len()].copy_from_slice(&readme);
+    image[24 * SECTOR_SIZE..24 * SECTOR_SIZE + 5].copy_from_slice(b"hello");
+
+    let device: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(RamDisk::from_image(&image)?));
+    if !probe(&*device.lock()) {
+        return Err(KernelError::ValidationError("ISO9660 image not recognised"));
+    }
+    let mut fs = IsoFileSystem::new(device, "/cdrom")?;
+    if fs.volume_id() != "SELFTEST" || fs.total_space() != 25 * SECTOR_SIZE as u64 {
+        return Err(KernelError::ValidationError("ISO9660 volume descriptor misread"));
+    }
+
+    // One entry per page exercises the cursor
+    let mut names = Vec::new();
+    let mut page = [None];
+    let mut cursor = Some(0);
+    while let Some(start) = cursor {
+        let (filled, next) = fs.read_dir_from("/cdrom", start, &mut page)?;
+        names.extend(page[..filled].iter_mut().filter_map(Option::take).map(|entry| entry.name));
+        cursor = next;
+    }
+    if names != ["DOCS", "README.TXT"] {
+        serial_println!("ISO9660: Root listing: {:?}", names);
+        return Err(KernelError::ValidationError("ISO9660 root listing wrong"));
+    }
+
+    let mut buffer = [0u8; 20];
+    let read = fs.read_at("/cdrom/readme.txt", 2040, &mut buffer)?;
+    let mut note = [0u8; 16];
+    let note_read = fs.read_at("/cdrom/docs/Note", 0, &mut note)?;
+    if read != 20 || buffer[..] != readme[2040..2060] || &note[..note_read] != b"hello" {
+        return Err(KernelError::ValidationError("ISO9660 file contents wrong"));
+    }
+    let metadata = fs.metadata("/cdrom/DOCS/NOTE")?;
+    let expected = DateTime { year: 2024, month: 1, day: 2, hour: 2, minute: 4, second: 5 }.to_unix_seconds();
+    if metadata.size != 5 || metadata.modified_at != expected || metadata.permissions & permissions::ALL_WRITE != 0
+        || fs.metadata("/cdrom/docs")?.node_type != NodeType::Directory {
+        return Err(KernelError::ValidationError("ISO9660 metadata wrong"));
+    }
+    if !matches!(fs.metadata("/cdrom/missing"), Err(KernelError::NotFound))
+        || !matches!(fs.metadata("/cdrom/docs/missing/x"), Err(KernelError::NotFound)) {
+        return Err(KernelError::ValidationError("ISO9660 found a file that isn't there"));
+    }
+
+    if !matches!(fs.write_at("/cdrom/README.TXT", 0, b"x"), Err(KernelError::UnsupportedFeature))
+        || !matches!(fs.create_file("/cdrom/NEW"), Err(KernelError::UnsupportedFeature))
+        || !matches!(fs.remove("/cdrom/README.TXT"), Err(KernelError::UnsupportedFeature))
+        || !matches!(fs.open("/cdrom/README.TXT", true), Err(KernelError::UnsupportedFeature)) {
+        return Err(KernelError::ValidationError("ISO9660 accepted a write"));
+    }
+
+    serial_println!("ISO9660: Self-test passed");
+    Ok(())
+}
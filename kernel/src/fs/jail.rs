@@ -0,0 +1,119 @@
+//! Per-task root directories
+//!
+//! A task whose root isn't "/" is jailed in it: the VFS makes every path
+//! the task hands it canonical first, which resolves `..` lexically and
+//! stops it at "/", and only then puts it under the root. "/..",
+//! "../../etc" and the like therefore name the top of the jail, never
+//! anything above it. The VFS has no symbolic links, so nothing met while
+//! resolving can lead back out, and a hard link can only be made between
+//! two names the jail already resolved.
+//!
+//! Tasks start with the root of whoever starts them; `chroot` in the shell
+//! starts one with a narrower root.
+
+use alloc::string::String;
+use crate::errors::KernelError;
+use crate::fs::path::Path;
+use crate::fs::vfs::{self, file_flags, NodeType};
+use crate::serial_println;
+use crate::task::scheduler;
+
+/// Where `path`, as seen by a task jailed in `root`, really is. `root`
+/// must be canonical; the result always is, and is `root` or below it.
+pub fn resolve(root: &str, path: &str) -> String {
+    let path = Path::new(path).canonical();
+    if root == "/" {
+        return path.into_string();
+    }
+    Path::new(root).join(path.as_str().trim_start_matches('/')).canonical().into_string()
+}
+
+/// `resolve` for the running task
+pub fn resolve_for_current(path: &str) -> String {
+    resolve(&scheduler::current_root(), path)
+}
+
+/// Check paths that climb with `..` stay in the jail, then jail a task in
+/// a scratch directory and have it try to reach, create and link files
+/// outside it
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("JAIL: Running self-test");
+
+    let cases = [
+        ("/", "/a/../b", "/b"),
+        ("/", "../..", "/"),
+        ("/jail", "/", "/jail"),
+        ("/jail", "/..", "/jail"),
+        ("/jail", "../../etc", "/jail/etc"),
+        ("/jail", "/a/../../..", "/jail"),
+        ("/jail", "/a/./b/../c", "/jail/a/c"),
+        ("/jail", "//..//jail/../../x", "/jail/x"),
+        ("/jail/inner", "/../../jail/secret", "/jail/inner/jail/secret"),
+    ];
+    for (root, path, expected) in cases {
+        let resolved = resolve(root, path);
+        if resolved != expected {
+            serial_println!("JAIL: {} in {} resolved to {}, not {}", path, root, resolved, expected);
+            return Err(KernelError::ValidationError("Path escaped its root"));
+        }
+    }
+
+    let Some(vfs) = vfs::get_vfs_manager() else {
+        serial_println!("JAIL: No VFS, skipping the jailed task");
+        serial_println!("JAIL: Self-test passed");
+        return Ok(());
+    };
+    let root = "/tmp/jail-selftest";
+    let secret = "/tmp/jail-selftest-secret";
+    let _ = vfs.remove_permanently(&Path::new(root).join("escaped").into_string());
+    let _ = vfs.remove_permanently(root);
+    vfs.create_directory(root)?;
+    if vfs.metadata(secret).is_err() {
+        vfs.create_file(secret)?;
+    }
+
+    let mut failure = None;
+    let task = scheduler::run_as_task_in(String::from(root), &mut || {
+        let check = || -> Result<(), &'static str> {
+            if scheduler::current_root() != root {
+                return Err("Task started outside its jail");
+            }
+            if !vfs.metadata("/").is_ok_and(|metadata| metadata.node_type == NodeType::Directory) {
+                return Err("Jail's root isn't its directory");
+            }
+            for path in ["/../jail-selftest-secret", "../../tmp/jail-selftest-secret", secret] {
+                if vfs.metadata(path).is_ok() {
+                    return Err("Jailed task reached a file outside with ..");
+                }
+            }
+            let handle = vfs.open("/../../escaped", file_flags::WRITE | file_flags::CREATE)
+                .map_err(|_| "Jailed task couldn't create a file in its jail")?;
+            drop(handle);
+            if vfs.link("/../jail-selftest-secret", "/stolen").is_ok() {
+                return Err("Jailed task linked a file from outside");
+            }
+            Ok(())
+        };
+        failure = check().err();
+        0
+    });
+
+    let created_inside = vfs.metadata(&Path::new(root).join("escaped").into_string()).is_ok();
+    let created_outside = vfs.metadata("/escaped").is_ok() || vfs.metadata("/tmp/escaped").is_ok();
+    let _ = vfs.remove_permanently(&Path::new(root).join("escaped").into_string());
+    let _ = vfs.remove_permanently(root);
+    let _ = vfs.remove_permanently(secret);
+    task?;
+    if let Some(failure) = failure {
+        return Err(KernelError::ValidationError(failure));
+    }
+    if !created_inside || created_outside {
+        return Err(KernelError::ValidationError("File made with .. landed outside the jail"));
+    }
+    if scheduler::current_root() != "/" {
+        return Err(KernelError::ValidationError("Jail outlived its task"));
+    }
+
+    serial_println!("JAIL: Self-test passed");
+    Ok(())
+}
@@ -1,16 +1,31 @@
 pub mod block_device;
 pub mod block_adapter;
+pub mod dd;
 pub mod ramdisk;
 pub mod simple_fs;
 pub mod tempfs;
 pub mod vfs;
 pub mod fat;
+pub mod iso9660;
+pub mod procfs;
+pub mod devfs;
 pub mod fd;
+pub mod filetype;
+pub mod jail;
+pub mod path;
+pub mod pipe;
+pub mod trash;
+pub mod walk;
+pub mod watch;
 
 use crate::serial_println;
 use crate::errors::KernelError;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use spin::Mutex;
+use crate::sync::DiagMutex;
+use crate::fs::vfs::MountFlags;
 
 /// Initialize the file system subsystem.
 /// This sets up the VFS and mounts the initial file systems.
@@ -37,6 +52,15 @@ pub fn init() -> Result<(), KernelError> {
         }
     }
     
+    // Safe mode leaves disks alone and roots on TempFS
+    if crate::safe_mode::is_active() {
+        crate::safe_mode::skip("disk file systems");
+        init_ram_fs()?;
+        mount_proc();
+        mount_dev();
+        return Ok(());
+    }
+    
     // Try to initialize device-based file system first
     serial_println!("DEBUG: Attempting to initialize device-based file system.");
     if let Err(e) = init_device_fs() {
@@ -45,9 +69,84 @@ pub fn init() -> Result<(), KernelError> {
         init_ram_fs()?;
     }
     
+    // Discs are extras; the system runs without them
+    mount_cdroms();
+    mount_proc();
+    mount_dev();
+    
     Ok(())
 }
 
+/// Mount the generated files at /proc
+fn mount_proc() {
+    let Some(vfs) = vfs::get_vfs_manager() else {
+        return;
+    };
+    // Not mounts: reading it would lock every file system, /proc included
+    let _ = procfs::register("iomem", crate::memory::iomem_text);
+    let _ = procfs::register("loadavg", crate::task::idle::loadavg_text);
+    let _ = procfs::register("pstore", crate::logger::pstore::last_boot_text);
+    let _ = procfs::register("cmdline", crate::cmdline::text);
+    let _ = procfs::register("meminfo", crate::allocator::meminfo_text);
+    let _ = procfs::register("diskstats", crate::device::iostats::diskstats_text);
+    let _ = procfs::register("tasks", crate::task::scheduler::tasks_text);
+    let _ = procfs::register("version", crate::version::proc_text);
+    let _ = vfs.create_directory("/proc");
+    match vfs.mount("/proc", Arc::new(DiagMutex::new("fs:proc", procfs::ProcFs::new("/proc"))), MountFlags::NONE) {
+        Ok(()) => serial_println!("DEBUG: Mounted procfs at /proc"),
+        Err(e) => serial_println!("DEBUG: Failed to mount procfs: {:?}", e),
+    }
+}
+
+/// Mount the registered character devices at /dev
+fn mount_dev() {
+    let Some(vfs) = vfs::get_vfs_manager() else {
+        return;
+    };
+    let _ = vfs.create_directory("/dev");
+    match vfs.mount("/dev", Arc::new(DiagMutex::new("fs:dev", devfs::DevFs::new("/dev"))), MountFlags::NONE) {
+        Ok(()) => serial_println!("DEBUG: Mounted devfs at /dev"),
+        Err(e) => serial_println!("DEBUG: Failed to mount devfs: {:?}", e),
+    }
+}
+
+/// Mount the ISO9660 disc in each CD-ROM drive read-only, at /cdrom,
+/// /cdrom1 and so on
+fn mount_cdroms() {
+    let Some(vfs) = vfs::get_vfs_manager() else {
+        return;
+    };
+    let drives = crate::device::get_block_devices().into_iter()
+        .filter(|device| device.lock().as_any().is::<crate::device::atapi::AtapiDevice>());
+    for (index, drive) in drives.enumerate() {
+        let path = if index == 0 { String::from("/cdrom") } else { format!("/cdrom{}", index) };
+        let adapter = block_adapter::DeviceBlockAdapter::new(drive);
+        let name = adapter.name().to_string();
+        let device: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(adapter));
+        let has_disc = {
+            let device = device.lock();
+            device.block_count() > 0 && iso9660::probe(&*device)
+        };
+        if !has_disc {
+            serial_println!("DEBUG: No ISO9660 disc in {}", name);
+            continue;
+        }
+        let fs = match iso9660::IsoFileSystem::new(device, &path) {
+            Ok(fs) => fs,
+            Err(e) => {
+                serial_println!("DEBUG: Could not read the disc in {}: {:?}", name, e);
+                continue;
+            }
+        };
+        // Give the mount point a directory to show up as; it may be there already
+        let _ = vfs.create_directory(&path);
+        match vfs.mount(&path, Arc::new(DiagMutex::new("fs:cdrom", fs)), MountFlags::READ_ONLY) {
+            Ok(()) => serial_println!("DEBUG: Disc in {} mounted read-only at {}", name, path),
+            Err(e) => serial_println!("DEBUG: Failed to mount the disc in {}: {:?}", name, e),
+        }
+    }
+}
+
 /// Initialize a file system based on hardware devices
 fn init_device_fs() -> Result<(), KernelError> {
     serial_println!("DEBUG: init_device_fs() called.");
@@ -65,44 +164,58 @@ fn init_device_fs() -> Result<(), KernelError> {
     let block_adapter = block_adapter::DeviceBlockAdapter::new_first_available()?;
     serial_println!("DEBUG: Using block device: {}", block_adapter.name());
     
-    // Try to create a FAT file system on top of the device
-    // This could fail if the device isn't formatted as FAT
-    match fat::FatFileSystem::new(Arc::new(Mutex::new(block_adapter))) {
-        Ok(fat_fs) => {
-            let fs = Arc::new(Mutex::new(fat_fs));
-            
-            // Mount the FAT file system
-            let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
-            vfs.mount("/", fs.clone())?;
-            
-            serial_println!("DEBUG: FAT file system mounted at /");
-            
-            // Store the mounted filesystem
-            unsafe {
-                GLOBAL_FS = Some(fs);
+    // SFS volumes are recognised by their magic number; anything else is
+    // tried as FAT, which fails if the device isn't formatted as FAT
+    let device: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(block_adapter));
+    let (is_sfs, flags) = {
+        let device = device.lock();
+        (simple_fs::probe(&*device), if device.read_only() { MountFlags::READ_ONLY } else { MountFlags::NONE })
+    };
+    let fs: Arc<DiagMutex<dyn vfs::FileSystem>> = if is_sfs {
+        Arc::new(DiagMutex::new("fs:/", simple_fs::SimpleFileSystem::new(device)?))
+    } else {
+        match fat::FatFileSystem::new(device) {
+            Ok(fat_fs) => Arc::new(DiagMutex::new("fs:/", fat_fs)),
+            Err(e) => {
+                serial_println!("DEBUG: Failed to create FAT file system: {:?}", e);
+                return Err(e);
             }
-            
-            Ok(())
-        }
-        Err(e) => {
-            serial_println!("DEBUG: Failed to create FAT file system: {:?}", e);
-            Err(e)
         }
+    };
+    
+    // Mount the file system
+    let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    vfs.mount("/", fs.clone(), flags)?;
+    
+    serial_println!("DEBUG: {} file system mounted at /", if is_sfs { "SFS" } else { "FAT" });
+    
+    // Store the mounted filesystem
+    unsafe {
+        GLOBAL_FS = Some(fs);
     }
+    
+    Ok(())
 }
 
 /// Initialize a RAM-based file system
 fn init_ram_fs() -> Result<(), KernelError> {
     serial_println!("DEBUG: Initializing RAM-based filesystem");
     
-    // Default to TempFS as per original logic
-    let use_tempfs_resolved = true; 
+    // TempFS unless fs.ram_fs asks for FAT on a RamDisk, or always in
+    // safe mode
+    let use_tempfs_resolved = crate::safe_mode::is_active() || crate::config::get("fs.ram_fs")
+        .and_then(|value| value.try_as_string().cloned())
+        .map_or(true, |kind| kind != "fat");
     serial_println!("DEBUG: RAM-based FS config: Using TempFS: {}", use_tempfs_resolved);
     
     if use_tempfs_resolved {
         serial_println!("DEBUG: Creating TempFS in-memory filesystem");
-        let tempfs = tempfs::TempFs::new("root");
-        let fs = Arc::new(Mutex::new(tempfs));
+        let capacity = crate::config::get("fs.tempfs_capacity")
+            .and_then(|value| value.try_as_integer())
+            .filter(|bytes| *bytes > 0)
+            .map_or(tempfs::DEFAULT_CAPACITY, |bytes| bytes as u64);
+        let tempfs = tempfs::TempFs::with_capacity("root", capacity);
+        let fs = Arc::new(DiagMutex::new("fs:/", tempfs));
         
         // Mount the TempFS
         serial_println!("DEBUG: Getting VFS manager");
@@ -123,7 +236,7 @@ fn init_ram_fs() -> Result<(), KernelError> {
         serial_println!("DEBUG: Arc and Mutex created");
 
         serial_println!("DEBUG: Calling vfs.mount()");
-        if let Err(e) = vfs.mount("/", tempfs_mutex.clone()) {
+        if let Err(e) = vfs.mount("/", tempfs_mutex.clone(), MountFlags::NONE) {
             serial_println!("DEBUG: Failed to mount TempFS: {:?}", e);
             return Err(e);
         }
@@ -139,14 +252,13 @@ fn init_ram_fs() -> Result<(), KernelError> {
         
         serial_println!("DEBUG: RAM filesystem initialization complete");
     } else {
-        // This branch is currently unlikely to be hit due to use_tempfs_resolved logic
         serial_println!("DEBUG: Creating FAT RamDisk filesystem");
         use crate::fs::fat::FatFileSystem;
         use crate::fs::ramdisk::RamDisk;
         
         // Create a RamDisk
         serial_println!("DEBUG: Attempting to create RamDisk for FAT");
-        let ramdisk = match RamDisk::new() {
+        let mut ramdisk = match RamDisk::new() {
             Ok(disk) => {
                 serial_println!("DEBUG: RamDisk created successfully");
                 disk
@@ -160,13 +272,19 @@ fn init_ram_fs() -> Result<(), KernelError> {
         serial_println!("DEBUG: Created RAM disk with {} blocks of size {} bytes",
             ramdisk.block_count(), ramdisk.block_size());
         
-        // Create a FatFileSystem
-        let fatfs = FatFileSystem::new(Arc::new(Mutex::new(ramdisk)))?;
-        let fs = Arc::new(Mutex::new(fatfs));
+        // A new RamDisk is blank, so give it an empty FAT volume
+        fat::format(&mut ramdisk, &fat::FormatOptions::default())?;
+        
+        // Create a FatFileSystem, on a disk dd can reach by name
+        let name = ramdisk.name().to_string();
+        let device: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(ramdisk));
+        block_device::register(&name, &device);
+        let fatfs = FatFileSystem::new(device)?;
+        let fs = Arc::new(DiagMutex::new("fs:/", fatfs));
         
         // Mount the FatFileSystem
         let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
-        vfs.mount("/", fs.clone())?;
+        vfs.mount("/", fs.clone(), MountFlags::NONE)?;
         
         serial_println!("DEBUG: FAT filesystem mounted at /");
         
@@ -201,10 +319,10 @@ fn create_initial_files() -> Result<(), KernelError> {
 }
 
 /// Global file system instance (primary file system)
-static mut GLOBAL_FS: Option<Arc<Mutex<dyn vfs::FileSystem>>> = None;
+static mut GLOBAL_FS: Option<Arc<DiagMutex<dyn vfs::FileSystem>>> = None;
 
 /// Get a reference to the global file system, if initialized.
-pub fn get_fs() -> Option<Arc<Mutex<dyn vfs::FileSystem>>> {
+pub fn get_fs() -> Option<Arc<DiagMutex<dyn vfs::FileSystem>>> {
     unsafe { GLOBAL_FS.as_ref().map(|fs| fs.clone()) }
 }
 
@@ -323,18 +441,23 @@ pub fn direct_write_file(path: &str, data: &[u8]) -> Result<usize, KernelError>
     serial_println!("DEBUG: direct_write_file - Got VFS manager");
     
     // Find the filesystem that contains this path
-    let fs = vfs.find_fs(path)?;
+    let fs = vfs.writable_fs(path)?;
     serial_println!("DEBUG: direct_write_file - Found filesystem for path");
     
     // Lock the filesystem and write directly
-    let mut fs_guard = fs.lock();
-    serial_println!("DEBUG: direct_write_file - Acquired filesystem lock");
-    
-    // Write at position 0
-    let result = fs_guard.write_at(path, 0, data);
+    let result = {
+        let mut fs_guard = fs.lock();
+        serial_println!("DEBUG: direct_write_file - Acquired filesystem lock");
+        
+        // Write at position 0
+        fs_guard.write_at(path, 0, data)
+    };
     
     match &result {
-        Ok(bytes) => serial_println!("DEBUG: direct_write_file - Successfully wrote {} bytes", bytes),
+        Ok(bytes) => {
+            serial_println!("DEBUG: direct_write_file - Successfully wrote {} bytes", bytes);
+            watch::notify(path, watch::WatchKind::Modified);
+        },
         Err(e) => serial_println!("DEBUG: direct_write_file - Error: {:?}", e),
     }
     
@@ -0,0 +1,317 @@
+//! Typed file system paths
+//!
+//! `Path` is a borrowed path and `PathBuf` an owned one. Both are plain
+//! '/'-separated strings underneath, so they convert to and from `&str`
+//! for free, but their operations work on components instead of slicing
+//! the string at slashes.
+//!
+//! The rules, pinned down by `self_test`:
+//! - Empty components (`a//b`, a trailing slash) and `.` are skipped, so
+//!   `/a/./b/` has the components `a` and `b`; `..` is a component.
+//! - `/` has no components, no parent and no file name.
+//! - `parent` and `file_name` are lexical: the parent of `/a/..` is `/a`,
+//!   and a path ending in `..` has no file name.
+//! - `normalize` resolves `..` lexically. `..` at the root stays at the
+//!   root; in a relative path it is kept once nothing is left to drop, and
+//!   a relative path that cancels out becomes `.`.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Deref;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// A borrowed path
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct Path(str);
+
+/// An owned path
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PathBuf(String);
+
+impl Path {
+    pub fn new(path: &str) -> &Path {
+        // Safe because Path is a transparent wrapper around str
+        unsafe { &*(path as *const str as *const Path) }
+    }
+
+    /// The root directory
+    pub fn root() -> &'static Path {
+        Path::new("/")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn to_path_buf(&self) -> PathBuf {
+        PathBuf(self.0.to_string())
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.0.starts_with('/')
+    }
+
+    /// The names between slashes, without empty ones and `.`
+    pub fn components(&self) -> impl DoubleEndedIterator<Item = &str> + '_ {
+        self.0.split('/').filter(|component| !component.is_empty() && *component != ".")
+    }
+
+    /// The last component, unless the path has none or ends in `..`
+    pub fn file_name(&self) -> Option<&str> {
+        self.components().next_back().filter(|name| *name != "..")
+    }
+
+    /// The path without its last component, or None if it has none
+    pub fn parent(&self) -> Option<&Path> {
+        let mut rest = &self.0;
+        loop {
+            let trimmed = rest.trim_end_matches('/');
+            if trimmed.is_empty() {
+                return None;
+            }
+            let (head, last) = trimmed.split_at(trimmed.rfind('/').map_or(0, |slash| slash + 1));
+            if last == "." {
+                rest = head;
+                continue;
+            }
+            let head = head.trim_end_matches('/');
+            return Some(if head.is_empty() && self.is_absolute() { Path::root() } else { Path::new(head) });
+        }
+    }
+
+    /// `other` inside this path, or `other` itself if it is absolute
+    pub fn join<P: AsRef<Path> + ?Sized>(&self, other: &P) -> PathBuf {
+        let other = other.as_ref();
+        if other.is_absolute() || self.0.is_empty() {
+            other.to_path_buf()
+        } else if other.0.is_empty() {
+            self.to_path_buf()
+        } else if self.0.ends_with('/') {
+            PathBuf(format!("{}{}", &self.0, &other.0))
+        } else {
+            PathBuf(format!("{}/{}", &self.0, &other.0))
+        }
+    }
+
+    /// Whether `base`'s components start this path's, both being absolute
+    /// or both relative. Compare normalized paths to see through `..`.
+    pub fn starts_with<P: AsRef<Path> + ?Sized>(&self, base: &P) -> bool {
+        let base = base.as_ref();
+        if self.is_absolute() != base.is_absolute() {
+            return false;
+        }
+        let mut mine = self.components();
+        base.components().all(|component| mine.next() == Some(component))
+    }
+
+    /// The same path with `.`, `..` and empty components resolved away
+    pub fn normalize(&self) -> PathBuf {
+        let absolute = self.is_absolute();
+        let mut kept: Vec<&str> = Vec::new();
+        for component in self.components() {
+            match (component, kept.last().copied()) {
+                ("..", Some(last)) if last != ".." => {
+                    kept.pop();
+                }
+                // The root is its own parent
+                ("..", _) if absolute => {}
+                (name, _) => kept.push(name),
+            }
+        }
+        let joined = kept.join("/");
+        PathBuf(match (absolute, joined.is_empty()) {
+            (true, _) => format!("/{}", joined),
+            (false, true) => ".".to_string(),
+            (false, false) => joined,
+        })
+    }
+
+    /// The normalized absolute path, taking a relative one from the root
+    pub fn canonical(&self) -> PathBuf {
+        Path::root().join(self).normalize()
+    }
+}
+
+impl PathBuf {
+    pub fn new() -> Self {
+        PathBuf(String::new())
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(&self.0)
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    /// Extend the path as `join` would
+    pub fn push<P: AsRef<Path> + ?Sized>(&mut self, other: &P) {
+        *self = self.join(other);
+    }
+}
+
+impl Deref for PathBuf {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl From<String> for PathBuf {
+    fn from(path: String) -> Self {
+        PathBuf(path)
+    }
+}
+
+impl From<&str> for PathBuf {
+    fn from(path: &str) -> Self {
+        PathBuf(path.to_string())
+    }
+}
+
+impl From<PathBuf> for String {
+    fn from(path: PathBuf) -> Self {
+        path.0
+    }
+}
+
+impl AsRef<Path> for Path {
+    fn as_ref(&self) -> &Path {
+        self
+    }
+}
+
+impl AsRef<Path> for PathBuf {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<Path> for str {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for String {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<str> for Path {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for PathBuf {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for PathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Check every operation against tables covering the root, the empty
+/// path, trailing slashes, empty components, `.` and `..`
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("PATH: Running self-test");
+
+    // (path, components, parent, file name, normalized)
+    let cases: &[(&str, &[&str], Option<&str>, Option<&str>, &str)] = &[
+        ("/", &[], None, None, "/"),
+        ("", &[], None, None, "."),
+        ("//", &[], None, None, "/"),
+        (".", &[], None, None, "."),
+        ("/.", &[], None, None, "/"),
+        ("/a", &["a"], Some("/"), Some("a"), "/a"),
+        ("/a/", &["a"], Some("/"), Some("a"), "/a"),
+        ("//a", &["a"], Some("/"), Some("a"), "/a"),
+        ("/a//b", &["a", "b"], Some("/a"), Some("b"), "/a/b"),
+        ("/a/b//", &["a", "b"], Some("/a"), Some("b"), "/a/b"),
+        ("/a/./b/.", &["a", "b"], Some("/a/."), Some("b"), "/a/b"),
+        ("/..", &[".."], Some("/"), None, "/"),
+        ("/a/../b", &["a", "..", "b"], Some("/a/.."), Some("b"), "/b"),
+        ("/a/b/../../..", &["a", "b", "..", "..", ".."], Some("/a/b/../.."), None, "/"),
+        ("a", &["a"], Some(""), Some("a"), "a"),
+        ("a/b/", &["a", "b"], Some("a"), Some("b"), "a/b"),
+        ("./a", &["a"], Some("."), Some("a"), "a"),
+        ("a/..", &["a", ".."], Some("a"), None, "."),
+        ("../a", &["..", "a"], Some(".."), Some("a"), "../a"),
+        ("a/../../b", &["a", "..", "..", "b"], Some("a/../.."), Some("b"), "../b"),
+    ];
+    for &(path, components, parent, file_name, normalized) in cases {
+        let path = Path::new(path);
+        let ok = path.components().eq(components.iter().copied())
+            && path.parent().map(Path::as_str) == parent
+            && path.file_name() == file_name
+            && path.normalize().as_str() == normalized;
+        if !ok {
+            serial_println!("PATH: {:?} gave components {:?}, parent {:?}, file name {:?}, normalized {:?}",
+                path.as_str(), path.components().collect::<Vec<_>>(), path.parent(), path.file_name(),
+                path.normalize().as_str());
+            return Err(KernelError::ValidationError("Path split wrongly"));
+        }
+    }
+
+    // (base, joined, result)
+    let joins = [
+        ("/", "a", "/a"),
+        ("/a", "b", "/a/b"),
+        ("/a/", "b", "/a/b"),
+        ("/a", "/b", "/b"),
+        ("/a", "", "/a"),
+        ("", "a", "a"),
+        ("a", "../b", "a/../b"),
+    ];
+    for (base, other, expected) in joins {
+        if Path::new(base).join(other).as_str() != expected {
+            serial_println!("PATH: {:?} joined with {:?} gave {:?}", base, other, Path::new(base).join(other));
+            return Err(KernelError::ValidationError("Paths joined wrongly"));
+        }
+    }
+
+    let canonical = [("", "/"), ("a/b/", "/a/b"), ("../x", "/x"), ("/a/./b/../c", "/a/c")];
+    for (path, expected) in canonical {
+        if Path::new(path).canonical().as_str() != expected {
+            return Err(KernelError::ValidationError("Path made canonical wrongly"));
+        }
+    }
+
+    // (path, base, expected)
+    let prefixes = [
+        ("/tmp/x", "/tmp", true),
+        ("/tmp", "/tmp/", true),
+        ("/tmpfoo", "/tmp", false),
+        ("/a", "/", true),
+        ("/", "/a", false),
+        ("a/b", "a", true),
+        ("a", "/", false),
+    ];
+    for (path, base, expected) in prefixes {
+        if Path::new(path).starts_with(base) != expected {
+            serial_println!("PATH: {:?} starts with {:?} should be {}", path, base, expected);
+            return Err(KernelError::ValidationError("Path prefix matched wrongly"));
+        }
+    }
+
+    serial_println!("PATH: Self-test passed");
+    Ok(())
+}
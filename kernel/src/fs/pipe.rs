@@ -0,0 +1,326 @@
+// kernel/src/fs/pipe.rs
+//! Pipes: a fixed-size ring buffer with separate read and write ends.
+//! Reads block while the pipe is empty and return 0 (EOF) once every writer
+//! has closed; writes block while it is full and fail with BrokenPipe once
+//! every reader has closed.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use spin::Mutex;
+use crate::sync::DiagMutex;
+use lazy_static::lazy_static;
+use crate::errors::KernelError;
+use crate::serial_println;
+use crate::task::wait_queue::WaitQueue;
+use super::vfs::{file_flags, DirEntry, FileHandle, FileSystem, Metadata};
+
+/// Bytes a pipe can hold before writers block
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+struct Pipe {
+    state: Mutex<PipeState>,
+    // Readers waiting for data or for the last writer to close
+    readable: WaitQueue,
+    // Writers waiting for space or for the last reader to close
+    writable: WaitQueue,
+}
+
+/// Which end of a pipe a handle refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeEndKind {
+    Read,
+    Write,
+}
+
+/// One end of a pipe; closing the last end of a kind wakes the other side
+pub struct PipeEnd {
+    io: PipeIo,
+    closed: bool,
+}
+
+/// Uncounted reference to one end of a pipe, used to block on the pipe
+/// without holding other locks (such as the FD table's)
+#[derive(Clone)]
+pub struct PipeIo {
+    pipe: Arc<Pipe>,
+    kind: PipeEndKind,
+}
+
+/// Create a pipe, returning its (read, write) ends
+pub fn create() -> (PipeEnd, PipeEnd) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+
+    let reader = PipeEnd { io: PipeIo { pipe: pipe.clone(), kind: PipeEndKind::Read }, closed: false };
+    let writer = PipeEnd { io: PipeIo { pipe, kind: PipeEndKind::Write }, closed: false };
+    (reader, writer)
+}
+
+impl PipeIo {
+    /// Read up to `buffer.len()` bytes, blocking until data arrives.
+    /// Returns 0 at end of file (pipe empty and no writers left).
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        if self.kind != PipeEndKind::Read {
+            return Err(KernelError::InvalidOperation);
+        }
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let pipe = &self.pipe;
+        pipe.readable.wait_until(|| {
+            let state = pipe.state.lock();
+            !state.buffer.is_empty() || state.writers == 0
+        });
+
+        let count = {
+            let mut state = pipe.state.lock();
+            let count = buffer.len().min(state.buffer.len());
+            for (slot, byte) in buffer.iter_mut().zip(state.buffer.drain(..count)) {
+                *slot = byte;
+            }
+            count
+        };
+
+        if count > 0 {
+            pipe.writable.wake_all();
+        }
+        Ok(count)
+    }
+
+    /// Write all of `buffer`, blocking while the pipe is full.
+    /// Fails with BrokenPipe if no readers remain before anything was written.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, KernelError> {
+        if self.kind != PipeEndKind::Write {
+            return Err(KernelError::InvalidOperation);
+        }
+
+        let pipe = &self.pipe;
+        let mut written = 0;
+        while written < buffer.len() {
+            pipe.writable.wait_until(|| {
+                let state = pipe.state.lock();
+                state.buffer.len() < PIPE_CAPACITY || state.readers == 0
+            });
+
+            {
+                let mut state = pipe.state.lock();
+                if state.readers == 0 {
+                    return if written > 0 { Ok(written) } else { Err(KernelError::BrokenPipe) };
+                }
+                let count = (PIPE_CAPACITY - state.buffer.len()).min(buffer.len() - written);
+                state.buffer.extend(&buffer[written..written + count]);
+                written += count;
+            }
+            pipe.readable.wake_all();
+        }
+        Ok(written)
+    }
+}
+
+impl PipeEnd {
+    pub fn kind(&self) -> PipeEndKind {
+        self.io.kind
+    }
+
+    /// Bytes currently buffered
+    pub fn len(&self) -> usize {
+        self.io.pipe.state.lock().buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Handle for blocking I/O that doesn't keep the end open
+    pub fn io(&self) -> Result<PipeIo, KernelError> {
+        if self.closed {
+            return Err(KernelError::InvalidOperation);
+        }
+        Ok(self.io.clone())
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        self.io()?.read(buffer)
+    }
+
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, KernelError> {
+        self.io()?.write(buffer)
+    }
+
+    /// Close this end; safe to call more than once
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+
+        let pipe = &self.io.pipe;
+        {
+            let mut state = pipe.state.lock();
+            match self.io.kind {
+                PipeEndKind::Read => state.readers -= 1,
+                PipeEndKind::Write => state.writers -= 1,
+            }
+        }
+
+        // Let the other side notice EOF or the broken pipe
+        match self.io.kind {
+            PipeEndKind::Read => pipe.writable.wake_all(),
+            PipeEndKind::Write => pipe.readable.wake_all(),
+        }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Placeholder filesystem that pipe file handles belong to. Pipes have no
+/// names, so every path operation fails.
+pub struct PipeFs;
+
+impl FileSystem for PipeFs {
+    fn mount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn unmount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn create_file(&mut self, _path: &str) -> Result<(), KernelError> {
+        Err(KernelError::InvalidOperation)
+    }
+
+    fn create_directory(&mut self, _path: &str) -> Result<(), KernelError> {
+        Err(KernelError::InvalidOperation)
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), KernelError> {
+        Err(KernelError::InvalidOperation)
+    }
+
+    fn open(&mut self, _path: &str, _write: bool) -> Result<Option<usize>, KernelError> {
+        Err(KernelError::InvalidOperation)
+    }
+
+    fn metadata(&self, _path: &str) -> Result<Metadata, KernelError> {
+        Err(KernelError::NotFound)
+    }
+
+    fn read_dir_from(&self, _path: &str, _cursor: usize, _out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError> {
+        Err(KernelError::NotADirectory)
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), KernelError> {
+        Err(KernelError::InvalidOperation)
+    }
+
+    fn name(&self) -> &str {
+        "pipefs"
+    }
+
+    fn total_space(&self) -> u64 {
+        0
+    }
+
+    fn available_space(&self) -> u64 {
+        0
+    }
+}
+
+lazy_static! {
+    static ref PIPE_FS: Arc<DiagMutex<dyn FileSystem>> = Arc::new(DiagMutex::new("fs:pipe", PipeFs));
+}
+
+/// Create a pipe wrapped in (read, write) file handles
+pub fn create_handles() -> (FileHandle, FileHandle) {
+    let (reader, writer) = create();
+    (
+        FileHandle::for_pipe(reader, PIPE_FS.clone(), file_flags::READ),
+        FileHandle::for_pipe(writer, PIPE_FS.clone(), file_flags::WRITE),
+    )
+}
+
+/// Move 1 MiB from a producer to a consumer and check totals, EOF and
+/// broken-pipe handling. The scheduler doesn't preempt yet, so the two
+/// sides take turns from one context instead of running as separate tasks.
+pub fn self_test() -> Result<(), KernelError> {
+    const TOTAL: usize = 1024 * 1024;
+    const CHUNK: usize = 1500;
+
+    serial_println!("PIPE: Running self-test");
+    let (mut reader, mut writer) = create();
+
+    let mut produced = 0usize;
+    let mut consumed = 0usize;
+    let mut checksum_out = 0u64;
+    let mut checksum_in = 0u64;
+    let mut chunk = [0u8; CHUNK];
+    let mut received = [0u8; PIPE_CAPACITY];
+
+    while consumed < TOTAL {
+        // Producer: fill as much as fits without blocking
+        while produced < TOTAL && writer.len() + CHUNK <= PIPE_CAPACITY {
+            let count = CHUNK.min(TOTAL - produced);
+            for (i, byte) in chunk[..count].iter_mut().enumerate() {
+                *byte = ((produced + i) % 251) as u8;
+                checksum_out = checksum_out.wrapping_add(*byte as u64);
+            }
+            produced += writer.write(&chunk[..count])?;
+        }
+        if produced == TOTAL {
+            writer.close();
+        }
+
+        // Consumer: drain what's there
+        let count = reader.read(&mut received)?;
+        if count == 0 {
+            break;
+        }
+        for (i, byte) in received[..count].iter().enumerate() {
+            if *byte != ((consumed + i) % 251) as u8 {
+                serial_println!("PIPE: Data mismatch at byte {}", consumed + i);
+                return Err(KernelError::ValidationError("Pipe data corrupted"));
+            }
+            checksum_in = checksum_in.wrapping_add(*byte as u64);
+        }
+        consumed += count;
+    }
+
+    if consumed != TOTAL || checksum_in != checksum_out {
+        serial_println!("PIPE: Moved {} of {} bytes", consumed, TOTAL);
+        return Err(KernelError::ValidationError("Pipe lost data"));
+    }
+    if reader.read(&mut received)? != 0 {
+        return Err(KernelError::ValidationError("Pipe missing EOF after writer closed"));
+    }
+
+    // Writing with no readers left must fail
+    let (reader, mut writer) = create();
+    drop(reader);
+    match writer.write(b"x") {
+        Err(KernelError::BrokenPipe) => {}
+        _ => return Err(KernelError::ValidationError("Write to closed pipe did not fail")),
+    }
+
+    serial_println!("PIPE: Self-test passed ({} bytes)", consumed);
+    Ok(())
+}
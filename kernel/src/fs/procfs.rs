@@ -0,0 +1,166 @@
+//! Read-only file system of generated files, mounted at /proc
+//!
+//! Each file is a name and a function producing its text, called afresh
+//! whenever the file is read or its size asked for. Subsystems add their
+//! files with `register`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::fs::vfs::{permissions, DirEntry, FileSystem, Metadata, MetadataUpdate, NodeType};
+
+/// Produces a file's current text
+pub type Generator = fn() -> String;
+
+lazy_static! {
+    static ref FILES: Mutex<Vec<(&'static str, Generator)>> = Mutex::new(Vec::new());
+}
+
+/// Add /proc/<name>. Fails with AlreadyExists if the name is taken.
+pub fn register(name: &'static str, generator: Generator) -> Result<(), KernelError> {
+    let mut files = FILES.lock();
+    if files.iter().any(|(existing, _)| *existing == name) {
+        return Err(KernelError::AlreadyExists);
+    }
+    files.push((name, generator));
+    Ok(())
+}
+
+fn generator(name: &str) -> Option<Generator> {
+    FILES.lock().iter().find(|(existing, _)| *existing == name).map(|(_, generator)| *generator)
+}
+
+pub struct ProcFs {
+    /// Where it's mounted; the VFS passes whole paths
+    prefix: String,
+}
+
+impl ProcFs {
+    pub fn new(prefix: &str) -> Self {
+        Self { prefix: String::from(prefix.trim_end_matches('/')) }
+    }
+
+    /// The file name `path` refers to, or None for the directory itself
+    fn file_name<'a>(&self, path: &'a str) -> Result<Option<&'a str>, KernelError> {
+        let rest = path.strip_prefix(self.prefix.as_str()).ok_or(KernelError::NotFound)?;
+        let name = rest.trim_matches('/');
+        if name.is_empty() {
+            return Ok(None);
+        }
+        if name.contains('/') {
+            return Err(KernelError::NotFound);
+        }
+        Ok(Some(name))
+    }
+
+    fn text(&self, path: &str) -> Result<String, KernelError> {
+        let name = self.file_name(path)?.ok_or(KernelError::IsADirectory)?;
+        let generator = generator(name).ok_or(KernelError::NotFound)?;
+        Ok(generator())
+    }
+
+    fn refuse_write() -> Result<(), KernelError> {
+        Err(KernelError::UnsupportedFeature)
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn mount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn unmount(&mut self) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    fn create_file(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn create_directory(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn open(&mut self, path: &str, write: bool) -> Result<Option<usize>, KernelError> {
+        if write {
+            return Err(KernelError::UnsupportedFeature);
+        }
+        let name = self.file_name(path)?.ok_or(KernelError::NotAFile)?;
+        generator(name).ok_or(KernelError::NotFound)?;
+        Ok(None)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
+        if self.file_name(path)?.is_none() {
+            let mut metadata = Metadata::new_directory();
+            metadata.permissions = permissions::ALL & !permissions::ALL_WRITE;
+            return Ok(metadata);
+        }
+        let mut metadata = Metadata::new_file();
+        metadata.size = self.text(path)?.len() as u64;
+        metadata.permissions = permissions::READ | permissions::GROUP_READ | permissions::OTHERS_READ;
+        Ok(metadata)
+    }
+
+    fn set_metadata(&mut self, _path: &str, _update: MetadataUpdate) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn read_dir_from(&self, path: &str, cursor: usize, out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError> {
+        if self.file_name(path)?.is_some() {
+            return Err(KernelError::NotADirectory);
+        }
+        // Files are only ever added, at the end, so an index stays valid
+        let files = FILES.lock();
+        let remaining = files.len().saturating_sub(cursor);
+        let filled = remaining.min(out.len());
+        for (slot, (index, (name, _))) in files.iter().enumerate().skip(cursor).take(filled).enumerate() {
+            out[slot] = Some(DirEntry::new(name, NodeType::File, index + 1));
+        }
+        let next = if cursor + filled < files.len() { Some(cursor + filled) } else { None };
+        Ok((filled, next))
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+
+    fn name(&self) -> &str {
+        "procfs"
+    }
+
+    fn total_space(&self) -> u64 {
+        0
+    }
+
+    fn available_space(&self) -> u64 {
+        0
+    }
+
+    fn read_at(&self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        let text = self.text(path)?;
+        let bytes = text.as_bytes();
+        if offset >= bytes.len() as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let count = buffer.len().min(bytes.len() - start);
+        buffer[..count].copy_from_slice(&bytes[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&mut self, _path: &str, _offset: u64, _buffer: &[u8]) -> Result<usize, KernelError> {
+        Err(KernelError::UnsupportedFeature)
+    }
+
+    fn truncate(&mut self, _path: &str, _length: u64) -> Result<(), KernelError> {
+        Self::refuse_write()
+    }
+}
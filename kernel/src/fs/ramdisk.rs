@@ -1,77 +1,158 @@
+//! RAM-backed block device
+//! Blocks are allocated on first write and kept in a BTreeMap, so a large,
+//! mostly empty disk costs only what has been written. Unwritten blocks
+//! read as zeros, or as the matching part of the disk image the RamDisk was
+//! created from.
+
 use super::block_device::{BlockDevice, DEFAULT_BLOCK_SIZE};
-use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::device::{self, iostats::{self, IoLayer, IoStats}};
+use crate::errors::KernelError;
 use crate::serial_println;
 
-// Further reduce the size to minimize memory usage
-const RAMDISK_SIZE_BYTES: usize = 2 * 1024; // 2 KiB RamDisk (even smaller)
-const BLOCKS_PER_RAMDISK: usize = RAMDISK_SIZE_BYTES / DEFAULT_BLOCK_SIZE;
+/// Size of the default RamDisk when `fs.ramdisk_size_kb` isn't set
+pub const DEFAULT_SIZE_KB: u64 = 4096;
+
+/// Number of the next RamDisk, for its name in the I/O statistics
+static NEXT_RAMDISK: AtomicUsize = AtomicUsize::new(0);
+
+/// Where unwritten blocks come from
+enum Backing {
+    Zeros,
+    /// An image built into the kernel, read in place
+    Image(&'static [u8]),
+}
 
 pub struct RamDisk {
-    data: Vec<u8>, // Change to Vec<u8> instead of Box<[u8]> for simpler handling
+    /// Blocks that have been written, by block number
+    blocks: BTreeMap<u64, Box<[u8]>>,
+    backing: Backing,
     block_size: usize,
+    block_count: u64,
+    read_only: bool,
+    id: u64,
+    /// ram0, ram1, ..., in the I/O statistics and for `dd`
+    name: String,
+    stats: Arc<IoStats>,
 }
 
 impl RamDisk {
-    /// Creates a new RamDisk with a default size and block size.
+    /// Creates a RamDisk of `fs.ramdisk_size_kb` kilobytes in 512-byte blocks.
     pub fn new() -> Result<Self, &'static str> {
-        serial_println!("DEBUG: Creating RamDisk with size {} bytes ({} blocks of {} bytes each)", 
-            RAMDISK_SIZE_BYTES, BLOCKS_PER_RAMDISK, DEFAULT_BLOCK_SIZE);
-        Self::with_size(RAMDISK_SIZE_BYTES, DEFAULT_BLOCK_SIZE)
+        let size_kb = crate::config::get("fs.ramdisk_size_kb")
+            .and_then(|value| value.try_as_integer())
+            .filter(|kb| *kb > 0)
+            .map_or(DEFAULT_SIZE_KB, |kb| kb as u64);
+        serial_println!("DEBUG: Creating RamDisk of {} KiB", size_kb);
+        Self::with_capacity(size_kb * 1024 / DEFAULT_BLOCK_SIZE as u64, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Creates an empty RamDisk of `blocks` blocks. Nothing is allocated
+    /// until blocks are written.
+    pub fn with_capacity(blocks: u64, block_size: usize) -> Result<Self, &'static str> {
+        if blocks == 0 || block_size == 0 {
+            return Err("Block count and block size must be non-zero.");
+        }
+        let id = device::generate_device_id();
+        let name = format!("ram{}", NEXT_RAMDISK.fetch_add(1, Ordering::Relaxed));
+        let stats = iostats::register(id, &name, IoLayer::Device, block_size);
+        Ok(RamDisk {
+            blocks: BTreeMap::new(),
+            backing: Backing::Zeros,
+            block_size,
+            block_count: blocks,
+            read_only: false,
+            id,
+            name,
+            stats,
+        })
     }
 
     /// Creates a new RamDisk with a specified total size and block size.
     pub fn with_size(total_size_bytes: usize, block_size: usize) -> Result<Self, &'static str> {
-        serial_println!("DEBUG: RamDisk::with_size called with {} bytes", total_size_bytes);
-        
-        if total_size_bytes == 0 || block_size == 0 {
-            serial_println!("DEBUG: RamDisk error: sizes must be non-zero");
-            return Err("Total size and block size must be non-zero.");
-        }
-        if total_size_bytes % block_size != 0 {
-            serial_println!("DEBUG: RamDisk error: total size must be multiple of block size");
+        if block_size == 0 || total_size_bytes % block_size != 0 {
             return Err("Total size must be a multiple of block size.");
         }
-        
-        // Use simpler allocation approach
-        serial_println!("DEBUG: Allocating RamDisk memory ({} bytes)", total_size_bytes);
-        
-        // Create an empty Vec with enough capacity for the first few blocks
-        let initial_capacity = core::cmp::min(total_size_bytes, 1024);
-        serial_println!("DEBUG: Creating vector with initial capacity of {} bytes", initial_capacity);
-        
-        // Create the Vec with initial capacity
-        let mut data_vec = Vec::with_capacity(initial_capacity);
-        serial_println!("DEBUG: Initial Vec created successfully");
-        
-        // Grow the Vec in smaller chunks to avoid large allocations
-        let chunk_size = block_size;
-        let num_chunks = total_size_bytes / chunk_size;
-        
-        serial_println!("DEBUG: Growing Vec in {} chunks of {} bytes each", num_chunks, chunk_size);
-        
-        for i in 0..num_chunks {
-            if i < 3 || i == num_chunks - 1 {
-                serial_println!("DEBUG: Adding chunk {}/{}", i+1, num_chunks);
-            }
-            
-            let current_size = data_vec.len();
-            // Add a single block worth of zeros
-            for _ in 0..chunk_size {
-                data_vec.push(0);
-            }
-            
-            if i < 3 || i == num_chunks - 1 {
-                serial_println!("DEBUG: Vec size now {} bytes", data_vec.len());
-            }
+        Self::with_capacity((total_size_bytes / block_size) as u64, block_size)
+    }
+
+    /// Creates a RamDisk holding a copy of `image`, in 512-byte blocks. A
+    /// partial last block is padded with zeros; all-zero blocks aren't stored.
+    pub fn from_image(image: &[u8]) -> Result<Self, &'static str> {
+        let block_count = ((image.len() + DEFAULT_BLOCK_SIZE - 1) / DEFAULT_BLOCK_SIZE) as u64;
+        let mut disk = Self::with_capacity(block_count, DEFAULT_BLOCK_SIZE)?;
+        for (index, chunk) in image.chunks(DEFAULT_BLOCK_SIZE).enumerate() {
+            let mut block = vec![0u8; DEFAULT_BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            disk.store_block(index as u64, &block)?;
         }
-        
-        serial_println!("DEBUG: Final Vec size: {} bytes", data_vec.len());
-        serial_println!("DEBUG: RamDisk allocation completed successfully");
+        Ok(disk)
+    }
 
-        Ok(RamDisk {
-            data: data_vec, // Keep as Vec instead of converting to Box<[u8]>
-            block_size,
-        })
+    /// Creates a RamDisk over an image built into the kernel. Blocks are read
+    /// from the image until written, so nothing is copied up front.
+    pub fn from_static_image(image: &'static [u8]) -> Result<Self, &'static str> {
+        let block_count = ((image.len() + DEFAULT_BLOCK_SIZE - 1) / DEFAULT_BLOCK_SIZE) as u64;
+        let mut disk = Self::with_capacity(block_count, DEFAULT_BLOCK_SIZE)?;
+        disk.backing = Backing::Image(image);
+        Ok(disk)
+    }
+
+    /// Refuse writes from now on, or allow them again
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// ID of this disk's I/O counters (see `device::io_stats`)
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The disk's name, such as ram0
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Blocks currently holding their own memory
+    pub fn allocated_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn check_request(&self, block_id: u64, buffer_len: usize) -> Result<(), &'static str> {
+        if buffer_len != self.block_size {
+            return Err("Buffer length does not match block size.");
+        }
+        if block_id >= self.block_count {
+            return Err("Block ID out of bounds.");
+        }
+        Ok(())
+    }
+
+    /// The write itself, counted by `write_block`
+    fn store_block(&mut self, block_id: u64, buffer: &[u8]) -> Result<(), &'static str> {
+        self.check_request(block_id, buffer.len())?;
+        if self.read_only {
+            return Err(KernelError::ReadOnlyFilesystem.to_str());
+        }
+
+        // Zeros over a zero background give the block's memory back
+        if matches!(self.backing, Backing::Zeros) && buffer.iter().all(|&byte| byte == 0) {
+            self.blocks.remove(&block_id);
+            return Ok(());
+        }
+        match self.blocks.get_mut(&block_id) {
+            Some(block) => block.copy_from_slice(buffer),
+            None => {
+                self.blocks.insert(block_id, buffer.into());
+            }
+        }
+        Ok(())
     }
 }
 
@@ -81,44 +162,100 @@ impl BlockDevice for RamDisk {
     }
 
     fn block_count(&self) -> u64 {
-        (self.data.len() / self.block_size) as u64
+        self.block_count
     }
 
     fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
-        serial_println!("DEBUG: RamDisk: Reading block {}", block_id);
-        
-        if buffer.len() != self.block_size {
-            return Err("Buffer length does not match block size.");
-        }
-        let num_blocks = self.block_count();
-        if block_id >= num_blocks {
-            return Err("Block ID out of bounds.");
-        }
+        self.stats.read(1, || {
+            self.check_request(block_id, buffer.len())?;
 
-        let start = (block_id as usize) * self.block_size;
-        let end = start + self.block_size;
-        
-        buffer.copy_from_slice(&self.data[start..end]);
-        Ok(())
+            if let Some(block) = self.blocks.get(&block_id) {
+                buffer.copy_from_slice(block);
+                return Ok(());
+            }
+            buffer.fill(0);
+            if let Backing::Image(image) = self.backing {
+                let start = (block_id as usize * self.block_size).min(image.len());
+                let end = (start + self.block_size).min(image.len());
+                buffer[..end - start].copy_from_slice(&image[start..end]);
+            }
+            Ok(())
+        })
     }
 
     fn write_block(&mut self, block_id: u64, buffer: &[u8]) -> Result<(), &'static str> {
-        serial_println!("DEBUG: RamDisk: Writing block {}", block_id);
-        
-        if buffer.len() != self.block_size {
-            return Err("Buffer length does not match block size.");
-        }
-        let num_blocks = self.block_count();
-        if block_id >= num_blocks {
-            return Err("Block ID out of bounds.");
-        }
+        let stats = self.stats.clone();
+        stats.write(1, || self.store_block(block_id, buffer))
+    }
 
-        let start = (block_id as usize) * self.block_size;
-        let end = start + self.block_size;
-        
-        self.data[start..end].copy_from_slice(buffer);
-        Ok(())
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn device_id(&self) -> Option<u64> {
+        Some(self.id)
     }
 }
 
-impl crate::fs::block_device::BlockDeviceMarker for RamDisk {} 
\ No newline at end of file
+impl crate::fs::block_device::BlockDeviceMarker for RamDisk {}
+
+/// Check sparse allocation, both kinds of image, a FAT16 volume made on a
+/// RamDisk mounting cleanly, and FAT refusing writes once the disk is
+/// read-only
+pub fn self_test() -> Result<(), KernelError> {
+    use spin::Mutex;
+    use crate::fs::fat::{self, FatFileSystem};
+    use crate::fs::vfs::FileSystem;
+
+    serial_println!("RAMDISK: Running self-test");
+
+    // 64 MiB costs nothing until written
+    let mut disk = RamDisk::with_capacity(64 * 1024 * 2, DEFAULT_BLOCK_SIZE)?;
+    let mut block = vec![0xA5u8; DEFAULT_BLOCK_SIZE];
+    disk.write_block(100_000, &block)?;
+    if disk.allocated_blocks() != 1 {
+        return Err(KernelError::ValidationError("RamDisk allocated blocks it was not given"));
+    }
+    disk.read_block(5, &mut block)?;
+    if block.iter().any(|&byte| byte != 0) {
+        return Err(KernelError::ValidationError("Unwritten RamDisk block not zero"));
+    }
+    disk.write_block(100_000, &block)?;
+    if disk.allocated_blocks() != 0 || disk.read_block(64 * 1024 * 2, &mut block).is_ok() {
+        return Err(KernelError::ValidationError("RamDisk kept a zeroed block or read past its end"));
+    }
+
+    static IMAGE: [u8; 700] = [7; 700];
+    let copied = RamDisk::from_image(&IMAGE)?;
+    let referenced = RamDisk::from_static_image(&IMAGE)?;
+    for disk in [&copied, &referenced] {
+        disk.read_block(1, &mut block)?;
+        if disk.block_count() != 2 || block[187] != 7 || block[188] != 0 {
+            return Err(KernelError::ValidationError("RamDisk image read back wrongly"));
+        }
+    }
+    if referenced.allocated_blocks() != 0 {
+        return Err(KernelError::ValidationError("Static image was copied"));
+    }
+
+    let mut disk = RamDisk::with_capacity(4096 * 2, DEFAULT_BLOCK_SIZE)?;
+    fat::format(&mut disk, &fat::FormatOptions { fat_type: Some(fat::FatType::Fat16), label: None })?;
+    let allocated = disk.allocated_blocks();
+    let mut fs = FatFileSystem::new(Arc::new(Mutex::new(disk)))?;
+    fs.mount()?;
+    if !fs.read_dir("/")?.is_empty() || allocated > 4 {
+        serial_println!("RAMDISK: {} blocks allocated by format", allocated);
+        return Err(KernelError::ValidationError("Freshly formatted FAT16 RamDisk looks wrong"));
+    }
+
+    let mut disk = RamDisk::with_capacity(4096 * 2, DEFAULT_BLOCK_SIZE)?;
+    fat::format(&mut disk, &fat::FormatOptions::default())?;
+    disk.set_read_only(true);
+    let mut fs = FatFileSystem::new(Arc::new(Mutex::new(disk)))?;
+    if !matches!(fs.create_file("/FILE"), Err(KernelError::ReadOnlyFilesystem)) || fs.read_dir("/").is_err() {
+        return Err(KernelError::ValidationError("FAT on a read-only RamDisk allowed a write"));
+    }
+
+    serial_println!("RAMDISK: Self-test passed");
+    Ok(())
+}
@@ -1,224 +1,1201 @@
 // kernel/src/fs/simple_fs.rs
-//! A very simple file system implementation for our OS.
-//! This is purposely kept minimal - a real file system would be more complex.
+//! SimpleFileSystem (SFS), UniverseK's own on-disk format.
+//! A volume is a superblock, an inode bitmap, a data block bitmap, the
+//! inode table and then data blocks. Files reach their blocks through
+//! twelve direct pointers, one indirect and one double indirect block;
+//! directories are files of fixed-size entries naming inodes. Unlike FAT
+//! it keeps permissions, owners, timestamps, link counts and names of up to 58
+//! bytes. The superblock is marked dirty while the volume is mounted, so
+//! one that was never unmounted is noticed the next time it is opened.
 
-use alloc::string::{String, ToString};
-use alloc::vec::Vec;
-use alloc::collections::BTreeMap;
-use alloc::sync::Arc;
 use alloc::format;
-use core::fmt;
-use super::block_device::BlockDevice;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use spin::Mutex;
+use super::block_device::BlockDevice;
+use super::vfs::{CheckReport, DirEntry, FileSystem, Metadata, MetadataUpdate, NodeType};
+use super::walk;
+use crate::errors::KernelError;
+use crate::serial_println;
 
-/// Represents a file entry in our simple file system
-#[derive(Debug, Clone)]
-pub struct FileEntry {
-    name: String,
-    size: usize,
-    content: Vec<u8>,
-    is_directory: bool,
-    // A real file system would have timestamps, permissions, etc.
+/// First bytes of the superblock: "SFS1"
+const MAGIC: u32 = 0x3153_4653;
+const VERSION: u32 = 1;
+/// SFS only runs on 512-byte blocks
+pub const BLOCK_SIZE: usize = 512;
+/// Smallest device `format` accepts, in blocks
+const MIN_BLOCKS: u64 = 64;
+/// Device blocks per inode when sizing the inode table
+const BLOCKS_PER_INODE: u64 = 8;
+
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+const BITS_PER_BLOCK: u32 = (BLOCK_SIZE * 8) as u32;
+const DIRECT_BLOCKS: u64 = 12;
+const POINTERS_PER_BLOCK: u64 = (BLOCK_SIZE / 4) as u64;
+/// Inode 0 is never used, so a 0 in a directory entry means a free slot
+const ROOT_INODE: u32 = 1;
+
+const DIR_ENTRY_SIZE: usize = 64;
+/// Longest name a directory entry holds, in bytes
+pub const MAX_NAME_LEN: usize = DIR_ENTRY_SIZE - 6;
+
+// Superblock states
+const STATE_CLEAN: u32 = 1;
+const STATE_DIRTY: u32 = 2;
+
+// Inode kinds
+const KIND_FREE: u8 = 0;
+const KIND_FILE: u8 = 1;
+const KIND_DIRECTORY: u8 = 2;
+
+fn get_u16(buffer: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buffer[at], buffer[at + 1]])
+}
+
+fn get_u32(buffer: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buffer[at..at + 4].try_into().unwrap())
+}
+
+fn get_u64(buffer: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buffer[at..at + 8].try_into().unwrap())
 }
 
-impl FileEntry {
-    pub fn new_file(name: &str) -> Self {
-        FileEntry {
-            name: name.to_string(),
-            size: 0,
-            content: Vec::new(),
-            is_directory: false,
+fn put_u16(buffer: &mut [u8], at: usize, value: u16) {
+    buffer[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut [u8], at: usize, value: u32) {
+    buffer[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut [u8], at: usize, value: u64) {
+    buffer[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Wall-clock time for timestamps
+fn now() -> u64 {
+    crate::time::wall_clock()
+}
+
+/// Block 0: where everything else is and whether the volume is mounted
+#[derive(Debug, Clone, Copy)]
+struct Superblock {
+    block_count: u32,
+    inode_count: u32,
+    inode_bitmap_start: u32,
+    block_bitmap_start: u32,
+    inode_table_start: u32,
+    data_start: u32,
+    state: u32,
+    mount_count: u32,
+}
+
+impl Superblock {
+    /// Where everything goes on a volume of `block_count` blocks
+    fn layout(block_count: u32) -> Self {
+        let inode_count = ((block_count as u64 / BLOCKS_PER_INODE).max(16) as u32).next_multiple_of(INODES_PER_BLOCK);
+        let inode_bitmap_start = 1;
+        let block_bitmap_start = inode_bitmap_start + inode_count.div_ceil(BITS_PER_BLOCK);
+        let inode_table_start = block_bitmap_start + block_count.div_ceil(BITS_PER_BLOCK);
+        let data_start = inode_table_start + inode_count / INODES_PER_BLOCK;
+        Self {
+            block_count,
+            inode_count,
+            inode_bitmap_start,
+            block_bitmap_start,
+            inode_table_start,
+            data_start,
+            state: STATE_CLEAN,
+            mount_count: 0,
         }
     }
-    
-    pub fn new_directory(name: &str) -> Self {
-        FileEntry {
-            name: name.to_string(),
-            size: 0,
-            content: Vec::new(),
-            is_directory: true,
+
+    fn decode(block: &[u8]) -> Result<Self, KernelError> {
+        if get_u32(block, 0) != MAGIC {
+            return Err(KernelError::InvalidData);
         }
+        if get_u32(block, 4) != VERSION || get_u32(block, 8) != BLOCK_SIZE as u32 {
+            return Err(KernelError::UnsupportedFeature);
+        }
+        let superblock = Self {
+            block_count: get_u32(block, 12),
+            inode_count: get_u32(block, 16),
+            inode_bitmap_start: get_u32(block, 20),
+            block_bitmap_start: get_u32(block, 24),
+            inode_table_start: get_u32(block, 28),
+            data_start: get_u32(block, 32),
+            state: get_u32(block, 36),
+            mount_count: get_u32(block, 40),
+        };
+        // The regions have to be where this block count puts them
+        let expected = Self::layout(superblock.block_count);
+        if (superblock.inode_count, superblock.data_start) != (expected.inode_count, expected.data_start)
+            || superblock.data_start >= superblock.block_count {
+            return Err(KernelError::InvalidData);
+        }
+        Ok(superblock)
     }
-    
-    pub fn name(&self) -> &str {
-        &self.name
-    }
-    
-    pub fn size(&self) -> usize {
-        self.size
-    }
-    
-    pub fn is_directory(&self) -> bool {
-        self.is_directory
-    }
-    
-    pub fn content(&self) -> &[u8] {
-        &self.content
+
+    fn encode(&self) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let fields = [
+            MAGIC, VERSION, BLOCK_SIZE as u32, self.block_count, self.inode_count, self.inode_bitmap_start,
+            self.block_bitmap_start, self.inode_table_start, self.data_start, self.state, self.mount_count,
+        ];
+        for (i, value) in fields.into_iter().enumerate() {
+            put_u32(&mut block, i * 4, value);
+        }
+        block
     }
-    
-    pub fn write(&mut self, data: &[u8]) {
-        self.content = data.to_vec();
-        self.size = data.len();
+}
+
+/// An inode as stored in the inode table
+#[derive(Debug, Clone, Copy, Default)]
+struct Inode {
+    kind: u8,
+    permissions: u16,
+    links: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    created: u64,
+    modified: u64,
+    accessed: u64,
+    direct: [u32; DIRECT_BLOCKS as usize],
+    indirect: u32,
+    double_indirect: u32,
+}
+
+impl Inode {
+    fn decode(raw: &[u8]) -> Self {
+        let mut direct = [0u32; DIRECT_BLOCKS as usize];
+        for (i, pointer) in direct.iter_mut().enumerate() {
+            *pointer = get_u32(raw, 40 + i * 4);
+        }
+        Self {
+            kind: raw[0],
+            permissions: get_u16(raw, 2),
+            links: get_u16(raw, 4),
+            uid: get_u32(raw, 96),
+            gid: get_u32(raw, 100),
+            size: get_u64(raw, 8),
+            created: get_u64(raw, 16),
+            modified: get_u64(raw, 24),
+            accessed: get_u64(raw, 32),
+            direct,
+            indirect: get_u32(raw, 88),
+            double_indirect: get_u32(raw, 92),
+        }
     }
-    
-    pub fn append(&mut self, data: &[u8]) {
-        self.content.extend_from_slice(data);
-        self.size = self.content.len();
+
+    fn encode(&self, raw: &mut [u8]) {
+        raw.fill(0);
+        raw[0] = self.kind;
+        put_u16(raw, 2, self.permissions);
+        put_u16(raw, 4, self.links);
+        put_u64(raw, 8, self.size);
+        put_u64(raw, 16, self.created);
+        put_u64(raw, 24, self.modified);
+        put_u64(raw, 32, self.accessed);
+        for (i, pointer) in self.direct.iter().enumerate() {
+            put_u32(raw, 40 + i * 4, *pointer);
+        }
+        put_u32(raw, 88, self.indirect);
+        put_u32(raw, 92, self.double_indirect);
+        put_u32(raw, 96, self.uid);
+        put_u32(raw, 100, self.gid);
     }
 }
 
-/// Our simple file system structure
-#[allow(dead_code)]
+/// A used slot in a directory
+#[derive(Debug, Clone)]
+struct Entry {
+    slot: usize,
+    inode: u32,
+    kind: u8,
+    name: String,
+}
+
+/// Which inode pointer a file block hangs from
+#[derive(Debug, Clone, Copy)]
+enum Root {
+    Direct(usize),
+    Indirect,
+    DoubleIndirect,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Bitmap {
+    Inodes,
+    Blocks,
+}
+
+/// An SFS volume on a block device
 pub struct SimpleFileSystem {
     device: Arc<Mutex<dyn BlockDevice>>,
-    // In-memory file table (not persistent yet)
-    files: BTreeMap<String, FileEntry>,
+    superblock: Superblock,
+    /// Both bitmaps are kept in memory and written through
+    inode_bitmap: Vec<u8>,
+    block_bitmap: Vec<u8>,
+    free_inodes: u32,
+    free_blocks: u32,
+    /// The volume was marked dirty when opened: it was never unmounted
+    was_dirty: bool,
+    /// The device refuses writes: the volume is left exactly as found
+    read_only: bool,
 }
 
 impl SimpleFileSystem {
-    /// Create a new file system on the given block device
-    pub fn new(device: Arc<Mutex<dyn BlockDevice>>) -> Result<Self, &'static str> {
-        let mut fs = SimpleFileSystem {
+    /// Open the SFS volume on a device. Fails with InvalidData if the
+    /// device doesn't hold one.
+    pub fn new(device: Arc<Mutex<dyn BlockDevice>>) -> Result<Self, KernelError> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let (device_blocks, read_only) = {
+            let device = device.lock();
+            if device.block_size() != BLOCK_SIZE {
+                return Err(KernelError::UnsupportedFeature);
+            }
+            device.read_block(0, &mut block).map_err(|_| KernelError::ReadError)?;
+            (device.block_count(), device.read_only())
+        };
+        let superblock = Superblock::decode(&block)?;
+        if superblock.block_count as u64 > device_blocks {
+            return Err(KernelError::InvalidData);
+        }
+
+        let mut fs = Self {
             device,
-            files: BTreeMap::new(),
+            superblock,
+            inode_bitmap: Vec::new(),
+            block_bitmap: Vec::new(),
+            free_inodes: 0,
+            free_blocks: 0,
+            was_dirty: superblock.state != STATE_CLEAN,
+            read_only,
         };
-        
-        // Initialize the root directory
-        fs.files.insert("/".to_string(), FileEntry::new_directory("/"));
-        
-        // Create some basic directories for testing
-        fs.mkdir("/home")?;
-        fs.mkdir("/bin")?;
-        fs.mkdir("/etc")?;
-        
-        // Create a test file
-        fs.write_file("/etc/version", b"BearOS v0.1")?;
-        
+        fs.inode_bitmap = fs.read_blocks(superblock.inode_bitmap_start, superblock.block_bitmap_start)?;
+        fs.block_bitmap = fs.read_blocks(superblock.block_bitmap_start, superblock.inode_table_start)?;
+        fs.free_inodes = (ROOT_INODE..superblock.inode_count).filter(|&i| !fs.bit(Bitmap::Inodes, i)).count() as u32;
+        fs.free_blocks = (superblock.data_start..superblock.block_count).filter(|&b| !fs.bit(Bitmap::Blocks, b)).count() as u32;
+
+        if fs.was_dirty {
+            serial_println!("DEBUG: SFS: volume was not unmounted cleanly (mounted {} times); fsck recommended",
+                superblock.mount_count);
+        }
         Ok(fs)
     }
-    
-    /// Create a new directory
-    pub fn mkdir(&mut self, path: &str) -> Result<(), &'static str> {
-        if self.files.contains_key(path) {
-            return Err("Directory already exists");
+
+    /// Whether the volume was still marked mounted when it was opened
+    pub fn was_dirty(&self) -> bool {
+        self.was_dirty
+    }
+
+    /// Fail with ReadOnlyFilesystem if the device refuses writes
+    fn writable(&self) -> Result<(), KernelError> {
+        if self.read_only {
+            return Err(KernelError::ReadOnlyFilesystem);
         }
-        
-        // Check if parent directory exists
-        let parent_path = parent_dir(path);
-        if !parent_path.is_empty() && !self.files.contains_key(parent_path) {
-            return Err("Parent directory does not exist");
+        Ok(())
+    }
+
+    fn read_block(&self, block: u32, buffer: &mut [u8]) -> Result<(), KernelError> {
+        self.device.lock().read_block(block as u64, buffer).map_err(|_| KernelError::ReadError)
+    }
+
+    fn write_block(&self, block: u32, buffer: &[u8]) -> Result<(), KernelError> {
+        self.device.lock().write_block(block as u64, buffer).map_err(|_| KernelError::WriteError)
+    }
+
+    fn read_blocks(&self, start: u32, end: u32) -> Result<Vec<u8>, KernelError> {
+        let mut data = vec![0u8; (end - start) as usize * BLOCK_SIZE];
+        for (block, buffer) in (start..end).zip(data.chunks_mut(BLOCK_SIZE)) {
+            self.read_block(block, buffer)?;
+        }
+        Ok(data)
+    }
+
+    fn write_superblock(&self) -> Result<(), KernelError> {
+        self.write_block(0, &self.superblock.encode())
+    }
+
+    fn bit(&self, map: Bitmap, index: u32) -> bool {
+        let bits = match map {
+            Bitmap::Inodes => &self.inode_bitmap,
+            Bitmap::Blocks => &self.block_bitmap,
+        };
+        bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    // Set or clear a bit and write its bitmap block back
+    fn set_bit(&mut self, map: Bitmap, index: u32, used: bool) -> Result<(), KernelError> {
+        let (bits, start) = match map {
+            Bitmap::Inodes => (&mut self.inode_bitmap, self.superblock.inode_bitmap_start),
+            Bitmap::Blocks => (&mut self.block_bitmap, self.superblock.block_bitmap_start),
+        };
+        let byte = (index / 8) as usize;
+        if used {
+            bits[byte] |= 1 << (index % 8);
+        } else {
+            bits[byte] &= !(1 << (index % 8));
+        }
+        let block = byte / BLOCK_SIZE;
+        let data = bits[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE].to_vec();
+        self.write_block(start + block as u32, &data)
+    }
+
+    // Take a free data block and zero it
+    fn allocate_block(&mut self) -> Result<u32, KernelError> {
+        let superblock = self.superblock;
+        let block = (superblock.data_start..superblock.block_count)
+            .find(|&block| !self.bit(Bitmap::Blocks, block))
+            .ok_or(KernelError::NoSpace)?;
+        self.write_block(block, &[0u8; BLOCK_SIZE])?;
+        self.set_bit(Bitmap::Blocks, block, true)?;
+        self.free_blocks -= 1;
+        Ok(block)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), KernelError> {
+        self.set_bit(Bitmap::Blocks, block, false)?;
+        self.free_blocks += 1;
+        Ok(())
+    }
+
+    fn inode_location(&self, number: u32) -> Result<(u32, usize), KernelError> {
+        if number < ROOT_INODE || number >= self.superblock.inode_count {
+            return Err(KernelError::InvalidData);
+        }
+        let block = self.superblock.inode_table_start + number / INODES_PER_BLOCK;
+        Ok((block, (number % INODES_PER_BLOCK) as usize * INODE_SIZE))
+    }
+
+    fn read_inode(&self, number: u32) -> Result<Inode, KernelError> {
+        let (block, offset) = self.inode_location(number)?;
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        self.read_block(block, &mut buffer)?;
+        Ok(Inode::decode(&buffer[offset..offset + INODE_SIZE]))
+    }
+
+    fn write_inode(&self, number: u32, inode: &Inode) -> Result<(), KernelError> {
+        let (block, offset) = self.inode_location(number)?;
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        self.read_block(block, &mut buffer)?;
+        inode.encode(&mut buffer[offset..offset + INODE_SIZE]);
+        self.write_block(block, &buffer)
+    }
+
+    // Take a free inode and give it one link
+    fn allocate_inode(&mut self, kind: u8, permissions: u16) -> Result<u32, KernelError> {
+        let number = (ROOT_INODE..self.superblock.inode_count)
+            .find(|&number| !self.bit(Bitmap::Inodes, number))
+            .ok_or(KernelError::NoSpace)?;
+        let now = now();
+        let inode = Inode {
+            kind,
+            permissions,
+            links: 1,
+            created: now,
+            modified: now,
+            accessed: now,
+            ..Inode::default()
+        };
+        self.write_inode(number, &inode)?;
+        self.set_bit(Bitmap::Inodes, number, true)?;
+        self.free_inodes -= 1;
+        Ok(number)
+    }
+
+    // Drop one link to an inode, freeing it and its blocks with the last
+    fn unlink(&mut self, number: u32) -> Result<(), KernelError> {
+        let mut inode = self.read_inode(number)?;
+        inode.links = inode.links.saturating_sub(1);
+        if inode.links > 0 {
+            return self.write_inode(number, &inode);
+        }
+        self.shrink(&mut inode, 0)?;
+        self.write_inode(number, &Inode::default())?;
+        self.set_bit(Bitmap::Inodes, number, false)?;
+        self.free_inodes += 1;
+        Ok(())
+    }
+
+    // Which inode pointer block `index` of a file hangs from, and the
+    // entries to follow through pointer blocks from there
+    fn route(index: u64) -> Result<(Root, Vec<usize>), KernelError> {
+        if index < DIRECT_BLOCKS {
+            return Ok((Root::Direct(index as usize), Vec::new()));
+        }
+        let index = index - DIRECT_BLOCKS;
+        if index < POINTERS_PER_BLOCK {
+            return Ok((Root::Indirect, vec![index as usize]));
+        }
+        let index = index - POINTERS_PER_BLOCK;
+        if index < POINTERS_PER_BLOCK * POINTERS_PER_BLOCK {
+            let steps = vec![(index / POINTERS_PER_BLOCK) as usize, (index % POINTERS_PER_BLOCK) as usize];
+            return Ok((Root::DoubleIndirect, steps));
+        }
+        // Past the largest file the pointers can reach
+        Err(KernelError::NoSpace)
+    }
+
+    // The disk block holding block `index` of a file; None for a hole
+    fn find_block(&self, inode: &Inode, index: u64) -> Result<Option<u32>, KernelError> {
+        let (root, steps) = Self::route(index)?;
+        let mut block = match root {
+            Root::Direct(i) => inode.direct[i],
+            Root::Indirect => inode.indirect,
+            Root::DoubleIndirect => inode.double_indirect,
+        };
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        for at in steps {
+            if block == 0 {
+                return Ok(None);
+            }
+            self.read_block(block, &mut buffer)?;
+            block = get_u32(&buffer, at * 4);
+        }
+        Ok(Some(block).filter(|&block| block != 0))
+    }
+
+    // The disk block holding block `index` of a file, allocating it and
+    // any pointer blocks on the way
+    fn map_block(&mut self, inode: &mut Inode, index: u64) -> Result<u32, KernelError> {
+        let (root, steps) = Self::route(index)?;
+        let slot = match root {
+            Root::Direct(i) => &mut inode.direct[i],
+            Root::Indirect => &mut inode.indirect,
+            Root::DoubleIndirect => &mut inode.double_indirect,
+        };
+        if *slot == 0 {
+            *slot = self.allocate_block()?;
+        }
+        let mut block = *slot;
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        for at in steps {
+            self.read_block(block, &mut buffer)?;
+            let mut next = get_u32(&buffer, at * 4);
+            if next == 0 {
+                next = self.allocate_block()?;
+                put_u32(&mut buffer, at * 4, next);
+                self.write_block(block, &buffer)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    fn read_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((inode.size - offset) as usize);
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let at = (position % BLOCK_SIZE as u64) as usize;
+            let chunk = (BLOCK_SIZE - at).min(count - done);
+            match self.find_block(inode, position / BLOCK_SIZE as u64)? {
+                Some(disk_block) => {
+                    self.read_block(disk_block, &mut block)?;
+                    buffer[done..done + chunk].copy_from_slice(&block[at..at + chunk]);
+                },
+                // Holes read as zeros
+                None => buffer[done..done + chunk].fill(0),
+            }
+            done += chunk;
         }
-        
-        self.files.insert(path.to_string(), FileEntry::new_directory(path));
+        Ok(count)
+    }
+
+    // Write into a file, growing it as needed. The size keeps up with each
+    // block written, so after a failure the inode still describes what
+    // made it to disk; the caller stores the inode either way.
+    fn write_data(&mut self, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<(), KernelError> {
+        offset.checked_add(data.len() as u64).ok_or(KernelError::InvalidParameter)?;
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let at = (position % BLOCK_SIZE as u64) as usize;
+            let chunk = (BLOCK_SIZE - at).min(data.len() - done);
+            let disk_block = self.map_block(inode, position / BLOCK_SIZE as u64)?;
+            if chunk < BLOCK_SIZE {
+                self.read_block(disk_block, &mut block)?;
+            }
+            block[at..at + chunk].copy_from_slice(&data[done..done + chunk]);
+            self.write_block(disk_block, &block)?;
+            done += chunk;
+            inode.size = inode.size.max(position + chunk as u64);
+        }
+        Ok(())
+    }
+
+    // Cut a file to `length` bytes, freeing blocks past the end. The rest of
+    // the last block is zeroed, so growing the file later reads zeros.
+    fn shrink(&mut self, inode: &mut Inode, length: u64) -> Result<(), KernelError> {
+        let block_size = BLOCK_SIZE as u64;
+        if length % block_size != 0 {
+            if let Some(block) = self.find_block(inode, length / block_size)? {
+                let mut buffer = vec![0u8; BLOCK_SIZE];
+                self.read_block(block, &mut buffer)?;
+                buffer[(length % block_size) as usize..].fill(0);
+                self.write_block(block, &buffer)?;
+            }
+        }
+
+        let keep = length.div_ceil(block_size);
+        for index in keep.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            let block = core::mem::take(&mut inode.direct[index as usize]);
+            if block != 0 {
+                self.free_block(block)?;
+            }
+        }
+        if inode.indirect != 0 && self.free_table(inode.indirect, 1, keep.saturating_sub(DIRECT_BLOCKS))? {
+            inode.indirect = 0;
+        }
+        let double_keep = keep.saturating_sub(DIRECT_BLOCKS + POINTERS_PER_BLOCK);
+        if inode.double_indirect != 0 && self.free_table(inode.double_indirect, 2, double_keep)? {
+            inode.double_indirect = 0;
+        }
+        inode.size = length;
         Ok(())
     }
-    
-    /// Create or overwrite a file with the given content
-    pub fn write_file(&mut self, path: &str, content: &[u8]) -> Result<(), &'static str> {
-        // Check if parent directory exists
-        let parent_path = parent_dir(path);
-        if !parent_path.is_empty() && !self.files.contains_key(parent_path) {
-            return Err("Parent directory does not exist");
-        }
-        
-        let mut file = FileEntry::new_file(path);
-        file.write(content);
-        self.files.insert(path.to_string(), file);
+
+    // Free what a pointer block reaches past its first `keep` data blocks.
+    // `depth` is 1 for a block of data pointers, 2 for a block of those.
+    // Returns true when nothing was kept and the pointer block is freed too.
+    fn free_table(&mut self, table: u32, depth: u32, keep: u64) -> Result<bool, KernelError> {
+        let span = POINTERS_PER_BLOCK.pow(depth - 1);
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        self.read_block(table, &mut buffer)?;
+        let mut changed = false;
+        for at in 0..POINTERS_PER_BLOCK as usize {
+            let pointer = get_u32(&buffer, at * 4);
+            if pointer == 0 {
+                continue;
+            }
+            let first = at as u64 * span;
+            let freed = if depth == 1 {
+                if first >= keep {
+                    self.free_block(pointer)?;
+                }
+                first >= keep
+            } else {
+                self.free_table(pointer, depth - 1, keep.saturating_sub(first))?
+            };
+            if freed {
+                put_u32(&mut buffer, at * 4, 0);
+                changed = true;
+            }
+        }
+        if keep == 0 {
+            self.free_block(table)?;
+            return Ok(true);
+        }
+        if changed {
+            self.write_block(table, &buffer)?;
+        }
+        Ok(false)
+    }
+
+    // Every block an inode uses, pointer blocks included. Pointer blocks
+    // outside the data area are listed but not followed.
+    fn blocks_of(&self, inode: &Inode) -> Result<Vec<u32>, KernelError> {
+        let mut blocks: Vec<u32> = inode.direct.iter().copied().filter(|&block| block != 0).collect();
+        let mut tables = Vec::new();
+        if inode.indirect != 0 {
+            tables.push((inode.indirect, 1));
+        }
+        if inode.double_indirect != 0 {
+            tables.push((inode.double_indirect, 2));
+        }
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        while let Some((table, depth)) = tables.pop() {
+            blocks.push(table);
+            if table < self.superblock.data_start || table >= self.superblock.block_count {
+                continue;
+            }
+            self.read_block(table, &mut buffer)?;
+            for at in 0..POINTERS_PER_BLOCK as usize {
+                let pointer = get_u32(&buffer, at * 4);
+                if pointer == 0 {
+                    continue;
+                }
+                if depth == 1 {
+                    blocks.push(pointer);
+                } else {
+                    tables.push((pointer, depth - 1));
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    // A directory's used slots
+    fn entries(&self, dir: &Inode) -> Result<Vec<Entry>, KernelError> {
+        let mut data = vec![0u8; dir.size as usize];
+        self.read_data(dir, 0, &mut data)?;
+        let entries = data.chunks_exact(DIR_ENTRY_SIZE).enumerate().filter_map(|(slot, raw)| {
+            let inode = get_u32(raw, 0);
+            if inode == 0 {
+                return None;
+            }
+            let length = (raw[4] as usize).min(MAX_NAME_LEN);
+            let name = String::from_utf8_lossy(&raw[6..6 + length]).into_owned();
+            Some(Entry { slot, inode, kind: raw[5], name })
+        });
+        Ok(entries.collect())
+    }
+
+    fn find_entry(&self, dir: u32, name: &str) -> Result<Option<Entry>, KernelError> {
+        let dir = self.read_inode(dir)?;
+        Ok(self.entries(&dir)?.into_iter().find(|entry| entry.name == name))
+    }
+
+    // Inode number for a path
+    fn lookup(&self, path: &str) -> Result<u32, KernelError> {
+        let mut number = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if self.read_inode(number)?.kind != KIND_DIRECTORY {
+                return Err(KernelError::NotADirectory);
+            }
+            number = self.find_entry(number, name)?.ok_or(KernelError::NotFound)?.inode;
+        }
+        Ok(number)
+    }
+
+    // The directory a new or existing name goes in, and the name
+    fn parent_of<'a>(&self, path: &'a str) -> Result<(u32, &'a str), KernelError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = match path.rfind('/') {
+            Some(slash) => (&path[..slash], &path[slash + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME_LEN {
+            return Err(KernelError::InvalidParameter);
+        }
+        let parent = self.lookup(parent)?;
+        if self.read_inode(parent)?.kind != KIND_DIRECTORY {
+            return Err(KernelError::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+
+    // Name an inode in a directory, in the first free slot
+    fn add_entry(&mut self, dir: u32, name: &str, inode: u32, kind: u8) -> Result<(), KernelError> {
+        let mut directory = self.read_inode(dir)?;
+        let entries = self.entries(&directory)?;
+        if entries.iter().any(|entry| entry.name == name) {
+            return Err(KernelError::AlreadyExists);
+        }
+        let slots = directory.size as usize / DIR_ENTRY_SIZE;
+        let slot = (0..slots).find(|slot| !entries.iter().any(|entry| entry.slot == *slot)).unwrap_or(slots);
+
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        put_u32(&mut raw, 0, inode);
+        raw[4] = name.len() as u8;
+        raw[5] = kind;
+        raw[6..6 + name.len()].copy_from_slice(name.as_bytes());
+        let result = self.write_data(&mut directory, (slot * DIR_ENTRY_SIZE) as u64, &raw);
+        directory.modified = now();
+        self.write_inode(dir, &directory)?;
+        result
+    }
+
+    fn clear_entry(&mut self, dir: u32, slot: usize) -> Result<(), KernelError> {
+        let mut directory = self.read_inode(dir)?;
+        self.write_data(&mut directory, (slot * DIR_ENTRY_SIZE) as u64, &[0u8; DIR_ENTRY_SIZE])?;
+        // Free slots at the end are dropped, so an emptied directory gives
+        // its blocks back
+        let end = self.entries(&directory)?.last().map_or(0, |entry| (entry.slot + 1) * DIR_ENTRY_SIZE) as u64;
+        if end < directory.size {
+            self.shrink(&mut directory, end)?;
+        }
+        directory.modified = now();
+        self.write_inode(dir, &directory)
+    }
+
+    fn create_node(&mut self, path: &str, kind: u8, permissions: u16) -> Result<(), KernelError> {
+        let (parent, name) = self.parent_of(path)?;
+        if self.find_entry(parent, name)?.is_some() {
+            return Err(KernelError::AlreadyExists);
+        }
+        let number = self.allocate_inode(kind, permissions)?;
+        if let Err(e) = self.add_entry(parent, name, number, kind) {
+            self.unlink(number)?;
+            return Err(e);
+        }
         Ok(())
     }
-    
-    /// Read the content of a file
-    pub fn read_file(&self, path: &str) -> Result<&[u8], &'static str> {
-        match self.files.get(path) {
-            Some(file) if !file.is_directory => Ok(file.content()),
-            Some(_) => Err("Path is a directory"),
-            None => Err("File not found"),
-        }
-    }
-    
-    /// List directory contents
-    pub fn list_directory(&self, path: &str) -> Result<Vec<String>, &'static str> {
-        // Check if directory exists
-        match self.files.get(path) {
-            Some(entry) if entry.is_directory => {
-                let mut entries = Vec::new();
-                let path_with_slash = if path.ends_with('/') { 
-                    path.to_string() 
-                } else { 
-                    format!("{}/", path) 
+
+    fn file_inode(&self, path: &str) -> Result<(u32, Inode), KernelError> {
+        let number = self.lookup(path)?;
+        let inode = self.read_inode(number)?;
+        if inode.kind == KIND_DIRECTORY {
+            return Err(KernelError::IsADirectory);
+        }
+        Ok((number, inode))
+    }
+}
+
+impl FileSystem for SimpleFileSystem {
+    fn mount(&mut self) -> Result<(), KernelError> {
+        if self.read_only {
+            return Ok(());
+        }
+        // Dirty until unmounted, so a crash leaves a mark
+        self.superblock.state = STATE_DIRTY;
+        self.superblock.mount_count = self.superblock.mount_count.wrapping_add(1);
+        self.write_superblock()
+    }
+
+    fn unmount(&mut self) -> Result<(), KernelError> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.superblock.state = STATE_CLEAN;
+        self.write_superblock()?;
+        self.device.lock().flush().map_err(|_| KernelError::WriteError)
+    }
+
+    fn create_file(&mut self, path: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        self.create_node(path, KIND_FILE, Metadata::new_file().permissions)
+    }
+
+    fn create_directory(&mut self, path: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        self.create_node(path, KIND_DIRECTORY, Metadata::new_directory().permissions)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        let (parent, name) = self.parent_of(path)?;
+        let entry = self.find_entry(parent, name)?.ok_or(KernelError::NotFound)?;
+        let inode = self.read_inode(entry.inode)?;
+        if inode.kind == KIND_DIRECTORY && !self.entries(&inode)?.is_empty() {
+            return Err(KernelError::DirectoryNotEmpty);
+        }
+        self.clear_entry(parent, entry.slot)?;
+        self.unlink(entry.inode)
+    }
+
+    fn open(&mut self, path: &str, write: bool) -> Result<Option<usize>, KernelError> {
+        // Handles work by path
+        if self.read_inode(self.lookup(path)?)?.kind == KIND_DIRECTORY {
+            return Err(KernelError::NotAFile);
+        }
+        if write {
+            self.writable()?;
+        }
+        Ok(None)
+    }
+
+    fn link(&mut self, existing: &str, new: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        let (number, mut inode) = self.file_inode(existing)?;
+        let (parent, name) = self.parent_of(new)?;
+        self.add_entry(parent, name, number, KIND_FILE)?;
+        inode.links += 1;
+        self.write_inode(number, &inode)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
+        let inode = self.read_inode(self.lookup(path)?)?;
+        Ok(Metadata {
+            node_type: if inode.kind == KIND_DIRECTORY { NodeType::Directory } else { NodeType::File },
+            size: inode.size,
+            permissions: inode.permissions,
+            links: inode.links as usize,
+            uid: inode.uid,
+            gid: inode.gid,
+            attributes: 0,
+            created_at: inode.created,
+            modified_at: inode.modified,
+            accessed_at: inode.accessed,
+        })
+    }
+
+    fn set_metadata(&mut self, path: &str, update: MetadataUpdate) -> Result<(), KernelError> {
+        self.writable()?;
+        let number = self.lookup(path)?;
+        let mut inode = self.read_inode(number)?;
+        let mut metadata = self.metadata(path)?;
+        update.apply(&mut metadata);
+        inode.permissions = metadata.permissions;
+        inode.uid = metadata.uid;
+        inode.gid = metadata.gid;
+        inode.modified = metadata.modified_at;
+        inode.accessed = metadata.accessed_at;
+        self.write_inode(number, &inode)
+    }
+
+    fn read_dir_from(&self, path: &str, cursor: usize, out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError> {
+        // The cursor is a slot number; slots don't move while entries
+        // around them come and go
+        let dir = self.read_inode(self.lookup(path)?)?;
+        if dir.kind != KIND_DIRECTORY {
+            return Err(KernelError::NotADirectory);
+        }
+        let mut filled = 0;
+        for entry in self.entries(&dir)?.into_iter().filter(|entry| entry.slot >= cursor) {
+            if filled == out.len() {
+                return Ok((filled, Some(entry.slot)));
+            }
+            let node_type = if entry.kind == KIND_DIRECTORY { NodeType::Directory } else { NodeType::File };
+            out[filled] = Some(DirEntry::new(&entry.name, node_type, entry.inode as usize));
+            filled += 1;
+        }
+        Ok((filled, None))
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), KernelError> {
+        self.writable()?;
+        let (old_parent, old_name) = self.parent_of(from)?;
+        let (new_parent, new_name) = self.parent_of(to)?;
+        let entry = self.find_entry(old_parent, old_name)?.ok_or(KernelError::NotFound)?;
+        let moving_directory = entry.kind == KIND_DIRECTORY;
+        if moving_directory && to.starts_with(&format!("{}/", from.trim_end_matches('/'))) {
+            // A directory can't move below itself
+            return Err(KernelError::InvalidOperation);
+        }
+
+        // Whatever `to` names now loses that name
+        if let Some(target) = self.find_entry(new_parent, new_name)? {
+            if target.inode == entry.inode {
+                return Ok(());
+            }
+            let target_inode = self.read_inode(target.inode)?;
+            match (target.kind == KIND_DIRECTORY, moving_directory) {
+                (true, true) if !self.entries(&target_inode)?.is_empty() => {
+                    return Err(KernelError::DirectoryNotEmpty);
+                },
+                (true, false) => return Err(KernelError::IsADirectory),
+                (false, true) => return Err(KernelError::NotADirectory),
+                _ => {},
+            }
+            self.clear_entry(new_parent, target.slot)?;
+            self.unlink(target.inode)?;
+        }
+
+        self.clear_entry(old_parent, entry.slot)?;
+        self.add_entry(new_parent, new_name, entry.inode, entry.kind)
+    }
+
+    fn name(&self) -> &str {
+        "SFS"
+    }
+
+    fn device_id(&self) -> Option<u64> {
+        self.device.lock().device_id()
+    }
+
+    fn total_space(&self) -> u64 {
+        (self.superblock.block_count - self.superblock.data_start) as u64 * BLOCK_SIZE as u64
+    }
+
+    fn available_space(&self) -> u64 {
+        self.free_blocks as u64 * BLOCK_SIZE as u64
+    }
+
+    fn read_at(&self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        let (number, mut inode) = self.file_inode(path)?;
+        let count = self.read_data(&inode, offset, buffer)?;
+        if !self.read_only {
+            inode.accessed = now();
+            self.write_inode(number, &inode)?;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
+        self.writable()?;
+        let (number, mut inode) = self.file_inode(path)?;
+        let result = self.write_data(&mut inode, offset, buffer);
+        inode.modified = now();
+        self.write_inode(number, &inode)?;
+        result.map(|_| buffer.len())
+    }
+
+    fn truncate(&mut self, path: &str, length: u64) -> Result<(), KernelError> {
+        self.writable()?;
+        let (number, mut inode) = self.file_inode(path)?;
+        if length < inode.size {
+            self.shrink(&mut inode, length)?;
+        } else {
+            // Blocks past the old end are holes until written
+            Self::route(length.saturating_sub(1) / BLOCK_SIZE as u64)?;
+            inode.size = length;
+        }
+        inode.modified = now();
+        self.write_inode(number, &inode)
+    }
+
+    fn check(&mut self, repair: bool) -> Result<CheckReport, KernelError> {
+        if repair {
+            self.writable()?;
+        }
+        let superblock = self.superblock;
+        let mut report = CheckReport::default();
+        // Names found for each inode, and a bit for each block in use
+        let mut names = vec![0u16; superblock.inode_count as usize];
+        let mut used = vec![0u8; superblock.block_count.div_ceil(8) as usize];
+        let mut bad_entries = Vec::new();
+
+        names[ROOT_INODE as usize] = 1;
+        let mut pending = vec![(String::from("/"), ROOT_INODE)];
+        while let Some((path, number)) = pending.pop() {
+            let inode = self.read_inode(number)?;
+            for block in self.blocks_of(&inode)? {
+                if block < superblock.data_start || block >= superblock.block_count {
+                    report.problems.push(format!("{}: points at block {} outside the data area", path, block));
+                } else if used[(block / 8) as usize] & (1 << (block % 8)) != 0 {
+                    report.problems.push(format!("{}: block {} is used twice", path, block));
+                } else {
+                    used[(block / 8) as usize] |= 1 << (block % 8);
+                }
+            }
+            if inode.kind != KIND_DIRECTORY {
+                continue;
+            }
+
+            for entry in self.entries(&inode)? {
+                let child = walk::join(&path, &entry.name);
+                let named = match self.read_inode(entry.inode) {
+                    Ok(target) if target.kind != KIND_FREE => target,
+                    _ => {
+                        report.problems.push(format!("{}: names free inode {}", child, entry.inode));
+                        bad_entries.push((number, entry.slot));
+                        continue;
+                    }
                 };
-                
-                // For a more efficient implementation, you'd organize directories differently
-                for (file_path, _) in self.files.iter() {
-                    if file_path != path && 
-                       file_path.starts_with(&path_with_slash) && 
-                       !file_path[path_with_slash.len()..].contains('/') {
-                        // Direct child of this directory
-                        entries.push(file_path.clone());
+                names[entry.inode as usize] += 1;
+                if names[entry.inode as usize] == 1 {
+                    pending.push((child, entry.inode));
+                } else if named.kind == KIND_DIRECTORY {
+                    report.problems.push(format!("{}: a second name for a directory", child));
+                }
+            }
+        }
+
+        // Compare what the walk found with the bitmaps and link counts
+        for number in ROOT_INODE..superblock.inode_count {
+            let found = names[number as usize];
+            if self.bit(Bitmap::Inodes, number) != (found > 0) {
+                report.problems.push(if found > 0 {
+                    format!("inode {} is named but marked free", number)
+                } else {
+                    format!("inode {} is marked in use but nothing names it", number)
+                });
+                if repair {
+                    if found == 0 {
+                        self.write_inode(number, &Inode::default())?;
                     }
+                    self.set_bit(Bitmap::Inodes, number, found > 0)?;
+                    report.repaired += 1;
                 }
-                
-                Ok(entries)
-            },
-            Some(_) => Err("Path is not a directory"),
-            None => Err("Directory not found"),
-        }
-    }
-    
-    /// Delete a file or empty directory
-    pub fn delete(&mut self, path: &str) -> Result<(), &'static str> {
-        if path == "/" {
-            return Err("Cannot delete root directory");
-        }
-        
-        match self.files.get(path) {
-            Some(entry) if entry.is_directory => {
-                // Check if directory is empty
-                let path_with_slash = if path.ends_with('/') { 
-                    path.to_string() 
-                } else { 
-                    format!("{}/", path) 
-                };
-                
-                for file_path in self.files.keys() {
-                    if file_path != path && file_path.starts_with(&path_with_slash) {
-                        return Err("Directory not empty");
+            }
+            if found > 0 {
+                let mut inode = self.read_inode(number)?;
+                if inode.links != found {
+                    report.problems.push(format!("inode {} has {} links but {} names", number, inode.links, found));
+                    if repair {
+                        inode.links = found;
+                        self.write_inode(number, &inode)?;
+                        report.repaired += 1;
                     }
                 }
-                
-                // Directory is empty, remove it
-                self.files.remove(path);
-                Ok(())
-            },
-            Some(_) => {
-                // Remove the file
-                self.files.remove(path);
-                Ok(())
-            },
-            None => Err("File or directory not found"),
-        }
-    }
-    
-    /// Persist the file system to the block device (not fully implemented yet)
-    pub fn sync(&self) -> Result<(), &'static str> {
-        // In a real implementation, this would serialize the file table and contents
-        // to the block device. For now, this is a placeholder.
-        
-        // Our simple implementation keeps everything in memory for simplicity
-        Ok(())
+            }
+        }
+        for block in superblock.data_start..superblock.block_count {
+            let in_use = used[(block / 8) as usize] & (1 << (block % 8)) != 0;
+            if self.bit(Bitmap::Blocks, block) != in_use {
+                report.problems.push(if in_use {
+                    format!("block {} is in use but marked free", block)
+                } else {
+                    format!("block {} is marked in use but nothing uses it", block)
+                });
+                if repair {
+                    self.set_bit(Bitmap::Blocks, block, in_use)?;
+                    report.repaired += 1;
+                }
+            }
+        }
+        if repair {
+            for (dir, slot) in bad_entries {
+                self.clear_entry(dir, slot)?;
+                report.repaired += 1;
+            }
+            self.free_inodes = (ROOT_INODE..superblock.inode_count).filter(|&i| !self.bit(Bitmap::Inodes, i)).count() as u32;
+            self.free_blocks = (superblock.data_start..superblock.block_count).filter(|&b| !self.bit(Bitmap::Blocks, b)).count() as u32;
+        }
+        Ok(report)
     }
 }
 
-impl fmt::Debug for SimpleFileSystem {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "SimpleFileSystem {{ files: {:?} }}", self.files)
+/// Whether a device holds an SFS volume
+pub fn probe(device: &dyn BlockDevice) -> bool {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    device.block_size() == BLOCK_SIZE
+        && device.read_block(0, &mut block).is_ok()
+        && get_u32(&block, 0) == MAGIC
+}
+
+/// Write an empty SFS volume over the whole device: the superblock, both
+/// bitmaps, an inode table holding only the root directory. Everything
+/// already on the device is lost.
+pub fn format(device: &mut dyn BlockDevice) -> Result<(), KernelError> {
+    if device.read_only() {
+        return Err(KernelError::ReadOnlyFilesystem);
+    }
+    if device.block_size() != BLOCK_SIZE {
+        return Err(KernelError::UnsupportedFeature);
+    }
+    if device.block_count() < MIN_BLOCKS {
+        return Err(KernelError::InvalidParameter);
+    }
+    // Anything past what a u32 block number reaches is left unused
+    let superblock = Superblock::layout(u32::try_from(device.block_count()).unwrap_or(u32::MAX));
+    let mut write = |block: u32, data: &[u8]| -> Result<(), KernelError> {
+        device.write_block(block as u64, data).map_err(|_| KernelError::WriteError)
+    };
+    write(0, &superblock.encode())?;
+
+    // Inode 0 is never used and 1 is the root; the blocks before the data
+    // area are taken
+    let mut bitmap = vec![0u8; BLOCK_SIZE];
+    for block in superblock.inode_bitmap_start..superblock.block_bitmap_start {
+        bitmap.fill(0);
+        if block == superblock.inode_bitmap_start {
+            bitmap[0] = 0b11;
+        }
+        write(block, &bitmap)?;
+    }
+    for (i, block) in (superblock.block_bitmap_start..superblock.inode_table_start).enumerate() {
+        bitmap.fill(0);
+        let first = i as u32 * BITS_PER_BLOCK;
+        for bit in first..(first + BITS_PER_BLOCK).min(superblock.data_start) {
+            bitmap[((bit - first) / 8) as usize] |= 1 << (bit % 8);
+        }
+        write(block, &bitmap)?;
+    }
+
+    let now = now();
+    let root = Inode {
+        kind: KIND_DIRECTORY,
+        permissions: Metadata::new_directory().permissions,
+        links: 1,
+        created: now,
+        modified: now,
+        accessed: now,
+        ..Inode::default()
+    };
+    let mut table = vec![0u8; BLOCK_SIZE];
+    for block in superblock.inode_table_start..superblock.data_start {
+        table.fill(0);
+        if block == superblock.inode_table_start {
+            let offset = ROOT_INODE as usize * INODE_SIZE;
+            root.encode(&mut table[offset..offset + INODE_SIZE]);
+        }
+        write(block, &table)?;
     }
+
+    serial_println!("DEBUG: SFS: formatted {} blocks with {} inodes, data from block {}",
+        superblock.block_count, superblock.inode_count, superblock.data_start);
+    Ok(())
 }
 
-// Helper function to get the parent directory of a path
-fn parent_dir(path: &str) -> &str {
-    match path.rfind('/') {
-        Some(pos) if pos > 0 => &path[..pos],
-        _ => "", // Root directory or invalid path
+/// Format a RamDisk, fill it, open it again after a crash and after a
+/// clean unmount, and check everything came back
+pub fn self_test() -> Result<(), KernelError> {
+    use super::ramdisk::RamDisk;
+
+    serial_println!("SFS: Running self-test");
+
+    let mut disk = RamDisk::with_capacity(8192, BLOCK_SIZE)?;
+    format(&mut disk)?;
+    let device: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(disk));
+    if !probe(&*device.lock()) {
+        return Err(KernelError::ValidationError("Formatted SFS volume not recognised"));
     }
-} 
\ No newline at end of file
+
+    // Big enough to need the double indirect block
+    let big: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+    let long_name = "/docs/a file name far too long for an 8.3 directory entry.txt";
+    let empty_free = {
+        let mut fs = SimpleFileSystem::new(device.clone())?;
+        fs.mount()?;
+        let free = fs.available_space();
+        fs.create_directory("/docs")?;
+        fs.create_file(long_name)?;
+        fs.write_at(long_name, 0, &big)?;
+        fs.create_file("/docs/small")?;
+        fs.write_at("/docs/small", 10, b"hello")?;
+        fs.link("/docs/small", "/second")?;
+        fs.rename("/second", "/docs/third")?;
+        let too_long = format!("/{}", "x".repeat(MAX_NAME_LEN + 1));
+        if !matches!(fs.create_file("/docs"), Err(KernelError::AlreadyExists)) || fs.create_file(&too_long).is_ok() {
+            return Err(KernelError::ValidationError("SFS accepted a clashing or overlong name"));
+        }
+        // Dropped while still mounted, as a crash would leave it
+        free
+    };
+
+    let mut fs = SimpleFileSystem::new(device.clone())?;
+    if !fs.was_dirty() {
+        return Err(KernelError::ValidationError("SFS volume left mounted was not marked dirty"));
+    }
+    fs.mount()?;
+    let report = fs.check(false)?;
+    if !report.is_clean() {
+        serial_println!("SFS: Problems: {:?}", report.problems);
+        return Err(KernelError::ValidationError("SFS volume fails its check after writing"));
+    }
+    let mut contents = vec![0u8; big.len() + 10];
+    let read = fs.read_at(long_name, 0, &mut contents)?;
+    let mut small = [0u8; 20];
+    let small_read = fs.read_at("/docs/third", 0, &mut small)?;
+    if read != big.len() || contents[..read] != big[..] || small_read != 15 || &small[..15] != b"\0\0\0\0\0\0\0\0\0\0hello" {
+        return Err(KernelError::ValidationError("SFS file contents wrong after reopening"));
+    }
+    let mut names: Vec<String> = fs.read_dir("/docs")?.into_iter().map(|entry| entry.name).collect();
+    names.sort();
+    if names != [&long_name[6..], "small", "third"] || fs.metadata("/docs/small")?.links != 2 || fs.read_dir("/")?.len() != 1 {
+        serial_println!("SFS: /docs holds {:?}", names);
+        return Err(KernelError::ValidationError("SFS names or links wrong after reopening"));
+    }
+    fs.unmount()?;
+
+    let mut fs = SimpleFileSystem::new(device.clone())?;
+    if fs.was_dirty() {
+        return Err(KernelError::ValidationError("Cleanly unmounted SFS volume was marked dirty"));
+    }
+    fs.mount()?;
+    // Shrinking then growing again must read zeros past the old end
+    fs.truncate(long_name, 3000)?;
+    fs.truncate(long_name, 5000)?;
+    let mut tail = [1u8; 16];
+    fs.read_at(long_name, 2992, &mut tail)?;
+    if tail[..8] != big[2992..3000] || tail[8..].iter().any(|&byte| byte != 0) {
+        return Err(KernelError::ValidationError("SFS truncate left old data behind"));
+    }
+
+    if !matches!(fs.remove("/docs"), Err(KernelError::DirectoryNotEmpty)) {
+        return Err(KernelError::ValidationError("SFS removed a directory that wasn't empty"));
+    }
+    for path in [long_name, "/docs/small", "/docs/third", "/docs"] {
+        fs.remove(path)?;
+    }
+    if fs.available_space() != empty_free || !fs.check(false)?.is_clean() {
+        return Err(KernelError::ValidationError("SFS did not free removed files' blocks"));
+    }
+    fs.unmount()?;
+
+    serial_println!("SFS: Self-test passed");
+    Ok(())
+}
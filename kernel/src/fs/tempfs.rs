@@ -1,547 +1,648 @@
-use alloc::borrow::ToOwned;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::format;
 use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
-use alloc::sync::Arc;
-use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::Mutex;
 
-use crate::{errors::KernelError, serial_println};
-use crate::fs::vfs::{DirEntry, FileHandle, FileSystem, Metadata, NodeType};
+use crate::{errors::KernelError, kdebug, serial_println};
+use crate::fs::vfs::{CheckReport, DirEntry, FileSystem, Metadata, MetadataUpdate, NodeType};
+use crate::fs::path::Path;
+
+/// Capacity reported when none is configured
+pub const DEFAULT_CAPACITY: u64 = 10 * 1024 * 1024;
+/// Rough bookkeeping cost of a node besides its names and data: metadata
+/// and map entries
+const NODE_OVERHEAD: u64 = 128;
 
 /// In-memory file system for temporary storage
 pub struct TempFs {
     /// Name of the file system
     name: String,
+    /// Bytes the file system reports as its size
+    capacity: u64,
     /// Root node inode number
     root_inode: usize,
     /// Next available inode number
-    next_inode: AtomicUsize,
-    /// Map of paths to nodes
-    nodes: BTreeMap<String, TempFsNode>,
+    next_inode: usize,
+    /// Order number for the next directory entry, which read_dir_from uses
+    /// as its cursor
+    next_entry: usize,
+    /// Every node, by inode number
+    inodes: BTreeMap<usize, TempFsNode>,
+}
+
+/// A name in a directory
+#[derive(Clone, Copy)]
+struct Link {
+    inode: usize,
+    /// When the entry was added, relative to the others
+    order: usize,
+}
+
+/// Where orphaned nodes are put back into the tree
+const LOST_AND_FOUND: &str = "/lost+found";
+
+/// A fix found by `check`, applied only when repairing
+enum Repair {
+    /// Drop a directory entry naming a missing node
+    DropEntry(usize, String),
+    /// Correct a node's link count
+    SetLinks(usize, usize),
+    /// Make a file's size match its data
+    SetSize(usize),
+    /// Name an unreachable node in /lost+found
+    Reattach(usize),
+    /// Move the next inode number past every node in use
+    SetNextInode(usize),
 }
 
 /// Node data variants
 enum NodeData {
     File(Vec<u8>),
-    Directory(BTreeMap<String, usize>), // Map of name to inode
+    Directory(BTreeMap<String, Link>),
 }
 
 /// File system node
 struct TempFsNode {
-    /// Inode number
-    inode: usize,
     /// Metadata
     metadata: Metadata,
     /// Actual data
     data: NodeData,
+    /// Directory entries naming this node
+    links: usize,
+    /// Handles open on this node. A node with no links is kept until the
+    /// last one is released.
+    open_handles: usize,
+}
+
+impl TempFsNode {
+    fn new(metadata: Metadata, data: NodeData) -> Self {
+        Self { metadata, data, links: 1, open_handles: 0 }
+    }
 }
 
 impl TempFs {
     /// Create a new TempFS
     pub fn new(name: &str) -> Self {
-        serial_println!("DEBUG: Creating new TempFS with name: {}", name);
-        
-        // Create root node
+        Self::with_capacity(name, DEFAULT_CAPACITY)
+    }
+
+    /// Create a new TempFS that reports `capacity` bytes of space
+    pub fn with_capacity(name: &str, capacity: u64) -> Self {
+        kdebug!("tempfs", "Creating new TempFS with name: {} ({} bytes)", name, capacity);
+
         let root_inode = 1;
-        let root_node = TempFsNode {
-            inode: root_inode,
-            metadata: Metadata::new_directory(),
-            data: NodeData::Directory(BTreeMap::new()),
-        };
-        
-        // Create nodes map with root
-        let mut nodes = BTreeMap::new();
-        nodes.insert("/".to_string(), root_node);
-        
+        let mut inodes = BTreeMap::new();
+        inodes.insert(root_inode, TempFsNode::new(Metadata::new_directory(), NodeData::Directory(BTreeMap::new())));
+
         Self {
             name: name.to_string(),
+            capacity,
             root_inode,
-            next_inode: AtomicUsize::new(2), // Start at 2 because 1 is root
-            nodes,
+            next_inode: 2, // Start at 2 because 1 is root
+            next_entry: 0,
+            inodes,
         }
     }
 
-    /// Normalize a path into a canonical form (absolute, no trailing slash, no double slashes)
-    pub fn normalize_path_canonical(&self, path: &str) -> String {
-        serial_println!("DEBUG: TempFS::normalize_path_canonical - Input: '{}'", path);
-        
-        // Handle empty path
-        if path.is_empty() {
-            return "/".to_string();
+    /// Estimated bytes in use: file contents, names, and the bookkeeping for
+    /// each node
+    pub fn used_space(&self) -> u64 {
+        self.inodes.values()
+            .map(|node| {
+                let data = match &node.data {
+                    NodeData::File(data) => data.len() as u64,
+                    NodeData::Directory(entries) => entries.keys().map(|name| name.len() as u64).sum(),
+                };
+                NODE_OVERHEAD + data
+            })
+            .sum()
+    }
+
+    /// Inode a path names, if any
+    fn lookup(&self, path: &str) -> Option<usize> {
+        let mut inode = self.root_inode;
+        for component in Path::new(path).canonical().components() {
+            inode = match &self.inodes.get(&inode)?.data {
+                NodeData::Directory(entries) => entries.get(component)?.inode,
+                NodeData::File(_) => return None,
+            };
         }
-        
-        // Ensure leading slash
-        let mut result = if path.starts_with('/') {
-            path.to_string()
-        } else {
-            format!("/{}", path)
-        };
-        
-        // Remove trailing slashes (except for root)
-        while result.len() > 1 && result.ends_with('/') {
-            result.pop();
-        }
-        
-        // Fix double slashes
-        while result.contains("//") {
-            result = result.replace("//", "/");
-        }
-        
-        serial_println!("DEBUG: TempFS::normalize_path_canonical - Output: '{}'", result);
-        result
-    }
-    
-    /// Checks if a path exists 
+        Some(inode)
+    }
+
+    /// Node a path names
+    fn node(&self, path: &str) -> Result<&TempFsNode, KernelError> {
+        self.lookup(path)
+            .and_then(|inode| self.inodes.get(&inode))
+            .ok_or(KernelError::NotFound)
+    }
+
+    /// Split a path into its parent directory's inode and the last component
+    fn parent_of(&self, path: &str) -> Result<(usize, String), KernelError> {
+        let canonical = Path::new(path).canonical();
+        // The root has no parent
+        let (parent, name) = canonical.parent().zip(canonical.file_name()).ok_or(KernelError::InvalidOperation)?;
+        let parent = self.lookup(parent.as_str()).ok_or(KernelError::NotFound)?;
+        Ok((parent, name.to_string()))
+    }
+
+    /// Add a name for `inode` to directory `parent`
+    fn add_link(&mut self, parent: usize, name: &str, inode: usize) -> Result<(), KernelError> {
+        let order = self.next_entry;
+        match self.inodes.get_mut(&parent).map(|node| &mut node.data) {
+            Some(NodeData::Directory(entries)) => {
+                if entries.contains_key(name) {
+                    return Err(KernelError::AlreadyExists);
+                }
+                entries.insert(name.to_string(), Link { inode, order });
+                self.next_entry += 1;
+                Ok(())
+            },
+            Some(NodeData::File(_)) => Err(KernelError::NotADirectory),
+            None => Err(KernelError::NotFound),
+        }
+    }
+
+    /// Create a node and name it `name` in directory `parent`
+    fn create_node(&mut self, parent: usize, name: &str, node: TempFsNode) -> Result<usize, KernelError> {
+        let inode = self.next_inode;
+        self.add_link(parent, name, inode)?;
+        self.next_inode += 1;
+        self.inodes.insert(inode, node);
+        Ok(inode)
+    }
+
+    /// Drop a node once nothing names it and no handle has it open
+    fn free_if_unused(&mut self, inode: usize) {
+        if let Some(node) = self.inodes.get(&inode) {
+            if node.links == 0 && node.open_handles == 0 {
+                kdebug!("tempfs", "TempFS: Freeing inode {}", inode);
+                self.inodes.remove(&inode);
+            }
+        }
+    }
+
+    /// File contents of a node
+    fn file_data(&self, inode: usize) -> Result<&Vec<u8>, KernelError> {
+        match self.inodes.get(&inode).map(|node| &node.data) {
+            Some(NodeData::File(data)) => Ok(data),
+            Some(NodeData::Directory(_)) => Err(KernelError::NotAFile),
+            None => Err(KernelError::NotFound),
+        }
+    }
+
+    /// Mutable file contents and metadata of a node
+    fn file_mut(&mut self, inode: usize) -> Result<(&mut Vec<u8>, &mut Metadata), KernelError> {
+        let node = self.inodes.get_mut(&inode).ok_or(KernelError::NotFound)?;
+        match &mut node.data {
+            NodeData::File(data) => Ok((data, &mut node.metadata)),
+            NodeData::Directory(_) => Err(KernelError::NotAFile),
+        }
+    }
+
+    /// Checks if a path exists
     pub fn path_exists(&self, path: &str) -> bool {
-        let canonical = self.normalize_path_canonical(path);
-        let exists = self.nodes.contains_key(&canonical);
-        serial_println!("DEBUG: TempFS::path_exists - Path '{}' exists: {}", canonical, exists);
-        exists
+        self.lookup(path).is_some()
     }
-    
+
     /// Safe, linear path-walking directory creator
     /// Creates a directory and all parent directories as needed
     pub fn ensure_path_exists(&mut self, path: &str) -> Result<(), KernelError> {
-        serial_println!("DEBUG: TempFS::ensure_path_exists - Starting for path: '{}'", path);
-        
-        // Handle root directory as a special case
-        if path == "/" {
-            serial_println!("DEBUG: TempFS::ensure_path_exists - Root path requested, already exists");
-            return Ok(());
-        }
-        
-        // Get canonical path
-        let canonical = self.normalize_path_canonical(path);
-        
-        // Check if path already exists
-        if self.path_exists(&canonical) {
-            serial_println!("DEBUG: TempFS::ensure_path_exists - Path '{}' already exists", canonical);
-            return Ok(());
-        }
-        
-        // Split path into components
-        let components: Vec<&str> = canonical.split('/')
-            .filter(|s| !s.is_empty())
-            .collect();
-            
-        serial_println!("DEBUG: TempFS::ensure_path_exists - Path components: {:?}", components);
-        
-        // Build path one component at a time, creating directories as needed
-        let mut current_path = String::from("/".to_owned());
-        
-        for component in components {
-            // Build the next path segment
-            if current_path == "/" {
-                current_path = format!("/{}", component);
-            } else {
-                current_path = format!("{}/{}", current_path, component);
-            }
-            
-            serial_println!("DEBUG: TempFS::ensure_path_exists - Processing component: '{}', current path: '{}'", component, current_path);
-            
-            // Check if this path segment exists
-            if !self.path_exists(&current_path) {
-                serial_println!("DEBUG: TempFS::ensure_path_exists - Creating directory: '{}'", current_path);
-                
-                // Find the parent path and directory name
-                let parent_path = if current_path.rfind('/').unwrap_or(0) == 0 {
-                    "/".to_string()
-                } else {
-                    let last_slash = current_path.rfind('/').unwrap();
-                    current_path[..last_slash].to_string()
-                };
-                
-                let dir_name = match current_path.rfind('/') {
-                    Some(pos) => &current_path[pos+1..],
-                    None => &current_path // Should never happen with our path construction
-                };
-                
-                serial_println!("DEBUG: TempFS::ensure_path_exists - Parent: '{}', Name: '{}'", parent_path, dir_name);
-                
-                // Create directory node
-                let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
-                
-                serial_println!("DEBUG: TempFS::ensure_path_exists - Creating directory node with inode: {}", inode);
-                let dir_node = TempFsNode {
-                    inode,
-                    metadata: Metadata::new_directory(),
-                    data: NodeData::Directory(BTreeMap::new()),
-                };
-                
-                // Insert node into filesystem
-                serial_println!("DEBUG: TempFS::ensure_path_exists - Inserting node for path: '{}'", current_path);
-                match self.nodes.insert(current_path.clone(), dir_node) {
-                    Some(_) => {
-                        serial_println!("DEBUG: TempFS::ensure_path_exists - Node unexpectedly already exists");
-                        // Continue anyway, another process might have created it
-                    },
-                    None => {
-                        serial_println!("DEBUG: TempFS::ensure_path_exists - Node inserted successfully");
-                        
-                        // Update parent directory's entries
-                        if let Some(parent_node) = self.nodes.get_mut(&parent_path) {
-                            serial_println!("DEBUG: TempFS::ensure_path_exists - Found parent node: '{}'", parent_path);
-                            
-                            // Safely update parent directory entries
-                            match &mut parent_node.data {
-                                NodeData::Directory(entries) => {
-                                    serial_println!("DEBUG: TempFS::ensure_path_exists - Adding '{}' to parent's directory entries", dir_name);
-                                    entries.insert(dir_name.to_string(), inode);
-                                },
-                                _ => {
-                                    serial_println!("ERROR: TempFS::ensure_path_exists - Parent is not a directory!");
-                                    return Err(KernelError::NotADirectory);
-                                }
-                            }
-                        } else {
-                            // This should never happen with our step-by-step approach
-                            serial_println!("ERROR: TempFS::ensure_path_exists - Parent path not found: '{}'", parent_path);
-                            return Err(KernelError::GenericError("Parent path not found"));
-                        }
-                    }
+        kdebug!("tempfs", "TempFS::ensure_path_exists - Starting for path: '{}'", path);
+
+        let mut inode = self.root_inode;
+        for component in Path::new(path).canonical().components() {
+            let existing = match &self.inodes.get(&inode).ok_or(KernelError::NotFound)?.data {
+                NodeData::Directory(entries) => entries.get(component).map(|link| link.inode),
+                NodeData::File(_) => return Err(KernelError::NotADirectory),
+            };
+            inode = match existing {
+                Some(child) => child,
+                None => {
+                    kdebug!("tempfs", "TempFS::ensure_path_exists - Creating directory: '{}'", component);
+                    let node = TempFsNode::new(Metadata::new_directory(), NodeData::Directory(BTreeMap::new()));
+                    self.create_node(inode, component, node)?
                 }
-            } else {
-                serial_println!("DEBUG: TempFS::ensure_path_exists - Path '{}' already exists, skipping", current_path);
-            }
+            };
+        }
+
+        match self.inodes.get(&inode).map(|node| &node.data) {
+            Some(NodeData::Directory(_)) => Ok(()),
+            _ => Err(KernelError::NotADirectory),
+        }
+    }
+
+    /// Carry out one fix found by `check`
+    fn apply_repair(&mut self, fix: Repair) -> Result<(), KernelError> {
+        match fix {
+            Repair::DropEntry(dir, name) => {
+                if let Some(NodeData::Directory(entries)) = self.inodes.get_mut(&dir).map(|node| &mut node.data) {
+                    entries.remove(&name);
+                }
+            },
+            Repair::SetLinks(inode, count) => {
+                self.inodes.get_mut(&inode).ok_or(KernelError::NotFound)?.links = count;
+            },
+            Repair::SetSize(inode) => {
+                let (data, metadata) = self.file_mut(inode)?;
+                metadata.size = data.len() as u64;
+            },
+            Repair::Reattach(inode) => {
+                self.ensure_path_exists(LOST_AND_FOUND)?;
+                let lost_and_found = self.lookup(LOST_AND_FOUND).ok_or(KernelError::NotFound)?;
+                self.add_link(lost_and_found, &format!("#{}", inode), inode)?;
+                self.inodes.get_mut(&inode).ok_or(KernelError::NotFound)?.links = 1;
+            },
+            Repair::SetNextInode(next) => {
+                self.next_inode = next;
+            },
         }
-        
-        serial_println!("DEBUG: TempFS::ensure_path_exists - Path creation complete for: '{}'", canonical);
         Ok(())
     }
 
     /// Emergency direct directory creation - bypasses normal path handling
     /// SAFETY: This is only intended for initial filesystem setup
     pub fn direct_create_directory(&mut self, path: &str) -> Result<(), KernelError> {
-        serial_println!("DEBUG: TempFS::direct_create_directory - Creating: {}", path);
-        
-        // Don't try to create root
-        if path == "/" {
-            return Ok(());
-        }
-
-        // First normalize the path string but don't try to handle parents recursively
-        let normalized = path.to_string()
-            .trim_end_matches('/')
-            .to_string();
-            
-        let normalized = if !normalized.starts_with('/') {
-            format!("/{}", normalized)
-        } else {
-            normalized
-        };
-        
-        // Check if it already exists
-        if self.nodes.contains_key(&normalized) {
-            serial_println!("DEBUG: TempFS::direct_create_directory - Already exists: {}", normalized);
-            return Ok(());
-        }
-        
-        // Split the path to get parent and name components
-        let components: Vec<&str> = normalized.split('/')
-            .filter(|s| !s.is_empty())
-            .collect();
-            
-        if components.is_empty() {
-            return Ok(());
-        }
-        
-        // Create an inode for this directory
-        let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
-        
-        // Create a new directory node
-        let dir_node = TempFsNode {
-            inode,
-            metadata: Metadata::new_directory(),
-            data: NodeData::Directory(BTreeMap::new()),
-        };
-        
-        // Actually add the node to the filesystem
-        self.nodes.insert(normalized.clone(), dir_node);
-        serial_println!("DEBUG: TempFS::direct_create_directory - Created node: {}", normalized);
-        
-        // Now try to add to parent, but don't fail if we can't
-        let parent_path = components.iter()
-            .take(components.len() - 1)
-            .fold(String::new(), |mut path, component| {
-                path.push('/');
-                path.push_str(component);
-                path
-            });
-            
-        let parent_path = if parent_path.is_empty() { "/".to_string() } else { parent_path };
-        let dir_name = components.last().unwrap().to_string();
-        
-        serial_println!("DEBUG: TempFS::direct_create_directory - Finding parent: {} for dir: {}", parent_path, dir_name);
-        
-        // Only try to update parent if it exists
-        if let Some(parent_node) = self.nodes.get_mut(&parent_path) {
-            if let NodeData::Directory(entries) = &mut parent_node.data {
-                entries.insert(dir_name.clone(), inode);
-                serial_println!("DEBUG: TempFS::direct_create_directory - Added to parent entries");
-            }
-        } else {
-            serial_println!("DEBUG: TempFS::direct_create_directory - Parent not found, but continuing");
-        }
-        
-        Ok(())
+        kdebug!("tempfs", "TempFS::direct_create_directory - Creating: {}", path);
+        self.ensure_path_exists(path)
     }
 }
 
 impl FileSystem for TempFs {
     fn mount(&mut self) -> Result<(), KernelError> {
-        serial_println!("DEBUG: TempFS: Mounting '{}'", self.name);
+        kdebug!("tempfs", "TempFS: Mounting '{}'", self.name);
         Ok(())
     }
-    
+
     fn unmount(&mut self) -> Result<(), KernelError> {
-        serial_println!("DEBUG: TempFS: Unmounting '{}'", self.name);
+        kdebug!("tempfs", "TempFS: Unmounting '{}'", self.name);
         Ok(())
     }
-    
+
     fn create_file(&mut self, path: &str) -> Result<(), KernelError> {
-        let canonical = self.normalize_path_canonical(path);
-        serial_println!("DEBUG: TempFS: Creating file '{}'", canonical);
-        
-        // Check if the file already exists
-        if self.nodes.contains_key(&canonical) {
+        kdebug!("tempfs", "TempFS: Creating file '{}'", path);
+
+        if self.path_exists(path) {
             return Err(KernelError::AlreadyExists);
         }
-        
-        // Find the parent directory
-        let last_slash = canonical.rfind('/').unwrap();
-        let parent_path = if last_slash == 0 {
-            "/".to_string()
-        } else {
-            canonical[..last_slash].to_string()
-        };
-        
-        let file_name = &canonical[last_slash+1..];
-        
+
         // Create parent directories if needed
-        if !self.nodes.contains_key(&parent_path) {
-            self.ensure_path_exists(&parent_path)?;
-        }
-        
-        // Get the parent node
-        let parent_node = self.nodes.get_mut(&parent_path)
-            .ok_or(KernelError::NotFound)?;
-        
-        // Ensure the parent is a directory
-        match &mut parent_node.data {
-            NodeData::Directory(entries) => {
-                // Create a new inode
-                let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
-                
-                // Add to parent directory
-                entries.insert(file_name.to_string(), inode);
-                
-                // Create the file node
-                let file_node = TempFsNode {
-                    inode,
-                    metadata: Metadata::new_file(),
-                    data: NodeData::File(Vec::new()),
-                };
-                
-                // Add the file to our nodes
-                self.nodes.insert(canonical, file_node);
-                
-                Ok(())
-            },
-            _ => Err(KernelError::NotADirectory),
+        let canonical = Path::new(path).canonical();
+        if let Some(parent) = canonical.parent() {
+            self.ensure_path_exists(parent.as_str())?;
         }
+
+        let (parent, name) = self.parent_of(canonical.as_str())?;
+        self.create_node(parent, &name, TempFsNode::new(Metadata::new_file(), NodeData::File(Vec::new())))?;
+        Ok(())
     }
-    
+
     fn create_directory(&mut self, path: &str) -> Result<(), KernelError> {
-        serial_println!("DEBUG: TempFS: Creating directory '{}'", path);
+        kdebug!("tempfs", "TempFS: Creating directory '{}'", path);
         self.ensure_path_exists(path)
     }
-    
+
     fn remove(&mut self, path: &str) -> Result<(), KernelError> {
-        let canonical = self.normalize_path_canonical(path);
-        
-        // Can't remove root
-        if canonical == "/" {
-            return Err(KernelError::InvalidOperation);
-        }
-        
-        // Check if it exists
-        if !self.nodes.contains_key(&canonical) {
-            return Err(KernelError::NotFound);
-        }
-        
-        // Get parent path
-        let last_slash = canonical.rfind('/').unwrap();
-        let parent_path = if last_slash == 0 {
-            "/".to_string()
-        } else {
-            canonical[..last_slash].to_string()
-        };
-        
-        let name = &canonical[last_slash+1..];
-        
-        // Remove from parent directory
-        if let Some(parent) = self.nodes.get_mut(&parent_path) {
-            if let NodeData::Directory(entries) = &mut parent.data {
-                entries.remove(name);
+        let (parent, name) = self.parent_of(path)?;
+        let inode = self.lookup(path).ok_or(KernelError::NotFound)?;
+
+        if let Some(NodeData::Directory(entries)) = self.inodes.get(&inode).map(|node| &node.data) {
+            if !entries.is_empty() {
+                return Err(KernelError::DirectoryNotEmpty);
             }
         }
-        
-        // Remove the node itself
-        self.nodes.remove(&canonical);
-        
+
+        // Remove the name; the data goes with the last link and handle
+        if let Some(NodeData::Directory(entries)) = self.inodes.get_mut(&parent).map(|node| &mut node.data) {
+            entries.remove(&name);
+        }
+        if let Some(node) = self.inodes.get_mut(&inode) {
+            node.links = node.links.saturating_sub(1);
+        }
+        self.free_if_unused(inode);
+
+        Ok(())
+    }
+
+    fn link(&mut self, existing: &str, new: &str) -> Result<(), KernelError> {
+        let inode = self.lookup(existing).ok_or(KernelError::NotFound)?;
+        if let Some(NodeData::Directory(_)) = self.inodes.get(&inode).map(|node| &node.data) {
+            // Directory links would let the tree loop
+            return Err(KernelError::IsADirectory);
+        }
+
+        let (parent, name) = self.parent_of(new)?;
+        self.add_link(parent, &name, inode)?;
+        if let Some(node) = self.inodes.get_mut(&inode) {
+            node.links += 1;
+        }
         Ok(())
     }
-    
-    fn open(&self, path: &str, write: bool) -> Result<FileHandle, KernelError> {
-        let canonical = self.normalize_path_canonical(path);
-        
-        // Check if it exists
-        if !self.nodes.contains_key(&canonical) {
-            return Err(KernelError::NotFound);
-        }
-        
-        // Check if it's a file
-        let node = self.nodes.get(&canonical).unwrap();
-        match &node.data {
+
+    fn open(&mut self, path: &str, _write: bool) -> Result<Option<usize>, KernelError> {
+        let inode = self.lookup(path).ok_or(KernelError::NotFound)?;
+        let node = self.inodes.get_mut(&inode).ok_or(KernelError::NotFound)?;
+        match node.data {
             NodeData::File(_) => {
-                // Create file handle
-                let flags = if write { 
-                    crate::fs::vfs::file_flags::WRITE 
-                } else { 
-                    crate::fs::vfs::file_flags::READ 
-                };
-                
-                // Safe to unwrap because we just checked that the node exists
-                Ok(FileHandle::new(&canonical, Arc::new(Mutex::new(self.clone())), flags))
+                node.open_handles += 1;
+                Ok(Some(inode))
             },
-            _ => Err(KernelError::NotAFile),
+            NodeData::Directory(_) => Err(KernelError::NotAFile),
+        }
+    }
+
+    fn release(&mut self, inode: usize) {
+        if let Some(node) = self.inodes.get_mut(&inode) {
+            node.open_handles = node.open_handles.saturating_sub(1);
         }
+        self.free_if_unused(inode);
     }
-    
+
     fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
-        let canonical = self.normalize_path_canonical(path);
-        
-        // Find the node
-        let node = self.nodes.get(&canonical)
-            .ok_or(KernelError::NotFound)?;
-        
-        Ok(node.metadata.clone())
-    }
-    
-    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, KernelError> {
-        let canonical = self.normalize_path_canonical(path);
-        
-        // Find the node
-        let node = self.nodes.get(&canonical)
-            .ok_or(KernelError::NotFound)?;
-        
-        match &node.data {
-            NodeData::Directory(entries) => {
-                let mut result = Vec::new();
-                
-                for (name, &inode) in entries {
-                    // Find the child node
-                    for (npath, node) in &self.nodes {
-                        if node.inode == inode {
-                            let node_type = match &node.data {
-                                NodeData::File(_) => NodeType::File,
-                                NodeData::Directory(_) => NodeType::Directory,
-                            };
-                            
-                            result.push(DirEntry::new(name, node_type, inode));
-                            break;
-                        }
-                    }
-                }
-                
-                Ok(result)
-            },
-            _ => Err(KernelError::NotADirectory),
+        let node = self.node(path)?;
+        let mut metadata = node.metadata.clone();
+        metadata.links = node.links;
+        Ok(metadata)
+    }
+
+    fn set_metadata(&mut self, path: &str, update: MetadataUpdate) -> Result<(), KernelError> {
+        let inode = self.lookup(path).ok_or(KernelError::NotFound)?;
+        let node = self.inodes.get_mut(&inode).ok_or(KernelError::NotFound)?;
+        update.apply(&mut node.metadata);
+        Ok(())
+    }
+
+    fn read_dir_from(&self, path: &str, cursor: usize, out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError> {
+        let entries = match &self.node(path)?.data {
+            NodeData::Directory(entries) => entries,
+            _ => return Err(KernelError::NotADirectory),
+        };
+        if out.is_empty() {
+            return Ok((0, Some(cursor)));
+        }
+
+        // Entries are returned in the order they were added and the cursor is
+        // the next order number wanted. New entries always get higher
+        // numbers, so creates and removes never make the walk repeat or skip one.
+        let mut page: Vec<(usize, &String, usize)> = Vec::with_capacity(out.len() + 1);
+        for (name, link) in entries {
+            if link.order < cursor {
+                continue;
+            }
+            let position = page.partition_point(|(order, _, _)| *order < link.order);
+            if position < out.len() {
+                page.insert(position, (link.order, name, link.inode));
+                page.truncate(out.len());
+            }
         }
+
+        for (slot, (_, name, inode)) in out.iter_mut().zip(&page) {
+            let node_type = match self.inodes.get(inode).map(|node| &node.data) {
+                Some(NodeData::Directory(_)) => NodeType::Directory,
+                _ => NodeType::File,
+            };
+            *slot = Some(DirEntry::new(name, node_type, *inode));
+        }
+
+        let next = if page.len() == out.len() {
+            page.last().map(|(order, _, _)| order + 1)
+        } else {
+            None
+        };
+        Ok((page.len(), next))
     }
-    
+
     fn rename(&mut self, from: &str, to: &str) -> Result<(), KernelError> {
-        // Not implemented yet
-        Err(KernelError::NotImplemented)
+        let inode = self.lookup(from).ok_or(KernelError::NotFound)?;
+        let (old_parent, old_name) = self.parent_of(from)?;
+        let (new_parent, new_name) = self.parent_of(to)?;
+        if !matches!(self.inodes.get(&new_parent).map(|node| &node.data), Some(NodeData::Directory(_))) {
+            return Err(KernelError::NotADirectory);
+        }
+
+        let moving_directory = matches!(self.inodes.get(&inode).map(|node| &node.data), Some(NodeData::Directory(_)));
+        if moving_directory {
+            // A directory can't move below itself
+            let (from, to) = (Path::new(from).canonical(), Path::new(to).canonical());
+            if from != to && to.starts_with(&from) {
+                return Err(KernelError::InvalidOperation);
+            }
+        }
+
+        // Whatever `to` names now loses that name in the same step, so the
+        // path always names either the old node or the new one
+        let target = self.lookup(to);
+        if let Some(target) = target {
+            if target == inode {
+                // Both names are links to one node already
+                return Ok(());
+            }
+            match (self.inodes.get(&target).map(|node| &node.data), moving_directory) {
+                (Some(NodeData::Directory(entries)), true) if !entries.is_empty() => {
+                    return Err(KernelError::DirectoryNotEmpty);
+                },
+                (Some(NodeData::Directory(_)), false) => return Err(KernelError::IsADirectory),
+                (Some(NodeData::File(_)), true) => return Err(KernelError::NotADirectory),
+                _ => {},
+            }
+        }
+
+        for (parent, name) in [(old_parent, &old_name), (new_parent, &new_name)] {
+            if let Some(NodeData::Directory(entries)) = self.inodes.get_mut(&parent).map(|node| &mut node.data) {
+                entries.remove(name);
+            }
+        }
+        self.add_link(new_parent, &new_name, inode)?;
+        if let Some(target) = target {
+            if let Some(node) = self.inodes.get_mut(&target) {
+                node.links = node.links.saturating_sub(1);
+            }
+            self.free_if_unused(target);
+        }
+        Ok(())
     }
-    
+
     fn name(&self) -> &str {
         &self.name
     }
-    
+
+    fn volume_label(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
     fn total_space(&self) -> u64 {
-        1024 * 1024 * 10 // 10 MB
+        self.capacity
     }
-    
+
     fn available_space(&self) -> u64 {
-        1024 * 1024 * 10 // Pretend we have all space available
+        self.capacity.saturating_sub(self.used_space())
     }
-    
+
     fn read_at(&self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
-        let canonical = self.normalize_path_canonical(path);
-        
-        // Find the node
-        let node = self.nodes.get(&canonical)
-            .ok_or(KernelError::NotFound)?;
-        
-        match &node.data {
-            NodeData::File(data) => {
-                let offset = offset as usize;
-                
-                // Check if we're at EOF
-                if offset >= data.len() {
-                    return Ok(0);
-                }
-                
-                // Calculate how many bytes we can read
-                let bytes_to_read = core::cmp::min(buffer.len(), data.len() - offset);
-                
-                // Copy the data
-                buffer[..bytes_to_read].copy_from_slice(&data[offset..offset + bytes_to_read]);
-                
-                Ok(bytes_to_read)
-            },
-            _ => Err(KernelError::NotAFile),
-        }
+        let inode = self.lookup(path).ok_or(KernelError::NotFound)?;
+        self.read_inode_at(inode, offset, buffer)
     }
-    
+
     fn write_at(&mut self, path: &str, offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
-        let canonical = self.normalize_path_canonical(path);
-        
-        // Find the node
-        let node = self.nodes.get_mut(&canonical)
-            .ok_or(KernelError::NotFound)?;
-        
-        match &mut node.data {
-            NodeData::File(data) => {
-                let offset = offset as usize;
-                
-                // Ensure the file is big enough
-                if offset > data.len() {
-                    // Pad with zeros
-                    data.resize(offset, 0);
+        let inode = self.lookup(path).ok_or(KernelError::NotFound)?;
+        self.write_inode_at(inode, offset, buffer)
+    }
+
+    fn truncate(&mut self, path: &str, length: u64) -> Result<(), KernelError> {
+        let inode = self.lookup(path).ok_or(KernelError::NotFound)?;
+        self.truncate_inode(inode, length)
+    }
+
+    fn read_inode_at(&self, inode: usize, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        let data = self.file_data(inode)?;
+        let offset = offset as usize;
+
+        // Check if we're at EOF
+        if offset >= data.len() {
+            return Ok(0);
+        }
+
+        // Calculate how many bytes we can read
+        let bytes_to_read = core::cmp::min(buffer.len(), data.len() - offset);
+
+        // Copy the data
+        buffer[..bytes_to_read].copy_from_slice(&data[offset..offset + bytes_to_read]);
+
+        Ok(bytes_to_read)
+    }
+
+    fn write_inode_at(&mut self, inode: usize, offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
+        crate::faultinject::TEMPFS_WRITE.check()?;
+        let _tag = crate::allocator::tag("tempfs");
+        let (data, metadata) = self.file_mut(inode)?;
+        let offset = offset as usize;
+
+        // Grow once for the whole write; only a gap past the old end is
+        // zeroed, the rest is copied straight in
+        let end = offset.checked_add(buffer.len()).ok_or(KernelError::InvalidParameter)?;
+        if end > data.len() {
+            data.reserve(end - data.len());
+            if offset > data.len() {
+                data.resize(offset, 0);
+            }
+        }
+        let overwritten = data.len().saturating_sub(offset).min(buffer.len());
+        data[offset..offset + overwritten].copy_from_slice(&buffer[..overwritten]);
+        data.extend_from_slice(&buffer[overwritten..]);
+
+        // Update metadata
+        metadata.size = data.len() as u64;
+
+        Ok(buffer.len())
+    }
+
+    fn truncate_inode(&mut self, inode: usize, length: u64) -> Result<(), KernelError> {
+        let _tag = crate::allocator::tag("tempfs");
+        let (data, metadata) = self.file_mut(inode)?;
+
+        // Growing fills the new bytes with zeros
+        data.resize(length as usize, 0);
+        metadata.size = length;
+        Ok(())
+    }
+
+    fn check(&mut self, repair: bool) -> Result<CheckReport, KernelError> {
+        let mut report = CheckReport::default();
+        if !matches!(self.inodes.get(&self.root_inode).map(|node| &node.data), Some(NodeData::Directory(_))) {
+            // Nothing else can be trusted without a root directory
+            report.problems.push(format!("root inode {} is missing or not a directory", self.root_inode));
+            return Ok(report);
+        }
+
+        // Count the names each node has, walking down from the root. The
+        // root is named by its mount.
+        let mut names: BTreeMap<usize, usize> = BTreeMap::new();
+        names.insert(self.root_inode, 1);
+        let mut reached = BTreeSet::from([self.root_inode]);
+        let mut pending = vec![self.root_inode];
+        let mut repairs = Vec::new();
+        while let Some(dir) = pending.pop() {
+            let entries = match self.inodes.get(&dir).map(|node| &node.data) {
+                Some(NodeData::Directory(entries)) => entries,
+                _ => continue,
+            };
+            for (name, link) in entries {
+                let node = match self.inodes.get(&link.inode) {
+                    Some(node) => node,
+                    None => {
+                        report.problems.push(format!("entry '{}' in directory inode {} names missing inode {}",
+                            name, dir, link.inode));
+                        repairs.push(Repair::DropEntry(dir, name.clone()));
+                        continue;
+                    }
+                };
+                *names.entry(link.inode).or_insert(0) += 1;
+                if reached.insert(link.inode) && matches!(node.data, NodeData::Directory(_)) {
+                    pending.push(link.inode);
                 }
-                
-                // Write the data
-                for (i, &byte) in buffer.iter().enumerate() {
-                    if offset + i < data.len() {
-                        data[offset + i] = byte;
-                    } else {
-                        data.push(byte);
+            }
+        }
+
+        // Fixed ahead of anything that might allocate an inode
+        if let Some(&last) = self.inodes.keys().next_back() {
+            if last >= self.next_inode {
+                report.problems.push(format!("inode {} is in use but the next free inode is {}",
+                    last, self.next_inode));
+                repairs.push(Repair::SetNextInode(last + 1));
+            }
+        }
+
+        // Nodes named from inside unreachable directories come back with them
+        let mut named_by_orphans = BTreeSet::new();
+        for (inode, node) in &self.inodes {
+            if let NodeData::Directory(entries) = &node.data {
+                if !reached.contains(inode) {
+                    named_by_orphans.extend(entries.values().map(|link| link.inode).filter(|child| child != inode));
+                }
+            }
+        }
+
+        for (&inode, node) in &self.inodes {
+            match names.get(&inode) {
+                Some(&count) => {
+                    if count > 1 && matches!(node.data, NodeData::Directory(_)) {
+                        report.problems.push(format!("directory inode {} has {} names", inode, count));
+                    }
+                    if node.links != count {
+                        report.problems.push(format!("inode {} has link count {} but {} names",
+                            inode, node.links, count));
+                        repairs.push(Repair::SetLinks(inode, count));
                     }
+                },
+                // Unlinked but still open is normal; the last close frees it
+                None if node.links == 0 && node.open_handles > 0 => {},
+                None if !named_by_orphans.contains(&inode) => {
+                    report.problems.push(format!("inode {} is not reachable from /", inode));
+                    repairs.push(Repair::Reattach(inode));
+                },
+                None => {},
+            }
+            if let NodeData::File(data) = &node.data {
+                if node.metadata.size != data.len() as u64 {
+                    report.problems.push(format!("inode {} has size {} but {} bytes of data",
+                        inode, node.metadata.size, data.len()));
+                    repairs.push(Repair::SetSize(inode));
                 }
-                
-                // Update metadata
-                node.metadata.size = data.len() as u64;
-                
-                Ok(buffer.len())
-            },
-            _ => Err(KernelError::NotAFile),
+            }
         }
+
+        if repair {
+            for fix in repairs {
+                if self.apply_repair(fix).is_ok() {
+                    report.repaired += 1;
+                }
+            }
+        }
+        Ok(report)
     }
-    
+
     fn is_tempfs(&self) -> bool {
         true
     }
@@ -558,11 +659,134 @@ pub fn as_tempfs(fs: &mut dyn FileSystem) -> Option<&mut TempFs> {
     }
 }
 
-// Add basic Clone implementation for TempFs
-impl Clone for TempFs {
-    fn clone(&self) -> Self {
-        // This is a simplistic clone that would create a new empty filesystem
-        // In a real implementation, we'd clone all the nodes too
-        Self::new(&self.name)
+/// Check link counting on a private TempFs, then page through a directory
+/// while files are created and removed, checking nothing comes back twice
+/// and no survivor is skipped. Then check renames and writes running past
+/// the end of a file, and last corrupt a TempFs behind its back and check
+/// `check` finds and repairs each problem.
+pub fn self_test() -> Result<(), KernelError> {
+    const FILES: usize = 40;
+    serial_println!("TEMPFS: Running self-test");
+
+    let mut fs = TempFs::new("selftest");
+
+    // Two names for one file; the data outlives the first name and, while a
+    // handle is open, the second
+    fs.create_file("/a")?;
+    fs.write_at("/a", 0, b"linked")?;
+    fs.link("/a", "/b")?;
+    if fs.metadata("/b")?.links != 2 || fs.link("/a", "/b").is_ok() {
+        return Err(KernelError::ValidationError("Hard link not counted"));
+    }
+    fs.remove("/a")?;
+    let inode = fs.open("/b", false)?.ok_or(KernelError::ValidationError("Open gave no inode"))?;
+    fs.remove("/b")?;
+    let mut buffer = [0u8; 8];
+    let count = fs.read_inode_at(inode, 0, &mut buffer)?;
+    if buffer[..count] != *b"linked" || fs.path_exists("/b") {
+        return Err(KernelError::ValidationError("Unlinked file lost its data while open"));
+    }
+    fs.release(inode);
+    if fs.read_inode_at(inode, 0, &mut buffer).is_ok() {
+        return Err(KernelError::ValidationError("Unlinked file kept after its last handle closed"));
+    }
+
+    for i in 0..FILES {
+        fs.create_file(&format!("/f{}", i))?;
+    }
+
+    let mut seen: Vec<String> = Vec::new();
+    let mut page: [Option<DirEntry>; 16] = core::array::from_fn(|_| None);
+    let mut cursor = Some(0);
+    let mut pages = 0;
+    while let Some(start) = cursor {
+        let (filled, next) = fs.read_dir_from("/", start, &mut page)?;
+        seen.extend(page[..filled].iter_mut().filter_map(Option::take).map(|entry| entry.name));
+        cursor = next;
+        pages += 1;
+
+        // Between the first and second pages, remove a file already listed
+        // and one not yet reached, and add a new one
+        if pages == 1 {
+            fs.remove("/f0")?;
+            fs.remove("/f30")?;
+            fs.create_file("/late")?;
+        }
+    }
+
+    let mut sorted = seen.clone();
+    sorted.sort();
+    sorted.dedup();
+    if sorted.len() != seen.len() {
+        return Err(KernelError::ValidationError("Directory paging returned an entry twice"));
+    }
+    let missing = (1..FILES)
+        .filter(|i| *i != 30)
+        .any(|i| !seen.iter().any(|name| *name == format!("f{}", i)));
+    if missing || seen.iter().any(|name| name == "f30") || pages < 3 {
+        serial_println!("TEMPFS: Paged {} entries in {} pages", seen.len(), pages);
+        return Err(KernelError::ValidationError("Directory paging skipped or kept the wrong entries"));
+    }
+
+    // Renaming over a file replaces it; a directory can't move into itself
+    fs.create_file("/old")?;
+    fs.write_at("/old", 0, b"new contents")?;
+    fs.create_directory("/dir/sub")?;
+    fs.rename("/old", "/f1")?;
+    let mut buffer = [0u8; 16];
+    let count = fs.read_at("/f1", 0, &mut buffer)?;
+    if buffer[..count] != *b"new contents" || fs.path_exists("/old") || fs.metadata("/f1")?.links != 1 {
+        return Err(KernelError::ValidationError("Rename did not replace the target"));
+    }
+    if fs.rename("/dir", "/dir/sub/inside").is_ok() || fs.rename("/f1", "/dir").is_ok() {
+        return Err(KernelError::ValidationError("Rename allowed an impossible move"));
+    }
+    fs.rename("/dir", "/moved")?;
+    if !fs.path_exists("/moved/sub") || fs.path_exists("/dir") || !fs.check(false)?.is_clean() {
+        return Err(KernelError::ValidationError("Directory rename lost its contents"));
+    }
+
+    // One write overlapping the end, one leaving a gap to be zeroed
+    fs.create_file("/spliced")?;
+    fs.write_at("/spliced", 0, b"abcdef")?;
+    fs.write_at("/spliced", 4, b"XYZ")?;
+    fs.write_at("/spliced", 9, b"!")?;
+    let mut spliced = [0xFFu8; 12];
+    let count = fs.read_at("/spliced", 0, &mut spliced)?;
+    if spliced[..count] != *b"abcdXYZ\0\0!" || fs.metadata("/spliced")?.size != 10 {
+        return Err(KernelError::ValidationError("Write past the end of a file came back wrong"));
+    }
+
+    let mut fs = TempFs::new("fsck");
+    fs.create_file("/sized")?;
+    fs.write_at("/sized", 0, b"abc")?;
+    fs.create_file("/linked")?;
+    if !fs.check(false)?.is_clean() {
+        return Err(KernelError::ValidationError("Check found problems in a sound file system"));
     }
-}
\ No newline at end of file
+
+    let sized = fs.lookup("/sized").ok_or(KernelError::NotFound)?;
+    let linked = fs.lookup("/linked").ok_or(KernelError::NotFound)?;
+    fs.inodes.get_mut(&sized).ok_or(KernelError::NotFound)?.metadata.size = 99;
+    fs.inodes.get_mut(&linked).ok_or(KernelError::NotFound)?.links = 3;
+    fs.add_link(fs.root_inode, "ghost", 999)?;
+    let orphan = fs.next_inode;
+    fs.inodes.insert(orphan, TempFsNode::new(Metadata::new_file(), NodeData::File(Vec::new())));
+    fs.next_inode += 1;
+
+    let report = fs.check(false)?;
+    if report.problems.len() != 4 || report.repaired != 0 {
+        for problem in &report.problems {
+            serial_println!("TEMPFS: check: {}", problem);
+        }
+        return Err(KernelError::ValidationError("Check missed or invented problems"));
+    }
+    let report = fs.check(true)?;
+    if report.repaired != 4 || !fs.check(false)?.is_clean()
+        || fs.metadata("/sized")?.size != 3 || !fs.path_exists(&format!("{}/#{}", LOST_AND_FOUND, orphan)) {
+        return Err(KernelError::ValidationError("Check did not repair the file system"));
+    }
+
+    serial_println!("TEMPFS: Self-test passed");
+    Ok(())
+}
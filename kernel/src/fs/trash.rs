@@ -0,0 +1,314 @@
+//! Trash for removed files
+//!
+//! With `fs.use_trash` set, removing anything inside a home under /Users
+//! moves it into that home's .Trash as `<timestamp>-<name>` instead of
+//! deleting it, and the trash's index file remembers where it came from so
+//! it can be put back. Removing something that is already in a trash
+//! deletes it for good, as do `rm --purge` and `trash empty`.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::config;
+use crate::errors::KernelError;
+use crate::serial_println;
+use crate::user;
+use super::vfs::{self, file_flags, NodeType, VfsManager};
+use super::walk::join;
+
+/// Config key that turns the trash on
+pub const CONFIG_KEY: &str = "fs.use_trash";
+/// Trash directory name, inside a home
+pub const TRASH_DIR: &str = ".Trash";
+/// File in the trash listing what is there and where it came from
+const INDEX_FILE: &str = ".index";
+/// Homes with a trash live here
+const USERS_DIR: &str = "/Users/";
+
+/// Something in the trash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    /// Name inside the trash
+    pub name: String,
+    /// Where it was removed from
+    pub original: String,
+}
+
+/// Whether removed files go to the trash
+pub fn enabled() -> bool {
+    config::get(CONFIG_KEY).and_then(|value| value.try_as_boolean()).unwrap_or(false)
+}
+
+/// The home under /Users that `path` is in, and the rest of the path
+fn split_home(path: &str) -> Option<(&str, &str)> {
+    let (user, inside) = path.strip_prefix(USERS_DIR)?.split_once('/')?;
+    (!user.is_empty()).then_some((user, inside.trim_end_matches('/')))
+}
+
+/// The trash removing `path` would move it to: its home's, if it is in a
+/// home under /Users, isn't the home itself and isn't in the trash already
+pub fn trash_for(path: &str) -> Option<String> {
+    let (user, inside) = split_home(path)?;
+    if inside.is_empty() || inside.split('/').next() == Some(TRASH_DIR) {
+        return None;
+    }
+    Some(format!("{}{}/{}", USERS_DIR, user, TRASH_DIR))
+}
+
+/// The trash `path` is in, if it is inside one
+fn containing_trash(path: &str) -> Option<String> {
+    let (user, inside) = split_home(path)?;
+    let rest = inside.strip_prefix(TRASH_DIR)?;
+    (rest.is_empty() || rest.starts_with('/')).then(|| format!("{}{}/{}", USERS_DIR, user, TRASH_DIR))
+}
+
+/// The current user's trash, if their home is under /Users
+pub fn user_trash() -> Option<String> {
+    let home = user::home_dir();
+    split_home(&format!("{}/", home)).map(|_| format!("{}/{}", home, TRASH_DIR))
+}
+
+/// Read all of a small file
+fn read_text(vfs: &VfsManager, path: &str) -> Result<String, KernelError> {
+    let mut handle = vfs.open(path, file_flags::READ)?;
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 512];
+    let result = loop {
+        match handle.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(count) => data.extend_from_slice(&buffer[..count]),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = handle.close();
+    result?;
+    String::from_utf8(data).map_err(|_| KernelError::InvalidData)
+}
+
+/// The index of `trash`, oldest first; a missing index is an empty one
+fn read_index(vfs: &VfsManager, trash: &str) -> Result<Vec<TrashEntry>, KernelError> {
+    let text = match read_text(vfs, &join(trash, INDEX_FILE)) {
+        Ok(text) => text,
+        Err(KernelError::NotFound) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(text.lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, original)| TrashEntry { name: name.to_string(), original: original.to_string() })
+        .collect())
+}
+
+fn write_index(vfs: &VfsManager, trash: &str, entries: &[TrashEntry]) -> Result<(), KernelError> {
+    let text: String = entries.iter().map(|entry| format!("{}\t{}\n", entry.name, entry.original)).collect();
+    vfs.write_file_atomic(&join(trash, INDEX_FILE), text.as_bytes())
+}
+
+/// A name for `name` removed at `stamp` that nothing in `trash` has yet
+fn free_name(vfs: &VfsManager, trash: &str, stamp: u64, name: &str) -> String {
+    let mut candidate = format!("{}-{}", stamp, name);
+    let mut copy = 1;
+    while vfs.metadata(&join(trash, &candidate)).is_ok() {
+        copy += 1;
+        candidate = format!("{}.{}-{}", stamp, copy, name);
+    }
+    candidate
+}
+
+/// Delete a file or a whole tree for good
+pub fn remove_tree(vfs: &VfsManager, path: &str) -> Result<(), KernelError> {
+    if vfs.metadata(path)?.node_type == NodeType::Directory {
+        for entry in vfs.read_dir(path)? {
+            if entry.name != "." && entry.name != ".." {
+                remove_tree(vfs, &join(path, &entry.name))?;
+            }
+        }
+    }
+    vfs.remove_permanently(path)
+}
+
+/// Move `from` to `to`, copying and deleting if they are on different
+/// file systems
+fn move_tree(vfs: &VfsManager, from: &str, to: &str) -> Result<(), KernelError> {
+    match vfs.rename(from, to) {
+        Err(KernelError::NotImplemented) => {
+            vfs.copy_tree(from, to)?;
+            remove_tree(vfs, from)
+        }
+        result => result,
+    }
+}
+
+/// Move `path` into `trash` and record where it came from; returns its
+/// name in the trash
+pub fn move_to_trash(vfs: &VfsManager, path: &str, trash: &str) -> Result<String, KernelError> {
+    vfs.metadata(path)?;
+    match vfs.metadata(trash) {
+        Ok(_) => {}
+        Err(KernelError::NotFound) => vfs.create_directory(trash)?,
+        Err(e) => return Err(e),
+    }
+    let path = path.trim_end_matches('/');
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let trashed = free_name(vfs, trash, crate::time::wall_clock(), name);
+    move_tree(vfs, path, &join(trash, &trashed))?;
+
+    let mut index = read_index(vfs, trash)?;
+    index.push(TrashEntry { name: trashed.clone(), original: path.to_string() });
+    write_index(vfs, trash, &index)?;
+    serial_println!("TRASH: {} -> {}/{}", path, trash, trashed);
+    Ok(trashed)
+}
+
+/// Delete `path` for good. In a trash that is the whole tree, and the
+/// index forgets it; anywhere else it is a plain file or empty directory.
+pub fn purge(vfs: &VfsManager, path: &str) -> Result<(), KernelError> {
+    let path = path.trim_end_matches('/');
+    let Some(trash) = containing_trash(path) else {
+        return vfs.remove_permanently(path);
+    };
+    remove_tree(vfs, path)?;
+    if path == trash {
+        return Ok(());
+    }
+    let mut index = read_index(vfs, &trash)?;
+    let before = index.len();
+    index.retain(|entry| join(&trash, &entry.name) != path);
+    if index.len() != before {
+        write_index(vfs, &trash, &index)?;
+    }
+    Ok(())
+}
+
+/// Remove `path` the way `VfsManager::remove` does with the trash on:
+/// moved to its home's trash, deleted for good if it is in a trash
+/// already. Returns None if the trash has nothing to do with it.
+pub fn remove(vfs: &VfsManager, path: &str) -> Option<Result<(), KernelError>> {
+    if let Some(trash) = trash_for(path) {
+        return Some(move_to_trash(vfs, path, &trash).map(drop));
+    }
+    containing_trash(path).map(|_| purge(vfs, path))
+}
+
+/// What is in `trash`, oldest first. Entries whose files are gone are
+/// left out.
+pub fn list(trash: &str) -> Result<Vec<TrashEntry>, KernelError> {
+    let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let mut index = read_index(vfs, trash)?;
+    index.retain(|entry| vfs.metadata(&join(trash, &entry.name)).is_ok());
+    Ok(index)
+}
+
+/// Put entry `name` of `trash` back where it was removed from; returns
+/// that path. Fails with AlreadyExists if something has taken its place.
+pub fn restore(trash: &str, name: &str) -> Result<String, KernelError> {
+    let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let mut index = read_index(vfs, trash)?;
+    let position = index.iter().position(|entry| entry.name == name).ok_or(KernelError::NotFound)?;
+    let original = index[position].original.clone();
+    if vfs.metadata(&original).is_ok() {
+        return Err(KernelError::AlreadyExists);
+    }
+    move_tree(vfs, &join(trash, name), &original)?;
+    index.remove(position);
+    write_index(vfs, trash, &index)?;
+    serial_println!("TRASH: Restored {}", original);
+    Ok(original)
+}
+
+/// Delete everything in `trash` for good; returns how many entries went
+pub fn empty(trash: &str) -> Result<usize, KernelError> {
+    let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let mut emptied = 0;
+    for entry in vfs.read_dir(trash)? {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        remove_tree(vfs, &join(trash, &entry.name))?;
+        if entry.name != INDEX_FILE {
+            emptied += 1;
+        }
+    }
+    Ok(emptied)
+}
+
+/// Trash a file twice under the same name, a directory tree, and then
+/// something already in the trash, in a scratch home under /Users
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("TRASH: Running self-test");
+
+    if trash_for("/Users/ann/notes.txt").as_deref() != Some("/Users/ann/.Trash")
+        || trash_for("/Users/ann").is_some() || trash_for("/Users/ann/").is_some()
+        || trash_for("/Users/ann/.Trash/1-notes.txt").is_some() || trash_for("/tmp/notes.txt").is_some()
+        || containing_trash("/Users/ann/.Trash/1-x").as_deref() != Some("/Users/ann/.Trash")
+        || containing_trash("/Users/ann/.Trashy").is_some() {
+        return Err(KernelError::ValidationError("Trash chosen wrongly"));
+    }
+
+    let vfs = match vfs::get_vfs_manager() {
+        Some(vfs) => vfs,
+        None => {
+            serial_println!("TRASH: Self-test passed (no file system)");
+            return Ok(());
+        }
+    };
+
+    let home = "/Users/trash-selftest";
+    let trash = "/Users/trash-selftest/.Trash";
+    let file = "/Users/trash-selftest/notes.txt";
+    let dir = "/Users/trash-selftest/project";
+    let inner = "/Users/trash-selftest/project/main.rs";
+    let result = (|| {
+        vfs.create_directory(home)?;
+
+        // The same name twice gets two entries
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            vfs.create_file(file)?;
+            names.push(move_to_trash(vfs, file, trash)?);
+        }
+        if names[0] == names[1] || vfs.metadata(file).is_ok() || list(trash)?.len() != 2 {
+            return Err(KernelError::ValidationError("Trashed names collided"));
+        }
+
+        // A tree goes whole and comes back whole
+        vfs.create_directory(dir)?;
+        vfs.create_file(inner)?;
+        vfs.write_at(inner, 0, b"fn main() {}")?;
+        let project = move_to_trash(vfs, dir, trash)?;
+        if vfs.metadata(dir).is_ok() || vfs.metadata(&format!("{}/{}/main.rs", trash, project)).is_err() {
+            return Err(KernelError::ValidationError("Directory tree not trashed whole"));
+        }
+        if restore(trash, &project)? != dir || read_text(vfs, inner)? != "fn main() {}" {
+            return Err(KernelError::ValidationError("Directory tree not restored whole"));
+        }
+
+        // Something restored over an existing file is refused
+        vfs.create_file(file)?;
+        if !matches!(restore(trash, &names[0]), Err(KernelError::AlreadyExists)) {
+            return Err(KernelError::ValidationError("Restore overwrote a file"));
+        }
+
+        // Removing from the trash deletes for good and forgets the entry
+        match remove(vfs, &join(trash, &names[0])) {
+            Some(Ok(())) => {}
+            _ => return Err(KernelError::ValidationError("Trashed file not purged")),
+        }
+        let left: Vec<String> = list(trash)?.into_iter().map(|entry| entry.name).collect();
+        if left != [names[1].clone()] || vfs.metadata(&join(trash, &names[0])).is_ok() {
+            return Err(KernelError::ValidationError("Purged entry still in the trash"));
+        }
+
+        if empty(trash)? != 1 || !list(trash)?.is_empty() {
+            return Err(KernelError::ValidationError("Trash not emptied"));
+        }
+        Ok(())
+    })();
+
+    if vfs.metadata(home).is_ok() {
+        let _ = remove_tree(vfs, home);
+    }
+
+    result?;
+    serial_println!("TRASH: Self-test passed");
+    Ok(())
+}
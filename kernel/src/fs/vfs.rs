@@ -1,26 +1,58 @@
 use crate::errors::KernelError;
+use alloc::format;
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
-use spin::Mutex;
+use crate::sync::DiagMutex;
 use core::fmt;
+use crate::kdebug;
 use crate::serial_println;
+use super::path::Path;
+use super::pipe::PipeEnd;
+use super::trash;
+use super::watch::{self, WatchHandle, WatchKind};
 
-/// File permissions bitflags
+/// File permissions bitflags. The owner's bits are the lowest three, then
+/// the group's, then everyone else's.
 pub mod permissions {
-    pub const READ: u8 = 0b0000_0100;
-    pub const WRITE: u8 = 0b0000_0010;
-    pub const EXECUTE: u8 = 0b0000_0001;
-    pub const OWNER_ALL: u8 = 0b0000_0111;
-    pub const GROUP_READ: u8 = 0b0000_0100 << 3;
-    pub const GROUP_WRITE: u8 = 0b0000_0010 << 3;
-    pub const GROUP_EXEC: u8 = 0b0000_0001 << 3;
-    pub const GROUP_ALL: u8 = 0b0000_0111 << 3;
-    pub const OTHERS_READ: u8 = 0b0000_0100 << 6;
-    pub const OTHERS_WRITE: u8 = 0b0000_0010 << 6;
-    pub const OTHERS_EXEC: u8 = 0b0000_0001 << 6;
-    pub const OTHERS_ALL: u8 = 0b0000_0111 << 6;
-    pub const ALL: u8 = 0b1111_1111;
+    pub const READ: u16 = 0b0000_0100;
+    pub const WRITE: u16 = 0b0000_0010;
+    pub const EXECUTE: u16 = 0b0000_0001;
+    pub const OWNER_ALL: u16 = 0b0000_0111;
+    pub const GROUP_READ: u16 = 0b0000_0100 << 3;
+    pub const GROUP_WRITE: u16 = 0b0000_0010 << 3;
+    pub const GROUP_EXEC: u16 = 0b0000_0001 << 3;
+    pub const GROUP_ALL: u16 = 0b0000_0111 << 3;
+    pub const OTHERS_READ: u16 = 0b0000_0100 << 6;
+    pub const OTHERS_WRITE: u16 = 0b0000_0010 << 6;
+    pub const OTHERS_EXEC: u16 = 0b0000_0001 << 6;
+    pub const OTHERS_ALL: u16 = 0b0000_0111 << 6;
+    pub const ALL: u16 = 0b1_1111_1111;
+    /// The write bit of every class
+    pub const ALL_WRITE: u16 = WRITE | GROUP_WRITE | OTHERS_WRITE;
+    
+    /// Parse an octal mode such as "644", owner digit first
+    pub fn parse_octal(mode: &str) -> Option<u16> {
+        let value = u16::from_str_radix(mode, 8).ok()?;
+        // Owner rwx is the top digit of an octal mode but the low bits here
+        let (owner, group, others) = ((value >> 6) & 7, (value >> 3) & 7, value & 7);
+        (value <= 0o777).then(|| owner | group << 3 | others << 6)
+    }
+    
+    /// The octal form `parse_octal` reads
+    pub fn to_octal(permissions: u16) -> u16 {
+        (permissions & 7) << 6 | (permissions >> 3 & 7) << 3 | (permissions >> 6 & 7)
+    }
+}
+
+/// DOS-style attribute flags, for file systems that keep them; the rest
+/// leave them all clear
+pub mod attributes {
+    pub const READ_ONLY: u8 = 0b0001;
+    pub const HIDDEN: u8 = 0b0010;
+    pub const SYSTEM: u8 = 0b0100;
+    pub const ARCHIVE: u8 = 0b1000;
 }
 
 /// Types of file system nodes
@@ -35,12 +67,33 @@ pub enum NodeType {
     Socket,
 }
 
+/// Problems found by `FileSystem::check`
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// One line per problem, as found before any repair
+    pub problems: Vec<String>,
+    /// How many of the problems were fixed
+    pub repaired: usize,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
 /// File system metadata
 #[derive(Debug, Clone)]
 pub struct Metadata {
     pub node_type: NodeType,
     pub size: u64,
-    pub permissions: u8,
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// `attributes` flags
+    pub attributes: u8,
+    /// Directory entries naming the node
+    pub links: usize,
     pub created_at: u64,
     pub modified_at: u64,
     pub accessed_at: u64,
@@ -52,6 +105,10 @@ impl Metadata {
             node_type: NodeType::File,
             size: 0,
             permissions: permissions::OWNER_ALL | permissions::GROUP_READ | permissions::OTHERS_READ,
+            uid: 0,
+            gid: 0,
+            attributes: 0,
+            links: 1,
             created_at: 0,
             modified_at: 0,
             accessed_at: 0,
@@ -63,6 +120,10 @@ impl Metadata {
             node_type: NodeType::Directory,
             size: 0,
             permissions: permissions::OWNER_ALL | permissions::GROUP_ALL | permissions::OTHERS_READ | permissions::OTHERS_EXEC,
+            uid: 0,
+            gid: 0,
+            attributes: 0,
+            links: 1,
             created_at: 0,
             modified_at: 0,
             accessed_at: 0,
@@ -70,6 +131,118 @@ impl Metadata {
     }
 }
 
+/// Changes for `FileSystem::set_metadata`; None leaves a field alone
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetadataUpdate {
+    pub permissions: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub modified_at: Option<u64>,
+    pub accessed_at: Option<u64>,
+}
+
+impl MetadataUpdate {
+    /// Copy the fields that are set into `metadata`
+    pub fn apply(&self, metadata: &mut Metadata) {
+        if let Some(permissions) = self.permissions {
+            metadata.permissions = permissions & permissions::ALL;
+        }
+        metadata.uid = self.uid.unwrap_or(metadata.uid);
+        metadata.gid = self.gid.unwrap_or(metadata.gid);
+        metadata.modified_at = self.modified_at.unwrap_or(metadata.modified_at);
+        metadata.accessed_at = self.accessed_at.unwrap_or(metadata.accessed_at);
+    }
+}
+
+/// Whether a user may access a node. `wanted` holds the owner-class bits
+/// asked for (READ, WRITE, EXECUTE); the node's owner is checked against
+/// its owner bits, its group against the group bits and anyone else
+/// against the others bits. uid 0 may read anything, and may write or
+/// execute anything at least one class may.
+pub fn check_access(metadata: &Metadata, uid: u32, gid: u32, wanted: u16) -> Result<(), KernelError> {
+    let shift = if uid == metadata.uid {
+        0
+    } else if gid == metadata.gid {
+        3
+    } else {
+        6
+    };
+    let granted = (metadata.permissions >> shift) & permissions::OWNER_ALL;
+    // A file nobody may write is read-only, root included
+    let anyone = (metadata.permissions | metadata.permissions >> 3 | metadata.permissions >> 6) & permissions::OWNER_ALL;
+    let root_may = uid == 0 && wanted & !permissions::READ & !anyone == 0;
+    if granted & wanted == wanted || root_may {
+        Ok(())
+    } else {
+        Err(KernelError::FilesystemError(crate::errors::FilesystemError::PermissionDenied))
+    }
+}
+
+/// The uid and gid file access is checked as: the logged-in user, or root
+/// when nobody is
+pub fn current_credentials() -> (u32, u32) {
+    crate::user::current_credentials()
+}
+
+// New nodes start out root's; hand them to whoever made them, where the
+// file system records owners at all
+fn give_to(fs: &mut dyn FileSystem, path: &str, (uid, gid): (u32, u32)) -> Result<(), KernelError> {
+    if (uid, gid) == (0, 0) {
+        return Ok(());
+    }
+    match fs.set_metadata(path, MetadataUpdate { uid: Some(uid), gid: Some(gid), ..Default::default() }) {
+        Err(KernelError::UnsupportedFeature) | Err(KernelError::NotImplemented) => Ok(()),
+        result => result,
+    }
+}
+
+/// The open rules every file system gets, so each only has to provide
+/// metadata, create_file, truncate and open: CREATE makes a missing file
+/// (EXCLUSIVE fails if it exists), directories need DIRECTORY and can't be
+/// written, files can't be opened with DIRECTORY, and access is checked
+/// against the caller's credentials. Returns the node the file system gave.
+pub fn open_node(fs: &mut dyn FileSystem, path: &str, flags: u8, credentials: (u32, u32))
+    -> Result<Option<usize>, KernelError> {
+    let write = flags & file_flags::WRITE != 0;
+    let create = flags & file_flags::CREATE != 0;
+    let metadata = match fs.metadata(path) {
+        Ok(_) if create && flags & file_flags::EXCLUSIVE != 0 => return Err(KernelError::AlreadyExists),
+        Ok(metadata) => metadata,
+        Err(KernelError::NotFound) if create => {
+            fs.create_file(path)?;
+            give_to(fs, path, credentials)?;
+            fs.metadata(path)?
+        },
+        Err(e) => return Err(e),
+    };
+    
+    let is_directory = metadata.node_type == NodeType::Directory;
+    match (is_directory, flags & file_flags::DIRECTORY != 0) {
+        (true, false) => return Err(KernelError::IsADirectory),
+        (true, true) if write => return Err(KernelError::IsADirectory),
+        (false, true) => return Err(KernelError::NotADirectory),
+        _ => {},
+    }
+    
+    let mut wanted = 0;
+    if flags & file_flags::READ != 0 {
+        wanted |= permissions::READ;
+    }
+    if write {
+        wanted |= permissions::WRITE;
+    }
+    check_access(&metadata, credentials.0, credentials.1, wanted)?;
+    
+    if is_directory {
+        // Nothing to read or write through; the handle just names it
+        return Ok(None);
+    }
+    if write && flags & file_flags::TRUNCATE != 0 && metadata.size != 0 {
+        fs.truncate(path, 0)?;
+    }
+    fs.open(path, write)
+}
+
 /// Directory entry
 #[derive(Debug, Clone)]
 pub struct DirEntry {
@@ -88,6 +261,9 @@ impl DirEntry {
     }
 }
 
+/// Directory entries fetched per `read_dir_from` call by the helpers here
+pub const DIR_PAGE_SIZE: usize = 16;
+
 /// Abstraction for file system operations
 pub trait FileSystem: Send + Sync {
     /// Mount the file system
@@ -105,14 +281,50 @@ pub trait FileSystem: Send + Sync {
     /// Remove a file or empty directory
     fn remove(&mut self, path: &str) -> Result<(), KernelError>;
     
-    /// Open a file and return a file handle
-    fn open(&self, path: &str, write: bool) -> Result<FileHandle, KernelError>;
+    /// Check a file can be opened. Returns the inode a handle should follow,
+    /// for file systems that keep a file's data while a handle is open;
+    /// those also get `release` when the handle closes.
+    fn open(&mut self, path: &str, write: bool) -> Result<Option<usize>, KernelError>;
+    
+    /// Give an existing file a second name
+    fn link(&mut self, existing: &str, new: &str) -> Result<(), KernelError> {
+        Err(KernelError::NotImplemented)
+    }
+    
+    /// A handle opened on `inode` was closed
+    fn release(&mut self, inode: usize) {}
     
     /// Get file metadata
     fn metadata(&self, path: &str) -> Result<Metadata, KernelError>;
     
-    /// List directory contents
-    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, KernelError>;
+    /// Change permissions, ownership or timestamps. File systems that can't
+    /// store one of the fields asked for fail with UnsupportedFeature and
+    /// change nothing.
+    fn set_metadata(&mut self, path: &str, update: MetadataUpdate) -> Result<(), KernelError> {
+        Err(KernelError::NotImplemented)
+    }
+    
+    /// Read directory entries from `cursor` on into `out`, returning how many
+    /// were filled and the cursor for the next call (None at the end). Start
+    /// with cursor 0. A cursor stays valid while the directory changes: no
+    /// entry is returned twice and none present throughout is skipped, but
+    /// entries created or removed meanwhile may or may not appear.
+    fn read_dir_from(&self, path: &str, cursor: usize, out: &mut [Option<DirEntry>])
+        -> Result<(usize, Option<usize>), KernelError>;
+    
+    /// List a whole directory; prefer `VfsManager::read_dir_paged` for
+    /// directories that may be large
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, KernelError> {
+        let mut entries = Vec::new();
+        let mut page: [Option<DirEntry>; DIR_PAGE_SIZE] = core::array::from_fn(|_| None);
+        let mut cursor = Some(0);
+        while let Some(start) = cursor {
+            let (filled, next) = self.read_dir_from(path, start, &mut page)?;
+            entries.extend(page[..filled].iter_mut().filter_map(Option::take));
+            cursor = if filled == 0 { None } else { next };
+        }
+        Ok(entries)
+    }
     
     /// Rename or move a file
     fn rename(&mut self, from: &str, to: &str) -> Result<(), KernelError>;
@@ -120,6 +332,16 @@ pub trait FileSystem: Send + Sync {
     /// Get file system name
     fn name(&self) -> &str;
     
+    /// The volume's label, if it has one
+    fn volume_label(&self) -> Option<String> {
+        None
+    }
+
+    /// ID of the block device the file system is on, if it's on one
+    fn device_id(&self) -> Option<u64> {
+        None
+    }
+    
     /// Returns total capacity of the file system
     fn total_space(&self) -> u64;
     
@@ -138,6 +360,32 @@ pub trait FileSystem: Send + Sync {
         Err(KernelError::NotImplemented)
     }
     
+    /// Set a file's length, dropping data past the end or adding zeros
+    fn truncate(&mut self, path: &str, length: u64) -> Result<(), KernelError> {
+        Err(KernelError::NotImplemented)
+    }
+    
+    /// Read from the node a handle was opened on
+    fn read_inode_at(&self, inode: usize, offset: u64, buffer: &mut [u8]) -> Result<usize, KernelError> {
+        Err(KernelError::NotImplemented)
+    }
+    
+    /// Write to the node a handle was opened on
+    fn write_inode_at(&mut self, inode: usize, offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
+        Err(KernelError::NotImplemented)
+    }
+    
+    /// Set the length of the node a handle was opened on
+    fn truncate_inode(&mut self, inode: usize, length: u64) -> Result<(), KernelError> {
+        Err(KernelError::NotImplemented)
+    }
+    
+    /// Look for inconsistencies in the file system's own structures, fixing
+    /// what can be fixed without losing data when `repair` is set
+    fn check(&mut self, repair: bool) -> Result<CheckReport, KernelError> {
+        Err(KernelError::NotImplemented)
+    }
+    
     /// Check if this is a TempFS (for emergency operations)
     fn is_tempfs(&self) -> bool {
         false
@@ -157,48 +405,79 @@ pub mod file_flags {
     pub const APPEND: u8 = 0b0000_0100;
     pub const CREATE: u8 = 0b0000_1000;
     pub const TRUNCATE: u8 = 0b0001_0000;
+    /// With CREATE, fail if the file already exists
+    pub const EXCLUSIVE: u8 = 0b0010_0000;
+    /// Open a directory; without it directories fail with IsADirectory
+    pub const DIRECTORY: u8 = 0b0100_0000;
 }
 
 /// Abstraction for file operations
 pub struct FileHandle {
     pub path: String,
-    pub fs: Arc<Mutex<dyn FileSystem>>,
+    pub fs: Arc<DiagMutex<dyn FileSystem>>,
     pub position: u64,
     pub flags: u8,
+    /// Set for pipe ends, whose reads and writes bypass the filesystem
+    pub pipe: Option<PipeEnd>,
+    /// Node the handle was opened on, when the file system gave one. I/O
+    /// goes to it rather than the path, so it keeps working after unlink.
+    pub inode: Option<usize>,
 }
 
 impl FileHandle {
-    pub fn new(path: &str, fs: Arc<Mutex<dyn FileSystem>>, flags: u8) -> Self {
+    pub fn new(path: &str, fs: Arc<DiagMutex<dyn FileSystem>>, flags: u8) -> Self {
         Self {
             path: path.to_string(),
             fs,
             position: 0,
             flags,
+            pipe: None,
+            inode: None,
+        }
+    }
+    
+    /// Wrap one end of a pipe
+    pub fn for_pipe(end: PipeEnd, fs: Arc<DiagMutex<dyn FileSystem>>, flags: u8) -> Self {
+        Self {
+            path: "pipe:".to_string(),
+            fs,
+            position: 0,
+            flags,
+            pipe: Some(end),
+            inode: None,
         }
     }
     
     /// Read from the file at the current position
     pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, KernelError> {
-        serial_println!("DEBUG: FileHandle: Reading from file '{}'", self.path);
+        kdebug!("vfs", "FileHandle: Reading from file '{}'", self.path);
         
         // Check if the file is opened for reading
         if self.flags & file_flags::READ == 0 {
             return Err(KernelError::InvalidOperation);
         }
         
+        if let Some(pipe) = self.pipe.as_mut() {
+            return pipe.read(buffer);
+        }
+        
         // Get a lock on the filesystem
         let fs_guard = self.fs.lock();
         
         // Try to use the filesystem's read_at implementation
-        match fs_guard.read_at(&self.path, self.position, buffer) {
+        let result = match self.inode {
+            Some(inode) => fs_guard.read_inode_at(inode, self.position, buffer),
+            None => fs_guard.read_at(&self.path, self.position, buffer),
+        };
+        match result {
             Ok(bytes_read) => {
-                serial_println!("DEBUG: FileHandle: Read {} bytes using filesystem implementation", bytes_read);
+                kdebug!("vfs", "FileHandle: Read {} bytes using filesystem implementation", bytes_read);
                 self.position += bytes_read as u64;
                 Ok(bytes_read)
             },
             Err(KernelError::NotImplemented) => {
                 // Fallback to simple implementation
-                serial_println!("DEBUG: FileHandle: Using fallback read implementation");
+                kdebug!("vfs", "FileHandle: Using fallback read implementation");
                 if buffer.len() > 0 {
                     buffer[0] = b'H';
                 }
@@ -217,13 +496,17 @@ impl FileHandle {
     
     /// Write to the file at the current position
     pub fn write(&mut self, buffer: &[u8]) -> Result<usize, KernelError> {
-        serial_println!("DEBUG: FileHandle: Writing to file '{}'", self.path);
+        kdebug!("vfs", "FileHandle: Writing to file '{}'", self.path);
         
         // Check if the file is opened for writing
         if self.flags & file_flags::WRITE == 0 {
             return Err(KernelError::InvalidOperation);
         }
         
+        if let Some(pipe) = self.pipe.as_mut() {
+            return pipe.write(buffer);
+        }
+        
         // Get path and position before locking filesystem
         let path = self.path.clone();
         let position = self.position;
@@ -234,23 +517,27 @@ impl FileHandle {
             let mut fs_guard = self.fs.lock();
             
             // Try to use the filesystem's write_at implementation
-            serial_println!("DEBUG: FileHandle: Calling write_at with pos={}, len={}", position, buffer.len());
-            fs_guard.write_at(&path, position, buffer)
+            kdebug!("vfs", "FileHandle: Calling write_at with pos={}, len={}", position, buffer.len());
+            match self.inode {
+                Some(inode) => fs_guard.write_inode_at(inode, position, buffer),
+                None => fs_guard.write_at(&path, position, buffer),
+            }
         };
         
         match &result {
             Ok(bytes_written) => {
-                serial_println!("DEBUG: FileHandle: Wrote {} bytes using filesystem implementation", bytes_written);
+                kdebug!("vfs", "FileHandle: Wrote {} bytes using filesystem implementation", bytes_written);
                 self.position += *bytes_written as u64;
+                watch::notify(&path, WatchKind::Modified);
             },
             Err(KernelError::NotImplemented) => {
                 // Fallback to simple implementation
-                serial_println!("DEBUG: FileHandle: Using fallback write implementation");
+                kdebug!("vfs", "FileHandle: Using fallback write implementation");
                 self.position += buffer.len() as u64;
                 return Ok(buffer.len());
             },
             Err(e) => {
-                serial_println!("DEBUG: FileHandle: Write error: {:?}", e);
+                kdebug!("vfs", "FileHandle: Write error: {:?}", e);
             },
         }
         
@@ -259,14 +546,44 @@ impl FileHandle {
     
     /// Seek to a new position in the file
     pub fn seek(&mut self, position: u64) -> Result<(), KernelError> {
+        // Pipes are not seekable
+        if self.pipe.is_some() {
+            return Err(KernelError::InvalidOperation);
+        }
         self.position = position;
         Ok(())
     }
     
+    /// Set the file's length. The position is left alone, even past the
+    /// new end; reads there return nothing and writes fill the gap with zeros.
+    pub fn truncate(&mut self, length: u64) -> Result<(), KernelError> {
+        if self.flags & file_flags::WRITE == 0 || self.pipe.is_some() {
+            return Err(KernelError::InvalidOperation);
+        }
+        let result = {
+            let mut fs_guard = self.fs.lock();
+            match self.inode {
+                Some(inode) => fs_guard.truncate_inode(inode, length),
+                None => fs_guard.truncate(&self.path, length),
+            }
+        };
+        if result.is_ok() {
+            watch::notify(&self.path, WatchKind::Modified);
+        }
+        result
+    }
+    
     /// Close the file handle
     pub fn close(&mut self) -> Result<(), KernelError> {
         // Any cleanup operations go here
-        serial_println!("DEBUG: FileHandle: Closed file '{}'", self.path);
+        if let Some(pipe) = self.pipe.as_mut() {
+            pipe.close();
+        }
+        // Let the file system free the data if the file was unlinked
+        if let Some(inode) = self.inode.take() {
+            self.fs.lock().release(inode);
+        }
+        kdebug!("vfs", "FileHandle: Closed file '{}'", self.path);
         Ok(())
     }
 }
@@ -281,11 +598,34 @@ impl fmt::Debug for FileHandle {
     }
 }
 
+/// Options for `VfsManager::mount`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountFlags(u8);
+
+impl MountFlags {
+    pub const NONE: MountFlags = MountFlags(0);
+    /// Every change below the mount point fails with ReadOnlyFilesystem
+    pub const READ_ONLY: MountFlags = MountFlags(0b0000_0001);
+    
+    pub fn contains(self, other: MountFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for MountFlags {
+    type Output = MountFlags;
+    
+    fn bitor(self, other: MountFlags) -> MountFlags {
+        MountFlags(self.0 | other.0)
+    }
+}
+
 /// Mount points in the VFS
 #[derive(Debug)]
 pub struct MountPoint {
     pub path: String,
-    pub fs: Arc<Mutex<dyn FileSystem>>,
+    pub fs: Arc<DiagMutex<dyn FileSystem>>,
+    pub flags: MountFlags,
 }
 
 /// VFS Manager handles mount points and provides the interface to access file systems
@@ -293,40 +633,68 @@ pub struct VfsManager {
     mount_points: Vec<MountPoint>,
 }
 
+/// Paths are made absolute and normalized on the way in, so mount lookup
+/// and the file systems all see one spelling of each path, then put under
+/// the running task's root (see `jail`)
+fn canonical(path: &str) -> String {
+    super::jail::resolve_for_current(path)
+}
+
 impl VfsManager {
     pub fn new() -> Self {
-        serial_println!("DEBUG: Creating new VfsManager");
+        kdebug!("vfs", "Creating new VfsManager");
         Self {
             mount_points: Vec::new(),
         }
     }
     
     /// Mount a file system at a specific path
-    pub fn mount(&mut self, path: &str, fs: Arc<Mutex<dyn FileSystem>>) -> Result<(), KernelError> {
-        serial_println!("DEBUG: VfsManager::mount - Mounting at path '{}'", path);
+    pub fn mount(&mut self, path: &str, fs: Arc<DiagMutex<dyn FileSystem>>, flags: MountFlags)
+        -> Result<(), KernelError> {
+        kdebug!("vfs", "VfsManager::mount - Mounting at path '{}'", path);
         
         // Mount the file system
         {
-            serial_println!("DEBUG: VfsManager::mount - Acquiring filesystem lock");
+            kdebug!("vfs", "VfsManager::mount - Acquiring filesystem lock");
             let mut fs_guard = fs.lock();
-            serial_println!("DEBUG: VfsManager::mount - Calling fs.mount()");
+            kdebug!("vfs", "VfsManager::mount - Calling fs.mount()");
             fs_guard.mount()?;
-            serial_println!("DEBUG: VfsManager::mount - fs.mount() successful");
+            kdebug!("vfs", "VfsManager::mount - fs.mount() successful");
+            
+            let check_on_mount = crate::config::get("fs.check_on_mount")
+                .and_then(|value| value.try_as_boolean())
+                .unwrap_or(false);
+            if check_on_mount {
+                // Only look; repairs are left to an explicit fsck
+                match fs_guard.check(false) {
+                    Ok(report) => {
+                        for problem in &report.problems {
+                            crate::logger::warning("fs", &format!("{} at {}: {}", fs_guard.name(), path, problem));
+                        }
+                    },
+                    Err(KernelError::NotImplemented) => {},
+                    Err(e) => {
+                        crate::logger::warning("fs", &format!("Could not check {} at {}: {:?}", fs_guard.name(), path, e));
+                    }
+                }
+            }
         }
         
         // Add to mount points
-        serial_println!("DEBUG: VfsManager::mount - Adding mount point to registry");
+        kdebug!("vfs", "VfsManager::mount - Adding mount point to registry");
         self.mount_points.push(MountPoint {
-            path: path.to_string(),
+            path: canonical(path),
             fs,
+            flags,
         });
         
-        serial_println!("DEBUG: VfsManager::mount - Mount operation complete");
+        kdebug!("vfs", "VfsManager::mount - Mount operation complete");
         Ok(())
     }
     
     /// Unmount a file system
     pub fn unmount(&mut self, path: &str) -> Result<(), KernelError> {
+        let path = canonical(path);
         let index = self.mount_points.iter()
             .position(|mp| mp.path == path)
             .ok_or(KernelError::NotFound)?;
@@ -340,89 +708,408 @@ impl VfsManager {
         Ok(())
     }
     
-    /// Find the file system for a given path
-    pub fn find_fs(&self, path: &str) -> Result<Arc<Mutex<dyn FileSystem>>, KernelError> {
+    /// Change the options of the file system mounted at `path`
+    pub fn remount(&mut self, path: &str, flags: MountFlags) -> Result<(), KernelError> {
+        let path = canonical(path);
+        let mount_point = self.mount_points.iter_mut()
+            .find(|mp| mp.path == path)
+            .ok_or(KernelError::NotFound)?;
+        mount_point.flags = flags;
+        Ok(())
+    }
+    
+    /// Mounted file systems as (mount path, file system name, volume label,
+    /// total bytes, available bytes), in mount order
+    pub fn list_mounts(&self) -> Vec<(String, String, Option<String>, u64, u64)> {
+        self.mount_points.iter()
+            .map(|mp| {
+                let fs = mp.fs.lock();
+                (mp.path.clone(), fs.name().to_string(), fs.volume_label(), fs.total_space(), fs.available_space())
+            })
+            .collect()
+    }
+    
+    /// Where the file system on block device `device_id` is mounted, if
+    /// it is
+    pub fn mount_of_device(&self, device_id: u64) -> Option<String> {
+        self.mount_points.iter()
+            .find(|mp| mp.fs.lock().device_id() == Some(device_id))
+            .map(|mp| mp.path.clone())
+    }
+    
+    /// Find the mount point a path is under
+    fn find_mount(&self, path: &str) -> Result<&MountPoint, KernelError> {
         // Find the best matching mount point
-        let mut best_match = "";
-        let mut best_fs = None;
+        let mut best: Option<&MountPoint> = None;
+        let path = Path::new(path).canonical();
         
-        serial_println!("DEBUG: VFS: Finding filesystem for path '{}'", path);
+        kdebug!("vfs", "VFS: Finding filesystem for path '{}'", path);
         
         for mp in &self.mount_points {
-            if path.starts_with(&mp.path) && mp.path.len() > best_match.len() {
-                best_match = &mp.path;
-                best_fs = Some(mp.fs.clone());
+            if path.starts_with(&mp.path) && !best.is_some_and(|best| mp.path.len() <= best.path.len()) {
+                best = Some(mp);
             }
         }
         
-        best_fs.ok_or(KernelError::NotFound)
+        best.ok_or(KernelError::NotFound)
     }
     
-    /// Open a file
+    /// Find the file system for a given path
+    pub fn find_fs(&self, path: &str) -> Result<Arc<DiagMutex<dyn FileSystem>>, KernelError> {
+        Ok(self.find_mount(path)?.fs.clone())
+    }
+    
+    /// Find the file system for a path about to be changed; fails with
+    /// ReadOnlyFilesystem if it's mounted read-only
+    pub fn writable_fs(&self, path: &str) -> Result<Arc<DiagMutex<dyn FileSystem>>, KernelError> {
+        let mount = self.find_mount(path)?;
+        if mount.flags.contains(MountFlags::READ_ONLY) {
+            return Err(KernelError::ReadOnlyFilesystem);
+        }
+        Ok(mount.fs.clone())
+    }
+    
+    /// Whether the mount holding `path` is read-only
+    pub fn is_read_only(&self, path: &str) -> bool {
+        self.find_mount(path).is_ok_and(|mount| mount.flags.contains(MountFlags::READ_ONLY))
+    }
+    
+    /// Open a file, or with DIRECTORY a directory, if the current user's
+    /// permissions allow it
     pub fn open(&self, path: &str, flags: u8) -> Result<FileHandle, KernelError> {
-        let fs = self.find_fs(path)?;
+        let path = &canonical(path);
+        let changes = file_flags::WRITE | file_flags::APPEND | file_flags::CREATE | file_flags::TRUNCATE;
+        let fs = if flags & changes != 0 { self.writable_fs(path)? } else { self.find_fs(path)? };
         
-        let write = (flags & file_flags::WRITE) != 0;
-        let fs_guard = fs.lock();
+        let (inode, created) = {
+            let mut fs_guard = fs.lock();
+            let created = flags & file_flags::CREATE != 0 && fs_guard.metadata(path).is_err();
+            (open_node(&mut *fs_guard, path, flags, current_credentials())?, created)
+        };
+        if created {
+            watch::notify(path, WatchKind::Created);
+        }
         
-        fs_guard.open(path, write)
+        let mut handle = FileHandle::new(path, fs, flags);
+        handle.inode = inode;
+        Ok(handle)
     }
     
-    /// Create a file
+    /// Give an existing file a second name on the same file system
+    pub fn link(&self, existing: &str, new: &str) -> Result<(), KernelError> {
+        let (existing, new) = (&canonical(existing), &canonical(new));
+        let fs = self.writable_fs(existing)?;
+        if !Arc::ptr_eq(&fs, &self.writable_fs(new)?) {
+            // Links can't cross file systems
+            return Err(KernelError::InvalidOperation);
+        }
+        
+        fs.lock().link(existing, new)?;
+        watch::notify(new, WatchKind::Created);
+        Ok(())
+    }
+    
+    /// Create a file, owned by the current user
     pub fn create_file(&self, path: &str) -> Result<(), KernelError> {
-        let fs = self.find_fs(path)?;
+        let path = &canonical(path);
+        let fs = self.writable_fs(path)?;
         
-        let mut fs_guard = fs.lock();
-        fs_guard.create_file(path)
+        {
+            let mut fs_guard = fs.lock();
+            fs_guard.create_file(path)?;
+            give_to(&mut *fs_guard, path, current_credentials())?;
+        }
+        watch::notify(path, WatchKind::Created);
+        Ok(())
     }
     
-    /// Create a directory
+    /// Create a directory, owned by the current user
     pub fn create_directory(&self, path: &str) -> Result<(), KernelError> {
-        let fs = self.find_fs(path)?;
+        let path = &canonical(path);
+        let fs = self.writable_fs(path)?;
         
-        let mut fs_guard = fs.lock();
-        fs_guard.create_directory(path)
+        {
+            let mut fs_guard = fs.lock();
+            fs_guard.create_directory(path)?;
+            give_to(&mut *fs_guard, path, current_credentials())?;
+        }
+        watch::notify(path, WatchKind::Created);
+        Ok(())
     }
     
-    /// Remove a file or directory
+    /// Remove a file or directory. With `fs.use_trash` set, anything in a
+    /// home under /Users goes to that home's trash instead (see `trash`).
     pub fn remove(&self, path: &str) -> Result<(), KernelError> {
-        let fs = self.find_fs(path)?;
+        // Both ways on resolve the path again, so leave it in the task's view
+        let path = Path::new(path).canonical();
+        let path = path.as_str();
+        if trash::enabled() {
+            if let Some(result) = trash::remove(self, path) {
+                return result;
+            }
+        }
+        self.remove_permanently(path)
+    }
+    
+    /// Remove a file or empty directory for good, trash or no trash
+    pub fn remove_permanently(&self, path: &str) -> Result<(), KernelError> {
+        let path = &canonical(path);
+        let fs = self.writable_fs(path)?;
         
-        let mut fs_guard = fs.lock();
-        fs_guard.remove(path)
+        fs.lock().remove(path)?;
+        watch::notify(path, WatchKind::Removed);
+        Ok(())
     }
     
     /// Get file metadata
     pub fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
+        let path = &canonical(path);
         let fs = self.find_fs(path)?;
         
         let fs_guard = fs.lock();
         fs_guard.metadata(path)
     }
     
+    /// Get told about changes to `prefix` and everything below it, until
+    /// the handle is dropped
+    pub fn watch(&self, prefix: &str) -> WatchHandle {
+        watch::watch(prefix)
+    }
+    
+    /// Change permissions, ownership or timestamps
+    pub fn set_metadata(&self, path: &str, update: MetadataUpdate) -> Result<(), KernelError> {
+        let path = &canonical(path);
+        let fs = self.writable_fs(path)?;
+        
+        let mut fs_guard = fs.lock();
+        fs_guard.set_metadata(path, update)
+    }
+    
     /// List directory contents
     pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, KernelError> {
+        let path = &canonical(path);
         let fs = self.find_fs(path)?;
         
         let fs_guard = fs.lock();
         fs_guard.read_dir(path)
     }
     
+    /// Write `buffer` into a file at `offset`, returning the bytes written
+    pub fn write_at(&self, path: &str, offset: u64, buffer: &[u8]) -> Result<usize, KernelError> {
+        let path = &canonical(path);
+        let fs = self.writable_fs(path)?;
+        
+        let count = fs.lock().write_at(path, offset, buffer)?;
+        watch::notify(path, WatchKind::Modified);
+        Ok(count)
+    }
+    
+    /// Shrink or zero-extend a file to `length` bytes
+    pub fn truncate(&self, path: &str, length: u64) -> Result<(), KernelError> {
+        let path = &canonical(path);
+        let fs = self.writable_fs(path)?;
+        
+        fs.lock().truncate(path, length)?;
+        watch::notify(path, WatchKind::Modified);
+        Ok(())
+    }
+    
+    /// Check the file system holding `path`, repairing it if asked
+    pub fn check(&self, path: &str, repair: bool) -> Result<CheckReport, KernelError> {
+        let path = &canonical(path);
+        let fs = if repair { self.writable_fs(path)? } else { self.find_fs(path)? };
+        
+        let mut fs_guard = fs.lock();
+        fs_guard.check(repair)
+    }
+    
+    /// Iterate over a directory a page at a time, so only one page of
+    /// entries is held and the file system is locked only while a page is read
+    pub fn read_dir_paged(&self, path: &str) -> DirReader<'_> {
+        DirReader {
+            vfs: self,
+            path: canonical(path),
+            page: core::array::from_fn(|_| None),
+            filled: 0,
+            index: 0,
+            next: Some(0),
+        }
+    }
+    
+    /// Copy a file or a whole tree to `to`, which must not exist
+    pub fn copy_tree(&self, from: &str, to: &str) -> Result<(), KernelError> {
+        copy_tree(self, from, to, 0)
+    }
+    
     /// Rename or move a file
     pub fn rename(&self, from: &str, to: &str) -> Result<(), KernelError> {
+        let (from, to) = (&canonical(from), &canonical(to));
         // Check if we're moving across file systems
-        let from_fs = self.find_fs(from)?;
-        let to_fs = self.find_fs(to)?;
+        let from_fs = self.writable_fs(from)?;
+        let to_fs = self.writable_fs(to)?;
         
         // Simple case: same file system
         if Arc::ptr_eq(&from_fs, &to_fs) {
-            let mut fs_guard = from_fs.lock();
-            return fs_guard.rename(from, to);
+            from_fs.lock().rename(from, to)?;
+            watch::notify(from, WatchKind::MovedFrom);
+            watch::notify(to, WatchKind::MovedTo);
+            return Ok(());
         }
         
         // Cross-file system moves are not supported yet
         Err(KernelError::NotImplemented)
     }
+    
+    /// Replace a file's contents with `bytes`. They go to `path.tmp`, which
+    /// is then renamed over the file, so a failure at any step leaves the
+    /// old contents or the new ones and never neither. File systems without
+    /// rename have the file overwritten in place instead.
+    pub fn write_file_atomic(&self, path: &str, bytes: &[u8]) -> Result<(), KernelError> {
+        self.replace_file(path, bytes, write_whole)
+    }
+    
+    /// `write_file_atomic` with the step that writes the data passed in
+    fn replace_file(&self, path: &str, bytes: &[u8],
+                    write: fn(&VfsManager, &str, &[u8]) -> Result<(), KernelError>) -> Result<(), KernelError> {
+        let temp = format!("{}.tmp", path);
+        
+        // A temporary file still here is left from an earlier failure
+        match self.remove_permanently(&temp) {
+            Ok(()) | Err(KernelError::NotFound) => {},
+            Err(e) => return Err(e),
+        }
+        
+        // Nothing caches writes yet, so there is nothing to flush before the rename
+        let result = self.create_file(&temp)
+            .and_then(|()| write(self, &temp, bytes))
+            .and_then(|()| self.rename(&temp, path));
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = self.remove_permanently(&temp);
+                if !matches!(e, KernelError::NotImplemented) {
+                    return Err(e);
+                }
+                
+                if let Err(KernelError::NotFound) = self.metadata(path) {
+                    self.create_file(path)?;
+                }
+                write(self, path, bytes)?;
+                self.truncate(path, bytes.len() as u64)
+            }
+        }
+    }
+}
+
+// `VfsManager::copy_tree`, `depth` levels down
+fn copy_tree(vfs: &VfsManager, from: &str, to: &str, depth: usize) -> Result<(), KernelError> {
+    if depth > super::walk::MAX_DEPTH {
+        return Err(KernelError::ValidationError("Tree too deep to copy"));
+    }
+    if vfs.metadata(from)?.node_type == NodeType::Directory {
+        vfs.create_directory(to)?;
+        for entry in vfs.read_dir(from)? {
+            if entry.name != "." && entry.name != ".." {
+                let (from, to) = (Path::new(from).join(&entry.name), Path::new(to).join(&entry.name));
+                copy_tree(vfs, from.as_str(), to.as_str(), depth + 1)?;
+            }
+        }
+        return Ok(());
+    }
+
+    vfs.create_file(to)?;
+    let mut reader = vfs.open(from, file_flags::READ)?;
+    let mut buffer = [0u8; 512];
+    let mut offset = 0;
+    let result = loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(count) => match vfs.write_at(to, offset, &buffer[..count]) {
+                Ok(_) => offset += count as u64,
+                Err(e) => break Err(e),
+            },
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = reader.close();
+    result
+}
+
+/// Write all of `bytes` at the start of a file
+fn write_whole(vfs: &VfsManager, path: &str, bytes: &[u8]) -> Result<(), KernelError> {
+    let fs = vfs.writable_fs(path)?;
+    {
+        let mut fs_guard = fs.lock();
+        let mut written = 0;
+        while written < bytes.len() {
+            let count = fs_guard.write_at(path, written as u64, &bytes[written..])?;
+            if count == 0 {
+                return Err(KernelError::WriteError);
+            }
+            written += count;
+        }
+    }
+    watch::notify(path, WatchKind::Modified);
+    Ok(())
+}
+
+/// Iterator over a directory's entries, from `VfsManager::read_dir_paged`.
+/// Yields an error at most once, after which it stops.
+pub struct DirReader<'a> {
+    vfs: &'a VfsManager,
+    path: String,
+    page: [Option<DirEntry>; DIR_PAGE_SIZE],
+    filled: usize,
+    index: usize,
+    /// Cursor of the page after this one, or None after the last
+    next: Option<usize>,
+}
+
+impl Iterator for DirReader<'_> {
+    type Item = Result<DirEntry, KernelError>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index == self.filled {
+            let cursor = self.next?;
+            let result = self.vfs.find_fs(&self.path)
+                .and_then(|fs| fs.lock().read_dir_from(&self.path, cursor, &mut self.page));
+            match result {
+                Ok((filled, next)) => {
+                    self.filled = filled;
+                    self.index = 0;
+                    // An empty page ends the listing even if a cursor came
+                    // back, so a misbehaving file system can't loop forever
+                    self.next = if filled == 0 { None } else { next };
+                }
+                Err(e) => {
+                    self.next = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+        let entry = self.page[self.index].take();
+        self.index += 1;
+        entry.map(Ok)
+    }
+}
+
+/// Mount table in /proc/mounts format, one "<source> <path> <type> <options>
+/// 0 0" line per mount, for `mount` and for a future /proc/mounts
+pub fn mounts_text(vfs: &VfsManager) -> String {
+    let mut text = String::new();
+    for mp in &vfs.mount_points {
+        let (name, label) = {
+            let fs = mp.fs.lock();
+            (fs.name().to_string(), fs.volume_label())
+        };
+        let mut options = String::from(if mp.flags.contains(MountFlags::READ_ONLY) { "ro" } else { "rw" });
+        if let Some(label) = label {
+            // Spaces would split the field, so they're escaped as in /proc/mounts
+            options.push_str(&format!(",label={}", label.replace(' ', "\\040")));
+        }
+        text.push_str(&format!("{} {} {} {} 0 0\n", name, mp.path, name.to_lowercase(), options));
+    }
+    text
 }
 
 /// Global VFS manager instance
@@ -430,11 +1117,11 @@ static mut VFS_MANAGER: Option<VfsManager> = None;
 
 /// Initialize the VFS subsystem
 pub fn init() -> Result<(), KernelError> {
-    serial_println!("DEBUG: Initializing VFS subsystem");
+    kdebug!("vfs", "Initializing VFS subsystem");
     unsafe {
         VFS_MANAGER = Some(VfsManager::new());
     }
-    serial_println!("DEBUG: VFS manager created and initialized");
+    kdebug!("vfs", "VFS manager created and initialized");
     
     Ok(())
 }
@@ -443,9 +1130,259 @@ pub fn init() -> Result<(), KernelError> {
 pub fn get_vfs_manager() -> Option<&'static mut VfsManager> {
     unsafe {
         let manager = VFS_MANAGER.as_mut();
-        serial_println!("DEBUG: get_vfs_manager() returning: {}",
+        kdebug!("vfs", "get_vfs_manager() returning: {}",
             if manager.is_some() { "Some" } else { "None" });
             
         manager
     }
-} 
\ No newline at end of file
+} 
+
+/// Replace a scratch file in /tmp atomically, including with a write that
+/// fails halfway, and check the old contents survive the failure; then
+/// chmod and chown one and check open() honours the new bits, and check a
+/// read-only mount refuses changes
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("VFS: Running self-test");
+    let vfs = get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let path = "/tmp/atomic-selftest";
+    let temp = "/tmp/atomic-selftest.tmp";
+    let _ = vfs.remove(path);
+    
+    let read_back = |path: &str| -> Result<Vec<u8>, KernelError> {
+        let mut buffer = vec![0u8; vfs.metadata(path)?.size as usize];
+        let count = vfs.find_fs(path)?.lock().read_at(path, 0, &mut buffer)?;
+        buffer.truncate(count);
+        Ok(buffer)
+    };
+    
+    // Writes half the data, then fails
+    fn failing_write(vfs: &VfsManager, path: &str, bytes: &[u8]) -> Result<(), KernelError> {
+        write_whole(vfs, path, &bytes[..bytes.len() / 2])?;
+        Err(KernelError::WriteError)
+    }
+    
+    let result = (|| {
+        vfs.write_file_atomic(path, b"first version, longer")?;
+        vfs.write_file_atomic(path, b"second")?;
+        if read_back(path)? != b"second" {
+            return Err(KernelError::ValidationError("Atomic write left the wrong contents"));
+        }
+        
+        if vfs.replace_file(path, b"third version", failing_write).is_ok()
+            || read_back(path)? != b"second" || vfs.metadata(temp).is_ok() {
+            return Err(KernelError::ValidationError("Failed atomic write damaged the file or left its temporary"));
+        }
+        
+        vfs.create_file(temp)?;
+        vfs.write_file_atomic(path, b"fourth")?;
+        if read_back(path)? != b"fourth" || vfs.metadata(temp).is_ok() {
+            return Err(KernelError::ValidationError("Stale temporary file blocked an atomic write"));
+        }
+        Ok(())
+    })();
+    
+    let _ = vfs.remove(path);
+    let _ = vfs.remove(temp);
+    result?;
+    
+    permissions_self_test(vfs)?;
+    open_rules_self_test()?;
+    watch_self_test(vfs)?;
+    read_only_self_test(vfs)?;
+    serial_println!("VFS: Self-test passed");
+    Ok(())
+}
+
+// Every flag combination open_node rules on, against a fresh TempFs and a
+// FAT volume on a RamDisk
+fn open_rules_self_test() -> Result<(), KernelError> {
+    use crate::fs::block_device::BlockDevice;
+    use crate::fs::fat::{self, FatFileSystem, FormatOptions};
+    use crate::fs::ramdisk::RamDisk;
+    use crate::fs::tempfs::TempFs;
+    use file_flags::*;
+    
+    fn check(fs: &mut dyn FileSystem) -> Result<(), KernelError> {
+        // As root, handing the node straight back
+        fn open(fs: &mut dyn FileSystem, path: &str, flags: u8) -> Result<Option<usize>, KernelError> {
+            let inode = open_node(fs, path, flags, (0, 0))?;
+            if let Some(inode) = inode {
+                fs.release(inode);
+            }
+            Ok(inode)
+        }
+        fn expect(result: Result<Option<usize>, KernelError>, wanted: KernelError, what: &'static str)
+            -> Result<(), KernelError> {
+            match result {
+                Err(e) if core::mem::discriminant(&e) == core::mem::discriminant(&wanted) => Ok(()),
+                _ => Err(KernelError::ValidationError(what)),
+            }
+        }
+        
+        fs.create_directory("/DIR")?;
+        expect(open(fs, "/FILE", READ), KernelError::NotFound, "Opened a missing file without CREATE")?;
+        open(fs, "/FILE", WRITE | CREATE)?;
+        if fs.metadata("/FILE")?.node_type != NodeType::File {
+            return Err(KernelError::ValidationError("CREATE didn't make a file"));
+        }
+        open(fs, "/FILE", READ | CREATE)?;
+        expect(open(fs, "/FILE", WRITE | CREATE | EXCLUSIVE), KernelError::AlreadyExists,
+            "CREATE|EXCLUSIVE opened an existing file")?;
+        open(fs, "/NEW", WRITE | CREATE | EXCLUSIVE)?;
+        
+        expect(open(fs, "/DIR", READ), KernelError::IsADirectory, "Opened a directory without DIRECTORY")?;
+        expect(open(fs, "/DIR", WRITE | DIRECTORY), KernelError::IsADirectory, "Opened a directory for writing")?;
+        if open(fs, "/DIR", READ | DIRECTORY)?.is_some() {
+            return Err(KernelError::ValidationError("Directory open gave a file node"));
+        }
+        expect(open(fs, "/FILE", READ | DIRECTORY), KernelError::NotADirectory, "Opened a file as a directory")?;
+        
+        fs.write_at("/FILE", 0, b"contents")?;
+        open(fs, "/FILE", WRITE | TRUNCATE)?;
+        if fs.metadata("/FILE")?.size != 0 {
+            return Err(KernelError::ValidationError("TRUNCATE kept the old contents"));
+        }
+        
+        // Read-only holds even for root
+        let read_only = permissions::parse_octal("444").ok_or(KernelError::InvalidParameter)?;
+        fs.set_metadata("/FILE", MetadataUpdate { permissions: Some(read_only), ..Default::default() })?;
+        expect(open(fs, "/FILE", READ | WRITE),
+            KernelError::FilesystemError(crate::errors::FilesystemError::PermissionDenied),
+            "Opened a read-only file for writing")?;
+        open(fs, "/FILE", READ)?;
+        Ok(())
+    }
+    
+    check(&mut TempFs::new("open-selftest"))?;
+    
+    let mut disk = RamDisk::with_capacity(4096 * 2, 512)?;
+    fat::format(&mut disk, &FormatOptions { fat_type: None, label: None })?;
+    let device: Arc<spin::Mutex<dyn BlockDevice>> = Arc::new(spin::Mutex::new(disk));
+    check(&mut FatFileSystem::new(device)?)
+}
+
+// A TempFs mounted read-only still reads, but every way of changing it
+// fails with ReadOnlyFilesystem until it's remounted writable
+fn read_only_self_test(vfs: &mut VfsManager) -> Result<(), KernelError> {
+    use crate::fs::tempfs::TempFs;
+    
+    let dir = "/tmp/readonly-selftest";
+    let file = "/tmp/readonly-selftest/file";
+    let new = "/tmp/readonly-selftest/new";
+    let mut tempfs = TempFs::new("readonly-selftest");
+    tempfs.create_file(file)?;
+    tempfs.write_at(file, 0, b"kept")?;
+    let _ = vfs.create_directory(dir);
+    vfs.mount(dir, Arc::new(DiagMutex::new("fs:readonly-selftest", tempfs)), MountFlags::READ_ONLY)?;
+    
+    let result = (|| {
+        let refused = |result: Result<(), KernelError>| matches!(result, Err(KernelError::ReadOnlyFilesystem));
+        if !refused(vfs.create_file(new)) || !refused(vfs.create_directory(new)) || !refused(vfs.remove(file))
+            || !refused(vfs.write_at(file, 0, b"lost").map(|_| ())) || !refused(vfs.truncate(file, 0))
+            || !refused(vfs.open(file, file_flags::WRITE).map(|_| ()))
+            || !refused(vfs.write_file_atomic(file, b"lost")) || !refused(vfs.rename(file, new)) {
+            return Err(KernelError::ValidationError("Read-only mount allowed a change"));
+        }
+        
+        let mut buffer = [0u8; 8];
+        let count = vfs.find_fs(file)?.lock().read_at(file, 0, &mut buffer)?;
+        vfs.open(file, file_flags::READ)?.close()?;
+        if buffer[..count] != b"kept"[..] || vfs.metadata(file)?.size != 4 || !vfs.is_read_only(file) {
+            return Err(KernelError::ValidationError("Read-only mount lost or refused a read"));
+        }
+        Ok(())
+    })();
+    let result = result.and_then(|()| {
+        vfs.remount(dir, MountFlags::NONE)?;
+        vfs.create_file(new)
+    });
+    
+    vfs.unmount(dir)?;
+    let _ = vfs.remove(dir);
+    result
+}
+
+// VFS operations post the events their watchers expect, once they've succeeded
+fn watch_self_test(vfs: &VfsManager) -> Result<(), KernelError> {
+    let dir = "/tmp/watch-selftest";
+    let _ = vfs.remove(&format!("{}/b", dir));
+    let _ = vfs.remove(dir);
+    vfs.create_directory(dir)?;
+    let watch = vfs.watch(dir);
+    
+    let result = (|| {
+        let a = format!("{}/a", dir);
+        let b = format!("{}/b", dir);
+        vfs.create_file(&a)?;
+        let mut handle = vfs.open(&a, file_flags::WRITE)?;
+        handle.write(b"one")?;
+        handle.write(b"two")?;
+        handle.close()?;
+        vfs.rename(&a, &b)?;
+        let _ = vfs.create_file(&b);
+        vfs.remove(&b)?;
+        
+        let (events, lost) = watch.take_events();
+        let seen: Vec<(&str, WatchKind)> = events.iter().map(|event| (event.path.as_str(), event.kind)).collect();
+        let expected = [
+            (a.as_str(), WatchKind::Created),
+            (a.as_str(), WatchKind::Modified),
+            (a.as_str(), WatchKind::MovedFrom),
+            (b.as_str(), WatchKind::MovedTo),
+            (b.as_str(), WatchKind::Removed),
+        ];
+        if lost || seen != expected {
+            serial_println!("VFS: watch events {:?}", seen);
+            return Err(KernelError::ValidationError("VFS posted the wrong watch events"));
+        }
+        Ok(())
+    })();
+    
+    let _ = vfs.remove(&format!("{}/b", dir));
+    let _ = vfs.remove(&format!("{}/a", dir));
+    let _ = vfs.remove(dir);
+    result
+}
+
+fn permissions_self_test(vfs: &VfsManager) -> Result<(), KernelError> {
+    let path = "/tmp/chmod-selftest";
+    let _ = vfs.remove(path);
+    vfs.create_file(path)?;
+    
+    let previous = crate::user::USER_MANAGER.lock().get_current_user().map(|user| user.uid);
+    let result = (|| {
+        // Owned by the system user (uid 1), readable by everyone, writable by nobody
+        let mode = permissions::parse_octal("444").ok_or(KernelError::InvalidParameter)?;
+        vfs.set_metadata(path, MetadataUpdate { permissions: Some(mode), uid: Some(1), gid: Some(1), ..Default::default() })?;
+        let metadata = vfs.metadata(path)?;
+        if metadata.permissions != mode || metadata.uid != 1 || metadata.gid != 1
+            || permissions::to_octal(metadata.permissions) != 0o444 {
+            return Err(KernelError::ValidationError("chmod/chown didn't stick"));
+        }
+        
+        crate::user::USER_MANAGER.lock().set_current_user(1)?;
+        vfs.open(path, file_flags::READ)?.close()?;
+        if vfs.open(path, file_flags::READ | file_flags::WRITE).is_ok() {
+            return Err(KernelError::ValidationError("Write allowed despite r--"));
+        }
+        
+        // Give the owner write back; others still can't
+        let mode = permissions::parse_octal("644").ok_or(KernelError::InvalidParameter)?;
+        vfs.set_metadata(path, MetadataUpdate { permissions: Some(mode), ..Default::default() })?;
+        vfs.open(path, file_flags::WRITE)?.close()?;
+        if check_access(&vfs.metadata(path)?, 1000, 1000, permissions::WRITE).is_ok() {
+            return Err(KernelError::ValidationError("Others allowed write under 644"));
+        }
+        Ok(())
+    })();
+    
+    {
+        let mut users = crate::user::USER_MANAGER.lock();
+        match previous {
+            Some(uid) => { let _ = users.set_current_user(uid); }
+            None => users.clear_current_user(),
+        }
+    }
+    let _ = vfs.remove(path);
+    result
+}
@@ -0,0 +1,238 @@
+//! Recursive directory traversal for UniverseK OS
+//! Walks a tree depth-first over the VFS, handing each entry to a visitor as
+//! soon as it is found. Unreadable subtrees are reported and skipped, and a
+//! depth cap plus a record of visited directories stop the walk looping.
+
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::errors::KernelError;
+use crate::serial_println;
+use super::vfs::{self, NodeType, VfsManager};
+
+/// Deepest level below the start that is descended into
+pub const MAX_DEPTH: usize = 32;
+
+/// An entry found by the walk
+pub struct WalkEntry<'a> {
+    pub path: &'a str,
+    pub node_type: NodeType,
+    /// Levels below the start, which is depth 0
+    pub depth: usize,
+}
+
+/// Receives entries as the walk finds them
+pub trait Visitor {
+    /// Called for every entry, the start included, before any children
+    fn enter(&mut self, entry: &WalkEntry);
+
+    /// Called once a directory's children have all been visited
+    fn leave(&mut self, _entry: &WalkEntry) {}
+
+    /// Called when a path can't be read; the walk carries on without it
+    fn error(&mut self, path: &str, error: KernelError);
+
+    /// Asked before each child; true ends the walk there, without further
+    /// `leave` calls, and `walk` returns Interrupted
+    fn stop(&mut self) -> bool {
+        false
+    }
+}
+
+/// Walk the tree under `start` depth-first
+pub fn walk(start: &str, visitor: &mut dyn Visitor) -> Result<(), KernelError> {
+    let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let node_type = vfs.metadata(start)?.node_type;
+
+    let mut walker = Walker { vfs, visited: BTreeSet::new(), stopped: false };
+    walker.visit(start, node_type, 0, visitor);
+    if walker.stopped {
+        return Err(KernelError::Interrupted);
+    }
+    Ok(())
+}
+
+struct Walker<'v> {
+    vfs: &'v VfsManager,
+    /// Directories entered so far, by file system and inode
+    visited: BTreeSet<(usize, usize)>,
+    /// The visitor asked to stop
+    stopped: bool,
+}
+
+impl Walker<'_> {
+    fn visit(&mut self, path: &str, node_type: NodeType, depth: usize, visitor: &mut dyn Visitor) {
+        let entry = WalkEntry { path, node_type, depth };
+        visitor.enter(&entry);
+        if node_type != NodeType::Directory {
+            return;
+        }
+
+        if depth >= MAX_DEPTH {
+            visitor.error(path, KernelError::ValidationError("Too deep, not descending"));
+        } else {
+            // Read a page at a time so each level holds only a few entries
+            let vfs = self.vfs;
+            for child in vfs.read_dir_paged(path) {
+                if self.stopped || visitor.stop() {
+                    self.stopped = true;
+                    return;
+                }
+                let child = match child {
+                    Ok(child) => child,
+                    Err(e) => {
+                        visitor.error(path, e);
+                        break;
+                    }
+                };
+                if child.name == "." || child.name == ".." {
+                    continue;
+                }
+                let child_path = join(path, &child.name);
+                if child.node_type == NodeType::Directory && !self.first_visit(&child_path, child.inode) {
+                    visitor.error(&child_path, KernelError::ValidationError("Directory loop, already visited"));
+                    continue;
+                }
+                self.visit(&child_path, child.node_type, depth + 1, visitor);
+            }
+        }
+        visitor.leave(&entry);
+    }
+
+    /// Record a directory as entered. Inode 0 means the file system doesn't
+    /// number its nodes, so only the depth cap protects those.
+    fn first_visit(&mut self, path: &str, inode: usize) -> bool {
+        if inode == 0 {
+            return true;
+        }
+        match self.vfs.find_fs(path) {
+            Ok(fs) => self.visited.insert((Arc::as_ptr(&fs) as *const () as usize, inode)),
+            Err(_) => true,
+        }
+    }
+}
+
+/// Path of `name` inside directory `dir`
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Match a name against a pattern where '*' stands for any run of characters
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Last '*' seen and the name position it was tried against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star, tried)) = backtrack {
+            // Let the '*' swallow one more character
+            p = star + 1;
+            n = tried + 1;
+            backtrack = Some((star, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Check wildcard matching, and walk and stop a walk of a scratch tree in /tmp
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("WALK: Running self-test");
+
+    let cases = [
+        ("*.txt", "notes.txt", true),
+        ("*.txt", "notes.txt.bak", false),
+        ("a*b*c", "aXbYbZc", true),
+        ("a*b*c", "acb", false),
+        ("*", "", true),
+        ("exact", "exact", true),
+    ];
+    for (pattern, name, expected) in cases {
+        if wildcard_match(pattern, name) != expected {
+            serial_println!("WALK: '{}' against '{}' should be {}", pattern, name, expected);
+            return Err(KernelError::ValidationError("Wildcard match is wrong"));
+        }
+    }
+
+    let vfs = match vfs::get_vfs_manager() {
+        Some(vfs) => vfs,
+        None => {
+            serial_println!("WALK: Self-test passed (no file system)");
+            return Ok(());
+        }
+    };
+
+    struct Counter {
+        entries: usize,
+        deepest: usize,
+        left: usize,
+        errors: usize,
+        /// Entries to take before asking to stop
+        stop_after: usize,
+    }
+    impl Visitor for Counter {
+        fn enter(&mut self, entry: &WalkEntry) {
+            self.entries += 1;
+            self.deepest = self.deepest.max(entry.depth);
+        }
+        fn leave(&mut self, _entry: &WalkEntry) {
+            self.left += 1;
+        }
+        fn error(&mut self, _path: &str, _error: KernelError) {
+            self.errors += 1;
+        }
+        fn stop(&mut self) -> bool {
+            self.entries >= self.stop_after
+        }
+    }
+
+    let root = "/tmp/walk-selftest";
+    let paths = [root, "/tmp/walk-selftest/a", "/tmp/walk-selftest/a/b"];
+    let files = ["/tmp/walk-selftest/one", "/tmp/walk-selftest/a/b/two"];
+    let result = (|| {
+        for dir in paths {
+            vfs.create_directory(dir)?;
+        }
+        for file in files {
+            vfs.create_file(file)?;
+        }
+
+        let mut counter = Counter { entries: 0, deepest: 0, left: 0, errors: 0, stop_after: usize::MAX };
+        walk(root, &mut counter)?;
+        if counter.entries != 5 || counter.left != 3 || counter.deepest != 3 || counter.errors != 0 {
+            serial_println!("WALK: {} entries, {} directories left, depth {}, {} errors",
+                counter.entries, counter.left, counter.deepest, counter.errors);
+            return Err(KernelError::ValidationError("Walk visited the wrong entries"));
+        }
+
+        // Stopping ends the walk at once, with no directories left
+        let mut counter = Counter { entries: 0, deepest: 0, left: 0, errors: 0, stop_after: 2 };
+        if !matches!(walk(root, &mut counter), Err(KernelError::Interrupted)) || counter.entries != 2 || counter.left != 0 {
+            return Err(KernelError::ValidationError("Walk did not stop when asked"));
+        }
+        Ok(())
+    })();
+
+    for path in files.iter().rev().chain(paths.iter().rev()) {
+        let _ = vfs.remove(path);
+    }
+
+    result?;
+    serial_println!("WALK: Self-test passed");
+    Ok(())
+}
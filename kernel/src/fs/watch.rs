@@ -0,0 +1,178 @@
+//! Change notification for the VFS.
+//! A watcher registers a path prefix and gets a bounded queue of events for
+//! paths under it. Events are posted after an operation has succeeded and
+//! its file system lock is released. Posting never waits: an event that
+//! doesn't fit, or that arrives while the queue is busy, is dropped and the
+//! queue marked overflowed, which tells its owner to rescan.
+
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// Events a watcher holds before further ones are dropped
+pub const QUEUE_CAPACITY: usize = 64;
+
+/// What happened to a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Created,
+    Removed,
+    /// Contents written or truncated
+    Modified,
+    /// Renamed away from this path
+    MovedFrom,
+    /// Renamed to this path
+    MovedTo,
+}
+
+impl WatchKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WatchKind::Created => "created",
+            WatchKind::Removed => "removed",
+            WatchKind::Modified => "modified",
+            WatchKind::MovedFrom => "moved from",
+            WatchKind::MovedTo => "moved to",
+        }
+    }
+}
+
+/// One change, to `path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    pub path: String,
+    pub kind: WatchKind,
+}
+
+struct Watcher {
+    prefix: String,
+    queue: Mutex<VecDeque<WatchEvent>>,
+    overflowed: AtomicBool,
+}
+
+impl Watcher {
+    fn matches(&self, path: &str) -> bool {
+        self.prefix == "/" || path == self.prefix
+            || (path.starts_with(&self.prefix) && path.as_bytes()[self.prefix.len()] == b'/')
+    }
+
+    fn post(&self, path: &str, kind: WatchKind) {
+        let Some(mut queue) = self.queue.try_lock() else {
+            self.overflowed.store(true, Ordering::Release);
+            return;
+        };
+        // Runs of writes to one file collapse into one event
+        if queue.back().is_some_and(|last| last.kind == kind && last.path == path) {
+            return;
+        }
+        if queue.len() == QUEUE_CAPACITY {
+            self.overflowed.store(true, Ordering::Release);
+            return;
+        }
+        queue.push_back(WatchEvent { path: path.to_string(), kind });
+    }
+}
+
+lazy_static! {
+    // Weak, so dropping a handle is all it takes to stop watching
+    static ref WATCHERS: Mutex<Vec<Weak<Watcher>>> = Mutex::new(Vec::new());
+}
+
+/// A registered watch; dropping it unregisters it
+pub struct WatchHandle {
+    watcher: Arc<Watcher>,
+}
+
+impl WatchHandle {
+    /// The prefix being watched
+    pub fn prefix(&self) -> &str {
+        &self.watcher.prefix
+    }
+
+    /// Take the queued events, and whether any were lost since the last
+    /// call. After a loss the events alone don't describe the changes.
+    pub fn take_events(&self) -> (Vec<WatchEvent>, bool) {
+        let events = self.watcher.queue.lock().drain(..).collect();
+        (events, self.watcher.overflowed.swap(false, Ordering::AcqRel))
+    }
+}
+
+/// Watch `prefix` and every path below it
+pub fn watch(prefix: &str) -> WatchHandle {
+    let prefix = match prefix.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    let watcher = Arc::new(Watcher {
+        prefix: prefix.to_string(),
+        queue: Mutex::new(VecDeque::new()),
+        overflowed: AtomicBool::new(false),
+    });
+    WATCHERS.lock().push(Arc::downgrade(&watcher));
+    WatchHandle { watcher }
+}
+
+/// Tell the watchers of `path` it changed. Call with no file system locked.
+pub fn notify(path: &str, kind: WatchKind) {
+    let watchers: Vec<Arc<Watcher>> = {
+        let mut watchers = WATCHERS.lock();
+        watchers.retain(|watcher| watcher.strong_count() > 0);
+        watchers.iter().filter_map(Weak::upgrade).collect()
+    };
+    for watcher in watchers.iter().filter(|watcher| watcher.matches(path)) {
+        watcher.post(path, kind);
+    }
+}
+
+/// Number of live watchers
+pub fn watcher_count() -> usize {
+    WATCHERS.lock().iter().filter(|watcher| watcher.strong_count() > 0).count()
+}
+
+/// Check prefix matching, coalescing, overflow and unregistering on drop
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("WATCH: Running self-test");
+
+    let before = watcher_count();
+    let docs = watch("/selftest/docs/");
+    let all = watch("/");
+
+    notify("/selftest/docs/a.txt", WatchKind::Created);
+    notify("/selftest/docs/a.txt", WatchKind::Modified);
+    notify("/selftest/docs/a.txt", WatchKind::Modified);
+    notify("/selftest/docsx/b.txt", WatchKind::Created);
+    notify("/selftest/docs", WatchKind::Removed);
+
+    let (events, lost) = docs.take_events();
+    let kinds: Vec<WatchKind> = events.iter().map(|event| event.kind).collect();
+    if lost || kinds != [WatchKind::Created, WatchKind::Modified, WatchKind::Removed] {
+        return Err(KernelError::ValidationError("Watcher got the wrong events for its prefix"));
+    }
+    if all.take_events().0.len() != 4 {
+        return Err(KernelError::ValidationError("Watcher on / missed events"));
+    }
+
+    for i in 0..QUEUE_CAPACITY + 1 {
+        let kind = if i % 2 == 0 { WatchKind::Created } else { WatchKind::Removed };
+        notify("/selftest/docs/c.txt", kind);
+    }
+    let (events, lost) = docs.take_events();
+    if events.len() != QUEUE_CAPACITY || !lost || docs.take_events().1 {
+        return Err(KernelError::ValidationError("Full watch queue didn't report the overflow once"));
+    }
+
+    drop(docs);
+    drop(all);
+    if watcher_count() != before {
+        return Err(KernelError::ValidationError("Dropped watch handle stayed registered"));
+    }
+
+    serial_println!("WATCH: Self-test passed");
+    Ok(())
+}
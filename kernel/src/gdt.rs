@@ -1,68 +1,252 @@
 // kernel/src/gdt.rs
+//! Global Descriptor Table and Task State Segment.
+//!
+//! Each CPU gets its own GDT and TSS; only the boot CPU is brought up, so
+//! there is one set for now. The GDT is laid out as:
+//!
+//! | index | selector | entry                         |
+//! |-------|----------|-------------------------------|
+//! | 0     | 0x00     | null                          |
+//! | 1     | 0x08     | kernel code (64-bit, DPL 0)   |
+//! | 2     | 0x10     | kernel data (DPL 0)           |
+//! | 3     | 0x1b     | user data (DPL 3, RPL 3)      |
+//! | 4     | 0x23     | user code (64-bit, DPL 3, RPL 3) |
+//! | 5-6   | 0x28     | TSS (a 16-byte system entry)  |
+//!
+//! User data comes before user code because SYSRET loads SS and CS from
+//! consecutive entries in that order.
+//!
+//! The GDT is loaded before the heap exists, so the double fault handler
+//! starts on a small static stack. `init_stacks` moves every IST slot onto
+//! a heap stack once the heap is up.
+
+use alloc::vec::Vec;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::serial_println;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-const STACK_SIZE: usize = 4096 * 2; // 8 KiB stack for double fault handler
+/// Interrupt stack table slots the IDT uses
+const IST_INDICES: [u16; 1] = [DOUBLE_FAULT_IST_INDEX];
+
+/// Default size of each interrupt stack
+pub const DEFAULT_IST_STACK_SIZE: usize = 4096 * 4;
+
+/// Size of the double fault stack used until `init_stacks` runs
+const BOOT_IST_STACK_SIZE: usize = 4096 * 2;
 #[allow(dead_code)]
-struct Stack([u8; STACK_SIZE]);
+#[repr(align(16))]
+struct BootStack([u8; BOOT_IST_STACK_SIZE]);
 
-// Static stack for the double fault handler
-// Using `static mut` is generally discouraged, but for a raw stack pointer in TSS it's common.
-// We must ensure this is only written to once during TSS setup.
-static mut DOUBLE_FAULT_STACK: Stack = Stack([0; STACK_SIZE]);
+// Catches double faults during memory and heap bring-up, before there is a
+// heap to allocate the real interrupt stacks from
+static mut BOOT_IST_STACK: BootStack = BootStack([0; BOOT_IST_STACK_SIZE]);
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // Safety: We are initializing the stack pointer. This static mut is only written here.
-            // The stack grows downwards, so we give the address of the top of the stack.
-            let stack_start = VirtAddr::from_ptr(unsafe { &raw const DOUBLE_FAULT_STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end // TSS expects the high address (top of the stack)
-        };
-        // We could also set tss.privilege_stack_table[0] for ring 0 stack if needed,
-        // but bootloader usually handles the initial kernel stack.
-        tss
-    };
+// The live TSS, only ever accessed through the raw pointer from `tss`.
+// RSP0 and the IST are filled in at runtime, by `set_kernel_stack`,
+// `init_gdt` and `init_stacks`.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The GDT and TSS of one CPU, with the selectors into the GDT
+struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
 }
 
-#[allow(dead_code)]
 struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector, // Though data segments are mostly unused in 64-bit mode for flat memory
-    tss_selector: SegmentSelector,
+    kernel_code: SegmentSelector,
+    kernel_data: SegmentSelector,
+    user_data: SegmentSelector,
+    user_code: SegmentSelector,
+    tss: SegmentSelector,
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    static ref BOOT_CPU: CpuTables = {
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment()); // Still needed for some ops
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, data_selector, tss_selector })
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        // User segments carry RPL 3 in their selectors
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        // The TSS is a static, so it outlives the descriptor
+        let tss = gdt.add_entry(unsafe { Descriptor::tss_segment_unchecked(tss()) });
+        CpuTables { gdt, selectors: Selectors { kernel_code, kernel_data, user_data, user_code, tss } }
     };
+
+    /// Heap stacks the IST points into; they must live as long as the TSS
+    static ref IST_STACKS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+// The live TSS. The CPU only reads RSP0 and the IST when it switches
+// stacks, so writing them in place from ring 0 is safe.
+fn tss() -> *mut TaskStateSegment {
+    &raw mut TSS
 }
 
 /// Initializes the Global Descriptor Table and Task State Segment.
-/// This function must be called before interrupts are enabled,
-/// especially if the double fault handler relies on the IST.
+/// This function must be called before interrupts are enabled.
 pub fn init_gdt() {
     use x86_64::instructions::segmentation::{CS, Segment}; // Removed LoadTr
 
-    GDT.0.load(); // Load the GDT structure itself
+    let boot_stack_top = VirtAddr::from_ptr(&raw const BOOT_IST_STACK) + BOOT_IST_STACK_SIZE;
+    unsafe {
+        (*tss()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = boot_stack_top;
+    }
+
+    BOOT_CPU.gdt.load(); // Load the GDT structure itself
 
     unsafe {
-        CS::set_reg(GDT.1.code_selector); // Reload code segment selector
-        
+        CS::set_reg(BOOT_CPU.selectors.kernel_code); // Reload code segment selector
+
         // Direct use of the `ltr` instruction via inline assembly
         // The 16-bit TSS selector needs to be in a 16-bit register, typically ax/bx/cx/dx
-        let tss_selector = GDT.1.tss_selector.0 as u16; // Get raw selector value
+        let tss_selector = BOOT_CPU.selectors.tss.0 as u16; // Get raw selector value
         core::arch::asm!("ltr ax", in("ax") tss_selector);
     }
     crate::serial_println!("GDT and TSS initialized and loaded.");
-}
\ No newline at end of file
+}
+
+/// Give every IST slot in use a heap stack of `stack_size` bytes. Needs
+/// the heap; call once, right after it is set up.
+pub fn init_stacks(stack_size: usize) -> Result<(), KernelError> {
+    let mut stacks = IST_STACKS.lock();
+    if !stacks.is_empty() {
+        return Err(KernelError::InvalidOperation);
+    }
+    for &index in IST_INDICES.iter() {
+        let mut stack = Vec::new();
+        stack.try_reserve_exact(stack_size).map_err(|_| KernelError::OutOfMemory)?;
+        stack.resize(stack_size, 0);
+        // The stack grows down, so the TSS gets its (16-byte aligned) top
+        let top = (VirtAddr::from_ptr(stack.as_ptr()) + stack_size).align_down(16u64);
+        unsafe {
+            (*tss()).interrupt_stack_table[index as usize] = top;
+        }
+        stacks.push(stack);
+    }
+    serial_println!("DEBUG: {} interrupt stack(s) of {} bytes installed", IST_INDICES.len(), stack_size);
+    Ok(())
+}
+
+/// Kernel code selector (CS in ring 0)
+pub fn kernel_code_selector() -> SegmentSelector {
+    BOOT_CPU.selectors.kernel_code
+}
+
+/// Kernel data selector (SS in ring 0)
+pub fn kernel_data_selector() -> SegmentSelector {
+    BOOT_CPU.selectors.kernel_data
+}
+
+/// User code selector, RPL 3
+pub fn user_code_selector() -> SegmentSelector {
+    BOOT_CPU.selectors.user_code
+}
+
+/// User data selector, RPL 3
+pub fn user_data_selector() -> SegmentSelector {
+    BOOT_CPU.selectors.user_data
+}
+
+/// Selectors (code, stack) used when returning to ring 3
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (user_code_selector(), user_data_selector())
+}
+
+/// Set the stack the CPU switches to when an interrupt or system call
+/// arrives from ring 3 (TSS RSP0). The scheduler calls this on every
+/// switch to a user task.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*tss()).privilege_stack_table[0] = stack_top;
+    }
+}
+
+/// The stack set by `set_kernel_stack`
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*tss()).privilege_stack_table[0] }
+}
+
+/// Read the loaded GDT back with SGDT and check each entry against the
+/// layout above, and that TR and the IST point where they should
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("GDT: Running self-test");
+
+    #[repr(C, packed)]
+    struct Gdtr {
+        limit: u16,
+        base: u64,
+    }
+    let mut gdtr = Gdtr { limit: 0, base: 0 };
+    let mut tr: u16;
+    unsafe {
+        core::arch::asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack, preserves_flags));
+        core::arch::asm!("str {0:x}", out(reg) tr, options(nomem, nostack, preserves_flags));
+    }
+    let (base, limit) = (gdtr.base, gdtr.limit);
+    if limit < 7 * 8 - 1 {
+        return Err(KernelError::ValidationError("Loaded GDT is too short"));
+    }
+    let entries = unsafe { core::slice::from_raw_parts(base as *const u64, 7) };
+
+    // Access byte bits
+    const PRESENT: u64 = 1 << 47;
+    const DPL_3: u64 = 3 << 45;
+    const CODE_OR_DATA: u64 = 1 << 44;
+    const EXECUTABLE: u64 = 1 << 43;
+    const LONG_MODE: u64 = 1 << 53;
+    let segment = |entry: u64, dpl: u64, code: bool| {
+        entry & (PRESENT | CODE_OR_DATA) == PRESENT | CODE_OR_DATA
+            && entry & DPL_3 == dpl
+            && (entry & EXECUTABLE != 0) == code
+            && (!code || entry & LONG_MODE != 0)
+    };
+    if entries[0] != 0 || !segment(entries[1], 0, true) || !segment(entries[2], 0, false)
+        || !segment(entries[3], DPL_3, false) || !segment(entries[4], DPL_3, true) {
+        return Err(KernelError::ValidationError("GDT segment entries don't match the documented layout"));
+    }
+
+    let expected = [(kernel_code_selector(), 0x08), (kernel_data_selector(), 0x10),
+        (user_data_selector(), 0x1b), (user_code_selector(), 0x23), (BOOT_CPU.selectors.tss, 0x28)];
+    if expected.iter().any(|&(selector, value)| selector.0 != value) || tr != 0x28 {
+        return Err(KernelError::ValidationError("Selectors don't match the documented layout"));
+    }
+
+    // A 64-bit TSS, busy once loaded, whose base is split across both halves
+    let (low, high) = (entries[5], entries[6]);
+    let tss_type = (low >> 40) & 0xF;
+    let tss_base = ((low >> 16) & 0xFF_FFFF) | ((low >> 56) & 0xFF) << 24 | (high & 0xFFFF_FFFF) << 32;
+    if low & PRESENT == 0 || tss_type != 0xB || tss_base != tss() as u64 {
+        return Err(KernelError::ValidationError("TSS descriptor doesn't describe the loaded TSS"));
+    }
+
+    let stacks = IST_STACKS.lock();
+    for (&index, stack) in IST_INDICES.iter().zip(stacks.iter()) {
+        let top = unsafe { (*tss()).interrupt_stack_table[index as usize] }.as_u64();
+        let start = stack.as_ptr() as u64;
+        if top % 16 != 0 || top <= start || top > start + stack.len() as u64 {
+            return Err(KernelError::ValidationError("IST entry doesn't point into its stack"));
+        }
+    }
+    if stacks.len() != IST_INDICES.len() {
+        return Err(KernelError::ValidationError("Interrupt stacks were never installed"));
+    }
+    drop(stacks);
+
+    let saved = kernel_stack();
+    set_kernel_stack(VirtAddr::new(0x1000));
+    let round_trip = kernel_stack();
+    set_kernel_stack(saved);
+    if round_trip != VirtAddr::new(0x1000) {
+        return Err(KernelError::ValidationError("RSP0 didn't take the stack set"));
+    }
+
+    serial_println!("GDT: Self-test passed");
+    Ok(())
+}
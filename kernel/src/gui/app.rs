@@ -4,25 +4,36 @@
 use crate::drivers::vga_enhanced::{self, Color};
 use crate::serial_println;
 use crate::errors::KernelError;
-use crate::gui::window::{Window, WindowHandle, create_window};
-use crate::gui::desktop;
+use crate::gui::window::{Window, WindowHandle, create_window, WINDOW_TEXT};
+use crate::gui::{calculator, desktop, events, settings, sysmon};
+use crate::shell::{CapturedOutput, Shell};
+use crate::user::motd;
 use alloc::string::String;
 use alloc::string::ToString;
 use alloc::boxed::Box;
 use alloc::format;
 use alloc::vec::Vec;
 use alloc::rc::Rc;
+use alloc::sync::Arc;
 use spin::Mutex;
 
-/// Callback type for creating an app window
+/// Callback type for creating an app window. Runs with the desktop locked,
+/// so it must not call back into the desktop.
 pub type AppCreateFn = Box<dyn Fn() -> Result<WindowHandle, KernelError> + Send + Sync>;
 
+/// Callback reopening an app window from the state it saved with
+/// `Window::set_session_state`; same locking rules as `AppCreateFn`
+pub type AppRestoreFn = Box<dyn Fn(&str) -> Result<WindowHandle, KernelError> + Send + Sync>;
+
 /// Represents an application icon on the desktop
 pub struct AppIcon {
     /// Application name
     pub name: String,
     /// Function to create an instance of the app
     pub create_fn: Option<AppCreateFn>,
+    /// Function to reopen the app with saved state; apps without one are
+    /// just created again
+    pub restore_fn: Option<AppRestoreFn>,
 }
 
 impl AppIcon {
@@ -31,16 +42,35 @@ impl AppIcon {
         Self {
             name: name.to_string(),
             create_fn: Some(create_fn),
+            restore_fn: None,
         }
     }
+    
+    /// Give the app a restore hook
+    pub fn with_restore(mut self, restore_fn: AppRestoreFn) -> Self {
+        self.restore_fn = Some(restore_fn);
+        self
+    }
+    
+    /// Open a window of the app, restoring `state` if the app can
+    pub fn launch(&self, state: Option<&str>) -> Result<WindowHandle, KernelError> {
+        let handle = match (state, &self.restore_fn, &self.create_fn) {
+            (Some(state), Some(restore_fn), _) => restore_fn(state)?,
+            (_, _, Some(create_fn)) => create_fn()?,
+            _ => return Err(KernelError::UnsupportedFeature),
+        };
+        handle.lock().set_app(&self.name);
+        Ok(handle)
+    }
 }
 
 /// Register the default applications for the GUI
 pub fn register_default_apps() -> Result<(), KernelError> {
     serial_println!("DEBUG: Registering default applications");
     
-    // Register Terminal app
-    desktop::add_icon(AppIcon::new("Terminal", Box::new(create_terminal_app)))?;
+    // Register Terminal app; it comes back in the directory it was left in
+    desktop::add_icon(AppIcon::new("Terminal", Box::new(create_terminal_app))
+        .with_restore(Box::new(restore_terminal_app)))?;
     
     // Register About app
     desktop::add_icon(AppIcon::new("About", Box::new(create_about_app)))?;
@@ -49,71 +79,107 @@ pub fn register_default_apps() -> Result<(), KernelError> {
     desktop::add_icon(AppIcon::new("Files", Box::new(create_files_app)))?;
     
     // Register Settings app
-    desktop::add_icon(AppIcon::new("Settings", Box::new(create_settings_app)))?;
+    desktop::add_icon(AppIcon::new("Settings", Box::new(settings::create_app)))?;
+    
+    // Register Calculator and System Monitor apps
+    desktop::add_icon(AppIcon::new("Calculator", Box::new(calculator::create_app)))?;
+    desktop::add_icon(AppIcon::new("Monitor", Box::new(sysmon::create_app)))?;
     
     Ok(())
 }
 
 /// Create a terminal app window
 fn create_terminal_app() -> Result<WindowHandle, KernelError> {
+    create_terminal("/")
+}
+
+/// Reopen a terminal in the directory saved with the session, or in / if
+/// that directory is gone
+fn restore_terminal_app(state: &str) -> Result<WindowHandle, KernelError> {
+    create_terminal(if is_directory(state) { state } else { "/" })
+}
+
+fn is_directory(path: &str) -> bool {
+    crate::fs::vfs::get_vfs_manager().and_then(|vfs| vfs.metadata(path).ok())
+        .map_or(false, |metadata| metadata.node_type == crate::fs::vfs::NodeType::Directory)
+}
+
+/// Where `cd target` from `dir` leads
+fn terminal_path(dir: &str, target: &str) -> String {
+    match target {
+        "" | "/" => "/".to_string(),
+        "." => dir.to_string(),
+        ".." => match dir.trim_end_matches('/').rsplit_once('/') {
+            Some((parent, _)) if !parent.is_empty() => parent.to_string(),
+            _ => "/".to_string(),
+        },
+        _ if target.starts_with('/') => target.to_string(),
+        _ => crate::fs::walk::join(dir, target),
+    }
+}
+
+/// Time between checks for fswatch events in a terminal (milliseconds)
+const TERMINAL_POLL_INTERVAL_MS: u64 = 200;
+
+/// Create a terminal window working in `dir`, with a shell of its own
+fn create_terminal(dir: &str) -> Result<WindowHandle, KernelError> {
     serial_println!("DEBUG: Creating terminal app window");
     
     // Create a terminal window
     let window_handle = create_window("Terminal", 10, 2, 60, 18);
     
-    // Clone for the closure
-    let window_handle_for_closure = window_handle.clone();
+    // Each terminal loads the shared history file but keeps its own list
+    let mut shell = Shell::detached(dir);
+    shell.load_history();
+    let shell = Arc::new(Mutex::new(shell));
     
     // Set up terminal functionality
     {
         let mut window = window_handle.lock();
         window.add_text("UniverseK OS Terminal\n");
-        window.add_text("Type 'help' for a list of commands\n");
+        window.add_text("Type 'help' for a list of commands; exit or Esc closes this window\n");
+        for line in motd::lines() {
+            window.add_text(&format!("{}\n", line));
+        }
+        window.set_session_state(dir);
+        window.set_escape_closes(true);
         
-        // Set up input handling with a closure that owns its own copy of the handle
-        window.enable_input(Box::new(move |input| {
-            // Simple command handling logic
-            match input.trim() {
-                "help" => {
-                    let mut window = window_handle_for_closure.lock();
-                    window.add_text("Available commands:\n");
-                    window.add_text("  help - Display this help message\n");
-                    window.add_text("  clear - Clear the screen\n");
-                    window.add_text("  exit - Close this terminal\n");
-                    window.add_text("  about - Display system information\n");
-                }
-                "clear" => {
-                    let mut window = window_handle_for_closure.lock();
-                    window.clear();
-                }
-                "exit" => {
-                    // Will be handled by desktop by closing window
-                    return Ok(());
-                }
-                "about" => {
-                    let mut window = window_handle_for_closure.lock();
-                    window.add_text("UniverseK OS v0.1.0\n");
-                    window.add_text("A simple operating system for learning\n");
-                }
-                "" => {}
-                _ => {
-                    let mut window = window_handle_for_closure.lock();
-                    window.add_text(&format!("Unknown command: {}\n", input));
-                }
+        // The callback is lent the window, so it doesn't keep a handle to it
+        let terminal = shell.clone();
+        window.enable_input(Box::new(move |window, input| {
+            let mut shell = terminal.lock();
+            if input.trim() == "exit" && !shell.reading_password() {
+                window.request_close();
+                return Ok(());
             }
-            
+            let output = shell.run_line(input);
+            show_terminal_output(window, output);
+            window.set_input_hidden(shell.reading_password());
+            window.set_session_state(shell.current_dir());
             Ok(())
         }));
     }
     
-    // Add window to desktop
-    let desktop = desktop::DESKTOP.lock();
-    // We're returning the window handle directly, no need to add it here
-    // The desktop management will need to be refactored later
+    // Watch notices arrive between lines
+    super::add_frame_hook(&window_handle, TERMINAL_POLL_INTERVAL_MS, move |window: &mut Window| {
+        let mut shell = shell.lock();
+        shell.poll_watch();
+        show_terminal_output(window, shell.take_output());
+    });
     
     Ok(window_handle)
 }
 
+/// Add what a terminal's shell printed to its window
+fn show_terminal_output(window: &mut Window, output: CapturedOutput) {
+    if output.clear {
+        window.clear();
+    }
+    for (line, color) in output.lines {
+        window.add_colored_text(&format!("{}\n", line), color);
+    }
+}
+
 /// Create an about app window
 fn create_about_app() -> Result<WindowHandle, KernelError> {
     serial_println!("DEBUG: Creating about app window");
@@ -123,82 +189,190 @@ fn create_about_app() -> Result<WindowHandle, KernelError> {
     
     {
         let mut window = window_handle.lock();
-        window.add_text("UniverseK OS v0.1.0\n\n");
+        window.add_text(&format!("{}\n", crate::version::name_and_version()));
+        window.add_text(&format!("Built {} UTC ({})\n\n", crate::version::BUILD_TIME, crate::version::GIT_HASH));
         window.add_text("A simple operating system for learning.\n");
         window.add_text("Features:\n");
         window.add_text("- Custom bootloader\n");
         window.add_text("- Memory management\n");
         window.add_text("- File system support\n");
         window.add_text("- Simple GUI environment\n\n");
-        window.add_text("Created as a learning project.\n");
+        window.add_text("Created as a learning project.\n\n");
+        window.add_text("Keyboard (no mouse needed):\n");
+        for (keys, action) in events::SHORTCUTS {
+            window.add_text(&format!("  {:<18} {}\n", keys, action));
+        }
     }
     
     Ok(window_handle)
 }
 
-/// Create a file explorer app window
-fn create_files_app() -> Result<WindowHandle, KernelError> {
-    serial_println!("DEBUG: Creating file explorer app window");
-    
-    // Create a file explorer window
-    let window_handle = create_window("File Explorer", 5, 3, 55, 16);
+/// Time between checks for changes to the shown directory (milliseconds)
+const FILES_WATCH_INTERVAL_MS: u64 = 250;
+
+/// What a file explorer window shows
+enum FilesView {
+    /// The listing of a directory
+    Directory(String),
+    /// The entries of a trash, at its path
+    Trash(String),
+}
+
+/// Fill a file explorer window with `view`
+fn show_files_view(window: &mut Window, view: &FilesView) {
+    match view {
+        FilesView::Directory(path) => show_directory(window, path),
+        FilesView::Trash(trash) => show_trash(window, trash),
+    }
+}
+
+/// Fill a file explorer window with the listing of `path`, each file
+/// labelled with what it holds
+fn show_directory(window: &mut Window, path: &str) {
+    window.clear();
+    window.add_text("File Explorer (open <dir>, delete <name>, trash)\n\n");
+    window.add_text(&format!("Contents of {}:\n", path));
     
-    {
-        let mut window = window_handle.lock();
-        window.add_text("File Explorer\n\n");
-        
-        // Show root directory contents
-        window.add_text("Contents of /:\n");
-        
-        // Try to read the directory if file system is available
-        match crate::fs::vfs::get_vfs_manager() {
-            Some(vfs) => {
-                match vfs.read_dir("/") {
-                    Ok(entries) => {
-                        if entries.is_empty() {
-                            window.add_text("  (empty directory)\n");
-                        } else {
-                            for entry in entries {
-                                let type_indicator = match entry.node_type {
-                                    crate::fs::vfs::NodeType::Directory => "/",
-                                    crate::fs::vfs::NodeType::File => "",
-                                    _ => "?",
-                                };
-                                window.add_text(&format!("  {}{}\n", entry.name, type_indicator));
+    // Try to read the directory if file system is available
+    match crate::fs::vfs::get_vfs_manager() {
+        Some(vfs) => {
+            let mut listed = 0;
+            let mut failed = false;
+            for entry in vfs.read_dir_paged(path) {
+                match entry {
+                    Ok(entry) => {
+                        // Files are labelled with what they hold, as `file` tells it
+                        let (line, color) = match entry.node_type {
+                            crate::fs::vfs::NodeType::Directory => (format!("{}/", entry.name), Color::LightCyan),
+                            crate::fs::vfs::NodeType::File => {
+                                let file = crate::fs::walk::join(path, &entry.name);
+                                let label = crate::fs::filetype::detect_path(vfs, &file)
+                                    .map_or("?", |(kind, _)| kind.label());
+                                (format!("{:<20} {}", entry.name, label), WINDOW_TEXT)
                             }
-                        }
+                            _ => (format!("{}?", entry.name), WINDOW_TEXT),
+                        };
+                        window.add_colored_text(&format!("  {}\n", line), color);
+                        listed += 1;
                     },
                     Err(e) => {
-                        window.add_text(&format!("Error reading directory: {:?}\n", e));
+                        window.add_colored_text(&format!("Error reading directory: {:?}\n", e), Color::Red);
+                        failed = true;
                     }
                 }
-            },
-            None => {
-                window.add_text("File system not initialized.\n");
             }
+            if listed == 0 && !failed {
+                window.add_text("  (empty directory)\n");
+            }
+        },
+        None => {
+            window.add_text("File system not initialized.\n");
         }
     }
-    
-    Ok(window_handle)
 }
 
-/// Create a settings app window
-fn create_settings_app() -> Result<WindowHandle, KernelError> {
-    serial_println!("DEBUG: Creating settings app window");
-    
-    // Create a settings window
-    let window_handle = create_window("System Settings", 20, 4, 40, 14);
+/// Fill a file explorer window with what is in `trash`
+fn show_trash(window: &mut Window, trash: &str) {
+    window.clear();
+    window.add_text("Trash (restore <name>, empty, open <dir>)\n\n");
+    let entries = match crate::fs::trash::list(trash) {
+        Err(KernelError::NotFound) => Ok(Vec::new()),
+        result => result,
+    };
+    match entries {
+        Ok(entries) if entries.is_empty() => window.add_text("  (the trash is empty)\n"),
+        Ok(entries) => {
+            for entry in entries {
+                window.add_text(&format!("  {}\n", entry.name));
+                window.add_colored_text(&format!("    from {}\n", entry.original), Color::DarkGray);
+            }
+        }
+        Err(e) => window.add_colored_text(&format!("Error reading the trash: {:?}\n", e), Color::Red),
+    }
+}
+
+/// Carry out a line typed into a file explorer, then show the view it
+/// leaves and what happened
+fn files_command(window: &mut Window, view: &mut FilesView, line: &str) {
+    let (command, argument) = line.trim().split_once(' ').map_or((line.trim(), ""), |(c, a)| (c, a.trim()));
+    let (dir, shown_trash) = match view {
+        FilesView::Directory(path) => (path.clone(), None),
+        FilesView::Trash(trash) => (user_home_or_root(), Some(trash.clone())),
+    };
+    let result: Result<Option<String>, KernelError> = match (command, shown_trash.as_deref()) {
+        ("open" | "cd", _) => {
+            let target = terminal_path(&dir, argument);
+            if is_directory(&target) {
+                *view = FilesView::Directory(target);
+                Ok(None)
+            } else {
+                Err(KernelError::NotADirectory)
+            }
+        }
+        ("delete", None) if !argument.is_empty() => {
+            let path = terminal_path(&dir, argument);
+            let trashed = crate::fs::trash::enabled() && crate::fs::trash::trash_for(&path).is_some();
+            crate::fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)
+                .and_then(|vfs| vfs.remove(&path))
+                .map(|()| Some(format!("{} {}", if trashed { "Moved to the trash:" } else { "Deleted:" }, path)))
+        }
+        ("trash", _) => match crate::fs::trash::user_trash() {
+            Some(trash) => {
+                *view = FilesView::Trash(trash);
+                Ok(None)
+            }
+            None => Ok(Some(String::from("Only users with a home under /Users have a trash"))),
+        },
+        ("restore", Some(trash)) if !argument.is_empty() => {
+            crate::fs::trash::restore(trash, argument).map(|original| Some(format!("Restored: {}", original)))
+        }
+        ("empty", Some(trash)) => {
+            crate::fs::trash::empty(trash).map(|count| Some(format!("Removed {} entries for good", count)))
+        }
+        _ => Ok(Some(String::from("Commands: open <dir>, delete <name>, trash; in the trash: restore <name>, empty"))),
+    };
+    show_files_view(window, view);
+    match result {
+        Ok(Some(message)) => window.add_text(&format!("\n{}\n", message)),
+        Ok(None) => {}
+        Err(e) => window.add_colored_text(&format!("\n{}: {}\n", line.trim(), e), Color::Red),
+    }
+}
+
+/// Where the file explorer goes back to from the trash
+fn user_home_or_root() -> String {
+    let home = crate::user::home_dir();
+    if is_directory(&home) { home } else { "/".to_string() }
+}
+
+/// Create a file explorer app window
+fn create_files_app() -> Result<WindowHandle, KernelError> {
+    serial_println!("DEBUG: Creating file explorer app window");
     
+    // Create a file explorer window
+    let window_handle = create_window("File Explorer", 5, 3, 55, 16);
+    let view = Arc::new(Mutex::new(FilesView::Directory("/".to_string())));
     {
         let mut window = window_handle.lock();
-        window.add_text("System Settings\n\n");
-        window.add_text("Note: This is a mock settings app. Features are not\n");
-        window.add_text("implemented yet.\n\n");
-        window.add_text("[ ] Enable advanced CPU features\n");
-        window.add_text("[ ] Use hardware acceleration\n");
-        window.add_text("[ ] Show system stats in taskbar\n");
-        window.add_text("[ ] Enable power saving\n");
+        show_files_view(&mut window, &view.lock());
+        let view = view.clone();
+        window.enable_input(Box::new(move |window, input| {
+            files_command(window, &mut view.lock(), input);
+            Ok(())
+        }));
+    }
+    
+    // Redraw whenever something changes; the watch goes away with the
+    // hook when the window closes
+    if let Some(vfs) = crate::fs::vfs::get_vfs_manager() {
+        let watch = vfs.watch("/");
+        super::add_frame_hook(&window_handle, FILES_WATCH_INTERVAL_MS, move |window: &mut Window| {
+            let (events, lost) = watch.take_events();
+            if !events.is_empty() || lost {
+                show_files_view(window, &view.lock());
+            }
+        });
     }
     
     Ok(window_handle)
-} 
\ No newline at end of file
+}
@@ -0,0 +1,272 @@
+//! Calculator app for UniverseK OS GUI
+//! A four-function calculator with integer and fixed-point modes, driven by
+//! its buttons or the keyboard
+
+use crate::errors::KernelError;
+use crate::gui::widget::WidgetEvent;
+use crate::gui::window::{create_window, WindowHandle};
+use crate::i18n::MSG_BUTTON_MODE;
+use crate::serial_println;
+use crate::tr;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use spin::Mutex;
+
+/// Fixed-point values carry this many decimal places
+const DECIMALS: usize = 4;
+const SCALE: i64 = 10_000;
+/// Longest number that can be typed, in digits
+const MAX_DIGITS: usize = 12;
+/// Width of the display line
+const DISPLAY_WIDTH: usize = 20;
+
+/// Whether results keep a fractional part
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Integer,
+    Fixed,
+}
+
+/// Calculator state; values are stored scaled by SCALE in both modes
+pub struct Calculator {
+    mode: Mode,
+    accumulator: Option<i64>,
+    pending_op: Option<char>,
+    entry: String,
+    error: Option<&'static str>,
+}
+
+impl Calculator {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Fixed,
+            accumulator: None,
+            pending_op: None,
+            entry: String::new(),
+            error: None,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Forget the current calculation
+    pub fn clear(&mut self) {
+        self.accumulator = None;
+        self.pending_op = None;
+        self.entry.clear();
+        self.error = None;
+    }
+
+    /// Switch between integer and fixed-point math, clearing the calculation
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            Mode::Integer => Mode::Fixed,
+            Mode::Fixed => Mode::Integer,
+        };
+        self.clear();
+    }
+
+    /// Feed one key: digits, '.', + - * /, '=' or Enter, Backspace, 'c' or Esc
+    pub fn press(&mut self, key: char) {
+        if self.error.is_some() && key != '\x08' {
+            self.clear();
+        }
+
+        match key {
+            '0'..='9' => {
+                let digits = self.entry.chars().filter(char::is_ascii_digit).count();
+                if digits < MAX_DIGITS {
+                    self.entry.push(key);
+                }
+            }
+            '.' => {
+                if self.mode == Mode::Fixed && !self.entry.contains('.') {
+                    if self.entry.is_empty() {
+                        self.entry.push('0');
+                    }
+                    self.entry.push('.');
+                }
+            }
+            '+' | '-' | '*' | '/' => {
+                if !self.entry.is_empty() {
+                    self.evaluate();
+                }
+                if self.accumulator.is_some() && self.error.is_none() {
+                    self.pending_op = Some(key);
+                }
+            }
+            '=' | '\n' => {
+                if self.pending_op.is_some() && !self.entry.is_empty() {
+                    self.evaluate();
+                    self.pending_op = None;
+                }
+            }
+            '\x08' => {
+                self.entry.pop();
+            }
+            'c' | 'C' | '\x1b' => self.clear(),
+            _ => {}
+        }
+    }
+
+    /// Fold the typed number into the accumulator
+    fn evaluate(&mut self) {
+        let value = self.parse_entry();
+        self.entry.clear();
+
+        let result = match (self.accumulator, self.pending_op) {
+            (Some(acc), Some(op)) => self.apply(acc, op, value),
+            _ => Ok(value),
+        };
+        match result {
+            Ok(result) => self.accumulator = Some(result),
+            Err(message) => {
+                self.error = Some(message);
+                self.accumulator = None;
+                self.pending_op = None;
+            }
+        }
+    }
+
+    fn apply(&self, lhs: i64, op: char, rhs: i64) -> Result<i64, &'static str> {
+        let (lhs, rhs) = (lhs as i128, rhs as i128);
+        let result = match op {
+            '+' => lhs + rhs,
+            '-' => lhs - rhs,
+            '*' => lhs * rhs / SCALE as i128,
+            '/' => {
+                if rhs == 0 {
+                    return Err("Division by zero");
+                }
+                lhs * SCALE as i128 / rhs
+            }
+            _ => return Err("Unknown operator"),
+        };
+        // Integer mode drops the fraction, truncating toward zero
+        let result = match self.mode {
+            Mode::Integer => result / SCALE as i128 * SCALE as i128,
+            Mode::Fixed => result,
+        };
+        i64::try_from(result).map_err(|_| "Overflow")
+    }
+
+    fn parse_entry(&self) -> i64 {
+        let (whole, fraction) = self.entry.split_once('.').unwrap_or((&self.entry, ""));
+        let whole: i64 = whole.parse().unwrap_or(0);
+        let mut scaled_fraction = 0;
+        for i in 0..DECIMALS {
+            let digit = fraction.as_bytes().get(i).map_or(0, |b| (b - b'0') as i64);
+            scaled_fraction = scaled_fraction * 10 + digit;
+        }
+        whole * SCALE + scaled_fraction
+    }
+
+    /// Text for the display: the number being typed, else the last result
+    pub fn display(&self) -> String {
+        if let Some(message) = self.error {
+            return String::from(message);
+        }
+        if !self.entry.is_empty() {
+            return self.entry.clone();
+        }
+        format_value(self.accumulator.unwrap_or(0))
+    }
+
+    /// Window content: the display line and the mode
+    fn render(&self) -> String {
+        let mode = match self.mode {
+            Mode::Integer => "integer",
+            Mode::Fixed => "fixed-point",
+        };
+        let op = self.pending_op.map_or(String::from(" "), |op| format!("{}", op));
+        format!("{} {:>width$}\nMode: {}\n", op, self.display(), mode, width = DISPLAY_WIDTH - 2)
+    }
+}
+
+/// Format a scaled value, dropping trailing zeros of the fraction
+fn format_value(value: i64) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    let magnitude = value.unsigned_abs();
+    let whole = magnitude / SCALE as u64;
+    let fraction = magnitude % SCALE as u64;
+    if fraction == 0 {
+        return format!("{}{}", sign, whole);
+    }
+    let digits = format!("{:0width$}", fraction, width = DECIMALS);
+    format!("{}{}.{}", sign, whole, digits.trim_end_matches('0'))
+}
+
+/// Create a calculator window
+pub fn create_app() -> Result<WindowHandle, KernelError> {
+    serial_println!("DEBUG: Creating calculator app window");
+
+    let window_handle = create_window("Calculator", 28, 3, 24, 13);
+    {
+        let mut window = window_handle.lock();
+        let rows = ["789/", "456*", "123-", "0.=+"];
+        for (row, keys) in rows.iter().enumerate() {
+            for (column, key) in keys.char_indices() {
+                window.add_button(&key.to_string(), 1 + column * 4, 3 + row);
+            }
+        }
+        window.add_button("C", 1, 7);
+        // Buttons are told apart by label, so remember the translated one
+        let mode_label = tr!(MSG_BUTTON_MODE);
+        window.add_button(mode_label, 5, 7);
+
+        let calculator = Mutex::new(Calculator::new());
+        window.set_text(&calculator.lock().render());
+        window.set_widget_callback(Box::new(move |window, event| {
+            let mut calculator = calculator.lock();
+            match event {
+                WidgetEvent::Button(label) if label == mode_label => calculator.toggle_mode(),
+                WidgetEvent::Button(label) => {
+                    if let Some(key) = label.chars().next() {
+                        calculator.press(key);
+                    }
+                }
+                WidgetEvent::Key('m') | WidgetEvent::Key('M') => calculator.toggle_mode(),
+                WidgetEvent::Key(key) => calculator.press(key),
+                WidgetEvent::Select(..) | WidgetEvent::Submit(_) => {}
+            }
+            window.set_text(&calculator.render());
+            Ok(())
+        }));
+    }
+
+    Ok(window_handle)
+}
+
+/// Run a few key sequences through the calculator and check the results
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("CALCULATOR: Running self-test");
+
+    let cases: [(Mode, &str, &str); 6] = [
+        (Mode::Fixed, "12.5*4=", "50"),
+        (Mode::Fixed, "1/3=", "0.3333"),
+        (Mode::Fixed, "2-5.25=", "-3.25"),
+        (Mode::Fixed, "7/0=", "Division by zero"),
+        (Mode::Integer, "7/2=", "3"),
+        (Mode::Integer, "6+4*3=", "30"),
+    ];
+
+    for (mode, keys, expected) in cases.iter() {
+        let mut calculator = Calculator::new();
+        if calculator.mode() != *mode {
+            calculator.toggle_mode();
+        }
+        for key in keys.chars() {
+            calculator.press(key);
+        }
+        if calculator.display() != *expected {
+            serial_println!("CALCULATOR: '{}' gave '{}', expected '{}'", keys, calculator.display(), expected);
+            return Err(KernelError::ValidationError("Calculator gave the wrong result"));
+        }
+    }
+
+    serial_println!("CALCULATOR: Self-test passed");
+    Ok(())
+}
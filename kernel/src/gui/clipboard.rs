@@ -0,0 +1,96 @@
+//! Clipboard module for UniverseK OS GUI
+//! A single text clipboard shared by GUI windows and the shell, with a short
+//! history of earlier entries
+
+use crate::errors::KernelError;
+use crate::serial_println;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Number of earlier clipboard entries kept
+pub const HISTORY_LEN: usize = 8;
+/// Largest text accepted, in bytes
+pub const MAX_CLIP_SIZE: usize = 16 * 1024;
+
+struct Clipboard {
+    current: String,
+    /// Earlier entries, most recent first
+    history: VecDeque<String>,
+}
+
+lazy_static! {
+    static ref CLIPBOARD: Mutex<Clipboard> = Mutex::new(Clipboard {
+        current: String::new(),
+        history: VecDeque::with_capacity(HISTORY_LEN),
+    });
+}
+
+/// Replace the clipboard contents, pushing the old text onto the history
+pub fn set(text: &str) -> Result<(), KernelError> {
+    if text.len() > MAX_CLIP_SIZE {
+        return Err(KernelError::ValidationError("Text too large for the clipboard"));
+    }
+
+    let mut clipboard = CLIPBOARD.lock();
+    if clipboard.current == text {
+        return Ok(());
+    }
+    let previous = core::mem::replace(&mut clipboard.current, String::from(text));
+    if !previous.is_empty() {
+        clipboard.history.push_front(previous);
+        clipboard.history.truncate(HISTORY_LEN);
+    }
+    Ok(())
+}
+
+/// Current clipboard contents
+pub fn get() -> String {
+    CLIPBOARD.lock().current.clone()
+}
+
+/// Earlier clipboard entries, most recent first
+pub fn history() -> Vec<String> {
+    CLIPBOARD.lock().history.iter().cloned().collect()
+}
+
+/// Empty the clipboard and its history
+pub fn clear() {
+    let mut clipboard = CLIPBOARD.lock();
+    clipboard.current.clear();
+    clipboard.history.clear();
+}
+
+/// Check set/get, history order and trimming, then restore the old contents
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("CLIPBOARD: Running self-test");
+
+    let saved_current = get();
+    let saved_history = history();
+    clear();
+
+    for i in 0..HISTORY_LEN + 3 {
+        set(&alloc::format!("entry {}", i))?;
+    }
+    let last = alloc::format!("entry {}", HISTORY_LEN + 2);
+    let result = if get() != last {
+        Err(KernelError::ValidationError("Clipboard returned the wrong text"))
+    } else if history().len() != HISTORY_LEN || history()[0] != alloc::format!("entry {}", HISTORY_LEN + 1) {
+        Err(KernelError::ValidationError("Clipboard history is wrong"))
+    } else if set(&"x".repeat(MAX_CLIP_SIZE + 1)).is_ok() {
+        Err(KernelError::ValidationError("Oversized clipboard text was accepted"))
+    } else {
+        Ok(())
+    };
+
+    let mut clipboard = CLIPBOARD.lock();
+    clipboard.current = saved_current;
+    clipboard.history = saved_history.into_iter().collect();
+    drop(clipboard);
+
+    result?;
+    serial_println!("CLIPBOARD: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,318 @@
+//! Compositor for UniverseK OS GUI
+//! Tracks which screen cells are damaged and redraws only those. GUI drawing
+//! goes into a back buffer, clipped to the damaged cells, and a frame is
+//! presented by copying just those cells to the VGA screen.
+
+use crate::drivers::cp437;
+use crate::drivers::vga_enhanced::{self, Color};
+use crate::errors::KernelError;
+use crate::serial_println;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Screen size in character cells
+pub const SCREEN_WIDTH: usize = 80;
+pub const SCREEN_HEIGHT: usize = 25;
+/// Cells in a full-screen redraw, for comparison with the frame counters
+pub const SCREEN_CELLS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// A rectangle of character cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self { x, y, width, height }
+    }
+
+    /// The whole screen
+    pub const fn screen() -> Self {
+        Self::new(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Smallest rectangle covering both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        !self.is_empty() && !other.is_empty()
+            && self.x < other.x + other.width && other.x < self.x + self.width
+            && self.y < other.y + other.height && other.y < self.y + self.height
+    }
+
+    /// Part of the rectangle that is on screen
+    fn clipped(&self) -> Rect {
+        let x = self.x.min(SCREEN_WIDTH);
+        let y = self.y.min(SCREEN_HEIGHT);
+        let right = (self.x + self.width).min(SCREEN_WIDTH);
+        let bottom = (self.y + self.height).min(SCREEN_HEIGHT);
+        Rect::new(x, y, right.saturating_sub(x), bottom.saturating_sub(y))
+    }
+}
+
+/// Redraw counters, so partial redraws can be compared with full ones
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// Frames presented
+    pub frames: u64,
+    /// Cells copied to the screen by the last frame
+    pub last_frame_cells: usize,
+    /// Cells copied to the screen by all frames
+    pub total_cells: u64,
+}
+
+impl FrameStats {
+    /// Average cells redrawn per frame
+    pub fn average_cells(&self) -> usize {
+        if self.frames == 0 { 0 } else { (self.total_cells / self.frames) as usize }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    byte: u8,
+    fg: Color,
+    bg: Color,
+}
+
+const BLANK: Cell = Cell { byte: b' ', fg: Color::White, bg: Color::Black };
+
+struct Compositor {
+    back: [[Cell; SCREEN_WIDTH]; SCREEN_HEIGHT],
+    /// Cells to redraw in the next frame; overlapping damage counts once
+    damaged: [[bool; SCREEN_WIDTH]; SCREEN_HEIGHT],
+    damaged_count: usize,
+    /// Bounding box of the damaged cells
+    bounds: Option<Rect>,
+    stats: FrameStats,
+}
+
+impl Compositor {
+    fn damage(&mut self, rect: Rect) {
+        let rect = rect.clipped();
+        if rect.is_empty() {
+            return;
+        }
+        for row in &mut self.damaged[rect.y..rect.y + rect.height] {
+            for cell in &mut row[rect.x..rect.x + rect.width] {
+                if !*cell {
+                    *cell = true;
+                    self.damaged_count += 1;
+                }
+            }
+        }
+        self.bounds = Some(self.bounds.map_or(rect, |bounds| bounds.union(&rect)));
+    }
+
+    fn clear_damage(&mut self) {
+        self.damaged = [[false; SCREEN_WIDTH]; SCREEN_HEIGHT];
+        self.damaged_count = 0;
+        self.bounds = None;
+    }
+}
+
+lazy_static! {
+    static ref COMPOSITOR: Mutex<Compositor> = Mutex::new(Compositor {
+        back: [[BLANK; SCREEN_WIDTH]; SCREEN_HEIGHT],
+        damaged: [[false; SCREEN_WIDTH]; SCREEN_HEIGHT],
+        damaged_count: 0,
+        bounds: None,
+        stats: FrameStats::default(),
+    });
+}
+
+/// Mark a rectangle to be redrawn in the next frame
+pub fn damage(rect: Rect) {
+    COMPOSITOR.lock().damage(rect);
+}
+
+/// Mark the whole screen to be redrawn
+pub fn damage_all() {
+    damage(Rect::screen());
+}
+
+/// Whether anything needs redrawing
+pub fn has_damage() -> bool {
+    COMPOSITOR.lock().damaged_count > 0
+}
+
+/// Bounding box of everything that needs redrawing
+pub fn damage_bounds() -> Option<Rect> {
+    COMPOSITOR.lock().bounds
+}
+
+/// Whether drawing inside `rect` could change the next frame
+pub fn intersects_damage(rect: &Rect) -> bool {
+    damage_bounds().map_or(false, |bounds| bounds.intersects(rect))
+}
+
+/// Set by `ui.color_scheme=high-contrast`
+static HIGH_CONTRAST: AtomicBool = AtomicBool::new(false);
+
+/// Turn high-contrast drawing on or off, redrawing everything if it changed
+pub fn set_high_contrast(on: bool) {
+    if HIGH_CONTRAST.swap(on, Ordering::Relaxed) != on {
+        damage_all();
+    }
+}
+
+pub fn high_contrast() -> bool {
+    HIGH_CONTRAST.load(Ordering::Relaxed)
+}
+
+/// The colors a cell is drawn in with high contrast: the background becomes
+/// black or white, whichever it is nearer, and the text the other. Light
+/// text on a dark background stays light and an inverted selection stays
+/// inverted.
+pub fn contrast_pair(bg: Color) -> (Color, Color) {
+    match bg {
+        Color::LightGray | Color::LightBlue | Color::LightGreen | Color::LightCyan
+            | Color::LightRed | Color::Pink | Color::Yellow | Color::White => (Color::Black, Color::White),
+        _ => (Color::White, Color::Black),
+    }
+}
+
+/// Write a string into the back buffer as CP437, like
+/// `vga_enhanced::write_at`, but only into damaged cells and never past the
+/// right edge of the screen
+pub fn write_at(row: usize, column: usize, s: &str, fg: Color, bg: Color) {
+    if row >= SCREEN_HEIGHT {
+        return;
+    }
+
+    let (fg, bg) = if high_contrast() { contrast_pair(bg) } else { (fg, bg) };
+    let mut compositor = COMPOSITOR.lock();
+    let mut column = column;
+    for byte in s.chars().filter(|c| !c.is_control()).map(cp437::encode) {
+        if column >= SCREEN_WIDTH {
+            break;
+        }
+        if compositor.damaged[row][column] {
+            compositor.back[row][column] = Cell { byte, fg, bg };
+        }
+        column += 1;
+    }
+}
+
+/// Copy the damaged cells to the screen and start a new frame, with the
+/// mouse cursor lifted off meanwhile. Returns the number of cells redrawn.
+pub fn present() -> usize {
+    let mut compositor = COMPOSITOR.lock();
+    let bounds = match compositor.bounds {
+        Some(bounds) => bounds,
+        None => return 0,
+    };
+
+    super::cursor::suspend();
+    for row in bounds.y..bounds.y + bounds.height {
+        for column in bounds.x..bounds.x + bounds.width {
+            if compositor.damaged[row][column] {
+                let cell = compositor.back[row][column];
+                vga_enhanced::write_cell(row, column, cell.byte, cell.fg, cell.bg);
+            }
+        }
+    }
+    super::cursor::resume();
+
+    let cells = compositor.damaged_count;
+    compositor.stats.frames += 1;
+    compositor.stats.last_frame_cells = cells;
+    compositor.stats.total_cells += cells as u64;
+    compositor.clear_damage();
+    cells
+}
+
+/// Copy the whole back buffer to the screen, for when something else has
+/// drawn over the desktop. Damage not yet drawn stays for the next frame.
+pub fn repaint() {
+    let compositor = COMPOSITOR.lock();
+    super::cursor::suspend();
+    for (row, cells) in compositor.back.iter().enumerate() {
+        for (column, cell) in cells.iter().enumerate() {
+            vga_enhanced::write_cell(row, column, cell.byte, cell.fg, cell.bg);
+        }
+    }
+    super::cursor::resume();
+}
+
+/// Redraw counters since boot
+pub fn frame_stats() -> FrameStats {
+    COMPOSITOR.lock().stats
+}
+
+/// Check damage merging, clipped drawing and high-contrast colors without
+/// touching the screen. The damage and back buffer contents are restored
+/// afterwards.
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("COMPOSITOR: Running self-test");
+
+    let mut compositor = COMPOSITOR.lock();
+    let saved_back = compositor.back;
+    let saved_damaged = compositor.damaged;
+    let saved_count = compositor.damaged_count;
+    let saved_bounds = compositor.bounds;
+    compositor.clear_damage();
+    compositor.back[5][8].byte = b'#';
+    compositor.back[5][9].byte = b'#';
+    drop(compositor);
+
+    // Two overlapping 4x2 rectangles cover 14 cells, and the part of the
+    // third that is on screen 2 more
+    damage(Rect::new(10, 5, 4, 2));
+    damage(Rect::new(12, 6, 4, 2));
+    damage(Rect::new(78, 24, 10, 10));
+    let was_high_contrast = HIGH_CONTRAST.swap(false, Ordering::Relaxed);
+    write_at(5, 8, "abcdefgh", Color::Yellow, Color::Blue);
+    HIGH_CONTRAST.store(true, Ordering::Relaxed);
+    write_at(6, 12, "ij", Color::Black, Color::LightGray);
+    write_at(6, 14, "kl", Color::Red, Color::Blue);
+    HIGH_CONTRAST.store(was_high_contrast, Ordering::Relaxed);
+
+    let mut compositor = COMPOSITOR.lock();
+    let written: [u8; 6] = core::array::from_fn(|i| compositor.back[5][8 + i].byte);
+    let contrast = (compositor.back[6][12], compositor.back[6][14]);
+    let result = if compositor.damaged_count != 16 {
+        serial_println!("COMPOSITOR: {} cells damaged, expected 16", compositor.damaged_count);
+        Err(KernelError::ValidationError("Overlapping damage counted incorrectly"))
+    } else if compositor.bounds != Some(Rect::new(10, 5, 70, 20)) {
+        Err(KernelError::ValidationError("Damage bounds are wrong"))
+    } else if written != *b"##cdef" {
+        Err(KernelError::ValidationError("Drawing was not clipped to the damage"))
+    } else if (contrast.0.fg, contrast.0.bg, contrast.1.fg, contrast.1.bg)
+        != (Color::Black, Color::White, Color::White, Color::Black) || compositor.back[5][10].fg != Color::Yellow {
+        Err(KernelError::ValidationError("High-contrast colors are wrong"))
+    } else {
+        Ok(())
+    };
+
+    compositor.back = saved_back;
+    compositor.damaged = saved_damaged;
+    compositor.damaged_count = saved_count;
+    compositor.bounds = saved_bounds;
+    drop(compositor);
+
+    result?;
+    serial_println!("COMPOSITOR: Self-test passed");
+    Ok(())
+}
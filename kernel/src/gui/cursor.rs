@@ -0,0 +1,136 @@
+//! Mouse cursor for UniverseK OS GUI
+//! The cursor is an overlay on the screen rather than part of the composited
+//! frame: the cell under it is saved before the glyph is drawn and put back
+//! when the cursor moves or is hidden.
+
+use crate::drivers::vga_enhanced::{self, Color, ScreenChar};
+use crate::errors::KernelError;
+use crate::serial_println;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// How the cursor is drawn
+const CURSOR_GLYPH: &str = "X";
+const CURSOR_FOREGROUND: Color = Color::White;
+const CURSOR_BACKGROUND: Color = Color::Red;
+
+struct Cursor {
+    /// Where the cursor should be, in cells
+    position: (usize, usize),
+    /// Whether the glyph is meant to be on screen
+    visible: bool,
+    /// Cell the glyph covers, if it is drawn right now
+    saved: Option<((usize, usize), ScreenChar)>,
+    /// Nesting depth of suspend(); the glyph stays off while it is above 0
+    suspended: usize,
+}
+
+impl Cursor {
+    /// Put back the cell under the glyph
+    fn erase(&mut self) {
+        if let Some(((x, y), cell)) = self.saved.take() {
+            vga_enhanced::restore_cell(y, x, cell);
+        }
+    }
+
+    /// Save the cell at the cursor position and draw the glyph over it
+    fn paint(&mut self) {
+        if !self.visible || self.suspended > 0 || self.saved.is_some() {
+            return;
+        }
+        let (x, y) = self.position;
+        if let Some(cell) = vga_enhanced::read_cell(y, x) {
+            self.saved = Some(((x, y), cell));
+            vga_enhanced::write_at(y, x, CURSOR_GLYPH, CURSOR_FOREGROUND, CURSOR_BACKGROUND);
+        }
+    }
+}
+
+lazy_static! {
+    static ref CURSOR: Mutex<Cursor> = Mutex::new(Cursor {
+        position: (0, 0),
+        visible: false,
+        saved: None,
+        suspended: 0,
+    });
+}
+
+/// Show the cursor at its current position
+pub fn show() {
+    let mut cursor = CURSOR.lock();
+    cursor.visible = true;
+    cursor.paint();
+}
+
+/// Remove the cursor from the screen, restoring the cell under it
+pub fn hide() {
+    let mut cursor = CURSOR.lock();
+    cursor.visible = false;
+    cursor.erase();
+}
+
+/// Move the cursor to cell (`x`, `y`): restore the old cell, then save and
+/// overdraw the new one
+pub fn move_to(x: usize, y: usize) {
+    let mut cursor = CURSOR.lock();
+    if cursor.position == (x, y) {
+        return;
+    }
+    cursor.erase();
+    cursor.position = (x, y);
+    cursor.paint();
+}
+
+/// Take the cursor off the screen while cells are redrawn, so a redraw
+/// neither overwrites the saved cell with stale contents nor saves the
+/// cursor's own glyph. Must be paired with `resume`.
+pub fn suspend() {
+    let mut cursor = CURSOR.lock();
+    cursor.suspended += 1;
+    cursor.erase();
+}
+
+/// End a `suspend`, drawing the cursor again over the fresh contents
+pub fn resume() {
+    let mut cursor = CURSOR.lock();
+    cursor.suspended = cursor.suspended.saturating_sub(1);
+    cursor.paint();
+}
+
+/// Move the cursor over a cell and back, checking the cell comes back intact
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("CURSOR: Running self-test");
+
+    let (old_position, was_visible) = {
+        let cursor = CURSOR.lock();
+        (cursor.position, cursor.visible)
+    };
+    hide();
+
+    let (x, y) = (79, 0);
+    let before = vga_enhanced::read_cell(y, x);
+    move_to(x, y);
+    show();
+    let covered = vga_enhanced::read_cell(y, x) != before;
+    suspend();
+    let suspended_intact = vga_enhanced::read_cell(y, x) == before;
+    resume();
+    move_to(x - 1, y);
+    let restored = vga_enhanced::read_cell(y, x) == before;
+
+    hide();
+    CURSOR.lock().position = old_position;
+    if was_visible {
+        show();
+    }
+
+    if !covered {
+        return Err(KernelError::ValidationError("Cursor was not drawn"));
+    }
+    if !suspended_intact || !restored {
+        return Err(KernelError::ValidationError("Cursor did not restore the cell under it"));
+    }
+
+    serial_println!("CURSOR: Self-test passed");
+    Ok(())
+}
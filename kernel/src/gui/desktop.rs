@@ -1,17 +1,29 @@
 //! Desktop module for UniverseK OS GUI
 //! Manages the desktop environment, including icons, taskbar, and windows
 
-use crate::drivers::vga_enhanced::{self, Color};
+use crate::drivers::ps2_keyboard::KeyCode;
+use crate::drivers::vga_enhanced::Color;
 use crate::serial_println;
 use crate::errors::KernelError;
+use crate::gui::compositor::{self, Rect};
+use crate::gui::cursor;
+use crate::gui::notify;
+use crate::gui::run_dialog;
 use crate::gui::window::{Window, WindowHandle};
 use crate::gui::app::AppIcon;
-use alloc::string::ToString;
+use crate::gui::wallpaper::{self, ImageMode, Wallpaper};
+use crate::config;
+use crate::text;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 
-/// Colors for the desktop environment
+/// Colors for the desktop environment; the background is only the default
+/// until `ui.wallpaper` is applied
 pub const DESKTOP_BACKGROUND: Color = Color::Blue;
 pub const DESKTOP_TEXT: Color = Color::White;
 pub const TASKBAR_BACKGROUND: Color = Color::LightGray;
@@ -19,6 +31,45 @@ pub const TASKBAR_TEXT: Color = Color::Black;
 pub const ICON_BACKGROUND: Color = Color::Cyan;
 pub const ICON_TEXT: Color = Color::Black;
 
+/// `ui.color_scheme` value that draws everything black on white or white
+/// on black
+pub const HIGH_CONTRAST_SCHEME: &str = "high-contrast";
+
+/// Width of the app launcher, borders included
+const LAUNCHER_WIDTH: usize = 20;
+
+/// Taskbar buttons, one per window and then System's, in a row from after
+/// the START divider up to the clock
+const TASKBAR_BUTTONS_COLUMN: usize = 10;
+const TASKBAR_BUTTON_WIDTH: usize = 12;
+const TASKBAR_BUTTON_TEXT: Color = Color::DarkGray;
+const TASKBAR_BADGE: Color = Color::Red;
+const TASKBAR_FLASH: Color = Color::Yellow;
+
+/// Taskbar row showing the newest notification
+const NOTIFICATION_ROW: usize = 23;
+
+/// Where the taskbar clock is drawn
+const CLOCK_COLUMN: usize = 73;
+const CLOCK_ROW: usize = 24;
+
+/// Wall-clock minute the taskbar clock last showed
+static CLOCK_MINUTE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// A taskbar button as drawn
+struct TaskbarButton {
+    /// Index of its window, or None for System
+    window: Option<usize>,
+    bounds: Rect,
+    title: String,
+    minimized: bool,
+    active: bool,
+    /// Notifications waiting
+    badge: usize,
+    /// Lit by a flash
+    lit: bool,
+}
+
 /// Desktop state
 lazy_static! {
     pub static ref DESKTOP: Mutex<Desktop> = Mutex::new(Desktop::new());
@@ -35,11 +86,19 @@ pub struct Desktop {
     /// Mouse position
     mouse_x: usize,
     mouse_y: usize,
-    /// Taskbar information
-    start_menu_open: bool,
+    /// App launcher (the start menu), open with the selected icon
+    launcher: Option<usize>,
     taskbar_height: usize,
     // Exit flag
     exit_requested: bool,
+    /// What is painted behind icons and windows
+    background: Wallpaper,
+    /// Set when something changed that should be shown before the next frame
+    redraw_requested: bool,
+    /// Left button state from the last mouse event, to find press and release
+    left_button_down: bool,
+    /// Window being resized by dragging its bottom-right corner
+    resize_drag: Option<WindowHandle>,
 }
 
 impl Desktop {
@@ -51,9 +110,13 @@ impl Desktop {
             active_window: None,
             mouse_x: 0,
             mouse_y: 0,
-            start_menu_open: false,
+            launcher: None,
             taskbar_height: 2,
             exit_requested: false,
+            background: Wallpaper::Solid(DESKTOP_BACKGROUND),
+            redraw_requested: false,
+            left_button_down: false,
+            resize_drag: None,
         }
     }
     
@@ -66,12 +129,13 @@ impl Desktop {
     pub fn add_window(&mut self, window: Mutex<Window>) -> WindowHandle {
         let handle = WindowHandle::new(window);
         self.windows.push(handle.clone());
-        self.active_window = Some(self.windows.len() - 1);
+        self.focus(Some(self.windows.len() - 1));
         handle
     }
     
-    /// Set the mouse position
+    /// Set the mouse position, moving the cursor with it
     pub fn set_mouse_position(&mut self, x: usize, y: usize) {
+        cursor::move_to(x, y);
         self.mouse_x = x;
         self.mouse_y = y;
     }
@@ -91,9 +155,163 @@ impl Desktop {
         self.is_in_taskbar(x, y) && x < 8
     }
     
-    /// Toggle the start menu
-    pub fn toggle_start_menu(&mut self) {
-        self.start_menu_open = !self.start_menu_open;
+    /// Open the app launcher with the first app selected, or close it
+    pub fn toggle_launcher(&mut self) {
+        compositor::damage(self.launcher_bounds());
+        self.launcher = match self.launcher {
+            Some(_) => None,
+            None if self.icons.is_empty() => None,
+            None => Some(0),
+        };
+    }
+    
+    /// Whether the app launcher is open
+    pub fn launcher_open(&self) -> bool {
+        self.launcher.is_some()
+    }
+    
+    /// Where the launcher is drawn: above the start button, one row per app
+    /// between a title row and a bottom border
+    fn launcher_bounds(&self) -> Rect {
+        let height = (self.icons.len() + 2).min(self.work_area().1);
+        Rect::new(0, self.work_area().1 - height, LAUNCHER_WIDTH, height)
+    }
+    
+    /// Handle a key while the launcher is open: Up and Down pick an app,
+    /// Enter opens it and Escape closes the launcher. Other keys are ignored.
+    fn launcher_key(&mut self, code: KeyCode) -> Result<(), KernelError> {
+        let selected = match self.launcher {
+            Some(selected) => selected,
+            None => return Ok(()),
+        };
+        compositor::damage(self.launcher_bounds());
+        match code {
+            KeyCode::ArrowUp => self.launcher = Some(selected.checked_sub(1).unwrap_or(self.icons.len() - 1)),
+            KeyCode::ArrowDown => self.launcher = Some((selected + 1) % self.icons.len()),
+            KeyCode::Escape => self.launcher = None,
+            KeyCode::Enter => {
+                self.launcher = None;
+                self.open_app(selected, None)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    
+    /// Bring the bottom window to the top and focus it, restoring it if it
+    /// was minimized, so repeated presses visit every window
+    pub fn cycle_windows(&mut self) {
+        if self.windows.len() < 2 && self.active_window.is_some() {
+            return;
+        }
+        // The old focused window's title bar changes color
+        if let Some(active) = self.active_window.and_then(|i| self.windows.get(i)) {
+            active.lock().mark_damaged();
+        }
+        let window = match self.windows.first() {
+            Some(window) => window.clone(),
+            None => return,
+        };
+        self.raise(0);
+        let mut window = window.lock();
+        if window.restore() {
+            compositor::damage(self.taskbar_bounds());
+        }
+        window.mark_damaged();
+    }
+    
+    /// Move the window at `index` to the top and focus it
+    fn raise(&mut self, index: usize) {
+        let window = self.windows.remove(index);
+        self.windows.push(window);
+        self.active_window = None;
+        self.focus(Some(self.windows.len() - 1));
+    }
+    
+    /// Hide the window at `index` to a taskbar button and focus the
+    /// topmost window still showing
+    fn minimize_window(&mut self, index: usize) {
+        if let Some(window) = self.windows.get(index) {
+            window.lock().minimize();
+            compositor::damage(self.taskbar_bounds());
+            if self.active_window == Some(index) {
+                self.active_window = None;
+                self.focus(self.topmost_visible());
+            }
+        }
+    }
+    
+    /// Show the minimized window at `index` where it was, on top and focused
+    fn restore_window(&mut self, index: usize) {
+        if let Some(window) = self.windows.get(index).cloned() {
+            window.lock().restore();
+            compositor::damage(self.taskbar_bounds());
+            self.raise(index);
+        }
+    }
+    
+    /// Minimize the focused window
+    pub fn minimize_active_window(&mut self) {
+        if let Some(i) = self.active_window {
+            self.minimize_window(i);
+        }
+    }
+    
+    /// Index of the top window that isn't minimized
+    fn topmost_visible(&self) -> Option<usize> {
+        self.windows.iter().rposition(|window| !window.lock().is_minimized())
+    }
+    
+    fn taskbar_bounds(&self) -> Rect {
+        let rows = self.work_area().1;
+        Rect::new(0, rows, 80, 25 - rows)
+    }
+    
+    /// Taskbar buttons that fit: the windows', bottom first, then System's
+    /// while it has notifications
+    fn taskbar_buttons(&self) -> Vec<TaskbarButton> {
+        let row = 24;
+        let slots = (CLOCK_COLUMN - 1 - TASKBAR_BUTTONS_COLUMN) / (TASKBAR_BUTTON_WIDTH + 1);
+        let system = notify::system_pending();
+        let owners = (0..self.windows.len()).map(Some).take(slots - usize::from(system));
+        owners.chain(system.then_some(None))
+            .enumerate()
+            .map(|(slot, owner)| {
+                let bounds = Rect::new(TASKBAR_BUTTONS_COLUMN + slot * (TASKBAR_BUTTON_WIDTH + 1), row,
+                    TASKBAR_BUTTON_WIDTH, 1);
+                let handle = owner.map(|i| &self.windows[i]);
+                let (title, minimized) = match handle {
+                    Some(handle) => { let window = handle.lock(); (String::from(window.title()), window.is_minimized()) }
+                    None => (String::from(notify::SYSTEM_TITLE), false),
+                };
+                TaskbarButton {
+                    window: owner,
+                    bounds,
+                    title,
+                    minimized,
+                    active: owner.is_some() && owner == self.active_window,
+                    badge: notify::badge(handle),
+                    lit: notify::is_lit(handle),
+                }
+            })
+            .collect()
+    }
+    
+    /// The newest notification not yet seen, as "title: text"
+    fn notification_line(&self) -> Option<String> {
+        let (window, text) = notify::latest()?;
+        let title = match window {
+            Some(window) => String::from(window.lock().title()),
+            None => String::from(notify::SYSTEM_TITLE),
+        };
+        Some(format!("{}: {}", title, text))
+    }
+    
+    /// Close the focused window
+    pub fn close_active_window(&mut self) {
+        if let Some(i) = self.active_window {
+            self.close_window(i);
+        }
     }
     
     /// Request exit from the GUI
@@ -101,6 +319,11 @@ impl Desktop {
         self.exit_requested = true;
     }
     
+    /// Forget an earlier exit request, so the GUI can run again
+    pub fn cancel_exit(&mut self) {
+        self.exit_requested = false;
+    }
+    
     /// Check if exit has been requested
     pub fn should_exit(&self) -> bool {
         self.exit_requested
@@ -111,6 +334,63 @@ impl Desktop {
         self.active_window
     }
     
+    /// Replace the desktop background
+    pub fn set_background(&mut self, background: Wallpaper) {
+        self.background = background;
+        self.redraw_requested = true;
+        compositor::damage_all();
+    }
+    
+    /// Give focus to the window at `index`; both title bars and taskbar
+    /// buttons change color, so they are redrawn
+    fn focus(&mut self, index: Option<usize>) {
+        if index == self.active_window {
+            return;
+        }
+        compositor::damage(self.taskbar_bounds());
+        for i in [self.active_window, index].into_iter().flatten() {
+            if let Some(window) = self.windows.get(i) {
+                window.lock().mark_damaged();
+            }
+        }
+        self.active_window = index;
+    }
+    
+    /// Close the window at `index`, uncovering what was underneath, and
+    /// focus the topmost window left showing
+    fn close_window(&mut self, index: usize) {
+        let window = self.windows.remove(index);
+        let (minimized, bounds) = { let window = window.lock(); (window.is_minimized(), window.bounds()) };
+        if !minimized {
+            compositor::damage(bounds);
+        }
+        // Its taskbar button goes too
+        compositor::damage(self.taskbar_bounds());
+        self.active_window = None;
+        self.focus(self.topmost_visible());
+    }
+    
+    /// Drop windows that asked to be closed, keeping focus on a window
+    /// that is still open
+    fn remove_closed_windows(&mut self) {
+        while let Some(i) = self.windows.iter().position(|window| window.lock().close_requested()) {
+            self.close_window(i);
+        }
+    }
+    
+    /// Open a window of the app at icon `index` on top and focus it
+    fn open_app(&mut self, index: usize, state: Option<&str>) -> Result<WindowHandle, KernelError> {
+        let handle = self.icons[index].launch(state)?;
+        self.windows.push(handle.clone());
+        self.focus(Some(self.windows.len() - 1));
+        Ok(handle)
+    }
+    
+    /// Screen area windows may cover, as (columns, rows) above the taskbar
+    pub fn work_area(&self) -> (usize, usize) {
+        (80, 25 - self.taskbar_height)
+    }
+    
     /// Get a reference to the windows list
     pub fn get_windows(&self) -> &Vec<WindowHandle> {
         &self.windows
@@ -120,131 +400,332 @@ impl Desktop {
 /// Initialize the desktop
 pub fn init() -> Result<(), KernelError> {
     serial_println!("DEBUG: Initializing desktop");
-    // No additional initialization needed yet
+    
+    apply_wallpaper_config("ui.wallpaper");
+    config::subscribe("ui.wallpaper", apply_wallpaper_config);
+    config::subscribe("ui.color_scheme", apply_wallpaper_config);
     Ok(())
 }
 
+/// Image layout from `ui.wallpaper_mode`
+fn wallpaper_mode() -> ImageMode {
+    match config::get("ui.wallpaper_mode").map(|value| value.as_string()) {
+        Some(mode) if mode == "stretch" => ImageMode::Stretch,
+        _ => ImageMode::Tile,
+    }
+}
+
+/// Reload the background from `ui.wallpaper` (falling back to
+/// `ui.color_scheme`), or switch to high contrast. A spec that can't be
+/// loaded is logged and the current background is kept.
+fn apply_wallpaper_config(_key: &str) {
+    // High contrast replaces any wallpaper with plain black
+    let high_contrast = config::get("ui.color_scheme")
+        .map_or(false, |value| value.as_string() == HIGH_CONTRAST_SCHEME);
+    compositor::set_high_contrast(high_contrast);
+    if high_contrast {
+        DESKTOP.lock().set_background(Wallpaper::Solid(Color::Black));
+        return;
+    }
+    
+    let spec = match config::get("ui.wallpaper").or_else(|| config::get("ui.color_scheme")) {
+        Some(value) => value.as_string(),
+        None => return,
+    };
+    
+    match wallpaper::load(&spec, wallpaper_mode()) {
+        Ok(background) => DESKTOP.lock().set_background(background),
+        Err(e) => serial_println!("WARNING: Can't use wallpaper '{}': {:?}; keeping the current background", spec, e),
+    }
+}
+
+/// Check and clear a pending redraw request
+pub fn take_redraw_request() -> bool {
+    core::mem::replace(&mut DESKTOP.lock().redraw_requested, false)
+}
+
 /// Draw the desktop environment
 pub fn draw() -> Result<(), KernelError> {
     serial_println!("DEBUG: Drawing desktop");
     
-    // Clear the screen with desktop background
-    vga_enhanced::clear_screen();
+    compositor::damage_all();
+    refresh()
+}
+
+/// Redraw the damaged parts of the desktop and show them. Everything that
+/// overlaps the damage is drawn back to front (background, taskbar, icons,
+/// windows in z-order); the compositor keeps only damaged cells. The mouse
+/// cursor is an overlay drawn by `cursor`, not part of the frame.
+pub fn refresh() -> Result<(), KernelError> {
+    // Redraw the taskbar clock when the minute changes
+    let minute = crate::time::wall_clock() / 60;
+    if CLOCK_MINUTE.swap(minute, Ordering::Relaxed) != minute {
+        compositor::damage(Rect::new(CLOCK_COLUMN, CLOCK_ROW, 5, 1));
+    }
     
-    // Draw the taskbar
-    draw_taskbar()?;
+    let mut desktop = DESKTOP.lock();
+    desktop.remove_closed_windows();
+    for handle in &desktop.windows {
+        let mut window = handle.lock();
+        if let Some(damage) = window.take_damage() {
+            compositor::damage(damage);
+        }
+        if window.take_bell() {
+            notify::bell(Some(handle));
+        }
+    }
+    
+    // The focused window has the user's attention already
+    if let Some(active) = desktop.active_window.and_then(|i| desktop.windows.get(i)) {
+        notify::clear(Some(active));
+    }
+    if notify::take_changed() {
+        compositor::damage(desktop.taskbar_bounds());
+    }
+    
+    let bounds = match compositor::damage_bounds() {
+        Some(bounds) => bounds,
+        None => return Ok(()),
+    };
+    
+    let desktop_rows = 25 - desktop.taskbar_height;
+    draw_background(&desktop.background, desktop_rows, bounds);
+    
+    if bounds.intersects(&desktop.taskbar_bounds()) {
+        draw_taskbar(&desktop.taskbar_buttons(), desktop.notification_line().as_deref())?;
+    }
     
-    // Draw the desktop icons
-    let desktop = DESKTOP.lock();
     for (i, icon) in desktop.icons.iter().enumerate() {
         let x = 2 + (i % 4) * 15;
         let y = 2 + (i / 4) * 4;
-        draw_icon(icon, x, y)?;
+        if bounds.intersects(&Rect::new(x, y, 10, 3)) {
+            draw_icon(icon, x, y)?;
+        }
     }
     
-    // Draw windows
     for (i, window) in desktop.windows.iter().enumerate() {
         let is_active = desktop.active_window.map_or(false, |active| active == i);
         let mut window = window.lock();
-        window.draw(is_active)?;
+        if !window.is_minimized() && bounds.intersects(&window.bounds()) {
+            window.draw(is_active)?;
+        }
     }
+    if let Some(selected) = desktop.launcher {
+        draw_launcher(&desktop.icons, selected, desktop.launcher_bounds());
+    }
+    drop(desktop);
+    run_dialog::draw();
     
-    // Draw the mouse cursor
-    draw_mouse_cursor(desktop.mouse_x, desktop.mouse_y)?;
-    
+    compositor::present();
     Ok(())
 }
 
-/// Refresh the desktop display
-pub fn refresh() -> Result<(), KernelError> {
-    // Just redraw everything for now
-    draw()
+/// Fill the part of `area` within the top `rows` rows with the wallpaper
+fn draw_background(background: &Wallpaper, rows: usize, area: Rect) {
+    for y in area.y..(area.y + area.height).min(rows) {
+        for x in area.x..area.x + area.width {
+            let color = background.color_at(x, y, 80, rows);
+            compositor::write_at(y, x, " ", DESKTOP_TEXT, color);
+        }
+    }
 }
 
-/// Draw the taskbar at the bottom of the screen
-fn draw_taskbar() -> Result<(), KernelError> {
+/// Draw the taskbar at the bottom of the screen, with `buttons` and the
+/// newest `notification` above them
+fn draw_taskbar(buttons: &[TaskbarButton], notification: Option<&str>) -> Result<(), KernelError> {
     // Draw taskbar background
     for y in 23..25 {
         for x in 0..80 {
-            vga_enhanced::write_at(y, x, " ", TASKBAR_TEXT, TASKBAR_BACKGROUND);
+            compositor::write_at(y, x, " ", TASKBAR_TEXT, TASKBAR_BACKGROUND);
         }
     }
     
     // Draw start button
-    vga_enhanced::write_at(24, 1, "START", Color::White, Color::Green);
+    compositor::write_at(24, 1, "START", Color::White, Color::Green);
     
     // Draw taskbar divider
-    vga_enhanced::write_at(24, 8, "|", TASKBAR_TEXT, TASKBAR_BACKGROUND);
+    compositor::write_at(24, 8, "|", TASKBAR_TEXT, TASKBAR_BACKGROUND);
+    
+    for button in buttons {
+        draw_taskbar_button(button);
+    }
+    if let Some(notification) = notification {
+        compositor::write_at(NOTIFICATION_ROW, 1, &text::ellipsize(notification, 78), TASKBAR_TEXT, TASKBAR_BACKGROUND);
+    }
     
-    // Draw clock on the right
-    vga_enhanced::write_at(24, 70, "12:00 PM", TASKBAR_TEXT, TASKBAR_BACKGROUND);
+    // Draw clock on the right (UTC, like the RTC)
+    let now = crate::time::now();
+    compositor::write_at(CLOCK_ROW, CLOCK_COLUMN, &format!("{:02}:{:02}", now.hour, now.minute),
+        TASKBAR_TEXT, TASKBAR_BACKGROUND);
     
     Ok(())
 }
 
+/// Draw a taskbar button: dimmed if minimized, inverted if focused,
+/// yellow while a flash lights it, with its badge count on the right
+fn draw_taskbar_button(button: &TaskbarButton) {
+    let (fg, bg) = if button.lit {
+        (TASKBAR_TEXT, TASKBAR_FLASH)
+    } else if button.active {
+        (TASKBAR_BACKGROUND, TASKBAR_TEXT)
+    } else if button.minimized {
+        (TASKBAR_BUTTON_TEXT, TASKBAR_BACKGROUND)
+    } else {
+        (TASKBAR_TEXT, TASKBAR_BACKGROUND)
+    };
+    let badge = match button.badge {
+        0 => String::new(),
+        count if count > 9 => String::from(" 9+"),
+        count => format!(" {}", count),
+    };
+    let Rect { x, y, width, .. } = button.bounds;
+    let inner = width - 2 - badge.len();
+    let title = format!("[{:<width$}", text::ellipsize(&button.title, inner), width = inner);
+    compositor::write_at(y, x, &title, fg, bg);
+    compositor::write_at(y, x + 1 + inner, &badge, TASKBAR_BADGE, bg);
+    compositor::write_at(y, x + width - 1, "]", fg, bg);
+}
+
+/// Draw the app launcher in `bounds`, with the selected app inverted
+fn draw_launcher(icons: &[AppIcon], selected: usize, bounds: Rect) {
+    let inner = bounds.width - 2;
+    let title = format!("{:─<width$}", "─ Apps ", width = inner);
+    compositor::write_at(bounds.y, bounds.x, &format!("┌{}┐", title), TASKBAR_TEXT, TASKBAR_BACKGROUND);
+    for (i, icon) in icons.iter().enumerate().take(bounds.height - 2) {
+        let (fg, bg) = if i == selected { (TASKBAR_BACKGROUND, TASKBAR_TEXT) } else { (TASKBAR_TEXT, TASKBAR_BACKGROUND) };
+        let row = bounds.y + 1 + i;
+        compositor::write_at(row, bounds.x, "│", TASKBAR_TEXT, TASKBAR_BACKGROUND);
+        let label = format!(" {:<width$}", text::ellipsize(&icon.name, inner - 1), width = inner - 1);
+        compositor::write_at(row, bounds.x + 1, &label, fg, bg);
+        compositor::write_at(row, bounds.x + bounds.width - 1, "│", TASKBAR_TEXT, TASKBAR_BACKGROUND);
+    }
+    compositor::write_at(bounds.y + bounds.height - 1, bounds.x, &format!("└{}┘", "─".repeat(inner)),
+        TASKBAR_TEXT, TASKBAR_BACKGROUND);
+}
+
 /// Draw a desktop icon
 fn draw_icon(icon: &AppIcon, x: usize, y: usize) -> Result<(), KernelError> {
     // Draw icon background
     for iy in 0..3 {
         for ix in 0..10 {
-            vga_enhanced::write_at(y + iy, x + ix, " ", ICON_TEXT, ICON_BACKGROUND);
+            compositor::write_at(y + iy, x + ix, " ", ICON_TEXT, ICON_BACKGROUND);
         }
     }
     
     // Draw icon label
-    let name = if icon.name.len() > 8 {
-        // Truncate with ...
-        let mut short = icon.name[..5].to_string();
-        short.push_str("...");
-        short
-    } else {
-        icon.name.clone()
-    };
+    // Truncate with ...
+    let name = text::ellipsize(&icon.name, 8);
     
-    let padding = (10 - name.len()) / 2;
-    vga_enhanced::write_at(y + 1, x + padding, &name, ICON_TEXT, ICON_BACKGROUND);
+    let padding = (10 - text::display_width(&name)) / 2;
+    compositor::write_at(y + 1, x + padding, &name, ICON_TEXT, ICON_BACKGROUND);
     
     Ok(())
 }
 
-/// Draw the mouse cursor
-fn draw_mouse_cursor(x: usize, y: usize) -> Result<(), KernelError> {
-    // Simple cursor representation
-    if x < 80 && y < 25 {
-        let current_char = vga_enhanced::read_char_at(y, x);
-        vga_enhanced::write_at(y, x, "X", Color::White, Color::Red);
+/// Handle the left button state from a mouse event at (`x`, `y`). A press
+/// on a window's bottom-right corner starts a resize that follows the mouse
+/// until the button is released; any other press is a click.
+pub fn handle_mouse_button(x: usize, y: usize, left: bool, double_click: bool) -> Result<(), KernelError> {
+    let mut desktop = DESKTOP.lock();
+    let pressed = left && !desktop.left_button_down;
+    desktop.left_button_down = left;
+    
+    if !left {
+        if desktop.resize_drag.take().is_some() {
+            desktop.redraw_requested = true;
+        }
+        return Ok(());
     }
     
-    Ok(())
+    let area = desktop.work_area();
+    if let Some(window) = desktop.resize_drag.clone() {
+        let mut window = window.lock();
+        let (left_x, top_y) = window.position();
+        window.resize(x.saturating_sub(left_x) + 1, y.saturating_sub(top_y) + 1, area);
+        return Ok(());
+    }
+    
+    if !pressed {
+        return Ok(());
+    }
+    
+    // Topmost window wins the grip
+    let grip = desktop.windows.iter().enumerate().rev().find(|(_, window)| {
+        let window = window.lock();
+        !window.is_minimized() && window.is_on_resize_grip(x, y)
+    }).map(|(i, window)| (i, window.clone()));
+    if let Some((i, window)) = grip {
+        desktop.focus(Some(i));
+        desktop.resize_drag = Some(window);
+        return Ok(());
+    }
+    
+    drop(desktop);
+    handle_mouse_click(x, y, double_click)
+}
+
+/// Grow or shrink the focused window by (`dw`, `dh`) cells
+pub fn resize_active_window(dw: isize, dh: isize) {
+    let desktop = DESKTOP.lock();
+    let area = desktop.work_area();
+    if let Some(window) = desktop.active_window.and_then(|i| desktop.windows.get(i)) {
+        let mut window = window.lock();
+        let (width, height) = window.size();
+        window.resize(width.saturating_add_signed(dw), height.saturating_add_signed(dh), area);
+    }
 }
 
 /// Handle a mouse click on the desktop
-pub fn handle_mouse_click(x: usize, y: usize) -> Result<(), KernelError> {
+pub fn handle_mouse_click(x: usize, y: usize, double_click: bool) -> Result<(), KernelError> {
     let mut desktop = DESKTOP.lock();
     
     // Check if click is on start button
     if desktop.is_in_start_button(x, y) {
-        desktop.toggle_start_menu();
+        desktop.toggle_launcher();
         return Ok(());
     }
     
+    // A window's taskbar button brings it to the top, restoring it if it
+    // was minimized; System's clears its notifications
+    let point = Rect::new(x, y, 1, 1);
+    if let Some(button) = desktop.taskbar_buttons().into_iter().find(|button| button.bounds.intersects(&point)) {
+        match button.window {
+            Some(i) => desktop.restore_window(i),
+            None => notify::clear(None),
+        }
+        return Ok(());
+    }
+    
+    // A click on a launcher entry opens that app; anywhere else closes it
+    if desktop.launcher_open() {
+        let bounds = desktop.launcher_bounds();
+        desktop.toggle_launcher();
+        if bounds.intersects(&Rect::new(x, y, 1, 1)) && y > bounds.y && y < bounds.y + bounds.height - 1 {
+            desktop.open_app(y - bounds.y - 1, None)?;
+            return Ok(());
+        }
+    }
+    
     // Check if click is on desktop icon
-    for (i, icon) in desktop.icons.iter().enumerate() {
+    let clicked_icon = (0..desktop.icons.len()).find(|i| {
         let icon_x = 2 + (i % 4) * 15;
         let icon_y = 2 + (i / 4) * 4;
+        x >= icon_x && x < icon_x + 10 && y >= icon_y && y < icon_y + 3
+    });
+    if let Some(i) = clicked_icon {
+        // Icons launch on double-click only
+        if !double_click {
+            return Ok(());
+        }
         
-        if x >= icon_x && x < icon_x + 10 && y >= icon_y && y < icon_y + 3 {
-            // Click on icon - launch app
-            serial_println!("DEBUG: Launching app: {}", icon.name);
-            
-            // Create an instance of the app
-            if let Some(ref create_fn) = icon.create_fn {
-                let handle = create_fn()?;
-                let mut desktop = DESKTOP.lock();
-                desktop.active_window = Some(desktop.windows.len() - 1);
-                return Ok(());
-            }
+        // Double-click on icon - launch app
+        serial_println!("DEBUG: Launching app: {}", desktop.icons[i].name);
+        
+        // Create an instance of the app and give it focus
+        if desktop.icons[i].create_fn.is_some() {
+            desktop.open_app(i, None)?;
         }
+        return Ok(());
     }
     
     // Store windows in a temporary vec to avoid borrowing issues
@@ -258,37 +739,98 @@ pub fn handle_mouse_click(x: usize, y: usize) -> Result<(), KernelError> {
     
     // Check if click is on a window
     for (i, window) in windows_to_check {
-        let window_guard = window.lock();
-        if window_guard.contains_point(x, y) {
-            // Set as active window
-            let mut desktop = DESKTOP.lock();
-            desktop.active_window = Some(i);
-            
-            // Check if click is on close button
-            if window_guard.is_on_close_button(x, y) {
-                // Close window logic
-                drop(window_guard); // Release the lock
-                let _ = desktop.windows.remove(i);
-                if desktop.windows.is_empty() {
-                    desktop.active_window = None;
-                } else {
-                    desktop.active_window = Some(desktop.windows.len() - 1);
-                }
-                return Ok(());
-            }
-            
-            // Pass click to the window
-            drop(window_guard); // Release the lock
-            drop(desktop);     // Release desktop lock
-            let mut window = window.lock();
-            window.handle_click(x, y)?;
+        let (inside, on_close_button, on_minimize_button) = {
+            let window = window.lock();
+            (!window.is_minimized() && window.contains_point(x, y), window.is_on_close_button(x, y),
+                window.is_on_minimize_button(x, y))
+        };
+        if !inside {
+            continue;
+        }
+        
+        let mut desktop = DESKTOP.lock();
+        if on_close_button {
+            desktop.close_window(i);
+            return Ok(());
+        }
+        if on_minimize_button {
+            desktop.minimize_window(i);
             return Ok(());
         }
+        
+        // Set as active window and pass the click on
+        desktop.focus(Some(i));
+        drop(desktop);
+        window.lock().handle_click(x, y, double_click)?;
+        return Ok(());
     }
     
     Ok(())
 }
 
+/// Move the focused window by (`dx`, `dy`) cells, staying on screen
+pub fn move_active_window(dx: isize, dy: isize) {
+    let desktop = DESKTOP.lock();
+    let area = desktop.work_area();
+    if let Some(window) = desktop.active_window.and_then(|i| desktop.windows.get(i)) {
+        let mut window = window.lock();
+        let (x, y) = window.position();
+        window.move_to(x.saturating_add_signed(dx), y.saturating_add_signed(dy), area);
+    }
+}
+
+/// Minimize the window `handle`, if it is on the desktop
+pub fn minimize(handle: &WindowHandle) {
+    let mut desktop = DESKTOP.lock();
+    if let Some(i) = desktop.windows.iter().position(|window| Arc::ptr_eq(window, handle)) {
+        desktop.minimize_window(i);
+    }
+}
+
+/// Raise and focus the topmost window titled `title`, restoring it if it
+/// was minimized; returns whether there was one
+pub fn focus_titled(title: &str) -> bool {
+    let mut desktop = DESKTOP.lock();
+    let Some(index) = desktop.windows.iter().rposition(|window| window.lock().title() == title) else {
+        return false;
+    };
+    // The old focused window's title bar changes color
+    if let Some(active) = desktop.active_window.and_then(|i| desktop.windows.get(i)) {
+        active.lock().mark_damaged();
+    }
+    desktop.restore_window(index);
+    true
+}
+
+/// Offer a key to the app launcher; returns whether it was open and took it
+pub fn launcher_key(code: KeyCode) -> Result<bool, KernelError> {
+    let mut desktop = DESKTOP.lock();
+    if !desktop.launcher_open() {
+        return Ok(false);
+    }
+    desktop.launcher_key(code)?;
+    Ok(true)
+}
+
+/// Handle of the focused window, if any
+pub fn active_window() -> Option<WindowHandle> {
+    let desktop = DESKTOP.lock();
+    desktop.active_window.and_then(|i| desktop.windows.get(i).cloned())
+}
+
+/// Open the app named `name`, restoring `state` if it has a restore hook.
+/// Fails with NotFound if no such app is registered.
+pub fn launch(name: &str, state: Option<&str>) -> Result<WindowHandle, KernelError> {
+    let mut desktop = DESKTOP.lock();
+    let index = desktop.icons.iter().position(|icon| icon.name == name).ok_or(KernelError::NotFound)?;
+    desktop.open_app(index, state)
+}
+
+/// Names of the registered apps, in launcher order
+pub fn app_names() -> Vec<String> {
+    DESKTOP.lock().icons.iter().map(|icon| icon.name.clone()).collect()
+}
+
 /// Add an icon to the desktop
 pub fn add_icon(icon: AppIcon) -> Result<(), KernelError> {
     let mut desktop = DESKTOP.lock();
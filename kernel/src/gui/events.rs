@@ -3,33 +3,42 @@
 
 use crate::serial_println;
 use crate::errors::KernelError;
-use crate::drivers::ps2_keyboard::{KeyCode, KeyEvent, KeyState};
+use crate::drivers::input;
+use crate::drivers::ps2_keyboard::{self, KeyCode, KeyEvent, KeyState};
 use crate::drivers::ps2_mouse::{MouseEvent, MouseButtons};
-use crate::gui::desktop;
+use crate::gui::{clipboard, desktop, recorder, run_dialog, screenshot};
+use crate::gui::compositor::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Pixels of pointer movement across one character cell
+pub const CELL_WIDTH: usize = 8;
+pub const CELL_HEIGHT: usize = 16;
+
+/// The pointer's range in pixels, the cell grid's size
+pub fn pointer_bounds() -> (u16, u16) {
+    ((SCREEN_WIDTH * CELL_WIDTH) as u16, (SCREEN_HEIGHT * CELL_HEIGHT) as u16)
+}
 
 /// Handle a mouse event
 pub fn handle_mouse_event(event: MouseEvent) -> Result<(), KernelError> {
     serial_println!("DEBUG: GUI received mouse event: x={}, y={}, btn_left={}, btn_right={}",
         event.x, event.y, event.buttons.left, event.buttons.right);
+    recorder::record(recorder::Recorded::mouse(&event), event.time_ms);
     
-    // Update mouse position in desktop
-    let x = (event.x / 8) as usize; // Convert to character coordinates
-    let y = (event.y / 16) as usize;
-    
-    // Ensure coordinates are in bounds
-    let x = x.min(79);
-    let y = y.min(24);
+    // Update mouse position in desktop, in character cells
+    let x = (event.x.max(0) as usize / CELL_WIDTH).min(SCREEN_WIDTH - 1);
+    let y = (event.y.max(0) as usize / CELL_HEIGHT).min(SCREEN_HEIGHT - 1);
     
     {
         let mut desktop_guard = desktop::DESKTOP.lock();
         desktop_guard.set_mouse_position(x, y);
     }
     
-    // Handle mouse button clicks
-    if event.buttons.left {
-        // Left button - trigger click event
-        desktop::handle_mouse_click(x, y)?;
-    }
+    // Handle mouse button presses, releases and corner drags
+    desktop::handle_mouse_button(x, y, event.buttons.left, event.double_click)?;
     
     // Refresh the display to show the new mouse position
     desktop::refresh()?;
@@ -37,42 +46,289 @@ pub fn handle_mouse_event(event: MouseEvent) -> Result<(), KernelError> {
     Ok(())
 }
 
+/// Keyboard paths to everything the mouse can do, as (keys, action); the
+/// About window lists them
+pub const SHORTCUTS: &[(&str, &str)] = &[
+    ("Ctrl+Space", "Open or close the app launcher (also Ctrl+Esc)"),
+    ("Up/Down, Enter", "Pick an app in the launcher and open it; Esc closes it"),
+    ("Ctrl+R", "Run an app, shell command or program by name"),
+    ("Ctrl+Tab or Alt+Tab", "Bring the next window to the front, restoring it if minimized"),
+    ("Ctrl+M", "Minimize the focused window to the taskbar"),
+    ("Ctrl+Arrows", "Move the focused window"),
+    ("Ctrl+Shift+Arrows", "Resize the focused window"),
+    ("Ctrl+W", "Close the focused window"),
+    ("PageUp/PageDown", "Scroll the focused window"),
+    ("Ctrl+A/C/V", "Select all, copy, paste"),
+    ("Ctrl+PrintScreen", "Save a screenshot to Pictures in your home"),
+    ("Ctrl+Alt+F1 or Ctrl+Alt+Enter", "Switch to the full-screen console (Ctrl+Alt+F2 comes back)"),
+    ("Ctrl+Alt+Q", "Leave the GUI for the console"),
+];
+
 /// Handle a keyboard event
 pub fn handle_keyboard_event(event: KeyEvent) -> Result<(), KernelError> {
     serial_println!("DEBUG: GUI received keyboard event: code={:?}, state={:?}",
         event.code, event.state);
+    if let Some(recorded) = recorder::Recorded::key(&event) {
+        recorder::record(recorded, event.time_ms);
+    }
     
     // Only process key press events
     if event.state != KeyState::Pressed {
         return Ok(());
     }
     
+    // Ctrl+Alt+F1 and the like: the GUI loop sees the request and returns
+    if let Some(result) = crate::vt::handle_hotkey(&event) {
+        return result;
+    }
+    
+    dispatch_key(event)?;
+    desktop::refresh()
+}
+
+/// Act on a key press, without redrawing: the run dialog if it's open,
+/// then desktop shortcuts and the launcher, then the focused window
+fn dispatch_key(event: KeyEvent) -> Result<(), KernelError> {
+    // The run dialog takes every key while it's open
+    if event.code == KeyCode::R && event.ctrl && !run_dialog::is_open() {
+        run_dialog::open();
+        return Ok(());
+    }
+    if run_dialog::handle_key(&event)? {
+        return Ok(());
+    }
+    
+    // The launcher toggle works whether or not it is open
+    if matches!(event.code, KeyCode::Space | KeyCode::Escape) && event.ctrl {
+        desktop::DESKTOP.lock().toggle_launcher();
+        return Ok(());
+    }
+    
+    // While open, the launcher takes every other key
+    if desktop::launcher_key(event.code)? {
+        return Ok(());
+    }
+    
     // Check for global keyboard shortcuts first
     match event.code {
-        KeyCode::Escape if event.ctrl => {
-            // Ctrl+Esc - toggle start menu
-            let mut desktop_guard = desktop::DESKTOP.lock();
-            desktop_guard.toggle_start_menu();
-            drop(desktop_guard);
-            desktop::refresh()?;
-            return Ok(());
-        },
         KeyCode::Q if event.ctrl && event.alt => {
             // Ctrl+Alt+Q - quit GUI
             desktop::request_exit();
             return Ok(());
         },
+        KeyCode::Tab if event.ctrl || event.alt => {
+            desktop::DESKTOP.lock().cycle_windows();
+            return Ok(());
+        },
+        KeyCode::W if event.ctrl => {
+            desktop::DESKTOP.lock().close_active_window();
+            return Ok(());
+        },
+        KeyCode::M if event.ctrl => {
+            desktop::DESKTOP.lock().minimize_active_window();
+            return Ok(());
+        },
+        KeyCode::PrintScreen if event.ctrl => {
+            match screenshot::take() {
+                Ok((text_path, _)) => crate::logger::info("gui", &format!("Screenshot saved to {}", text_path)),
+                Err(e) => crate::logger::warning("gui", &format!("Screenshot failed: {}", e)),
+            }
+            return Ok(());
+        },
+        KeyCode::PageUp | KeyCode::PageDown => {
+            if let Some(window) = desktop::active_window() {
+                let mut window = window.lock();
+                if event.code == KeyCode::PageUp {
+                    window.page_up();
+                } else {
+                    window.page_down();
+                }
+            }
+            return Ok(());
+        },
+        KeyCode::ArrowLeft | KeyCode::ArrowRight | KeyCode::ArrowUp | KeyCode::ArrowDown if event.ctrl => {
+            // Ctrl+Arrow moves the focused window, Ctrl+Shift+Arrow resizes it
+            let (dx, dy) = match event.code {
+                KeyCode::ArrowLeft => (-1, 0),
+                KeyCode::ArrowRight => (1, 0),
+                KeyCode::ArrowUp => (0, -1),
+                _ => (0, 1),
+            };
+            if event.shift {
+                desktop::resize_active_window(dx, dy);
+            } else {
+                desktop::move_active_window(dx, dy);
+            }
+            return Ok(());
+        },
         _ => {}
     }
     
-    // Refresh display - we'll only handle global keys for now
-    // until we add proper accessor methods for the desktop's active window
-    desktop::refresh()?;
+    // A focused text box takes editing keys, clipboard ones included
+    if let Some(window) = desktop::active_window() {
+        if window.lock().handle_key_event(&event)? {
+            return Ok(());
+        }
+    }
+    if event.ctrl && matches!(event.code, KeyCode::A | KeyCode::C | KeyCode::V) {
+        return handle_clipboard_shortcut(event.code);
+    }
     
+    // Everything else goes to the focused window
+    let key = match event.code {
+        KeyCode::Enter => Some('\n'),
+        KeyCode::Backspace => Some('\x08'),
+        KeyCode::Escape => Some('\x1b'),
+        _ if event.ctrl || event.alt => None,
+        _ => event.to_char(),
+    };
+    if let (Some(key), Some(window)) = (key, desktop::active_window()) {
+        window.lock().handle_key(key)?;
+    }
+    Ok(())
+}
+
+/// Ctrl+A select all, Ctrl+C copy and Ctrl+V paste in the active window
+fn handle_clipboard_shortcut(code: KeyCode) -> Result<(), KernelError> {
+    let window = match desktop::active_window() {
+        Some(window) => window,
+        None => return Ok(()),
+    };
+    let mut window = window.lock();
+    
+    match code {
+        KeyCode::A => window.select_all(),
+        KeyCode::C => {
+            if let Some(text) = window.selected_text() {
+                clipboard::set(text)?;
+            }
+        },
+        KeyCode::V => window.paste(&clipboard::get()),
+        _ => {}
+    }
     Ok(())
 }
 
 /// Check if the GUI should exit
 pub fn should_exit() -> bool {
     desktop::should_exit()
-} 
\ No newline at end of file
+}
+
+/// Set-1 scancodes for the self-test
+const CTRL: u8 = 0x1D;
+const SHIFT: u8 = 0x2A;
+const SPACE: u8 = 0x39;
+const ENTER: u8 = 0x1C;
+const KEY_W: u8 = 0x11;
+const KEY_M: u8 = 0x32;
+const ALT: u8 = 0x38;
+const TAB: u8 = 0x0F;
+const EXTENDED: u8 = 0xE0;
+const ARROW_RIGHT: u8 = 0x4D;
+const ARROW_DOWN: u8 = 0x50;
+const RELEASE: u8 = 0x80;
+
+/// Press and release `key` with Ctrl (and Shift) held
+fn chord(key: &[u8], shift: bool) -> Vec<u8> {
+    let mut scancodes = vec![CTRL];
+    if shift {
+        scancodes.push(SHIFT);
+    }
+    if let Some((last, prefix)) = key.split_last() {
+        scancodes.extend_from_slice(key);
+        scancodes.extend_from_slice(prefix);
+        scancodes.push(last | RELEASE);
+    }
+    if shift {
+        scancodes.push(SHIFT | RELEASE);
+    }
+    scancodes.push(CTRL | RELEASE);
+    scancodes
+}
+
+/// Feed injected scancodes through the keyboard driver into the GUI
+fn type_keys(scancodes: &[u8]) -> Result<(), KernelError> {
+    input::queue_keys(scancodes)?;
+    while let Some(event) = ps2_keyboard::get_event() {
+        if event.state == KeyState::Pressed {
+            dispatch_key(event)?;
+        }
+    }
+    Ok(())
+}
+
+/// Open a window from the launcher, move, resize, cycle, minimize and close it with
+/// injected keys only. Nothing is drawn; the first real frame redraws the
+/// whole screen anyway.
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("GUI EVENTS: Running self-test");
+
+    input::clear();
+    ps2_keyboard::reset_state();
+    let windows = desktop::DESKTOP.lock().get_windows().len();
+    let result = (|| {
+        // Ctrl+Space, then Enter opens the first app
+        type_keys(&chord(&[SPACE], false))?;
+        if !desktop::DESKTOP.lock().launcher_open() {
+            return Err(KernelError::ValidationError("Ctrl+Space didn't open the launcher"));
+        }
+        type_keys(&[ENTER, ENTER | RELEASE])?;
+        let window = desktop::active_window().ok_or(KernelError::NotFound)?;
+        let (opened, launcher_open) = {
+            let desktop = desktop::DESKTOP.lock();
+            (desktop.get_windows().len(), desktop.launcher_open())
+        };
+        if opened != windows + 1 || launcher_open {
+            return Err(KernelError::ValidationError("Launcher didn't open a window"));
+        }
+
+        // Move right, then grow a row taller
+        let (position, size) = { let window = window.lock(); (window.position(), window.size()) };
+        type_keys(&chord(&[EXTENDED, ARROW_RIGHT], false))?;
+        type_keys(&chord(&[EXTENDED, ARROW_DOWN], true))?;
+        let (moved, resized) = { let window = window.lock(); (window.position(), window.size()) };
+        if moved != (position.0 + 1, position.1) || resized != (size.0, size.1 + 1) {
+            serial_println!("GUI EVENTS: Window went from {:?} {:?} to {:?} {:?}", position, size, moved, resized);
+            return Err(KernelError::ValidationError("Ctrl+arrows didn't move and resize the window"));
+        }
+
+        // With other windows open, Ctrl+Tab brings one of them up
+        type_keys(&chord(&[TAB], false))?;
+        if windows > 0 && desktop::active_window().map_or(true, |active| Arc::ptr_eq(&active, &window)) {
+            return Err(KernelError::ValidationError("Ctrl+Tab didn't change the focused window"));
+        }
+        while !desktop::active_window().map_or(true, |active| Arc::ptr_eq(&active, &window)) {
+            type_keys(&chord(&[TAB], false))?;
+        }
+
+        // Ctrl+M hides it and Alt+Tab brings it back
+        let focused = || desktop::active_window().is_some_and(|active| Arc::ptr_eq(&active, &window));
+        type_keys(&chord(&[KEY_M], false))?;
+        let minimized = window.lock().is_minimized();
+        if !minimized || focused() {
+            return Err(KernelError::ValidationError("Ctrl+M didn't minimize the window"));
+        }
+        for _ in 0..=windows {
+            if focused() {
+                break;
+            }
+            type_keys(&[ALT, TAB, TAB | RELEASE, ALT | RELEASE])?;
+        }
+        let minimized = window.lock().is_minimized();
+        if minimized || !focused() {
+            return Err(KernelError::ValidationError("Alt+Tab didn't restore the minimized window"));
+        }
+
+        type_keys(&chord(&[KEY_W], false))?;
+        if desktop::DESKTOP.lock().get_windows().len() != windows {
+            return Err(KernelError::ValidationError("Ctrl+W didn't close the window"));
+        }
+        Ok(())
+    })();
+    input::clear();
+    ps2_keyboard::reset_state();
+
+    result?;
+    serial_println!("GUI EVENTS: Self-test passed");
+    Ok(())
+}
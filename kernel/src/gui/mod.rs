@@ -5,40 +5,157 @@ pub mod window;
 pub mod desktop;
 pub mod app;
 pub mod events;
+pub mod wallpaper;
+pub mod clipboard;
+pub mod widget;
+pub mod calculator;
+pub mod settings;
+pub mod sysmon;
+pub mod compositor;
+pub mod cursor;
+pub mod session;
+pub mod recorder;
+pub mod screenshot;
+pub mod textbox;
+pub mod notify;
+pub mod screensaver;
+pub mod run_dialog;
 
 use crate::drivers::vga_enhanced::{self, Color};
-use crate::drivers::ps2_mouse;
+use crate::drivers::{pit, ps2_mouse};
 use crate::serial_println;
 use crate::errors::KernelError;
 use alloc::vec::Vec;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
+use lazy_static::lazy_static;
+use window::{Window, WindowHandle};
+
+pub use notify::notify;
+
+/// Minimum time between periodic desktop redraws (milliseconds)
+const FRAME_INTERVAL_MS: u64 = 100;
+
+/// Set once `init` has succeeded
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Set once `run` has drawn the desktop; later runs restore it instead
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Periodic update run on a window from the GUI loop; it may keep state
+/// between runs
+pub type FrameHook = Arc<Mutex<dyn FnMut(&mut Window) + Send>>;
+
+struct FrameHookEntry {
+    // Weak, so a closed window drops its hook instead of being kept alive
+    window: Weak<Mutex<Window>>,
+    interval_ms: u64,
+    last_run_ms: u64,
+    hook: FrameHook,
+}
+
+lazy_static! {
+    static ref FRAME_HOOKS: Mutex<Vec<FrameHookEntry>> = Mutex::new(Vec::new());
+}
+
+/// Run `hook` on `window` every `interval_ms`, paced by the GUI frame loop.
+/// The hook is removed automatically once the window is closed.
+pub fn add_frame_hook(window: &WindowHandle, interval_ms: u64, hook: impl FnMut(&mut Window) + Send + 'static) {
+    FRAME_HOOKS.lock().push(FrameHookEntry {
+        window: Arc::downgrade(window),
+        interval_ms,
+        last_run_ms: pit::uptime_ms(),
+        hook: Arc::new(Mutex::new(hook)),
+    });
+}
+
+/// Number of registered frame hooks
+pub fn frame_hook_count() -> usize {
+    FRAME_HOOKS.lock().len()
+}
+
+/// Drop hooks whose window is gone and run the ones that are due
+pub fn run_frame_hooks(now_ms: u64) {
+    let mut due: Vec<(WindowHandle, FrameHook)> = Vec::new();
+    {
+        let mut hooks = FRAME_HOOKS.lock();
+        hooks.retain(|entry| entry.window.strong_count() > 0);
+        for entry in hooks.iter_mut() {
+            if now_ms.saturating_sub(entry.last_run_ms) >= entry.interval_ms {
+                if let Some(window) = entry.window.upgrade() {
+                    due.push((window, entry.hook.clone()));
+                    entry.last_run_ms = now_ms;
+                }
+            }
+        }
+    }
+    
+    // Run without the hook list locked so hooks may add others
+    for (window, hook) in due {
+        (hook.lock())(&mut window.lock());
+    }
+}
 
 /// Initialize the GUI subsystem
 pub fn init() -> Result<(), KernelError> {
     serial_println!("DEBUG: Initializing GUI subsystem");
+    if !vga_enhanced::display_available() {
+        serial_println!("GUI: No display to show a desktop on");
+        return Err(KernelError::DeviceNotFound);
+    }
     
     // Initialize the desktop
     desktop::init()?;
+    // Pointer positions are pixels over the cell grid
+    let (width, height) = events::pointer_bounds();
+    ps2_mouse::set_bounds(width, height);
     
     // Add basic applications to the desktop
     app::register_default_apps()?;
     
+    // Reopen the windows left open last time
+    match session::restore() {
+        Ok(0) => {}
+        Ok(count) => serial_println!("DEBUG: Restored {} windows from the last session", count),
+        Err(e) => serial_println!("WARNING: Can't restore the last session: {:?}", e),
+    }
+    
+    INITIALIZED.store(true, Ordering::Relaxed);
     serial_println!("DEBUG: GUI subsystem initialized successfully");
     Ok(())
 }
 
-/// Start the GUI (blocking)
+/// Whether the GUI came up at boot
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Relaxed)
+}
+
+/// Run the GUI until it's left, by Ctrl+Alt+Q or a switch to the console
+/// (see `vt`). The desktop and its windows live on, so a later call picks
+/// up where this one stopped.
 pub fn run() -> Result<(), KernelError> {
     serial_println!("DEBUG: Starting GUI main loop");
+    desktop::DESKTOP.lock().cancel_exit();
     
-    // Draw the desktop
-    desktop::draw()?;
+    // Draw the desktop, or put back the last frame over the console
+    if STARTED.swap(true, Ordering::Relaxed) {
+        compositor::repaint();
+        desktop::refresh()?;
+    } else {
+        desktop::draw()?;
+        recorder::start_from_config();
+    }
+    cursor::show();
     
     // Main GUI loop
     let mut loop_count = 0;
+    let mut last_frame_ms = pit::uptime_ms();
+    let heartbeat = crate::task::watchdog::register("gui");
     
     loop {
+        heartbeat.kick();
+        
         // Check for mouse events
         if let Some(event) = ps2_mouse::get_event() {
             // Handle mouse event
@@ -51,24 +168,56 @@ pub fn run() -> Result<(), KernelError> {
             events::handle_keyboard_event(event)?;
         }
         
-        // Periodic redraw
-        if loop_count % 10_000_000 == 0 {
+        // Process network traffic and other deferred work
+        crate::net::poll();
+        crate::drivers::rtc::poll();
+        pit::poll();
+        crate::task::deferred::run_pending();
+        recorder::poll(pit::uptime_ms());
+        
+        // Nothing else is drawn while the screensaver is up
+        if screensaver::is_due() {
+            cursor::hide();
+            screensaver::run(screensaver::lock_on_idle(), &mut || {
+                heartbeat.kick();
+                crate::net::poll();
+                crate::drivers::rtc::poll();
+                pit::poll();
+                crate::task::deferred::run_pending();
+            });
+            cursor::show();
+        }
+        
+        // Periodic redraw, paced by the PIT
+        let now = pit::uptime_ms();
+        if desktop::take_redraw_request() || now - last_frame_ms >= FRAME_INTERVAL_MS {
+            run_frame_hooks(now);
+            notify::tick();
             desktop::refresh()?;
+            session::poll(now);
+            last_frame_ms = now;
         }
         
         // Check for exit request
-        if events::should_exit() {
+        if events::should_exit() || crate::vt::switch_pending() {
             break;
         }
         
-        // Use HLT to save CPU when possible
+        // Idle when possible, which also keeps the load figure
         if loop_count % 1000 == 0 {
-            x86_64::instructions::hlt();
+            crate::task::idle::idle_once();
         }
         
         loop_count += 1;
     }
     
+    cursor::hide();
+    if let Some((path, events)) = recorder::stop() {
+        serial_println!("DEBUG: Recorded {} GUI input events to {}", events, path);
+    }
+    if let Err(e) = session::save() {
+        serial_println!("WARNING: Can't save the desktop session: {:?}", e);
+    }
     serial_println!("DEBUG: GUI main loop exited");
     Ok(())
 } 
\ No newline at end of file
@@ -0,0 +1,245 @@
+//! Getting attention without taking focus: the bell, taskbar button
+//! flashes and notification badges
+//!
+//! `notify` adds one to a window's badge, drawn on its taskbar button until
+//! the window has focus, and shows its text on the taskbar's top row.
+//! Notifications for no window, such as logged errors, go on the System
+//! button, which is only there while it has some; clicking it clears them.
+//!
+//! The bell beeps the PC speaker or, with `ui.visual_bell`, flashes the
+//! window's taskbar button instead. Flashes go a step per GUI frame.
+
+use crate::config;
+use crate::drivers::pit;
+use crate::errors::KernelError;
+use crate::gui::window::{Window, WindowHandle};
+use crate::serial_println;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Taskbar button for notifications that aren't about a window
+pub const SYSTEM_TITLE: &str = "System";
+
+/// Setting that flashes instead of beeping
+pub const VISUAL_BELL_KEY: &str = "ui.visual_bell";
+
+/// Frames a flash lasts; the button is lit on every other one
+const FLASH_FRAMES: u8 = 6;
+
+/// The audible bell
+const BEEP_HZ: u32 = 880;
+const BEEP_MS: u64 = 120;
+
+/// What a window, or System, has waiting
+#[derive(Default)]
+struct Attention {
+    badge: usize,
+    flash_frames: u8,
+}
+
+/// Who a notification is for: a window, or System when None
+type Owner = Option<Weak<Mutex<Window>>>;
+
+struct Notifications {
+    windows: Vec<(Weak<Mutex<Window>>, Attention)>,
+    system: Attention,
+    /// Text of the newest notification not yet seen, and who it's for
+    latest: Option<(Owner, String)>,
+    /// Set when the taskbar needs drawing again
+    changed: bool,
+}
+
+impl Notifications {
+    /// What `window` has waiting, or System's for None
+    fn attention(&mut self, window: Option<&WindowHandle>) -> &mut Attention {
+        let Some(window) = window else {
+            return &mut self.system;
+        };
+        let index = match self.windows.iter().position(|(weak, _)| weak.as_ptr() == Arc::as_ptr(window)) {
+            Some(index) => index,
+            None => {
+                self.windows.push((Arc::downgrade(window), Attention::default()));
+                self.windows.len() - 1
+            }
+        };
+        &mut self.windows[index].1
+    }
+
+    /// What `window` has waiting, without adding it
+    fn find(&self, window: Option<&WindowHandle>) -> Option<&Attention> {
+        match window {
+            Some(window) => self.windows.iter()
+                .find(|(weak, _)| weak.as_ptr() == Arc::as_ptr(window))
+                .map(|(_, attention)| attention),
+            None => Some(&self.system),
+        }
+    }
+
+    fn post(&mut self, window: Option<&WindowHandle>, text: &str) {
+        self.attention(window).badge += 1;
+        self.latest = Some((window.map(Arc::downgrade), String::from(text)));
+        self.changed = true;
+    }
+
+    fn flash(&mut self, window: Option<&WindowHandle>) {
+        self.attention(window).flash_frames = FLASH_FRAMES;
+        self.changed = true;
+    }
+
+    fn clear(&mut self, window: Option<&WindowHandle>) {
+        let attention = self.attention(window);
+        if attention.badge == 0 {
+            return;
+        }
+        attention.badge = 0;
+        let pointer = window.map(Arc::as_ptr);
+        if self.latest.as_ref().is_some_and(|(owner, _)| owner.as_ref().map(Weak::as_ptr) == pointer) {
+            self.latest = None;
+        }
+        self.changed = true;
+    }
+
+    /// Step flashes on a frame, and forget closed windows
+    fn tick(&mut self) {
+        self.windows.retain(|(weak, _)| weak.strong_count() > 0);
+        if self.latest.as_ref().is_some_and(|(owner, _)| owner.as_ref().is_some_and(|weak| weak.strong_count() == 0)) {
+            self.latest = None;
+            self.changed = true;
+        }
+        for attention in self.windows.iter_mut().map(|(_, attention)| attention).chain([&mut self.system]) {
+            if attention.flash_frames > 0 {
+                attention.flash_frames -= 1;
+                self.changed = true;
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref NOTIFICATIONS: Mutex<Notifications> = Mutex::new(Notifications {
+        windows: Vec::new(),
+        system: Attention::default(),
+        latest: None,
+        changed: false,
+    });
+}
+
+/// Tell the user `text` about `window`, or about the system for None,
+/// without focusing anything. Never locks `window`, so apps may call it
+/// with their own window locked.
+pub fn notify(window: Option<&WindowHandle>, text: &str) {
+    serial_println!("NOTIFY: {}", text);
+    NOTIFICATIONS.lock().post(window, text);
+}
+
+/// Ring the bell for `window`, or the system for None: a beep, or with
+/// `ui.visual_bell` and the GUI up, a flash of its taskbar button
+pub fn bell(window: Option<&WindowHandle>) {
+    let visual = config::get(VISUAL_BELL_KEY).and_then(|value| value.try_as_boolean()).unwrap_or(false);
+    if visual && crate::gui::is_initialized() {
+        NOTIFICATIONS.lock().flash(window);
+    } else {
+        pit::beep(BEEP_HZ, BEEP_MS);
+    }
+}
+
+/// Clear `window`'s badge, or System's for None
+pub fn clear(window: Option<&WindowHandle>) {
+    NOTIFICATIONS.lock().clear(window);
+}
+
+/// Notifications `window` (System for None) has waiting
+pub fn badge(window: Option<&WindowHandle>) -> usize {
+    NOTIFICATIONS.lock().find(window).map_or(0, |attention| attention.badge)
+}
+
+/// Whether `window`'s taskbar button (System's for None) is lit right now
+pub fn is_lit(window: Option<&WindowHandle>) -> bool {
+    NOTIFICATIONS.lock().find(window).is_some_and(|attention| attention.flash_frames % 2 == 1)
+}
+
+/// Whether the System button has anything to show
+pub fn system_pending() -> bool {
+    let notifications = NOTIFICATIONS.lock();
+    notifications.system.badge > 0 || notifications.system.flash_frames > 0
+}
+
+/// The newest notification not yet seen: its window (None for System)
+/// and text
+pub fn latest() -> Option<(Option<WindowHandle>, String)> {
+    let notifications = NOTIFICATIONS.lock();
+    let (owner, text) = notifications.latest.as_ref()?;
+    match owner {
+        Some(weak) => weak.upgrade().map(|window| (Some(window), text.clone())),
+        None => Some((None, text.clone())),
+    }
+}
+
+/// Step flashes; call once a GUI frame
+pub fn tick() {
+    NOTIFICATIONS.lock().tick();
+}
+
+/// Check and clear whether the taskbar needs drawing again
+pub fn take_changed() -> bool {
+    core::mem::replace(&mut NOTIFICATIONS.lock().changed, false)
+}
+
+/// Check badges, clearing, flashing and forgetting closed windows, on
+/// state of its own
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("NOTIFY: Running self-test");
+
+    let mut notifications = Notifications { windows: Vec::new(), system: Attention::default(), latest: None, changed: false };
+    let first = crate::gui::window::create_window("first", 0, 0, 20, 5);
+    let second = crate::gui::window::create_window("second", 0, 0, 20, 5);
+
+    notifications.post(Some(&first), "one");
+    notifications.post(Some(&first), "two");
+    notifications.post(None, "disk error");
+    let badges = |n: &Notifications| [Some(&first), Some(&second), None]
+        .map(|window| n.find(window).map_or(0, |attention| attention.badge));
+    if badges(&notifications) != [2, 0, 1] || !notifications.changed {
+        return Err(KernelError::ValidationError("Notifications counted wrongly"));
+    }
+    if notifications.latest.as_ref().map(|(owner, text)| (owner.is_none(), text.as_str())) != Some((true, "disk error")) {
+        return Err(KernelError::ValidationError("Newest notification not shown"));
+    }
+
+    // Clearing one window leaves the others, and the newest text if it isn't
+    // that window's
+    notifications.clear(Some(&first));
+    if badges(&notifications) != [0, 0, 1] || notifications.latest.is_none() {
+        return Err(KernelError::ValidationError("Clearing a badge touched another"));
+    }
+    notifications.clear(None);
+    if badges(&notifications) != [0, 0, 0] || notifications.latest.is_some() {
+        return Err(KernelError::ValidationError("System badge not cleared"));
+    }
+
+    // A flash lights the button on alternate frames, then stops
+    notifications.flash(Some(&second));
+    let mut lit = Vec::new();
+    for _ in 0..FLASH_FRAMES + 2 {
+        notifications.tick();
+        lit.push(notifications.find(Some(&second)).is_some_and(|attention| attention.flash_frames % 2 == 1));
+    }
+    if lit != [true, false, true, false, true, false, false, false] {
+        serial_println!("NOTIFY: Flash went {:?}", lit);
+        return Err(KernelError::ValidationError("Taskbar flash went wrong"));
+    }
+
+    // A closed window's notifications go with it
+    notifications.post(Some(&second), "done");
+    drop(second);
+    notifications.tick();
+    if notifications.windows.len() != 1 || notifications.latest.is_some() {
+        return Err(KernelError::ValidationError("Closed window's notifications kept"));
+    }
+
+    serial_println!("NOTIFY: Self-test passed");
+    Ok(())
+}
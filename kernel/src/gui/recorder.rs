@@ -0,0 +1,347 @@
+//! Recording GUI input to a file and playing it back, for repeatable GUI
+//! tests
+//!
+//! While recording, every keyboard and mouse event the GUI handles is
+//! written to the file as a line, with the milliseconds from the start of
+//! recording to when the event was queued, not when the GUI got to it. The file begins with where the pointer was and which window had
+//! focus, and playback puts those back first. Playback injects the events
+//! through `drivers::input`, so it needs `debug.input_injection`, and is
+//! driven by `poll` from the GUI loop, keeping the original gaps between
+//! events (scaled by a speed). Each event waits for the previous one to be
+//! taken, since mouse moves are injected relative to where the pointer is.
+//! Started from the same session (see `session`), the GUI sees the same
+//! input in the same order.
+//!
+//! The file, one item a line:
+//!
+//! ```text
+//! # guirec 1
+//! pointer <x> <y>
+//! focus <window title>
+//! <ms> key <make code in hex> down|up [ext]
+//! <ms> mouse <x> <y> <buttons: 1 left, 2 right, 4 middle>
+//! ```
+//!
+//! `gui.record_file` records each GUI run to that file, from start to exit.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::config;
+use crate::drivers::{input, pit, ps2_mouse};
+use crate::time;
+use crate::drivers::ps2_keyboard::{KeyEvent, KeyState};
+use crate::drivers::ps2_mouse::MouseEvent;
+use crate::errors::KernelError;
+use crate::fs::{self, vfs::file_flags};
+use crate::gui::desktop;
+use crate::serial_println;
+
+const HEADER: &str = "# guirec 1";
+
+/// Largest recording played back
+const MAX_FILE_SIZE: usize = 256 * 1024;
+
+/// One recorded input event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recorded {
+    Key { scancode: u8, extended: bool, pressed: bool },
+    Mouse { x: i16, y: i16, buttons: u8 },
+}
+
+impl Recorded {
+    /// The event for a key, if the key has a scancode
+    pub fn key(event: &KeyEvent) -> Option<Self> {
+        let (scancode, extended) = input::key_scancode(event.code)?;
+        Some(Recorded::Key { scancode, extended, pressed: event.state == KeyState::Pressed })
+    }
+
+    /// The event for a mouse report
+    pub fn mouse(event: &MouseEvent) -> Self {
+        let buttons = u8::from(event.buttons.left) | u8::from(event.buttons.right) << 1 | u8::from(event.buttons.middle) << 2;
+        Recorded::Mouse { x: event.x, y: event.y, buttons }
+    }
+
+    /// The event's line, `ms` after recording started
+    pub fn line(&self, ms: u64) -> String {
+        match *self {
+            Recorded::Key { scancode, extended, pressed } => format!("{} key {:02x} {}{}", ms, scancode,
+                if pressed { "down" } else { "up" }, if extended { " ext" } else { "" }),
+            Recorded::Mouse { x, y, buttons } => format!("{} mouse {} {} {}", ms, x, y, buttons),
+        }
+    }
+
+    /// Inject the event as if it came from the keyboard or mouse
+    fn inject(&self) -> Result<(), KernelError> {
+        match *self {
+            Recorded::Key { scancode, extended, pressed } => input::inject_scancode(scancode, extended, pressed),
+            Recorded::Mouse { x, y, buttons } => input::mouse_to(x, y, buttons),
+        }
+    }
+}
+
+/// A recording read back from its file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    /// Where the pointer was when recording started
+    pub pointer: (i16, i16),
+    /// Title of the window that had focus, if any
+    pub focus: Option<String>,
+    /// Events with their milliseconds since the start, in order
+    pub events: Vec<(u64, Recorded)>,
+}
+
+/// The lines a recording starts with
+fn header(pointer: (i16, i16), focus: Option<&str>) -> String {
+    let mut text = format!("{}\npointer {} {}\n", HEADER, pointer.0, pointer.1);
+    if let Some(title) = focus {
+        text.push_str(&format!("focus {}\n", title));
+    }
+    text
+}
+
+/// Read a recording; InvalidData if it isn't one or a line doesn't parse
+pub fn parse(content: &str) -> Result<Recording, KernelError> {
+    let mut lines = content.lines();
+    if lines.next().map(str::trim) != Some(HEADER) {
+        return Err(KernelError::InvalidData);
+    }
+    let mut recording = Recording { pointer: (0, 0), focus: None, events: Vec::new() };
+    for line in lines.map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(title) = line.strip_prefix("focus ") {
+            recording.focus = Some(title.to_string());
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |word: &str| word.parse::<i16>().map_err(|_| KernelError::InvalidData);
+        match words.as_slice() {
+            ["pointer", x, y] => recording.pointer = (number(x)?, number(y)?),
+            [ms, "key", scancode, state, rest @ ..] => {
+                let event = Recorded::Key {
+                    scancode: u8::from_str_radix(scancode, 16).map_err(|_| KernelError::InvalidData)?,
+                    extended: match rest {
+                        [] => false,
+                        ["ext"] => true,
+                        _ => return Err(KernelError::InvalidData),
+                    },
+                    pressed: match *state {
+                        "down" => true,
+                        "up" => false,
+                        _ => return Err(KernelError::InvalidData),
+                    },
+                };
+                recording.events.push((ms.parse().map_err(|_| KernelError::InvalidData)?, event));
+            }
+            [ms, "mouse", x, y, buttons] => {
+                let buttons = buttons.parse().map_err(|_| KernelError::InvalidData)?;
+                let event = Recorded::Mouse { x: number(x)?, y: number(y)?, buttons };
+                recording.events.push((ms.parse().map_err(|_| KernelError::InvalidData)?, event));
+            }
+            _ => return Err(KernelError::InvalidData),
+        }
+    }
+    // Out of order times would play back in a burst
+    if recording.events.windows(2).any(|pair| pair[1].0 < pair[0].0) {
+        return Err(KernelError::InvalidData);
+    }
+    Ok(recording)
+}
+
+struct Recorder {
+    fd: u32,
+    path: String,
+    start_ms: u64,
+    /// Time of the last event written, since the start
+    last_ms: u64,
+    events: usize,
+}
+
+/// Events being played back
+struct Player {
+    events: VecDeque<(u64, Recorded)>,
+    start_ms: u64,
+    /// 100 is the original pace, 200 twice as fast
+    speed_percent: u64,
+}
+
+lazy_static! {
+    static ref RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+    static ref PLAYER: Mutex<Option<Player>> = Mutex::new(None);
+}
+
+/// Start recording GUI input to `path`, replacing the file. Fails with
+/// InvalidOperation if already recording.
+pub fn start(path: &str) -> Result<(), KernelError> {
+    let mut recorder = RECORDER.lock();
+    if recorder.is_some() {
+        return Err(KernelError::InvalidOperation);
+    }
+    let pointer = ps2_mouse::get_state();
+    let focus = desktop::active_window().map(|window| window.lock().title().to_string());
+    let fd = fs::fd::open_tagged("guirec", path, file_flags::WRITE | file_flags::CREATE | file_flags::TRUNCATE)?;
+    if let Err(e) = fs::fd::write(fd, header((pointer.x, pointer.y), focus.as_deref()).as_bytes()) {
+        let _ = fs::fd::close(fd);
+        return Err(e);
+    }
+    *recorder = Some(Recorder { fd, path: path.to_string(), start_ms: time::monotonic_ms(), last_ms: 0, events: 0 });
+    Ok(())
+}
+
+/// Stop recording; returns the file and the number of events written, or
+/// None if nothing was being recorded
+pub fn stop() -> Option<(String, usize)> {
+    let recorder = RECORDER.lock().take()?;
+    let _ = fs::fd::close(recorder.fd);
+    Some((recorder.path, recorder.events))
+}
+
+/// Whether input is being recorded
+pub fn is_recording() -> bool {
+    RECORDER.lock().is_some()
+}
+
+/// Start recording to `gui.record_file`, if it is set, when the GUI starts
+pub fn start_from_config() {
+    let Some(path) = config::get("gui.record_file").map(|value| value.as_string()).filter(|path| !path.is_empty()) else {
+        return;
+    };
+    match start(&path) {
+        Ok(()) => serial_println!("DEBUG: Recording GUI input to {}", path),
+        Err(e) => serial_println!("WARNING: Can't record GUI input to {}: {:?}", path, e),
+    }
+}
+
+/// Write an event the GUI is handling, queued at `time_ms` (see
+/// `KeyEvent::time_ms`), if recording. A write that fails ends the
+/// recording.
+pub fn record(event: Recorded, time_ms: u64) {
+    let mut recorder = RECORDER.lock();
+    let Some(active) = recorder.as_mut() else {
+        return;
+    };
+    // Keys and mouse come from separate queues, so an event can be older
+    // than the last one written; the file must not go back in time
+    let ms = time_ms.saturating_sub(active.start_ms).max(active.last_ms);
+    let line = format!("{}\n", event.line(ms));
+    match fs::fd::write(active.fd, line.as_bytes()) {
+        Ok(_) => {
+            active.events += 1;
+            active.last_ms = ms;
+        }
+        Err(e) => {
+            serial_println!("WARNING: Stopped recording GUI input to {}: {:?}", active.path, e);
+            let _ = fs::fd::close(active.fd);
+            *recorder = None;
+        }
+    }
+}
+
+/// Read the recording in `path`
+pub fn load(path: &str) -> Result<Recording, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let size = vfs.metadata(path)?.size as usize;
+    if size > MAX_FILE_SIZE {
+        return Err(KernelError::InvalidParameter);
+    }
+    let mut data = alloc::vec![0u8; size];
+    let read = vfs.find_fs(path)?.lock().read_at(path, 0, &mut data)?;
+    parse(core::str::from_utf8(&data[..read]).map_err(|_| KernelError::InvalidData)?)
+}
+
+/// Play back the recording in `path` at `speed_percent` of the original
+/// pace: focus the window that had focus (if one has that title), move
+/// the pointer back, then leave the events to `poll`. Returns the number
+/// of events. Needs input injection on.
+pub fn play(path: &str, speed_percent: u64) -> Result<usize, KernelError> {
+    if !input::injection_enabled() {
+        return Err(KernelError::InvalidOperation);
+    }
+    if speed_percent == 0 {
+        return Err(KernelError::InvalidParameter);
+    }
+    let recording = load(path)?;
+    if let Some(title) = recording.focus.as_deref() {
+        if !desktop::focus_titled(title) {
+            serial_println!("DEBUG: guirec - no window titled '{}' to focus", title);
+        }
+    }
+    let count = recording.events.len();
+    input::mouse_to(recording.pointer.0, recording.pointer.1, 0)?;
+    *PLAYER.lock() = Some(Player { events: recording.events.into(), start_ms: pit::uptime_ms(), speed_percent });
+    Ok(count)
+}
+
+/// Stop playing back; returns whether something was playing
+pub fn stop_playing() -> bool {
+    PLAYER.lock().take().is_some()
+}
+
+/// Events left to play back, if playing
+pub fn remaining() -> Option<usize> {
+    PLAYER.lock().as_ref().map(|player| player.events.len())
+}
+
+/// Inject the events that are due, from the GUI loop. Only one goes in at
+/// a time, once the drivers have taken the last; playback ends after the
+/// last event or one that can't be injected.
+pub fn poll(now_ms: u64) {
+    let mut player = PLAYER.lock();
+    let Some(active) = player.as_mut() else {
+        return;
+    };
+    if !input::is_idle() {
+        return;
+    }
+    let elapsed = now_ms.saturating_sub(active.start_ms) * active.speed_percent / 100;
+    match active.events.front().copied() {
+        Some((ms, event)) if ms <= elapsed => {
+            if let Err(e) = event.inject() {
+                serial_println!("WARNING: GUI playback stopped: {:?}", e);
+                *player = None;
+                return;
+            }
+            active.events.pop_front();
+        }
+        Some(_) => {}
+        None => *player = None,
+    }
+}
+
+/// Write events to lines and read them back, and check bad files are
+/// refused
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("GUIREC: Running self-test");
+
+    let events = [
+        (0, Recorded::Key { scancode: 0x1D, extended: false, pressed: true }),
+        (40, Recorded::Key { scancode: 0x48, extended: true, pressed: false }),
+        (75, Recorded::Mouse { x: -3, y: 200, buttons: 1 }),
+    ];
+    let mut content = header((320, 200), Some("Text Editor - notes.txt"));
+    for (ms, event) in &events {
+        content.push_str(&event.line(*ms));
+        content.push('\n');
+    }
+    let recording = parse(&content)?;
+    if recording.pointer != (320, 200) || recording.focus.as_deref() != Some("Text Editor - notes.txt")
+        || recording.events != events || events[1].1.line(40) != "40 key 48 up ext" {
+        serial_println!("GUIREC: Read back {:?}", recording);
+        return Err(KernelError::ValidationError("Recording read back wrongly"));
+    }
+
+    let bad = [
+        "pointer 1 2\n",
+        "# guirec 1\n10 key 1e sideways\n",
+        "# guirec 1\n10 mouse 1 2\n",
+        "# guirec 1\n20 key 1e down\n10 key 1e up\n",
+    ];
+    if bad.iter().any(|content| parse(content).is_ok()) || parse(HEADER)?.focus.is_some() {
+        return Err(KernelError::ValidationError("Bad recording accepted"));
+    }
+
+    serial_println!("GUIREC: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! Run dialog
+//!
+//! Ctrl+R opens a small dialog that takes a command line and runs it. Its
+//! first word is looked up as a desktop app (by name, in any case), then as
+//! a shell command, then as a program: a path, or a name in /bin. Apps open
+//! their window; commands and programs run in a new terminal. Lines that
+//! ran are kept in $HOME/.run_history, and Up/Down bring them back. When
+//! nothing matches, the dialog says so and stays open.
+//!
+//! While open the dialog takes every key, but it doesn't stop the desktop:
+//! windows underneath keep redrawing, and it is drawn over them like the
+//! launcher.
+
+use crate::drivers::ps2_keyboard::{KeyCode, KeyEvent};
+use crate::drivers::vga_enhanced::Color;
+use crate::errors::KernelError;
+use crate::fs::vfs::{self, NodeType};
+use crate::gui::compositor::{self, Rect, SCREEN_WIDTH};
+use crate::gui::desktop;
+use crate::gui::textbox::TextBox;
+use crate::i18n::{MSG_RUN_HINT, MSG_RUN_PROMPT, MSG_RUN_TITLE};
+use crate::serial_println;
+use crate::shell::commands;
+use crate::shell::history::History;
+use crate::text;
+use crate::tr;
+use crate::user;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// History file name, inside the user's home directory
+pub const HISTORY_FILE: &str = ".run_history";
+/// Lines of history kept
+const HISTORY_SIZE: usize = 50;
+/// Where programs named without a path are looked for
+const PROGRAM_DIR: &str = "/bin";
+/// App that runs commands and programs
+const TERMINAL_APP: &str = "Terminal";
+
+/// Centered over the desktop, above the taskbar
+const WIDTH: usize = 56;
+const HEIGHT: usize = 6;
+const BOUNDS: Rect = Rect::new((SCREEN_WIDTH - WIDTH) / 2, (23 - HEIGHT) / 2, WIDTH, HEIGHT);
+const ERROR_ROW: usize = 3;
+
+lazy_static! {
+    static ref DIALOG: Mutex<Option<RunDialog>> = Mutex::new(None);
+}
+
+/// What a command line runs
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    /// A desktop app, by its registered name
+    App(String),
+    /// A shell command line
+    Command(String),
+    /// A program's path and its arguments, as a terminal line
+    Program(String),
+}
+
+/// Work out what `line` runs: one of `apps`, then a shell command, then a
+/// program `is_program` finds. The error says why nothing matched.
+fn resolve(line: &str, apps: &[String], is_program: &dyn Fn(&str) -> bool) -> Result<Target, String> {
+    let line = line.trim();
+    let Some(name) = line.split_whitespace().next() else {
+        return Err(String::from("Type the name of an app, command or program"));
+    };
+    if let Some(app) = apps.iter().find(|app| app.eq_ignore_ascii_case(name)) {
+        return Ok(Target::App(app.clone()));
+    }
+    if !name.contains('/') && commands::find(name).is_some() {
+        return Ok(Target::Command(String::from(line)));
+    }
+    let path = match name {
+        _ if name.starts_with('/') => String::from(name),
+        _ if name.contains('/') => format!("/{}", name),
+        _ => format!("{}/{}", PROGRAM_DIR, name),
+    };
+    if is_program(&path) {
+        return Ok(Target::Program(format!("{}{}", path, &line[name.len()..])));
+    }
+    Err(format!("{}: no app, command or program by that name", name))
+}
+
+fn is_program(path: &str) -> bool {
+    vfs::get_vfs_manager().and_then(|vfs| vfs.metadata(path).ok())
+        .is_some_and(|metadata| metadata.node_type == NodeType::File)
+}
+
+/// Open what `target` names. Runs without the dialog locked, as it locks
+/// the desktop.
+fn run(target: Target) -> Result<(), KernelError> {
+    serial_println!("RUN: {:?}", target);
+    match target {
+        Target::App(name) => desktop::launch(&name, None).map(drop),
+        Target::Command(line) | Target::Program(line) => {
+            let terminal = desktop::launch(TERMINAL_APP, None)?;
+            let result = terminal.lock().submit_input(&line);
+            result
+        }
+    }
+}
+
+struct RunDialog {
+    input: TextBox,
+    history: History,
+    /// History entry shown, counting from 1 for the oldest
+    browsing: Option<usize>,
+    /// Why the last line didn't run
+    error: Option<String>,
+}
+
+impl RunDialog {
+    fn new(history: History) -> Self {
+        RunDialog { input: TextBox::new(2, 2, WIDTH - 4), history, browsing: None, error: None }
+    }
+
+    /// Show the next older or newer history entry; newer than the newest
+    /// is an empty line again
+    fn browse(&mut self, older: bool) {
+        let newest = self.history.len();
+        let next = match (self.browsing, older) {
+            (None, true) if newest > 0 => Some(newest),
+            (None, _) => return,
+            (Some(number), true) => Some(number.saturating_sub(1).max(1)),
+            (Some(number), false) if number < newest => Some(number + 1),
+            (Some(_), false) => None,
+        };
+        self.browsing = next;
+        let line = next.and_then(|number| self.history.get(number)).unwrap_or("");
+        self.input.set_text(line);
+    }
+
+    fn draw(&self) {
+        let (fg, bg) = (Color::Black, Color::LightGray);
+        let Rect { x, y, width, height } = BOUNDS;
+        let inner = width - 2;
+        let title = format!("─ {} ", tr!(MSG_RUN_TITLE));
+        compositor::write_at(y, x, &format!("┌{:─<width$}┐", title, width = inner), Color::White, Color::Blue);
+        let lines = [tr!(MSG_RUN_PROMPT), "", "", tr!(MSG_RUN_HINT)];
+        for (offset, line) in lines.iter().enumerate() {
+            let line = text::ellipsize(&format!(" {}", line), inner);
+            compositor::write_at(y + 1 + offset, x, &format!("│{:<width$}│", line, width = inner), fg, bg);
+        }
+        compositor::write_at(y + height - 1, x, &format!("└{:─<width$}┘", "", width = inner), fg, bg);
+        if let Some(error) = &self.error {
+            compositor::write_at(y + ERROR_ROW, x + 2, &text::ellipsize(error, inner - 2), Color::Red, bg);
+        }
+        self.input.draw(x, y, true);
+    }
+}
+
+/// Open the dialog with an empty line, closing the launcher
+pub fn open() {
+    let mut dialog = DIALOG.lock();
+    if dialog.is_none() {
+        let path = format!("{}/{}", user::home_dir(), HISTORY_FILE);
+        *dialog = Some(RunDialog::new(History::load(&path, HISTORY_SIZE)));
+        compositor::damage(BOUNDS);
+    }
+    drop(dialog);
+    let mut desktop = desktop::DESKTOP.lock();
+    if desktop.launcher_open() {
+        desktop.toggle_launcher();
+    }
+}
+
+pub fn is_open() -> bool {
+    DIALOG.lock().is_some()
+}
+
+/// Offer a key press to the dialog; returns whether it was open and took
+/// it. Enter runs the line, or shows why it can't; Esc closes the dialog.
+pub fn handle_key(event: &KeyEvent) -> Result<bool, KernelError> {
+    let mut guard = DIALOG.lock();
+    let Some(dialog) = guard.as_mut() else {
+        return Ok(false);
+    };
+    compositor::damage(BOUNDS);
+    match event.code {
+        KeyCode::Escape => *guard = None,
+        KeyCode::ArrowUp | KeyCode::ArrowDown => dialog.browse(event.code == KeyCode::ArrowUp),
+        KeyCode::Enter => {
+            let line = String::from(dialog.input.text().trim());
+            let target = match resolve(&line, &desktop::app_names(), &is_program) {
+                Ok(target) => target,
+                Err(message) => {
+                    dialog.error = Some(message);
+                    return Ok(true);
+                }
+            };
+            dialog.history.push(&line);
+            let mut closed = guard.take();
+            drop(guard);
+            if let Err(e) = run(target) {
+                // It matched but wouldn't open: back to the dialog to say so
+                if let Some(dialog) = closed.as_mut() {
+                    dialog.error = Some(format!("Can't run {}: {}", line, e));
+                }
+                *DIALOG.lock() = closed;
+            }
+        }
+        _ => {
+            if dialog.input.handle_key(event)? {
+                dialog.error = None;
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Draw the dialog, if open, over whatever the desktop drew
+pub fn draw() {
+    if let Some(dialog) = DIALOG.lock().as_ref() {
+        dialog.draw();
+    }
+}
+
+/// Check lines resolve in order and that history browsing stops at both ends
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("RUN: Running self-test");
+
+    let apps = vec![String::from("Calculator"), String::from("Help Viewer")];
+    let programs = |path: &str| path == "/bin/hello";
+    let cases = [
+        ("calculator", Ok(Target::App(String::from("Calculator")))),
+        ("help me", Ok(Target::Command(String::from("help me")))),
+        ("hello one two", Ok(Target::Program(String::from("/bin/hello one two")))),
+        ("  /bin/hello ", Ok(Target::Program(String::from("/bin/hello")))),
+        ("bin/hello", Ok(Target::Program(String::from("/bin/hello")))),
+    ];
+    for (line, expected) in cases {
+        if resolve(line, &apps, &programs) != expected {
+            serial_println!("RUN: {:?} resolved to {:?}", line, resolve(line, &apps, &programs));
+            return Err(KernelError::ValidationError("Run line resolved wrongly"));
+        }
+    }
+    if resolve("nosuchthing", &apps, &programs).is_ok() || resolve("   ", &apps, &programs).is_ok() {
+        return Err(KernelError::ValidationError("Run line matched nothing but resolved"));
+    }
+
+    let mut history = History::new(HISTORY_SIZE);
+    history.push("first");
+    history.push("second");
+    let mut dialog = RunDialog::new(history);
+    let mut shown = vec![];
+    for older in [true, true, true, false, false] {
+        dialog.browse(older);
+        shown.push(String::from(dialog.input.text()));
+    }
+    if shown != ["second", "first", "first", "second", ""] {
+        serial_println!("RUN: History browsing showed {:?}", shown);
+        return Err(KernelError::ValidationError("Run history browsed wrongly"));
+    }
+
+    serial_println!("RUN: Self-test passed");
+    Ok(())
+}
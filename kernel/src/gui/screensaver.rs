@@ -0,0 +1,295 @@
+//! Screensaver and screen lock
+//!
+//! After `ui.screensaver_timeout` seconds without keyboard or mouse input
+//! the GUI blanks the screen, or with `ui.screensaver` set to "text" bounces
+//! the system's name around it. Any input puts the screen back exactly as
+//! it was. With `user.lock_on_idle` set and a password for the current
+//! user, input brings up a lock dialog instead, and the screen only comes
+//! back once the password is typed. The `lock` command locks at once.
+//!
+//! The saver runs a loop of its own, so nothing on the desktop is redrawn
+//! meanwhile; the caller passes in the work that has to keep going.
+
+use crate::config;
+use crate::drivers::ps2_keyboard::{self, KeyCode, KeyEvent, KeyState};
+use crate::drivers::vga_enhanced::{self, Color, ScreenChar};
+use crate::drivers::{input, pit, ps2_mouse};
+use crate::errors::KernelError;
+use crate::gui::compositor::{self, Rect, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::gui::textbox::TextBox;
+use crate::i18n::{MSG_LOCKED_TITLE, MSG_PASSWORD, MSG_SESSION_LOCKED, MSG_WRONG_PASSWORD};
+use crate::serial_println;
+use crate::tr;
+use crate::user::{self, password};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const TIMEOUT_KEY: &str = "ui.screensaver_timeout";
+pub const STYLE_KEY: &str = "ui.screensaver";
+pub const LOCK_KEY: &str = "user.lock_on_idle";
+
+/// Text the moving saver shows
+const SAVER_TEXT: &str = " UniverseK ";
+
+/// Time between steps of the moving text
+const STEP_MS: u64 = 1000;
+
+/// The lock dialog goes back to the saver after this long without input
+const DIALOG_TIMEOUT_MS: u64 = 30_000;
+
+/// The lock dialog, centered
+const DIALOG: Rect = Rect::new((SCREEN_WIDTH - 44) / 2, (SCREEN_HEIGHT - 7) / 2, 44, 7);
+const PASSWORD_COLUMN: usize = 12;
+const PASSWORD_ROW: usize = 3;
+
+/// Idle time before the saver starts, or None if it never does
+pub fn timeout_ms() -> Option<u64> {
+    let seconds = config::get(TIMEOUT_KEY).and_then(|value| value.try_as_integer()).unwrap_or(0);
+    (seconds > 0).then(|| seconds as u64 * 1000)
+}
+
+/// Whether the machine has been idle long enough for the saver
+pub fn is_due() -> bool {
+    timeout_ms().is_some_and(|timeout| input::idle_ms() >= timeout)
+}
+
+/// Whether the saver started by idling should lock the session: it's set
+/// to, and the current user has a password to unlock it with
+pub fn lock_on_idle() -> bool {
+    config::get(LOCK_KEY).and_then(|value| value.try_as_boolean()).unwrap_or(false)
+        && user::current_username().is_some_and(|name| password::has_password(&name))
+}
+
+/// The next position and velocity of something moving along a line of
+/// `room` cells, turning around at either end
+fn bounce(position: usize, velocity: isize, room: usize) -> (usize, isize) {
+    if room == 0 {
+        return (0, velocity);
+    }
+    let velocity = match position.checked_add_signed(velocity) {
+        Some(next) if next <= room => velocity,
+        _ => -velocity,
+    };
+    (position.saturating_add_signed(velocity).min(room), velocity)
+}
+
+/// Clear `rect` to black in the back buffer
+fn blank(rect: Rect) {
+    compositor::damage(rect);
+    let spaces = format!("{:width$}", "", width = rect.width);
+    for row in rect.y..rect.y + rect.height {
+        compositor::write_at(row, rect.x, &spaces, Color::Black, Color::Black);
+    }
+}
+
+/// The moving-text saver, or a blank screen
+struct Saver {
+    moving: bool,
+    column: usize,
+    row: usize,
+    velocity: (isize, isize),
+}
+
+impl Saver {
+    fn new() -> Self {
+        let moving = config::get(STYLE_KEY).and_then(|value| value.try_as_string().map(|style| style == "text"))
+            .unwrap_or(true);
+        Saver { moving, column: 0, row: 0, velocity: (1, 1) }
+    }
+
+    fn text_rect(&self) -> Rect {
+        Rect::new(self.column, self.row, SAVER_TEXT.len(), 1)
+    }
+
+    /// Draw the whole saver
+    fn draw(&self) {
+        blank(Rect::new(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT));
+        if self.moving {
+            compositor::write_at(self.row, self.column, SAVER_TEXT, Color::LightCyan, Color::Black);
+        }
+        compositor::present();
+    }
+
+    /// Move the text on, redrawing only where it was and where it goes
+    fn step(&mut self) {
+        if !self.moving {
+            return;
+        }
+        blank(self.text_rect());
+        (self.column, self.velocity.0) = bounce(self.column, self.velocity.0, SCREEN_WIDTH - SAVER_TEXT.len());
+        (self.row, self.velocity.1) = bounce(self.row, self.velocity.1, SCREEN_HEIGHT - 1);
+        compositor::damage(self.text_rect());
+        compositor::write_at(self.row, self.column, SAVER_TEXT, Color::LightCyan, Color::Black);
+        compositor::present();
+    }
+}
+
+/// What a key did to the lock dialog
+enum Outcome {
+    Typing,
+    Unlocked,
+    /// Escape on an empty box: back to the saver
+    Dismissed,
+}
+
+/// The password prompt shown when a locked saver is woken
+struct LockDialog {
+    user: String,
+    password: TextBox,
+    wrong: bool,
+}
+
+impl LockDialog {
+    fn new(user: &str) -> Self {
+        let mut password = TextBox::new(PASSWORD_COLUMN, PASSWORD_ROW, DIALOG.width - PASSWORD_COLUMN - 2);
+        password.set_masked(true);
+        LockDialog { user: String::from(user), password, wrong: false }
+    }
+
+    fn handle_key(&mut self, event: &KeyEvent) -> Outcome {
+        if event.state != KeyState::Pressed {
+            return Outcome::Typing;
+        }
+        match event.code {
+            KeyCode::Enter => {
+                if password::verify(&self.user, self.password.text()) {
+                    return Outcome::Unlocked;
+                }
+                serial_println!("SCREENSAVER: Wrong password for {}", self.user);
+                self.wrong = true;
+                self.password.set_text("");
+            }
+            KeyCode::Escape if self.password.text().is_empty() => return Outcome::Dismissed,
+            KeyCode::Escape => self.password.set_text(""),
+            _ => {
+                if let Err(e) = self.password.handle_key(event) {
+                    serial_println!("SCREENSAVER: Password box: {:?}", e);
+                }
+            }
+        }
+        Outcome::Typing
+    }
+
+    fn draw(&self) {
+        compositor::damage(DIALOG);
+        let (fg, bg) = (Color::Black, Color::LightGray);
+        let inner = DIALOG.width - 2;
+        let title = format!("{:─^width$}", format!(" {} ", tr!(MSG_LOCKED_TITLE)), width = inner);
+        compositor::write_at(DIALOG.y, DIALOG.x, &format!("┌{}┐", title), Color::White, Color::Blue);
+        let lines = [
+            String::new(),
+            format!(" {}", tr!(MSG_SESSION_LOCKED, self.user)),
+            String::new(),
+            format!(" {}", tr!(MSG_PASSWORD)),
+            String::new(),
+        ];
+        for (offset, line) in lines.iter().enumerate() {
+            let line: String = line.chars().take(inner).collect();
+            compositor::write_at(DIALOG.y + 1 + offset, DIALOG.x, &format!("│{:<width$}│", line, width = inner), fg, bg);
+        }
+        compositor::write_at(DIALOG.y + DIALOG.height - 1, DIALOG.x, &format!("└{:─<width$}┘", "", width = inner), fg, bg);
+        if self.wrong {
+            compositor::write_at(DIALOG.y + 5, DIALOG.x + 2, tr!(MSG_WRONG_PASSWORD), Color::Red, bg);
+        }
+        // The box's row counts from the row under the top border
+        self.password.draw(DIALOG.x, DIALOG.y + 1, true);
+        compositor::present();
+    }
+}
+
+/// The whole screen, to put back afterwards
+fn save_screen() -> Vec<ScreenChar> {
+    (0..SCREEN_HEIGHT)
+        .flat_map(|row| (0..SCREEN_WIDTH).map(move |column| (row, column)))
+        .filter_map(|(row, column)| vga_enhanced::read_cell(row, column))
+        .collect()
+}
+
+fn restore_screen(cells: &[ScreenChar]) {
+    for (index, cell) in cells.iter().enumerate() {
+        vga_enhanced::restore_cell(index / SCREEN_WIDTH, index % SCREEN_WIDTH, *cell);
+    }
+}
+
+/// Show the saver until input wakes it, or with `lock` and a password for
+/// the current user, until the password is typed; then put the screen back.
+/// Takes the input that wakes it. `background` runs on every pass, for work
+/// such as network polling that must not stop meanwhile.
+pub fn run(lock: bool, background: &mut dyn FnMut()) {
+    let user = user::current_username().filter(|name| lock && password::has_password(name));
+    serial_println!("SCREENSAVER: Starting{}", if user.is_some() { ", locked" } else { "" });
+
+    let saved = save_screen();
+    let mut saver = Saver::new();
+    saver.draw();
+    let mut dialog: Option<LockDialog> = None;
+    let mut last_step_ms = pit::uptime_ms();
+
+    let mut loop_count: u64 = 0;
+    loop {
+        background();
+        let key = ps2_keyboard::get_event();
+        let mouse = ps2_mouse::get_event();
+
+        match (dialog.as_mut(), user.as_deref()) {
+            (None, None) if key.is_some() || mouse.is_some() => break,
+            (None, Some(name)) if key.is_some() || mouse.is_some() => {
+                let opened = LockDialog::new(name);
+                opened.draw();
+                dialog = Some(opened);
+            }
+            (Some(open), _) => {
+                let outcome = key.map_or(Outcome::Typing, |event| open.handle_key(&event));
+                match outcome {
+                    Outcome::Unlocked => break,
+                    Outcome::Dismissed => dialog = None,
+                    Outcome::Typing if input::idle_ms() >= DIALOG_TIMEOUT_MS => dialog = None,
+                    Outcome::Typing if key.is_some() => open.draw(),
+                    Outcome::Typing => {}
+                }
+                if dialog.is_none() {
+                    saver.draw();
+                }
+            }
+            _ => {}
+        }
+
+        let now = pit::uptime_ms();
+        if dialog.is_none() && now.saturating_sub(last_step_ms) >= STEP_MS {
+            saver.step();
+            last_step_ms = now;
+        }
+
+        if loop_count % 1000 == 0 {
+            crate::task::idle::idle_once();
+        }
+        loop_count += 1;
+    }
+
+    restore_screen(&saved);
+    serial_println!("SCREENSAVER: Stopped");
+}
+
+/// Check the moving text stays on screen and turns at the edges
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SCREENSAVER: Running self-test");
+
+    if bounce(3, 1, 10) != (4, 1) || bounce(10, 1, 10) != (9, -1) || bounce(0, -1, 10) != (1, 1) {
+        return Err(KernelError::ValidationError("Saver text bounced wrongly"));
+    }
+    if bounce(0, 1, 0) != (0, 1) {
+        return Err(KernelError::ValidationError("Saver text moved with no room"));
+    }
+
+    let (mut position, mut velocity) = (0, 1);
+    for _ in 0..100 {
+        (position, velocity) = bounce(position, velocity, SCREEN_HEIGHT - 1);
+        if position > SCREEN_HEIGHT - 1 {
+            return Err(KernelError::ValidationError("Saver text left the screen"));
+        }
+    }
+
+    serial_println!("SCREENSAVER: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Screenshots of the text screen, for bug reports
+//!
+//! A screenshot is every cell of the 80x25 screen, read in one go with the
+//! mouse cursor lifted off, so it's never half a frame. It's saved as two
+//! files: `<name>.txt` with the characters (as Unicode, trailing spaces
+//! trimmed) and `<name>.vga` with the raw cells as VGA text memory holds
+//! them, a character byte then a color attribute byte, row by row, for a
+//! viewer to draw again.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::drivers::{cp437, vga_enhanced};
+use crate::errors::KernelError;
+use crate::fs;
+use crate::gui::compositor::{SCREEN_CELLS, SCREEN_WIDTH};
+use crate::gui::cursor;
+use crate::serial_println;
+
+/// Numbered screenshots tried before giving up on finding a free name
+const MAX_NUMBER: usize = 9999;
+
+/// The screen at one moment
+pub struct Screenshot {
+    /// Character and attribute bytes of each cell, row by row
+    cells: Vec<[u8; 2]>,
+}
+
+impl Screenshot {
+    /// The characters, one line per row with trailing spaces trimmed
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(SCREEN_CELLS + self.cells.len() / SCREEN_WIDTH);
+        for row in self.cells.chunks(SCREEN_WIDTH) {
+            let line: String = row.iter().map(|cell| cp437::decode(cell[0])).collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// The cells as VGA text memory holds them
+    pub fn raw(&self) -> Vec<u8> {
+        self.cells.iter().flatten().copied().collect()
+    }
+
+    /// Write `<base>.txt` and `<base>.vga`; returns their paths
+    pub fn save(&self, base: &str) -> Result<(String, String), KernelError> {
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        let (text_path, raw_path) = (format!("{}.txt", base), format!("{}.vga", base));
+        vfs.write_file_atomic(&text_path, self.text().as_bytes())?;
+        vfs.write_file_atomic(&raw_path, &self.raw())?;
+        Ok((text_path, raw_path))
+    }
+}
+
+/// Take a screenshot
+pub fn capture() -> Screenshot {
+    cursor::suspend();
+    let cells = vga_enhanced::snapshot();
+    cursor::resume();
+    Screenshot { cells }
+}
+
+/// `path` without a ".txt" or ".vga" ending, so either names the pair
+pub fn base_path(path: &str) -> &str {
+    path.strip_suffix(".txt").or_else(|| path.strip_suffix(".vga")).unwrap_or(path)
+}
+
+/// The first free `<dir>/screenshot-<n>`, creating `dir` if need be
+pub fn next_base(dir: &str) -> Result<String, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    match vfs.create_directory(dir) {
+        Ok(()) | Err(KernelError::AlreadyExists) => {}
+        Err(e) => return Err(e),
+    }
+    (1..=MAX_NUMBER)
+        .map(|n| format!("{}/screenshot-{}", dir, n))
+        .find(|base| vfs.metadata(&format!("{}.txt", base)).is_err() && vfs.metadata(&format!("{}.vga", base)).is_err())
+        .ok_or(KernelError::AlreadyExists)
+}
+
+/// Where screenshots go when no path is given: Pictures in the current
+/// user's home
+pub fn default_dir() -> String {
+    fs::walk::join(&crate::user::home_dir(), "Pictures")
+}
+
+/// Take a screenshot and save it under the next free name in
+/// `default_dir`; returns the paths written
+pub fn take() -> Result<(String, String), KernelError> {
+    let shot = capture();
+    shot.save(&next_base(&default_dir())?)
+}
+
+/// Turn made-up cells into text and raw bytes, and check file naming
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SCREENSHOT: Running self-test");
+
+    let mut cells = alloc::vec![[b' ', 0x07]; SCREEN_CELLS];
+    for (i, byte) in b"Hi".iter().enumerate() {
+        cells[i] = [*byte, 0x1F];
+    }
+    cells[SCREEN_WIDTH] = [0xC9, 0x17];
+    cells[SCREEN_WIDTH + 1] = [0x00, 0x07];
+    cells[SCREEN_WIDTH + 2] = [b'x', 0x07];
+    let shot = Screenshot { cells };
+    let text = shot.text();
+    let raw = shot.raw();
+    if !text.starts_with("Hi\n\u{2554} x\n\n") || text.lines().count() != SCREEN_CELLS / SCREEN_WIDTH
+        || raw.len() != SCREEN_CELLS * 2 || raw[..4] != [b'H', 0x1F, b'i', 0x1F] {
+        serial_println!("SCREENSHOT: Text came out as {:?}", text.lines().take(3).collect::<Vec<_>>());
+        return Err(KernelError::ValidationError("Screenshot converted wrongly"));
+    }
+
+    if base_path("/tmp/a.txt") != "/tmp/a" || base_path("/tmp/a.vga") != "/tmp/a" || base_path("/tmp/a") != "/tmp/a" {
+        return Err(KernelError::ValidationError("Screenshot paths named wrongly"));
+    }
+    if fs::vfs::get_vfs_manager().is_some() {
+        let dir = "/tmp/screenshot-selftest";
+        let result = (|| {
+            let (text_path, _) = shot.save(&next_base(dir)?)?;
+            if text_path != "/tmp/screenshot-selftest/screenshot-1.txt" || next_base(dir)? != format!("{}/screenshot-2", dir) {
+                return Err(KernelError::ValidationError("Screenshots numbered wrongly"));
+            }
+            Ok(())
+        })();
+        if let Some(vfs) = fs::vfs::get_vfs_manager() {
+            let _ = vfs.remove(&format!("{}/screenshot-1.txt", dir));
+            let _ = vfs.remove(&format!("{}/screenshot-1.vga", dir));
+            let _ = vfs.remove(dir);
+        }
+        result?;
+    }
+
+    serial_println!("SCREENSHOT: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! Desktop session: which app windows were open, and where
+//!
+//! The open windows' apps, positions, sizes and saved state go to
+//! SESSION_FILE, in the config file format, when the GUI exits and every
+//! SAVE_INTERVAL_MS while it runs (if anything changed). At boot
+//! `restore` reopens them through the desktop's registered apps, bottom
+//! window first, skipping apps that are no longer registered, and
+//! minimizes those that were minimized. The
+//! `gui.restore_session` setting turns this off.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::config::{self, ConfigValue};
+use crate::errors::KernelError;
+use crate::fs;
+use crate::gui::compositor::Rect;
+use crate::gui::desktop;
+use crate::serial_println;
+
+pub const SESSION_FILE: &str = "/Library/Preferences/session.ini";
+
+/// Time between saves while the GUI runs (milliseconds)
+const SAVE_INTERVAL_MS: u64 = 60_000;
+
+/// Windows read back from a session file, at most
+const MAX_WINDOWS: usize = 32;
+
+/// Largest session file read
+const MAX_FILE_SIZE: usize = 16 * 1024;
+
+/// Uptime of the last periodic save
+static LAST_SAVE_MS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// What was last written, so unchanged sessions aren't written again
+    static ref LAST_SAVED: Mutex<String> = Mutex::new(String::new());
+}
+
+/// One window to reopen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedWindow {
+    /// Name of the app, as registered with the desktop
+    pub app: String,
+    pub bounds: Rect,
+    /// Handed to the app's restore hook
+    pub state: Option<String>,
+    pub minimized: bool,
+}
+
+/// Whether `gui.restore_session` allows saving and restoring; on unless
+/// set to false
+pub fn enabled() -> bool {
+    config::get("gui.restore_session").and_then(|value| value.try_as_boolean()).unwrap_or(true)
+}
+
+/// The desktop's app windows, bottom first
+pub fn capture() -> Vec<SavedWindow> {
+    let desktop = desktop::DESKTOP.lock();
+    desktop.get_windows().iter().filter_map(|window| {
+        let window = window.lock();
+        Some(SavedWindow {
+            app: window.app()?.to_string(),
+            bounds: window.bounds(),
+            state: window.session_state().map(|state| state.to_string()),
+            minimized: window.is_minimized(),
+        })
+    }).collect()
+}
+
+/// Write `windows` in the config file format
+pub fn to_ini(windows: &[SavedWindow]) -> String {
+    let mut entries = vec![(String::from("windows"), ConfigValue::integer(windows.len() as i64))];
+    for (i, window) in windows.iter().enumerate() {
+        let key = |field: &str| format!("window.{}.{}", i + 1, field);
+        entries.push((key("app"), ConfigValue::string(&window.app)));
+        entries.push((key("x"), ConfigValue::integer(window.bounds.x as i64)));
+        entries.push((key("y"), ConfigValue::integer(window.bounds.y as i64)));
+        entries.push((key("width"), ConfigValue::integer(window.bounds.width as i64)));
+        entries.push((key("height"), ConfigValue::integer(window.bounds.height as i64)));
+        if let Some(ref state) = window.state {
+            entries.push((key("state"), ConfigValue::string(state)));
+        }
+        if window.minimized {
+            entries.push((key("minimized"), ConfigValue::boolean(true)));
+        }
+    }
+    config::format_entries("# UniverseK OS desktop session\n# Written by the GUI; 'gui reset-session' deletes it\n",
+        entries.iter().map(|(key, value)| (key.as_str(), value)))
+}
+
+/// Read windows back from `to_ini` output, skipping any with a missing
+/// or malformed field
+pub fn from_ini(content: &str) -> Vec<SavedWindow> {
+    let entries = config::parse_entries(content);
+    let get = |key: &str| entries.iter().find(|(name, _)| name == key).map(|(_, value)| value);
+    let count = get("windows").and_then(|value| value.try_as_integer()).unwrap_or(0).clamp(0, MAX_WINDOWS as i64);
+
+    let mut windows = Vec::new();
+    for i in 1..=count {
+        let number = |field: &str| get(&format!("window.{}.{}", i, field))
+            .and_then(|value| value.try_as_integer())
+            .and_then(|value| usize::try_from(value).ok());
+        let app = match get(&format!("window.{}.app", i)) {
+            Some(app) => app.as_string(),
+            None => continue,
+        };
+        let (x, y, width, height) = match (number("x"), number("y"), number("width"), number("height")) {
+            (Some(x), Some(y), Some(width), Some(height)) => (x, y, width, height),
+            _ => continue,
+        };
+        let state = get(&format!("window.{}.state", i)).map(|value| value.as_string());
+        let minimized = get(&format!("window.{}.minimized", i)).and_then(|value| value.try_as_boolean()).unwrap_or(false);
+        windows.push(SavedWindow { app, bounds: Rect::new(x, y, width, height), state, minimized });
+    }
+    windows
+}
+
+/// Write the current session to `path`, creating its directory if needed
+pub fn save_to(path: &str) -> Result<(), KernelError> {
+    write_file(path, &to_ini(&capture()))
+}
+
+fn write_file(path: &str, content: &str) -> Result<(), KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    // The directories may be there already
+    if let Some((dir, _)) = path.rsplit_once('/') {
+        let mut partial = String::new();
+        for part in dir.split('/').filter(|part| !part.is_empty()) {
+            partial.push('/');
+            partial.push_str(part);
+            let _ = vfs.create_directory(&partial);
+        }
+    }
+    vfs.write_file_atomic(path, content.as_bytes())
+}
+
+/// Save the session to SESSION_FILE, if enabled
+pub fn save() -> Result<(), KernelError> {
+    if !enabled() {
+        return Ok(());
+    }
+    let content = to_ini(&capture());
+    write_file(SESSION_FILE, &content)?;
+    *LAST_SAVED.lock() = content;
+    Ok(())
+}
+
+/// Save every SAVE_INTERVAL_MS from the GUI loop, when the session has
+/// changed since the last save
+pub fn poll(now_ms: u64) {
+    if !enabled() || now_ms.saturating_sub(LAST_SAVE_MS.load(Ordering::Relaxed)) < SAVE_INTERVAL_MS {
+        return;
+    }
+    LAST_SAVE_MS.store(now_ms, Ordering::Relaxed);
+    if to_ini(&capture()) == *LAST_SAVED.lock() {
+        return;
+    }
+    if let Err(e) = save() {
+        serial_println!("DEBUG: session - can't save {}: {:?}", SESSION_FILE, e);
+    }
+}
+
+/// Read the windows saved in `path`; none if there is no file
+pub fn load_from(path: &str) -> Result<Vec<SavedWindow>, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let size = match vfs.metadata(path) {
+        Ok(metadata) => (metadata.size as usize).min(MAX_FILE_SIZE),
+        Err(KernelError::NotFound) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut data = vec![0u8; size];
+    let read = vfs.find_fs(path)?.lock().read_at(path, 0, &mut data)?;
+    let content = core::str::from_utf8(&data[..read]).map_err(|_| KernelError::InvalidData)?;
+    Ok(from_ini(content))
+}
+
+/// Reopen the windows of the last session, if enabled. Returns how many
+/// were reopened.
+pub fn restore() -> Result<usize, KernelError> {
+    if !enabled() {
+        return Ok(0);
+    }
+    let saved = load_from(SESSION_FILE)?;
+    let area = desktop::DESKTOP.lock().work_area();
+    let mut restored = 0;
+    for window in &saved {
+        match desktop::launch(&window.app, window.state.as_deref()) {
+            Ok(handle) => {
+                handle.lock().set_bounds(window.bounds, area);
+                if window.minimized {
+                    desktop::minimize(&handle);
+                }
+                restored += 1;
+            }
+            Err(e) => serial_println!("DEBUG: session - not reopening {}: {:?}", window.app, e),
+        }
+    }
+    *LAST_SAVED.lock() = to_ini(&capture());
+    Ok(restored)
+}
+
+/// Delete the saved session, so the next boot starts with a bare desktop
+pub fn reset() -> Result<(), KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    match vfs.remove(SESSION_FILE) {
+        Ok(()) | Err(KernelError::NotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check the file format round trip and that broken entries are skipped,
+/// then save and load through a scratch file in /tmp
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SESSION: Running self-test");
+
+    let windows = vec![
+        SavedWindow { app: String::from("Terminal"), bounds: Rect::new(4, 3, 50, 12), state: Some(String::from("/tmp")),
+            minimized: false },
+        SavedWindow { app: String::from("Files"), bounds: Rect::new(20, 6, 40, 10), state: None, minimized: true },
+    ];
+    if from_ini(&to_ini(&windows)) != windows {
+        return Err(KernelError::ValidationError("Session didn't survive the file format"));
+    }
+    let broken = "windows=3\nwindow.1.app=Monitor\nwindow.1.x=1\nwindow.2.app=About\nwindow.2.x=1\n\
+        window.2.y=2\nwindow.2.width=30\nwindow.2.height=8\n";
+    let read = from_ini(broken);
+    if read.len() != 1 || read[0].app != "About" || read[0].bounds != Rect::new(1, 2, 30, 8) {
+        return Err(KernelError::ValidationError("Incomplete session entries not skipped"));
+    }
+
+    if fs::vfs::get_vfs_manager().is_some() {
+        let path = "/tmp/session-selftest/session.ini";
+        save_to(path)?;
+        let loaded = load_from(path)?;
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        vfs.remove(path)?;
+        vfs.remove("/tmp/session-selftest")?;
+        if loaded != capture() || !load_from(path)?.is_empty() {
+            return Err(KernelError::ValidationError("Saved session read back wrongly"));
+        }
+    }
+
+    serial_println!("SESSION: Self-test passed");
+    Ok(())
+}
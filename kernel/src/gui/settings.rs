@@ -0,0 +1,428 @@
+//! Settings app: the configuration, a category at a time
+//!
+//! The list on the left picks a category; the one on the right shows its
+//! settings, or for Users and About what there is to know. Selecting a
+//! setting puts its value in the editor below, and Enter or Apply sets it
+//! through `config::set`, so it lands in the user layer while someone is
+//! logged in and whatever watches it follows at once. Save writes the
+//! settings out. A value the key's rule refuses is shown under the editor
+//! and changes nothing. Panels needing the file system stay read-only
+//! without one.
+
+use crate::config::{self, Layer};
+use crate::drivers::vga_enhanced::Color;
+use crate::errors::KernelError;
+use crate::gui::textbox::TextBox;
+use crate::gui::wallpaper::{self, ImageMode};
+use crate::gui::widget::{ListView, WidgetEvent};
+use crate::gui::window::{create_window, Window, WindowHandle, WINDOW_TEXT};
+use crate::{serial_println, user, version};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Where the panel's controls sit in the content area
+const CATEGORY_WIDTH: usize = 13;
+const PANEL_COLUMN: usize = 15;
+const PANEL_WIDTH: usize = 49;
+const PANEL_ROWS: usize = 7;
+const EDITOR_ROW: usize = 8;
+const EDITOR_WIDTH: usize = 28;
+/// Rows above the notes under the editor
+const NOTES_ROW: usize = 10;
+/// Width of the labels in the settings list
+const LABEL_WIDTH: usize = 21;
+
+/// Widget indexes, in the order `build` adds them
+const CATEGORY_LIST: usize = 0;
+const PANEL_LIST: usize = 1;
+const EDITOR: usize = 0;
+
+const APPLY: &str = "Apply";
+const SAVE: &str = "Save";
+const ADD_USER: &str = "Add user";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Appearance,
+    Input,
+    Filesystem,
+    Users,
+    About,
+}
+
+pub const CATEGORIES: [Category; 5] =
+    [Category::Appearance, Category::Input, Category::Filesystem, Category::Users, Category::About];
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Appearance => "Appearance",
+            Category::Input => "Input",
+            Category::Filesystem => "Filesystem",
+            Category::Users => "Users",
+            Category::About => "About",
+        }
+    }
+
+    /// The settings the category edits, as (key, label)
+    fn settings(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Category::Appearance => &[
+                ("ui.theme", "Theme"),
+                ("ui.color_scheme", "Color scheme"),
+                ("ui.wallpaper", "Wallpaper"),
+                ("ui.wallpaper_mode", "Wallpaper layout"),
+                ("ui.visual_bell", "Visual bell"),
+                ("ui.screensaver", "Screensaver"),
+                ("ui.screensaver_timeout", "Screensaver after (s)"),
+            ],
+            Category::Input => &[
+                ("input.repeat_delay_ms", "Repeat delay (ms)"),
+                ("input.repeat_rate_cps", "Repeat rate (/s)"),
+                ("input.mouse_sensitivity", "Mouse speed (%)"),
+                ("input.mouse_accel", "Mouse acceleration"),
+            ],
+            Category::Filesystem => &[
+                ("fs.use_trash", "Use the trash"),
+                ("fs.automount", "Mount disks at boot"),
+                ("fs.check_on_mount", "Check on mount"),
+                ("fs.readahead_blocks", "Readahead (blocks)"),
+            ],
+            Category::Users | Category::About => &[],
+        }
+    }
+}
+
+/// What the Settings window shows and edits, apart from the window
+pub struct Settings {
+    category: Category,
+    /// Lines of the panel's list, each with the setting behind it if any
+    items: Vec<(String, Option<(&'static str, &'static str)>)>,
+    selected: Option<usize>,
+    /// What the last change did, and whether it failed
+    status: Option<(String, bool)>,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        let mut settings = Self { category: Category::Appearance, items: Vec::new(), selected: None, status: None };
+        settings.refresh();
+        settings
+    }
+
+    pub fn category(&self) -> Category {
+        self.category
+    }
+
+    /// Switch to `category`, forgetting the selection and last change
+    pub fn show(&mut self, category: Category) {
+        self.category = category;
+        self.selected = None;
+        self.status = None;
+        self.refresh();
+    }
+
+    /// Read the panel's lines afresh
+    fn refresh(&mut self) {
+        self.items = match self.category {
+            Category::Users => {
+                let current = user::current_username();
+                user::list_users().into_iter().map(|account| {
+                    let marker = if current.as_deref() == Some(account.username.as_str()) { " (you)" } else { "" };
+                    (format!("{:<12} {:>5}  {}{}", account.username, account.uid, account.home_dir, marker), None)
+                }).collect()
+            }
+            Category::About => [
+                ("System", version::name_and_version()),
+                ("Kernel", format!("{} {}", version::SYSNAME, version::VERSION)),
+                ("Commit", String::from(version::GIT_HASH)),
+                ("Built", format!("{} UTC", version::BUILD_TIME)),
+                ("Compiler", String::from(version::RUSTC_VERSION)),
+                ("Machine", String::from(version::MACHINE)),
+            ].into_iter().map(|(label, value)| (format!("{:<LABEL_WIDTH$} {}", label, value), None)).collect(),
+            category => {
+                let mut items: Vec<_> = category.settings().iter()
+                    .map(|&(key, label)| (format!("{:<LABEL_WIDTH$} {}", label, value_text(key)), Some((key, label))))
+                    .collect();
+                if category == Category::Input {
+                    items.push((format!("{:<LABEL_WIDTH$} us (the only one built in)", "Keyboard layout"), None));
+                }
+                items
+            }
+        };
+        self.selected = self.selected.filter(|&index| index < self.items.len());
+    }
+
+    /// The panel's lines
+    pub fn items(&self) -> Vec<String> {
+        self.items.iter().map(|(text, _)| text.clone()).collect()
+    }
+
+    /// Select line `index`; returns the value to put in the editor if it's
+    /// a setting
+    pub fn select(&mut self, index: usize) -> Option<String> {
+        self.selected = (index < self.items.len()).then_some(index);
+        let (key, _) = self.selected_setting()?;
+        Some(config::get(key).map_or(String::new(), |value| value.as_string()))
+    }
+
+    /// The selected setting, as (key, label)
+    pub fn selected_setting(&self) -> Option<(&'static str, &'static str)> {
+        self.items.get(self.selected?)?.1
+    }
+
+    /// Whether the panel has an editor: settings panels do, Users does
+    /// while there's a file system for new homes, About doesn't
+    pub fn editable(&self) -> bool {
+        match self.category {
+            Category::Users => crate::fs::vfs::get_vfs_manager().is_some(),
+            Category::About => false,
+            _ => true,
+        }
+    }
+
+    /// What the last change did, and whether it failed
+    pub fn status(&self) -> Option<(&str, bool)> {
+        self.status.as_ref().map(|(text, failed)| (text.as_str(), *failed))
+    }
+
+    fn fail(&mut self, message: String) {
+        self.status = Some((message, true));
+    }
+
+    /// Act on the editor's `text`: set the selected setting to it, or on
+    /// the Users panel add a user by that name
+    pub fn apply(&mut self, text: &str) {
+        let text = text.trim();
+        if self.category == Category::Users {
+            return self.add_user(text);
+        }
+        let Some((key, label)) = self.selected_setting() else {
+            return self.fail(String::from("Select a setting to change first"));
+        };
+        let value = config::parse_value(text);
+        if let Some(rule) = config::rule(key).filter(|rule| !rule.allows(&value)) {
+            return self.fail(format!("{} must be {}", label, rule.describe()));
+        }
+        // The desktop would keep its old background, so say why here
+        if key == "ui.wallpaper" {
+            if let Err(e) = wallpaper::load(text, ImageMode::Tile) {
+                return self.fail(format!("Can't use '{}' as a wallpaper: {}", text, e));
+            }
+        }
+        config::set(key, value);
+        let layer = if config::default_layer() == Layer::User { "your preferences" } else { "the system settings" };
+        self.status = Some((format!("{} changed in {}; Save keeps it", label, layer), false));
+        self.refresh();
+    }
+
+    fn add_user(&mut self, name: &str) {
+        if !self.editable() {
+            return self.fail(String::from("No file system for a home directory; users are read-only"));
+        }
+        if !user::valid_username(name) {
+            return self.fail(String::from("A user name is a lowercase letter, then letters, digits, - or _"));
+        }
+        match user::create_user(name, name) {
+            Ok(()) => self.status = Some((format!("Added {}, home /Users/{}", name, name), false)),
+            Err(KernelError::AlreadyExists) => self.fail(format!("{} already exists", name)),
+            Err(e) => self.fail(format!("Couldn't add {}: {}", name, e)),
+        }
+        self.refresh();
+    }
+
+    /// Write the settings out
+    pub fn save(&mut self) {
+        self.status = Some(match config::save() {
+            Ok(()) => (String::from("Settings saved"), false),
+            Err(e) => (format!("Couldn't save the settings: {}", e), true),
+        });
+    }
+
+    /// Text under the editor: how to use the panel, what it shows beyond
+    /// the list, and how the last change went
+    pub fn notes(&self) -> Vec<(String, Color)> {
+        let mut notes = Vec::new();
+        let mut note = |text: String| notes.push((text, WINDOW_TEXT));
+        match self.category {
+            Category::Appearance | Category::Input =>
+                note(String::from("Select a setting, type a value and press Enter or Apply.\n")),
+            Category::Filesystem => match crate::fs::vfs::get_vfs_manager() {
+                Some(vfs) => {
+                    for (path, name, _, total, available) in vfs.list_mounts() {
+                        let used = total.saturating_sub(available);
+                        let percent = if total == 0 { 0 } else { used * 100 / total };
+                        note(format!("{:<10} {:>3}% of {:>6} KiB used  {}\n", name, percent, total / 1024, path));
+                    }
+                }
+                None => note(String::from("File system not initialized.\n")),
+            },
+            Category::Users if self.editable() => note(String::from("Type a name and press Enter or Add user.\n")),
+            Category::Users => note(String::from("No file system, so no users can be added.\n")),
+            Category::About => note(String::from("A simple operating system for learning.\n")),
+        }
+        if let Some((text, failed)) = &self.status {
+            notes.push((format!("{}\n", text), if *failed { Color::Red } else { Color::Green }));
+        }
+        notes
+    }
+}
+
+/// A setting's value for the list
+fn value_text(key: &str) -> String {
+    config::get(key).map_or(String::from("(not set)"), |value| value.as_string())
+}
+
+/// Lay out the controls for the current category, with the category list
+/// focused
+fn build(window: &mut Window, settings: &Settings) {
+    window.clear_widgets();
+    let mut categories = ListView::new(0, 0, CATEGORY_WIDTH, CATEGORIES.len());
+    categories.set_items(CATEGORIES.iter().map(|category| category.name().to_string()).collect());
+    categories.select(CATEGORIES.iter().position(|&category| category == settings.category()));
+    window.add_list_view(categories);
+    window.add_list_view(ListView::new(PANEL_COLUMN, 0, PANEL_WIDTH, PANEL_ROWS));
+
+    if settings.editable() {
+        window.add_text_box(TextBox::new(PANEL_COLUMN, EDITOR_ROW, EDITOR_WIDTH));
+        let action_column = PANEL_COLUMN + EDITOR_WIDTH + 1;
+        if settings.category() == Category::Users {
+            window.add_button(ADD_USER, action_column, EDITOR_ROW);
+        } else {
+            window.add_button(APPLY, action_column, EDITOR_ROW);
+            window.add_button(SAVE, action_column + APPLY.len() + 3, EDITOR_ROW);
+        }
+    }
+    window.focus_list_view(CATEGORY_LIST);
+    render(window, settings);
+}
+
+/// Show the panel's lines and notes
+fn render(window: &mut Window, settings: &Settings) {
+    if let Some(panel) = window.list_view_mut(PANEL_LIST) {
+        panel.set_items(settings.items());
+    }
+    window.clear();
+    for (text, color) in settings.notes() {
+        window.add_colored_text(&text, color);
+    }
+}
+
+/// Create a settings window
+pub fn create_app() -> Result<WindowHandle, KernelError> {
+    serial_println!("DEBUG: Creating settings app window");
+
+    let window_handle = create_window("System Settings", 7, 2, 66, 20);
+    {
+        let mut window = window_handle.lock();
+        window.set_content_inset(PANEL_COLUMN, NOTES_ROW);
+        let settings = Mutex::new(Settings::new());
+        build(&mut window, &settings.lock());
+
+        window.set_widget_callback(Box::new(move |window, event| {
+            let mut settings = settings.lock();
+            match event {
+                WidgetEvent::Select(CATEGORY_LIST, index) => {
+                    if let Some(&category) = CATEGORIES.get(index) {
+                        if category != settings.category() {
+                            settings.show(category);
+                            build(window, &settings);
+                        }
+                    }
+                    return Ok(());
+                }
+                WidgetEvent::Select(PANEL_LIST, index) => {
+                    if let (Some(value), Some(editor)) = (settings.select(index), window.text_box_mut(EDITOR)) {
+                        editor.set_text(&value);
+                    }
+                }
+                WidgetEvent::Submit(EDITOR) | WidgetEvent::Button(APPLY) | WidgetEvent::Button(ADD_USER) => {
+                    let text = window.text_box(EDITOR).map_or(String::new(), |editor| editor.text().to_string());
+                    settings.apply(&text);
+                    let added = settings.category() == Category::Users && settings.status().is_some_and(|(_, failed)| !failed);
+                    if let (true, Some(editor)) = (added, window.text_box_mut(EDITOR)) {
+                        editor.set_text("");
+                    }
+                }
+                WidgetEvent::Button(SAVE) => settings.save(),
+                _ => return Ok(()),
+            }
+            render(window, &settings);
+            Ok(())
+        }));
+    }
+
+    Ok(window_handle)
+}
+
+/// Check the list view and the panels: each category's lines, values the
+/// schema refuses shown as errors without changing anything, and user
+/// names checked before anything is made
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SETTINGS: Running self-test");
+
+    let mut list = ListView::new(0, 2, 10, 3);
+    list.set_items((0..6).map(|i| format!("item {}", i)).collect());
+    if list.move_selection(-1) != Some(0) || list.move_selection(10) != Some(5)
+        || list.item_at(2) != Some(3) || list.item_at(5) != None || list.item_at(1) != None {
+        return Err(KernelError::ValidationError("List view selected or scrolled wrongly"));
+    }
+    list.set_items(vec![String::from("only")]);
+    if list.selected().is_some() || list.item_at(2) != Some(0) || list.contains(10, 2) || !list.contains(9, 4) {
+        return Err(KernelError::ValidationError("List view kept a selection past its items"));
+    }
+
+    let mut settings = Settings::new();
+    for category in CATEGORIES {
+        settings.show(category);
+        let settings_lines = settings.items.iter().filter(|(_, setting)| setting.is_some()).count();
+        let editable = category == Category::Users || (category != Category::About) == settings.editable();
+        if settings_lines != category.settings().len() || !editable {
+            return Err(KernelError::ValidationError("Settings panel shows the wrong lines"));
+        }
+    }
+    if !settings.items().iter().any(|line| line.contains(version::VERSION)) {
+        return Err(KernelError::ValidationError("About panel left out the version"));
+    }
+
+    settings.show(Category::Input);
+    settings.apply("100");
+    if !settings.status().is_some_and(|(_, failed)| failed) {
+        return Err(KernelError::ValidationError("Applied a value with no setting selected"));
+    }
+    let accel = settings.items.iter().position(|(_, setting)| setting.is_some_and(|(key, _)| key == "input.mouse_accel"))
+        .ok_or(KernelError::ValidationError("Input panel has no mouse acceleration"))?;
+    let before = settings.select(accel).unwrap_or_default();
+    for refused in ["500", "fast", "-1"] {
+        settings.apply(refused);
+        let shown = settings.notes().iter().any(|(text, color)| *color == Color::Red && text.contains("must be"));
+        if !shown || value_text("input.mouse_accel") != before {
+            return Err(KernelError::ValidationError("Refused value changed a setting or wasn't shown"));
+        }
+    }
+    if !before.is_empty() {
+        settings.apply(&before);
+        if settings.status().map_or(true, |(_, failed)| failed) || value_text("input.mouse_accel") != before {
+            return Err(KernelError::ValidationError("Setting a valid value failed"));
+        }
+    }
+
+    settings.show(Category::Users);
+    let users = settings.items().len();
+    for name in ["", "Root", "9lives", "has space", "a-very-long-name-that-goes-past-32"] {
+        settings.apply(name);
+        if !settings.status().is_some_and(|(_, failed)| failed) || settings.items().len() != users {
+            return Err(KernelError::ValidationError("Bad user name accepted"));
+        }
+    }
+    if !user::valid_username("new_user-2") {
+        return Err(KernelError::ValidationError("Good user name refused"));
+    }
+
+    serial_println!("SETTINGS: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! System monitor app for UniverseK OS GUI
+//! Shows uptime, heap usage, tasks and interrupt counts, refreshed once a
+//! second from the GUI frame loop
+
+use crate::errors::KernelError;
+use crate::gui::window::{create_window, Window, WindowHandle};
+use crate::serial_println;
+use alloc::format;
+use alloc::string::String;
+
+/// Time between refreshes (milliseconds)
+const REFRESH_INTERVAL_MS: u64 = 1000;
+/// Tasks listed before the rest are summarized
+const MAX_TASK_LINES: usize = 5;
+/// Width available for the interrupt list
+const LINE_WIDTH: usize = 40;
+
+/// Build the monitor text from the same sources as ps, free and irqstat
+fn render() -> String {
+    use crate::task::TaskState;
+
+    let mut text = String::new();
+
+    let secs = crate::drivers::pit::uptime_ms() / 1000;
+    text.push_str(&format!("Uptime  {:02}:{:02}:{:02}  load {}%\n", secs / 3600, (secs / 60) % 60, secs % 60,
+        crate::task::idle::load_percent()));
+
+    let heap = crate::allocator::heap_stats();
+    let percent = if heap.size > 0 { heap.used * 100 / heap.size } else { 0 };
+    text.push_str(&format!("Heap    {} / {} KiB used ({}%)\n", heap.used / 1024, heap.size / 1024, percent));
+
+    let frames = super::compositor::frame_stats();
+    text.push_str(&format!("Redraw  {} cells last frame, {} avg (full {})\n\n",
+        frames.last_frame_cells, frames.average_cells(), super::compositor::SCREEN_CELLS));
+
+    let tasks = crate::task::scheduler::task_list();
+    text.push_str("  PID  STATE       TICKS\n");
+    for task in tasks.iter().take(MAX_TASK_LINES) {
+        let state = match task.state {
+            TaskState::Runnable => "runnable",
+            TaskState::Running => "running",
+            TaskState::Blocked => "blocked",
+            TaskState::Terminated => "terminated",
+            TaskState::Zombie => "zombie",
+        };
+        text.push_str(&format!("{:>5}  {:<10} {:>6}\n", task.id, state, task.cpu_ticks));
+    }
+    if tasks.len() > MAX_TASK_LINES {
+        text.push_str(&format!("  ... {} more\n", tasks.len() - MAX_TASK_LINES));
+    }
+
+    text.push_str("\nInterrupts:\n");
+    let mut line = String::new();
+    for (vector, count) in crate::interrupts::interrupt_counts() {
+        let item = match crate::interrupts::vector_name(vector) {
+            Some(name) => format!("{} {}", name, count),
+            None => format!("{:#04x} {}", vector, count),
+        };
+        if !line.is_empty() && line.len() + 2 + item.len() > LINE_WIDTH {
+            text.push_str(&format!(" {}\n", line));
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push_str("  ");
+        }
+        line.push_str(&item);
+    }
+    if line.is_empty() {
+        line.push_str("(none yet)");
+    }
+    text.push_str(&format!(" {}\n", line));
+    text
+}
+
+/// Frame hook: redraw the statistics
+fn refresh(window: &mut Window) {
+    window.set_text(&render());
+}
+
+/// Create a system monitor window
+pub fn create_app() -> Result<WindowHandle, KernelError> {
+    serial_println!("DEBUG: Creating system monitor app window");
+
+    let window_handle = create_window("System Monitor", 2, 2, 44, 18);
+    refresh(&mut window_handle.lock());
+
+    // Held weakly, so closing the window also ends the refreshes
+    super::add_frame_hook(&window_handle, REFRESH_INTERVAL_MS, refresh);
+    Ok(window_handle)
+}
+
+/// Check that closing the monitor removes its refresh hook
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SYSMON: Running self-test");
+
+    let before = super::frame_hook_count();
+    let window = create_app()?;
+    if super::frame_hook_count() != before + 1 {
+        return Err(KernelError::ValidationError("System monitor did not register its refresh"));
+    }
+    if window.lock().close_requested() {
+        return Err(KernelError::ValidationError("System monitor window closed itself"));
+    }
+
+    drop(window);
+    super::run_frame_hooks(crate::drivers::pit::uptime_ms());
+    if super::frame_hook_count() != before {
+        return Err(KernelError::ValidationError("Closed system monitor left its refresh registered"));
+    }
+
+    serial_println!("SYSMON: Self-test passed");
+    Ok(())
+}
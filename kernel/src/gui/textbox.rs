@@ -0,0 +1,449 @@
+//! Single-line text boxes, for prompts such as file names and settings
+//!
+//! A text box edits one line of ASCII text that may be wider than the box:
+//! it scrolls sideways to keep the cursor in view. Shift with the arrows,
+//! Home or End selects, shown inverted; Ctrl+A/C/X/V select all, copy, cut
+//! and paste through the clipboard; a double-click selects a word. A
+//! validator can refuse edits, such as characters a file name can't have,
+//! and callbacks hear about each change and about Enter.
+
+use crate::drivers::ps2_keyboard::{KeyCode, KeyEvent, KeyState};
+use crate::drivers::vga_enhanced::Color;
+use crate::errors::KernelError;
+use crate::gui::{clipboard, compositor};
+use crate::serial_println;
+use alloc::boxed::Box;
+use alloc::string::String;
+
+/// Colors used for text boxes
+pub const TEXTBOX_TEXT: Color = Color::Black;
+pub const TEXTBOX_BACKGROUND: Color = Color::White;
+pub const TEXTBOX_CURSOR: Color = Color::LightCyan;
+
+/// Longest text a box holds unless told otherwise, in bytes
+pub const DEFAULT_MAX_LEN: usize = 255;
+
+/// Called with the text after it changes, or when Enter is pressed
+pub type TextCallback = Box<dyn Fn(&str) -> Result<(), KernelError> + Send>;
+
+/// Says whether the text an edit would leave is allowed
+pub type Validator = Box<dyn Fn(&str) -> bool + Send>;
+
+/// Validator for file names: no path separators, wildcards or quotes
+pub fn filename_chars(text: &str) -> bool {
+    !text.chars().any(|c| matches!(c, '/' | '\\' | '*' | '?' | '"' | '<' | '>' | '|' | ':'))
+}
+
+/// A one-line editable text field
+pub struct TextBox {
+    /// Position relative to the window's content area
+    pub column: usize,
+    pub row: usize,
+    /// Cells on screen
+    width: usize,
+    text: String,
+    /// Byte offset of the cursor; the text is ASCII, so also its column
+    cursor: usize,
+    /// Other end of the selection; the selection runs from here to the cursor
+    anchor: Option<usize>,
+    /// First character shown
+    scroll: usize,
+    max_len: usize,
+    validator: Option<Validator>,
+    on_change: Option<TextCallback>,
+    on_submit: Option<TextCallback>,
+    /// Shown as `*`s and kept off the clipboard, for passwords
+    masked: bool,
+}
+
+impl TextBox {
+    /// An empty box `width` cells wide, at least 2
+    pub fn new(column: usize, row: usize, width: usize) -> Self {
+        Self {
+            column,
+            row,
+            width: width.max(2),
+            text: String::new(),
+            cursor: 0,
+            anchor: None,
+            scroll: 0,
+            max_len: DEFAULT_MAX_LEN,
+            validator: None,
+            on_change: None,
+            on_submit: None,
+            masked: false,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// First character shown
+    pub fn scroll(&self) -> usize {
+        self.scroll
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Selected byte range, start first; none if nothing is selected
+    pub fn selection(&self) -> Option<(usize, usize)> {
+        self.anchor
+            .filter(|anchor| *anchor != self.cursor)
+            .map(|anchor| (anchor.min(self.cursor), anchor.max(self.cursor)))
+    }
+
+    /// Selected text, if any
+    pub fn selected_text(&self) -> Option<&str> {
+        self.selection().map(|(start, end)| &self.text[start..end])
+    }
+
+    /// Replace the text without validating it or calling `on_change`, with
+    /// the cursor at the end. Characters a box can't hold become spaces.
+    pub fn set_text(&mut self, text: &str) {
+        self.text = printable(text);
+        self.text.truncate(self.max_len);
+        self.cursor = self.text.len();
+        self.anchor = None;
+        self.scroll_to_cursor();
+    }
+
+    /// Hold at most `max_len` bytes, cutting the text short if it's longer
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+        if self.text.len() > max_len {
+            self.set_text(&self.text.clone());
+        }
+    }
+
+    /// Refuse edits that would leave text `validator` rejects
+    pub fn set_validator(&mut self, validator: Validator) {
+        self.validator = Some(validator);
+    }
+
+    /// Call `callback` with the text after every edit
+    pub fn set_on_change(&mut self, callback: TextCallback) {
+        self.on_change = Some(callback);
+    }
+
+    /// Call `callback` with the text when Enter is pressed
+    pub fn set_on_submit(&mut self, callback: TextCallback) {
+        self.on_submit = Some(callback);
+    }
+
+    /// Show the text as `*`s and keep it off the clipboard
+    pub fn set_masked(&mut self, masked: bool) {
+        self.masked = masked;
+    }
+
+    /// Whether a point relative to the content area is on the box
+    pub fn contains(&self, column: usize, row: usize) -> bool {
+        row == self.row && column >= self.column && column < self.column + self.width
+    }
+
+    /// Act on a key press; returns whether the box used it. Keys it has no
+    /// use for, like Tab and Esc, are left for the window.
+    pub fn handle_key(&mut self, event: &KeyEvent) -> Result<bool, KernelError> {
+        if event.state != KeyState::Pressed || event.alt {
+            return Ok(false);
+        }
+        let end = self.text.len();
+        match event.code {
+            KeyCode::ArrowLeft | KeyCode::ArrowRight if !event.ctrl => {
+                let left = event.code == KeyCode::ArrowLeft;
+                let target = match self.selection() {
+                    // Without Shift an arrow drops the selection at its edge
+                    Some((start, end)) if !event.shift => if left { start } else { end },
+                    _ if left => self.cursor.saturating_sub(1),
+                    _ => (self.cursor + 1).min(end),
+                };
+                self.move_cursor(target, event.shift);
+            }
+            KeyCode::Home if !event.ctrl => self.move_cursor(0, event.shift),
+            KeyCode::End if !event.ctrl => self.move_cursor(end, event.shift),
+            KeyCode::A if event.ctrl => {
+                self.anchor = Some(0);
+                self.cursor = end;
+                self.scroll_to_cursor();
+            }
+            KeyCode::C | KeyCode::X if event.ctrl && self.masked => {}
+            KeyCode::C if event.ctrl => {
+                if let Some(text) = self.selected_text() {
+                    clipboard::set(text)?;
+                }
+            }
+            KeyCode::X if event.ctrl => {
+                if let Some((start, end)) = self.selection() {
+                    clipboard::set(&self.text[start..end])?;
+                    self.edit(start, end, "")?;
+                }
+            }
+            KeyCode::V if event.ctrl => {
+                let (start, end) = self.selection().unwrap_or((self.cursor, self.cursor));
+                self.edit(start, end, &printable(&clipboard::get()))?;
+            }
+            KeyCode::Backspace if !event.ctrl => {
+                let (start, end) = self.selection().unwrap_or((self.cursor.saturating_sub(1), self.cursor));
+                self.edit(start, end, "")?;
+            }
+            KeyCode::Delete if !event.ctrl => {
+                let (start, end) = self.selection().unwrap_or((self.cursor, (self.cursor + 1).min(end)));
+                self.edit(start, end, "")?;
+            }
+            KeyCode::Enter if !event.ctrl => {
+                if let Some(callback) = &self.on_submit {
+                    callback(&self.text)?;
+                }
+            }
+            _ if event.ctrl => return Ok(false),
+            _ => match event.to_char() {
+                Some(c) if c == ' ' || c.is_ascii_graphic() => {
+                    let (start, end) = self.selection().unwrap_or((self.cursor, self.cursor));
+                    let mut typed = [0u8; 1];
+                    self.edit(start, end, c.encode_utf8(&mut typed))?;
+                }
+                _ => return Ok(false),
+            },
+        }
+        Ok(true)
+    }
+
+    /// Put the cursor under a click `column` cells from the box's left
+    /// edge; a double-click selects the word there
+    pub fn handle_click(&mut self, column: usize, double_click: bool) {
+        let position = (self.scroll + column).min(self.text.len());
+        self.anchor = None;
+        self.cursor = position;
+        if double_click {
+            let (start, end) = self.word_at(position);
+            self.anchor = Some(start);
+            self.cursor = end;
+        }
+        self.scroll_to_cursor();
+    }
+
+    /// Draw the box with the content area's top-left corner at (`x`, `y`);
+    /// the cursor is shown only when `focused`
+    pub fn draw(&self, x: usize, y: usize, focused: bool) {
+        let (x, y) = (x + self.column, y + self.row);
+        let shown_end = (self.scroll + self.width).min(self.text.len());
+        let shown = self.shown(self.scroll.min(shown_end), shown_end);
+        compositor::write_at(y, x, &alloc::format!("{:<width$}", shown, width = self.width),
+            TEXTBOX_TEXT, TEXTBOX_BACKGROUND);
+
+        // The visible part of the selection, inverted
+        if let Some((start, end)) = self.selection() {
+            let (start, end) = (start.max(self.scroll), end.min(shown_end));
+            if start < end {
+                compositor::write_at(y, x + start - self.scroll, &self.shown(start, end),
+                    TEXTBOX_BACKGROUND, TEXTBOX_TEXT);
+            }
+        }
+
+        if focused {
+            let under = if self.cursor < self.text.len() {
+                self.shown(self.cursor, self.cursor + 1)
+            } else {
+                String::from(" ")
+            };
+            compositor::write_at(y, x + self.cursor - self.scroll, &under, TEXTBOX_TEXT, TEXTBOX_CURSOR);
+        }
+    }
+
+    /// Bytes `start..end` of the text as drawn
+    fn shown(&self, start: usize, end: usize) -> String {
+        if self.masked {
+            "*".repeat(end - start)
+        } else {
+            String::from(&self.text[start..end])
+        }
+    }
+
+    /// Move the cursor to `position`, extending the selection if `extend`
+    /// or dropping it if not
+    fn move_cursor(&mut self, position: usize, extend: bool) {
+        if extend {
+            self.anchor.get_or_insert(self.cursor);
+        } else {
+            self.anchor = None;
+        }
+        self.cursor = position;
+        self.scroll_to_cursor();
+    }
+
+    /// Replace bytes `start..end` with `insert`, cut short to fit
+    /// `max_len`, unless the validator refuses the result; returns whether
+    /// the text changed
+    fn edit(&mut self, start: usize, end: usize, insert: &str) -> Result<bool, KernelError> {
+        let room = self.max_len.saturating_sub(self.text.len() - (end - start));
+        let insert = &insert[..insert.len().min(room)];
+        if start == end && insert.is_empty() {
+            return Ok(false);
+        }
+        let mut text = String::with_capacity(self.text.len() - (end - start) + insert.len());
+        text.push_str(&self.text[..start]);
+        text.push_str(insert);
+        text.push_str(&self.text[end..]);
+        if self.validator.as_ref().is_some_and(|validator| !validator(&text)) {
+            return Ok(false);
+        }
+
+        self.text = text;
+        self.cursor = start + insert.len();
+        self.anchor = None;
+        self.scroll_to_cursor();
+        if let Some(callback) = &self.on_change {
+            callback(&self.text)?;
+        }
+        Ok(true)
+    }
+
+    /// Scroll as little as keeps the cursor in view, using the last cell
+    /// for it when it's at the end, and show as much text as fits
+    fn scroll_to_cursor(&mut self) {
+        let last = self.width - 1;
+        self.scroll = self.scroll
+            .min(self.text.len().saturating_sub(last))
+            .min(self.cursor)
+            .max(self.cursor.saturating_sub(last));
+    }
+
+    /// Byte range of the word around `position`: letters, digits and
+    /// underscores, or just the character there if it's none of those
+    fn word_at(&self, position: usize) -> (usize, usize) {
+        let bytes = self.text.as_bytes();
+        let is_word = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_';
+        // At the very end, take the word the text ends with
+        let position = if position == bytes.len() { position.saturating_sub(1) } else { position };
+        match bytes.get(position) {
+            Some(&byte) if is_word(byte) => {
+                let start = bytes[..position].iter().rposition(|&b| !is_word(b)).map_or(0, |i| i + 1);
+                let end = bytes[position..].iter().position(|&b| !is_word(b)).map_or(bytes.len(), |i| position + i);
+                (start, end)
+            }
+            Some(_) => (position, position + 1),
+            None => (0, 0),
+        }
+    }
+}
+
+/// `text` with anything a box can't show turned into spaces
+fn printable(text: &str) -> String {
+    text.chars().map(|c| if c.is_ascii_graphic() { c } else { ' ' }).collect()
+}
+
+/// A key press for the self-test
+fn press(code: KeyCode, shift: bool, ctrl: bool) -> KeyEvent {
+    KeyEvent { code, state: KeyState::Pressed, shift, ctrl, alt: false, time_ms: 0 }
+}
+
+/// Type, select, cut, paste, click and submit in a narrow box and check
+/// the text, selection and scroll after each step
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("TEXTBOX: Running self-test");
+
+    let saved_clipboard = clipboard::get();
+    let saved_history = clipboard::history();
+    let result = run_steps();
+    // Put the clipboard back as it was, history and all
+    clipboard::clear();
+    for entry in saved_history.iter().rev() {
+        clipboard::set(entry)?;
+    }
+    clipboard::set(&saved_clipboard)?;
+    result?;
+
+    serial_println!("TEXTBOX: Self-test passed");
+    Ok(())
+}
+
+fn run_steps() -> Result<(), KernelError> {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use spin::Mutex;
+
+    let changes = Arc::new(AtomicUsize::new(0));
+    let submitted = Arc::new(Mutex::new(String::new()));
+    let mut text_box = TextBox::new(0, 0, 8);
+    let counter = changes.clone();
+    text_box.set_on_change(Box::new(move |_| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }));
+    let sink = submitted.clone();
+    text_box.set_on_submit(Box::new(move |text| {
+        *sink.lock() = String::from(text);
+        Ok(())
+    }));
+    text_box.set_validator(Box::new(filename_chars));
+
+    let check = |text_box: &TextBox, text: &str, cursor: usize, selection: Option<(usize, usize)>, scroll: usize,
+                 step: &'static str| {
+        if text_box.text() != text || text_box.cursor() != cursor || text_box.selection() != selection
+            || text_box.scroll() != scroll {
+            serial_println!("TEXTBOX: After {}: {:?} cursor {} selection {:?} scroll {}", step,
+                text_box.text(), text_box.cursor(), text_box.selection(), text_box.scroll());
+            return Err(KernelError::ValidationError("Text box edited wrongly"));
+        }
+        Ok(())
+    };
+
+    // Typing past the width scrolls, keeping the last cell for the cursor
+    let typed = [KeyCode::H, KeyCode::E, KeyCode::L, KeyCode::L, KeyCode::O, KeyCode::Space,
+        KeyCode::W, KeyCode::O, KeyCode::R, KeyCode::L, KeyCode::D];
+    for code in typed {
+        text_box.handle_key(&press(code, false, false))?;
+    }
+    check(&text_box, "hello world", 11, None, 4, "typing")?;
+    if changes.load(Ordering::Relaxed) != typed.len() {
+        return Err(KernelError::ValidationError("Text box missed change callbacks"));
+    }
+
+    for _ in 0..5 {
+        text_box.handle_key(&press(KeyCode::ArrowLeft, true, false))?;
+    }
+    check(&text_box, "hello world", 6, Some((6, 11)), 4, "Shift+Left")?;
+
+    // Cutting shortens the text, so it scrolls back to show all of it
+    text_box.handle_key(&press(KeyCode::X, false, true))?;
+    check(&text_box, "hello ", 6, None, 0, "Ctrl+X")?;
+    if clipboard::get() != "world" {
+        return Err(KernelError::ValidationError("Text box cut the wrong text"));
+    }
+
+    text_box.handle_key(&press(KeyCode::Home, false, false))?;
+    text_box.handle_key(&press(KeyCode::V, false, true))?;
+    check(&text_box, "worldhello ", 5, None, 0, "Home, Ctrl+V")?;
+
+    text_box.handle_key(&press(KeyCode::End, true, false))?;
+    check(&text_box, "worldhello ", 11, Some((5, 11)), 4, "Shift+End")?;
+    text_box.handle_key(&press(KeyCode::X, true, false))?;
+    check(&text_box, "worldX", 6, None, 0, "typing over a selection")?;
+
+    // The validator turns away a slash; unused keys are left for the window
+    text_box.handle_key(&press(KeyCode::Slash, false, false))?;
+    check(&text_box, "worldX", 6, None, 0, "a refused slash")?;
+    if text_box.handle_key(&press(KeyCode::Tab, false, false))? {
+        return Err(KernelError::ValidationError("Text box took Tab"));
+    }
+
+    text_box.set_text("ab cd_ef.txt");
+    text_box.handle_click(3, true);
+    check(&text_box, "ab cd_ef.txt", 9, Some((8, 9)), 5, "double-clicking a dot")?;
+    text_box.handle_click(0, true);
+    check(&text_box, "ab cd_ef.txt", 8, Some((3, 8)), 5, "double-clicking a word")?;
+    text_box.handle_key(&press(KeyCode::ArrowLeft, false, false))?;
+    check(&text_box, "ab cd_ef.txt", 3, None, 3, "Left over a selection")?;
+
+    text_box.handle_key(&press(KeyCode::Enter, false, false))?;
+    if *submitted.lock() != "ab cd_ef.txt" {
+        return Err(KernelError::ValidationError("Text box didn't submit its text"));
+    }
+    Ok(())
+}
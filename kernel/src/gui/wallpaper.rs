@@ -0,0 +1,293 @@
+//! Desktop wallpaper
+//! Resolves the `ui.wallpaper` setting into something the text-mode desktop
+//! can paint: a solid color, a dithered two-color pattern, or a BMP image
+//! reduced to one VGA color per character cell.
+
+use crate::drivers::vga_enhanced::Color;
+use crate::errors::KernelError;
+use crate::fs;
+use crate::serial_println;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Directory searched for wallpaper images given by bare file name
+pub const WALLPAPER_DIR: &str = "/Library/Wallpapers";
+/// Largest BMP file accepted
+const MAX_BMP_SIZE: u64 = 512 * 1024;
+
+/// How an image smaller or larger than the desktop is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageMode {
+    /// Repeat the image, one pixel per cell
+    Tile,
+    /// Scale the image to cover the whole desktop
+    Stretch,
+}
+
+/// A wallpaper ready to paint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Wallpaper {
+    Solid(Color),
+    /// Checkerboard of two colors
+    Dither(Color, Color),
+    Image {
+        width: usize,
+        height: usize,
+        pixels: Vec<Color>,
+        mode: ImageMode,
+    },
+}
+
+impl Wallpaper {
+    /// Background color of the cell at (`x`, `y`) on a desktop of the given size
+    pub fn color_at(&self, x: usize, y: usize, desktop_width: usize, desktop_height: usize) -> Color {
+        match self {
+            Wallpaper::Solid(color) => *color,
+            Wallpaper::Dither(first, second) => {
+                if (x + y) % 2 == 0 { *first } else { *second }
+            }
+            Wallpaper::Image { width, height, pixels, mode } => {
+                let (px, py) = match mode {
+                    ImageMode::Tile => (x % width, y % height),
+                    ImageMode::Stretch => (
+                        x * width / desktop_width.max(1),
+                        y * height / desktop_height.max(1),
+                    ),
+                };
+                pixels[py * width + px]
+            }
+        }
+    }
+}
+
+/// Look up a VGA color by name ("blue", "light_gray", "lightgray", ...)
+pub fn parse_color(name: &str) -> Option<Color> {
+    let mut key = [0u8; 16];
+    let mut len = 0;
+    for byte in name.bytes().filter(|b| *b != b'_' && *b != b'-') {
+        if len == key.len() {
+            return None;
+        }
+        key[len] = byte.to_ascii_lowercase();
+        len += 1;
+    }
+
+    let color = match &key[..len] {
+        b"black" => Color::Black,
+        b"blue" => Color::Blue,
+        b"green" => Color::Green,
+        b"cyan" => Color::Cyan,
+        b"red" => Color::Red,
+        b"magenta" => Color::Magenta,
+        b"brown" => Color::Brown,
+        b"lightgray" | b"lightgrey" | b"gray" | b"grey" => Color::LightGray,
+        b"darkgray" | b"darkgrey" => Color::DarkGray,
+        b"lightblue" => Color::LightBlue,
+        b"lightgreen" => Color::LightGreen,
+        b"lightcyan" => Color::LightCyan,
+        b"lightred" => Color::LightRed,
+        b"pink" => Color::Pink,
+        b"yellow" => Color::Yellow,
+        b"white" => Color::White,
+        _ => return None,
+    };
+    Some(color)
+}
+
+/// Parse a color spec: a single color name, or two names joined by ':' for a
+/// dithered pattern
+pub fn parse_colors(spec: &str) -> Option<Wallpaper> {
+    match spec.split_once(':') {
+        Some((first, second)) => Some(Wallpaper::Dither(parse_color(first)?, parse_color(second)?)),
+        None => Some(Wallpaper::Solid(parse_color(spec)?)),
+    }
+}
+
+/// Whether a wallpaper spec names an image file rather than colors
+pub fn is_image_spec(spec: &str) -> bool {
+    spec.starts_with('/') || spec.to_ascii_lowercase().ends_with(".bmp")
+}
+
+/// Full path of an image spec; bare names are looked up in WALLPAPER_DIR
+pub fn image_path(spec: &str) -> alloc::string::String {
+    if spec.starts_with('/') {
+        spec.into()
+    } else {
+        alloc::format!("{}/{}", WALLPAPER_DIR, spec)
+    }
+}
+
+/// Resolve a wallpaper spec (colors or an image path)
+pub fn load(spec: &str, mode: ImageMode) -> Result<Wallpaper, KernelError> {
+    if is_image_spec(spec) {
+        load_bmp(&image_path(spec), mode)
+    } else {
+        parse_colors(spec).ok_or(KernelError::InvalidParameter)
+    }
+}
+
+/// The 16 VGA text colors as RGB, in palette order
+const PALETTE: [(Color, [u8; 3]); 16] = [
+    (Color::Black, [0x00, 0x00, 0x00]),
+    (Color::Blue, [0x00, 0x00, 0xAA]),
+    (Color::Green, [0x00, 0xAA, 0x00]),
+    (Color::Cyan, [0x00, 0xAA, 0xAA]),
+    (Color::Red, [0xAA, 0x00, 0x00]),
+    (Color::Magenta, [0xAA, 0x00, 0xAA]),
+    (Color::Brown, [0xAA, 0x55, 0x00]),
+    (Color::LightGray, [0xAA, 0xAA, 0xAA]),
+    (Color::DarkGray, [0x55, 0x55, 0x55]),
+    (Color::LightBlue, [0x55, 0x55, 0xFF]),
+    (Color::LightGreen, [0x55, 0xFF, 0x55]),
+    (Color::LightCyan, [0x55, 0xFF, 0xFF]),
+    (Color::LightRed, [0xFF, 0x55, 0x55]),
+    (Color::Pink, [0xFF, 0x55, 0xFF]),
+    (Color::Yellow, [0xFF, 0xFF, 0x55]),
+    (Color::White, [0xFF, 0xFF, 0xFF]),
+];
+
+/// Closest VGA color to an RGB value
+fn nearest_color(rgb: [u8; 3]) -> Color {
+    let distance = |entry: &[u8; 3]| -> u32 {
+        rgb.iter().zip(entry.iter())
+            .map(|(a, b)| {
+                let d = *a as i32 - *b as i32;
+                (d * d) as u32
+            })
+            .sum()
+    };
+    PALETTE.iter()
+        .min_by_key(|(_, entry)| distance(entry))
+        .map(|(color, _)| *color)
+        .unwrap_or(Color::Black)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Decode an uncompressed 24- or 32-bit BMP into VGA colors
+pub fn decode_bmp(data: &[u8], mode: ImageMode) -> Result<Wallpaper, KernelError> {
+    const FILE_HEADER_SIZE: usize = 14;
+    const MIN_INFO_HEADER_SIZE: usize = 40;
+    const BI_RGB: u32 = 0;
+    const BI_BITFIELDS: u32 = 3;
+
+    if data.len() < FILE_HEADER_SIZE + MIN_INFO_HEADER_SIZE || &data[0..2] != b"BM" {
+        return Err(KernelError::ValidationError("Not a BMP file"));
+    }
+    let pixel_offset = u32_at(data, 10) as usize;
+    let info_size = u32_at(data, 14) as usize;
+    if info_size < MIN_INFO_HEADER_SIZE {
+        return Err(KernelError::ValidationError("Unsupported BMP header"));
+    }
+
+    let raw_width = u32_at(data, 18) as i32;
+    let raw_height = u32_at(data, 22) as i32;
+    let bits_per_pixel = u16_at(data, 28);
+    let compression = u32_at(data, 30);
+
+    if raw_width <= 0 || raw_height == 0 {
+        return Err(KernelError::ValidationError("BMP has no pixels"));
+    }
+    if bits_per_pixel != 24 && bits_per_pixel != 32 {
+        return Err(KernelError::ValidationError("Only 24- and 32-bit BMP files are supported"));
+    }
+    if compression != BI_RGB && !(compression == BI_BITFIELDS && bits_per_pixel == 32) {
+        return Err(KernelError::ValidationError("Compressed BMP files are not supported"));
+    }
+
+    // Negative height means rows are stored top-down
+    let top_down = raw_height < 0;
+    let width = raw_width as usize;
+    let height = raw_height.unsigned_abs() as usize;
+    let bytes_per_pixel = bits_per_pixel as usize / 8;
+    let row_size = (width * bytes_per_pixel).checked_add(3)
+        .ok_or(KernelError::ValidationError("BMP dimensions out of range"))? & !3;
+    let pixel_end = row_size.checked_mul(height)
+        .and_then(|size| size.checked_add(pixel_offset))
+        .ok_or(KernelError::ValidationError("BMP dimensions out of range"))?;
+    if pixel_end > data.len() {
+        return Err(KernelError::ValidationError("BMP pixel data is truncated"));
+    }
+
+    let mut pixels = vec![Color::Black; width * height];
+    for y in 0..height {
+        let stored_row = if top_down { y } else { height - 1 - y };
+        let row = &data[pixel_offset + stored_row * row_size..];
+        for x in 0..width {
+            let pixel = &row[x * bytes_per_pixel..];
+            // Stored as blue, green, red
+            pixels[y * width + x] = nearest_color([pixel[2], pixel[1], pixel[0]]);
+        }
+    }
+
+    Ok(Wallpaper::Image { width, height, pixels, mode })
+}
+
+/// Load a BMP wallpaper from the VFS
+pub fn load_bmp(path: &str, mode: ImageMode) -> Result<Wallpaper, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let metadata = vfs.metadata(path)?;
+    if metadata.node_type == fs::vfs::NodeType::Directory {
+        return Err(KernelError::IsADirectory);
+    }
+    if metadata.size > MAX_BMP_SIZE {
+        return Err(KernelError::ValidationError("Wallpaper image too large"));
+    }
+
+    let mut data = vec![0u8; metadata.size as usize];
+    let read = fs::direct_read_file(path, &mut data)?;
+    data.truncate(read);
+
+    let wallpaper = decode_bmp(&data, mode)?;
+    serial_println!("WALLPAPER: Loaded {} ({} bytes)", path, read);
+    Ok(wallpaper)
+}
+
+/// Check color parsing and BMP decoding on a generated 2x2 image
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("WALLPAPER: Running self-test");
+
+    if parse_colors("light_blue") != Some(Wallpaper::Solid(Color::LightBlue))
+        || parse_colors("blue:cyan") != Some(Wallpaper::Dither(Color::Blue, Color::Cyan))
+        || parse_colors("plaid").is_some()
+    {
+        return Err(KernelError::ValidationError("Wallpaper color spec parsed incorrectly"));
+    }
+
+    // Bottom-up 2x2, 24 bits: bottom row red, white; top row blue, black
+    let mut bmp = Vec::new();
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&70u32.to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&54u32.to_le_bytes());
+    bmp.extend_from_slice(&40u32.to_le_bytes());
+    bmp.extend_from_slice(&2u32.to_le_bytes());
+    bmp.extend_from_slice(&2u32.to_le_bytes());
+    bmp.extend_from_slice(&1u16.to_le_bytes());
+    bmp.extend_from_slice(&24u16.to_le_bytes());
+    bmp.extend_from_slice(&[0; 24]);
+    bmp.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0]);
+    bmp.extend_from_slice(&[0xAA, 0x00, 0x00, 0x00, 0x00, 0x00, 0, 0]);
+
+    let image = decode_bmp(&bmp, ImageMode::Tile)?;
+    let expected = [Color::Blue, Color::Black, Color::Red, Color::White];
+    for (i, color) in expected.iter().enumerate() {
+        if image.color_at(i % 2 + 2, i / 2 + 4, 80, 23) != *color {
+            return Err(KernelError::ValidationError("BMP decoded to the wrong colors"));
+        }
+    }
+
+    // Truncated pixel data must be rejected
+    if decode_bmp(&bmp[..60], ImageMode::Tile).is_ok() {
+        return Err(KernelError::ValidationError("Truncated BMP was accepted"));
+    }
+
+    serial_println!("WALLPAPER: Self-test passed");
+    Ok(())
+}
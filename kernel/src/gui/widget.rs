@@ -0,0 +1,166 @@
+//! Widget module for UniverseK OS GUI
+//! Simple controls placed inside a window's content area
+
+use crate::drivers::vga_enhanced::Color;
+use crate::errors::KernelError;
+use crate::gui::compositor;
+use crate::gui::window::Window;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Colors used for buttons
+pub const BUTTON_BACKGROUND: Color = Color::DarkGray;
+pub const BUTTON_TEXT: Color = Color::White;
+
+/// Colors used for list views; the selected item is inverted, in
+/// LIST_SELECTED while the list has the focus
+pub const LIST_TEXT: Color = Color::Black;
+pub const LIST_BACKGROUND: Color = Color::White;
+pub const LIST_SELECTED: Color = Color::Blue;
+
+/// Input delivered to a window's widget callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WidgetEvent<'a> {
+    /// A button was clicked; carries its label
+    Button(&'a str),
+    /// A key was typed while the window had focus
+    Key(char),
+    /// An item of a list view was chosen, by click or arrow key; carries
+    /// the list's index, then the item's
+    Select(usize, usize),
+    /// Enter was pressed in a text box; carries its index
+    Submit(usize),
+}
+
+/// Callback for widget events; gets the window the widgets belong to
+pub type WidgetCallback = Box<dyn Fn(&mut Window, WidgetEvent) -> Result<(), KernelError> + Send>;
+
+/// A clickable button drawn as `[label]`
+pub struct Button {
+    pub label: String,
+    /// Position relative to the window's content area
+    pub column: usize,
+    pub row: usize,
+}
+
+impl Button {
+    pub fn new(label: &str, column: usize, row: usize) -> Self {
+        Self {
+            label: label.to_string(),
+            column,
+            row,
+        }
+    }
+
+    /// Width on screen, including the brackets
+    pub fn width(&self) -> usize {
+        self.label.len() + 2
+    }
+
+    /// Whether a point relative to the content area is on the button
+    pub fn contains(&self, column: usize, row: usize) -> bool {
+        row == self.row && column >= self.column && column < self.column + self.width()
+    }
+
+    /// Draw the button with the content area's top-left corner at (`x`, `y`)
+    pub fn draw(&self, x: usize, y: usize) {
+        compositor::write_at(y + self.row, x + self.column, &format!("[{}]", self.label),
+            BUTTON_TEXT, BUTTON_BACKGROUND);
+    }
+}
+
+/// A scrolling column of lines, one of which can be selected
+pub struct ListView {
+    /// Position relative to the window's content area
+    pub column: usize,
+    pub row: usize,
+    width: usize,
+    height: usize,
+    items: Vec<String>,
+    selected: Option<usize>,
+    /// First item shown
+    scroll: usize,
+}
+
+impl ListView {
+    /// An empty list `width` x `height` cells, at least 1 x 1
+    pub fn new(column: usize, row: usize, width: usize, height: usize) -> Self {
+        Self {
+            column,
+            row,
+            width: width.max(1),
+            height: height.max(1),
+            items: Vec::new(),
+            selected: None,
+            scroll: 0,
+        }
+    }
+
+    /// Replace the items, keeping the selection if it's still in range
+    pub fn set_items(&mut self, items: Vec<String>) {
+        self.items = items;
+        self.select(self.selected);
+    }
+
+    pub fn items(&self) -> &[String] {
+        &self.items
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    /// Select item `index`, or nothing, scrolling it into view
+    pub fn select(&mut self, index: Option<usize>) {
+        self.selected = index.filter(|&index| index < self.items.len());
+        if let Some(index) = self.selected {
+            if index < self.scroll {
+                self.scroll = index;
+            } else if index >= self.scroll + self.height {
+                self.scroll = index + 1 - self.height;
+            }
+        }
+        self.scroll = self.scroll.min(self.items.len().saturating_sub(self.height));
+    }
+
+    /// Move the selection `by` items, stopping at either end; from nothing
+    /// it starts at the first item. Returns the item then selected.
+    pub fn move_selection(&mut self, by: isize) -> Option<usize> {
+        let last = self.items.len().checked_sub(1)?;
+        let target = match self.selected {
+            Some(index) => (index as isize + by).clamp(0, last as isize) as usize,
+            None => 0,
+        };
+        self.select(Some(target));
+        self.selected
+    }
+
+    /// Whether a point relative to the content area is on the list
+    pub fn contains(&self, column: usize, row: usize) -> bool {
+        column >= self.column && column < self.column + self.width
+            && row >= self.row && row < self.row + self.height
+    }
+
+    /// The item shown on `row` of the content area, if any
+    pub fn item_at(&self, row: usize) -> Option<usize> {
+        let index = self.scroll + row.checked_sub(self.row).filter(|&offset| offset < self.height)?;
+        (index < self.items.len()).then_some(index)
+    }
+
+    /// Draw the list with the content area's top-left corner at (`x`, `y`)
+    pub fn draw(&self, x: usize, y: usize, focused: bool) {
+        for line in 0..self.height {
+            let index = self.scroll + line;
+            let text = self.items.get(index).map_or("", String::as_str);
+            let cell = format!("{:<width$}", crate::text::ellipsize(text, self.width), width = self.width);
+            let (foreground, background) = match self.selected {
+                Some(selected) if selected == index && focused => (BUTTON_TEXT, LIST_SELECTED),
+                Some(selected) if selected == index => (BUTTON_TEXT, BUTTON_BACKGROUND),
+                _ => (LIST_TEXT, LIST_BACKGROUND),
+            };
+            compositor::write_at(y + self.row + line, x + self.column, &cell, foreground, background);
+        }
+    }
+}
@@ -1,9 +1,14 @@
 //! Window module for UniverseK OS GUI
 //! Implements window management for the graphical user interface
 
-use crate::drivers::vga_enhanced::{self, Color};
+use crate::drivers::vga_enhanced::Color;
 use crate::serial_println;
 use crate::errors::KernelError;
+use crate::gui::compositor::{self, Rect};
+use crate::drivers::ps2_keyboard::{KeyCode, KeyEvent, KeyState};
+use crate::gui::textbox::TextBox;
+use crate::gui::widget::{Button, ListView, WidgetCallback, WidgetEvent};
+use crate::text;
 use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::boxed::Box;
@@ -18,11 +23,28 @@ pub const WINDOW_TEXT: Color = Color::White;
 pub const WINDOW_BACKGROUND: Color = Color::LightGray;
 pub const WINDOW_BORDER: Color = Color::White;
 
+/// Smallest window size
+pub const MIN_WIDTH: usize = 10;
+pub const MIN_HEIGHT: usize = 5;
+
+/// Most content kept per window, in bytes; older text is dropped first
+const MAX_CONTENT_BYTES: usize = 4096;
+
 /// Window handle for shared access to windows
 pub type WindowHandle = Arc<Mutex<Window>>;
 
-/// Callback for handling input in a window
-pub type InputCallback = Box<dyn Fn(&str) -> Result<(), KernelError> + Send>;
+/// Callback for handling a submitted input line; gets the window it was typed in
+pub type InputCallback = Box<dyn Fn(&mut Window, &str) -> Result<(), KernelError> + Send>;
+
+/// A run of content text drawn in one color
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub text: String,
+    pub color: Color,
+}
+
+/// One screen row of wrapped content
+type WrappedRow = Vec<Segment>;
 
 /// A window in the GUI
 pub struct Window {
@@ -34,14 +56,54 @@ pub struct Window {
     /// Window size
     width: usize,
     height: usize,
-    /// Window content buffer
-    content: String,
+    /// Window content, as colored segments
+    content: Vec<Segment>,
+    /// Total bytes in `content`
+    content_len: usize,
+    /// Content wrapped to the inner width it was computed for
+    wrapped: Option<(usize, Vec<WrappedRow>)>,
+    /// Rows scrolled back from the end of the content
+    scroll_offset: usize,
     /// Input buffer (for shell-like windows)
     input_buffer: String,
+    /// Selected byte range of the input buffer
+    selection: Option<(usize, usize)>,
     /// Input callback
     input_callback: Option<InputCallback>,
     /// Whether this window accepts input
     accepts_input: bool,
+    /// The input line is a password: it shows as stars and can't be copied
+    input_hidden: bool,
+    /// Esc closes the window, rather than being ignored
+    escape_closes: bool,
+    /// Buttons in the content area
+    buttons: Vec<Button>,
+    /// Text boxes in the content area
+    text_boxes: Vec<TextBox>,
+    /// Text box that gets typing, if any
+    focused_text_box: Option<usize>,
+    /// List views in the content area
+    list_views: Vec<ListView>,
+    /// List view the up and down arrows move in, if any; never set at the
+    /// same time as `focused_text_box`
+    focused_list: Option<usize>,
+    /// Columns left of, and rows above, the content text, kept for widgets
+    content_inset: (usize, usize),
+    /// Receives button clicks and typed keys, instead of the input line
+    widget_callback: Option<WidgetCallback>,
+    /// Set when the window asks the desktop to close it
+    close_requested: bool,
+    /// Screen area to redraw since the compositor last asked
+    damage: Option<Rect>,
+    /// Name of the desktop app that opened the window, if any
+    app: Option<String>,
+    /// What the app wants back when the session is restored, such as a
+    /// terminal's directory
+    session_state: Option<String>,
+    /// Hidden from the screen and input, shown as a taskbar button
+    minimized: bool,
+    /// Content had a BEL, for the desktop to ring
+    bell_pending: bool,
 }
 
 impl Window {
@@ -51,38 +113,341 @@ impl Window {
             title: title.to_string(),
             x,
             y,
-            width: width.max(10), // Minimum size
-            height: height.max(5),
-            content: String::new(),
+            width: width.max(MIN_WIDTH), // Minimum size
+            height: height.max(MIN_HEIGHT),
+            content: Vec::new(),
+            content_len: 0,
+            wrapped: None,
+            scroll_offset: 0,
             input_buffer: String::new(),
+            selection: None,
             input_callback: None,
+            input_hidden: false,
             accepts_input: false,
+            escape_closes: false,
+            buttons: Vec::new(),
+            text_boxes: Vec::new(),
+            focused_text_box: None,
+            list_views: Vec::new(),
+            focused_list: None,
+            content_inset: (0, 0),
+            widget_callback: None,
+            close_requested: false,
+            damage: Some(Rect::new(x, y, width.max(MIN_WIDTH), height.max(MIN_HEIGHT))),
+            app: None,
+            session_state: None,
+            minimized: false,
+            bell_pending: false,
         }
     }
     
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+    
+    /// Record which app opened the window
+    pub fn set_app(&mut self, name: &str) {
+        self.app = Some(name.to_string());
+    }
+    
+    /// Name of the app that opened the window
+    pub fn app(&self) -> Option<&str> {
+        self.app.as_deref()
+    }
+    
+    /// Set the state handed to the app's restore hook next session
+    pub fn set_session_state(&mut self, state: &str) {
+        self.session_state = Some(state.to_string());
+    }
+    
+    pub fn session_state(&self) -> Option<&str> {
+        self.session_state.as_deref()
+    }
+    
     /// Enable input handling for this window
     pub fn enable_input(&mut self, callback: InputCallback) {
         self.accepts_input = true;
         self.input_callback = Some(callback);
     }
     
+    /// Show the input line as stars, for a password
+    pub fn set_input_hidden(&mut self, hidden: bool) {
+        if self.input_hidden != hidden {
+            self.input_hidden = hidden;
+            self.mark_damaged();
+        }
+    }
+    
+    /// Have Esc on the input line close the window, as it does a terminal
+    pub fn set_escape_closes(&mut self, closes: bool) {
+        self.escape_closes = closes;
+    }
+    
     /// Add text to the window's content
     pub fn add_text(&mut self, text: &str) {
-        self.content.push_str(text);
+        self.add_colored_text(text, WINDOW_TEXT);
+    }
+    
+    /// Add text drawn in `color` to the window's content
+    pub fn add_colored_text(&mut self, text: &str, color: Color) {
+        // A BEL rings the bell rather than being shown
+        let stripped;
+        let text = if text.contains('\x07') {
+            self.bell_pending = true;
+            stripped = text.replace('\x07', "");
+            stripped.as_str()
+        } else {
+            text
+        };
+        if text.is_empty() {
+            return;
+        }
+        let _tag = crate::allocator::tag("window");
+        match self.content.last_mut() {
+            Some(last) if last.color == color => last.text.push_str(text),
+            _ => self.content.push(Segment { text: text.to_string(), color }),
+        }
+        self.content_len += text.len();
         
-        // Limit content size
-        if self.content.len() > 1000 {
-            self.content = self.content[self.content.len() - 1000..].to_string();
+        // Limit content size, dropping the oldest text
+        while self.content_len > MAX_CONTENT_BYTES {
+            let excess = self.content_len - MAX_CONTENT_BYTES;
+            let first = &mut self.content[0];
+            if first.text.len() <= excess {
+                self.content_len -= first.text.len();
+                self.content.remove(0);
+            } else {
+                let cut = (excess..=first.text.len())
+                    .find(|&i| first.text.is_char_boundary(i))
+                    .unwrap_or(first.text.len());
+                first.text.drain(..cut);
+                self.content_len -= cut;
+            }
         }
+        self.wrapped = None;
+        self.mark_damaged();
+    }
+    
+    /// Check and clear whether content had a BEL since the last call
+    pub fn take_bell(&mut self) -> bool {
+        core::mem::take(&mut self.bell_pending)
     }
     
     /// Clear the window's content
     pub fn clear(&mut self) {
+        self.mark_damaged();
         self.content.clear();
+        self.content_len = 0;
+        self.wrapped = None;
+        self.scroll_offset = 0;
+    }
+    
+    /// Replace the window's content
+    pub fn set_text(&mut self, text: &str) {
+        // Keep the scroll position, so periodic refreshes don't jump
+        let scroll_offset = self.scroll_offset;
+        self.clear();
+        self.add_text(text);
+        self.scroll_offset = scroll_offset;
+    }
+    
+    /// Width available for content inside the borders
+    fn inner_width(&self) -> usize {
+        (self.width - 2).saturating_sub(self.content_inset.0).max(1)
+    }
+    
+    /// Rows available for content (borders and the input line excluded)
+    fn content_rows(&self) -> usize {
+        (self.height - 3).saturating_sub(self.content_inset.1).max(1)
+    }
+    
+    /// Start the content text `columns` in and `rows` down, leaving the
+    /// space above and to its left for widgets
+    pub fn set_content_inset(&mut self, columns: usize, rows: usize) {
+        self.content_inset = (columns, rows);
+        self.wrapped = None;
+        self.mark_damaged();
+    }
+    
+    /// Content wrapped to the current inner width, recomputed only after the
+    /// content or the width changed
+    fn wrapped_rows(&mut self) -> &[WrappedRow] {
+        let width = self.inner_width();
+        if self.wrapped.as_ref().map_or(true, |(cached_width, _)| *cached_width != width) {
+            self.wrapped = Some((width, wrap_segments(&self.content, width)));
+        }
+        &self.wrapped.as_ref().unwrap().1
+    }
+    
+    /// Scroll the content by `rows` (positive scrolls back toward older text)
+    pub fn scroll_by(&mut self, rows: isize) {
+        let visible = self.content_rows();
+        let max_offset = self.wrapped_rows().len().saturating_sub(visible);
+        let offset = self.scroll_offset as isize + rows;
+        self.scroll_offset = offset.clamp(0, max_offset as isize) as usize;
+        self.mark_damaged();
+    }
+    
+    /// Scroll back one page
+    pub fn page_up(&mut self) {
+        let page = self.content_rows().saturating_sub(1).max(1);
+        self.scroll_by(page as isize);
+    }
+    
+    /// Scroll forward one page
+    pub fn page_down(&mut self) {
+        let page = self.content_rows().saturating_sub(1).max(1);
+        self.scroll_by(-(page as isize));
+    }
+    
+    /// Add a button at a position relative to the content area
+    pub fn add_button(&mut self, label: &str, column: usize, row: usize) {
+        self.buttons.push(Button::new(label, column, row));
+        self.mark_damaged();
+    }
+    
+    /// Add a text box; the first one added gets the focus. Returns its
+    /// index for `text_box`.
+    pub fn add_text_box(&mut self, text_box: TextBox) -> usize {
+        self.text_boxes.push(text_box);
+        let index = self.text_boxes.len() - 1;
+        if self.focused_list.is_none() {
+            self.focused_text_box.get_or_insert(index);
+        }
+        self.mark_damaged();
+        index
+    }
+    
+    pub fn text_box(&self, index: usize) -> Option<&TextBox> {
+        self.text_boxes.get(index)
+    }
+    
+    pub fn text_box_mut(&mut self, index: usize) -> Option<&mut TextBox> {
+        self.mark_damaged();
+        self.text_boxes.get_mut(index)
+    }
+    
+    /// Add a list view; it gets the focus if nothing has it. Returns its
+    /// index for `list_view`.
+    pub fn add_list_view(&mut self, list_view: ListView) -> usize {
+        self.list_views.push(list_view);
+        let index = self.list_views.len() - 1;
+        if self.focused_text_box.is_none() {
+            self.focused_list.get_or_insert(index);
+        }
+        self.mark_damaged();
+        index
+    }
+    
+    pub fn list_view(&self, index: usize) -> Option<&ListView> {
+        self.list_views.get(index)
+    }
+    
+    pub fn list_view_mut(&mut self, index: usize) -> Option<&mut ListView> {
+        self.mark_damaged();
+        self.list_views.get_mut(index)
+    }
+    
+    /// Give the arrow keys to list view `index`
+    pub fn focus_list_view(&mut self, index: usize) {
+        if index < self.list_views.len() {
+            self.focused_list = Some(index);
+            self.focused_text_box = None;
+            self.mark_damaged();
+        }
+    }
+    
+    /// Give typing to text box `index`
+    pub fn focus_text_box(&mut self, index: usize) {
+        if index < self.text_boxes.len() {
+            self.focused_text_box = Some(index);
+            self.focused_list = None;
+            self.mark_damaged();
+        }
+    }
+    
+    /// Remove every button, text box and list view, for a window that
+    /// swaps one set of controls for another
+    pub fn clear_widgets(&mut self) {
+        self.buttons.clear();
+        self.text_boxes.clear();
+        self.focused_text_box = None;
+        self.list_views.clear();
+        self.focused_list = None;
+        self.mark_damaged();
+    }
+    
+    /// Route button clicks and typed keys to `callback`
+    pub fn set_widget_callback(&mut self, callback: WidgetCallback) {
+        self.widget_callback = Some(callback);
+    }
+    
+    /// Ask the desktop to close this window on its next redraw
+    pub fn request_close(&mut self) {
+        self.close_requested = true;
+    }
+    
+    /// Whether the window has asked to be closed
+    pub fn close_requested(&self) -> bool {
+        self.close_requested
+    }
+    
+    /// Hide the window, keeping its position and size. What it covered
+    /// is damaged so the desktop repaints it.
+    pub fn minimize(&mut self) {
+        if !self.minimized {
+            compositor::damage(self.bounds());
+            self.damage = None;
+            self.minimized = true;
+        }
+    }
+    
+    /// Show a minimized window where it was; returns whether it was minimized
+    pub fn restore(&mut self) -> bool {
+        let was_minimized = core::mem::replace(&mut self.minimized, false);
+        if was_minimized {
+            self.mark_damaged();
+        }
+        was_minimized
+    }
+    
+    pub fn is_minimized(&self) -> bool {
+        self.minimized
+    }
+    
+    /// Screen area covered by the window
+    pub fn bounds(&self) -> Rect {
+        Rect::new(self.x, self.y, self.width, self.height)
+    }
+    
+    /// Have the whole window redrawn in the next frame
+    pub fn mark_damaged(&mut self) {
+        let bounds = self.bounds();
+        self.damage = Some(self.damage.map_or(bounds, |damage| damage.union(&bounds)));
+    }
+    
+    /// Area changed since the last call, including any the window no longer
+    /// covers after being moved or resized. Nothing while minimized, as
+    /// nothing of it is on screen.
+    pub fn take_damage(&mut self) -> Option<Rect> {
+        let damage = self.damage.take();
+        if self.minimized { None } else { damage }
+    }
+    
+    /// Run the widget callback, lending it this window
+    fn dispatch_widget_event(&mut self, event: WidgetEvent) -> Result<(), KernelError> {
+        match self.widget_callback.take() {
+            Some(callback) => {
+                let result = callback(self, event);
+                self.widget_callback = Some(callback);
+                result
+            }
+            None => Ok(()),
+        }
     }
     
     /// Draw the window
-    pub fn draw(&self, is_active: bool) -> Result<(), KernelError> {
+    pub fn draw(&mut self, is_active: bool) -> Result<(), KernelError> {
         // Draw window border and background
         let title_color = if is_active { WINDOW_TITLE_ACTIVE } else { WINDOW_TITLE_INACTIVE };
         
@@ -95,34 +460,28 @@ impl Window {
             } else {
                 '═' // Horizontal border
             };
-            vga_enhanced::write_at(self.y, self.x + i, &c.to_string(), WINDOW_TEXT, title_color);
+            compositor::write_at(self.y, self.x + i, &c.to_string(), WINDOW_TEXT, title_color);
         }
         
-        // Draw title
-        let title = if self.title.len() > self.width - 4 {
-            // Truncate title if it's too long
-            let mut truncated = self.title[0..self.width - 7].to_string();
-            truncated.push_str("...");
-            truncated
-        } else {
-            self.title.clone()
-        };
+        // Draw title, truncated if it's too long
+        let title = text::ellipsize(&self.title, self.width - 6);
         
-        vga_enhanced::write_at(self.y, self.x + 2, &title, WINDOW_TEXT, title_color);
+        compositor::write_at(self.y, self.x + 2, &title, WINDOW_TEXT, title_color);
         
-        // Draw close button
-        vga_enhanced::write_at(self.y, self.x + self.width - 2, "X", Color::White, Color::Red);
+        // Draw minimize and close buttons
+        compositor::write_at(self.y, self.x + self.width - 3, "_", WINDOW_TEXT, title_color);
+        compositor::write_at(self.y, self.x + self.width - 2, "X", Color::White, Color::Red);
         
         // Side borders and content area
         for i in 1..self.height - 1 {
             // Left border
-            vga_enhanced::write_at(self.y + i, self.x, "║", WINDOW_BORDER, WINDOW_BACKGROUND);
+            compositor::write_at(self.y + i, self.x, "║", WINDOW_BORDER, WINDOW_BACKGROUND);
             // Right border
-            vga_enhanced::write_at(self.y + i, self.x + self.width - 1, "║", WINDOW_BORDER, WINDOW_BACKGROUND);
+            compositor::write_at(self.y + i, self.x + self.width - 1, "║", WINDOW_BORDER, WINDOW_BACKGROUND);
             
             // Window background
             for j in 1..self.width - 1 {
-                vga_enhanced::write_at(self.y + i, self.x + j, " ", WINDOW_TEXT, WINDOW_BACKGROUND);
+                compositor::write_at(self.y + i, self.x + j, " ", WINDOW_TEXT, WINDOW_BACKGROUND);
             }
         }
         
@@ -135,12 +494,25 @@ impl Window {
             } else {
                 '═' // Horizontal border
             };
-            vga_enhanced::write_at(self.y + self.height - 1, self.x + i, &c.to_string(), WINDOW_BORDER, WINDOW_BACKGROUND);
+            compositor::write_at(self.y + self.height - 1, self.x + i, &c.to_string(), WINDOW_BORDER, WINDOW_BACKGROUND);
         }
         
+        // Resize grip
+        compositor::write_at(self.y + self.height - 1, self.x + self.width - 1, "/", WINDOW_BORDER, title_color);
+        
         // Draw content
         self.draw_content()?;
         
+        for button in &self.buttons {
+            button.draw(self.x + 1, self.y + 1);
+        }
+        for (index, text_box) in self.text_boxes.iter().enumerate() {
+            text_box.draw(self.x + 1, self.y + 1, self.focused_text_box == Some(index));
+        }
+        for (index, list_view) in self.list_views.iter().enumerate() {
+            list_view.draw(self.x + 1, self.y + 1, self.focused_list == Some(index));
+        }
+        
         // Draw input buffer if window accepts input
         if self.accepts_input {
             self.draw_input_line()?;
@@ -149,32 +521,23 @@ impl Window {
         Ok(())
     }
     
-    /// Draw the window's content
-    fn draw_content(&self) -> Result<(), KernelError> {
-        // Simple content drawing - just split by newlines
-        let lines: Vec<&str> = self.content.split('\n').collect();
-        let available_height = self.height - 3; // Account for borders and input line
+    /// Draw the window's content: the rows that fit, ending `scroll_offset`
+    /// rows before the last one
+    fn draw_content(&mut self) -> Result<(), KernelError> {
+        let visible = self.content_rows();
+        let (x, y) = (self.x + 1 + self.content_inset.0, self.y + 1 + self.content_inset.1);
+        let scroll_offset = self.scroll_offset;
+        let rows = self.wrapped_rows();
         
-        // Draw only the last N lines that fit
-        let start_line = if lines.len() > available_height {
-            lines.len() - available_height
-        } else {
-            0
-        };
+        let end = rows.len().saturating_sub(scroll_offset.min(rows.len().saturating_sub(visible)));
+        let start = end.saturating_sub(visible);
         
-        for (i, line) in lines[start_line..].iter().enumerate() {
-            if i >= available_height {
-                break;
+        for (i, row) in rows[start..end].iter().enumerate() {
+            let mut column = x;
+            for segment in row {
+                compositor::write_at(y + i, column, &segment.text, segment.color, WINDOW_BACKGROUND);
+                column += segment.text.chars().count();
             }
-            
-            // Truncate line if it's too long
-            let display_line = if line.len() > self.width - 2 {
-                &line[0..self.width - 5]
-            } else {
-                line
-            };
-            
-            vga_enhanced::write_at(self.y + 1 + i, self.x + 1, display_line, WINDOW_TEXT, WINDOW_BACKGROUND);
         }
         
         Ok(())
@@ -186,49 +549,130 @@ impl Window {
             let y = self.y + self.height - 2;
             
             // Draw input prompt
-            vga_enhanced::write_at(y, self.x + 1, "> ", Color::Green, WINDOW_BACKGROUND);
+            compositor::write_at(y, self.x + 1, "> ", Color::Green, WINDOW_BACKGROUND);
             
-            // Draw input buffer
-            let buffer_display = if self.input_buffer.len() > self.width - 4 {
-                &self.input_buffer[self.input_buffer.len() - (self.width - 4)..]
-            } else {
-                &self.input_buffer
-            };
+            // Draw input buffer; the input is ASCII, so stars standing in
+            // for a password line up with it
+            let stars = "*".repeat(self.input_buffer.len());
+            let input = if self.input_hidden { &stars } else { &self.input_buffer };
+            let buffer_display = text::tail_to_width(input, self.width - 4);
+            
+            compositor::write_at(y, self.x + 3, buffer_display, WINDOW_TEXT, WINDOW_BACKGROUND);
             
-            vga_enhanced::write_at(y, self.x + 3, buffer_display, WINDOW_TEXT, WINDOW_BACKGROUND);
+            // Show the selection inverted
+            if let Some((start, end)) = self.selection {
+                let hidden = input.len() - buffer_display.len();
+                let start = start.max(hidden);
+                if start < end {
+                    let column = self.x + 3 + text::display_width(&input[hidden..start]);
+                    compositor::write_at(y, column, &input[start..end],
+                        WINDOW_BACKGROUND, WINDOW_TEXT);
+                }
+            }
             
             // Draw cursor
-            let cursor_pos = self.x + 3 + buffer_display.len();
+            let cursor_pos = self.x + 3 + text::display_width(buffer_display);
             if cursor_pos < self.x + self.width - 1 {
-                vga_enhanced::write_at(y, cursor_pos, "_", WINDOW_TEXT, WINDOW_BACKGROUND);
+                compositor::write_at(y, cursor_pos, "_", WINDOW_TEXT, WINDOW_BACKGROUND);
             }
         }
         
         Ok(())
     }
     
+    /// Give a key press to the focused text box or list view, or move the
+    /// focus to the next one on Tab (list views first, then text boxes);
+    /// returns whether it was used. Keys it isn't used for go to
+    /// `handle_key` as characters.
+    pub fn handle_key_event(&mut self, event: &KeyEvent) -> Result<bool, KernelError> {
+        if event.state != KeyState::Pressed {
+            return Ok(false);
+        }
+        let lists = self.list_views.len();
+        let count = lists + self.text_boxes.len();
+        let focused = match (self.focused_list, self.focused_text_box) {
+            (Some(list), _) => list,
+            (None, Some(text_box)) => lists + text_box,
+            (None, None) => return Ok(false),
+        };
+        if event.code == KeyCode::Tab && !event.ctrl && !event.alt {
+            let next = if event.shift { (focused + count - 1) % count } else { (focused + 1) % count };
+            if next < lists {
+                self.focus_list_view(next);
+            } else {
+                self.focus_text_box(next - lists);
+            }
+            return Ok(true);
+        }
+        
+        if let Some(list) = self.focused_list {
+            let by = match event.code {
+                KeyCode::ArrowUp if !event.ctrl => -1,
+                KeyCode::ArrowDown if !event.ctrl => 1,
+                _ => return Ok(false),
+            };
+            self.mark_damaged();
+            if let Some(item) = self.list_views[list].move_selection(by) {
+                self.dispatch_widget_event(WidgetEvent::Select(list, item))?;
+            }
+            return Ok(true);
+        }
+        
+        let text_box = focused - lists;
+        let used = self.text_boxes[text_box].handle_key(event)?;
+        if used {
+            self.mark_damaged();
+            if event.code == KeyCode::Enter {
+                self.dispatch_widget_event(WidgetEvent::Submit(text_box))?;
+            }
+        }
+        Ok(used)
+    }
+    
+    /// Enter `input` on the input line as if it had been typed, echoing it
+    /// and passing it to the input callback
+    pub fn submit_input(&mut self, input: &str) -> Result<(), KernelError> {
+        self.mark_damaged();
+        
+        // Add the input line to the content first, and jump back to the end
+        // of the output; a password is left out
+        let shown = if self.input_hidden { "" } else { input };
+        self.add_colored_text(&format!("> {}\n", shown), Color::Green);
+        self.scroll_offset = 0;
+        
+        // Call callback if available, lending it this window
+        if let Some(callback) = self.input_callback.take() {
+            let result = callback(self, input);
+            self.input_callback = Some(callback);
+            result?;
+        }
+        Ok(())
+    }
+    
     /// Handle keyboard input
     pub fn handle_key(&mut self, key: char) -> Result<(), KernelError> {
+        self.mark_damaged();
+        if self.widget_callback.is_some() {
+            return self.dispatch_widget_event(WidgetEvent::Key(key));
+        }
         if !self.accepts_input {
             return Ok(());
         }
         
+        // Typing over a selection replaces it; backspace just removes it
+        if key == '\x08' || key == ' ' || key.is_ascii_graphic() {
+            if self.delete_selection() && key == '\x08' {
+                return Ok(());
+            }
+        } else {
+            self.selection = None;
+        }
+        
         match key {
             '\n' => {
-                // Process input
-                let input = self.input_buffer.clone();
-                
-                // Add the input line to the content first
-                self.add_text(&format!("> {}\n", input));
-                
                 // Clear input buffer before calling callback
-                self.input_buffer.clear();
-                
-                // Call callback if available
-                if let Some(ref callback) = self.input_callback {
-                    // Now call the callback with the input
-                    callback(&input)?;
-                }
+                let input = core::mem::take(&mut self.input_buffer);
+                self.submit_input(&input)?;
             },
             '\x08' => {
                 // Backspace
@@ -240,17 +684,80 @@ impl Window {
                 // Add character to input
                 self.input_buffer.push(c);
             },
+            '\x1b' if self.escape_closes => self.request_close(),
             _ => {}
         }
         
         Ok(())
     }
     
+    /// Select the whole input line
+    pub fn select_all(&mut self) {
+        if self.accepts_input && !self.input_buffer.is_empty() {
+            self.selection = Some((0, self.input_buffer.len()));
+            self.mark_damaged();
+        }
+    }
+    
+    /// Selected input text, if any
+    pub fn selected_text(&self) -> Option<&str> {
+        self.selection.filter(|_| !self.input_hidden).map(|(start, end)| &self.input_buffer[start..end])
+    }
+    
+    /// Remove the selected text; returns whether there was a selection
+    fn delete_selection(&mut self) -> bool {
+        match self.selection.take() {
+            Some((start, end)) => {
+                self.input_buffer.replace_range(start..end, "");
+                self.mark_damaged();
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// Insert text at the end of the input line, replacing any selection.
+    /// Line breaks and other control characters become spaces.
+    pub fn paste(&mut self, text: &str) {
+        if !self.accepts_input {
+            return;
+        }
+        self.delete_selection();
+        self.input_buffer.extend(text.chars().map(|c| if c.is_ascii_graphic() { c } else { ' ' }));
+        self.mark_damaged();
+    }
+    
     /// Handle a mouse click
-    pub fn handle_click(&mut self, x: usize, y: usize) -> Result<(), KernelError> {
-        // For now, just focus the window (done by the desktop)
-        // Could handle scrolling, buttons, etc. here
-        Ok(())
+    pub fn handle_click(&mut self, x: usize, y: usize, double_click: bool) -> Result<(), KernelError> {
+        // Focusing is done by the desktop; here we only look for buttons
+        // and text boxes
+        if x <= self.x || y <= self.y {
+            return Ok(());
+        }
+        let (column, row) = (x - self.x - 1, y - self.y - 1);
+        if let Some(index) = self.text_boxes.iter().position(|text_box| text_box.contains(column, row)) {
+            let text_box = &mut self.text_boxes[index];
+            text_box.handle_click(column - text_box.column, double_click);
+            self.focus_text_box(index);
+            return Ok(());
+        }
+        if let Some(index) = self.list_views.iter().position(|list_view| list_view.contains(column, row)) {
+            self.focus_list_view(index);
+            let item = self.list_views[index].item_at(row);
+            if let Some(item) = item {
+                self.list_views[index].select(Some(item));
+                self.dispatch_widget_event(WidgetEvent::Select(index, item))?;
+            }
+            return Ok(());
+        }
+        let label = self.buttons.iter()
+            .find(|button| button.contains(column, row))
+            .map(|button| button.label.clone());
+        
+        match label {
+            Some(label) => self.dispatch_widget_event(WidgetEvent::Button(&label)),
+            None => Ok(()),
+        }
     }
     
     /// Check if a point is inside this window
@@ -263,10 +770,183 @@ impl Window {
     pub fn is_on_close_button(&self, x: usize, y: usize) -> bool {
         y == self.y && x == self.x + self.width - 2
     }
+    
+    /// Check if a point is on the minimize button, left of the close button
+    pub fn is_on_minimize_button(&self, x: usize, y: usize) -> bool {
+        y == self.y && x == self.x + self.width - 3
+    }
+    
+    /// Check if a point is on the resize grip (the bottom-right corner cells)
+    pub fn is_on_resize_grip(&self, x: usize, y: usize) -> bool {
+        y == self.y + self.height - 1 && x + 2 >= self.x + self.width && x < self.x + self.width
+    }
+    
+    /// Top-left corner
+    pub fn position(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+    
+    /// (width, height) including the borders
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+    
+    /// Change the size, keeping the top-left corner, at least MIN_WIDTH x
+    /// MIN_HEIGHT and within an `area` of (columns, rows). Content is
+    /// rewrapped on the next draw.
+    pub fn resize(&mut self, width: usize, height: usize, area: (usize, usize)) {
+        let max_width = area.0.saturating_sub(self.x).max(MIN_WIDTH);
+        let max_height = area.1.saturating_sub(self.y).max(MIN_HEIGHT);
+        // Damage the old bounds too, so whatever a shrink uncovers is redrawn
+        self.mark_damaged();
+        self.width = width.clamp(MIN_WIDTH, max_width);
+        self.height = height.clamp(MIN_HEIGHT, max_height);
+        self.mark_damaged();
+    }
+    
+    /// Move the top-left corner, keeping the window within an `area` of
+    /// (columns, rows) where it fits
+    pub fn move_to(&mut self, x: usize, y: usize, area: (usize, usize)) {
+        self.mark_damaged();
+        self.x = x.min(area.0.saturating_sub(self.width));
+        self.y = y.min(area.1.saturating_sub(self.height));
+        self.mark_damaged();
+    }
+    
+    /// Move and resize at once, as far as `area` allows
+    pub fn set_bounds(&mut self, bounds: Rect, area: (usize, usize)) {
+        self.move_to(0, 0, area);
+        self.resize(bounds.width, bounds.height, area);
+        self.move_to(bounds.x, bounds.y, area);
+    }
+}
+
+/// Break content into rows of at most `width` cells, splitting on '\n' and
+/// wrapping at spaces where possible. A trailing newline doesn't start an
+/// empty row.
+pub fn wrap_segments(content: &[Segment], width: usize) -> Vec<WrappedRow> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    
+    // Lay the content out as (char, color) cells, one logical line at a time
+    let mut line: Vec<(char, Color)> = Vec::new();
+    let finish_line = |line: &mut Vec<(char, Color)>, rows: &mut Vec<WrappedRow>| {
+        let mut start = 0;
+        while line.len() - start > width {
+            // Break at the last space that keeps the row within the width
+            match (start + 1..=start + width).rev().find(|&i| line[i].0 == ' ') {
+                Some(space) => {
+                    rows.push(cells_to_row(&line[start..space]));
+                    start = space + 1;
+                }
+                None => {
+                    rows.push(cells_to_row(&line[start..start + width]));
+                    start += width;
+                }
+            }
+        }
+        rows.push(cells_to_row(&line[start..]));
+        line.clear();
+    };
+    
+    for segment in content {
+        for c in segment.text.chars() {
+            match c {
+                '\n' => finish_line(&mut line, &mut rows),
+                '\t' => line.push((' ', segment.color)),
+                c => line.push((c, segment.color)),
+            }
+        }
+    }
+    if !line.is_empty() {
+        finish_line(&mut line, &mut rows);
+    }
+    rows
+}
+
+/// Group a row of cells back into colored segments
+fn cells_to_row(cells: &[(char, Color)]) -> WrappedRow {
+    let mut row: WrappedRow = Vec::new();
+    for &(c, color) in cells {
+        match row.last_mut() {
+            Some(segment) if segment.color == color => segment.text.push(c),
+            _ => row.push(Segment { text: c.to_string(), color }),
+        }
+    }
+    row
 }
 
 /// Create a new window with a handle
 pub fn create_window(title: &str, x: usize, y: usize, width: usize, height: usize) -> WindowHandle {
     let window = Window::new(title, x, y, width, height);
     Arc::new(Mutex::new(window))
-} 
\ No newline at end of file
+} 
+/// Check word wrapping, hard breaks, blank lines and color runs
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("WINDOW: Running self-test");
+    
+    let content = [
+        Segment { text: "hello world ".to_string(), color: Color::White },
+        Segment { text: "abcdefghij\n\nend\n".to_string(), color: Color::Red },
+    ];
+    let rows = wrap_segments(&content, 6);
+    let text: Vec<String> = rows.iter()
+        .map(|row| row.iter().map(|segment| segment.text.as_str()).collect())
+        .collect();
+    let expected = ["hello", "world", "abcdef", "ghij", "", "end"];
+    if text != expected {
+        serial_println!("WINDOW: Wrapped to {:?}", text);
+        return Err(KernelError::ValidationError("Window content wrapped incorrectly"));
+    }
+    if rows[2].len() != 1 || rows[2][0].color != Color::Red || rows[0][0].color != Color::White {
+        return Err(KernelError::ValidationError("Window content lost its colors"));
+    }
+    
+    // Scrolling stops at the oldest row
+    let mut window = Window::new("test", 0, 0, 8, 5);
+    window.set_text("1\n2\n3\n4\n5\n6\n");
+    for _ in 0..6 {
+        window.page_up();
+    }
+    if window.scroll_offset != 4 {
+        return Err(KernelError::ValidationError("Window scrolled past its content"));
+    }
+    
+    // Resizing stays within the minimum size and the given area
+    window.resize(1, 1, (80, 23));
+    if window.size() != (MIN_WIDTH, MIN_HEIGHT) {
+        return Err(KernelError::ValidationError("Window shrank below its minimum size"));
+    }
+    let mut window = Window::new("test", 10, 2, 20, 8);
+    window.resize(200, 200, (80, 23));
+    if window.size() != (70, 21) {
+        return Err(KernelError::ValidationError("Window grew past the screen"));
+    }
+    
+    // Moving damages both where the window was and where it is now
+    window.resize(20, 8, (80, 23));
+    window.take_damage();
+    window.move_to(40, 10, (80, 23));
+    if window.take_damage() != Some(Rect::new(10, 2, 50, 16)) || window.take_damage().is_some() {
+        return Err(KernelError::ValidationError("Moved window damaged the wrong area"));
+    }
+    
+    // A minimized window reports no damage until restored, then all of it
+    window.minimize();
+    window.add_text("hidden");
+    if !window.is_minimized() || window.take_damage().is_some() {
+        return Err(KernelError::ValidationError("Minimized window asked to be redrawn"));
+    }
+    if !window.restore() || window.restore() || window.take_damage() != Some(window.bounds()) {
+        return Err(KernelError::ValidationError("Restored window not redrawn"));
+    }
+    
+    // A BEL rings the bell once and isn't shown
+    window.set_text("ding\x07");
+    if !window.take_bell() || window.take_bell() || window.content != [Segment { text: "ding".to_string(), color: WINDOW_TEXT }] {
+        return Err(KernelError::ValidationError("BEL shown or not rung"));
+    }
+    
+    serial_println!("WINDOW: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! German
+
+use super::*;
+
+pub const LOCALE: Locale = Locale {
+    code: "de",
+    name: "Deutsch",
+    messages: &[
+        (MSG_ERROR, "Fehler: {}"),
+        (MSG_CANCELLED, "Abgebrochen."),
+        (MSG_EVENT_NOT_FOUND, "{}: Eintrag nicht gefunden"),
+        (MSG_UNKNOWN_COMMAND, "Unbekannter Befehl: {}"),
+        (MSG_UNKNOWN_COMMAND_SUGGEST, "Unbekannter Befehl: {}. Meinten Sie: {}?"),
+        (MSG_USAGE, "Aufruf: {}"),
+        (MSG_HELP_HEADER, "Verfügbare Befehle (help <Befehl> für Details):"),
+        (MSG_HELP_EDITING, concat!(
+            "Bearbeiten: Pos1/Ende oder Strg+A/E, wortweise mit Strg+Links/Rechts\n",
+            "  oder Alt+B/F, Strg+W/K/U schneidet Wort/bis Ende/bis Anfang aus,\n",
+            "  Strg+Y fügt es ein, Strg+Umschalt+A wählt alles, Strg+C/V kopiert/fügt ein"
+        )),
+        (MSG_HELP_ALIASES, "Auch: {}"),
+        (MSG_NO_SUCH_COMMAND, "Kein solcher Befehl: {}"),
+        (MSG_CREATED_FILE, "Datei angelegt: {}"),
+        (MSG_CREATED_DIRECTORY, "Verzeichnis angelegt: {}"),
+        (MSG_REMOVED, "Entfernt: {}"),
+        (MSG_BUTTON_MODE, "Modus"),
+        (MSG_RUN_TITLE, "Ausführen"),
+        (MSG_RUN_PROMPT, "App öffnen oder Befehl bzw. Programm ausführen:"),
+        (MSG_RUN_HINT, "Enter führt aus, Esc bricht ab, Auf/Ab für frühere"),
+        (MSG_LOCKED_TITLE, "Gesperrt"),
+        (MSG_SESSION_LOCKED, "Die Sitzung von {} ist gesperrt."),
+        (MSG_PASSWORD, "Passwort:"),
+        (MSG_WRONG_PASSWORD, "Falsches Passwort"),
+    ],
+    commands: &[
+        ("help", "Befehle auflisten oder einen erklären"),
+        ("echo", "Text ausgeben"),
+        ("ls", "Verzeichnisinhalt auflisten, nach Typ gefärbt (-l: ausführlich, -d: Verzeichnisse zuerst)"),
+        ("cd", "Verzeichnis wechseln (/ ohne Angabe)"),
+        ("pwd", "Aktuelles Verzeichnis ausgeben"),
+        ("cat", "Dateiinhalt anzeigen"),
+        ("clear", "Bildschirm leeren"),
+        ("touch", "Datei anlegen oder die Zeiten einer vorhandenen aktualisieren"),
+        ("mkdir", "Neues Verzeichnis anlegen"),
+        ("rm", "Datei oder leeres Verzeichnis entfernen (--purge: endgültig, auch mit fs.use_trash)"),
+        ("trash", "Papierkorb anzeigen, wiederherstellen oder leeren"),
+        ("reboot", "System neu starten"),
+        ("version", "Betriebssystemversion anzeigen"),
+    ],
+    errors: &[
+        ("Not found", "Nicht gefunden"),
+        ("Already exists", "Existiert bereits"),
+        ("Not a directory", "Kein Verzeichnis"),
+        ("Not a file", "Keine Datei"),
+        ("Is a directory", "Ist ein Verzeichnis"),
+        ("Directory not empty", "Verzeichnis nicht leer"),
+        ("Invalid parameter", "Ungültiger Parameter"),
+        ("Invalid operation", "Ungültige Operation"),
+        ("Not implemented", "Nicht implementiert"),
+        ("Not initialized", "Nicht initialisiert"),
+        ("I/O error", "E/A-Fehler"),
+        ("Out of memory", "Kein Speicher mehr"),
+        ("No space left on device", "Kein Platz mehr auf dem Gerät"),
+        ("Read-only file system", "Schreibgeschütztes Dateisystem"),
+        ("Interrupted", "Unterbrochen"),
+        ("Validation error", "Prüffehler"),
+        ("Generic error", "Fehler"),
+    ],
+};
@@ -0,0 +1,38 @@
+//! English, the text every other locale falls back to
+
+use super::*;
+
+pub const LOCALE: Locale = Locale {
+    code: "en",
+    name: "English",
+    messages: &[
+        (MSG_ERROR, "Error: {}"),
+        (MSG_CANCELLED, "Cancelled."),
+        (MSG_EVENT_NOT_FOUND, "{}: event not found"),
+        (MSG_UNKNOWN_COMMAND, "Unknown command: {}"),
+        (MSG_UNKNOWN_COMMAND_SUGGEST, "Unknown command: {}. Did you mean: {}?"),
+        (MSG_USAGE, "Usage: {}"),
+        (MSG_HELP_HEADER, "Available commands (help <command> for details):"),
+        (MSG_HELP_EDITING, concat!(
+            "Editing: Home/End or Ctrl+A/E, Ctrl+Left/Right or Alt+B/F by word,\n",
+            "  Ctrl+W/K/U cut word/to end/to start, Ctrl+Y paste cut text,\n",
+            "  Ctrl+Shift+A select all, Ctrl+C/V copy/paste"
+        )),
+        (MSG_HELP_ALIASES, "Also: {}"),
+        (MSG_NO_SUCH_COMMAND, "No such command: {}"),
+        (MSG_CREATED_FILE, "Created file: {}"),
+        (MSG_CREATED_DIRECTORY, "Created directory: {}"),
+        (MSG_REMOVED, "Removed: {}"),
+        (MSG_BUTTON_MODE, "Mode"),
+        (MSG_RUN_TITLE, "Run"),
+        (MSG_RUN_PROMPT, "Open an app, or run a command or program:"),
+        (MSG_RUN_HINT, "Enter runs it, Esc cancels, Up/Down for earlier ones"),
+        (MSG_LOCKED_TITLE, "Locked"),
+        (MSG_SESSION_LOCKED, "{}'s session is locked."),
+        (MSG_PASSWORD, "Password:"),
+        (MSG_WRONG_PASSWORD, "Wrong password"),
+    ],
+    // The command table and `KernelError::to_str` are already English
+    commands: &[],
+    errors: &[],
+};
@@ -0,0 +1,267 @@
+//! Translated user-visible text
+//!
+//! Messages are looked up by id in string tables built into the kernel,
+//! one per locale, and `system.locale` picks the table used. `tr!(MSG_ID)`
+//! gives a message's text; with arguments, `tr!(MSG_ID, a, b)` gives
+//! something to format that fills the text's `{}` (or `{0}`, `{1}`, for
+//! translations that reorder them) only when it is written out, so a
+//! message a logger filters out costs no allocation.
+//!
+//! A message missing from the active locale falls back to English, and the
+//! first miss of each message is logged at Debug. Besides messages, a
+//! locale can translate shell command summaries, by command name, and
+//! error descriptions, by their English text.
+
+mod de;
+mod en;
+
+use crate::errors::KernelError;
+use crate::serial_println;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Config key naming the locale
+pub const CONFIG_KEY: &str = "system.locale";
+/// Locale used when none is set, and for anything a locale lacks
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Identifies a message in the string tables
+pub type MessageId = &'static str;
+
+pub const MSG_ERROR: MessageId = "error";
+pub const MSG_CANCELLED: MessageId = "cancelled";
+pub const MSG_EVENT_NOT_FOUND: MessageId = "event_not_found";
+pub const MSG_UNKNOWN_COMMAND: MessageId = "unknown_command";
+pub const MSG_UNKNOWN_COMMAND_SUGGEST: MessageId = "unknown_command_suggest";
+pub const MSG_USAGE: MessageId = "usage";
+pub const MSG_HELP_HEADER: MessageId = "help_header";
+pub const MSG_HELP_EDITING: MessageId = "help_editing";
+pub const MSG_HELP_ALIASES: MessageId = "help_aliases";
+pub const MSG_NO_SUCH_COMMAND: MessageId = "no_such_command";
+pub const MSG_CREATED_FILE: MessageId = "created_file";
+pub const MSG_CREATED_DIRECTORY: MessageId = "created_directory";
+pub const MSG_REMOVED: MessageId = "removed";
+pub const MSG_BUTTON_MODE: MessageId = "button_mode";
+pub const MSG_RUN_TITLE: MessageId = "run_title";
+pub const MSG_RUN_PROMPT: MessageId = "run_prompt";
+pub const MSG_RUN_HINT: MessageId = "run_hint";
+pub const MSG_LOCKED_TITLE: MessageId = "locked_title";
+pub const MSG_SESSION_LOCKED: MessageId = "session_locked";
+pub const MSG_PASSWORD: MessageId = "password";
+pub const MSG_WRONG_PASSWORD: MessageId = "wrong_password";
+
+/// A locale's string tables
+pub struct Locale {
+    /// Code `system.locale` names it by
+    pub code: &'static str,
+    /// Name in its own language
+    pub name: &'static str,
+    messages: &'static [(MessageId, &'static str)],
+    /// Shell command summaries, by command name
+    commands: &'static [(&'static str, &'static str)],
+    /// Error descriptions, by `KernelError::to_str`
+    errors: &'static [(&'static str, &'static str)],
+}
+
+/// Every locale built in; English first, as the fallback
+pub static LOCALES: &[Locale] = &[en::LOCALE, de::LOCALE];
+
+/// Codes of the built-in locales, for the config rule
+pub const LOCALE_CODES: &[&str] = &["en", "de"];
+
+/// Index in `LOCALES` of the active locale
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Messages whose miss was logged already, with the locale they were
+/// missing from
+static REPORTED: Mutex<Vec<(usize, MessageId)>> = Mutex::new(Vec::new());
+
+/// Resolve a message id in the active locale, formatting any arguments.
+/// Arguments are borrowed, so use the result within the same statement.
+#[macro_export]
+macro_rules! tr {
+    ($id:expr) => {
+        $crate::i18n::text($id)
+    };
+    ($id:expr, $($arg:expr),+ $(,)?) => {
+        $crate::i18n::Message::new($crate::i18n::text($id), [$(&$arg as &dyn core::fmt::Display),+])
+    };
+}
+
+/// Use `system.locale`, and follow it when it changes
+pub fn init() {
+    reload(CONFIG_KEY);
+    crate::config::subscribe(CONFIG_KEY, reload);
+}
+
+fn reload(_key: &str) {
+    let code = crate::config::get(CONFIG_KEY).and_then(|value| value.try_as_string().cloned());
+    let index = code.as_deref().and_then(find).unwrap_or(0);
+    ACTIVE.store(index, Ordering::Relaxed);
+    serial_println!("I18N: Locale is {}", LOCALES[index].code);
+}
+
+fn find(code: &str) -> Option<usize> {
+    LOCALES.iter().position(|locale| locale.code == code)
+}
+
+/// The locale in use
+pub fn active() -> &'static Locale {
+    &LOCALES[ACTIVE.load(Ordering::Relaxed)]
+}
+
+fn lookup(table: &[(&'static str, &'static str)], key: &str) -> Option<&'static str> {
+    table.iter().find(|(known, _)| *known == key).map(|(_, text)| *text)
+}
+
+/// Text of message `id` in the active locale, or in English if it has none
+pub fn text(id: MessageId) -> &'static str {
+    let index = ACTIVE.load(Ordering::Relaxed);
+    if let Some(text) = lookup(LOCALES[index].messages, id) {
+        return text;
+    }
+    report_missing(index, id);
+    lookup(LOCALES[0].messages, id).unwrap_or(id)
+}
+
+/// Log the first miss of `id` in locale `index`, if Debug is wanted
+fn report_missing(index: usize, id: MessageId) {
+    if !crate::logger::debug_enabled() {
+        return;
+    }
+    let mut reported = REPORTED.lock();
+    if reported.contains(&(index, id)) {
+        return;
+    }
+    reported.push((index, id));
+    drop(reported);
+    crate::kdebug!("I18N", "No {} text for message {}, using English", LOCALES[index].code, id);
+}
+
+/// Summary of shell command `name` in the active locale; `english` if it
+/// has none
+pub fn command_summary(name: &str, english: &'static str) -> &'static str {
+    lookup(active().commands, name).unwrap_or(english)
+}
+
+/// Description of `error` in the active locale
+pub fn error(error: &KernelError) -> ErrorText<'_> {
+    ErrorText(error)
+}
+
+/// Formats a kernel error in the active locale
+pub struct ErrorText<'a>(&'a KernelError);
+
+impl fmt::Display for ErrorText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let english = self.0.to_str();
+        match (self.0, lookup(active().errors, english)) {
+            // Errors carrying details keep them
+            (KernelError::ValidationError(detail) | KernelError::GenericError(detail), Some(text)) => {
+                write!(f, "{}: {}", text, detail)
+            }
+            (KernelError::ValidationError(_) | KernelError::GenericError(_), None) => write!(f, "{}", self.0),
+            (_, Some(text)) => f.write_str(text),
+            (_, None) => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// A message's text with its arguments, filled in as it is formatted
+pub struct Message<'a, const N: usize> {
+    template: &'static str,
+    args: [&'a dyn fmt::Display; N],
+}
+
+impl<'a, const N: usize> Message<'a, N> {
+    pub fn new(template: &'static str, args: [&'a dyn fmt::Display; N]) -> Self {
+        Message { template, args }
+    }
+}
+
+impl<const N: usize> fmt::Display for Message<'_, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self.template;
+        let mut next = 0;
+        while let Some(start) = rest.find('{') {
+            f.write_str(&rest[..start])?;
+            let after = &rest[start + 1..];
+            let Some(end) = after.find('}') else {
+                f.write_str(&rest[start..])?;
+                return Ok(());
+            };
+            let position = match &after[..end] {
+                "" => {
+                    next += 1;
+                    Some(next - 1)
+                }
+                digits => digits.parse::<usize>().ok(),
+            };
+            match position.and_then(|position| self.args.get(position)) {
+                Some(arg) => arg.fmt(f)?,
+                // Not a placeholder, or no argument for it: left as written
+                None => f.write_str(&rest[start..start + end + 2])?,
+            }
+            rest = &after[end + 1..];
+        }
+        f.write_str(rest)
+    }
+}
+
+/// Check every locale's messages exist in English with the same number of
+/// placeholders, that arguments land where a translation puts them, and
+/// that a missing message falls back to English
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("I18N: Running self-test");
+
+    fn placeholders(text: &str) -> usize {
+        text.matches('{').count()
+    }
+    for locale in &LOCALES[1..] {
+        for (id, text) in locale.messages {
+            match lookup(LOCALES[0].messages, id) {
+                Some(english) if placeholders(english) == placeholders(text) => {}
+                _ => {
+                    serial_println!("I18N: {} message {} doesn't match English", locale.code, id);
+                    return Err(KernelError::ValidationError("Translated message doesn't match English"));
+                }
+            }
+        }
+    }
+    if LOCALE_CODES.len() != LOCALES.len() || LOCALES.iter().zip(LOCALE_CODES).any(|(locale, code)| locale.code != *code) {
+        return Err(KernelError::ValidationError("Locale codes out of step with the locales"));
+    }
+
+    let cases = [
+        (format_message("Created file: {}", "/a", "x"), "Created file: /a"),
+        (format_message("{1} before {0}", "/a", "x"), "x before /a"),
+        (format_message("{} {} {}", "/a", "x"), "/a x {}"),
+        (format_message("{oops} {", "/a", "x"), "{oops} {"),
+    ];
+    for (got, expected) in cases {
+        if got != expected {
+            serial_println!("I18N: Formatted {:?}, expected {:?}", got, expected);
+            return Err(KernelError::ValidationError("Message arguments placed wrongly"));
+        }
+    }
+
+    let saved = ACTIVE.load(Ordering::Relaxed);
+    ACTIVE.store(1, Ordering::Relaxed);
+    let translated = text(MSG_CANCELLED) != lookup(LOCALES[0].messages, MSG_CANCELLED).unwrap_or("");
+    let fallback = text("no_such_message");
+    ACTIVE.store(saved, Ordering::Relaxed);
+    if !translated || fallback != "no_such_message" {
+        return Err(KernelError::ValidationError("Locale lookup didn't fall back"));
+    }
+
+    serial_println!("I18N: Self-test passed");
+    Ok(())
+}
+
+fn format_message(template: &'static str, first: &str, second: &str) -> String {
+    format!("{}", Message::new(template, [&first as &dyn fmt::Display, &second]))
+}
@@ -174,6 +174,7 @@ fn write_apic_reg(reg: u64, value: u32) {
 
 /// Timer interrupt handler for APIC timer
 pub extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = super::enter_handler();
     unsafe {
         // Write directly to COM1 port for debugging
         let com1_data_port: *mut u8 = 0x3F8 as *mut u8;
@@ -0,0 +1,179 @@
+// kernel/src/interrupts/irq.rs
+//! Runtime interrupt handler registration
+//! The IDT is built once, so every vector that can be claimed at runtime gets
+//! a small stub that dispatches through a handler table.
+
+use spin::Mutex;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use crate::errors::KernelError;
+use crate::serial_println;
+use super::apic;
+use super::pic::{self, InterruptIndex, PIC_1_OFFSET};
+
+/// Handler invoked with the vector that fired
+pub type IrqHandler = fn(u8);
+
+/// First vector handed out by `allocate_vector` (MSI and other APIC-delivered interrupts)
+pub const DYNAMIC_VECTOR_BASE: u8 = 0x50;
+/// Number of dynamically allocatable vectors
+pub const DYNAMIC_VECTOR_COUNT: usize = 16;
+
+// Legacy PIC vectors occupy PIC_1_OFFSET..PIC_1_OFFSET + 16
+const LEGACY_VECTOR_COUNT: usize = 16;
+
+lazy_static! {
+    static ref LEGACY_HANDLERS: Mutex<[Option<IrqHandler>; LEGACY_VECTOR_COUNT]> =
+        Mutex::new([None; LEGACY_VECTOR_COUNT]);
+    static ref DYNAMIC_HANDLERS: Mutex<[Option<IrqHandler>; DYNAMIC_VECTOR_COUNT]> =
+        Mutex::new([None; DYNAMIC_VECTOR_COUNT]);
+}
+
+/// Legacy vectors that already have dedicated handlers in the IDT
+fn is_reserved_legacy(vector: u8) -> bool {
+    vector == InterruptIndex::Timer.as_u8()
+        || vector == InterruptIndex::Keyboard.as_u8()
+        || vector == InterruptIndex::Mouse.as_u8()
+}
+
+fn is_legacy(vector: u8) -> bool {
+    vector >= PIC_1_OFFSET && ((vector - PIC_1_OFFSET) as usize) < LEGACY_VECTOR_COUNT
+}
+
+fn is_dynamic(vector: u8) -> bool {
+    vector >= DYNAMIC_VECTOR_BASE && ((vector - DYNAMIC_VECTOR_BASE) as usize) < DYNAMIC_VECTOR_COUNT
+}
+
+/// Register a handler for a legacy PIC vector or a previously allocated dynamic vector
+pub fn register_irq_handler(vector: u8, handler: IrqHandler) -> Result<(), KernelError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if is_legacy(vector) && !is_reserved_legacy(vector) {
+            let mut handlers = LEGACY_HANDLERS.lock();
+            let slot = &mut handlers[(vector - PIC_1_OFFSET) as usize];
+            if slot.is_some() {
+                return Err(KernelError::AlreadyExists);
+            }
+            *slot = Some(handler);
+            Ok(())
+        } else if is_dynamic(vector) {
+            let mut handlers = DYNAMIC_HANDLERS.lock();
+            let slot = &mut handlers[(vector - DYNAMIC_VECTOR_BASE) as usize];
+            if slot.is_some() {
+                return Err(KernelError::AlreadyExists);
+            }
+            *slot = Some(handler);
+            Ok(())
+        } else {
+            Err(KernelError::InvalidParameter)
+        }
+    })
+}
+
+/// Remove the handler for a vector
+pub fn unregister_irq_handler(vector: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if is_legacy(vector) && !is_reserved_legacy(vector) {
+            LEGACY_HANDLERS.lock()[(vector - PIC_1_OFFSET) as usize] = None;
+        } else if is_dynamic(vector) {
+            DYNAMIC_HANDLERS.lock()[(vector - DYNAMIC_VECTOR_BASE) as usize] = None;
+        }
+    });
+}
+
+/// Claim a free dynamic vector and install `handler` on it
+pub fn allocate_vector(handler: IrqHandler) -> Result<u8, KernelError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = DYNAMIC_HANDLERS.lock();
+        for (i, slot) in handlers.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(handler);
+                let vector = DYNAMIC_VECTOR_BASE + i as u8;
+                serial_println!("IRQ: Allocated vector {:#x}", vector);
+                return Ok(vector);
+            }
+        }
+        Err(KernelError::GenericError("No free interrupt vectors"))
+    })
+}
+
+/// Release a dynamic vector obtained from `allocate_vector`
+pub fn free_vector(vector: u8) {
+    if is_dynamic(vector) {
+        unregister_irq_handler(vector);
+    }
+}
+
+/// Look up and run the handler for a vector, then acknowledge the interrupt
+fn dispatch(vector: u8) {
+    let _handler = super::enter_handler();
+    super::record_interrupt(vector);
+    let handler = if is_legacy(vector) {
+        LEGACY_HANDLERS.try_lock().and_then(|h| h[(vector - PIC_1_OFFSET) as usize])
+    } else {
+        DYNAMIC_HANDLERS.try_lock().and_then(|h| h[(vector - DYNAMIC_VECTOR_BASE) as usize])
+    };
+
+    if let Some(handler) = handler {
+        handler(vector);
+    }
+
+    if is_legacy(vector) {
+        pic::PIC_CONTROLLER.lock().notify_end_of_interrupt(vector - PIC_1_OFFSET);
+    } else {
+        apic::send_eoi();
+    }
+}
+
+macro_rules! irq_stubs {
+    ($($name:ident => $vector:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($vector);
+            }
+        )*
+
+        /// Point every runtime-registrable vector at its dispatch stub
+        pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+            $(
+                if !is_reserved_legacy($vector) {
+                    idt[$vector as usize].set_handler_fn($name);
+                }
+            )*
+        }
+    };
+}
+
+irq_stubs! {
+    legacy_irq_0 => PIC_1_OFFSET,
+    legacy_irq_1 => PIC_1_OFFSET + 1,
+    legacy_irq_2 => PIC_1_OFFSET + 2,
+    legacy_irq_3 => PIC_1_OFFSET + 3,
+    legacy_irq_4 => PIC_1_OFFSET + 4,
+    legacy_irq_5 => PIC_1_OFFSET + 5,
+    legacy_irq_6 => PIC_1_OFFSET + 6,
+    legacy_irq_7 => PIC_1_OFFSET + 7,
+    legacy_irq_8 => PIC_1_OFFSET + 8,
+    legacy_irq_9 => PIC_1_OFFSET + 9,
+    legacy_irq_10 => PIC_1_OFFSET + 10,
+    legacy_irq_11 => PIC_1_OFFSET + 11,
+    legacy_irq_12 => PIC_1_OFFSET + 12,
+    legacy_irq_13 => PIC_1_OFFSET + 13,
+    legacy_irq_14 => PIC_1_OFFSET + 14,
+    legacy_irq_15 => PIC_1_OFFSET + 15,
+    dynamic_irq_0 => DYNAMIC_VECTOR_BASE,
+    dynamic_irq_1 => DYNAMIC_VECTOR_BASE + 1,
+    dynamic_irq_2 => DYNAMIC_VECTOR_BASE + 2,
+    dynamic_irq_3 => DYNAMIC_VECTOR_BASE + 3,
+    dynamic_irq_4 => DYNAMIC_VECTOR_BASE + 4,
+    dynamic_irq_5 => DYNAMIC_VECTOR_BASE + 5,
+    dynamic_irq_6 => DYNAMIC_VECTOR_BASE + 6,
+    dynamic_irq_7 => DYNAMIC_VECTOR_BASE + 7,
+    dynamic_irq_8 => DYNAMIC_VECTOR_BASE + 8,
+    dynamic_irq_9 => DYNAMIC_VECTOR_BASE + 9,
+    dynamic_irq_10 => DYNAMIC_VECTOR_BASE + 10,
+    dynamic_irq_11 => DYNAMIC_VECTOR_BASE + 11,
+    dynamic_irq_12 => DYNAMIC_VECTOR_BASE + 12,
+    dynamic_irq_13 => DYNAMIC_VECTOR_BASE + 13,
+    dynamic_irq_14 => DYNAMIC_VECTOR_BASE + 14,
+    dynamic_irq_15 => DYNAMIC_VECTOR_BASE + 15,
+}
@@ -4,22 +4,86 @@
 
 pub mod pic; // Make the PIC controller module available
 pub mod apic; // Add APIC support
+pub mod irq; // Runtime IRQ handler registration
 
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use crate::{println, serial_print, serial_println, hlt_loop};
 use crate::gdt;
 use lazy_static::lazy_static;
 use pic::InterruptIndex;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use spin::Mutex;
+use crate::sync::DiagMutex;
+use crate::drivers::event_queue::EventQueue;
+use crate::drivers::ps2_keyboard::{KeyCode, KeyEvent, KeyState};
+use crate::errors::KernelError;
+use crate::serial::{LineBuffer, ISR_LINE_LEN};
 
 // Re-export PIC controller for convenience
 pub use pic::PIC_CONTROLLER;
+pub use irq::{register_irq_handler, unregister_irq_handler, allocate_vector, free_vector, IrqHandler};
 
 // A counter to track the number of timer interrupts
 static TIMER_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+// Times each vector has fired, for irqstat and the system monitor
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_COUNT: AtomicU64 = AtomicU64::new(0);
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [ZERO_COUNT; 256];
+
+/// Count one occurrence of `vector`
+pub fn record_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Interrupt handlers currently running, counting nested ones
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks the running code as an interrupt handler until dropped. Every
+/// hardware interrupt handler holds one, which is what lets the allocator
+/// catch allocations from interrupt context (see `allocator`).
+pub struct HandlerContext(());
+
+impl Drop for HandlerContext {
+    fn drop(&mut self) {
+        HANDLER_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Called at the top of a hardware interrupt handler; also how the
+/// self-test pretends to be one
+pub fn enter_handler() -> HandlerContext {
+    HANDLER_DEPTH.fetch_add(1, Ordering::SeqCst);
+    HandlerContext(())
+}
+
+/// Whether the running code is an interrupt handler
+pub fn in_handler() -> bool {
+    HANDLER_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// Short name for well-known vectors
+pub fn vector_name(vector: u8) -> Option<&'static str> {
+    match vector {
+        v if v == InterruptIndex::Timer.as_u8() => Some("timer"),
+        v if v == InterruptIndex::Keyboard.as_u8() => Some("keyboard"),
+        v if v == InterruptIndex::Mouse.as_u8() => Some("mouse"),
+        v if v == crate::syscall::SYSCALL_VECTOR => Some("syscall"),
+        _ => None,
+    }
+}
+
+/// (vector, count) for every vector that has fired at least once
+pub fn interrupt_counts() -> Vec<(u8, u64)> {
+    INTERRUPT_COUNTS.iter().enumerate()
+        .map(|(vector, count)| (vector as u8, count.load(Ordering::Relaxed)))
+        .filter(|&(_, count)| count > 0)
+        .collect()
+}
+
 /// Safely write a single character to the serial port (COM1)
 /// This function checks if the transmitter is ready before writing
 unsafe fn safe_serial_write(c: u8) {
@@ -47,6 +111,10 @@ lazy_static! {
         
         // Add Page Fault handler (#PF, vector 14)
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        
+        // Dispatch stubs for runtime-registered handlers
+        irq::install(&mut idt);
         
         // Add PIC interrupt handlers
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
@@ -58,14 +126,21 @@ lazy_static! {
         if apic::is_apic_available() {
             idt[apic::APIC_TIMER_VECTOR as usize].set_handler_fn(apic_timer_handler);
         }
+
+        // System call gate, reachable from ring 3
+        unsafe {
+            idt[crate::syscall::SYSCALL_VECTOR as usize]
+                .set_handler_addr(crate::syscall::entry_address())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        }
         
         idt
     };
 }
 
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-        Mutex::new(Keyboard::new(
+    static ref KEYBOARD: DiagMutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        DiagMutex::new("interrupts::KEYBOARD", Keyboard::new(
             layouts::Us104Key,
             ScancodeSet1,
             HandleControl::Ignore
@@ -145,16 +220,50 @@ extern "x86-interrupt" fn double_fault_handler(
     hlt_loop();
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    if is_user_mode(&stack_frame) {
+        crate::task::user_mode::handle_fault("general protection fault", None, stack_frame.instruction_pointer);
+    }
+
+    serial_print!("EXCEPTION: GENERAL PROTECTION FAULT\n");
+    serial_print!("Error Code: {:#x}\n", error_code);
+    serial_print!("At: {}\n", crate::ksyms::Symbolized(stack_frame.instruction_pointer.as_u64()));
+    serial_print!("Stack frame: {:#?}\n", stack_frame);
+    crate::ksyms::print_fault_backtrace(stack_frame.instruction_pointer.as_u64());
+    hlt_loop();
+}
+
+/// Whether the interrupted code was running in ring 3
+fn is_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0x3 == 3
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    // Writes to a page shared by fork get a private copy and are retried.
+    // Kernel writes into user buffers land here too, as fork sets CR0.WP.
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && crate::task::user_mode::handle_copy_on_write_fault(Cr2::read()) {
+        return;
+    }
+
+    // Faults from ring 3 only take down the offending program
+    if is_user_mode(&stack_frame) {
+        crate::task::user_mode::handle_fault("page fault", Some(Cr2::read()), stack_frame.instruction_pointer);
+    }
+
     serial_print!("EXCEPTION: PAGE FAULT\n");
     serial_print!("Accessed Address: {:?}\n", Cr2::read());
     serial_print!("Error Code: {:?}\n", error_code);
+    serial_print!("At: {}\n", crate::ksyms::Symbolized(stack_frame.instruction_pointer.as_u64()));
     serial_print!("Stack frame: {:#?}\n", stack_frame);
+    crate::ksyms::print_fault_backtrace(stack_frame.instruction_pointer.as_u64());
     hlt_loop(); 
 }
 
@@ -162,8 +271,13 @@ extern "x86-interrupt" fn page_fault_handler(
 
 // PIC Timer interrupt handler
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler();
     // Increment timer counter
     TIMER_COUNT.fetch_add(1, Ordering::SeqCst);
+    record_interrupt(InterruptIndex::Timer.as_u8());
+    crate::drivers::pit::tick();
+    crate::task::scheduler::account_tick();
+    crate::task::watchdog::timer_tick(crate::drivers::pit::ticks());
     
     // Send EOI to PIC
     unsafe {
@@ -177,6 +291,7 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 
 // APIC Timer interrupt handler
 extern "x86-interrupt" fn apic_timer_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler();
     unsafe {
         // Write 'A' to show APIC timer interrupts
         safe_serial_write(b'A');
@@ -187,6 +302,7 @@ extern "x86-interrupt" fn apic_timer_handler(_stack_frame: InterruptStackFrame)
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = enter_handler();
     unsafe {
         // Write a direct indicator that the keyboard handler is starting
         safe_serial_write(b'K');
@@ -195,6 +311,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
         // Read scancode directly, with minimal operations
         let keyboard_port = 0x60 as *mut u8;
         let scancode: u8 = *keyboard_port;
+        record_interrupt(InterruptIndex::Keyboard.as_u8());
         
         // Simple debugging - write scancode to COM1 as hex digits
         let hex_chars = b"0123456789ABCDEF";
@@ -214,4 +331,55 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
         // Final indicator that handler completed
         safe_serial_write(b'2');
     }
-} 
\ No newline at end of file
+}
+
+/// Pretend to be an interrupt handler and check the handler flag follows
+/// it, that formatting a log line and queueing events there don't allocate,
+/// and, in `diagnostics` builds, that an allocation there is caught
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("INTERRUPT: Running self-test");
+    if in_handler() {
+        return Err(KernelError::ValidationError("Handler flag set outside a handler"));
+    }
+
+    let event = KeyEvent { code: KeyCode::A, state: KeyState::Pressed, shift: false, ctrl: false, alt: false, time_ms: 0 };
+    let (flagged, allocated, caught) = {
+        let _handler = enter_handler();
+        let flagged = in_handler();
+        let allocated = crate::allocator::catch_interrupt_allocation(|| {
+            let mut line = LineBuffer::<ISR_LINE_LEN>::new();
+            let _ = write!(line, "Key pressed: {:?}", event);
+            let mut queue: EventQueue<KeyEvent, 4> = EventQueue::new();
+            for _ in 0..5 {
+                let _ = queue.push_back(event);
+            }
+            core::hint::black_box((line.as_str().len(), queue.pop_front()));
+        });
+        let caught = crate::allocator::catch_interrupt_allocation(|| {
+            drop(core::hint::black_box(Box::new(0u64)));
+        });
+        (flagged, allocated, caught)
+    };
+    if !flagged || in_handler() {
+        return Err(KernelError::ValidationError("Handler flag not set and cleared with the handler"));
+    }
+    if allocated {
+        return Err(KernelError::ValidationError("Handler logging or event queueing allocated"));
+    }
+    if cfg!(feature = "diagnostics") && !caught {
+        return Err(KernelError::ValidationError("Allocation from a handler went unnoticed"));
+    }
+
+    // Cut at the buffer's end, and before a character that doesn't fit
+    let mut line = LineBuffer::<8>::new();
+    let _ = write!(line, "abcdefg\u{e9}");
+    let mut queue: EventQueue<u8, 2> = EventQueue::new();
+    let refused = [1, 2, 3].map(|n| queue.push_back(n).is_err());
+    if line.as_str() != "abcdefg" || refused != [false, false, true] || queue.pop_front() != Some(1)
+        || queue.push_back(4).is_err() || queue.pop_front() != Some(2) || queue.pop_front() != Some(4) {
+        return Err(KernelError::ValidationError("Fixed-size line or queue overflowed wrongly"));
+    }
+
+    serial_println!("INTERRUPT: Self-test passed");
+    Ok(())
+}
@@ -53,6 +53,8 @@ impl InterruptIndex {
 pub struct PicController {
     primary_offset: u8,
     secondary_offset: u8,
+    /// IRQ lines claimed by drivers; kept unmasked by `configure_irqs`
+    claimed_irqs: u16,
 }
 
 impl PicController {
@@ -61,6 +63,7 @@ impl PicController {
         Self {
             primary_offset,
             secondary_offset,
+            claimed_irqs: 0,
         }
     }
 
@@ -111,6 +114,10 @@ impl PicController {
             let safe_primary_mask = primary_mask & 0xFCu8; // Only allow IRQ0 (timer) and IRQ1 (keyboard)
             let safe_secondary_mask = secondary_mask & 0xEFu8; // Only allow IRQ12 (mouse)
             
+            // Lines claimed by drivers stay open
+            let safe_primary_mask = safe_primary_mask & !(self.claimed_irqs as u8);
+            let safe_secondary_mask = safe_secondary_mask & !((self.claimed_irqs >> 8) as u8);
+            
             // Write the masks
             Port::new(PIC_1_DATA).write(safe_primary_mask);
             Port::new(PIC_2_DATA).write(safe_secondary_mask);
@@ -126,6 +133,29 @@ impl PicController {
         }
     }
 
+    /// Unmask an IRQ line for a driver and keep it unmasked across reconfiguration
+    pub fn unmask_irq(&mut self, irq: u8) {
+        if irq >= 16 {
+            return;
+        }
+        
+        self.claimed_irqs |= 1 << irq;
+        // Slave lines also need the cascade (IRQ2) open
+        if irq >= 8 {
+            self.claimed_irqs |= 1 << 2;
+        }
+        
+        unsafe {
+            let mut primary: Port<u8> = Port::new(PIC_1_DATA);
+            let mut secondary: Port<u8> = Port::new(PIC_2_DATA);
+            let primary_mask = primary.read() & !(self.claimed_irqs as u8);
+            let secondary_mask = secondary.read() & !((self.claimed_irqs >> 8) as u8);
+            primary.write(primary_mask);
+            secondary.write(secondary_mask);
+        }
+        serial_println!("PIC: IRQ {} unmasked", irq);
+    }
+
     /// Sends an end of interrupt signal for the given IRQ
     pub fn notify_end_of_interrupt(&mut self, irq: u8) {
         if irq >= 8 {
@@ -0,0 +1,321 @@
+//! Kernel symbol names, so backtraces and fault reports say
+//! `fn_name+0x1a` instead of a bare address
+//!
+//! The table comes from `nm` run on an earlier build of the same source;
+//! build.rs turns it into ksyms.bin when UNIVERSEK_KSYMS names the output:
+//!
+//! ```text
+//! cargo bootimage
+//! nm -C -S --defined-only target/x86_64-bear_os/debug/kernel > target/kernel.nm
+//! UNIVERSEK_KSYMS=$PWD/target/kernel.nm cargo bootimage
+//! ```
+//!
+//! The table sits in .data, which comes after the code, and code only
+//! reaches it through a pointer read at run time, so embedding it moves
+//! no function. A table from any other build is noticed, as its address
+//! for `anchor` is wrong, and not used. Lookups read the table in place
+//! without the heap, so they work in a panic.
+//!
+//! Backtraces follow the saved frame pointers, which .cargo/config.toml
+//! keeps in every function.
+
+use core::fmt::{self, Write};
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// Layout, all little-endian: "KSYM", the symbol count (u32) and the
+/// address of `anchor` (u64); then per symbol, by address, its address
+/// (u64), size (u32, 0 if unknown), and the offset and length of its name
+/// (u32 each) in the names that follow
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 20;
+
+/// How far past the start of a symbol with no size an address is still
+/// taken to be in it
+const MAX_UNSIZED_OFFSET: u64 = 0x1_0000;
+
+/// Most frames a backtrace shows
+const MAX_FRAMES: usize = 32;
+
+/// Biggest gap between one saved frame pointer and the next that's still
+/// taken for a caller's frame, rather than garbage
+const MAX_FRAME_SIZE: u64 = 0x10_0000;
+
+const EMBEDDED_LEN: usize = include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")).len();
+
+#[link_section = ".data.ksyms"]
+static EMBEDDED: [u8; EMBEDDED_LEN] = *include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+/// Read through `embedded`, so no code depends on the table's size
+static EMBEDDED_REF: &[u8] = &EMBEDDED;
+
+/// The table built into the kernel, whatever state it's in
+fn embedded() -> &'static [u8] {
+    unsafe { core::ptr::read_volatile(core::ptr::addr_of!(EMBEDDED_REF)) }
+}
+
+/// A function whose address the table records, to tell whether the table
+/// was made from this build
+#[inline(never)]
+pub fn anchor() {}
+
+/// A symbol table laid out as described at MAGIC
+#[derive(Clone, Copy)]
+pub struct Table<'a> {
+    bytes: &'a [u8],
+    count: usize,
+}
+
+impl<'a> Table<'a> {
+    /// The table in `bytes`, if they hold a whole one
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            return None;
+        }
+        let count = read_u32(bytes, 4) as usize;
+        let table = Self { bytes, count };
+        let names_start = HEADER_SIZE + count * ENTRY_SIZE;
+        let names_fit = (0..count).all(|index| {
+            let (_, _, offset, len) = table.entry(index);
+            names_start + offset + len <= bytes.len()
+        });
+        (names_start <= bytes.len() && names_fit).then_some(table)
+    }
+
+    /// Symbols in the table
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Address recorded for `anchor`
+    pub fn anchor(&self) -> u64 {
+        read_u64(self.bytes, 8)
+    }
+
+    /// (address, size, name offset, name length) of symbol `index`
+    fn entry(&self, index: usize) -> (u64, u64, usize, usize) {
+        let at = HEADER_SIZE + index * ENTRY_SIZE;
+        (read_u64(self.bytes, at), u64::from(read_u32(self.bytes, at + 8)),
+            read_u32(self.bytes, at + 12) as usize, read_u32(self.bytes, at + 16) as usize)
+    }
+
+    /// The function `address` is in and how far into it, if any
+    pub fn lookup(&self, address: u64) -> Option<(&'a str, u64)> {
+        // Symbols starting at or before the address
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.entry(middle).0 <= address {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        let (start, size, name_offset, name_len) = self.entry(low.checked_sub(1)?);
+        let offset = address - start;
+        if offset >= if size == 0 { MAX_UNSIZED_OFFSET } else { size } {
+            return None;
+        }
+        let names = &self.bytes[HEADER_SIZE + self.count * ENTRY_SIZE..];
+        let name = core::str::from_utf8(&names[name_offset..name_offset + name_len]).unwrap_or("?");
+        Some((name, offset))
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from(read_u32(bytes, at)) | (u64::from(read_u32(bytes, at + 4)) << 32)
+}
+
+/// What the built-in table is good for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Built without UNIVERSEK_KSYMS
+    Missing,
+    /// Made from another build, so its addresses are wrong
+    Stale,
+    /// Usable, with this many symbols
+    Ready(usize),
+}
+
+/// State of the built-in table
+pub fn status() -> Status {
+    match Table::parse(embedded()) {
+        None => Status::Missing,
+        Some(table) if table.anchor() != anchor as usize as u64 => Status::Stale,
+        Some(table) => Status::Ready(table.len()),
+    }
+}
+
+/// The built-in table, if it fits this build
+pub fn table() -> Option<Table<'static>> {
+    Table::parse(embedded()).filter(|table| table.anchor() == anchor as usize as u64)
+}
+
+/// The kernel function `address` is in and how far into it, if known
+pub fn lookup(address: u64) -> Option<(&'static str, u64)> {
+    table()?.lookup(address)
+}
+
+/// An address shown as `name+0x1a` when it resolves, else as hex
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(self.0) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// The current function's frame pointer
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let frame: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack)) };
+    frame
+}
+
+/// Call `visit` with each return address on the stack above `frame`,
+/// innermost first, by following saved frame pointers. Stops at a pointer
+/// that doesn't lead up the stack, so a broken chain ends the walk rather
+/// than faulting.
+pub fn walk_stack(mut frame: u64, mut visit: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if frame == 0 || frame % 8 != 0 {
+            break;
+        }
+        let (caller_frame, return_address) = unsafe {
+            (*(frame as *const u64), *((frame + 8) as *const u64))
+        };
+        if return_address == 0 {
+            break;
+        }
+        visit(return_address);
+        // A caller's frame is above this one on the stack, and not far
+        if caller_frame <= frame || caller_frame - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = caller_frame;
+    }
+}
+
+fn write_frame(out: &mut dyn fmt::Write, index: usize, address: u64) -> fmt::Result {
+    match lookup(address) {
+        Some((name, offset)) => writeln!(out, "  #{:<2} {:#018x} {}+{:#x}", index, address, name, offset),
+        None => writeln!(out, "  #{:<2} {:#018x}", index, address),
+    }
+}
+
+/// Write the frames from `frame` up, numbering them from `first`
+fn write_frames(out: &mut dyn fmt::Write, first: usize, frame: u64) -> fmt::Result {
+    let mut result = Ok(());
+    let mut index = first;
+    walk_stack(frame, |address| {
+        if result.is_ok() {
+            result = write_frame(out, index, address);
+        }
+        index += 1;
+    });
+    result
+}
+
+/// Write a backtrace of the caller, a frame a line
+#[inline(always)]
+pub fn write_backtrace(out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(out, "Backtrace:")?;
+    write_frames(out, 0, frame_pointer())
+}
+
+/// Writes straight to the serial port
+struct SerialOut;
+
+impl fmt::Write for SerialOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Print a backtrace of the caller on serial
+#[inline(always)]
+pub fn print_backtrace() {
+    let _ = write_backtrace(&mut SerialOut);
+}
+
+/// Print a backtrace of the code an exception interrupted, which was at
+/// `instruction_pointer`; call straight from the exception handler
+#[inline(always)]
+pub fn print_fault_backtrace(instruction_pointer: u64) {
+    // The handler's saved frame pointer is the interrupted code's; above
+    // it is the exception's stack frame, not a return address
+    let interrupted = unsafe { *(frame_pointer() as *const u64) };
+    let mut out = SerialOut;
+    let _ = writeln!(out, "Backtrace:")
+        .and_then(|()| write_frame(&mut out, 0, instruction_pointer))
+        .and_then(|()| write_frames(&mut out, 1, interrupted));
+}
+
+/// Check lookups on a made-up table, and on the built-in one if it fits
+/// this build
+pub fn self_test() -> Result<(), KernelError> {
+    use alloc::vec::Vec;
+    serial_println!("KSYMS: Running self-test");
+
+    // What build.rs writes, for three symbols, the middle one unsized
+    let symbols: [(u64, u32, &str); 3] = [(0x1000, 0x20, "alpha"), (0x1020, 0, "beta"), (0x3_0000, 0x10, "gamma")];
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&0x1000u64.to_le_bytes());
+    let mut names = Vec::new();
+    for (address, size, name) in symbols {
+        bytes.extend_from_slice(&address.to_le_bytes());
+        bytes.extend_from_slice(&size.to_le_bytes());
+        bytes.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    bytes.extend_from_slice(&names);
+
+    let table = Table::parse(&bytes).ok_or(KernelError::ValidationError("Symbol table didn't parse"))?;
+    let checks: [(u64, Option<(&str, u64)>); 7] = [
+        (0x0fff, None), (0x1000, Some(("alpha", 0))), (0x101a, Some(("alpha", 0x1a))),
+        (0x1020, Some(("beta", 0))), (0x1_101f, Some(("beta", 0xffff))), (0x1_1020, None),
+        (0x3_0010, None),
+    ];
+    for (address, expected) in checks {
+        if table.lookup(address) != expected {
+            serial_println!("KSYMS: {:#x} looked up as {:?}", address, table.lookup(address));
+            return Err(KernelError::ValidationError("Symbol looked up wrongly"));
+        }
+    }
+    if Table::parse(&bytes[..bytes.len() - 1]).is_some() || Table::parse(b"KSYX\0\0\0\0\0\0\0\0\0\0\0\0").is_some() {
+        return Err(KernelError::ValidationError("Broken symbol table accepted"));
+    }
+
+    match status() {
+        Status::Ready(count) => {
+            let found = lookup(self_test as usize as u64);
+            if !found.is_some_and(|(name, offset)| name.ends_with("ksyms::self_test") && offset == 0) {
+                serial_println!("KSYMS: self_test looked up as {:?}", found);
+                return Err(KernelError::ValidationError("Built-in symbol table gave the wrong name"));
+            }
+            serial_println!("KSYMS: {} kernel symbols", count);
+        }
+        Status::Stale => serial_println!("KSYMS: The built-in table is from another build; rebuild it"),
+        Status::Missing => serial_println!("KSYMS: Built without symbols (see UNIVERSEK_KSYMS)"),
+    }
+
+    serial_println!("KSYMS: Self-test passed");
+    Ok(())
+}
@@ -23,188 +23,375 @@ pub mod shell; // Shell/terminal functionality
 pub mod logger; // Logging system
 pub mod config; // Configuration management
 pub mod gui; // GUI subsystem
+pub mod net; // Network stack
+pub mod syscall; // System call interface
+pub mod loader; // ELF program loader
+pub mod sync; // Lock diagnostics
+pub mod time; // Monotonic and wall-clock time
+pub mod boot; // Boot progress splash
+pub mod startup; // Init steps and their ordering
+pub mod text; // Screen width of strings
+pub mod cmdline; // Boot command line
+pub mod safe_mode; // Minimal boot for recovery
+pub mod ksyms; // Kernel symbol names for backtraces
+pub mod i18n; // Translated user-visible text
+pub mod vt; // Switching between the GUI and the console
+pub mod version; // Version and build details
+pub mod faultinject; // Making operations fail on demand for testing
 
 use alloc::format;
 use bootloader::BootInfo;
 use x86_64::VirtAddr;
 use memory::BootInfoFrameAllocator;
+use startup::InitStep;
 
-/// Kernel initialization phases for more structured startup
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum InitPhase {
-    CoreHardware, // Phase 1
-    Memory,       // Phase 2
-    DeviceDrivers,// Phase 3
-    TaskSystem,   // Phase 4 (Moved FS from here)
-    FinalChecks,  // Phase 5 (Moved User Setup from here)
-    Filesystem,   // Phase 6 (New Position)
-    UserSetup,    // Phase 7 (New Position)
-    GuiSetup,     // Phase 8 (New for GUI)
-    Complete,     // Final Phase
+/// State the init steps share
+struct BootContext {
+    boot_info: &'static BootInfo,
+    fs_initialized: bool,
 }
 
-/// Main initialization function called by kernel_main in main.rs
-pub fn init(boot_info: &'static BootInfo) {
-    println!("Starting kernel initialization...");
-    serial_println!("DEBUG: Beginning kernel initialization...");
+/// Kernel start-up, run in dependency order by `startup::run`. Where
+/// dependencies allow, steps run in the order listed.
+static INIT_STEPS: [InitStep<BootContext>; 9] = [
+    InitStep { name: "Core hardware", depends_on: &[], critical: true, run: init_core_hardware },
+    InitStep { name: "Memory", depends_on: &["Core hardware"], critical: true, run: init_memory },
+    InitStep { name: "Device drivers", depends_on: &["Memory"], critical: false, run: init_device_drivers },
+    InitStep { name: "Task system", depends_on: &["Memory"], critical: false, run: init_task_system },
+    InitStep { name: "System services", depends_on: &["Device drivers", "Task system"], critical: false, run: init_system_services },
+    InitStep { name: "File system", depends_on: &["System services"], critical: false, run: init_filesystem },
+    InitStep { name: "User environment", depends_on: &["File system"], critical: false, run: init_user_environment },
+    InitStep { name: "Graphical interface", depends_on: &["System services"], critical: false, run: init_gui },
+    InitStep { name: "Starting up", depends_on: &["User environment", "Graphical interface"], critical: false, run: init_final },
+];
 
-    // ===== PHASE 1: Core Hardware =====
-    let phase = InitPhase::CoreHardware;
-    serial_println!("DEBUG: [INIT Phase {:?}] Initializing core hardware", phase);
+/// Run a subsystem's self-test, warning if it fails. The tests exercise live
+/// global state (the heap, the input queue, files under /tmp), so they only
+/// run in kernels built with the `self_tests` feature.
+fn self_test(name: &str, test: fn() -> Result<(), errors::KernelError>) {
+    if !cfg!(feature = "self_tests") {
+        return;
+    }
+    if let Err(e) = test() {
+        boot::warn(&format!("{} self-test failed: {:?}", name, e));
+    }
+}
+
+/// GDT and interrupt tables
+fn init_core_hardware(_context: &mut BootContext) -> Result<(), errors::KernelError> {
     gdt::init_gdt();
     interrupts::init();
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete", phase);
+    Ok(())
+}
 
-    // ===== PHASE 2: Memory Management =====
-    let phase = InitPhase::Memory;
-    serial_println!("DEBUG: [INIT Phase {:?}] Initializing memory subsystems", phase);
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+/// Page tables, the heap and interrupt stacks
+fn init_memory(context: &mut BootContext) -> Result<(), errors::KernelError> {
+    let phys_mem_offset = VirtAddr::new(context.boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init_page_table(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&context.boot_info.memory_map) };
     match allocator::init_heap(&mut mapper, &mut frame_allocator) {
-        Ok(_) => serial_println!("DEBUG: Heap initialized successfully"),
+        Ok(_) => boot::detail("Heap initialized successfully"),
         Err(e) => panic!("Failed to initialize heap: {:?}", e),
     }
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete", phase);
+    memory::init_globals(mapper, frame_allocator, phys_mem_offset);
+    logger::pstore::init();
+    if let Err(e) = gdt::init_stacks(gdt::DEFAULT_IST_STACK_SIZE) {
+        panic!("Failed to allocate interrupt stacks: {:?}", e);
+    }
+    self_test("GDT", gdt::self_test);
+    self_test("Heap", allocator::self_test);
+    self_test("Memory map", memory::self_test);
+    self_test("Persistent log", logger::pstore::self_test);
+    self_test("Kernel symbol", ksyms::self_test);
+    self_test("Version", version::self_test);
+    self_test("Fault injection", faultinject::self_test);
+    Ok(())
+}
 
-    // ===== PHASE 3: Device Drivers =====
-    let phase = InitPhase::DeviceDrivers;
-    serial_println!("DEBUG: [INIT Phase {:?}] Initializing device drivers", phase);
+/// The boot command line, then devices, drivers and timekeeping
+fn init_device_drivers(_context: &mut BootContext) -> Result<(), errors::KernelError> {
+    cmdline::init();
+    if let Err(e) = drivers::vga_enhanced::init() {
+        boot::warn(&format!("VGA initialization failed: {:?}", e));
+    }
+    safe_mode::init();
+    self_test("Command line", cmdline::self_test);
     if let Err(e) = device::init() {
-        serial_println!("DEBUG: Warning: Device driver initialization failed: {:?}", e);
+        boot::warn(&format!("Device driver initialization failed: {:?}", e));
     }
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete", phase);
+    self_test("VGA", drivers::vga_enhanced::self_test);
+    self_test("Text width", text::self_test);
+    self_test("Path", fs::path::self_test);
+    time::init();
+    self_test("Timekeeping", time::self_test);
+    self_test("RTC", drivers::rtc::self_test);
+    Ok(())
+}
 
-    // ===== PHASE 4: Task System =====
-    let phase = InitPhase::TaskSystem;
-    serial_println!("DEBUG: [INIT Phase {:?}] Initializing task scheduler", phase);
+/// The scheduler
+fn init_task_system(_context: &mut BootContext) -> Result<(), errors::KernelError> {
     task::scheduler::init();
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete", phase);
+    #[cfg(feature = "heap_debug")]
+    if let Err(e) = allocator::start_periodic_check() {
+        boot::warn(&format!("Periodic heap check not started: {:?}", e));
+    }
+    Ok(())
+}
 
-    // ===== PHASE 5: Final Checks =====
-    let phase = InitPhase::FinalChecks;
-    serial_println!("DEBUG: [INIT Phase {:?}] Performing final system checks", phase);
+/// Logging, configuration, diagnostics, caches and networking
+fn init_system_services(_context: &mut BootContext) -> Result<(), errors::KernelError> {
     if let Err(e) = logger::init() {
-        serial_println!("DEBUG: Warning: Failed to initialize logging system: {:?}", e);
+        boot::warn(&format!("Failed to initialize logging system: {:?}", e));
     }
     if let Err(e) = config::init() {
-        serial_println!("DEBUG: Warning: Failed to initialize configuration system: {:?}", e);
+        boot::warn(&format!("Failed to initialize configuration system: {:?}", e));
+    }
+    boot::configure();
+    logger::configure();
+    drivers::ps2_keyboard::configure();
+    drivers::ps2_mouse::configure();
+    self_test("Config", config::self_test);
+    self_test("Log rate limit", logger::ratelimit::self_test);
+    self_test("Init step", startup::self_test);
+    sync::init();
+    self_test("Lock diagnostics", sync::self_test);
+    self_test("Interrupt context", interrupts::self_test);
+    task::watchdog::init();
+    self_test("Watchdog", task::watchdog::self_test);
+    fs::block_adapter::init();
+    self_test("Block cache", fs::block_adapter::self_test);
+    self_test("I/O statistics", device::iostats::self_test);
+    self_test("Scheduler trace", task::trace::self_test);
+    task::idle::init();
+    self_test("Idle loop", task::idle::self_test);
+    i18n::init();
+    self_test("Localization", i18n::self_test);
+    self_test("Device power management", device::self_test);
+    self_test("Memory device", device::memdev::self_test);
+    match net::init() {
+        Ok(_) => {},
+        Err(errors::KernelError::DeviceNotFound) => boot::detail("No network card, networking disabled"),
+        Err(e) => boot::warn(&format!("Failed to initialize network: {:?}", e)),
     }
     if let Err(e) = errors::perform_system_checks() {
         errors::report_error(&e, false);
-        serial_println!("DEBUG: WARNING: System check failed but continuing boot process");
+        boot::warn("System check failed but continuing boot process");
     }
     interrupts::configure_for_operation(); // Configure which IRQs are active
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete", phase);
-
-    // ===== PHASE 6: File System (MOVED HERE) =====
-    let phase = InitPhase::Filesystem;
-    serial_println!("DEBUG: [INIT Phase {:?}] Initializing file system (standard mode)", phase);
-    let fs_initialized = match fs::init() {
-        Ok(_) => {
-            serial_println!("DEBUG: File system initialized successfully.");
-            true
-        },
-        Err(e) => {
-            serial_println!("DEBUG: Warning: File system initialization failed: {:?}", e);
-            false
-        },
-    };
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete (fs_initialized: {})", phase, fs_initialized);
-
-    // ===== PHASE 7: User Setup (MOVED HERE) =====
-    let phase = InitPhase::UserSetup;
-    serial_println!("DEBUG: [INIT Phase {:?}] Setting up user environment", phase);
+    Ok(())
+}
+
+/// Mount the root file system and load the saved settings from it; fails
+/// if there is none
+fn init_filesystem(context: &mut BootContext) -> Result<(), errors::KernelError> {
+    fs::init()?;
+    boot::detail("File system initialized successfully.");
+    context.fs_initialized = true;
+    // The saved settings may be what needs safe mode to get around
+    if safe_mode::is_forced() {
+        safe_mode::skip("saved settings");
+    } else if let Err(e) = config::load_saved() {
+        boot::warn(&format!("Couldn't load the saved settings: {:?}", e));
+    }
+    safe_mode::configure();
+    Ok(())
+}
+
+/// Users and their directories, then the self-tests that need them
+fn init_user_environment(context: &mut BootContext) -> Result<(), errors::KernelError> {
     if let Err(e) = user::init() {
-        serial_println!("DEBUG: Warning: Failed to initialize user management: {:?}", e);
+        boot::warn(&format!("Failed to initialize user management: {:?}", e));
     }
     // Attempt initial filesystem structure setup only if FS is initialized
-    if fs_initialized {
+    if context.fs_initialized {
         if let Err(e) = user::setup_filesystem() {
-            serial_println!("DEBUG: Warning: Failed to setup filesystem structure: {:?}", e);
+            boot::warn(&format!("Failed to setup filesystem structure: {:?}", e));
         } else {
-            serial_println!("DEBUG: Filesystem structure created successfully.");
+            boot::detail("Filesystem structure created successfully.");
+        }
+        if let Err(e) = user::auto_login() {
+            boot::warn(&format!("Automatic login failed: {:?}", e));
+        }
+        if let Err(e) = logger::pstore::save_last_boot() {
+            boot::warn(&format!("Could not save the previous boot's log: {:?}", e));
         }
     } else {
-        serial_println!("DEBUG: Skipping filesystem structure setup as FS is not initialized.");
+        boot::detail("Skipping filesystem structure setup as FS is not initialized.");
+    }
+    self_test("System call", syscall::self_test);
+    self_test("History", shell::history::self_test);
+    self_test("Shell tokenizer", shell::parse::self_test);
+    self_test("Shell command registry", shell::commands::self_test);
+    self_test("Key repeat", drivers::key_repeat::self_test);
+    self_test("Mouse", drivers::ps2_mouse::self_test);
+    self_test("Input injection", drivers::input::self_test);
+    self_test("Shell", shell::self_test);
+    self_test("ls", shell::ls::self_test);
+    self_test("motd", user::motd::self_test);
+    self_test("Skeleton", user::skel::self_test);
+    self_test("Password", user::password::self_test);
+    self_test("bench", shell::bench::self_test);
+    self_test("at", shell::at::self_test);
+    self_test("Pipe", fs::pipe::self_test);
+    self_test("RamDisk", fs::ramdisk::self_test);
+    self_test("dd", fs::dd::self_test);
+    self_test("FAT", fs::fat::self_test);
+    self_test("ISO9660", fs::iso9660::self_test);
+    self_test("SFS", fs::simple_fs::self_test);
+    self_test("TempFS", fs::tempfs::self_test);
+    self_test("Watch", fs::watch::self_test);
+    self_test("Directory walk", fs::walk::self_test);
+    self_test("User mode", task::user_mode::self_test);
+    if context.fs_initialized {
+        self_test("VFS", fs::vfs::self_test);
+        self_test("Trash", fs::trash::self_test);
+        self_test("Jail", fs::jail::self_test);
+        self_test("Config backup", config::backup_self_test);
+        self_test("File descriptor", fs::fd::self_test);
+        self_test("Device file", fs::devfs::self_test);
+        self_test("File type", fs::filetype::self_test);
+        self_test("ELF loader", loader::self_test);
     }
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete", phase);
+    Ok(())
+}
 
-    // ===== PHASE 8: GUI Setup (NEW) =====
-    let phase = InitPhase::GuiSetup;
-    serial_println!("DEBUG: [INIT Phase {:?}] Initializing GUI subsystem", phase);
-    match gui::init() {
-        Ok(_) => serial_println!("DEBUG: GUI subsystem initialized successfully"),
-        Err(e) => serial_println!("DEBUG: Warning: GUI initialization failed: {:?}", e),
+/// The GUI and its self-tests, unless in safe mode; fails if the GUI
+/// can't start
+fn init_gui(_context: &mut BootContext) -> Result<(), errors::KernelError> {
+    if safe_mode::is_active() {
+        safe_mode::skip("GUI");
+        return Ok(());
     }
-    serial_println!("DEBUG: [INIT Phase {:?}] Complete", phase);
+    if !drivers::vga_enhanced::display_available() {
+        boot::detail("No display, so no GUI; the shell runs on the serial console");
+        return Ok(());
+    }
+    // The self-tests run whether or not the GUI came up
+    let result = gui::init();
+    if result.is_ok() {
+        boot::detail("GUI subsystem initialized successfully");
+    }
+    self_test("Wallpaper", gui::wallpaper::self_test);
+    self_test("Window", gui::window::self_test);
+    self_test("Clipboard", gui::clipboard::self_test);
+    self_test("Text box", gui::textbox::self_test);
+    self_test("Notification", gui::notify::self_test);
+    self_test("Screensaver", gui::screensaver::self_test);
+    self_test("Run dialog", gui::run_dialog::self_test);
+    self_test("Calculator", gui::calculator::self_test);
+    self_test("Settings", gui::settings::self_test);
+    self_test("System monitor", gui::sysmon::self_test);
+    self_test("Compositor", gui::compositor::self_test);
+    self_test("Cursor", gui::cursor::self_test);
+    self_test("Session", gui::session::self_test);
+    self_test("GUI recorder", gui::recorder::self_test);
+    self_test("Screenshot", gui::screenshot::self_test);
+    self_test("GUI keyboard", gui::events::self_test);
+    self_test("Mode switch", vt::self_test);
+    result
+}
 
-    // ===== COMPLETE =====
-    let phase = InitPhase::Complete;
-    serial_println!("DEBUG: [INIT Phase {:?}] Kernel initialization complete", phase);
-    println!("Kernel initialization complete!");
+/// Final status messages and interrupt controller set-up
+fn init_final(context: &mut BootContext) -> Result<(), errors::KernelError> {
 
     // Log final status messages
     logger::info("kernel", "UniverseK OS initialized");
     logger::info("kernel", &format!("Heap size: {} KB", allocator::HEAP_SIZE / 1024));
-    if fs_initialized {
+    if context.fs_initialized {
         logger::info("kernel", "File system: Ready");
     } else {
         logger::warning("kernel", "File system: Not initialized");
     }
 
     // Enable CPU interrupts - this allows the configured device IRQs to be processed
-    serial_println!("DEBUG: About to enable CPU interrupts");
+    boot::detail("About to enable CPU interrupts");
     
     // Initialize PIC and configure interrupts
     unsafe {
         // First disable CPU interrupts during initialization
         x86_64::instructions::interrupts::disable();
-        serial_println!("DEBUG: CPU interrupts disabled during initialization");
+        boot::detail("CPU interrupts disabled during initialization");
         
         // Initialize PICs with all interrupts masked
         interrupts::pic::PIC_CONTROLLER.lock().initialize();
-        serial_println!("DEBUG: PIC initialization complete");
+        boot::detail("PIC initialization complete");
         
         // Configure specific interrupts we want to handle
         // Only enable timer (IRQ0) initially
         interrupts::pic::PIC_CONTROLLER.lock().configure_irqs(0b11111110u8, 0b11111111u8);
-        serial_println!("DEBUG: IRQs configured - Timer only enabled");
+        boot::detail("IRQs configured - Timer only enabled");
         
         // Make sure IDT is properly set up
-        serial_println!("DEBUG: Verifying IDT setup");
+        boot::detail("Verifying IDT setup");
         interrupts::init_idt();
-        serial_println!("DEBUG: IDT setup verified");
+        boot::detail("IDT setup verified");
         
         // Now enable CPU interrupts
-        serial_println!("DEBUG: Enabling CPU interrupts");
+        boot::detail("Enabling CPU interrupts");
         // x86_64::instructions::interrupts::enable();
-        serial_println!("DEBUG: CPU interrupts enabled");
+        boot::detail("CPU interrupts enabled");
         
         // Add a small delay to let any pending interrupts clear
         for _ in 0..1000 {
             x86_64::instructions::nop();
         }
-        serial_println!("DEBUG: Initial delay complete");
+        boot::detail("Initial delay complete");
     }
+    Ok(())
+}
 
-    // Start the GUI (which includes shell window)
-    serial_println!("DEBUG: Starting GUI");
-    match gui::run() {
-        Ok(_) => serial_println!("DEBUG: GUI exited normally"),
-        Err(e) => serial_println!("ERROR: Error running GUI: {:?}", e),
+/// Main initialization function called by kernel_main in main.rs
+pub fn init(boot_info: &'static BootInfo) {
+    serial_println!("DEBUG: Beginning kernel initialization...");
+    let mut context = BootContext { boot_info, fs_initialized: false };
+    match startup::run(&INIT_STEPS, &mut context, true) {
+        Ok(report) => startup::publish(&report),
+        Err((_, e)) => panic!("Kernel initialization failed: {:?}", e),
     }
+    let _ = fs::procfs::register("boot", startup::boot_text);
+    boot::finish();
 
-    // GUI has exited, so enter the kernel main loop
-    serial_println!("DEBUG: Entering kernel main loop");
-    let mut counter = 0;
-    loop {
-        counter += 1;
-        if counter % 100_000_000 == 0 {
-            serial_println!("Kernel main loop heartbeat ({} iterations)", counter / 100_000_000);
-        }
-        x86_64::instructions::hlt(); // Wait for the next interrupt
+    // The GUI, or the full-screen shell if boot.start_gui is off; safe mode
+    // has only the shell, and headless the shell is on the serial console
+    vt::init();
+    boot::detail(match vt::current() {
+        vt::Mode::Gui => "Starting GUI",
+        vt::Mode::Console => "Starting shell",
+    });
+    vt::run();
+
+    // Nothing is left running, so the kernel has nothing left to do but idle
+    task::idle::run()
+}
+
+/// Restart the machine through the 8042 keyboard controller
+pub fn reboot() -> ! {
+    unsafe {
+        use x86_64::instructions::port::Port;
+        let mut port = Port::new(0x64);
+        port.write(0xFE as u8);
+    }
+    // The reset didn't take
+    hlt_loop();
+}
+
+/// Suspend every device, so disks flush their caches, then switch the
+/// machine off. Without ACPI tables to read, this uses the fixed power-off
+/// ports QEMU, Bochs and VirtualBox provide; elsewhere it just halts.
+pub fn power_off() -> ! {
+    let report = device::suspend_all();
+    for (name, e) in &report.failed {
+        serial_println!("DEBUG: Warning: {} didn't suspend before power-off: {:?}", name, e);
+    }
+    unsafe {
+        use x86_64::instructions::port::Port;
+        Port::<u16>::new(0x604).write(0x2000); // QEMU
+        Port::<u16>::new(0xB004).write(0x2000); // Bochs, older QEMU
+        Port::<u16>::new(0x4004).write(0x3400); // VirtualBox
     }
+    serial_println!("DEBUG: Power-off didn't take; halting");
+    x86_64::instructions::interrupts::disable();
+    hlt_loop();
 }
 
 /// Basic halt loop
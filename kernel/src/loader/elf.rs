@@ -0,0 +1,135 @@
+// kernel/src/loader/elf.rs
+//! ELF64 header and program header parsing
+
+use alloc::vec::Vec;
+use crate::errors::KernelError;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 0x3E;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Object file types
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+/// Program header types
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+
+/// Segment permission flags
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+/// The fields of the ELF header the loader uses
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub elf_type: u16,
+    pub machine: u16,
+    pub entry: u64,
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+}
+
+/// A program header (segment descriptor)
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Parse and validate the ELF header of a static x86_64 executable
+pub fn parse_header(data: &[u8]) -> Result<ElfHeader, KernelError> {
+    if data.len() < ELF_HEADER_SIZE || data[0..4] != ELF_MAGIC {
+        return Err(KernelError::ValidationError("Not an ELF file"));
+    }
+    if data[4] != ELFCLASS64 {
+        return Err(KernelError::ValidationError("Only 64-bit ELF files are supported"));
+    }
+    if data[5] != ELFDATA2LSB {
+        return Err(KernelError::ValidationError("Only little-endian ELF files are supported"));
+    }
+
+    let header = ElfHeader {
+        elf_type: u16_at(data, 16),
+        machine: u16_at(data, 18),
+        entry: u64_at(data, 24),
+        phoff: u64_at(data, 32),
+        phentsize: u16_at(data, 54),
+        phnum: u16_at(data, 56),
+    };
+
+    if header.machine != EM_X86_64 {
+        return Err(KernelError::ValidationError("ELF file is not for x86_64"));
+    }
+    match header.elf_type {
+        ET_EXEC => {}
+        ET_DYN => return Err(KernelError::ValidationError(
+            "Dynamically linked executables (ET_DYN) are not supported")),
+        _ => return Err(KernelError::ValidationError("ELF file is not an executable")),
+    }
+    if header.phentsize as usize != PROGRAM_HEADER_SIZE {
+        return Err(KernelError::ValidationError("Unexpected ELF program header size"));
+    }
+
+    Ok(header)
+}
+
+/// Read the program header table
+pub fn program_headers(data: &[u8], header: &ElfHeader) -> Result<Vec<ProgramHeader>, KernelError> {
+    let table_size = header.phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+    let table_end = header.phoff.checked_add(table_size)
+        .ok_or(KernelError::ValidationError("ELF program headers out of range"))?;
+    if table_end > data.len() as u64 {
+        return Err(KernelError::ValidationError("ELF program headers out of range"));
+    }
+
+    let mut headers = Vec::with_capacity(header.phnum as usize);
+    for i in 0..header.phnum as usize {
+        let base = header.phoff as usize + i * PROGRAM_HEADER_SIZE;
+        headers.push(ProgramHeader {
+            p_type: u32_at(data, base),
+            flags: u32_at(data, base + 4),
+            offset: u64_at(data, base + 8),
+            vaddr: u64_at(data, base + 16),
+            filesz: u64_at(data, base + 32),
+            memsz: u64_at(data, base + 40),
+        });
+    }
+    Ok(headers)
+}
@@ -0,0 +1,160 @@
+// kernel/src/loader/mod.rs
+//! Program loader
+//! Loads static ELF64 executables from the VFS into a fresh user address
+//! space and runs them in ring 3.
+
+pub mod elf;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+use crate::errors::KernelError;
+use crate::fs;
+use crate::serial_println;
+use crate::task::user_mode::{self, AddressSpace, USER_CODE_BASE, USER_STACK_BOTTOM, USER_STACK_TOP};
+use elf::{PT_INTERP, PT_LOAD};
+
+/// Largest executable file accepted
+const MAX_EXECUTABLE_SIZE: u64 = 1024 * 1024;
+/// Most of the stack argv may take, leaving the rest for the program
+const MAX_ARGS_SIZE: u64 = 4096;
+
+/// An executable ready to run
+pub struct LoadedImage {
+    pub space: AddressSpace,
+    pub entry: VirtAddr,
+}
+
+/// Read a whole file through the VFS
+fn read_file(path: &str) -> Result<Vec<u8>, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let metadata = vfs.metadata(path)?;
+    if metadata.node_type == fs::vfs::NodeType::Directory {
+        return Err(KernelError::IsADirectory);
+    }
+    if metadata.size > MAX_EXECUTABLE_SIZE {
+        return Err(KernelError::ValidationError("Executable too large"));
+    }
+
+    let mut data = vec![0u8; metadata.size as usize];
+    let read = fs::direct_read_file(path, &mut data)?;
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Map the PT_LOAD segments of an ELF image into a new address space
+pub fn load_image(data: &[u8]) -> Result<LoadedImage, KernelError> {
+    let header = elf::parse_header(data)?;
+    let segments = elf::program_headers(data, &header)?;
+
+    if segments.iter().any(|ph| ph.p_type == PT_INTERP) {
+        return Err(KernelError::ValidationError("Executables needing an interpreter are not supported"));
+    }
+
+    let mut space = AddressSpace::new()?;
+    let mut entry_mapped = false;
+
+    for segment in segments.iter().filter(|ph| ph.p_type == PT_LOAD && ph.memsz > 0) {
+        let end = segment.vaddr.checked_add(segment.memsz)
+            .ok_or(KernelError::ValidationError("ELF segment out of range"))?;
+        if segment.vaddr < USER_CODE_BASE || end > USER_STACK_BOTTOM {
+            return Err(KernelError::ValidationError("ELF segment outside the user address range"));
+        }
+        if segment.filesz > segment.memsz
+            || segment.offset.checked_add(segment.filesz).map_or(true, |e| e > data.len() as u64)
+        {
+            return Err(KernelError::ValidationError("ELF segment data out of range"));
+        }
+
+        // Pages start zeroed, which also fills the BSS past filesz
+        space.map_user(segment.vaddr, segment.memsz, segment.is_writable(), segment.is_executable())?;
+        let file_data = &data[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+        space.write(segment.vaddr, file_data)?;
+
+        if segment.is_executable() && (segment.vaddr..end).contains(&header.entry) {
+            entry_mapped = true;
+        }
+    }
+
+    if !entry_mapped {
+        return Err(KernelError::ValidationError("ELF entry point is not in an executable segment"));
+    }
+
+    space.map_stack()?;
+    Ok(LoadedImage { space, entry: VirtAddr::new(header.entry) })
+}
+
+/// Build the initial stack (argc, argv[], NULL, envp NULL, auxv AT_NULL) and
+/// return the stack pointer the program starts with
+fn setup_stack(space: &mut AddressSpace, args: &[&str]) -> Result<VirtAddr, KernelError> {
+    let strings_size: u64 = args.iter().map(|arg| arg.len() as u64 + 1).sum();
+    // argc + argv pointers + argv NULL + envp NULL + AT_NULL pair
+    let words = 1 + args.len() as u64 + 1 + 1 + 2;
+    if strings_size + words * 8 + 16 > MAX_ARGS_SIZE {
+        return Err(KernelError::ValidationError("Argument list too long"));
+    }
+
+    let strings_start = (USER_STACK_TOP - strings_size) & !0xF;
+    let mut string_addr = strings_start;
+    let mut argv = Vec::with_capacity(args.len());
+    for arg in args {
+        space.write(string_addr, arg.as_bytes())?;
+        space.write(string_addr + arg.len() as u64, &[0])?;
+        argv.push(string_addr);
+        string_addr += arg.len() as u64 + 1;
+    }
+
+    let mut table: Vec<u64> = Vec::with_capacity(words as usize);
+    table.push(args.len() as u64);
+    table.extend_from_slice(&argv);
+    table.extend_from_slice(&[0, 0, 0, 0]);
+
+    // The SysV ABI wants rsp 16-byte aligned at the entry point
+    let rsp = (strings_start - words * 8) & !0xF;
+    let bytes: Vec<u8> = table.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.write(rsp, &bytes)?;
+    Ok(VirtAddr::new(rsp))
+}
+
+/// Load the executable at `path` and run it with `args` (args[0] is the
+/// program name), returning its exit code
+pub fn exec(path: &str, args: &[&str]) -> Result<i64, KernelError> {
+    serial_println!("LOADER: exec {} {:?}", path, args);
+    let data = read_file(path)?;
+    let mut image = load_image(&data)?;
+    let stack = setup_stack(&mut image.space, args)?;
+    user_mode::run_in(&image.space, image.entry, stack)
+}
+
+/// Run /bin/hello and check its output and exit code, and that ET_DYN
+/// images are rejected
+pub fn self_test() -> Result<(), KernelError> {
+    const TEST_BINARY: &str = "/bin/hello";
+    const EXPECTED_OUTPUT: &str = "Hello from /bin/hello!\none\ntwo\n";
+
+    serial_println!("LOADER: Running self-test");
+
+    crate::syscall::start_capture();
+    let result = exec(TEST_BINARY, &[TEST_BINARY, "one", "two"]);
+    let output = crate::syscall::take_capture();
+
+    let exit_code = result?;
+    if output != EXPECTED_OUTPUT {
+        serial_println!("LOADER: Unexpected output {:?}", output);
+        return Err(KernelError::ValidationError("Test binary produced the wrong output"));
+    }
+    if exit_code != 2 {
+        serial_println!("LOADER: Unexpected exit code {}", exit_code);
+        return Err(KernelError::ValidationError("Test binary exited with the wrong code"));
+    }
+
+    // Same image marked as a shared object must be refused
+    let mut dynamic = read_file(TEST_BINARY)?;
+    dynamic[16..18].copy_from_slice(&elf::ET_DYN.to_le_bytes());
+    if load_image(&dynamic).is_ok() {
+        return Err(KernelError::ValidationError("ET_DYN image was accepted"));
+    }
+
+    serial_println!("LOADER: Self-test passed");
+    Ok(())
+}
@@ -1,14 +1,40 @@
 //! Logging system for UniverseK OS
 //! Provides different log levels and output targets
+//!
+//! `log.level` sets the lowest level printed. Serial output is rate limited
+//! (see `ratelimit`); `kdebug!` skips even formatting its message unless
+//! Debug messages are wanted, so it can stay on hot paths.
+
+pub mod pstore;
+pub mod ratelimit;
 
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::format;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::serial_println;
 use crate::drivers::vga_enhanced::{self, Color};
+use ratelimit::{RateLimiter, SerialStats};
+
+/// Serial messages a second per module when the configuration doesn't say
+const DEFAULT_SERIAL_RATE: u32 = 200;
+
+/// Whether Debug messages pass the minimum level, for `kdebug!`
+static DEBUG_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Log a Debug message for `module`, formatted like `format!`. Costs one
+/// atomic load unless `log.level` is "debug".
+#[macro_export]
+macro_rules! kdebug {
+    ($module:expr, $($arg:tt)*) => {
+        if $crate::logger::debug_enabled() {
+            $crate::logger::debug_fmt($module, format_args!($($arg)*));
+        }
+    };
+}
 
 /// Log levels
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,6 +47,18 @@ pub enum LogLevel {
 }
 
 impl LogLevel {
+    /// Parse a level name as used in the configuration
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warning" => Some(LogLevel::Warning),
+            "error" => Some(LogLevel::Error),
+            "critical" => Some(LogLevel::Critical),
+            _ => None,
+        }
+    }
+
     /// Convert log level to string
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -62,16 +100,14 @@ pub struct LogEntry {
     pub module: String,
     /// Log message
     pub message: String,
-    /// Timestamp (seconds since boot)
+    /// Timestamp (milliseconds since boot)
     pub timestamp: u64,
 }
 
 impl LogEntry {
     /// Create a new log entry
     pub fn new(level: LogLevel, module: &str, message: &str) -> Self {
-        // Get timestamp (for now just use a placeholder)
-        // In a real implementation, we would get this from a timer
-        let timestamp = 0; // Placeholder
+        let timestamp = crate::time::monotonic_ms();
         
         Self {
             level,
@@ -102,6 +138,8 @@ pub struct Logger {
     log_buffer: Vec<LogEntry>,
     /// Maximum log buffer size
     max_buffer_size: usize,
+    /// Holds back repeats and floods on the way to serial
+    serial_limiter: RateLimiter,
 }
 
 impl Logger {
@@ -112,12 +150,14 @@ impl Logger {
             target: LogTarget::Both,
             log_buffer: Vec::new(),
             max_buffer_size: 1000,
+            serial_limiter: RateLimiter::new(DEFAULT_SERIAL_RATE, true),
         }
     }
     
     /// Set minimum log level
     pub fn set_min_level(&mut self, level: LogLevel) {
         self.min_level = level;
+        DEBUG_ENABLED.store(level == LogLevel::Debug, Ordering::Relaxed);
     }
     
     /// Set log target
@@ -150,20 +190,26 @@ impl Logger {
             return;
         }
         
-        // Create log entry
+        // Create log entry; what the buffer keeps is counted as the logger's
+        let _tag = crate::allocator::tag("logger");
         let entry = LogEntry::new(level, module, message);
         let formatted = entry.format();
         
-        // Output to selected targets
-        match self.target {
+        // Output to selected targets; with no display the screen is left out
+        let target = match self.target {
+            LogTarget::Screen if !vga_enhanced::display_available() => LogTarget::Memory,
+            LogTarget::Both if !vga_enhanced::display_available() => LogTarget::Serial,
+            target => target,
+        };
+        match target {
             LogTarget::Serial => {
-                serial_println!("{}", formatted);
+                self.log_to_serial(&entry, &formatted);
             },
             LogTarget::Screen => {
                 self.log_to_screen(&entry);
             },
             LogTarget::Both => {
-                serial_println!("{}", formatted);
+                self.log_to_serial(&entry, &formatted);
                 self.log_to_screen(&entry);
             },
             LogTarget::Memory => {
@@ -172,16 +218,48 @@ impl Logger {
         }
         
         // Store in memory buffer
+        self.store(entry);
+    }
+    
+    /// Keep an entry in the memory buffer, dropping the oldest when full.
+    /// Warnings and worse also go to the persistent log.
+    fn store(&mut self, entry: LogEntry) {
+        if matches!(entry.level, LogLevel::Warning | LogLevel::Error | LogLevel::Critical) {
+            pstore::append(&entry.format());
+        }
         self.log_buffer.push(entry);
-        
-        // Trim buffer if needed
         if self.log_buffer.len() > self.max_buffer_size {
             self.log_buffer.remove(0);
         }
     }
     
-    /// Output to screen
+    /// Output to serial, unless the rate limiter holds it back
+    fn log_to_serial(&mut self, entry: &LogEntry, formatted: &str) {
+        // The clock only runs with interrupts on
+        let now = if x86_64::instructions::interrupts::are_enabled() {
+            Some(crate::time::monotonic_ns())
+        } else {
+            None
+        };
+        let admission = self.serial_limiter.admit(entry.level, &entry.module, &entry.message, now);
+        if let Some(repeated) = admission.repeated {
+            serial_println!("{}", repeated);
+        }
+        if admission.print {
+            serial_println!("{}", formatted);
+        }
+    }
+    
+    /// Output to screen. Under the GUI line 24 is the taskbar, so errors
+    /// go on its System button instead and lesser messages aren't shown.
     fn log_to_screen(&self, entry: &LogEntry) {
+        if crate::gui::is_initialized() {
+            if matches!(entry.level, LogLevel::Error | LogLevel::Critical) {
+                crate::gui::notify(None, &format!("{}: {}", entry.level.as_str(), entry.message));
+            }
+            return;
+        }
+        
         // For now, just write to bottom of screen
         // In a real implementation, this would scroll a log area
         
@@ -191,11 +269,7 @@ impl Logger {
         }
         
         // Write the log message, trimmed to fit on one line
-        let mut msg = entry.format();
-        if msg.len() > 79 {
-            msg.truncate(76);
-            msg.push_str("...");
-        }
+        let msg = crate::text::ellipsize(&entry.format(), 79);
         
         vga_enhanced::write_at(24, 0, &msg, entry.level.color(), Color::Black);
     }
@@ -225,11 +299,54 @@ pub fn init() -> Result<(), crate::errors::KernelError> {
     Ok(())
 }
 
+/// Read the `log.` settings once the configuration is loaded, and again
+/// whenever they change
+pub fn configure() {
+    reload("log.");
+    crate::config::subscribe("log.", reload);
+}
+
+fn reload(_key: &str) {
+    // Safe mode logs everything, whatever the settings say
+    let level = if crate::safe_mode::is_active() {
+        LogLevel::Debug
+    } else {
+        crate::config::get("log.level")
+            .and_then(|value| value.try_as_string().and_then(|name| LogLevel::parse(name)))
+            .unwrap_or(LogLevel::Info)
+    };
+    let rate = crate::config::get("log.serial_rate")
+        .and_then(|value| value.try_as_integer())
+        .filter(|rate| *rate >= 0)
+        .map_or(DEFAULT_SERIAL_RATE, |rate| rate.min(u32::MAX as i64) as u32);
+    let dedup = crate::config::get("log.serial_dedup")
+        .and_then(|value| value.try_as_boolean())
+        .unwrap_or(true);
+    let mut logger = LOGGER.lock();
+    logger.set_min_level(level);
+    logger.serial_limiter.configure(rate, dedup);
+}
+
+/// Log at `level` and above, until the `log.` settings next change
+pub fn set_min_level(level: LogLevel) {
+    LOGGER.lock().set_min_level(level);
+}
+
+/// Whether Debug messages are wanted at all
+pub fn debug_enabled() -> bool {
+    DEBUG_ENABLED.load(Ordering::Relaxed)
+}
+
 /// Log a debug message
 pub fn debug(module: &str, message: &str) {
     LOGGER.lock().log(LogLevel::Debug, module, message);
 }
 
+/// Log a debug message from format arguments; see `kdebug!`
+pub fn debug_fmt(module: &str, args: fmt::Arguments) {
+    debug(module, &format!("{}", args));
+}
+
 /// Log an info message
 pub fn info(module: &str, message: &str) {
     LOGGER.lock().log(LogLevel::Info, module, message);
@@ -245,11 +362,51 @@ pub fn error(module: &str, message: &str) {
     LOGGER.lock().log(LogLevel::Error, module, message);
 }
 
+/// Add an entry to the log buffer without printing it, whatever the
+/// minimum level
+pub fn record(level: LogLevel, module: &str, message: &str) {
+    LOGGER.lock().store(LogEntry::new(level, module, message));
+}
+
+/// Add an entry to the log buffer without printing it, unless the logger
+/// is busy. For interrupt handlers, which mustn't wait for the lock.
+pub fn try_record(level: LogLevel, module: &str, message: &str) -> bool {
+    match LOGGER.try_lock() {
+        Some(mut logger) => {
+            logger.store(LogEntry::new(level, module, message));
+            true
+        },
+        None => false,
+    }
+}
+
 /// Log a critical message
 pub fn critical(module: &str, message: &str) {
     LOGGER.lock().log(LogLevel::Critical, module, message);
 }
 
+/// The newest `count` entries in the log buffer, oldest first
+pub fn recent(count: usize) -> Vec<LogEntry> {
+    let logger = LOGGER.lock();
+    let entries = logger.get_entries();
+    entries[entries.len().saturating_sub(count)..].to_vec()
+}
+
+/// Empty the log buffer
+pub fn clear() {
+    LOGGER.lock().clear();
+}
+
+/// What the serial rate limiter has printed, collapsed and dropped. Ends
+/// any run of repeats, so its count shows up in the log.
+pub fn serial_stats() -> SerialStats {
+    let mut logger = LOGGER.lock();
+    if let Some(repeated) = logger.serial_limiter.take_repeats() {
+        serial_println!("{}", repeated);
+    }
+    logger.serial_limiter.stats()
+}
+
 // Implement for format! support
 impl fmt::Write for Logger {
     fn write_str(&mut self, s: &str) -> fmt::Result {
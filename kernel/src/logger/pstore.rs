@@ -0,0 +1,263 @@
+//! Crash-persistent log (pstore)
+//!
+//! The memory module sets aside a few pages of RAM at the same physical
+//! address every boot. They hold a ring buffer of Warning-and-worse log lines
+//! and any panic report, behind a header with a magic number and checksum.
+//! A warm reboot (a triple fault, the watchdog, QEMU's system_reset) leaves
+//! RAM alone, so the next boot finds the last boot's final words there. It
+//! keeps a copy for /proc/pstore and /var/log/lastboot.log and starts the
+//! region afresh.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"UKPSTORE");
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 32;
+/// Where the last boot's log is saved once the file system is up
+pub const LAST_BOOT_LOG: &str = "/var/log/lastboot.log";
+
+/// Kernel virtual address and length of the region; zero until `init`
+static BASE: AtomicU64 = AtomicU64::new(0);
+static SIZE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Serializes writers; the panic handler goes ahead without it if it has to
+    static ref WRITER: Mutex<()> = Mutex::new(());
+    /// The previous boot's log, found at init
+    static ref LAST_BOOT: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+}
+
+fn fnv1a(hash: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The region's bytes: a header, then a ring buffer of text.
+///
+/// Header: magic (8 bytes), version, capacity, head (next write offset),
+/// length (bytes held, at most capacity) and a checksum over the other
+/// fields and the bytes held, each 4 bytes little-endian.
+struct Ring<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> Ring<'a> {
+    fn capacity(&self) -> usize {
+        self.bytes.len() - HEADER_SIZE
+    }
+
+    fn head(&self) -> usize {
+        read_u32(self.bytes, 16) as usize
+    }
+
+    fn len(&self) -> usize {
+        read_u32(self.bytes, 20) as usize
+    }
+
+    fn checksum(&self) -> u32 {
+        let fields = fnv1a(0x811C_9DC5, &self.bytes[8..24]);
+        fnv1a(fields, &self.bytes[HEADER_SIZE..HEADER_SIZE + self.len()])
+    }
+
+    /// Whether the header is intact and the checksum matches
+    fn is_valid(&self) -> bool {
+        self.bytes.len() > HEADER_SIZE
+            && self.bytes[..8] == MAGIC.to_le_bytes()
+            && read_u32(self.bytes, 8) == VERSION
+            && read_u32(self.bytes, 12) as usize == self.capacity()
+            && self.head() < self.capacity()
+            && self.len() <= self.capacity()
+            && read_u32(self.bytes, 24) == self.checksum()
+    }
+
+    /// Start empty
+    fn reset(&mut self) {
+        let capacity = self.capacity() as u32;
+        self.bytes[..8].copy_from_slice(&MAGIC.to_le_bytes());
+        write_u32(self.bytes, 8, VERSION);
+        write_u32(self.bytes, 12, capacity);
+        write_u32(self.bytes, 16, 0);
+        write_u32(self.bytes, 20, 0);
+        self.seal();
+    }
+
+    /// Bring the checksum up to date after writes
+    fn seal(&mut self) {
+        let checksum = self.checksum();
+        write_u32(self.bytes, 24, checksum);
+    }
+
+    /// Add bytes, overwriting the oldest when full. Call `seal` after.
+    fn push(&mut self, data: &[u8]) {
+        let capacity = self.capacity();
+        let mut head = self.head();
+        let mut len = self.len();
+        // Only the newest capacity bytes can be kept
+        for &byte in &data[data.len().saturating_sub(capacity)..] {
+            self.bytes[HEADER_SIZE + head] = byte;
+            head = (head + 1) % capacity;
+            len = (len + 1).min(capacity);
+        }
+        write_u32(self.bytes, 16, head as u32);
+        write_u32(self.bytes, 20, len as u32);
+    }
+
+    /// The bytes held, oldest first
+    fn contents(&self) -> Vec<u8> {
+        let data = &self.bytes[HEADER_SIZE..];
+        let (head, len) = (self.head(), self.len());
+        if len < self.capacity() {
+            data[..len].to_vec()
+        } else {
+            let mut contents = data[head..].to_vec();
+            contents.extend_from_slice(&data[..head]);
+            contents
+        }
+    }
+}
+
+/// The real region, once `init` has found it
+fn region() -> Option<Ring<'static>> {
+    let base = BASE.load(Ordering::SeqCst);
+    let size = SIZE.load(Ordering::SeqCst) as usize;
+    if base == 0 {
+        return None;
+    }
+    // The region is never handed out by the frame allocator, and writers
+    // are serialized by WRITER
+    Some(Ring { bytes: unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size) } })
+}
+
+/// Find the region, keep what the last boot left in it, and start it
+/// afresh. Call once the physical memory mapping and heap are ready.
+pub fn init() {
+    let Some((start, size)) = crate::memory::pstore_region() else {
+        serial_println!("PSTORE: No memory set aside; crash logs won't survive reboots");
+        return;
+    };
+    let base = crate::memory::phys_to_virt(start).as_u64();
+    let mut ring = Ring { bytes: unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size as usize) } };
+    if ring.is_valid() && ring.len() > 0 {
+        let contents = ring.contents();
+        serial_println!("PSTORE: Recovered {} bytes logged by the previous boot", contents.len());
+        *LAST_BOOT.lock() = Some(contents);
+    }
+    ring.reset();
+    SIZE.store(size, Ordering::SeqCst);
+    BASE.store(base, Ordering::SeqCst);
+}
+
+/// Add a line to the region, if there is one
+pub fn append(line: &str) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _guard = WRITER.lock();
+        if let Some(mut ring) = region() {
+            ring.push(line.as_bytes());
+            ring.push(b"\n");
+            ring.seal();
+        }
+    });
+}
+
+/// Writes a panic report straight into the region, without allocating
+struct PanicWriter(Ring<'static>);
+
+impl fmt::Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Record a panic report. For the panic handler: it doesn't allocate, and
+/// doesn't wait for a writer that the panic may have interrupted.
+pub fn record_panic(info: &core::panic::PanicInfo) {
+    use core::fmt::Write;
+    let _guard = WRITER.try_lock();
+    let Some(ring) = region() else {
+        return;
+    };
+    let mut writer = PanicWriter(ring);
+    let _ = writeln!(writer, "KERNEL PANIC at {} ms: {}", crate::time::monotonic_ms(), info);
+    let _ = crate::ksyms::write_backtrace(&mut writer);
+    writer.0.seal();
+}
+
+/// What the previous boot left, as text; empty if nothing
+pub fn last_boot_text() -> String {
+    LAST_BOOT.lock().as_deref().map(|bytes| String::from_utf8_lossy(bytes).into_owned()).unwrap_or_default()
+}
+
+/// Write what the previous boot left to LAST_BOOT_LOG, if anything
+pub fn save_last_boot() -> Result<(), KernelError> {
+    let Some(contents) = LAST_BOOT.lock().clone() else {
+        return Ok(());
+    };
+    let vfs = crate::fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    for dir in ["/var", "/var/log"] {
+        match vfs.create_directory(dir) {
+            Ok(()) | Err(KernelError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    // Replace the log from an earlier crash
+    match vfs.remove(LAST_BOOT_LOG) {
+        Ok(()) | Err(KernelError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    vfs.create_file(LAST_BOOT_LOG)?;
+    crate::fs::direct_write_file(LAST_BOOT_LOG, &contents)?;
+    serial_println!("PSTORE: Saved the previous boot's log to {}", LAST_BOOT_LOG);
+    Ok(())
+}
+
+/// Check the ring on a heap buffer: round trip, wrapping, and that
+/// corruption is noticed
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("PSTORE: Running self-test");
+
+    let mut bytes = vec![0xA5u8; HEADER_SIZE + 64];
+    let mut ring = Ring { bytes: &mut bytes };
+    if ring.is_valid() {
+        return Err(KernelError::ValidationError("Uninitialized region taken as valid"));
+    }
+    ring.reset();
+    ring.push(b"first line\n");
+    ring.push(b"second\n");
+    ring.seal();
+    if !ring.is_valid() || ring.contents() != b"first line\nsecond\n" {
+        return Err(KernelError::ValidationError("Persistent log lost what was written"));
+    }
+
+    // 18 + 60 bytes into 64: the oldest 14 go
+    ring.push(&[b'x'; 60]);
+    ring.seal();
+    let contents = ring.contents();
+    if !ring.is_valid() || contents.len() != 64 || !contents.starts_with(b"ond\n") || contents[4..] != [b'x'; 60] {
+        return Err(KernelError::ValidationError("Persistent log wrapped wrongly"));
+    }
+
+    // A byte changed behind the checksum's back
+    ring.bytes[HEADER_SIZE + 3] ^= 0xFF;
+    if ring.is_valid() {
+        return Err(KernelError::ValidationError("Corrupt persistent log taken as valid"));
+    }
+
+    serial_println!("PSTORE: Self-test passed");
+    Ok(())
+}
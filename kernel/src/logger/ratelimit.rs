@@ -0,0 +1,222 @@
+//! Rate limiting for the serial log target
+//!
+//! Every serial byte is a busy-wait, so a flood of debug lines slows the
+//! whole kernel and scrolls real problems away. Two things stand between a
+//! logged message and the serial port:
+//!
+//! - A message identical to the one before it (same level, module and text)
+//!   isn't printed again. A "last message repeated N times" line stands in
+//!   for the run once something else gets printed.
+//! - Each module has a token bucket allowing `log.serial_rate` messages a
+//!   second, with up to a second's worth saved up. Past that, messages are
+//!   dropped from serial and counted.
+//!
+//! Errors and worse skip both, so an emergency always gets out. Messages
+//! still reach the log buffer either way. Buckets refill from the monotonic
+//! clock, which only runs with interrupts on; until then only duplicates
+//! are held back.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::errors::KernelError;
+use crate::serial_println;
+use super::LogLevel;
+
+/// Token fractions per token, so slow refills aren't lost to rounding
+const MILLI: u64 = 1000;
+
+/// One module's allowance
+struct Bucket {
+    module: String,
+    /// Thousandths of a message that may still be printed
+    tokens: u64,
+    /// Monotonic time tokens were last added
+    refilled_ns: u64,
+    dropped: u64,
+}
+
+/// Counters for `dmesg stats`
+#[derive(Debug, Clone, Default)]
+pub struct SerialStats {
+    pub printed: u64,
+    /// Repeats folded into "last message repeated" lines
+    pub collapsed: u64,
+    /// Messages over their module's rate
+    pub dropped: u64,
+    /// Messages dropped, per module, for modules that lost any
+    pub dropped_by_module: Vec<(String, u64)>,
+}
+
+/// What to print for one message
+#[derive(Debug, PartialEq, Eq)]
+pub struct Admission {
+    /// Summary of a run of repeats just ended, to print first
+    pub repeated: Option<String>,
+    /// Whether the message itself is printed
+    pub print: bool,
+}
+
+pub struct RateLimiter {
+    /// Messages a second per module; 0 for no limit
+    rate: u32,
+    dedup: bool,
+    last: Option<(LogLevel, String, String)>,
+    repeats: u64,
+    buckets: Vec<Bucket>,
+    printed: u64,
+    collapsed: u64,
+    dropped: u64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, dedup: bool) -> Self {
+        Self {
+            rate,
+            dedup,
+            last: None,
+            repeats: 0,
+            buckets: Vec::new(),
+            printed: 0,
+            collapsed: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Change the limits; buckets start again full
+    pub fn configure(&mut self, rate: u32, dedup: bool) {
+        self.rate = rate;
+        self.dedup = dedup;
+        self.buckets.clear();
+    }
+
+    /// Decide whether a message is printed. `now_ns` is the monotonic time,
+    /// or None while the clock isn't running.
+    pub fn admit(&mut self, level: LogLevel, module: &str, message: &str, now_ns: Option<u64>) -> Admission {
+        let emergency = matches!(level, LogLevel::Error | LogLevel::Critical);
+        let repeat = self.last.as_ref()
+            .map_or(false, |(last_level, last_module, last_message)| {
+                *last_level == level && last_module == module && last_message == message
+            });
+        if self.dedup && repeat && !emergency {
+            self.repeats += 1;
+            self.collapsed += 1;
+            return Admission { repeated: None, print: false };
+        }
+
+        let repeated = self.take_repeats();
+        if !emergency && !self.take_token(module, now_ns) {
+            self.dropped += 1;
+            // A dropped message isn't one the next can repeat
+            self.last = None;
+            return Admission { repeated, print: false };
+        }
+        self.last = Some((level, String::from(module), String::from(message)));
+        self.printed += 1;
+        Admission { repeated, print: true }
+    }
+
+    /// End any run of repeats, returning its summary line
+    pub fn take_repeats(&mut self) -> Option<String> {
+        match core::mem::take(&mut self.repeats) {
+            0 => None,
+            1 => Some(String::from("last message repeated 1 time")),
+            count => Some(format!("last message repeated {} times", count)),
+        }
+    }
+
+    fn take_token(&mut self, module: &str, now_ns: Option<u64>) -> bool {
+        let (Some(now), rate) = (now_ns, u64::from(self.rate)) else {
+            return true;
+        };
+        if rate == 0 {
+            return true;
+        }
+        let capacity = rate * MILLI;
+        let index = match self.buckets.iter().position(|bucket| bucket.module == module) {
+            Some(index) => index,
+            None => {
+                self.buckets.push(Bucket { module: String::from(module), tokens: capacity, refilled_ns: now, dropped: 0 });
+                self.buckets.len() - 1
+            }
+        };
+        let bucket = &mut self.buckets[index];
+
+        // rate tokens a second is rate * MILLI thousandths per 10^9 ns
+        let elapsed = now.saturating_sub(bucket.refilled_ns);
+        let earned = elapsed.saturating_mul(rate) / 1_000_000;
+        if earned > 0 {
+            bucket.tokens = bucket.tokens.saturating_add(earned).min(capacity);
+            bucket.refilled_ns = now;
+        }
+        if bucket.tokens >= MILLI {
+            bucket.tokens -= MILLI;
+            true
+        } else {
+            bucket.dropped += 1;
+            false
+        }
+    }
+
+    pub fn stats(&self) -> SerialStats {
+        SerialStats {
+            printed: self.printed,
+            collapsed: self.collapsed,
+            dropped: self.dropped,
+            dropped_by_module: self.buckets.iter()
+                .filter(|bucket| bucket.dropped > 0)
+                .map(|bucket| (bucket.module.clone(), bucket.dropped))
+                .collect(),
+        }
+    }
+}
+
+/// Check collapsing, the per-module limit and its refill, the emergency
+/// bypass, and that nothing is dropped while the clock is stopped
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("LOGGER: Running rate limit self-test");
+
+    const SEC: u64 = 1_000_000_000;
+    let mut limiter = RateLimiter::new(2, true);
+    let at = |limiter: &mut RateLimiter, level, module: &str, message: &str, now| {
+        limiter.admit(level, module, message, Some(now))
+    };
+
+    // Three in a row collapse to one line and a summary
+    let mut printed = 0;
+    for _ in 0..3 {
+        printed += at(&mut limiter, LogLevel::Debug, "fs", "same", 0).print as u32;
+    }
+    let next = at(&mut limiter, LogLevel::Debug, "fs", "other", 0);
+    if printed != 1 || next != (Admission { repeated: Some(String::from("last message repeated 2 times")), print: true }) {
+        return Err(KernelError::ValidationError("Repeated log messages not collapsed"));
+    }
+
+    // "fs" has spent its two a second; "net" has its own
+    if at(&mut limiter, LogLevel::Info, "fs", "third", 0).print || !at(&mut limiter, LogLevel::Info, "net", "up", 0).print {
+        return Err(KernelError::ValidationError("Log rate limit not kept per module"));
+    }
+    // Half a second earns one more, not two
+    if !at(&mut limiter, LogLevel::Info, "fs", "a", SEC / 2).print || at(&mut limiter, LogLevel::Info, "fs", "b", SEC / 2).print {
+        return Err(KernelError::ValidationError("Log rate limit refilled wrongly"));
+    }
+    // Errors go out regardless, even repeated
+    let errors = (0..3).filter(|_| at(&mut limiter, LogLevel::Error, "fs", "disk gone", SEC / 2).print).count();
+    if errors != 3 {
+        return Err(KernelError::ValidationError("Error messages were rate limited"));
+    }
+    // With the clock stopped only duplicates are held back
+    let unclocked = (0..5).filter(|i| limiter.admit(LogLevel::Debug, "fs", &format!("{}", i), None).print).count();
+    if unclocked != 5 {
+        return Err(KernelError::ValidationError("Messages dropped while the clock was stopped"));
+    }
+
+    let stats = limiter.stats();
+    if stats.collapsed != 2 || stats.dropped != 2 || stats.dropped_by_module != [(String::from("fs"), 2)] {
+        serial_println!("LOGGER: Rate limit stats {:?}", stats);
+        return Err(KernelError::ValidationError("Rate limit counts wrong"));
+    }
+
+    serial_println!("LOGGER: Rate limit self-test passed");
+    Ok(())
+}
@@ -11,7 +11,10 @@ use bootloader::{BootInfo, entry_point};
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Keep the report for the next boot, in case this ends in a reboot
+    kernel::logger::pstore::record_panic(info);
     println!("KERNEL PANIC: {}", info);
+    kernel::ksyms::print_backtrace();
     loop {}
 }
 
@@ -1,12 +1,102 @@
 // kernel/src/memory.rs
 use x86_64::{
-    structures::paging::{PageTable, PhysFrame, Size4KiB, FrameAllocator, OffsetPageTable},
+    structures::paging::{PageTable, PageTableFlags, PhysFrame, Size4KiB, FrameAllocator, OffsetPageTable},
     VirtAddr,
     PhysAddr,
 };
+use x86_64::structures::paging::mapper::Translate;
 use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use lazy_static::lazy_static;
+use crate::errors::KernelError;
 use crate::serial_println;
 
+const FRAME_SIZE: u64 = 4096;
+
+/// Bytes set aside for the persistent log (see logger::pstore)
+pub const PSTORE_SIZE: u64 = 64 * 1024;
+
+/// Virtual address at which the bootloader mapped all physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Physical range set aside for the persistent log; empty if there was no room
+static PSTORE_START: AtomicU64 = AtomicU64::new(0);
+static PSTORE_END: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Kernel page table, available once `init_globals` has run
+    static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+    /// Physical frame allocator, available once `init_globals` has run
+    static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+    /// Copy of the bootloader memory map, taken by `init_globals`
+    static ref REGIONS: Mutex<Vec<Region>> = Mutex::new(Vec::new());
+}
+
+/// One physical memory region from the bootloader's map, `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryRegionType,
+}
+
+impl Region {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Short name of the region type
+    pub fn kind_name(&self) -> &'static str {
+        if self.kind == MemoryRegionType::Reserved && self.start == PSTORE_START.load(Ordering::SeqCst)
+            && self.end == PSTORE_END.load(Ordering::SeqCst) {
+            return "Persistent log";
+        }
+        match self.kind {
+            MemoryRegionType::Usable => "Usable",
+            MemoryRegionType::InUse => "In use",
+            MemoryRegionType::Reserved => "Reserved",
+            MemoryRegionType::AcpiReclaimable => "ACPI reclaimable",
+            MemoryRegionType::AcpiNvs => "ACPI NVS",
+            MemoryRegionType::BadMemory => "Bad memory",
+            MemoryRegionType::Kernel => "Kernel",
+            MemoryRegionType::KernelStack => "Kernel stack",
+            MemoryRegionType::PageTable => "Page table",
+            MemoryRegionType::Bootloader => "Bootloader",
+            MemoryRegionType::FrameZero => "Frame zero",
+            MemoryRegionType::Empty => "Empty",
+            MemoryRegionType::BootInfo => "Boot info",
+            MemoryRegionType::Package => "Package",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Bytes of physical memory by kind of region
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryTotals {
+    pub usable: u64,
+    /// Kernel image, stacks, page tables and bootloader data
+    pub kernel: u64,
+    pub reserved: u64,
+    pub acpi_reclaimable: u64,
+    pub acpi_nvs: u64,
+    pub bad: u64,
+    pub other: u64,
+}
+
+/// How much of the usable memory the frame allocator has handed out
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    /// Frames handed out, including ones skipped for contiguous runs
+    pub allocated: usize,
+    pub remaining: usize,
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -59,6 +149,8 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
 pub struct BootInfoFrameAllocator {
     memory_map: &'static [MemoryRegion],
     next: usize,
+    /// Usable memory never handed out: the persistent log
+    reserved: core::ops::Range<u64>,
 }
 
 impl BootInfoFrameAllocator {
@@ -77,12 +169,31 @@ impl BootInfoFrameAllocator {
             .count();
         serial_println!("DEBUG: memory: Found {} usable memory regions", usable_count);
         
+        let reserved = Self::choose_pstore(memory_map);
+        PSTORE_START.store(reserved.start, Ordering::SeqCst);
+        PSTORE_END.store(reserved.end, Ordering::SeqCst);
+        serial_println!("DEBUG: memory: Persistent log at {:#x}..{:#x}", reserved.start, reserved.end);
+        
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            reserved,
         }
     }
 
+    /// The top of the highest usable region big enough to spare it. The
+    /// map is the same every boot, so this is the same place every boot.
+    fn choose_pstore(memory_map: &[MemoryRegion]) -> core::ops::Range<u64> {
+        memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .filter(|r| r.range.end_addr() - r.range.start_addr() >= 4 * PSTORE_SIZE)
+            .max_by_key(|r| r.range.start_addr())
+            .map_or(0..0, |r| {
+                let end = r.range.end_addr() & !(FRAME_SIZE - 1);
+                end - PSTORE_SIZE..end
+            })
+    }
+
     /// Returns an iterator over the usable frames according to the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
@@ -94,6 +205,9 @@ impl BootInfoFrameAllocator {
             .map(|r| r.range.start_addr()..r.range.end_addr());
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // leave out the persistent log
+        let reserved = self.reserved.clone();
+        let frame_addresses = frame_addresses.filter(move |addr| !reserved.contains(addr));
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
@@ -112,5 +226,228 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
-// TODO: Function to initialize a static allocator instance.
-// pub fn init_frame_allocator(boot_info: &'static BootInfo) { ... } 
\ No newline at end of file
+impl BootInfoFrameAllocator {
+    /// Allocate `count` physically contiguous frames, returning the first one.
+    ///
+    /// Frames skipped while looking for a contiguous run are not reused.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut run_start: Option<(usize, PhysFrame)> = None;
+        let mut previous: Option<PhysFrame> = None;
+
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            let contiguous = previous.map_or(false, |p| p + 1 == frame);
+            if !contiguous {
+                run_start = Some((index, frame));
+            }
+            previous = Some(frame);
+
+            if let Some((start_index, start_frame)) = run_start {
+                if index + 1 - start_index == count {
+                    self.next = index + 1;
+                    return Some(start_frame);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Make the page table and frame allocator available to the rest of the kernel
+pub fn init_globals(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+    physical_memory_offset: VirtAddr,
+) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
+    let mut regions: Vec<Region> = Vec::new();
+    let reserved = frame_allocator.reserved.clone();
+    for r in frame_allocator.memory_map.iter() {
+        let region = Region { start: r.range.start_addr(), end: r.range.end_addr(), kind: r.region_type };
+        if reserved.is_empty() || reserved.start < region.start || reserved.end > region.end {
+            regions.push(region);
+            continue;
+        }
+        // Show the persistent log as a region of its own
+        regions.push(Region { end: reserved.start, ..region });
+        regions.push(Region { start: reserved.start, end: reserved.end, kind: MemoryRegionType::Reserved });
+        regions.push(Region { start: reserved.end, ..region });
+    }
+    regions.retain(|r| r.end > r.start);
+    regions.sort_by_key(|r| r.start);
+    serial_println!("DEBUG: memory: Kept a copy of {} memory regions", regions.len());
+    *REGIONS.lock() = regions;
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Physical start and length of the persistent log region, if one was set aside
+pub fn pstore_region() -> Option<(PhysAddr, u64)> {
+    let start = PSTORE_START.load(Ordering::SeqCst);
+    let end = PSTORE_END.load(Ordering::SeqCst);
+    (end > start).then(|| (PhysAddr::new(start), end - start))
+}
+
+/// Virtual address through which a physical address can be accessed
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(phys.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+}
+
+/// Translate a mapped virtual address to its physical address
+pub fn virt_to_phys(virt: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.lock().as_ref()?.translate_addr(virt)
+}
+
+/// Allocate a single physical frame
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+}
+
+/// Frame allocator handle backed by the global allocator, for use with
+/// page tables other than the kernel's
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
+/// Translate through the page table currently loaded in CR3.
+///
+/// With `user` set, every level must be user accessible, so kernel-only
+/// mappings are rejected.
+pub fn translate_active(virt: VirtAddr, user: bool) -> Option<PhysAddr> {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_frame, _) = Cr3::read();
+    let mut table_addr = level_4_frame.start_address();
+    let indices = [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()];
+
+    for (level, index) in indices.iter().enumerate() {
+        let table: &PageTable = unsafe { &*phys_to_virt(table_addr).as_ptr() };
+        let entry = &table[*index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if user && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            return None;
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            // 1 GiB pages at level 3, 2 MiB pages at level 2
+            let size: u64 = match level {
+                1 => 1 << 30,
+                2 => 1 << 21,
+                _ => return None,
+            };
+            return Some(entry.addr() + (virt.as_u64() & (size - 1)));
+        }
+        table_addr = entry.addr();
+    }
+
+    Some(table_addr + u64::from(virt.page_offset()))
+}
+
+/// Allocate physically contiguous frames (for DMA buffers)
+pub fn allocate_contiguous_frames(count: usize) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_contiguous(count)
+}
+
+/// Run `f` with the kernel page table and frame allocator
+pub fn with_mapper<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+{
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    Some(f(mapper.as_mut()?, frame_allocator.as_mut()?))
+} 
+/// The physical memory map the kernel was booted with, sorted by address
+pub fn regions() -> Vec<Region> {
+    REGIONS.lock().clone()
+}
+
+/// Sum the region sizes by kind
+pub fn totals() -> MemoryTotals {
+    let mut totals = MemoryTotals::default();
+    for region in REGIONS.lock().iter() {
+        let bucket = match region.kind {
+            MemoryRegionType::Usable => &mut totals.usable,
+            MemoryRegionType::Reserved => &mut totals.reserved,
+            MemoryRegionType::AcpiReclaimable => &mut totals.acpi_reclaimable,
+            MemoryRegionType::AcpiNvs => &mut totals.acpi_nvs,
+            MemoryRegionType::BadMemory => &mut totals.bad,
+            MemoryRegionType::InUse | MemoryRegionType::Kernel | MemoryRegionType::KernelStack
+            | MemoryRegionType::PageTable | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo | MemoryRegionType::Package => &mut totals.kernel,
+            _ => &mut totals.other,
+        };
+        *bucket += region.size();
+    }
+    totals
+}
+
+/// Frames handed out by the frame allocator and frames left
+pub fn frame_stats() -> Option<FrameStats> {
+    let allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_ref()?;
+    let total = allocator.usable_frames().count();
+    let allocated = allocator.next.min(total);
+    Some(FrameStats { total, allocated, remaining: total - allocated })
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 && b % (1 << 30) == 0 => format!("{} GiB", b >> 30),
+        b if b >= 1 << 20 => format!("{} MiB", b >> 20),
+        b => format!("{} KiB", b >> 10),
+    }
+}
+
+/// Memory map in /proc/iomem format, one "<start>-<end> : <type>" line per
+/// region with its size, for `memmap` and for a future /proc/iomem
+pub fn iomem_text() -> String {
+    let mut text = String::new();
+    for region in REGIONS.lock().iter() {
+        text.push_str(&format!("{:016x}-{:016x} : {} ({})\n",
+            region.start, region.end - 1, region.kind_name(), format_size(region.size())));
+    }
+    text
+}
+
+/// Check the saved memory map is sorted, doesn't overlap, and agrees with
+/// the frame allocator about how much memory is usable
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("MEMORY: Running self-test");
+
+    let regions = regions();
+    if regions.is_empty() {
+        return Err(KernelError::ValidationError("No memory regions were saved"));
+    }
+    if regions.windows(2).any(|pair| pair[0].end > pair[1].start) {
+        return Err(KernelError::ValidationError("Memory regions overlap or are out of order"));
+    }
+
+    let stats = frame_stats().ok_or(KernelError::ValidationError("Frame allocator is not set up"))?;
+    let usable_frames: u64 = regions.iter()
+        .filter(|r| r.kind == MemoryRegionType::Usable)
+        .map(|r| (r.size() + FRAME_SIZE - 1) / FRAME_SIZE)
+        .sum();
+    if usable_frames != stats.total as u64 || totals().usable == 0 {
+        return Err(KernelError::ValidationError("Usable memory doesn't match the frame allocator"));
+    }
+    if stats.allocated + stats.remaining != stats.total || stats.allocated == 0 {
+        return Err(KernelError::ValidationError("Frame counts don't add up"));
+    }
+    if iomem_text().lines().count() != regions.len() {
+        return Err(KernelError::ValidationError("iomem text has the wrong number of lines"));
+    }
+
+    serial_println!("MEMORY: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Address Resolution Protocol (IPv4 over Ethernet)
+
+use alloc::vec::Vec;
+use super::Ipv4Addr;
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+const PACKET_LEN: usize = 28;
+
+/// ARP request opcode
+pub const OP_REQUEST: u16 = 1;
+/// ARP reply opcode
+pub const OP_REPLY: u16 = 2;
+
+/// An Ethernet/IPv4 ARP packet
+#[derive(Debug, Clone, Copy)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: [u8; 6],
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Parse an ARP payload, rejecting anything but Ethernet/IPv4
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < PACKET_LEN {
+            return None;
+        }
+        let htype = u16::from_be_bytes([data[0], data[1]]);
+        let ptype = u16::from_be_bytes([data[2], data[3]]);
+        if htype != HTYPE_ETHERNET || ptype != PTYPE_IPV4 || data[4] != 6 || data[5] != 4 {
+            return None;
+        }
+
+        let mut sender_mac = [0u8; 6];
+        let mut target_mac = [0u8; 6];
+        sender_mac.copy_from_slice(&data[8..14]);
+        target_mac.copy_from_slice(&data[18..24]);
+
+        Some(Self {
+            operation: u16::from_be_bytes([data[6], data[7]]),
+            sender_mac,
+            sender_ip: Ipv4Addr::from_slice(&data[14..18]),
+            target_mac,
+            target_ip: Ipv4Addr::from_slice(&data[24..28]),
+        })
+    }
+
+    /// Serialize the packet
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PACKET_LEN);
+        data.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        data.extend_from_slice(&PTYPE_IPV4.to_be_bytes());
+        data.push(6);
+        data.push(4);
+        data.extend_from_slice(&self.operation.to_be_bytes());
+        data.extend_from_slice(&self.sender_mac);
+        data.extend_from_slice(&self.sender_ip.0);
+        data.extend_from_slice(&self.target_mac);
+        data.extend_from_slice(&self.target_ip.0);
+        data
+    }
+}
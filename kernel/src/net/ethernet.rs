@@ -0,0 +1,52 @@
+//! Ethernet II framing
+
+use alloc::vec::Vec;
+
+/// EtherType for IPv4
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+/// EtherType for ARP
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Broadcast hardware address
+pub const BROADCAST_MAC: [u8; 6] = [0xFF; 6];
+
+const HEADER_LEN: usize = 14;
+
+/// A parsed Ethernet frame borrowing its payload
+pub struct EthernetFrame<'a> {
+    pub destination: [u8; 6],
+    pub source: [u8; 6],
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    /// Parse a raw frame (without the trailing CRC)
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+
+        let mut destination = [0u8; 6];
+        let mut source = [0u8; 6];
+        destination.copy_from_slice(&data[0..6]);
+        source.copy_from_slice(&data[6..12]);
+
+        Some(Self {
+            destination,
+            source,
+            ethertype: u16::from_be_bytes([data[12], data[13]]),
+            payload: &data[HEADER_LEN..],
+        })
+    }
+}
+
+/// Build a frame ready for transmission
+pub fn build(destination: [u8; 6], source: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&destination);
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
@@ -0,0 +1,25 @@
+//! ICMP echo (ping) support
+
+use alloc::vec::Vec;
+use super::ipv4::checksum;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_LEN: usize = 8;
+
+/// Build the reply to an echo request, or `None` if the message isn't one
+pub fn echo_reply(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < HEADER_LEN || request[0] != TYPE_ECHO_REQUEST || checksum(request) != 0 {
+        return None;
+    }
+
+    // Same identifier, sequence and payload; only type and checksum change
+    let mut reply = request.to_vec();
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[1] = 0;
+    reply[2] = 0;
+    reply[3] = 0;
+    let sum = checksum(&reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    Some(reply)
+}
@@ -0,0 +1,88 @@
+//! IPv4 header handling
+
+use alloc::vec::Vec;
+use super::Ipv4Addr;
+
+/// ICMP protocol number
+pub const PROTOCOL_ICMP: u8 = 1;
+
+const MIN_HEADER_LEN: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+
+/// A parsed IPv4 packet borrowing its payload
+pub struct Ipv4Packet<'a> {
+    pub protocol: u8,
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Parse and validate an IPv4 packet; fragments are not supported
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < MIN_HEADER_LEN || data[0] >> 4 != 4 {
+            return None;
+        }
+
+        let header_len = ((data[0] & 0x0F) as usize) * 4;
+        let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if header_len < MIN_HEADER_LEN || total_len < header_len || total_len > data.len() {
+            return None;
+        }
+        if checksum(&data[..header_len]) != 0 {
+            return None;
+        }
+
+        // More-fragments flag or a non-zero fragment offset
+        let fragment = u16::from_be_bytes([data[6], data[7]]);
+        if fragment & 0x3FFF != 0 {
+            return None;
+        }
+
+        Some(Self {
+            protocol: data[9],
+            source: Ipv4Addr::from_slice(&data[12..16]),
+            destination: Ipv4Addr::from_slice(&data[16..20]),
+            payload: &data[header_len..total_len],
+        })
+    }
+}
+
+/// Build an IPv4 packet with a minimal header
+pub fn build(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_len = (MIN_HEADER_LEN + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.push(0x45); // Version 4, 5-dword header
+    packet.push(0);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]); // Identification
+    packet.extend_from_slice(&[0x40, 0]); // Don't fragment
+    packet.push(DEFAULT_TTL);
+    packet.push(protocol);
+    packet.extend_from_slice(&[0, 0]); // Checksum placeholder
+    packet.extend_from_slice(&source.0);
+    packet.extend_from_slice(&destination.0);
+
+    let sum = checksum(&packet);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Internet checksum (RFC 1071); verifying a correct header yields 0
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,243 @@
+//! Minimal network stack for UniverseK OS
+//! Ethernet, ARP and ICMP echo on a single statically configured interface
+
+pub mod ethernet;
+pub mod arp;
+pub mod ipv4;
+pub mod icmp;
+
+use alloc::collections::BTreeMap;
+use core::fmt;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use crate::config::{self, ConfigValue};
+use crate::drivers::rtl8139::{self, NicStats};
+use crate::errors::KernelError;
+use crate::serial_println;
+use crate::task::deferred;
+use ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use arp::ArpPacket;
+use ipv4::Ipv4Packet;
+
+// Defaults match QEMU's user-mode networking
+const DEFAULT_IP: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr([255, 255, 255, 0]);
+const DEFAULT_GATEWAY: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+
+/// An IPv4 address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    /// Build an address from the first four bytes of a slice
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        Self([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// Parse dotted-quad notation
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.trim().split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self(octets))
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+/// Static interface configuration
+#[derive(Debug, Clone, Copy)]
+pub struct NetConfig {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+/// Protocol-level counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetStats {
+    pub arp_requests: u64,
+    pub arp_replies: u64,
+    pub icmp_echo: u64,
+    pub dropped: u64,
+}
+
+/// Snapshot of the interface for display
+#[derive(Debug, Clone, Copy)]
+pub struct NetStatus {
+    pub mac: [u8; 6],
+    pub config: NetConfig,
+    pub nic: NicStats,
+    pub stats: NetStats,
+    pub arp_entries: usize,
+}
+
+struct NetState {
+    mac: [u8; 6],
+    config: NetConfig,
+    arp_cache: BTreeMap<Ipv4Addr, [u8; 6]>,
+    stats: NetStats,
+}
+
+lazy_static! {
+    static ref NET: Mutex<Option<NetState>> = Mutex::new(None);
+}
+
+/// Read an address from the config, falling back to a default
+fn config_addr(key: &str, default: Ipv4Addr) -> Ipv4Addr {
+    match config::get(key) {
+        Some(ConfigValue::String(s)) => Ipv4Addr::parse(&s).unwrap_or_else(|| {
+            serial_println!("NET: Invalid {} '{}', using {}", key, s, default);
+            default
+        }),
+        _ => default,
+    }
+}
+
+/// Bring up the network interface using the network.* config keys
+pub fn init() -> Result<(), KernelError> {
+    if let Some(ConfigValue::Boolean(false)) = config::get("network.enabled") {
+        serial_println!("NET: Disabled by configuration");
+        return Ok(());
+    }
+
+    let mac = rtl8139::mac_address().ok_or(KernelError::DeviceNotFound)?;
+    let config = NetConfig {
+        ip: config_addr("network.ip", DEFAULT_IP),
+        netmask: config_addr("network.netmask", DEFAULT_NETMASK),
+        gateway: config_addr("network.gateway", DEFAULT_GATEWAY),
+    };
+
+    *NET.lock() = Some(NetState {
+        mac,
+        config,
+        arp_cache: BTreeMap::new(),
+        stats: NetStats::default(),
+    });
+
+    // Frames are processed from the main loop, not in the interrupt handler
+    let work = deferred::register(process_received)?;
+    rtl8139::set_receive_work(work);
+
+    serial_println!("NET: Interface up: {} netmask {} gateway {}",
+        config.ip, config.netmask, config.gateway);
+    Ok(())
+}
+
+/// Poll the NIC; needed while interrupts are disabled
+pub fn poll() {
+    if NET.lock().is_some() {
+        rtl8139::poll();
+    }
+}
+
+/// Deferred work: drain the receive ring and handle each frame
+fn process_received() {
+    for frame in rtl8139::receive_frames() {
+        handle_frame(&frame);
+    }
+}
+
+/// Dispatch a received frame by EtherType
+fn handle_frame(data: &[u8]) {
+    let frame = match EthernetFrame::parse(data) {
+        Some(frame) => frame,
+        None => return,
+    };
+
+    let reply = {
+        let mut guard = NET.lock();
+        let state = match guard.as_mut() {
+            Some(state) => state,
+            None => return,
+        };
+
+        match frame.ethertype {
+            ETHERTYPE_ARP => handle_arp(state, frame.payload),
+            ETHERTYPE_IPV4 => handle_ipv4(state, &frame),
+            _ => None,
+        }
+    };
+
+    // Send outside the state lock
+    if let Some(reply) = reply {
+        if let Err(e) = rtl8139::send_frame(&reply) {
+            serial_println!("NET: Failed to send frame: {:?}", e);
+        }
+    }
+}
+
+/// Learn the sender and answer requests for our address
+fn handle_arp(state: &mut NetState, payload: &[u8]) -> Option<alloc::vec::Vec<u8>> {
+    let packet = match ArpPacket::parse(payload) {
+        Some(packet) => packet,
+        None => {
+            state.stats.dropped += 1;
+            return None;
+        }
+    };
+
+    state.arp_cache.insert(packet.sender_ip, packet.sender_mac);
+
+    if packet.operation != arp::OP_REQUEST || packet.target_ip != state.config.ip {
+        return None;
+    }
+
+    state.stats.arp_requests += 1;
+    state.stats.arp_replies += 1;
+
+    let reply = ArpPacket {
+        operation: arp::OP_REPLY,
+        sender_mac: state.mac,
+        sender_ip: state.config.ip,
+        target_mac: packet.sender_mac,
+        target_ip: packet.sender_ip,
+    };
+    Some(ethernet::build(packet.sender_mac, state.mac, ETHERTYPE_ARP, &reply.to_bytes()))
+}
+
+/// Answer ICMP echo requests addressed to us
+fn handle_ipv4(state: &mut NetState, frame: &EthernetFrame) -> Option<alloc::vec::Vec<u8>> {
+    let packet = match Ipv4Packet::parse(frame.payload) {
+        Some(packet) => packet,
+        None => {
+            state.stats.dropped += 1;
+            return None;
+        }
+    };
+
+    if packet.destination != state.config.ip || packet.protocol != ipv4::PROTOCOL_ICMP {
+        return None;
+    }
+
+    let reply = icmp::echo_reply(packet.payload)?;
+    state.stats.icmp_echo += 1;
+
+    // Reply straight to the sender's hardware address
+    state.arp_cache.insert(packet.source, frame.source);
+    let ip = ipv4::build(state.config.ip, packet.source, ipv4::PROTOCOL_ICMP, &reply);
+    Some(ethernet::build(frame.source, state.mac, ETHERTYPE_IPV4, &ip))
+}
+
+/// Current interface status, if the network is up
+pub fn status() -> Option<NetStatus> {
+    let nic = rtl8139::stats()?;
+    let guard = NET.lock();
+    let state = guard.as_ref()?;
+    Some(NetStatus {
+        mac: state.mac,
+        config: state.config,
+        nic,
+        stats: state.stats,
+        arp_entries: state.arp_cache.len(),
+    })
+}
@@ -0,0 +1,91 @@
+//! Safe mode: a boot that starts as little as it can, for when something
+//! that normally starts is what's broken
+//!
+//! In safe mode the PS/2 mouse isn't set up, disks aren't probed for file
+//! systems (the root is TempFS), the GUI doesn't start and the kernel
+//! boots to the full-screen shell, logging at Debug. The `safe` boot flag
+//! turns it on before anything is probed and keeps the saved settings
+//! from loading, in case they're the problem. `system.safe_mode` in the
+//! saved settings turns it on too, but those are read from the root file
+//! system, by which time the mouse and disks are already set up.
+
+use alloc::format;
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::config::{self, ConfigValue, Layer};
+use crate::errors::KernelError;
+use crate::logger::{self, LogLevel};
+use crate::{cmdline, serial_println};
+
+/// Boot flag that forces safe mode
+pub const BOOT_FLAG: &str = "safe";
+
+/// Saved setting that asks for safe mode
+pub const CONFIG_KEY: &str = "system.safe_mode";
+
+/// Shown on the shell's title bar
+pub const BANNER: &str = " SAFE MODE ";
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static FORCED: AtomicBool = AtomicBool::new(false);
+
+/// Turn safe mode on if the boot flag asks; call before any device is
+/// set up
+pub fn init() {
+    if cmdline::flag(BOOT_FLAG) {
+        FORCED.store(true, Ordering::Relaxed);
+        ACTIVE.store(true, Ordering::Relaxed);
+        serial_println!("SAFE MODE: On, from the '{}' boot flag", BOOT_FLAG);
+    }
+}
+
+/// Whether this boot is in safe mode
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Whether the boot flag, rather than the saved setting, asked for it
+pub fn is_forced() -> bool {
+    FORCED.load(Ordering::Relaxed)
+}
+
+/// Whether the settings ask for safe mode
+pub fn configured() -> bool {
+    config::get(CONFIG_KEY).and_then(|value| value.try_as_boolean()).unwrap_or(false)
+}
+
+/// Follow `system.safe_mode` once the settings are loaded, and log at
+/// Debug if in safe mode
+pub fn configure() {
+    if !is_active() && configured() {
+        ACTIVE.store(true, Ordering::Relaxed);
+        serial_println!("SAFE MODE: On, from {}", CONFIG_KEY);
+    }
+    if is_active() {
+        logger::set_min_level(LogLevel::Debug);
+    }
+}
+
+/// Note on serial that a boot phase was left out
+pub fn skip(phase: &str) {
+    serial_println!("SAFE MODE: Skipping {}", phase);
+}
+
+/// Ask for safe mode, or not, from the next boot on, and save the settings
+pub fn set_saved(enabled: bool) -> Result<(), KernelError> {
+    config::set_in(Layer::System, CONFIG_KEY, ConfigValue::boolean(enabled))?;
+    config::save().map_err(|e| {
+        serial_println!("SAFE MODE: Couldn't save {}: {:?}", CONFIG_KEY, e);
+        e
+    })
+}
+
+/// One line on where safe mode stands, for the `safemode` command
+pub fn status() -> String {
+    let now = match (is_active(), is_forced()) {
+        (true, true) => format!("on for this boot (the '{}' boot flag)", BOOT_FLAG),
+        (true, false) => String::from("on for this boot"),
+        (false, _) => String::from("off for this boot"),
+    };
+    format!("Safe mode is {}; {} is {}", now, CONFIG_KEY, configured())
+}
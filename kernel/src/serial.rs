@@ -43,6 +43,69 @@ pub fn _print_simple(s: &str) {
     // Silently fail if we can't get the lock (better than deadlock)
 }
 
+/// Longest line `isr_println!` prints; the rest is cut off
+pub const ISR_LINE_LEN: usize = 128;
+
+/// Formats into a fixed buffer, dropping whatever doesn't fit, so it never
+/// allocates
+pub struct LineBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+
+    /// What was written, without a character cut in half at the end
+    pub fn as_str(&self) -> &str {
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(text) => text,
+            Err(e) => core::str::from_utf8(&self.bytes[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for LineBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(N - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print_isr(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut line = LineBuffer::<ISR_LINE_LEN>::new();
+    let _ = line.write_fmt(args);
+    _print_simple(line.as_str());
+    _print_simple("\n");
+}
+
+/// A byte received on COM1, if one is waiting; for the shell when there
+/// is no screen
+pub fn try_read_byte() -> Option<u8> {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    interrupts::without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        let mut line_status: Port<u8> = Port::new(0x3FD);
+        let mut data: Port<u8> = Port::new(0x3F8);
+        // Bit 0 of the line status register: data ready
+        unsafe { (line_status.read() & 1 != 0).then(|| data.read()) }
+    })
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -51,6 +114,14 @@ macro_rules! serial_print {
     }};
 }
 
+/// `serial_println!` for interrupt handlers: the line is formatted on the
+/// stack, cut off after `ISR_LINE_LEN` bytes, and dropped if the port is
+/// busy rather than waited for.
+#[macro_export]
+macro_rules! isr_println {
+    ($($arg:tt)*) => ($crate::serial::_print_isr(format_args!($($arg)*)));
+}
+
 /// Prints to the host through the serial interface, appending a newline.
 #[macro_export]
 macro_rules! serial_println {
@@ -0,0 +1,187 @@
+//! Commands scheduled with `at`, run when the RTC alarm goes off
+//!
+//! The RTC has a single alarm, so it is always set for the job due soonest.
+//! The alarm callback runs in interrupt context and only raises deferred
+//! work. That work takes the jobs that are due, sets the alarm for the next
+//! one, and runs each in a detached shell of its own, working in /. What a
+//! job prints goes to the log, and a notification says it ran.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::drivers::rtc;
+use crate::errors::KernelError;
+use crate::serial_println;
+use crate::task::deferred::{self, WorkId};
+use super::Shell;
+
+const SECONDS_PER_DAY: u32 = 86_400;
+
+/// A command waiting for its time
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: u32,
+    pub hour: u8,
+    pub minute: u8,
+    pub command: String,
+}
+
+impl Job {
+    /// Seconds from `now` (into the RTC's day) until the job runs. A time
+    /// already past, or this very second, is tomorrow's.
+    fn wait(&self, now: u32) -> u32 {
+        let at = u32::from(self.hour) * 3600 + u32::from(self.minute) * 60;
+        match (at + SECONDS_PER_DAY - now) % SECONDS_PER_DAY {
+            0 => SECONDS_PER_DAY,
+            wait => wait,
+        }
+    }
+}
+
+struct Queue {
+    jobs: Vec<Job>,
+    next_id: u32,
+    /// Hour and minute the alarm is set for
+    armed: Option<(u8, u8)>,
+}
+
+lazy_static! {
+    static ref QUEUE: Mutex<Queue> = Mutex::new(Queue { jobs: Vec::new(), next_id: 1, armed: None });
+    /// Raised by the alarm callback
+    static ref DUE_WORK: Mutex<Option<WorkId>> = Mutex::new(None);
+}
+
+/// The job due soonest after `now`, as an index into `jobs`
+fn soonest(jobs: &[Job], now: u32) -> Option<usize> {
+    jobs.iter().enumerate().min_by_key(|(_, job)| job.wait(now)).map(|(index, _)| index)
+}
+
+fn seconds_into_day() -> u32 {
+    let now = rtc::get_datetime();
+    u32::from(now.hour) * 3600 + u32::from(now.minute) * 60 + u32::from(now.second)
+}
+
+/// Point the alarm at the job due soonest, or turn it off
+fn rearm(queue: &mut Queue) -> Result<(), KernelError> {
+    match soonest(&queue.jobs, seconds_into_day()) {
+        Some(index) => {
+            let (hour, minute) = (queue.jobs[index].hour, queue.jobs[index].minute);
+            rtc::set_alarm(hour, minute, 0, alarm_rang)?;
+            queue.armed = Some((hour, minute));
+        }
+        None => {
+            rtc::cancel_alarm();
+            queue.armed = None;
+        }
+    }
+    Ok(())
+}
+
+/// Alarm callback, in interrupt context
+fn alarm_rang() {
+    if let Some(work) = DUE_WORK.try_lock().and_then(|work| *work) {
+        deferred::raise(work);
+    }
+}
+
+/// Deferred work: run the jobs the alarm went off for
+fn run_due() {
+    let due: Vec<Job> = {
+        let mut queue = QUEUE.lock();
+        let Some(armed) = queue.armed.take() else {
+            return;
+        };
+        let (due, waiting): (Vec<Job>, Vec<Job>) = core::mem::take(&mut queue.jobs).into_iter()
+            .partition(|job| (job.hour, job.minute) == armed);
+        queue.jobs = waiting;
+        if let Err(e) = rearm(&mut queue) {
+            serial_println!("AT: Couldn't set the alarm for the next job: {:?}", e);
+        }
+        due
+    };
+
+    for job in due {
+        serial_println!("AT: Running job {}: {}", job.id, job.command);
+        let output = Shell::detached("/").run_line(&job.command);
+        for (line, _) in &output.lines {
+            crate::logger::info("at", line);
+        }
+        crate::gui::notify(None, &format!("at: job {} ran: {}", job.id, job.command));
+    }
+}
+
+/// Run `command` at the next hour:minute of the RTC's time (UTC), and
+/// return the job's number
+pub fn schedule(hour: u8, minute: u8, command: &str) -> Result<u32, KernelError> {
+    if hour > 23 || minute > 59 || command.trim().is_empty() {
+        return Err(KernelError::InvalidParameter);
+    }
+    {
+        let mut work = DUE_WORK.lock();
+        if work.is_none() {
+            *work = Some(deferred::register(run_due)?);
+        }
+    }
+
+    let mut queue = QUEUE.lock();
+    let id = queue.next_id;
+    queue.jobs.push(Job { id, hour, minute, command: String::from(command) });
+    if let Err(e) = rearm(&mut queue) {
+        queue.jobs.pop();
+        return Err(e);
+    }
+    queue.next_id += 1;
+    Ok(id)
+}
+
+/// Drop job `id` before it runs
+pub fn cancel(id: u32) -> Result<(), KernelError> {
+    let mut queue = QUEUE.lock();
+    let index = queue.jobs.iter().position(|job| job.id == id).ok_or(KernelError::NotFound)?;
+    queue.jobs.remove(index);
+    rearm(&mut queue)
+}
+
+/// Jobs waiting, soonest first
+pub fn jobs() -> Vec<Job> {
+    let now = seconds_into_day();
+    let mut jobs = QUEUE.lock().jobs.clone();
+    jobs.sort_by_key(|job| job.wait(now));
+    jobs
+}
+
+/// Parse "HH:MM" (24-hour)
+pub fn parse_time(text: &str) -> Option<(u8, u8)> {
+    let (hour, minute) = text.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u8>().ok()?, minute.parse::<u8>().ok()?);
+    if hour > 23 || minute > 59 {
+        return None;
+    }
+    Some((hour, minute))
+}
+
+/// Check time parsing and which job comes due first across midnight
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("AT: Running self-test");
+
+    if parse_time("07:05") != Some((7, 5)) || parse_time("24:00").is_some() || parse_time("12:60").is_some()
+        || parse_time("1230").is_some() {
+        return Err(KernelError::ValidationError("at time parsed wrongly"));
+    }
+
+    let job = |id, hour, minute| Job { id, hour, minute, command: String::new() };
+    let jobs = [job(1, 9, 0), job(2, 23, 30), job(3, 0, 15)];
+    // 23:00: 23:30 comes before tomorrow's 00:15 and 09:00
+    let late = 23 * 3600;
+    // 09:00:00 exactly: job 1 is tomorrow's, so 23:30 is next
+    let nine = 9 * 3600;
+    if soonest(&jobs, late) != Some(1) || soonest(&jobs, nine) != Some(1) || soonest(&jobs, 8 * 3600) != Some(0)
+        || soonest(&jobs, 23 * 3600 + 45 * 60) != Some(2) || jobs[0].wait(nine) != SECONDS_PER_DAY {
+        return Err(KernelError::ValidationError("at picked the wrong job to run next"));
+    }
+
+    serial_println!("AT: Self-test passed");
+    Ok(())
+}
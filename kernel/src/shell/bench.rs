@@ -0,0 +1,317 @@
+//! `bench`: rough throughput figures for the heap, files and drawing
+//!
+//! Each run is timed with the monotonic clock and gives a few rows for a
+//! small table. The numbers are only good for comparing one build or
+//! setting with another on the same machine: nothing else is stopped
+//! meanwhile, and short runs are at the mercy of the clock's resolution.
+//!
+//! `bench fs` also notes how many commands reached the block devices
+//! during each pass, which shows how much the caches took off them.
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::device::iostats::{self, IoCounters};
+use crate::drivers::vga_enhanced::{self, Color};
+use crate::errors::KernelError;
+use crate::fs::{self, tempfs::TempFs, vfs::{file_flags, FileSystem}};
+use crate::gui::compositor::{self, SCREEN_CELLS, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::serial_println;
+use crate::time::monotonic_ns;
+
+/// Allocation sizes `bench heap` goes through, in bytes
+const HEAP_SIZES: [usize; 5] = [16, 64, 256, 1024, 4096];
+
+/// Allocations held at once before they're all freed
+const HEAP_BATCH: usize = 32;
+
+/// Read and write size for `bench fs`
+const FS_BLOCK_SIZE: usize = 4096;
+
+/// Where `bench fs` keeps its file while it runs
+pub const FS_FILE: &str = "/tmp/.bench";
+
+/// Size of the sequential TempFs write the self-test times; the heap is
+/// too small for the 1 MiB that would be a fairer test
+const TEMPFS_WRITE_SIZE: usize = 128 * 1024;
+
+/// Longest that write may take. A few copies take well under a
+/// millisecond; copying a byte at a time, or growing the file for each
+/// write, runs far past it.
+const TEMPFS_WRITE_BUDGET_NS: u64 = 250_000_000;
+
+/// What a row counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Ops,
+    Bytes,
+    Frames,
+}
+
+/// One timed run
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub name: String,
+    pub count: u64,
+    pub unit: Unit,
+    pub ns: u64,
+    /// Transfers the block devices did meanwhile, for file system runs
+    pub device_io: Option<IoCounters>,
+}
+
+impl Row {
+    /// `count` a second, to the nearest whole one
+    pub fn per_second(&self) -> u64 {
+        (u128::from(self.count) * 1_000_000_000 / u128::from(self.ns.max(1))) as u64
+    }
+
+    /// How much was done, like "32000 ops" or "64 KiB"
+    fn amount(&self) -> String {
+        match self.unit {
+            Unit::Ops => format!("{} ops", self.count),
+            Unit::Bytes => format!("{} KiB", self.count / 1024),
+            Unit::Frames => format!("{} frames", self.count),
+        }
+    }
+
+    /// The rate, like "1250000 ops/s", "21.3 MB/s" or "480 fps"
+    fn rate(&self) -> String {
+        let per_second = self.per_second();
+        match self.unit {
+            Unit::Ops => format!("{} ops/s", per_second),
+            Unit::Bytes => format!("{}.{} MB/s", per_second / 1_000_000, per_second / 100_000 % 10),
+            Unit::Frames => format!("{} fps", per_second),
+        }
+    }
+
+    /// Device commands issued, like "12r 3w", or "-" if not counted
+    fn device_ops(&self) -> String {
+        self.device_io.map_or_else(|| String::from("-"), |io| format!("{}r {}w", io.reads, io.writes))
+    }
+}
+
+/// "12.345ms"
+fn milliseconds(ns: u64) -> String {
+    format!("{}.{:03}ms", ns / 1_000_000, ns / 1000 % 1000)
+}
+
+/// The rows as a table with a heading, one line each. There's a device
+/// column only if some row counted device commands.
+pub fn table(rows: &[Row]) -> Vec<String> {
+    let with_device = rows.iter().any(|row| row.device_io.is_some());
+    let line = |name: &str, amount: &str, time: &str, rate: &str, device: &str| if with_device {
+        format!("{:<16} {:>14} {:>12}  {:<12} {}", name, amount, time, rate, device)
+    } else {
+        format!("{:<16} {:>14} {:>12}  {}", name, amount, time, rate)
+    };
+    let mut lines = Vec::with_capacity(rows.len() + 1);
+    lines.push(line("TEST", "AMOUNT", "TIME", "RATE", "DEVICE OPS"));
+    for row in rows {
+        lines.push(line(&row.name, &row.amount(), &milliseconds(row.ns), &row.rate(), &row.device_ops()));
+    }
+    lines
+}
+
+/// Time `f`, returning what it gave and the nanoseconds it took
+fn timed<T>(f: impl FnOnce() -> Result<T, KernelError>) -> Result<(T, u64), KernelError> {
+    let start = monotonic_ns();
+    let value = f()?;
+    Ok((value, monotonic_ns().saturating_sub(start)))
+}
+
+/// Allocate and free `iterations` blocks of each size in `HEAP_SIZES`, a
+/// batch at a time so the allocator sees more than one block live
+pub fn heap(iterations: usize) -> Result<Vec<Row>, KernelError> {
+    let mut rows = Vec::with_capacity(HEAP_SIZES.len());
+    for size in HEAP_SIZES {
+        let layout = Layout::from_size_align(size, 8).map_err(|_| KernelError::InvalidParameter)?;
+        let mut blocks = [core::ptr::null_mut(); HEAP_BATCH];
+        let ((), ns) = timed(|| {
+            let mut done = 0;
+            while done < iterations {
+                let batch = HEAP_BATCH.min(iterations - done);
+                for block in blocks.iter_mut().take(batch) {
+                    *block = unsafe { alloc(layout) };
+                }
+                let failed = blocks[..batch].iter().any(|block| block.is_null());
+                for block in blocks.iter_mut().take(batch) {
+                    if !block.is_null() {
+                        unsafe { dealloc(*block, layout) };
+                    }
+                    *block = core::ptr::null_mut();
+                }
+                if failed {
+                    return Err(KernelError::OutOfMemory);
+                }
+                done += batch;
+            }
+            Ok(())
+        })?;
+        // An alloc and a free each
+        rows.push(Row {
+            name: format!("alloc+free {}B", size),
+            count: 2 * iterations as u64,
+            unit: Unit::Ops,
+            ns,
+            device_io: None,
+        });
+    }
+    Ok(rows)
+}
+
+/// Next offset for random I/O (xorshift), a whole block into a file of
+/// `blocks` blocks
+fn next_block(state: &mut u64, blocks: usize) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state % blocks as u64) * FS_BLOCK_SIZE as u64
+}
+
+/// Write or read the file sequentially or at random offsets, a block at
+/// a time; returns the bytes moved
+fn fs_pass(fd: u32, blocks: usize, write: bool, random: bool) -> Result<u64, KernelError> {
+    let mut buffer = [0u8; FS_BLOCK_SIZE];
+    for (index, byte) in buffer.iter_mut().enumerate() {
+        *byte = index as u8;
+    }
+    let mut state = 0x2545_F491_4F6C_DD1D;
+    fs::fd::seek(fd, 0)?;
+    for _ in 0..blocks {
+        if random {
+            fs::fd::seek(fd, next_block(&mut state, blocks))?;
+        }
+        let done = if write { fs::fd::write(fd, &buffer)? } else { fs::fd::read(fd, &mut buffer)? };
+        if done != FS_BLOCK_SIZE {
+            return Err(if write { KernelError::WriteError } else { KernelError::ReadError });
+        }
+    }
+    Ok((blocks * FS_BLOCK_SIZE) as u64)
+}
+
+/// Sequential then random writes and reads of a `size` byte file at
+/// `FS_FILE`, which is removed afterwards whatever happened
+pub fn fs(size: usize) -> Result<Vec<Row>, KernelError> {
+    let blocks = size.div_ceil(FS_BLOCK_SIZE).max(1);
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let fd = fs::fd::open_tagged("bench", FS_FILE,
+        file_flags::READ | file_flags::WRITE | file_flags::CREATE | file_flags::TRUNCATE)?;
+
+    let passes = [("seq write", true, false), ("seq read", false, false),
+        ("random write", true, true), ("random read", false, true)];
+    let mut rows = Vec::with_capacity(passes.len());
+    let mut result = Ok(());
+    for (name, write, random) in passes {
+        let before = iostats::device_totals();
+        match timed(|| fs_pass(fd, blocks, write, random)) {
+            Ok((bytes, ns)) => rows.push(Row {
+                name: String::from(name),
+                count: bytes,
+                unit: Unit::Bytes,
+                ns,
+                device_io: Some(iostats::device_totals().since(&before)),
+            }),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    let _ = fs::fd::close(fd);
+    if let Err(e) = vfs.remove(FS_FILE) {
+        serial_println!("BENCH: Couldn't remove {}: {:?}", FS_FILE, e);
+    }
+    result.map(|()| rows)
+}
+
+/// Redraw the whole screen `frames` times through the compositor, then put
+/// back what was on it before
+pub fn draw(frames: usize) -> Result<Row, KernelError> {
+    let mut saved = Vec::with_capacity(SCREEN_CELLS);
+    for row in 0..SCREEN_HEIGHT {
+        for column in 0..SCREEN_WIDTH {
+            saved.push(vga_enhanced::read_cell(row, column));
+        }
+    }
+
+    let colors = [Color::Blue, Color::Green, Color::Cyan, Color::Red, Color::Magenta, Color::Brown];
+    let ((), ns) = timed(|| {
+        for frame in 0..frames {
+            // Something different every frame, so every cell really changes
+            let line: String = core::iter::repeat((b'A' + (frame % 26) as u8) as char).take(SCREEN_WIDTH).collect();
+            let bg = colors[frame % colors.len()];
+            compositor::damage_all();
+            for row in 0..SCREEN_HEIGHT {
+                compositor::write_at(row, 0, &line, Color::White, bg);
+            }
+            compositor::present();
+        }
+        Ok(())
+    })?;
+
+    for (index, cell) in saved.into_iter().enumerate() {
+        if let Some(cell) = cell {
+            vga_enhanced::restore_cell(index / SCREEN_WIDTH, index % SCREEN_WIDTH, cell);
+        }
+    }
+    Ok(Row { name: String::from("full redraw"), count: frames as u64, unit: Unit::Frames, ns, device_io: None })
+}
+
+/// Check the rates and the table's layout on made-up rows
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("BENCH: Running self-test");
+
+    let rows = [
+        Row { name: String::from("alloc+free 16B"), count: 2000, unit: Unit::Ops, ns: 4_000_000, device_io: None },
+        Row { name: String::from("seq read"), count: 64 * 1024, unit: Unit::Bytes, ns: 2_500_000, device_io: None },
+        Row { name: String::from("full redraw"), count: 100, unit: Unit::Frames, ns: 0, device_io: None },
+    ];
+    if rows[0].per_second() != 500_000 || rows[1].rate() != "26.2 MB/s" || rows[2].per_second() != 100_000_000_000 {
+        return Err(KernelError::ValidationError("Benchmark rates worked out wrongly"));
+    }
+
+    let lines = table(&rows);
+    if lines.len() != 4 || lines[1] != "alloc+free 16B         2000 ops      4.000ms  500000 ops/s"
+        || lines[2] != "seq read                 64 KiB      2.500ms  26.2 MB/s" {
+        serial_println!("BENCH: Table came out as {:?}", lines);
+        return Err(KernelError::ValidationError("Benchmark table laid out wrongly"));
+    }
+
+    let io = IoCounters { reads: 3, writes: 12, ..IoCounters::default() };
+    let fs_rows = [Row { device_io: Some(io), ..rows[1].clone() }, rows[0].clone()];
+    let lines = table(&fs_rows);
+    if lines[1] != "seq read                 64 KiB      2.500ms  26.2 MB/s    3r 12w"
+        || lines[2] != "alloc+free 16B         2000 ops      4.000ms  500000 ops/s -" {
+        serial_println!("BENCH: Table came out as {:?}", lines);
+        return Err(KernelError::ValidationError("Device column laid out wrongly"));
+    }
+
+    let mut state = 1;
+    if (0..100).any(|_| next_block(&mut state, 16) >= 16 * FS_BLOCK_SIZE as u64 || state == 0) {
+        return Err(KernelError::ValidationError("Random offset past the end of the file"));
+    }
+
+    let mut tempfs = TempFs::new("bench");
+    tempfs.create_file("/seq")?;
+    let block = [0xA5u8; FS_BLOCK_SIZE];
+    let ((), ns) = timed(|| {
+        for offset in (0..TEMPFS_WRITE_SIZE).step_by(FS_BLOCK_SIZE) {
+            tempfs.write_at("/seq", offset as u64, &block)?;
+        }
+        Ok(())
+    })?;
+    let mut last = [0u8; FS_BLOCK_SIZE];
+    let read = tempfs.read_at("/seq", (TEMPFS_WRITE_SIZE - FS_BLOCK_SIZE) as u64, &mut last)?;
+    if read != FS_BLOCK_SIZE || last != block || tempfs.metadata("/seq")?.size != TEMPFS_WRITE_SIZE as u64 {
+        return Err(KernelError::ValidationError("TempFs sequential write came back wrong"));
+    }
+    if ns > TEMPFS_WRITE_BUDGET_NS {
+        serial_println!("BENCH: {} KiB TempFs write took {}ms", TEMPFS_WRITE_SIZE / 1024, ns / 1_000_000);
+        return Err(KernelError::ValidationError("TempFs sequential write too slow"));
+    }
+
+    serial_println!("BENCH: Self-test passed");
+    Ok(())
+}
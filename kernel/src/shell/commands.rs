@@ -0,0 +1,339 @@
+//! Command registry for the shell
+//! Every command is registered with its name, aliases, a one-line summary,
+//! a usage line and how many arguments it takes. The shell dispatches
+//! through the registry and builds `help` from it, and apps or debug code
+//! can add their own commands with `register_command`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::i18n::{self, MSG_HELP_ALIASES, MSG_HELP_HEADER, MSG_USAGE};
+use crate::serial_println;
+use crate::tr;
+use super::Shell;
+
+/// Runs a command; gets the arguments after the command name
+pub type Handler = fn(&mut Shell, &[&str]) -> Result<(), KernelError>;
+
+/// A shell command and how to call it
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// One line for the `help` list, in English; locales translate it by
+    /// the command's name
+    pub summary: &'static str,
+    /// Arguments as shown after "Usage:", including the command name
+    pub usage: &'static str,
+    pub min_args: usize,
+    /// None for any number
+    pub max_args: Option<usize>,
+    pub handler: Handler,
+}
+
+impl Command {
+    /// Whether `name` calls this command
+    pub fn answers_to(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+
+    /// The summary in the active locale
+    pub fn summary_text(&self) -> &'static str {
+        i18n::command_summary(self.name, self.summary)
+    }
+
+    /// Whether `count` arguments are acceptable
+    pub fn accepts(&self, count: usize) -> bool {
+        count >= self.min_args && self.max_args.map_or(true, |max| count <= max)
+    }
+}
+
+/// Most suggestions offered for a mistyped command
+const MAX_SUGGESTIONS: usize = 3;
+
+lazy_static! {
+    static ref COMMANDS: Mutex<Vec<Command>> = Mutex::new(builtin_commands());
+}
+
+/// Shorthand for the builtin table
+const fn command(
+    name: &'static str,
+    aliases: &'static [&'static str],
+    usage: &'static str,
+    summary: &'static str,
+    args: (usize, Option<usize>),
+    handler: Handler,
+) -> Command {
+    Command { name, aliases, summary, usage, min_args: args.0, max_args: args.1, handler }
+}
+
+fn builtin_commands() -> Vec<Command> {
+    const NONE: (usize, Option<usize>) = (0, Some(0));
+    alloc::vec![
+        command("help", &[], "help [command]", "List commands, or explain one", (0, Some(1)), Shell::cmd_help),
+        command("echo", &[], "echo [text...]", "Display a message", (0, None), Shell::cmd_echo),
+        command("ls", &["dir"], "ls [-l] [-d] [-a] [dir]",
+            "List directory contents, colored by type (-l: long format, -d: directories first, -a: hidden too)", (0, Some(4)),
+            Shell::cmd_ls),
+        command("cd", &[], "cd [dir | -]", "Change directory (/ when none is given, - for the previous one)",
+            (0, Some(1)), Shell::cmd_cd),
+        command("pushd", &[], "pushd <dir>", "Change directory, saving the current one on the directory stack",
+            (1, Some(1)), Shell::cmd_pushd),
+        command("popd", &[], "popd", "Return to the directory on top of the directory stack", NONE, Shell::cmd_popd),
+        command("dirs", &[], "dirs", "List the current directory and the directory stack", NONE, Shell::cmd_dirs),
+        command("pwd", &[], "pwd", "Print working directory", NONE, Shell::cmd_pwd),
+        command("cat", &[], "cat <file>", "Display file contents", (1, Some(1)), Shell::cmd_cat),
+        command("file", &[], "file <path>...", "Say what files hold, judged by their first bytes, and their size",
+            (1, None), Shell::cmd_file),
+        command("clear", &["cls"], "clear", "Clear the screen", NONE, Shell::cmd_clear),
+        command("touch", &["mkfile"], "touch <file>", "Create a file, or update an existing one's times",
+            (1, Some(1)), Shell::cmd_touch),
+        command("mkdir", &[], "mkdir <dir>", "Create a new directory", (1, Some(1)), Shell::cmd_mkdir),
+        command("rm", &[], "rm [--purge] <path>",
+            "Remove a file or an empty directory (--purge: for good, even with fs.use_trash set)", (1, Some(2)), Shell::cmd_rm),
+        command("trash", &[], "trash [list | restore <name> | empty]",
+            "List, restore or empty your trash", (0, Some(2)), Shell::cmd_trash),
+        command("chmod", &[], "chmod <mode> <path>", "Set permission bits, in octal (e.g. 644)",
+            (2, Some(2)), Shell::cmd_chmod),
+        command("chown", &[], "chown <uid>[:gid] <path>", "Change a file's owner and group",
+            (2, Some(2)), Shell::cmd_chown),
+        command("cp", &[], "cp <source> <target>", "Copy a file (Ctrl+C stops it and removes the partial copy)",
+            (2, Some(2)), Shell::cmd_cp),
+        command("ln", &[], "ln <existing> <new>", "Give a file a second name (hard link)", (2, Some(2)), Shell::cmd_ln),
+        command("find", &[], "find <dir> [pattern]", "List paths below dir, names matching a * pattern",
+            (1, Some(2)), Shell::cmd_find),
+        command("du", &[], "du [-s] [path]", "Show bytes used by each directory (-s: total only)",
+            (0, Some(2)), Shell::cmd_du),
+        command("df", &[], "df", "Show size and free space of mounted file systems", NONE, Shell::cmd_df),
+        command("lsof", &[], "lsof", "List open file descriptors, who opened them and when", NONE, Shell::cmd_lsof),
+        command("mount", &["mountinfo"], "mount [-r|-w <path>]",
+            "List mounted file systems, or make the one at path read-only (-r) or writable (-w)",
+            (0, Some(2)), Shell::cmd_mount),
+        command("fsck", &[], "fsck [-r] [path]", "Check the file system holding path; -r repairs",
+            (0, Some(2)), Shell::cmd_fsck),
+        command("mkfs", &[], "mkfs <device> [fat16|fat32|sfs]", "Format a block device (FAT unless sfs is given)",
+            (1, Some(2)), Shell::cmd_mkfs),
+        command("dd", &[], "dd if=<source> of=<target> [bs=N] [count=N] [seek=N] [skip=N] [progress=MB] [--force]",
+            "Copy raw blocks between devices (ram0, /dev/ram0) and files", (2, None), Shell::cmd_dd),
+        command("fswatch", &[], "fswatch [path]", "Print changes under path as they happen (no path: stop)",
+            (0, Some(1)), Shell::cmd_fswatch),
+        command("startx", &[], "startx", "Switch to the desktop (also Ctrl+Alt+F2)", NONE, Shell::cmd_startx),
+        command("exitgui", &[], "exitgui", "Switch from the desktop to this console (also Ctrl+Alt+F1)",
+            NONE, Shell::cmd_exitgui),
+        command("reboot", &[], "reboot", "Restart the system", NONE, Shell::cmd_reboot),
+        command("panic", &[], "panic [message]", "Crash the kernel on purpose, to test crash logs",
+            (0, None), Shell::cmd_panic),
+        command("ksym", &[], "ksym <address>", "Name the kernel function at a hex address from a crash log",
+            (1, Some(1)), Shell::cmd_ksym),
+        command("shutdown", &["poweroff"], "shutdown", "Suspend devices and power off", NONE, Shell::cmd_shutdown),
+        command("suspend", &[], "suspend", "Suspend every device (undo with resume)", NONE, Shell::cmd_suspend),
+        command("resume", &[], "resume", "Resume suspended devices", NONE, Shell::cmd_resume),
+        command("version", &[], "version", "Display OS version", NONE, Shell::cmd_version),
+        command("uname", &[], "uname [-a]", "Print the kernel name, or with -a its version and build",
+            (0, Some(1)), Shell::cmd_uname),
+        command("motd", &[], "motd [set <text> | edit]",
+            "Show or change the message of the day (\\n in text starts a new line)", (0, None), Shell::cmd_motd),
+        command("date", &[], "date", "Show the date and time (UTC)", NONE, Shell::cmd_date),
+        command("hwclock", &[], "hwclock [--set YYYY-MM-DD HH:MM:SS]",
+            "Show the hardware clock, or set it and the system time", (0, Some(3)), Shell::cmd_hwclock),
+        command("at", &[], "at [HH:MM <command...> | -d <job>]",
+            "Run a command at a time of day (UTC), list waiting jobs, or drop one", (0, None), Shell::cmd_at),
+        command("random", &[], "random [n]", "Print n bytes (default 16) from /dev/random in hex",
+            (0, Some(1)), Shell::cmd_random),
+        command("time", &[], "time <command...>", "Run a command and show the real and CPU time it took",
+            (1, None), Shell::cmd_time),
+        command("chroot", &[], "chroot <dir> <command...>",
+            "Run a command that sees dir as / and can't reach outside it", (2, None), Shell::cmd_chroot),
+        command("bench", &[], "bench <heap [count] | fs [KiB] | draw [frames]>",
+            "Time heap allocations, file reads and writes, or screen redraws", (1, Some(2)), Shell::cmd_bench),
+        command("trace", &[], "trace sched start|stop|dump [file]",
+            "Record task switches and why they happened, then print them or save them to a file",
+            (2, Some(3)), Shell::cmd_trace),
+        command("fault", &[], "fault [list | arm <point> [after N] [error=<kind> | delay=<ms>] [times=N] | disarm <point|all>]",
+            "Make a named operation fail or stall, to test error handling (fault_injection builds)",
+            (0, None), Shell::cmd_fault),
+        command("uptime", &[], "uptime", "Show time since boot", NONE, Shell::cmd_uptime),
+        command("ifconfig", &["netstat"], "ifconfig", "Show network interface status", NONE, Shell::cmd_ifconfig),
+        command("exec", &[], "exec <program> [args...]", "Run a program (or just type its path)",
+            (1, None), Shell::cmd_exec),
+        command("ps", &[], "ps", "List tasks, including unreaped zombies", NONE, Shell::cmd_ps),
+        command("mouse", &[], "mouse [speed <10-1000> | accel <0-100>]",
+            "Show or set the pointer speed (percent) and acceleration", (0, Some(2)), Shell::cmd_mouse),
+        command("free", &[], "free [-v] [-f]", "Show kernel heap usage (-v: by subsystem, in heap_debug builds; -f: fragmentation)",
+            (0, Some(2)), Shell::cmd_free),
+        command("iostat", &[], "iostat",
+            "Show transfers per block device, with throughput since the last iostat", NONE, Shell::cmd_iostat),
+        command("cachestat", &[], "cachestat", "Show disk block cache and readahead counters", NONE, Shell::cmd_cachestat),
+        command("memmap", &[], "memmap", "Show the physical memory map and frame usage", NONE, Shell::cmd_memmap),
+        command("irqstat", &[], "irqstat", "Show interrupt counts by vector", NONE, Shell::cmd_irqstat),
+        command("dmesg", &[], "dmesg [stats|clear]",
+            "Show recent log messages, serial rate limit counts, or clear the log", (0, Some(1)), Shell::cmd_dmesg),
+        command("config", &[],
+            "config <list [prefix] | get <key> | set <key> <value> | unset <key> | rollback [n] | profile list | profile switch <name|none>> [--system|--profile|--user|--runtime] [--save]",
+            "Show or change configuration settings", (1, None), Shell::cmd_config),
+        command("safemode", &[], "safemode [on|off]",
+            "Show safe mode, or turn it on or off from the next boot", (0, Some(1)), Shell::cmd_safemode),
+        command("wallpaper", &[], "wallpaper [color|color:color|image.bmp] [tile|stretch]",
+            "Show or set the desktop background", (0, Some(2)), Shell::cmd_wallpaper),
+        command("gui", &[], "gui reset-session", "Delete the saved desktop layout (gui.restore_session turns it off)",
+            (1, Some(1)), Shell::cmd_gui),
+        command("guirec", &[], "guirec start <file> | stop | play <file> [speed] | status",
+            "Record GUI input to a file, or play a recording back (speed 2 is twice as fast)",
+            (1, Some(3)), Shell::cmd_guirec),
+        command("screenshot", &[], "screenshot [path]",
+            "Save the screen as path.txt (text) and path.vga (raw cells); default ~/Pictures/screenshot-N",
+            (0, Some(1)), Shell::cmd_screenshot),
+        command("clip", &[], "clip set <text> | clip get | clip history | clip clear",
+            "Read or change the clipboard", (1, None), Shell::cmd_clip),
+        command("history", &[], "history [n]", "List earlier commands; !! or !N reruns one",
+            (0, Some(1)), Shell::cmd_history),
+        command("sendkeys", &[], "sendkeys <text>", "Type text as if on the keyboard (\\n is Enter, \\t Tab)",
+            (1, None), Shell::cmd_sendkeys),
+        command("lock", &[], "lock", "Start the screensaver now; a password is needed to leave it",
+            NONE, Shell::cmd_lock),
+        command("login", &["su"], "login <user> [password]",
+            "Switch to another user and load their preferences", (1, Some(2)), Shell::cmd_login),
+        command("passwd", &[], "passwd [-d]",
+            "Set the current user's password, used to unlock the screen (-d: remove it)",
+            (0, Some(1)), Shell::cmd_passwd),
+    ]
+}
+
+/// Add a command. Fails with AlreadyExists if its name or an alias is taken.
+pub fn register_command(new: Command) -> Result<(), KernelError> {
+    let mut commands = COMMANDS.lock();
+    let taken = commands.iter().any(|existing| {
+        existing.answers_to(new.name) || new.aliases.iter().any(|alias| existing.answers_to(alias))
+    });
+    if taken {
+        return Err(KernelError::AlreadyExists);
+    }
+    serial_println!("DEBUG: shell: registered command '{}'", new.name);
+    commands.push(new);
+    Ok(())
+}
+
+/// Remove a command added with `register_command`
+pub fn unregister_command(name: &str) -> Result<(), KernelError> {
+    let mut commands = COMMANDS.lock();
+    let index = commands.iter().position(|command| command.name == name).ok_or(KernelError::NotFound)?;
+    commands.remove(index);
+    Ok(())
+}
+
+/// The command `name` calls, by name or alias
+pub fn find(name: &str) -> Option<Command> {
+    COMMANDS.lock().iter().find(|command| command.answers_to(name)).copied()
+}
+
+/// Every command, sorted by name
+pub fn all() -> Vec<Command> {
+    let mut commands = COMMANDS.lock().clone();
+    commands.sort_by_key(|command| command.name);
+    commands
+}
+
+/// Names close to a mistyped `name`: ones it is a prefix of, then ones
+/// within two edits of it
+pub fn suggest(name: &str) -> Vec<&'static str> {
+    let commands = all();
+    let mut suggestions: Vec<&'static str> = commands.iter()
+        .map(|command| command.name)
+        .filter(|candidate| !name.is_empty() && candidate.starts_with(name))
+        .collect();
+    let mut close: Vec<(usize, &'static str)> = commands.iter()
+        .flat_map(|command| core::iter::once(&command.name).chain(command.aliases.iter()))
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .collect();
+    close.sort();
+    for (_, candidate) in close {
+        if !suggestions.contains(&candidate) {
+            suggestions.push(candidate);
+        }
+    }
+    suggestions.truncate(MAX_SUGGESTIONS);
+    suggestions
+}
+
+/// Levenshtein distance, counting characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = alloc::vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitute.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The full `help` listing
+pub fn help_text() -> String {
+    let mut text = format!("{}\n", tr!(MSG_HELP_HEADER));
+    for command in all() {
+        text.push_str(&format!("  {:<10} - {}\n", command.name, command.summary_text()));
+    }
+    text
+}
+
+/// `help <command>`: usage, summary and aliases
+pub fn detail_text(command: &Command) -> String {
+    let mut text = format!("{}\n  {}", tr!(MSG_USAGE, command.usage), command.summary_text());
+    if !command.aliases.is_empty() {
+        text.push_str(&format!("\n  {}", tr!(MSG_HELP_ALIASES, command.aliases.join(", "))));
+    }
+    text
+}
+
+/// Check lookups, argument counts, suggestions and registration
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SHELL: Running command registry self-test");
+
+    fn noop(_shell: &mut Shell, _args: &[&str]) -> Result<(), KernelError> {
+        Ok(())
+    }
+
+    if find("dir").map(|command| command.name) != Some("ls") || find("nonsense").is_some() {
+        return Err(KernelError::ValidationError("Command lookup by alias failed"));
+    }
+    let ln = find("ln").ok_or(KernelError::ValidationError("ln is not registered"))?;
+    if ln.accepts(1) || !ln.accepts(2) || ln.accepts(3) || !find("echo").map_or(false, |echo| echo.accepts(20)) {
+        return Err(KernelError::ValidationError("Argument counts checked wrongly"));
+    }
+    if !suggest("fsk").contains(&"fsck") || !suggest("hist").contains(&"history") || !suggest("zzzzzz").is_empty() {
+        serial_println!("SHELL: Suggestions: {:?} {:?}", suggest("fsk"), suggest("hist"));
+        return Err(KernelError::ValidationError("Wrong suggestions for a mistyped command"));
+    }
+
+    let test = Command {
+        name: "selftest-cmd",
+        aliases: &["selftest-alias"],
+        summary: "Registry self-test",
+        usage: "selftest-cmd",
+        min_args: 0,
+        max_args: Some(0),
+        handler: noop,
+    };
+    register_command(test)?;
+    let result = (|| {
+        if !matches!(register_command(Command { name: "other", aliases: &["cat"], ..test }), Err(KernelError::AlreadyExists)) {
+            return Err(KernelError::ValidationError("Alias clashing with a builtin was accepted"));
+        }
+        if find("selftest-alias").is_none() || !help_text().contains("selftest-cmd") {
+            return Err(KernelError::ValidationError("Registered command not listed"));
+        }
+        Ok(())
+    })();
+    unregister_command("selftest-cmd")?;
+
+    result?;
+    serial_println!("SHELL: Command registry self-test passed");
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! Directory stack for pushd, popd and dirs, and the directory `cd -`
+//! goes back to. Each shell keeps its own for as long as it runs; none of
+//! it is saved.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::config;
+
+/// Entries kept when `shell.dir_stack_size` isn't set
+pub const DEFAULT_STACK_SIZE: usize = 10;
+/// Upper bound for `shell.dir_stack_size`
+const MAX_STACK_SIZE: usize = 100;
+
+/// Entries to keep, from `shell.dir_stack_size`
+pub fn configured_size() -> usize {
+    config::get("shell.dir_stack_size")
+        .and_then(|value| value.try_as_integer())
+        .map_or(DEFAULT_STACK_SIZE, |size| size.clamp(1, MAX_STACK_SIZE as i64) as usize)
+}
+
+/// Directories saved by pushd, newest last, plus the one before the last cd
+pub struct DirStack {
+    entries: Vec<String>,
+    capacity: usize,
+    previous: Option<String>,
+}
+
+impl DirStack {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: Vec::new(), capacity: capacity.max(1), previous: None }
+    }
+
+    /// Save `dir` on top, dropping the oldest entry if the stack is full
+    pub fn push(&mut self, dir: String) {
+        if self.entries.len() == self.capacity {
+            self.entries.remove(0);
+        }
+        self.entries.push(dir);
+    }
+
+    /// The directory popd would return to
+    pub fn top(&self) -> Option<&str> {
+        self.entries.last().map(String::as_str)
+    }
+
+    pub fn pop(&mut self) -> Option<String> {
+        self.entries.pop()
+    }
+
+    /// Saved directories, newest first
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().rev().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Where `cd -` goes
+    pub fn previous(&self) -> Option<&str> {
+        self.previous.as_deref()
+    }
+
+    /// Note that the shell has just left `dir`
+    pub fn left(&mut self, dir: String) {
+        self.previous = Some(dir);
+    }
+}
@@ -0,0 +1,295 @@
+//! Command history for the shell and terminal windows
+//! Each shell keeps its own in-memory list. Every command is also appended
+//! to $HOME/.history, which new shells load when they start.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::config;
+use crate::errors::KernelError;
+use crate::fs;
+use crate::serial_println;
+use crate::user;
+
+/// History file name, inside the user's home directory
+pub const HISTORY_FILE: &str = ".history";
+/// Entries kept when `shell.history_size` isn't set
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+/// Upper bound for `shell.history_size`
+const MAX_HISTORY_SIZE: usize = 1000;
+/// Longest line accepted from the file; anything longer is corruption
+const MAX_LINE_LEN: usize = 1024;
+/// Most of the file read at startup, counted back from its end
+const MAX_FILE_READ: usize = 64 * 1024;
+
+/// Entries to keep, from `shell.history_size`
+pub fn configured_size() -> usize {
+    config::get("shell.history_size")
+        .and_then(|value| value.try_as_integer())
+        .map_or(DEFAULT_HISTORY_SIZE, |size| size.clamp(1, MAX_HISTORY_SIZE as i64) as usize)
+}
+
+/// $HOME/.history for the current user
+pub fn default_path() -> String {
+    format!("{}/{}", user::home_dir(), HISTORY_FILE)
+}
+
+/// A bounded list of commands, oldest first
+pub struct History {
+    entries: VecDeque<String>,
+    capacity: usize,
+    /// File new commands are appended to
+    path: Option<String>,
+    /// Lines in the file, so it can be trimmed once it grows too long
+    file_lines: usize,
+}
+
+impl History {
+    /// An empty history that is not saved anywhere
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity: capacity.max(1),
+            path: None,
+            file_lines: 0,
+        }
+    }
+
+    /// Load the newest `capacity` commands from `path` and append new ones
+    /// to it. A missing file system just gives an empty history; a corrupt
+    /// file is cut back to the lines before the damage.
+    pub fn load(path: &str, capacity: usize) -> Self {
+        let mut history = Self::new(capacity);
+        history.path = Some(path.to_string());
+
+        match read_lines(path) {
+            Ok((lines, intact)) => {
+                let start = lines.len().saturating_sub(history.capacity);
+                if !intact || start > 0 {
+                    if let Err(e) = rewrite(path, &lines[start..]) {
+                        serial_println!("DEBUG: history - can't rewrite {}: {:?}", path, e);
+                    }
+                }
+                history.file_lines = lines.len() - start;
+                history.entries.extend(lines.into_iter().skip(start));
+            }
+            Err(e) => serial_println!("DEBUG: history - not loading {}: {:?}", path, e),
+        }
+        history
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entry `number`, counting from 1 for the oldest one kept
+    pub fn get(&self, number: usize) -> Option<&str> {
+        number.checked_sub(1)
+            .and_then(|i| self.entries.get(i))
+            .map(String::as_str)
+    }
+
+    /// Most recent entry
+    pub fn last(&self) -> Option<&str> {
+        self.entries.back().map(String::as_str)
+    }
+
+    /// Entries with their numbers, oldest first
+    pub fn numbered(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.entries.iter().enumerate().map(|(i, entry)| (i + 1, entry.as_str()))
+    }
+
+    /// Record a command unless it repeats the previous one
+    pub fn push(&mut self, line: &str) {
+        if line.is_empty() || self.last() == Some(line) {
+            return;
+        }
+        self.entries.push_back(line.to_string());
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+
+        if let Some(path) = self.path.clone() {
+            self.save_line(&path, line);
+        }
+    }
+
+    /// Append to the file, trimming it once it holds twice the capacity
+    fn save_line(&mut self, path: &str, line: &str) {
+        if let Err(e) = append_line(path, line) {
+            serial_println!("DEBUG: history - not saving to {}: {:?}", path, e);
+            return;
+        }
+        self.file_lines += 1;
+        if self.file_lines > 2 * self.capacity {
+            // Other shells append to the same file, so trim what's on disk
+            // rather than writing out this shell's entries
+            if let Ok((lines, _)) = read_lines(path) {
+                let start = lines.len().saturating_sub(self.capacity);
+                if rewrite(path, &lines[start..]).is_ok() {
+                    self.file_lines = lines.len() - start;
+                }
+            }
+        }
+    }
+
+    /// Expand a leading `!!` (last command) or `!N` (entry N). Returns None
+    /// when the line doesn't start with an expansion.
+    pub fn expand(&self, line: &str) -> Result<Option<String>, KernelError> {
+        let (entry, rest) = if let Some(rest) = line.strip_prefix("!!") {
+            (self.last(), rest)
+        } else if let Some(tail) = line.strip_prefix('!') {
+            let digits = tail.bytes().take_while(u8::is_ascii_digit).count();
+            if digits == 0 {
+                return Ok(None);
+            }
+            let number = tail[..digits].parse::<usize>().unwrap_or(0);
+            (self.get(number), &tail[digits..])
+        } else {
+            return Ok(None);
+        };
+
+        let entry = entry.ok_or(KernelError::ValidationError("No such history entry"))?;
+        Ok(Some(format!("{}{}", entry, rest)))
+    }
+}
+
+/// Read the history file. Returns the good lines and whether the file was
+/// intact; reading stops at the first line that can't be a command.
+fn read_lines(path: &str) -> Result<(Vec<String>, bool), KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let size = match vfs.metadata(path) {
+        Ok(metadata) => metadata.size as usize,
+        Err(KernelError::NotFound) => return Ok((Vec::new(), true)),
+        Err(e) => return Err(e),
+    };
+
+    let offset = size.saturating_sub(MAX_FILE_READ);
+    let mut data = vec![0u8; size - offset];
+    let read = vfs.find_fs(path)?.lock().read_at(path, offset as u64, &mut data)?;
+    data.truncate(read);
+
+    // Starting mid-file means the first line is partial
+    let mut start = 0;
+    if offset > 0 {
+        start = data.iter().position(|b| *b == b'\n').map_or(data.len(), |i| i + 1);
+    }
+
+    let (text, mut intact) = match core::str::from_utf8(&data[start..]) {
+        Ok(text) => (text, true),
+        Err(e) => (core::str::from_utf8(&data[start..start + e.valid_up_to()]).unwrap_or(""), false),
+    };
+
+    let mut lines = Vec::new();
+    for piece in text.split_inclusive('\n') {
+        if !piece.ends_with('\n') {
+            // Unterminated: cut short by bad bytes (dropped) or by an
+            // interrupted write (kept, and rewritten properly)
+            if !intact {
+                break;
+            }
+            intact = false;
+        }
+        let line = piece.trim_end_matches('\n').trim_end_matches('\r');
+        if line.len() > MAX_LINE_LEN || line.chars().any(char::is_control) {
+            intact = false;
+            break;
+        }
+        if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+    Ok((lines, intact))
+}
+
+/// Add one line to the end of the file, creating it if needed
+fn append_line(path: &str, line: &str) -> Result<(), KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let size = match vfs.metadata(path) {
+        Ok(metadata) => metadata.size,
+        Err(KernelError::NotFound) => {
+            vfs.create_file(path)?;
+            0
+        }
+        Err(e) => return Err(e),
+    };
+    let text = format!("{}\n", line);
+    vfs.write_at(path, size, text.as_bytes())?;
+    Ok(())
+}
+
+/// Replace the file's contents with `lines`
+fn rewrite(path: &str, lines: &[String]) -> Result<(), KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let mut text = String::new();
+    for line in lines {
+        text.push_str(line);
+        text.push('\n');
+    }
+    vfs.write_file_atomic(path, text.as_bytes())
+}
+
+/// Check duplicate suppression, trimming, expansion and recovery from a
+/// corrupt file, using a scratch file in /tmp
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("HISTORY: Running self-test");
+
+    let mut history = History::new(3);
+    for line in ["ls", "ls", "pwd", "cd /", "echo hi"] {
+        history.push(line);
+    }
+    if history.len() != 3 || history.get(1) != Some("pwd") || history.last() != Some("echo hi") {
+        return Err(KernelError::ValidationError("History kept the wrong entries"));
+    }
+    if history.expand("!!")? != Some("echo hi".to_string())
+        || history.expand("!2 /tmp")? != Some("cd / /tmp".to_string())
+        || history.expand("echo !!")?.is_some()
+        || history.expand("!9").is_ok()
+    {
+        return Err(KernelError::ValidationError("History expansion is wrong"));
+    }
+
+    // Persistence needs a file system; without one there is nothing to check
+    let vfs = match fs::vfs::get_vfs_manager() {
+        Some(vfs) => vfs,
+        None => {
+            serial_println!("HISTORY: Self-test passed (no file system)");
+            return Ok(());
+        }
+    };
+
+    let path = "/tmp/.history-selftest";
+    let _ = vfs.remove(path);
+    vfs.create_file(path)?;
+    fs::direct_write_file(path, b"one\ntwo\nthr\xFFee\nfour\n")?;
+
+    let result = (|| {
+        let mut first = History::load(path, 10);
+        if first.len() != 2 || first.last() != Some("two") {
+            return Err(KernelError::ValidationError("Corrupt history file not cut back"));
+        }
+        let mut second = History::load(path, 10);
+        first.push("from first");
+        second.push("from second");
+        if first.last() != Some("from first") || second.last() != Some("from second") {
+            return Err(KernelError::ValidationError("Shells share in-memory history"));
+        }
+        let reloaded = History::load(path, 3);
+        let entries: Vec<&str> = reloaded.numbered().map(|(_, entry)| entry).collect();
+        if entries != ["two", "from first", "from second"] {
+            return Err(KernelError::ValidationError("History file not appended and trimmed"));
+        }
+        Ok(())
+    })();
+    let _ = vfs.remove(path);
+
+    result?;
+    serial_println!("HISTORY: Self-test passed");
+    Ok(())
+}
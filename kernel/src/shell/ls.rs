@@ -0,0 +1,217 @@
+//! What `ls` prints: sorting, colors by file type and the long format
+//!
+//! Colors are screen attributes, not escape codes in the text, so a line
+//! is the same plain text whatever color it's shown in.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::drivers::rtc::DateTime;
+use crate::drivers::vga_enhanced::Color;
+use crate::errors::KernelError;
+use crate::fs::vfs::{attributes, permissions, Metadata, NodeType};
+use crate::serial_println;
+
+/// How `ls` was asked to list
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    /// -l: permissions, attributes, size and modified time too
+    pub long: bool,
+    /// -d: directories before everything else
+    pub dirs_first: bool,
+    /// -a: hidden entries too
+    pub all: bool,
+}
+
+impl Options {
+    /// Read flags such as "-l" or "-ld" from `args`, returning the options
+    /// and the arguments that aren't flags
+    pub fn parse<'a>(args: &[&'a str]) -> Result<(Self, Vec<&'a str>), KernelError> {
+        let mut options = Self::default();
+        let mut rest = Vec::new();
+        for arg in args {
+            let flags = match arg.strip_prefix('-') {
+                Some(flags) if !flags.is_empty() => flags,
+                _ => {
+                    rest.push(*arg);
+                    continue;
+                }
+            };
+            for flag in flags.chars() {
+                match flag {
+                    'l' => options.long = true,
+                    'd' => options.dirs_first = true,
+                    'a' => options.all = true,
+                    _ => return Err(KernelError::InvalidParameter),
+                }
+            }
+        }
+        Ok((options, rest))
+    }
+}
+
+/// One directory entry with what's known about it
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    /// None if the file system couldn't say
+    pub metadata: Option<Metadata>,
+    pub node_type: NodeType,
+}
+
+/// Whether `ls` leaves an entry out without -a: its name starts with a
+/// dot, or the file system marks it hidden
+pub fn is_hidden(entry: &Entry) -> bool {
+    entry.name.starts_with('.') || entry.metadata.as_ref().is_some_and(|metadata| metadata.attributes & attributes::HIDDEN != 0)
+}
+
+/// Sort by name, with directories first if asked
+pub fn sort(entries: &mut [Entry], dirs_first: bool) {
+    entries.sort_by(|a, b| {
+        let group = |entry: &Entry| dirs_first && entry.node_type != NodeType::Directory;
+        group(a).cmp(&group(b)).then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+/// Color an entry is listed in
+pub fn color(entry: &Entry) -> Color {
+    match entry.node_type {
+        NodeType::Directory => Color::LightCyan,
+        NodeType::SymbolicLink => Color::Yellow,
+        NodeType::CharacterDevice | NodeType::BlockDevice => Color::Magenta,
+        NodeType::File if entry.metadata.as_ref().is_some_and(|metadata| metadata.permissions & permissions::EXECUTE != 0) => {
+            Color::LightGreen
+        }
+        _ => Color::White,
+    }
+}
+
+/// The name with "/" after a directory
+fn name(entry: &Entry) -> String {
+    match entry.node_type {
+        NodeType::Directory => format!("{}/", entry.name),
+        _ => entry.name.clone(),
+    }
+}
+
+/// Letter the long format shows for a type
+fn type_letter(node_type: NodeType) -> char {
+    match node_type {
+        NodeType::File => '-',
+        NodeType::Directory => 'd',
+        NodeType::SymbolicLink => 'l',
+        NodeType::BlockDevice => 'b',
+        NodeType::CharacterDevice => 'c',
+        NodeType::FIFO => 'p',
+        NodeType::Socket => 's',
+    }
+}
+
+/// Type letter and rwx for owner, group and others, like "drwxr-x---"
+pub fn mode_string(node_type: NodeType, mode: u16) -> String {
+    let mut text = String::from(type_letter(node_type));
+    // Owner bits are the low three here
+    for shift in [0, 3, 6] {
+        let bits = mode >> shift;
+        text.push(if bits & permissions::READ != 0 { 'r' } else { '-' });
+        text.push(if bits & permissions::WRITE != 0 { 'w' } else { '-' });
+        text.push(if bits & permissions::EXECUTE != 0 { 'x' } else { '-' });
+    }
+    text
+}
+
+/// Read-only, hidden, system and archive as "rhsa", with '-' for each
+/// that's clear
+pub fn attribute_string(bits: u8) -> String {
+    [(attributes::READ_ONLY, 'r'), (attributes::HIDDEN, 'h'), (attributes::SYSTEM, 's'), (attributes::ARCHIVE, 'a')]
+        .iter()
+        .map(|(bit, letter)| if bits & bit != 0 { *letter } else { '-' })
+        .collect()
+}
+
+/// "2026-10-17 09:30", or "-" where the file system keeps no time
+fn modified(seconds: u64) -> String {
+    if seconds == 0 {
+        return String::from("-");
+    }
+    let mut text = DateTime::from_unix_seconds(seconds).format();
+    text.truncate("YYYY-MM-DD HH:MM".len());
+    text
+}
+
+/// Each entry's line and color, in the order given. The long format lines
+/// its sizes up on the right.
+pub fn lines(entries: &[Entry], options: Options) -> Vec<(String, Color)> {
+    if !options.long {
+        return entries.iter().map(|entry| (name(entry), color(entry))).collect();
+    }
+    let sizes: Vec<String> = entries.iter()
+        .map(|entry| entry.metadata.as_ref().map_or(String::from("?"), |metadata| format!("{}", metadata.size)))
+        .collect();
+    let width = sizes.iter().map(|size| size.len()).max().unwrap_or(0);
+    entries.iter().zip(&sizes).map(|(entry, size)| {
+        let (mode, attributes, time) = match entry.metadata {
+            Some(ref metadata) => (mode_string(entry.node_type, metadata.permissions), attribute_string(metadata.attributes),
+                modified(metadata.modified_at)),
+            None => (format!("{}?????????", type_letter(entry.node_type)), String::from("????"), String::from("-")),
+        };
+        (format!("{}  {}  {:>width$}  {:<16}  {}", mode, attributes, size, time, name(entry), width = width), color(entry))
+    }).collect()
+}
+
+/// Check flag parsing, sort order, colors, hiding and long lines
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("LS: Running self-test");
+
+    let (options, rest) = Options::parse(&["-ld", "/tmp"])?;
+    if options != (Options { long: true, dirs_first: true, all: false }) || rest != ["/tmp"] || !Options::parse(&["-a"])?.0.all
+        || Options::parse(&["-x"]).is_ok() || Options::parse(&["-"])?.1 != ["-"] {
+        return Err(KernelError::ValidationError("ls flags parsed wrongly"));
+    }
+
+    let entry = |name: &str, node_type, mode, size| {
+        let mut metadata = Metadata::new_file();
+        metadata.node_type = node_type;
+        metadata.permissions = mode;
+        metadata.size = size;
+        Entry { name: String::from(name), metadata: Some(metadata), node_type }
+    };
+    let mut entries = [
+        entry("run.sh", NodeType::File, permissions::OWNER_ALL, 12),
+        entry("docs", NodeType::Directory, permissions::ALL, 0),
+        entry("a.txt", NodeType::File, permissions::READ | permissions::WRITE, 2048),
+        entry("null", NodeType::CharacterDevice, permissions::ALL, 0),
+    ];
+    sort(&mut entries, false);
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    if names != ["a.txt", "docs", "null", "run.sh"] {
+        return Err(KernelError::ValidationError("ls sorted wrongly"));
+    }
+    sort(&mut entries, true);
+    let colors: Vec<Color> = entries.iter().map(color).collect();
+    if entries[0].name != "docs"
+        || colors != [Color::LightCyan, Color::White, Color::Magenta, Color::LightGreen] {
+        return Err(KernelError::ValidationError("ls grouped or colored wrongly"));
+    }
+
+    let long = lines(&entries, Options { long: true, dirs_first: true, all: false });
+    if long[0].0 != "drwxrwxrwx  ----     0  -                 docs/"
+        || long[1].0 != "-rw-------  ----  2048  -                 a.txt"
+        || attribute_string(attributes::READ_ONLY | attributes::ARCHIVE) != "r--a"
+        || mode_string(NodeType::File, permissions::OWNER_ALL | permissions::GROUP_READ | permissions::OTHERS_EXEC)
+            != "-rwxr----x" {
+        serial_println!("LS: Long format gave {:?}", long);
+        return Err(KernelError::ValidationError("ls -l lines wrong"));
+    }
+
+    let mut marked = entry("BOOT.SYS", NodeType::File, permissions::READ, 1);
+    if let Some(metadata) = marked.metadata.as_mut() {
+        metadata.attributes = attributes::HIDDEN | attributes::SYSTEM;
+    }
+    if !is_hidden(&marked) || !is_hidden(&entry(".aliases", NodeType::File, permissions::READ, 1)) || is_hidden(&entries[0]) {
+        return Err(KernelError::ValidationError("ls hid the wrong entries"));
+    }
+
+    serial_println!("LS: Self-test passed");
+    Ok(())
+}
@@ -1,17 +1,114 @@
 //! Shell implementation for UniverseK OS
 //! Provides a simple command-line interface for the kernel
 
+pub mod at;
+pub mod bench;
+pub mod commands;
+pub mod dirstack;
+pub mod history;
+pub mod ls;
+pub mod parse;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use crate::serial_println;
 use crate::drivers::vga_enhanced::{self, Color};
-use crate::drivers::ps2_keyboard::{KeyCode, KeyEvent, KeyState};
+use crate::drivers::ps2_keyboard::{self, KeyCode, KeyEvent, KeyState};
+use crate::drivers::input;
 use crate::fs;
+use crate::fs::path::Path;
+use crate::config;
+use crate::device::iostats;
+use crate::gui::clipboard;
 use crate::errors::KernelError;
+use crate::faultinject;
+use crate::i18n::{self, MSG_CANCELLED, MSG_CREATED_DIRECTORY, MSG_CREATED_FILE, MSG_ERROR, MSG_EVENT_NOT_FOUND,
+    MSG_HELP_EDITING, MSG_NO_SUCH_COMMAND, MSG_REMOVED, MSG_UNKNOWN_COMMAND, MSG_UNKNOWN_COMMAND_SUGGEST, MSG_USAGE};
+use crate::tr;
+use crate::task::cancel::CancellationToken;
+use crate::task::scheduler;
+use crate::text;
+use crate::user::motd;
+use dirstack::DirStack;
+use history::History;
+
+pub use commands::{register_command, Command};
+
+/// Killed text kept for yanking
+const KILL_RING_SIZE: usize = 8;
+
+/// Keys typed while a command runs that are kept for the prompt
+const TYPEAHEAD_SIZE: usize = 64;
+/// Bytes `cp` moves between checks for Ctrl+C
+const COPY_BLOCK_SIZE: usize = 512;
+/// Exit code of a command stopped with Ctrl+C, as other shells report it
+const INTERRUPTED_STATUS: i64 = 130;
+/// Why `startx` and Ctrl+Alt+F2 did nothing
+const NO_DESKTOP: &str = "The desktop isn't available (no display, safe mode, or the GUI failed to start)";
+
+/// Commands a shell in a GUI window refuses: they take over the screen
+const CONSOLE_ONLY: &[&str] = &["bench", "lock"];
+/// Why `run` refused to start
+const NESTED_RUN: &str = "The full-screen shell can't start while the desktop or another session has the screen";
+
+/// Something a command will do once the user answers yes
+type Confirmation = Box<dyn FnOnce(&mut Shell) -> Result<(), KernelError> + Send>;
+
+/// A password `passwd` is asking for; the next line entered is the answer
+/// and is neither shown nor kept in the history
+struct PasswordPrompt {
+    user: String,
+    /// `passwd -d`: remove the password once the current one is given
+    remove: bool,
+    step: PasswordStep,
+}
+
+enum PasswordStep {
+    Current,
+    New,
+    /// Typed again to catch typos, with what was typed the first time
+    Retype(String),
+}
+
+impl PasswordStep {
+    fn question(&self) -> &'static str {
+        match self {
+            PasswordStep::Current => "Current password: ",
+            PasswordStep::New => "New password: ",
+            PasswordStep::Retype(_) => "Retype new password: ",
+        }
+    }
+}
+
+/// What Esc does in the full-screen shell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitPolicy {
+    /// `run` returns to whoever started it
+    ReturnToCaller,
+    /// There's nothing to go back to, so Esc offers to reboot instead
+    RebootPrompt,
+}
 
-/// Maximum number of command history entries
-const MAX_HISTORY: usize = 10;
+/// How a `run` of the full-screen shell ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellExit {
+    /// Esc was pressed and the exit policy let the shell return
+    Escaped,
+    /// A switch to the desktop was asked for
+    Switched,
+}
+
+/// What a shell that isn't on the screen printed, for its owner to show
+#[derive(Debug, Default)]
+pub struct CapturedOutput {
+    pub lines: Vec<(String, Color)>,
+    /// `clear` ran; earlier output should go before `lines` are shown
+    pub clear: bool,
+}
 
 /// Shell state and configuration
 pub struct Shell {
@@ -19,12 +116,20 @@ pub struct Shell {
     input_buffer: String,
     /// Cursor position in the input buffer
     cursor_position: usize,
+    /// Other end of the selection; the selection runs from here to the cursor
+    selection_anchor: Option<usize>,
+    /// First input character shown, when the line is wider than the window
+    input_scroll: usize,
+    /// Text removed by Ctrl+W/K/U, most recent first
+    kill_ring: VecDeque<String>,
     /// Command history
-    history: Vec<String>,
+    history: History,
     /// Current position in history (when navigating with up/down arrows)
     history_position: usize,
     /// Current working directory
     current_dir: String,
+    /// Directories saved by pushd, and where `cd -` goes
+    dirs: DirStack,
     /// Shell prompt string
     prompt: String,
     /// Shell window position and size
@@ -32,6 +137,35 @@ pub struct Shell {
     window_y: usize,
     window_width: usize,
     window_height: usize,
+    /// Waiting on a yes/no answer; the next line entered is the answer
+    pending_confirmation: Option<Confirmation>,
+    /// Lines of a new message of the day being typed after `motd edit`
+    motd_draft: Option<Vec<String>>,
+    /// Waiting on a password for `passwd`
+    password_prompt: Option<PasswordPrompt>,
+    /// Changes `fswatch` is printing as they happen
+    fs_watch: Option<fs::watch::WatchHandle>,
+    /// Cancels the running command; Ctrl+C sets it
+    cancel: CancellationToken,
+    /// Keys that arrived while a command ran, handled once it's done
+    typeahead: VecDeque<KeyEvent>,
+    /// The last command was stopped with Ctrl+C; the prompt says so
+    interrupted: bool,
+    /// Line being typed on the serial console, when there is no screen
+    serial_line: String,
+    /// The last serial byte was a carriage return, so a line feed after it
+    /// ends no further line
+    serial_after_cr: bool,
+    /// When `iostat` last ran and the counters it saw, for its rates
+    io_snapshot: Option<(u64, Vec<iostats::DeviceIo>)>,
+    /// `run` has shown the welcome already, so coming back from the
+    /// desktop doesn't show it again
+    ran_before: bool,
+    /// What Esc does during the `run` in progress
+    exit_policy: ExitPolicy,
+    /// Output kept for the owner instead of drawn, for a shell that isn't
+    /// on the screen (see `detached`)
+    captured: Option<CapturedOutput>,
 }
 
 impl Shell {
@@ -40,17 +174,75 @@ impl Shell {
         Self {
             input_buffer: String::new(),
             cursor_position: 0,
-            history: Vec::new(),
+            selection_anchor: None,
+            input_scroll: 0,
+            kill_ring: VecDeque::with_capacity(KILL_RING_SIZE),
+            history: History::new(history::configured_size()),
             history_position: 0,
             current_dir: "/".to_string(),
+            dirs: DirStack::new(dirstack::configured_size()),
             prompt: "$ ".to_string(),
             window_x: 1,
             window_y: 2,
             window_width: 78,
             window_height: 22,
+            pending_confirmation: None,
+            motd_draft: None,
+            password_prompt: None,
+            fs_watch: None,
+            cancel: CancellationToken::new(),
+            typeahead: VecDeque::new(),
+            interrupted: false,
+            serial_line: String::new(),
+            serial_after_cr: false,
+            io_snapshot: None,
+            ran_before: false,
+            exit_policy: ExitPolicy::ReturnToCaller,
+            captured: None,
         }
     }
     
+    /// A shell working in `dir` that doesn't draw on the screen or read
+    /// the keyboard: what it prints is kept for `take_output`. GUI
+    /// terminal windows and `at` jobs run their lines in one.
+    pub fn detached(dir: &str) -> Self {
+        let mut shell = Self::new();
+        shell.current_dir = dir.to_string();
+        shell.captured = Some(CapturedOutput::default());
+        shell
+    }
+    
+    /// Pick up commands from earlier sessions and other shells, and add
+    /// new ones to the shared history file
+    pub fn load_history(&mut self) {
+        self.history = History::load(&history::default_path(), history::configured_size());
+    }
+    
+    pub fn current_dir(&self) -> &str {
+        &self.current_dir
+    }
+    
+    /// Run `line` as if it had been typed at the prompt, and return what it
+    /// printed; for a `detached` shell
+    pub fn run_line(&mut self, line: &str) -> CapturedOutput {
+        self.input_buffer = line.to_string();
+        self.cursor_position = self.input_buffer.len();
+        self.selection_anchor = None;
+        self.execute_command();
+        self.take_output()
+    }
+    
+    /// Whether the next line is a password, for a window to hide it as
+    /// it's typed
+    pub fn reading_password(&self) -> bool {
+        self.password_prompt.is_some()
+    }
+    
+    /// What a `detached` shell has printed since the last call
+    pub fn take_output(&mut self) -> CapturedOutput {
+        self.captured.as_mut().map(core::mem::take).unwrap_or_default()
+    }
+    
     /// Set the shell prompt
     pub fn set_prompt(&mut self, prompt: &str) {
         self.prompt = prompt.to_string();
@@ -72,12 +264,19 @@ impl Shell {
         serial_println!("DEBUG: Shell.init() - Drawing initial prompt");
         self.draw_prompt();
         
+        // Pick up commands from earlier sessions and other shells
+        self.load_history();
+        
         // Log initialization success
         serial_println!("DEBUG: Shell.init() - Shell initialization complete");
     }
     
     /// Clear the shell screen
     pub fn clear_screen(&self) {
+        // Headless, output goes line by line to the serial console
+        if !vga_enhanced::display_available() || self.captured.is_some() {
+            return;
+        }
         serial_println!("DEBUG: Shell.clear_screen() - Clearing VGA screen");
         // Check if VGA is working
         let test_msg = "Testing VGA";
@@ -95,15 +294,37 @@ impl Shell {
         
         // Draw title and border
         vga_enhanced::write_at(0, 2, " UniverseK OS Terminal ", Color::White, Color::Blue);
-        vga_enhanced::write_at(0, 68, " [ESC] Exit ", Color::White, Color::Blue);
+        let exit_label = match self.exit_policy {
+            ExitPolicy::ReturnToCaller => " [ESC] Exit ",
+            ExitPolicy::RebootPrompt => " [ESC] Reboot ",
+        };
+        vga_enhanced::write_at(0, 80 - exit_label.len(), exit_label, Color::White, Color::Blue);
+        if crate::safe_mode::is_active() {
+            let banner = crate::safe_mode::BANNER;
+            vga_enhanced::write_at(0, (80 - banner.len()) / 2, banner, Color::White, Color::Red);
+        }
         
         // Draw border around terminal area
         vga_enhanced::draw_shadowed_box(1, 1, 78, 22);
         serial_println!("DEBUG: Shell.clear_screen() - Screen cleared successfully");
     }
     
+    /// Blank the screen for whoever has it next, and drop keys and serial
+    /// input meant for this session
+    fn leave_screen(&mut self) {
+        self.typeahead.clear();
+        self.serial_line.clear();
+        if vga_enhanced::display_available() {
+            vga_enhanced::clear_screen();
+            vga_enhanced::set_cursor_position(0, 0);
+        }
+    }
+    
     /// Display welcome message
     fn display_welcome(&self) {
+        if self.captured.is_some() {
+            return;
+        }
         let welcome_text = concat!(
             "Welcome to UniverseK OS Terminal\n",
             "Type 'help' for a list of available commands.\n",
@@ -112,10 +333,20 @@ impl Shell {
         vga_enhanced::write_at(2, 2, welcome_text, Color::LightGreen, Color::Black);
     }
     
+    /// Print the message of the day, if there is one
+    fn show_motd(&mut self) {
+        let lines = motd::lines();
+        if !lines.is_empty() {
+            self.output_line(&lines.join("\n"));
+        }
+    }
+    
     /// Draw the command prompt
     fn draw_prompt(&self) {
-        // Format the prompt with current directory
-        let full_prompt = format!("{}:{}{}", "user", self.current_dir, self.prompt);
+        if self.captured.is_some() {
+            return;
+        }
+        let full_prompt = self.prompt_text();
         vga_enhanced::write_at(self.window_height - 2, 2, &full_prompt, 
                              Color::LightCyan, Color::Black);
     }
@@ -129,33 +360,94 @@ impl Shell {
         
         match key_event.code {
             // Handle special keys
-            KeyCode::Escape => return true, // Signal to exit shell
+            KeyCode::Escape => return self.confirm_exit(), // Signal to exit shell
             KeyCode::Enter => {
                 self.execute_command();
                 return false;
             },
             KeyCode::Backspace => {
-                if self.cursor_position > 0 {
+                if self.delete_selection() {
+                    self.redraw_input_line();
+                } else if self.cursor_position > 0 {
                     self.input_buffer.remove(self.cursor_position - 1);
                     self.cursor_position -= 1;
                     self.redraw_input_line();
                 }
                 return false;
             },
-            KeyCode::LeftBracket if key_event.ctrl => { // Use Ctrl+[ as left arrow
-                if self.cursor_position > 0 {
-                    self.cursor_position -= 1;
-                    self.update_cursor();
+            KeyCode::Delete => {
+                if !self.delete_selection() && self.cursor_position < self.input_buffer.len() {
+                    self.input_buffer.remove(self.cursor_position);
                 }
+                self.redraw_input_line();
                 return false;
             },
-            KeyCode::RightBracket if key_event.ctrl => { // Use Ctrl+] as right arrow
-                if self.cursor_position < self.input_buffer.len() {
-                    self.cursor_position += 1;
-                    self.update_cursor();
+            KeyCode::ArrowLeft | KeyCode::ArrowRight | KeyCode::Home | KeyCode::End => {
+                self.move_cursor(key_event);
+                return false;
+            },
+            // Ctrl+[ and Ctrl+] also work where the arrows don't
+            KeyCode::LeftBracket | KeyCode::RightBracket | KeyCode::E if key_event.ctrl => {
+                self.move_cursor(key_event);
+                return false;
+            },
+            KeyCode::B | KeyCode::F if key_event.alt => {
+                self.move_cursor(key_event);
+                return false;
+            },
+            KeyCode::A if key_event.ctrl && key_event.shift => { // Select the whole line
+                self.selection_anchor = Some(0);
+                self.cursor_position = self.input_buffer.len();
+                self.redraw_input_line();
+                return false;
+            },
+            KeyCode::A if key_event.ctrl => { // Start of line
+                self.selection_anchor = None;
+                self.cursor_position = 0;
+                self.redraw_input_line();
+                return false;
+            },
+            KeyCode::W if key_event.ctrl => { // Kill the word before the cursor
+                let start = self.word_start_before(self.cursor_position);
+                self.kill(start, self.cursor_position);
+                return false;
+            },
+            KeyCode::K if key_event.ctrl => { // Kill to the end of the line
+                self.kill(self.cursor_position, self.input_buffer.len());
+                return false;
+            },
+            KeyCode::U if key_event.ctrl => { // Kill to the start of the line
+                self.kill(0, self.cursor_position);
+                return false;
+            },
+            KeyCode::Y if key_event.ctrl => { // Yank the last killed text
+                if let Some(text) = self.kill_ring.front().cloned() {
+                    self.delete_selection();
+                    self.insert_text(&text);
+                    self.redraw_input_line();
+                }
+                return false;
+            },
+            KeyCode::ArrowUp => {
+                self.navigate_history_up();
+                return false;
+            },
+            KeyCode::ArrowDown => {
+                self.navigate_history_down();
+                return false;
+            },
+            KeyCode::C if key_event.ctrl => { // Copy the selection
+                if let Some((start, end)) = self.selection_range().filter(|_| !self.reading_password()) {
+                    if let Err(e) = clipboard::set(&self.input_buffer[start..end]) {
+                        serial_println!("DEBUG: Shell - copy failed: {:?}", e);
+                    }
                 }
                 return false;
             },
+            KeyCode::V if key_event.ctrl => { // Paste
+                self.paste(&clipboard::get());
+                return false;
+            },
             KeyCode::P if key_event.ctrl => { // Use Ctrl+P as up arrow
                 self.navigate_history_up();
                 return false;
@@ -166,7 +458,11 @@ impl Shell {
             },
             // Handle regular keys (convert to ASCII/Unicode)
             _ => {
-                if let Some(c) = self.key_to_char(key_event) {
+                if key_event.ctrl || key_event.alt {
+                    return false;
+                }
+                if let Some(c) = key_event.to_char() {
+                    self.delete_selection();
                     self.input_buffer.insert(self.cursor_position, c);
                     self.cursor_position += 1;
                     self.redraw_input_line();
@@ -176,66 +472,169 @@ impl Shell {
         }
     }
     
-    /// Convert a key event to a character
-    fn key_to_char(&self, key_event: KeyEvent) -> Option<char> {
-        let key_code = key_event.code;
-        let shift = key_event.shift;
-        
-        match key_code {
-            KeyCode::Key1 => Some(if shift { '!' } else { '1' }),
-            KeyCode::Key2 => Some(if shift { '@' } else { '2' }),
-            KeyCode::Key3 => Some(if shift { '#' } else { '3' }),
-            KeyCode::Key4 => Some(if shift { '$' } else { '4' }),
-            KeyCode::Key5 => Some(if shift { '%' } else { '5' }),
-            KeyCode::Key6 => Some(if shift { '^' } else { '6' }),
-            KeyCode::Key7 => Some(if shift { '&' } else { '7' }),
-            KeyCode::Key8 => Some(if shift { '*' } else { '8' }),
-            KeyCode::Key9 => Some(if shift { '(' } else { '9' }),
-            KeyCode::Key0 => Some(if shift { ')' } else { '0' }),
-            KeyCode::A => Some(if shift { 'A' } else { 'a' }),
-            KeyCode::B => Some(if shift { 'B' } else { 'b' }),
-            KeyCode::C => Some(if shift { 'C' } else { 'c' }),
-            KeyCode::D => Some(if shift { 'D' } else { 'd' }),
-            KeyCode::E => Some(if shift { 'E' } else { 'e' }),
-            KeyCode::F => Some(if shift { 'F' } else { 'f' }),
-            KeyCode::G => Some(if shift { 'G' } else { 'g' }),
-            KeyCode::H => Some(if shift { 'H' } else { 'h' }),
-            KeyCode::I => Some(if shift { 'I' } else { 'i' }),
-            KeyCode::J => Some(if shift { 'J' } else { 'j' }),
-            KeyCode::K => Some(if shift { 'K' } else { 'k' }),
-            KeyCode::L => Some(if shift { 'L' } else { 'l' }),
-            KeyCode::M => Some(if shift { 'M' } else { 'm' }),
-            KeyCode::N => Some(if shift { 'N' } else { 'n' }),
-            KeyCode::O => Some(if shift { 'O' } else { 'o' }),
-            KeyCode::P => Some(if shift { 'P' } else { 'p' }),
-            KeyCode::Q => Some(if shift { 'Q' } else { 'q' }),
-            KeyCode::R => Some(if shift { 'R' } else { 'r' }),
-            KeyCode::S => Some(if shift { 'S' } else { 's' }),
-            KeyCode::T => Some(if shift { 'T' } else { 't' }),
-            KeyCode::U => Some(if shift { 'U' } else { 'u' }),
-            KeyCode::V => Some(if shift { 'V' } else { 'v' }),
-            KeyCode::W => Some(if shift { 'W' } else { 'w' }),
-            KeyCode::X => Some(if shift { 'X' } else { 'x' }),
-            KeyCode::Y => Some(if shift { 'Y' } else { 'y' }),
-            KeyCode::Z => Some(if shift { 'Z' } else { 'z' }),
-            KeyCode::Space => Some(' '),
-            KeyCode::Minus => Some(if shift { '_' } else { '-' }),
-            KeyCode::Equals => Some(if shift { '+' } else { '=' }),
-            KeyCode::LeftBracket => Some(if shift { '{' } else { '[' }),
-            KeyCode::RightBracket => Some(if shift { '}' } else { ']' }),
-            KeyCode::Backslash => Some(if shift { '|' } else { '\\' }),
-            KeyCode::Semicolon => Some(if shift { ':' } else { ';' }),
-            KeyCode::Apostrophe => Some(if shift { '"' } else { '\'' }),
-            KeyCode::Backtick => Some(if shift { '~' } else { '`' }),
-            KeyCode::Comma => Some(if shift { '<' } else { ',' }),
-            KeyCode::Period => Some(if shift { '>' } else { '.' }),
-            KeyCode::Slash => Some(if shift { '?' } else { '/' }),
-            _ => None,
+    /// Start or extend the selection when `extend` (Shift) is held, otherwise drop it
+    fn update_selection(&mut self, extend: bool) {
+        if extend {
+            if self.selection_anchor.is_none() {
+                self.selection_anchor = Some(self.cursor_position);
+            }
+        } else {
+            self.selection_anchor = None;
+        }
+    }
+    
+    /// Selected byte range of the input buffer, if any
+    fn selection_range(&self) -> Option<(usize, usize)> {
+        let anchor = self.selection_anchor?;
+        let cursor = self.cursor_position;
+        if anchor == cursor {
+            None
+        } else {
+            Some((anchor.min(cursor), anchor.max(cursor)))
+        }
+    }
+    
+    /// Move the cursor for a movement key, extending the selection with Shift
+    fn move_cursor(&mut self, key_event: KeyEvent) {
+        let target = match key_event.code {
+            KeyCode::ArrowLeft if key_event.ctrl => self.word_start_before(self.cursor_position),
+            KeyCode::ArrowRight if key_event.ctrl => self.word_end_after(self.cursor_position),
+            KeyCode::B => self.word_start_before(self.cursor_position),
+            KeyCode::F => self.word_end_after(self.cursor_position),
+            KeyCode::ArrowLeft | KeyCode::LeftBracket => self.cursor_position.saturating_sub(1),
+            KeyCode::ArrowRight | KeyCode::RightBracket => (self.cursor_position + 1).min(self.input_buffer.len()),
+            KeyCode::Home => 0,
+            KeyCode::End | KeyCode::E => self.input_buffer.len(),
+            _ => return,
+        };
+        self.update_selection(key_event.shift);
+        self.cursor_position = target;
+        self.redraw_input_line();
+    }
+    
+    /// Start of the word before `position`, skipping spaces first
+    fn word_start_before(&self, position: usize) -> usize {
+        let bytes = self.input_buffer.as_bytes();
+        let mut i = position;
+        while i > 0 && bytes[i - 1].is_ascii_whitespace() {
+            i -= 1;
+        }
+        while i > 0 && !bytes[i - 1].is_ascii_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+    
+    /// End of the word after `position`, skipping spaces first
+    fn word_end_after(&self, position: usize) -> usize {
+        let bytes = self.input_buffer.as_bytes();
+        let mut i = position;
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        i
+    }
+    
+    /// Cut `start..end` of the input into the kill ring
+    fn kill(&mut self, start: usize, end: usize) {
+        self.selection_anchor = None;
+        if start >= end {
+            return;
+        }
+        let text: String = self.input_buffer.drain(start..end).collect();
+        if !self.reading_password() {
+            self.kill_ring.push_front(text);
+            self.kill_ring.truncate(KILL_RING_SIZE);
+        }
+        self.cursor_position = start;
+        self.redraw_input_line();
+    }
+    
+    /// Remove the selected text; returns whether there was a selection
+    fn delete_selection(&mut self) -> bool {
+        let range = self.selection_range();
+        self.selection_anchor = None;
+        match range {
+            Some((start, end)) => {
+                self.input_buffer.replace_range(start..end, "");
+                self.cursor_position = start;
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// Insert text at the cursor, keeping line breaks but dropping other
+    /// characters the input line can't hold
+    fn insert_text(&mut self, text: &str) {
+        for c in text.chars() {
+            let c = if c == '\t' { ' ' } else { c };
+            if c == '\n' || c == ' ' || c.is_ascii_graphic() {
+                self.input_buffer.insert(self.cursor_position, c);
+                self.cursor_position += 1;
+            }
         }
     }
     
+    /// Paste text at the cursor. Line breaks are inserted literally unless
+    /// `shell.paste_executes` is set, in which case each complete line is
+    /// run as a command.
+    fn paste(&mut self, text: &str) {
+        let run_lines = config::get("shell.paste_executes")
+            .and_then(|value| value.try_as_boolean())
+            .unwrap_or(false);
+        
+        self.delete_selection();
+        let mut rest = text;
+        if run_lines {
+            while let Some((line, tail)) = rest.split_once('\n') {
+                self.insert_text(line.trim_end_matches('\r'));
+                self.execute_command();
+                rest = tail;
+            }
+        }
+        self.insert_text(rest);
+        self.redraw_input_line();
+    }
+    
+    /// Prompt shown before the input line, marked ^C after a command
+    /// stopped with Ctrl+C
+    fn prompt_text(&self) -> String {
+        if let Some(prompt) = &self.password_prompt {
+            return prompt.step.question().to_string();
+        }
+        let status = if self.interrupted { "^C " } else { "" };
+        format!("{}{}:{}{}", status, "user", self.current_dir, self.prompt)
+    }
+    
+    /// Columns left for input after the prompt. The input itself is ASCII,
+    /// but the prompt holds the directory name, which may not be
+    fn input_width(&self) -> usize {
+        (self.window_width - 2).saturating_sub(text::display_width(&self.prompt_text())).max(1)
+    }
+    
+    /// Scroll the input line horizontally so the cursor stays visible,
+    /// without leaving blank columns once the line got shorter
+    fn scroll_input_to_cursor(&mut self) {
+        let width = self.input_width();
+        if self.cursor_position < self.input_scroll {
+            self.input_scroll = self.cursor_position;
+        } else if self.cursor_position >= self.input_scroll + width {
+            self.input_scroll = self.cursor_position + 1 - width;
+        }
+        self.input_scroll = self.input_scroll.min((self.input_buffer.len() + 1).saturating_sub(width));
+    }
+    
     /// Redraw the input line (current command being typed)
-    fn redraw_input_line(&self) {
+    fn redraw_input_line(&mut self) {
+        self.scroll_input_to_cursor();
+        if self.captured.is_some() {
+            return;
+        }
+        
         // Clear the input line first
         for i in 0..self.window_width - 2 {
             vga_enhanced::write_at(self.window_height - 2, 2 + i, " ", 
@@ -245,10 +644,28 @@ impl Shell {
         // Draw the prompt
         self.draw_prompt();
         
-        // Draw the current input
-        let prompt_len = format!("{}:{}{}", "user", self.current_dir, self.prompt).len();
-        vga_enhanced::write_at(self.window_height - 2, 2 + prompt_len, 
-                             &self.input_buffer, Color::White, Color::Black);
+        // Draw the visible part of the input
+        // Pasted line breaks stay in the buffer but show as spaces, and
+        // passwords as stars
+        let column = 2 + text::display_width(&self.prompt_text());
+        let start = self.input_scroll;
+        let end = self.input_buffer.len().min(start + self.input_width());
+        let display = if self.reading_password() {
+            "*".repeat(end - start)
+        } else {
+            self.input_buffer[start..end].replace('\n', " ")
+        };
+        vga_enhanced::write_at(self.window_height - 2, column, 
+                             &display, Color::White, Color::Black);
+        
+        // Show the visible part of the selection inverted
+        if let Some((sel_start, sel_end)) = self.selection_range() {
+            let (sel_start, sel_end) = (sel_start.max(start), sel_end.min(end));
+            if sel_start < sel_end {
+                vga_enhanced::write_at(self.window_height - 2, column + sel_start - start,
+                                     &display[sel_start - start..sel_end - start], Color::Black, Color::White);
+            }
+        }
         
         // Position the cursor
         self.update_cursor();
@@ -256,8 +673,8 @@ impl Shell {
     
     /// Update the cursor position
     fn update_cursor(&self) {
-        let prompt_len = format!("{}:{}{}", "user", self.current_dir, self.prompt).len();
-        vga_enhanced::set_cursor_position(self.window_height - 2, 2 + prompt_len + self.cursor_position);
+        let column = 2 + text::display_width(&self.prompt_text()) + self.cursor_position - self.input_scroll;
+        vga_enhanced::set_cursor_position(self.window_height - 2, column);
     }
     
     /// Navigate command history upward (older commands)
@@ -268,9 +685,10 @@ impl Shell {
         
         if self.history_position < self.history.len() {
             self.history_position += 1;
-            let index = self.history.len() - self.history_position;
-            self.input_buffer = self.history[index].clone();
+            let number = self.history.len() + 1 - self.history_position;
+            self.input_buffer = self.history.get(number).unwrap_or_default().to_string();
             self.cursor_position = self.input_buffer.len();
+            self.selection_anchor = None;
             self.redraw_input_line();
         }
     }
@@ -284,11 +702,12 @@ impl Shell {
                 // Back to empty line at bottom of history
                 self.input_buffer.clear();
             } else {
-                let index = self.history.len() - self.history_position;
-                self.input_buffer = self.history[index].clone();
+                let number = self.history.len() + 1 - self.history_position;
+                self.input_buffer = self.history.get(number).unwrap_or_default().to_string();
             }
             
             self.cursor_position = self.input_buffer.len();
+            self.selection_anchor = None;
             self.redraw_input_line();
         }
     }
@@ -296,70 +715,278 @@ impl Shell {
     /// Execute the current command
     fn execute_command(&mut self) {
         // Add the command to output area with prompt
-        let prompt = format!("{}:{}{}", "user", self.current_dir, self.prompt);
+        let prompt = self.prompt_text();
         let input_copy = self.input_buffer.clone();
-        self.output_line(&format!("{}{}", prompt, input_copy));
+        // The serial console echoed the line as it was typed, and a window
+        // shows it on its input line
+        if vga_enhanced::display_available() && self.captured.is_none() {
+            let shown = if self.reading_password() { "" } else { input_copy.as_str() };
+            self.output_line(&format!("{}{}", prompt, shown));
+        }
+        
+        // A password answering `passwd` isn't a command, and stays out of the history
+        if let Some(prompt) = self.password_prompt.take() {
+            self.answer_password_prompt(prompt, input_copy);
+            self.input_buffer.clear();
+            self.cursor_position = 0;
+            self.selection_anchor = None;
+            self.redraw_input_line();
+            return;
+        }
+        
+        // A line answering a question isn't a command
+        if let Some(action) = self.pending_confirmation.take() {
+            let answer = input_copy.trim().to_ascii_lowercase();
+            if answer == "y" || answer == "yes" {
+                if let Err(e) = action(self) {
+                    self.output_line(&format!("{}", tr!(MSG_ERROR, i18n::error(&e))));
+                }
+            } else {
+                self.output_line(tr!(MSG_CANCELLED));
+            }
+            self.input_buffer.clear();
+            self.cursor_position = 0;
+            self.selection_anchor = None;
+            self.redraw_input_line();
+            return;
+        }
         
-        // Add to history if not empty and not the same as the last command
-        if !self.input_buffer.is_empty() {
-            if self.history.is_empty() || self.history.last().unwrap() != &self.input_buffer {
-                self.history.push(self.input_buffer.clone());
-                // Trim history if it gets too long
-                if self.history.len() > MAX_HISTORY {
-                    self.history.remove(0);
+        // Lines typed after `motd edit` are the new message, up to a "."
+        if let Some(mut draft) = self.motd_draft.take() {
+            if input_copy.trim_end() == "." {
+                match motd::set(&draft.join("\n")) {
+                    Ok(()) => self.output_line(&format!("Saved {} lines to {}", draft.len(), motd::MOTD_PATH)),
+                    Err(e) => self.output_line(&format!("Can't save {}: {}", motd::MOTD_PATH, e)),
                 }
+            } else {
+                draft.push(input_copy);
+                self.motd_draft = Some(draft);
+            }
+            self.input_buffer.clear();
+            self.cursor_position = 0;
+            self.selection_anchor = None;
+            self.redraw_input_line();
+            return;
+        }
+        
+        // Expand !! and !N before the command is recorded or parsed
+        let mut command = self.input_buffer.trim().to_string();
+        match self.history.expand(&command) {
+            Ok(Some(expanded)) => {
+                self.output_line(&expanded);
+                command = expanded;
+            }
+            Ok(None) => {}
+            Err(_) => {
+                self.output_line(&format!("{}", tr!(MSG_EVENT_NOT_FOUND, command)));
+                command.clear();
             }
+        }
+        
+        // Add to history; repeats of the last command are skipped
+        if !command.is_empty() {
+            self.history.push(&command);
             self.history_position = 0;
         }
         
         // Process command
-        let command = self.input_buffer.trim().to_string();
         if !command.is_empty() {
             let result = self.process_command(&command);
             if let Err(e) = result {
-                self.output_line(&format!("Error: {:?}", e));
+                self.output_line(&format!("{}", tr!(MSG_ERROR, i18n::error(&e))));
             }
         }
         
         // Clear input and redraw prompt
         self.input_buffer.clear();
         self.cursor_position = 0;
+        self.selection_anchor = None;
         self.redraw_input_line();
     }
     
-    /// Process a command and execute it
+    /// Run a command line as a task of its own, which Ctrl+C cancels. A
+    /// line ending in & is refused: tasks don't switch yet, so nothing could
+    /// run it alongside the prompt.
     fn process_command(&mut self, command: &str) -> Result<(), KernelError> {
-        // Split command and arguments
-        let parts: Vec<&str> = command.split_whitespace().collect();
+        if parse::strip_background(command).is_some() {
+            self.output_line("Commands can't run in the background yet; leave off the &.");
+            return Err(KernelError::UnsupportedFeature);
+        }
+        
+        self.interrupted = false;
+        let result = self.run_task(command, CancellationToken::new());
+        if let Err(KernelError::Interrupted) = result {
+            self.interrupted = true;
+            self.output_line("^C");
+            return Ok(());
+        }
+        result
+    }
+    
+    /// Run `command` as a task of its own, cancelled through `cancel`
+    fn run_task(&mut self, command: &str, cancel: CancellationToken) -> Result<(), KernelError> {
+        self.cancel = cancel;
+        let mut result = Ok(());
+        let task = scheduler::run_as_task(&mut || {
+            result = self.dispatch(command);
+            exit_status(&result)
+        });
+        if let Err(e) = task {
+            serial_println!("SHELL: Running '{}' without a task of its own: {:?}", command, e);
+            result = self.dispatch(command);
+        }
+        result
+    }
+    
+    /// Whether Esc may close the shell. With nothing to go back to it asks
+    /// to reboot instead.
+    fn confirm_exit(&mut self) -> bool {
+        if self.exit_policy == ExitPolicy::RebootPrompt {
+            self.output_line("There's nothing to go back to. Reboot? [y/N]");
+            self.pending_confirmation = Some(Box::new(|shell: &mut Shell| shell.cmd_reboot(&[])));
+            return false;
+        }
+        true
+    }
+    
+    /// Whether the running command should stop. Checks the keyboard for
+    /// Ctrl+C; other keys are kept for the prompt. A detached shell leaves
+    /// the keyboard to whoever owns it.
+    fn cancelled(&mut self) -> bool {
+        if self.captured.is_some() {
+            return self.cancel.is_cancelled();
+        }
+        while let Some(event) = ps2_keyboard::get_event() {
+            if event.code == KeyCode::C && event.ctrl {
+                if event.state == KeyState::Pressed {
+                    self.cancel.cancel();
+                }
+            } else if self.typeahead.len() < TYPEAHEAD_SIZE {
+                self.typeahead.push_back(event);
+            }
+        }
+        self.cancel.is_cancelled()
+    }
+    
+    /// Err(Interrupted) once Ctrl+C has been pressed, for command loops
+    fn check_cancelled(&mut self) -> Result<(), KernelError> {
+        self.cancelled();
+        self.cancel.check()
+    }
+    
+    /// Take what has been typed on the serial console, echoing it, and run
+    /// each line as it is finished; for when there is no screen
+    fn poll_serial(&mut self) {
+        while let Some(byte) = crate::serial::try_read_byte() {
+            let after_cr = core::mem::replace(&mut self.serial_after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    serial_println!();
+                    self.input_buffer = core::mem::take(&mut self.serial_line);
+                    self.cursor_position = self.input_buffer.len();
+                    self.execute_command();
+                    crate::serial_print!("{}", self.prompt_text());
+                }
+                // Backspace or Delete
+                0x08 | 0x7F => {
+                    if self.serial_line.pop().is_some() {
+                        crate::serial_print!("\x08 \x08");
+                    }
+                }
+                0x20..=0x7E => {
+                    self.serial_line.push(byte as char);
+                    crate::serial_print!("{}", if self.reading_password() { '*' } else { byte as char });
+                }
+                _ => {}
+            }
+        }
+    }
+    
+    /// Next key to handle: typeahead first, then the keyboard
+    fn next_key(&mut self) -> Option<KeyEvent> {
+        self.typeahead.pop_front().or_else(ps2_keyboard::get_event)
+    }
+    
+    /// Find and run the command a line names
+    fn dispatch(&mut self, command: &str) -> Result<(), KernelError> {
+        // Split command and arguments, honouring quotes and backslashes
+        let words = match parse::tokenize(command) {
+            Ok(words) => words,
+            Err(e) => {
+                self.output_line(&format!("syntax error: {}", e));
+                return Ok(());
+            }
+        };
+        let parts: Vec<&str> = words.iter().map(String::as_str).collect();
         if parts.is_empty() {
             return Ok(());
         }
         
-        let cmd = parts[0];
+        let name = parts[0];
         let args = &parts[1..];
+        if self.captured.is_some() && CONSOLE_ONLY.contains(&name) {
+            self.output_line(&format!("{}: only in the full-screen shell (exitgui switches to it)", name));
+            return Ok(());
+        }
         
-        match cmd {
-            "help" => self.cmd_help(),
-            "echo" => self.cmd_echo(args),
-            "ls" | "dir" => self.cmd_ls(args),
-            "cd" => self.cmd_cd(args),
-            "cat" => self.cmd_cat(args),
-            "cls" | "clear" => self.cmd_clear(),
-            "pwd" => self.cmd_pwd(),
-            "touch" | "mkfile" => self.cmd_touch(args),
-            "mkdir" => self.cmd_mkdir(args),
-            "rm" => self.cmd_rm(args),
-            "reboot" => self.cmd_reboot(),
-            "version" => self.cmd_version(),
-            _ => {
-                self.output_line(&format!("Unknown command: {}", cmd));
-                Ok(())
+        let command = match commands::find(name) {
+            Some(command) => command,
+            // A path runs the program directly
+            None if name.contains('/') => return self.cmd_exec(&parts),
+            None => {
+                let suggestions = commands::suggest(name);
+                if suggestions.is_empty() {
+                    self.output_line(&format!("{}", tr!(MSG_UNKNOWN_COMMAND, name)));
+                } else {
+                    self.output_line(&format!("{}", tr!(MSG_UNKNOWN_COMMAND_SUGGEST, name, suggestions.join(", "))));
+                }
+                return Ok(());
             }
+        };
+        if !command.accepts(args.len()) {
+            self.output_line(&format!("{}", tr!(MSG_USAGE, command.usage)));
+            return Ok(());
+        }
+        (command.handler)(self, args)
+    }
+    
+    /// Print the usage line of a registered command
+    fn show_usage(&mut self, name: &str) {
+        if let Some(command) = commands::find(name) {
+            self.output_line(&format!("{}", tr!(MSG_USAGE, command.usage)));
+        }
+    }
+    
+    /// Print the changes `fswatch` has seen since the last call
+    pub fn poll_watch(&mut self) {
+        let Some((events, lost)) = self.fs_watch.as_ref().map(|watch| watch.take_events()) else {
+            return;
+        };
+        for event in events {
+            self.output_line(&format!("fswatch: {} {}", event.kind.name(), event.path));
+        }
+        if lost {
+            self.output_line("fswatch: some events were lost");
         }
     }
     
     /// Output a line of text in the shell
-    fn output_line(&mut self, text: &str) {
+    pub fn output_line(&mut self, text: &str) {
+        self.output_colored_line(text, Color::White);
+    }
+    
+    /// Output a line of text in `color`
+    pub fn output_colored_line(&mut self, text: &str, color: Color) {
+        if let Some(captured) = self.captured.as_mut() {
+            captured.lines.push((text.to_string(), color));
+            return;
+        }
+        if !vga_enhanced::display_available() {
+            serial_println!("{}", text);
+            return;
+        }
+        
         // Scroll the screen up to make room for new output
         // TODO: Implement proper scrolling
         
@@ -369,7 +996,7 @@ impl Shell {
         
         // Add the new line
         vga_enhanced::write_at(self.window_height - 4, 2, text, 
-                             Color::White, Color::Black);
+                             color, Color::Black);
         
         // Redraw the prompt and input
         self.redraw_input_line();
@@ -377,25 +1004,17 @@ impl Shell {
     
     // Command implementations
     
-    /// Display help information
-    fn cmd_help(&mut self) -> Result<(), KernelError> {
-        let help_text = concat!(
-            "Available commands:\n",
-            "  help       - Display this help message\n",
-            "  echo [msg] - Display a message\n",
-            "  ls [dir]   - List directory contents\n",
-            "  cd [dir]   - Change directory\n",
-            "  pwd        - Print working directory\n",
-            "  cat [file] - Display file contents\n",
-            "  clear/cls  - Clear the screen\n",
-            "  touch [f]  - Create a new file\n",
-            "  mkdir [d]  - Create a new directory\n",
-            "  rm [path]  - Remove a file or directory\n",
-            "  reboot     - Restart the system\n",
-            "  version    - Display OS version\n"
-        );
+    /// List every command, or explain one
+    fn cmd_help(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let text = match args.first() {
+            Some(name) => match commands::find(name) {
+                Some(command) => commands::detail_text(&command),
+                None => format!("{}", tr!(MSG_NO_SUCH_COMMAND, name)),
+            },
+            None => format!("{}{}\n", commands::help_text(), tr!(MSG_HELP_EDITING)),
+        };
         
-        self.output_line(help_text);
+        self.output_line(&text);
         Ok(())
     }
     
@@ -407,59 +1026,96 @@ impl Shell {
     
     /// List directory contents
     fn cmd_ls(&mut self, args: &[&str]) -> Result<(), KernelError> {
-        let path = if args.is_empty() {
-            self.current_dir.clone()
-        } else {
-            self.resolve_path(args[0])
+        let (options, paths) = ls::Options::parse(args)?;
+        let path = match paths.as_slice() {
+            [] => self.current_dir.clone(),
+            [path] => self.resolve_path(path),
+            _ => return Err(KernelError::InvalidParameter),
         };
         
         let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
-        let entries = vfs.read_dir(&path)?;
+        let mut entries = Vec::new();
+        for entry in vfs.read_dir_paged(&path) {
+            let entry = entry?;
+            let metadata = vfs.metadata(&format!("{}/{}", path.trim_end_matches('/'), entry.name)).ok();
+            entries.push(ls::Entry { name: entry.name, metadata, node_type: entry.node_type });
+        }
+        if !options.all {
+            entries.retain(|entry| !ls::is_hidden(entry));
+        }
         
         if entries.is_empty() {
             self.output_line("Directory is empty.");
-        } else {
-            for entry in entries {
-                let type_indicator = match entry.node_type {
-                    fs::vfs::NodeType::Directory => "/",
-                    fs::vfs::NodeType::File => "",
-                    _ => "?",
-                };
-                self.output_line(&format!("{}{}", entry.name, type_indicator));
-            }
+            return Ok(());
+        }
+        ls::sort(&mut entries, options.dirs_first);
+        for (line, color) in ls::lines(&entries, options) {
+            self.output_colored_line(&line, color);
         }
-        
         Ok(())
     }
     
     /// Change current directory
     fn cmd_cd(&mut self, args: &[&str]) -> Result<(), KernelError> {
-        if args.is_empty() {
-            self.current_dir = "/".to_string();
-            return Ok(());
+        match args.first() {
+            None => self.change_dir("/").map(|_| ()),
+            Some(&"-") => {
+                let previous = self.dirs.previous().ok_or(KernelError::InvalidOperation)?.to_string();
+                self.change_dir(&previous)?;
+                let dir = self.current_dir.clone();
+                self.output_line(&dir);
+                Ok(())
+            }
+            Some(path) => self.change_dir(path).map(|_| ()),
         }
-        
-        let new_path = self.resolve_path(args[0]);
+    }
+    
+    /// Make `path` the current directory, if it is one, returning the
+    /// directory left. On failure nothing changes.
+    fn change_dir(&mut self, path: &str) -> Result<String, KernelError> {
+        let new_path = self.resolve_path(path);
         
         // Verify that the directory exists
         let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
         let metadata = vfs.metadata(&new_path)?;
+        if metadata.node_type != fs::vfs::NodeType::Directory {
+            return Err(KernelError::NotADirectory);
+        }
         
-        if let fs::vfs::NodeType::Directory = metadata.node_type {
-            self.current_dir = new_path;
-            Ok(())
-        } else {
-            Err(KernelError::NotADirectory)
+        let old = core::mem::replace(&mut self.current_dir, new_path);
+        self.dirs.left(old.clone());
+        Ok(old)
+    }
+    
+    /// Change directory, saving the one left on the directory stack
+    fn cmd_pushd(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let old = self.change_dir(args[0])?;
+        self.dirs.push(old);
+        self.cmd_dirs(&[])
+    }
+    
+    /// Return to the directory on top of the stack, which stays there if
+    /// it can't be entered
+    fn cmd_popd(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let top = self.dirs.top().ok_or(KernelError::InvalidOperation)?.to_string();
+        self.change_dir(&top)?;
+        self.dirs.pop();
+        self.cmd_dirs(&[])
+    }
+    
+    /// Current directory, then the stack newest first, as popd visits them
+    fn cmd_dirs(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let mut line = self.current_dir.clone();
+        for dir in self.dirs.iter() {
+            line.push(' ');
+            line.push_str(dir);
         }
+        self.output_line(&line);
+        Ok(())
     }
     
     /// Display file contents
     fn cmd_cat(&mut self, args: &[&str]) -> Result<(), KernelError> {
-        if args.is_empty() {
-            self.output_line("Usage: cat <file>");
-            return Ok(());
-        }
-        
         let path = self.resolve_path(args[0]);
         
         // Read and display the file
@@ -477,8 +1133,28 @@ impl Shell {
         Ok(())
     }
     
+    /// Say what each file holds, judged by its first bytes, and how big it is
+    fn cmd_file(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::fs::filetype::{self, FileType};
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        for arg in args {
+            let path = self.resolve_path(arg);
+            let line = match filetype::detect_path(vfs, &path) {
+                Ok((kind @ (FileType::Directory | FileType::Special(_)), _)) => format!("{}: {}", arg, kind.description()),
+                Ok((kind, size)) => format!("{}: {}, {} bytes", arg, kind.description(), size),
+                Err(e) => format!("file: {}: {}", arg, e),
+            };
+            self.output_line(&line);
+        }
+        Ok(())
+    }
+    
     /// Clear the screen
-    fn cmd_clear(&mut self) -> Result<(), KernelError> {
+    fn cmd_clear(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        if let Some(captured) = self.captured.as_mut() {
+            *captured = CapturedOutput { lines: Vec::new(), clear: true };
+            return Ok(());
+        }
         self.clear_screen();
         self.display_welcome();
         self.redraw_input_line();
@@ -486,7 +1162,7 @@ impl Shell {
     }
     
     /// Print working directory
-    fn cmd_pwd(&mut self) -> Result<(), KernelError> {
+    fn cmd_pwd(&mut self, _args: &[&str]) -> Result<(), KernelError> {
         let dir = self.current_dir.clone();
         self.output_line(&dir);
         Ok(())
@@ -494,163 +1170,1730 @@ impl Shell {
     
     /// Create a new file
     fn cmd_touch(&mut self, args: &[&str]) -> Result<(), KernelError> {
-        if args.is_empty() {
-            self.output_line("Usage: touch <filename>");
-            return Ok(());
+        let path = self.resolve_path(args[0]);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        
+        if vfs.metadata(&path).is_ok() {
+            // Existing file: just bump its timestamps, where the fs keeps them
+            let now = crate::time::wall_clock();
+            let update = fs::vfs::MetadataUpdate {
+                modified_at: Some(now),
+                accessed_at: Some(now),
+                ..Default::default()
+            };
+            return match vfs.set_metadata(&path, update) {
+                Err(KernelError::UnsupportedFeature) | Err(KernelError::NotImplemented) => Ok(()),
+                result => result,
+            };
         }
         
+        vfs.create_file(&path)?;
+        self.output_line(&format!("{}", tr!(MSG_CREATED_FILE, path)));
+        
+        Ok(())
+    }
+    
+    /// Change a file's permission bits, given in octal
+    fn cmd_chmod(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let mode = fs::vfs::permissions::parse_octal(args[0]).ok_or(KernelError::InvalidParameter)?;
+        let path = self.resolve_path(args[1]);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        
+        vfs.set_metadata(&path, fs::vfs::MetadataUpdate { permissions: Some(mode), ..Default::default() })
+    }
+    
+    /// Change a file's owner and, optionally, its group
+    fn cmd_chown(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let (uid, gid) = match args[0].split_once(':') {
+            Some((uid, gid)) => (uid, Some(gid)),
+            None => (args[0], None),
+        };
+        let uid = uid.parse::<u32>().map_err(|_| KernelError::InvalidParameter)?;
+        let gid = match gid {
+            Some(gid) => Some(gid.parse::<u32>().map_err(|_| KernelError::InvalidParameter)?),
+            None => None,
+        };
+        let path = self.resolve_path(args[1]);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        
+        vfs.set_metadata(&path, fs::vfs::MetadataUpdate { uid: Some(uid), gid, ..Default::default() })
+    }
+    
+    /// Create a new directory
+    fn cmd_mkdir(&mut self, args: &[&str]) -> Result<(), KernelError> {
         let path = self.resolve_path(args[0]);
         let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
         
-        vfs.create_file(&path)?;
-        self.output_line(&format!("Created file: {}", path));
+        vfs.create_directory(&path)?;
+        self.output_line(&format!("{}", tr!(MSG_CREATED_DIRECTORY, path)));
+        
+        Ok(())
+    }
+    
+    /// Remove a file or directory
+    fn cmd_rm(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let (purge, path) = match args {
+            ["--purge", path] | [path, "--purge"] => (true, *path),
+            [path] => (false, *path),
+            _ => return Err(KernelError::InvalidParameter),
+        };
+        let path = self.resolve_path(path);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        
+        if purge {
+            fs::trash::purge(vfs, &path)?;
+        } else {
+            vfs.remove(&path)?;
+        }
+        self.output_line(&format!("{}", tr!(MSG_REMOVED, path)));
+        
+        Ok(())
+    }
+    
+    /// Show what is in the current user's trash, put an entry back, or
+    /// empty it
+    fn cmd_trash(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let Some(trash) = fs::trash::user_trash() else {
+            self.output_line("Only users with a home under /Users have a trash");
+            return Ok(());
+        };
+        match args {
+            [] | ["list"] => {
+                let entries = match fs::trash::list(&trash) {
+                    Err(KernelError::NotFound) => alloc::vec![],
+                    result => result?,
+                };
+                if entries.is_empty() {
+                    self.output_line("The trash is empty");
+                }
+                for entry in entries {
+                    self.output_line(&format!("{:<32} {}", entry.name, entry.original));
+                }
+            }
+            ["restore", name] => {
+                let original = fs::trash::restore(&trash, name)?;
+                self.output_line(&format!("Restored: {}", original));
+            }
+            ["empty"] => {
+                let emptied = match fs::trash::empty(&trash) {
+                    Err(KernelError::NotFound) => 0,
+                    result => result?,
+                };
+                self.output_line(&format!("Emptied the trash: {} entries removed for good", emptied));
+            }
+            _ => return Err(KernelError::InvalidParameter),
+        }
+        Ok(())
+    }
+    
+    /// Create a hard link
+    fn cmd_ln(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let existing = self.resolve_path(args[0]);
+        let new = self.resolve_path(args[1]);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        vfs.link(&existing, &new)?;
+        self.output_line(&format!("Linked {} to {}", new, existing));
+        Ok(())
+    }
+    
+    /// Copy a file a block at a time. A copy cut short, by Ctrl+C or an
+    /// error, is removed rather than left half written.
+    fn cmd_cp(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use fs::vfs::{file_flags, NodeType};
+        let source = self.resolve_path(args[0]);
+        let mut target = self.resolve_path(args[1]);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        if vfs.metadata(&source)?.node_type == NodeType::Directory {
+            return Err(KernelError::IsADirectory);
+        }
+        // Into a directory, under the source's name
+        if vfs.metadata(&target).map_or(false, |metadata| metadata.node_type == NodeType::Directory) {
+            let name = Path::new(&source).file_name().unwrap_or_default();
+            target = Path::new(&target).join(name).into_string();
+        }
+        if target == source {
+            return Err(KernelError::InvalidOperation);
+        }
+        
+        let reader = fs::fd::open_tagged("cp", &source, file_flags::READ)?;
+        let writer = match fs::fd::open_tagged("cp", &target, file_flags::WRITE | file_flags::CREATE | file_flags::TRUNCATE) {
+            Ok(writer) => writer,
+            Err(e) => {
+                let _ = fs::fd::close(reader);
+                return Err(e);
+            }
+        };
+        let copied = self.copy_blocks(reader, writer);
+        let _ = fs::fd::close(reader);
+        let _ = fs::fd::close(writer);
+        
+        match copied {
+            Ok(bytes) => {
+                self.output_line(&format!("Copied {} bytes to {}", bytes, target));
+                Ok(())
+            }
+            Err(e) => {
+                // The target's old contents went with the truncate, so
+                // there's nothing better to leave than no file. Devices
+                // such as /dev/full stay.
+                let is_file = vfs.metadata(&target).map_or(false, |metadata| metadata.node_type == NodeType::File);
+                if !is_file {
+                    return Err(e);
+                }
+                if let Err(remove_error) = vfs.remove_permanently(&target) {
+                    serial_println!("SHELL: Couldn't remove partial copy {}: {:?}", target, remove_error);
+                }
+                Err(e)
+            }
+        }
+    }
+    
+    /// Copy the rest of `reader` to `writer`, checking for Ctrl+C between
+    /// blocks; returns the bytes copied
+    fn copy_blocks(&mut self, reader: u32, writer: u32) -> Result<u64, KernelError> {
+        let mut buffer = [0u8; COPY_BLOCK_SIZE];
+        let mut copied = 0;
+        loop {
+            self.check_cancelled()?;
+            let read = fs::fd::read(reader, &mut buffer)?;
+            if read == 0 {
+                return Ok(copied);
+            }
+            let mut written = 0;
+            while written < read {
+                match fs::fd::write(writer, &buffer[written..read])? {
+                    0 => return Err(KernelError::WriteError),
+                    count => written += count,
+                }
+            }
+            copied += read as u64;
+        }
+    }
+    
+    /// Print every path under a directory whose name matches a pattern
+    fn cmd_find(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let start = self.resolve_path(args[0]);
+        let pattern = args.get(1).map_or("*", |pattern| *pattern);
+        
+        let mut finder = Finder { shell: self, pattern, matches: 0 };
+        fs::walk::walk(&start, &mut finder)?;
+        if finder.matches == 0 {
+            self.output_line("No matches.");
+        }
+        Ok(())
+    }
+    
+    /// Print the bytes used under each directory, or just the total with -s
+    fn cmd_du(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let summary_only = args.first() == Some(&"-s");
+        let paths = if summary_only { &args[1..] } else { args };
+        let start = match paths.first() {
+            Some(path) => self.resolve_path(path),
+            None => self.current_dir.clone(),
+        };
+        
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        let mut usage = DiskUsage { shell: self, vfs, summary_only, totals: Vec::new(), total: 0 };
+        fs::walk::walk(&start, &mut usage)?;
+        if summary_only {
+            let total = usage.total;
+            self.output_line(&format!("{:>10}  {}", total, start));
+        }
+        Ok(())
+    }
+    
+    /// Show space used and free on each mounted file system
+    fn cmd_df(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        let mut text = String::from("Filesystem Label            Size      Used     Avail  Use%  Mounted on");
+        for (path, name, label, total, available) in vfs.list_mounts() {
+            let used = total.saturating_sub(available);
+            let percent = if total == 0 { 0 } else { used * 100 / total };
+            let label = label.map_or(String::from("-"), |label| text::ellipsize(&label, 11));
+            text.push_str(&format!("\n{:<10} {:<11} {:>9} {:>9} {:>9}  {:>3}%  {}",
+                name, label, format_size(total), format_size(used), format_size(available), percent, path));
+        }
+        self.output_line(&text);
+        Ok(())
+    }
+    
+    /// List mounted file systems, or switch one between read-only and
+    /// writable; mounting devices from the shell isn't supported
+    fn cmd_mount(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use fs::vfs::MountFlags;
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        let flags = match args {
+            [] => {
+                let text = fs::vfs::mounts_text(vfs);
+                self.output_line(text.trim_end());
+                return Ok(());
+            }
+            ["-r", _] => MountFlags::READ_ONLY,
+            ["-w", _] => MountFlags::NONE,
+            _ => {
+                self.show_usage("mount");
+                return Ok(());
+            }
+        };
+        let path = self.resolve_path(args[1]);
+        match vfs.remount(&path, flags) {
+            Ok(()) => self.output_line(&format!("{} is now {}", path,
+                if flags.contains(MountFlags::READ_ONLY) { "read-only" } else { "writable" })),
+            Err(KernelError::NotFound) => self.output_line(&format!("Nothing is mounted at {}", path)),
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+    
+    /// List every open file descriptor: who opened it, where it is and for
+    /// how long
+    fn cmd_lsof(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let files = fs::fd::list_open();
+        let mut text = String::from("   FD  TASK  FLAGS       POS      AGE  OPENED BY     PATH");
+        for file in &files {
+            let task = file.owner.map_or(String::from("-"), |owner| format!("{}", owner));
+            text.push_str(&format!("\n{:>5}  {:>4}  {:<5} {:>9} {:>7}s  {:<12}  {}", file.fd, task,
+                fd_flags(file.flags), file.position, file.age_ms / 1000, text::ellipsize(file.tag, 12), file.path));
+        }
+        text.push_str(&format!("\n{} open (limit {})", files.len(), config::get("fs.max_open_files")
+            .and_then(|value| value.try_as_integer())
+            .unwrap_or(fs::fd::DEFAULT_MAX_OPEN_FILES as i64)));
+        let unknown = fs::fd::unknown_closes();
+        if unknown > 0 {
+            text.push_str(&format!("; {} close(s) of descriptors that weren't open", unknown));
+        }
+        self.output_line(&text);
+        Ok(())
+    }
+    
+    /// Check a file system for inconsistencies, optionally repairing them
+    fn cmd_fsck(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let repair = args.first() == Some(&"-r");
+        let rest = if repair { &args[1..] } else { args };
+        if rest.len() > 1 {
+            self.show_usage("fsck");
+            return Ok(());
+        }
+        let path = rest.first().map_or_else(|| String::from("/"), |path| self.resolve_path(path));
+        
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        let report = match vfs.check(&path, repair) {
+            Err(KernelError::NotImplemented) => {
+                self.output_line("This file system can't be checked.");
+                return Ok(());
+            },
+            result => result?,
+        };
+        
+        let mut text = String::new();
+        for problem in &report.problems {
+            text.push_str(&format!("{}\n", problem));
+        }
+        if report.is_clean() {
+            text.push_str("No problems found.");
+        } else if repair {
+            text.push_str(&format!("{} problems, {} repaired.", report.problems.len(), report.repaired));
+        } else {
+            text.push_str(&format!("{} problems. Run fsck -r to repair.", report.problems.len()));
+        }
+        self.output_line(&text);
+        Ok(())
+    }
+    
+    /// Format a block device as FAT16, FAT32 or SFS, after asking
+    /// Print changes under a path as they happen, or stop
+    fn cmd_fswatch(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let Some(path) = args.first() else {
+            return match self.fs_watch.take() {
+                Some(watch) => {
+                    self.output_line(&format!("Stopped watching {}", watch.prefix()));
+                    Ok(())
+                }
+                None => Err(KernelError::InvalidOperation),
+            };
+        };
+        let path = self.resolve_path(path);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        vfs.metadata(&path)?;
+        
+        self.fs_watch = Some(vfs.watch(&path));
+        self.output_line(&format!("Watching {} (fswatch with no path stops)", path));
+        Ok(())
+    }
+    
+    fn cmd_mkfs(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let sfs = args.get(1) == Some(&"sfs");
+        let fat_type = match args.get(1).copied() {
+            None | Some("sfs") => None,
+            Some("fat16") => Some(fs::fat::FatType::Fat16),
+            Some("fat32") => Some(fs::fat::FatType::Fat32),
+            Some(_) => {
+                self.show_usage("mkfs");
+                return Ok(());
+            }
+        };
+        
+        // Devices are named by id or by the first word of their name
+        let wanted = args[0];
+        let Some(device) = crate::device::find_block_device(wanted) else {
+            let names: Vec<String> = crate::device::get_block_devices().iter()
+                .map(|device| device.lock().name().to_string())
+                .collect();
+            self.output_line(&format!("No block device '{}'. Devices: {}", wanted,
+                if names.is_empty() { String::from("none") } else { names.join(", ") }));
+            return Ok(());
+        };
+        
+        let name = device.lock().name().to_string();
+        self.output_line(&format!("Everything on {} will be lost. Format it? [y/N]", name));
+        self.pending_confirmation = Some(Box::new(move |shell: &mut Shell| {
+            let mut adapter = fs::block_adapter::DeviceBlockAdapter::new(device);
+            if sfs {
+                fs::simple_fs::format(&mut adapter)?;
+            } else {
+                fs::fat::format(&mut adapter, &fs::fat::FormatOptions { fat_type, label: None })?;
+            }
+            fs::block_device::BlockDevice::flush(&mut adapter).map_err(|_| KernelError::WriteError)?;
+            shell.output_line(&format!("Formatted {}.", name));
+            Ok(())
+        }));
+        Ok(())
+    }
+    
+    /// Copy raw data between block devices and files, with progress every
+    /// few MB and a summary at the end, refusing mounted targets unless
+    /// forced
+    fn cmd_dd(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use fs::dd::{self, Endpoint, Request, Summary};
+        let Ok(request) = Request::parse(args) else {
+            self.show_usage("dd");
+            return Ok(());
+        };
+        let size = request.record_size as u64;
+        let mut input = match dd::find_device(&request.input) {
+            Some((name, device)) => Endpoint::Device { name, device },
+            None => Endpoint::open_input_file(&self.resolve_path(&request.input), request.skip * size)?,
+        };
+        
+        let mut refusal = None;
+        let output = match dd::find_device(&request.output) {
+            Some((name, device)) => {
+                if device.lock().read_only() {
+                    refusal = Some(format!("{} is read-only", name));
+                } else if let Some(path) = dd::mounted_at(&device).filter(|_| !request.force) {
+                    refusal = Some(format!("{} is mounted at {}; use --force to write to it anyway", name, path));
+                }
+                Ok(Endpoint::Device { name, device })
+            }
+            None => Endpoint::open_output_file(&self.resolve_path(&request.output), request.seek * size),
+        };
+        let mut output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                let _ = input.finish();
+                return Err(e);
+            }
+        };
+        for end in [&input, &output] {
+            if refusal.is_none() && request.record_size % end.block_size() != 0 {
+                refusal = Some(format!("bs must be a multiple of {}'s {}-byte blocks", end.name(), end.block_size()));
+            }
+        }
+        if let Some(refusal) = refusal {
+            let _ = input.finish();
+            let _ = output.finish();
+            self.output_line(&refusal);
+            return Ok(());
+        }
+        
+        let mut summary = Summary::default();
+        let copied = dd::copy(&mut input, &mut output, &request, &mut summary, &mut |summary, report| {
+            if report {
+                self.output_line(&summary.progress_line());
+            }
+            self.check_cancelled()
+        });
+        let finished = output.finish();
+        let _ = input.finish();
+        self.output_line(&summary.to_string());
+        copied.and(finished)
+    }
+    
+    /// Hand the screen back to the desktop
+    fn cmd_startx(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        if self.captured.is_some() {
+            self.output_line("Already on the desktop (exitgui goes to the console)");
+            return Ok(());
+        }
+        match crate::vt::request(crate::vt::Mode::Gui) {
+            Ok(()) => self.output_line("Starting the desktop..."),
+            Err(_) => self.output_line(NO_DESKTOP),
+        }
+        Ok(())
+    }
+    
+    /// Leave the desktop for the console, from one of its terminals; the
+    /// desktop keeps running behind the console until `startx`
+    fn cmd_exitgui(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        if self.captured.is_none() {
+            self.output_line("Already at the console (startx goes to the desktop)");
+            return Ok(());
+        }
+        if let Err(e) = crate::vt::request(crate::vt::Mode::Console) {
+            self.output_line(&format!("exitgui: {}", e));
+        }
+        Ok(())
+    }
+    
+    /// Reboot the system
+    fn cmd_reboot(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        self.output_line("Rebooting...");
+        
+        // Wait a moment for the message to be seen
+        crate::drivers::pit::busy_sleep_us(500_000);
+        
+        // Give disks the chance to flush
+        crate::device::suspend_all();
+        crate::reboot();
+    }
+    
+    /// Panic the kernel, after asking, so the persistent log can be checked
+    /// across a reboot
+    fn cmd_panic(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let message = if args.is_empty() { String::from("panic command") } else { args.join(" ") };
+        self.output_line("This crashes the kernel. Panic now? [y/N]");
+        self.pending_confirmation = Some(Box::new(move |_shell: &mut Shell| {
+            panic!("{}", message);
+        }));
+        Ok(())
+    }
+
+    /// Name the kernel function a hex address is in, like one from a
+    /// crash log
+    fn cmd_ksym(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::ksyms::{self, Status};
+        let digits = args[0].strip_prefix("0x").unwrap_or(args[0]);
+        let Ok(address) = u64::from_str_radix(digits, 16) else {
+            self.show_usage("ksym");
+            return Ok(());
+        };
+        let message = match (ksyms::status(), ksyms::lookup(address)) {
+            (_, Some((name, offset))) => format!("{:#x} is {}+{:#x}", address, name, offset),
+            (Status::Ready(_), None) => format!("{:#x} isn't in a known kernel function", address),
+            (Status::Stale, None) => String::from("The kernel's symbol table is from another build; rebuild with UNIVERSEK_KSYMS"),
+            (Status::Missing, None) => String::from("The kernel was built without symbols; set UNIVERSEK_KSYMS to add them"),
+        };
+        self.output_line(&message);
+        Ok(())
+    }
+
+    /// Switch the machine off
+    fn cmd_shutdown(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        self.output_line("Shutting down...");
+        crate::drivers::pit::busy_sleep_us(500_000);
+        crate::power_off();
+    }
+    
+    /// Print what a suspend or resume did to each device
+    fn show_power_report(&mut self, verb: &str, report: &crate::device::PowerReport) {
+        for name in &report.done {
+            self.output_line(&format!("{}: {}", verb, name));
+        }
+        for (name, e) in &report.failed {
+            self.output_line(&format!("{} failed: {}: {}", verb, name, e));
+        }
+        for name in &report.rolled_back {
+            self.output_line(&format!("Resumed again: {}", name));
+        }
+    }
+    
+    /// Suspend every device, as a power-management dry run
+    fn cmd_suspend(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let report = crate::device::suspend_all();
+        self.show_power_report("Suspended", &report);
+        if report.is_ok() {
+            self.output_line("All devices suspended; 'resume' brings them back");
+            Ok(())
+        } else {
+            Err(KernelError::DeviceError(crate::errors::DeviceError::NotResponding))
+        }
+    }
+    
+    /// Resume the devices a suspend left suspended
+    fn cmd_resume(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let report = crate::device::resume_all();
+        self.show_power_report("Resumed", &report);
+        if report.is_ok() {
+            Ok(())
+        } else {
+            Err(KernelError::DeviceError(crate::errors::DeviceError::NotResponding))
+        }
+    }
+    
+    /// Display OS version information
+    fn cmd_version(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        use crate::version;
+        self.output_line(&version::name_and_version());
+        self.output_line(&format!("A minimal Unix-like OS for {}", version::MACHINE));
+        self.output_line(&format!("Built {} UTC from {} with {}", version::BUILD_TIME, version::GIT_HASH,
+            version::RUSTC_VERSION));
+        Ok(())
+    }
+    
+    /// Print the kernel name, or with -a the whole build line
+    fn cmd_uname(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args {
+            [] => self.output_line(crate::version::SYSNAME),
+            ["-a"] => self.output_line(&crate::version::uname_line()),
+            _ => self.show_usage("uname"),
+        }
+        Ok(())
+    }
+    
+    /// Show the wall-clock date and time
+    fn cmd_date(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        self.output_line(&format!("{} UTC", crate::time::now().format()));
+        Ok(())
+    }
+    
+    /// Compare the RTC with the system clock, or set both
+    fn cmd_hwclock(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args {
+            [] => {
+                let rtc = crate::drivers::rtc::get_datetime();
+                let offset = crate::time::wall_clock() as i64 - rtc.to_unix_seconds() as i64;
+                self.output_line(&format!("{} UTC (system clock {:+} s)", rtc.format(), offset));
+                Ok(())
+            }
+            ["--set", date, time] => {
+                let time = parse_datetime(date, time).ok_or(KernelError::InvalidParameter)?;
+                crate::time::set_wall_clock(time.to_unix_seconds())?;
+                self.output_line(&format!("Clock set to {} UTC", time.format()));
+                Ok(())
+            }
+            _ => Err(KernelError::InvalidParameter),
+        }
+    }
+    
+    /// List `at` jobs, drop one, or run a command at HH:MM (RTC time, UTC)
+    fn cmd_at(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args {
+            [] => {
+                let mut text = String::new();
+                for job in at::jobs() {
+                    text.push_str(&format!("{:>3}  {:02}:{:02}  {}\n", job.id, job.hour, job.minute, job.command));
+                }
+                if text.is_empty() {
+                    text.push_str("No jobs waiting.");
+                }
+                self.output_line(&text);
+            }
+            ["-d", id] => {
+                let id = id.parse::<u32>().map_err(|_| KernelError::InvalidParameter)?;
+                at::cancel(id)?;
+                self.output_line(&format!("Job {} removed.", id));
+            }
+            [time, command @ ..] if !command.is_empty() => {
+                let Some((hour, minute)) = at::parse_time(time) else {
+                    self.show_usage("at");
+                    return Ok(());
+                };
+                let id = at::schedule(hour, minute, &command.join(" "))?;
+                self.output_line(&format!("Job {} will run at {:02}:{:02} UTC.", id, hour, minute));
+            }
+            _ => self.show_usage("at"),
+        }
+        Ok(())
+    }
+    
+    /// Print bytes read from /dev/random as hex, 16 to a line
+    fn cmd_random(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        const MAX_BYTES: usize = 256;
+        let count = match args.first() {
+            Some(count) => count.parse::<usize>().map_err(|_| KernelError::InvalidParameter)?,
+            None => 16,
+        };
+        if count == 0 || count > MAX_BYTES {
+            return Err(KernelError::InvalidParameter);
+        }
+        
+        let mut bytes = [0u8; MAX_BYTES];
+        let random = fs::fd::open_tagged("random", "/dev/random", fs::vfs::file_flags::READ)?;
+        let read = fs::fd::read(random, &mut bytes[..count]);
+        fs::fd::close(random)?;
+        let read = read?;
+        let lines: Vec<String> = bytes[..read].chunks(16)
+            .map(|line| line.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" "))
+            .collect();
+        self.output_line(&lines.join("\n"));
+        Ok(())
+    }
+    
+    /// Display time since boot
+    fn cmd_uptime(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let ms = crate::drivers::pit::uptime_ms();
+        let secs = ms / 1000;
+        self.output_line(&format!("up {:02}:{:02}:{:02}.{:03} ({} ticks at {} Hz), load {}%",
+            secs / 3600, (secs / 60) % 60, secs % 60, ms % 1000,
+            crate::drivers::pit::ticks(), crate::drivers::pit::frequency(),
+            crate::task::idle::load_percent()));
+        Ok(())
+    }
+    
+    /// Run the rest of the line and report how long it took. It runs in
+    /// this command's task, so the CPU figure is the ticks charged to that
+    /// task meanwhile; `time time ls` times the inner `time` too.
+    fn cmd_time(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let command = parse::join(args);
+        let start_ns = crate::time::monotonic_ns();
+        let start_ticks = scheduler::current_cpu_ticks();
+        let result = self.dispatch(&command);
+        let real_ns = crate::time::monotonic_ns() - start_ns;
+        let cpu_ticks = match (start_ticks, scheduler::current_cpu_ticks()) {
+            (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+            _ => None,
+        };
+        self.output_line(&timing_line(real_ns, cpu_ticks, crate::drivers::pit::frequency(),
+            crate::time::resolution_ns()));
+        result
+    }
+    
+    /// Run the rest of the line as a task jailed in `dir`, from "/" there:
+    /// it sees `dir` as "/" and can't name anything outside it
+    fn cmd_chroot(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let dir = self.resolve_path(args[0]);
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        if vfs.metadata(&dir)?.node_type != fs::vfs::NodeType::Directory {
+            return Err(KernelError::NotADirectory);
+        }
+        // A jail inside a jail is inside the outer one too
+        let root = fs::jail::resolve(&scheduler::current_root(), &dir);
+        let command = parse::join(&args[1..]);
+        
+        let outside = core::mem::replace(&mut self.current_dir, String::from("/"));
+        let mut result = Ok(());
+        let task = scheduler::run_as_task_in(root, &mut || {
+            result = self.dispatch(&command);
+            exit_status(&result)
+        });
+        self.current_dir = outside;
+        task?;
+        result
+    }
+    
+    /// List the fault injection points, or arm or disarm one
+    fn cmd_fault(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args {
+            [] | ["list"] => {
+                if !faultinject::enabled() {
+                    self.output_line("Built without fault_injection: points can be listed but not armed");
+                }
+                self.output_line(&faultinject::list_text().join("\n"));
+                Ok(())
+            }
+            ["arm", point, rest @ ..] => {
+                let arming = faultinject::Arming::parse(rest)?;
+                faultinject::arm(point, arming)?;
+                let after = if arming.after == 0 { String::from("next hit") } else { format!("hit {}", arming.after + 1) };
+                let times = if arming.times == 0 { String::from("until disarmed") } else { format!("{} time(s)", arming.times) };
+                self.output_line(&format!("{}: {} from the {}, {}", point, arming.fault, after, times));
+                Ok(())
+            }
+            ["disarm", "all"] => {
+                faultinject::disarm_all();
+                Ok(())
+            }
+            ["disarm", point] => faultinject::disarm(point),
+            _ => {
+                self.show_usage("fault");
+                Ok(())
+            }
+        }
+    }
+    
+    /// Start or stop recording task switches, or print or save the record
+    fn cmd_trace(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::task::trace;
+        match args {
+            ["sched", "start"] => {
+                trace::start();
+                self.output_line(&format!("Recording task switches (the last {} are kept)", trace::CAPACITY));
+            }
+            ["sched", "stop"] => {
+                trace::stop();
+                self.output_line("Stopped recording task switches");
+            }
+            ["sched", "dump"] => self.output_line(&trace::dump_text()),
+            ["sched", "dump", path] => {
+                let path = self.resolve_path(path);
+                let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+                let mut text = trace::dump_text();
+                text.push('\n');
+                vfs.write_file_atomic(&path, text.as_bytes())?;
+                self.output_line(&format!("Saved the trace to {}", path));
+            }
+            _ => self.show_usage("trace"),
+        }
+        Ok(())
+    }
+    
+    /// Run one of the benchmarks in `bench` and print its table; the rows
+    /// also go to the log
+    fn cmd_bench(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        const MAX_HEAP_COUNT: usize = 1_000_000;
+        const MAX_FS_KIB: usize = 256;
+        const MAX_DRAW_FRAMES: usize = 10_000;
+        let (default, max) = match args[0] {
+            "heap" => (10_000, MAX_HEAP_COUNT),
+            "fs" => (64, MAX_FS_KIB),
+            "draw" => (100, MAX_DRAW_FRAMES),
+            _ => {
+                self.show_usage("bench");
+                return Ok(());
+            }
+        };
+        let count = match args.get(1) {
+            Some(count) => count.parse::<usize>().map_err(|_| KernelError::InvalidParameter)?,
+            None => default,
+        };
+        if count == 0 || count > max {
+            return Err(KernelError::InvalidParameter);
+        }
+        
+        let rows = match args[0] {
+            "heap" => bench::heap(count)?,
+            "fs" if fs::vfs::get_vfs_manager().is_none() => {
+                self.output_line("bench fs: the file system isn't initialized, skipping");
+                return Ok(());
+            }
+            "fs" => bench::fs(count * 1024)?,
+            _ if !crate::gui::is_initialized() => {
+                self.output_line("bench draw: the GUI isn't initialized, skipping");
+                return Ok(());
+            }
+            _ => alloc::vec![bench::draw(count)?],
+        };
+        let lines = bench::table(&rows);
+        for line in &lines[1..] {
+            crate::logger::info("bench", line);
+        }
+        self.output_line(&lines.join("\n"));
+        Ok(())
+    }
+    
+    /// Display network interface status and counters
+    fn cmd_ifconfig(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let status = match crate::net::status() {
+            Some(status) => status,
+            None => {
+                self.output_line("No network interface is up.");
+                return Ok(());
+            }
+        };
+        
+        self.output_line(&format!(
+            "eth0: mac {}\n  inet {} netmask {} gateway {}\n  RX {} packets {} bytes {} errors\n  TX {} packets {} bytes {} errors\n  ARP {} req {} replies {} cached, ICMP echo {}, dropped {}",
+            crate::drivers::rtl8139::format_mac(&status.mac),
+            status.config.ip, status.config.netmask, status.config.gateway,
+            status.nic.rx_packets, status.nic.rx_bytes, status.nic.rx_errors,
+            status.nic.tx_packets, status.nic.tx_bytes, status.nic.tx_errors,
+            status.stats.arp_requests, status.stats.arp_replies, status.arp_entries,
+            status.stats.icmp_echo, status.stats.dropped));
+        Ok(())
+    }
+    
+    /// Load and run an ELF executable, showing its output and exit code
+    fn cmd_exec(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let path = self.resolve_path(args[0]);
+        
+        crate::syscall::start_capture();
+        let result = crate::loader::exec(&path, args);
+        let output = crate::syscall::take_capture();
+        let exit_code = result?;
+        
+        self.output_line(&format!("{}[{} exited with code {}]", output, args[0], exit_code));
+        Ok(())
+    }
+    
+    /// List tasks and their states
+    fn cmd_ps(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        use crate::task::TaskState;
+        
+        let tasks = crate::task::scheduler::task_list();
+        let mut text = String::from("  PID  KIND     TICKS  STATE");
+        let mut zombies = 0;
+        for task in &tasks {
+            let state = match task.state {
+                TaskState::Runnable => "runnable".to_string(),
+                TaskState::Running => "running".to_string(),
+                TaskState::Blocked => "blocked".to_string(),
+                TaskState::Terminated => "terminated".to_string(),
+                TaskState::Zombie => {
+                    zombies += 1;
+                    format!("ZOMBIE (exit {})", task.exit_code.unwrap_or(0))
+                }
+            };
+            let kind = if task.id == 0 { "kernel" } else if task.user { "user" } else { "task" };
+            text.push_str(&format!("\n{:>5}  {:<6} {:>6}  {}", task.id, kind, task.cpu_ticks, state));
+        }
+        text.push_str(&format!("\n{} tasks, {} zombies", tasks.len(), zombies));
+        
+        self.output_line(&text);
+        Ok(())
+    }
+    
+    /// Show kernel heap usage
+    fn cmd_mouse(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::drivers::ps2_mouse;
+        let key = match args {
+            [] => {
+                let (sensitivity, accel) = ps2_mouse::pointer_settings();
+                let (width, height) = ps2_mouse::bounds();
+                self.output_line(&format!("Speed {}%, acceleration {}, pointer bounds {}x{}",
+                    sensitivity, accel, width, height));
+                return Ok(());
+            }
+            ["speed", _] => "input.mouse_sensitivity",
+            ["accel", _] => "input.mouse_accel",
+            _ => {
+                self.show_usage("mouse");
+                return Ok(());
+            }
+        };
+        let value = config::ConfigValue::integer(args[1].parse().map_err(|_| KernelError::InvalidParameter)?);
+        if let Some(rule) = config::rule(key).filter(|rule| !rule.allows(&value)) {
+            self.output_line(&format!("{} must be {}", args[0], rule.describe()));
+            return Err(KernelError::InvalidParameter);
+        }
+        config::set(key, value);
+        Ok(())
+    }
+    
+    fn cmd_free(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let (verbose, fragmentation) = match args {
+            [] => (false, false),
+            ["-v"] => (true, false),
+            ["-f"] => (false, true),
+            ["-v", "-f"] | ["-f", "-v"] => (true, true),
+            _ => return Err(KernelError::InvalidParameter),
+        };
+        let heap = crate::allocator::heap_stats();
+        self.output_line(&format!(
+            "          total       used       free\nHeap: {:>9}  {:>9}  {:>9}",
+            heap.size, heap.used, heap.free));
+        if fragmentation {
+            let report = crate::allocator::fragmentation_report();
+            self.output_line(&format!("Free blocks: {}, largest {} bytes, {}% fragmented",
+                report.free_blocks, report.largest_free, report.percent()));
+        }
+        if verbose {
+            let usage = crate::allocator::tag_usage();
+            if usage.is_empty() {
+                self.output_line("Usage by subsystem is only counted in heap_debug builds");
+            }
+            for usage in usage {
+                self.output_line(&format!("  {:<16} {:>9} bytes in {} blocks", usage.tag, usage.bytes, usage.blocks));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Show each block device's counters and how fast it has moved data
+    /// since the last iostat
+    fn cmd_iostat(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let now = crate::time::monotonic_ns();
+        let devices = iostats::all();
+        if devices.is_empty() {
+            self.output_line("No block devices");
+            return Ok(());
+        }
+        // Rates are since the last run, or since boot the first time
+        let (since, earlier) = self.io_snapshot.take().unwrap_or((0, Vec::new()));
+        let elapsed_ms = (now.saturating_sub(since) / 1_000_000).max(1);
+        let mut text = String::from("DEVICE               READS  RD-BLK  WRITES  WR-BLK  ERRS      BUSY  RD KB/s  WR KB/s");
+        for device in &devices {
+            let counters = device.counters;
+            let delta = earlier.iter().find(|old| old.id == device.id)
+                .map_or(counters, |old| counters.since(&old.counters));
+            let rate = |blocks: u64| blocks * device.block_size as u64 * 1000 / 1024 / elapsed_ms;
+            text.push_str(&format!("\n{:<18} {:>7} {:>7} {:>7} {:>7} {:>5} {:>7}ms {:>8} {:>8}",
+                text::ellipsize(&device.name, 18), counters.reads, counters.blocks_read, counters.writes,
+                counters.blocks_written, counters.errors, counters.busy_ns / 1_000_000,
+                rate(delta.blocks_read), rate(delta.blocks_written)));
+        }
+        text.push_str(&format!("\nRates over the last {}.{}s{}", elapsed_ms / 1000, elapsed_ms / 100 % 10,
+            if since == 0 { " (since boot)" } else { "" }));
+        self.output_line(&text);
+        self.io_snapshot = Some((now, devices));
+        Ok(())
+    }
+    
+    /// Show the block cache counters of each disk
+    fn cmd_cachestat(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let caches = fs::block_adapter::all_stats();
+        if caches.is_empty() {
+            self.output_line("No disk block caches");
+            return Ok(());
+        }
+        let mut text = String::from("  HITS  MISSES  RA-HITS  RA-BLOCKS  READS  WRITES  WRITTEN");
+        for stats in caches {
+            text.push_str(&format!("\n{:>6}  {:>6}  {:>7}  {:>9}  {:>5}  {:>6}  {:>7}",
+                stats.hits, stats.misses, stats.readahead_hits, stats.readahead_blocks,
+                stats.device_reads, stats.device_writes, stats.blocks_written));
+        }
+        self.output_line(&text);
+        Ok(())
+    }
+    
+    /// Show the message of the day, replace it, or type a new one line by
+    /// line
+    fn cmd_motd(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args {
+            [] => {
+                let lines = motd::lines();
+                if lines.is_empty() {
+                    self.output_line(&format!("No message of the day ({} is missing or empty)", motd::MOTD_PATH));
+                } else {
+                    self.output_line(&lines.join("\n"));
+                }
+            }
+            ["set", text @ ..] if !text.is_empty() => {
+                motd::set(&unescape(&text.join(" ")))?;
+                self.output_line(&format!("Updated {}", motd::MOTD_PATH));
+            }
+            ["edit"] => {
+                self.output_line("Type the new message; a line with just '.' saves it.");
+                self.motd_draft = Some(Vec::new());
+            }
+            _ => self.show_usage("motd"),
+        }
+        Ok(())
+    }
+    
+    /// Type text through the keyboard driver, for scripting the shell and GUI
+    fn cmd_sendkeys(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        if !input::injection_enabled() {
+            self.output_line("Input injection is off; set debug.input_injection to true");
+            return Ok(());
+        }
+        let text = unescape(&args.join(" "));
+        if let Some(c) = text.chars().find(|c| input::text_scancodes(&c.to_string()).is_none()) {
+            self.output_line(&format!("sendkeys: no key types '{}'", c));
+            return Ok(());
+        }
+        input::send_text(&text)
+    }
+    
+    /// Show the physical memory map, totals by kind and frame usage
+    fn cmd_memmap(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let mut text = crate::memory::iomem_text();
+        let totals = crate::memory::totals();
+        text.push_str(&format!(
+            "Usable: {} KiB  Kernel: {} KiB  Reserved: {} KiB  ACPI: {} KiB reclaimable, {} KiB NVS  Bad: {} KiB",
+            totals.usable >> 10, totals.kernel >> 10, totals.reserved >> 10,
+            totals.acpi_reclaimable >> 10, totals.acpi_nvs >> 10, totals.bad >> 10));
+        if let Some(frames) = crate::memory::frame_stats() {
+            text.push_str(&format!("\nFrames: {} usable, {} handed out, {} remaining",
+                frames.total, frames.allocated, frames.remaining));
+        }
+        self.output_line(&text);
+        Ok(())
+    }
+    
+    /// Show how often each interrupt vector has fired
+    fn cmd_irqstat(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        let mut text = String::from("VECTOR  NAME          COUNT");
+        for (vector, count) in crate::interrupts::interrupt_counts() {
+            let name = crate::interrupts::vector_name(vector).unwrap_or("-");
+            text.push_str(&format!("\n  {:#04x}  {:<8} {:>10}", vector, name, count));
+        }
+        self.output_line(&text);
+        Ok(())
+    }
+    
+    /// Show the newest log messages, the serial rate limiter's counts, or
+    /// clear the log
+    fn cmd_dmesg(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args.first() {
+            None => {
+                let mut text = String::new();
+                for entry in crate::logger::recent(self.window_height.saturating_sub(6)) {
+                    text.push_str(&entry.format());
+                    text.push('\n');
+                }
+                if text.is_empty() {
+                    text.push_str("(log is empty)");
+                }
+                self.output_line(&text);
+            }
+            Some(&"stats") => {
+                let stats = crate::logger::serial_stats();
+                let mut text = format!("serial: {} printed, {} repeats collapsed, {} dropped over the rate limit",
+                    stats.printed, stats.collapsed, stats.dropped);
+                for (module, dropped) in stats.dropped_by_module {
+                    text.push_str(&format!("\n  {:<12} {:>8} dropped", module, dropped));
+                }
+                self.output_line(&text);
+            }
+            Some(&"clear") => {
+                crate::logger::clear();
+                self.output_line("Log cleared.");
+            }
+            Some(_) => self.show_usage("dmesg"),
+        }
+        Ok(())
+    }
+    
+    /// Desktop session control; for now only forgetting the saved layout
+    fn cmd_gui(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args {
+            ["reset-session"] => {
+                crate::gui::session::reset()?;
+                self.output_line("Saved desktop session deleted; the next boot starts with a bare desktop.");
+            }
+            _ => self.show_usage("gui"),
+        }
+        Ok(())
+    }
+    
+    /// List, show or change configuration settings. A value is read as a
+    /// config file would read it, and checked against the key's rule if
+    /// it has one. `set` and `unset` work on the user layer while someone
+    /// is logged in, else the system one, unless a layer is named with
+    /// --system, --profile, --user or --runtime; `--save` writes the files
+    /// too. `profile` lists the profiles or switches to one, saving it.
+    fn cmd_config(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::config::Layer;
+        
+        let (flags, args): (Vec<&str>, Vec<&str>) = args.iter().copied().partition(|arg| arg.starts_with("--"));
+        let save = flags.contains(&"--save");
+        let layer = match flags.iter().find(|flag| **flag != "--save") {
+            Some(flag) => match Layer::from_name(&flag[2..]) {
+                Some(layer) => layer,
+                None => {
+                    self.show_usage("config");
+                    return Ok(());
+                }
+            },
+            None => config::default_layer(),
+        };
         
+        match (args.first().copied().unwrap_or(""), args.len()) {
+            ("list", 1 | 2) => {
+                let entries = config::entries(args.get(1).copied().unwrap_or(""));
+                if entries.is_empty() {
+                    self.output_line("No settings match.");
+                    return Ok(());
+                }
+                let width = entries.iter().map(|(key, _, _)| key.len()).max().unwrap_or(0);
+                let lines: Vec<String> = entries.iter().map(|(key, value, layer)| {
+                    let unregistered = if config::rule(key).is_none() { "  (unregistered)" } else { "" };
+                    format!("{:<width$}  {:<7}  {:<7}  {}{}", key, config_type(value), layer.name(), value.as_string(),
+                        unregistered, width = width)
+                }).collect();
+                self.output_line(&lines.join("\n"));
+            }
+            ("get", 2) => match (config::get(args[1]), config::layer_of(args[1])) {
+                (Some(value), Some(from)) => self.output_line(&format!("{} ({}, from the {} layer)",
+                    value.as_string(), config_type(&value), from.name())),
+                _ => self.output_line(&format!("{} isn't set", args[1])),
+            },
+            ("set", 3..) => {
+                let key = args[1];
+                let value = config::parse_value(&args[2..].join(" "));
+                match config::rule(key) {
+                    Some(rule) if !rule.allows(&value) => {
+                        self.output_line(&format!("{} must be {}", key, rule.describe()));
+                        return Err(KernelError::InvalidParameter);
+                    }
+                    Some(_) => {}
+                    None => self.output_line(&format!("Note: {} is unregistered; nothing checks its value", key)),
+                }
+                if config::set_in(layer, key, value.clone()).is_err() {
+                    self.output_line(match layer {
+                        Layer::Profile => "No profile is active to keep the setting in",
+                        _ => "Nobody is logged in to keep user settings for",
+                    });
+                    return Ok(());
+                }
+                let shown = format!("{} = {} in the {} layer", key, value.as_string(), layer.name());
+                self.finish_config_change(&shown, save && layer != Layer::Runtime);
+            }
+            ("unset", 2) => {
+                if config::unset_in(layer, args[1]).is_err() {
+                    self.output_line(&format!("{} isn't set in the {} layer", args[1], layer.name()));
+                    return Ok(());
+                }
+                let shown = match config::get(args[1]) {
+                    Some(value) => format!("{} = {} again", args[1], value.as_string()),
+                    None => format!("{} unset", args[1]),
+                };
+                self.finish_config_change(&shown, save && layer != Layer::Runtime);
+            }
+            ("profile", 2) if args[1] == "list" => {
+                let profiles = config::profiles();
+                if profiles.is_empty() {
+                    self.output_line("No profiles; add settings under a [profile.<name>] header in the config file");
+                    return Ok(());
+                }
+                let active = config::active_profile();
+                let lines: Vec<String> = profiles.iter().map(|(name, keys)| {
+                    let marker = if active.as_deref() == Some(name.as_str()) { '*' } else { ' ' };
+                    format!("{} {:<16} {} settings", marker, name, keys)
+                }).collect();
+                self.output_line(&lines.join("\n"));
+            }
+            ("profile", 3) if args[1] == "switch" => {
+                let name = if args[2] == "none" { "" } else { args[2] };
+                let changed = match config::switch_profile(name) {
+                    Ok(changed) => changed,
+                    Err(KernelError::NotFound) => {
+                        self.output_line(&format!("There's no profile {}", name));
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+                let mut shown = format!("Profile {}: {} settings changed now, the rest apply after a reboot", args[2], changed);
+                if let Some(booted) = crate::cmdline::value("profile") {
+                    shown.push_str(&format!("\nprofile={} on the boot command line keeps {} in use until then", booted, booted));
+                }
+                self.finish_config_change(&shown, true);
+            }
+            ("rollback", 1 | 2) => {
+                let n = match args.get(1) {
+                    Some(n) => n.parse::<usize>().ok().filter(|n| (1..=config::MAX_BACKUPS).contains(n))
+                        .ok_or(KernelError::InvalidParameter)?,
+                    None => 1,
+                };
+                match config::rollback(n) {
+                    Ok((backup, changed)) => self.output_line(&format!("Restored {}: {} settings changed", backup, changed)),
+                    Err(KernelError::NotFound) => self.output_line(&format!("There's no backup {}", n)),
+                    Err(e) => return Err(e),
+                }
+            }
+            _ => self.show_usage("config"),
+        }
         Ok(())
     }
     
-    /// Create a new directory
-    fn cmd_mkdir(&mut self, args: &[&str]) -> Result<(), KernelError> {
-        if args.is_empty() {
-            self.output_line("Usage: mkdir <directory>");
+    /// Report a config change as `shown`, saving it first if `save`
+    fn finish_config_change(&mut self, shown: &str, save: bool) {
+        if !save {
+            self.output_line(&format!("{} (not saved)", shown));
+            return;
+        }
+        match config::save() {
+            Ok(()) => self.output_line(&format!("{} (saved)", shown)),
+            Err(e) => self.output_line(&format!("{} (not saved: {})", shown, e)),
+        }
+    }
+    
+    /// Show safe mode, or save whether the next boot is in it
+    fn cmd_safemode(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::safe_mode;
+        let enabled = match args.first() {
+            None => {
+                self.output_line(&safe_mode::status());
+                return Ok(());
+            }
+            Some(&"on") => true,
+            Some(&"off") => false,
+            Some(_) => {
+                self.show_usage("safemode");
+                return Ok(());
+            }
+        };
+        let mut message = match safe_mode::set_saved(enabled) {
+            Ok(()) => format!("Safe mode will be {} from the next boot", if enabled { "on" } else { "off" }),
+            Err(e) => format!("{} is {} for now but wasn't saved: {}", safe_mode::CONFIG_KEY, enabled, e),
+        };
+        if !enabled && safe_mode::is_forced() {
+            message.push_str(&format!("\nThe '{}' boot flag still puts a boot in safe mode", safe_mode::BOOT_FLAG));
+        }
+        self.output_line(&message);
+        Ok(())
+    }
+    
+    /// Record GUI input to a file, play a recording back, or say what's
+    /// going on
+    fn cmd_guirec(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::gui::recorder;
+        match args {
+            ["start", file] => {
+                let path = self.resolve_path(file);
+                recorder::start(&path)?;
+                self.output_line(&format!("Recording GUI input to {}", path));
+            }
+            ["stop"] => match recorder::stop() {
+                Some((path, events)) => self.output_line(&format!("Recorded {} events to {}", events, path)),
+                None => self.output_line("Not recording."),
+            },
+            ["play", file] | ["play", file, _] => {
+                if !input::injection_enabled() {
+                    self.output_line("Input injection is off; set debug.input_injection to true");
+                    return Ok(());
+                }
+                let speed = match args.get(2) {
+                    Some(speed) => parse_speed(speed).ok_or(KernelError::InvalidParameter)?,
+                    None => 100,
+                };
+                let path = self.resolve_path(file);
+                let events = recorder::play(&path, speed)?;
+                self.output_line(&format!("Playing {} events from {} in the GUI", events, path));
+            }
+            ["status"] => {
+                let recording = if recorder::is_recording() { "recording" } else { "not recording" };
+                let playing = recorder::remaining()
+                    .map_or(String::from("not playing"), |left| format!("playing, {} events left", left));
+                self.output_line(&format!("{}; {}", recording, playing));
+            }
+            _ => self.show_usage("guirec"),
+        }
+        Ok(())
+    }
+    
+    /// Save what's on the screen, as it is before this command prints
+    /// anything
+    fn cmd_screenshot(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::gui::screenshot;
+        let shot = screenshot::capture();
+        let base = match args.first() {
+            Some(path) => String::from(screenshot::base_path(&self.resolve_path(path))),
+            None => screenshot::next_base(&screenshot::default_dir())?,
+        };
+        let (text_path, raw_path) = shot.save(&base)?;
+        self.output_line(&format!("Saved {} and {}", text_path, raw_path));
+        Ok(())
+    }
+    
+    /// Lock the screen until the current user's password is typed
+    fn cmd_lock(&mut self, _args: &[&str]) -> Result<(), KernelError> {
+        use crate::user::{self, password};
+        if !user::current_username().is_some_and(|name| password::has_password(&name)) {
+            self.output_line("No password to unlock with; set one with passwd first");
             return Ok(());
         }
-        
-        let path = self.resolve_path(args[0]);
-        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
-        
-        vfs.create_directory(&path)?;
-        self.output_line(&format!("Created directory: {}", path));
-        
+        crate::gui::screensaver::run(true, &mut || {
+            crate::net::poll();
+            crate::drivers::rtc::poll();
+            crate::drivers::pit::poll();
+            crate::task::deferred::run_pending();
+        });
         Ok(())
     }
     
-    /// Remove a file or directory
-    fn cmd_rm(&mut self, args: &[&str]) -> Result<(), KernelError> {
+    /// Switch to another user, whose preferences replace the last one's
+    fn cmd_login(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::user::{self, password};
+        let name = args[0];
+        if password::has_password(name) && !args.get(1).is_some_and(|given| password::verify(name, given)) {
+            self.output_line("Login incorrect");
+            return Ok(());
+        }
+        match user::log_in(name) {
+            Ok(()) => self.output_line(&format!("Logged in as {}", name)),
+            Err(KernelError::NotFound) => self.output_line(&format!("login: no user named {}", name)),
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+    
+    /// Set or remove the current user's password. The passwords are asked
+    /// for on the lines that follow, the current one first if there is one.
+    fn cmd_passwd(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::user::{self, password};
+        let remove = match args.first() {
+            None => false,
+            Some(&"-d") => true,
+            Some(_) => return Err(KernelError::InvalidParameter),
+        };
+        let user = user::current_username().ok_or(KernelError::NotInitialized)?;
+        let step = if password::has_password(&user) {
+            PasswordStep::Current
+        } else if remove {
+            self.output_line(&format!("{} has no password to remove", user));
+            return Ok(());
+        } else {
+            PasswordStep::New
+        };
+        self.ask_password(PasswordPrompt { user, remove, step });
+        Ok(())
+    }
+    
+    /// Wait for the answer to `prompt`. A window shows the question as
+    /// output, as its input line has no prompt of the shell's.
+    fn ask_password(&mut self, prompt: PasswordPrompt) {
+        if self.captured.is_some() {
+            self.output_line(prompt.step.question());
+        }
+        self.password_prompt = Some(prompt);
+    }
+    
+    /// Take `answer` to `prompt`, and ask the next question or make the change
+    fn answer_password_prompt(&mut self, prompt: PasswordPrompt, answer: String) {
+        use crate::user::password;
+        let PasswordPrompt { user, remove, step } = prompt;
+        let (new_password, message) = match step {
+            PasswordStep::Current if !password::verify(&user, &answer) => {
+                self.output_line("passwd: Authentication failed; password unchanged");
+                return;
+            }
+            PasswordStep::Current if remove => ("", "Removed the password for"),
+            PasswordStep::Current => {
+                self.ask_password(PasswordPrompt { user, remove, step: PasswordStep::New });
+                return;
+            }
+            PasswordStep::New if answer.is_empty() => {
+                self.output_line("passwd: No password given; use passwd -d to remove it");
+                return;
+            }
+            PasswordStep::New => {
+                self.ask_password(PasswordPrompt { user, remove, step: PasswordStep::Retype(answer) });
+                return;
+            }
+            PasswordStep::Retype(first) if first != answer => {
+                self.output_line("passwd: The passwords don't match; password unchanged");
+                return;
+            }
+            PasswordStep::Retype(_) => (answer.as_str(), "Set the password for"),
+        };
+        match password::set(&user, new_password) {
+            Ok(()) => self.output_line(&format!("{} {}", message, user)),
+            Err(e) => self.output_line(&format!("{}", tr!(MSG_ERROR, i18n::error(&e)))),
+        }
+    }
+    
+    /// Show or change the desktop wallpaper
+    fn cmd_wallpaper(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        use crate::config::ConfigValue;
+        use crate::gui::wallpaper::{self, ImageMode};
+        
         if args.is_empty() {
-            self.output_line("Usage: rm <path>");
+            let current = config::get("ui.wallpaper").map(|value| value.as_string())
+                .unwrap_or_else(|| "(default)".to_string());
+            self.output_line(&format!("Wallpaper: {}", current));
+            self.show_usage("wallpaper");
             return Ok(());
         }
         
-        let path = self.resolve_path(args[0]);
-        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        let mode = match args.get(1) {
+            None | Some(&"tile") => ImageMode::Tile,
+            Some(&"stretch") => ImageMode::Stretch,
+            Some(other) => {
+                self.output_line(&format!("Unknown layout '{}' (use tile or stretch)", other));
+                return Ok(());
+            }
+        };
         
-        vfs.remove(&path)?;
-        self.output_line(&format!("Removed: {}", path));
+        // Bare image names live in the wallpaper directory; relative paths
+        // with a directory part are taken from the current directory
+        let spec = if wallpaper::is_image_spec(args[0]) && args[0].contains('/') {
+            self.resolve_path(args[0])
+        } else {
+            args[0].to_string()
+        };
+        
+        // Check it loads before storing it, so a bad file keeps the old one
+        if let Err(e) = wallpaper::load(&spec, mode) {
+            self.output_line(&format!("Can't use '{}' as wallpaper: {}", spec, e));
+            return Ok(());
+        }
         
+        let mode_name = if mode == ImageMode::Stretch { "stretch" } else { "tile" };
+        config::set("ui.wallpaper_mode", ConfigValue::string(mode_name));
+        config::set("ui.wallpaper", ConfigValue::string(&spec));
+        match config::save() {
+            Ok(()) => self.output_line(&format!("Wallpaper set to {}", spec)),
+            Err(e) => self.output_line(&format!("Wallpaper set to {} (not saved: {})", spec, e)),
+        }
         Ok(())
     }
     
-    /// Reboot the system
-    fn cmd_reboot(&mut self) -> Result<(), KernelError> {
-        self.output_line("Rebooting...");
+    /// List the command history, or just the last `n` entries
+    fn cmd_history(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        let count = match args.first() {
+            Some(arg) => arg.parse::<usize>().map_err(|_| KernelError::InvalidParameter)?,
+            None => self.history.len(),
+        };
         
-        // Wait a moment for the message to be seen
-        for _ in 0..10_000_000 {
-            // Spin to create visual delay
+        let skip = self.history.len().saturating_sub(count);
+        let mut text = String::new();
+        for (number, entry) in self.history.numbered().skip(skip) {
+            text.push_str(&format!("{:>5}  {}\n", number, entry));
         }
-        
-        // Reboot using the 8042 keyboard controller
-        unsafe {
-            use x86_64::instructions::port::Port;
-            let mut port = Port::new(0x64);
-            port.write(0xFE as u8);
+        if text.is_empty() {
+            text.push_str("(no history)");
         }
-        
-        // Should not reach here, but just in case
+        self.output_line(&text);
         Ok(())
     }
     
-    /// Display OS version information
-    fn cmd_version(&mut self) -> Result<(), KernelError> {
-        self.output_line("UniverseK OS v0.1.0");
-        self.output_line("A minimal Unix-like OS for x86_64");
+    /// Read or change the clipboard from the command line
+    fn cmd_clip(&mut self, args: &[&str]) -> Result<(), KernelError> {
+        match args.first() {
+            Some(&"set") if args.len() > 1 => {
+                clipboard::set(&args[1..].join(" "))?;
+                self.output_line("Copied to clipboard.");
+            }
+            Some(&"get") => {
+                let text = clipboard::get();
+                self.output_line(&text);
+            }
+            Some(&"history") => {
+                let mut text = String::from("Clipboard history (newest first):");
+                for (i, entry) in clipboard::history().iter().enumerate() {
+                    text.push_str(&format!("\n{:>2}: {}", i + 1, entry));
+                }
+                self.output_line(&text);
+            }
+            Some(&"clear") => {
+                clipboard::clear();
+                self.output_line("Clipboard cleared.");
+            }
+            _ => self.show_usage("clip"),
+        }
         Ok(())
     }
     
-    /// Resolve a relative path to an absolute path
+    /// Resolve a path against the current directory into a normalized
+    /// absolute path
     fn resolve_path(&self, path: &str) -> String {
-        if path.starts_with('/') {
-            return path.to_string();
+        Path::new(&self.current_dir).join(path).canonical().into_string()
+    }
+}
+
+/// Exit code of a task that ran a command: 0 if it succeeded
+fn exit_status(result: &Result<(), KernelError>) -> i64 {
+    match result {
+        Ok(()) => 0,
+        Err(KernelError::Interrupted) => INTERRUPTED_STATUS,
+        Err(_) => 1,
+    }
+}
+
+/// Parse "YYYY-MM-DD" and "HH:MM:SS" into a date and time
+fn parse_datetime(date: &str, time: &str) -> Option<crate::drivers::rtc::DateTime> {
+    let mut date = date.split('-').map(|part| part.parse::<u16>().ok());
+    let mut time = time.split(':').map(|part| part.parse::<u8>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if date.next().is_some() || time.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    Some(crate::drivers::rtc::DateTime { second, minute, hour, day: day as u8, month: month as u8, year })
+}
+
+/// Nanoseconds as seconds with three decimals, "0.532s"
+fn format_seconds(ns: u64) -> String {
+    let ms = ns / 1_000_000;
+    format!("{}.{:03}s", ms / 1000, ms % 1000)
+}
+
+/// What `time` prints: real time, CPU time when ticks are being counted
+/// (`tick_hz` is nonzero), and the clock's step when it is coarser than
+/// the milliseconds shown
+fn timing_line(real_ns: u64, cpu_ticks: Option<u64>, tick_hz: u32, resolution_ns: Option<u64>) -> String {
+    let mut line = format!("real {}", format_seconds(real_ns));
+    match cpu_ticks {
+        Some(ticks) if tick_hz > 0 => {
+            line.push_str(&format!("  cpu {}", format_seconds(ticks * 1_000_000_000 / u64::from(tick_hz))));
         }
-        
-        // Handle ".." to go up a directory
-        if path == ".." {
-            let parts: Vec<&str> = self.current_dir.split('/').collect();
-            if parts.len() <= 2 {
-                return "/".to_string();
-            } else {
-                return parts[..parts.len()-1].join("/");
-            }
+        _ => line.push_str("  cpu n/a"),
+    }
+    match resolution_ns {
+        Some(step) if step > 1_000_000 => line.push_str(&format!("  (clock steps {}ms)", step / 1_000_000)),
+        None => line.push_str("  (clock not running)"),
+        _ => {}
+    }
+    line
+}
+
+/// Open flags as letters: r(ead), w(rite), a(ppend), c(reate), t(runcate)
+fn fd_flags(flags: u8) -> String {
+    use fs::vfs::file_flags;
+    [(file_flags::READ, 'r'), (file_flags::WRITE, 'w'), (file_flags::APPEND, 'a'),
+        (file_flags::CREATE, 'c'), (file_flags::TRUNCATE, 't')]
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, letter)| *letter)
+        .collect()
+}
+
+/// Text with `\n`, `\t` and `\\` escapes turned into the characters, as
+/// `sendkeys` and `motd set` take it
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
         }
-        
-        // Handle "." to stay in current directory
-        if path == "." {
-            return self.current_dir.clone();
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
         }
-        
-        // Handle relative path
-        if self.current_dir.ends_with('/') {
-            format!("{}{}", self.current_dir, path)
-        } else {
-            format!("{}/{}", self.current_dir, path)
+    }
+    unescaped
+}
+
+/// A playback speed such as "2" or "0.5" as a percentage of the original
+/// pace; None unless it's a positive number with at most two decimals
+fn parse_speed(text: &str) -> Option<u64> {
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if fraction.len() > 2 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let whole: u64 = if whole.is_empty() { 0 } else { whole.parse().ok()? };
+    let hundredths: u64 = format!("{:0<2}", fraction).parse().ok()?;
+    let percent = whole.checked_mul(100)?.checked_add(hundredths)?;
+    (percent > 0).then_some(percent)
+}
+
+/// A config value's type as `config` shows it
+fn config_type(value: &config::ConfigValue) -> &'static str {
+    match value {
+        config::ConfigValue::String(_) => "string",
+        config::ConfigValue::Integer(_) => "integer",
+        config::ConfigValue::Boolean(_) => "bool",
+    }
+}
+
+/// Byte count in B, KiB or MiB with one decimal
+fn format_size(bytes: u64) -> String {
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+    if bytes >= MIB {
+        format!("{}.{} MiB", bytes / MIB, bytes % MIB * 10 / MIB)
+    } else if bytes >= KIB {
+        format!("{}.{} KiB", bytes / KIB, bytes % KIB * 10 / KIB)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// Prints paths as `find` comes across them
+struct Finder<'a> {
+    shell: &'a mut Shell,
+    pattern: &'a str,
+    matches: usize,
+}
+
+impl fs::walk::Visitor for Finder<'_> {
+    fn enter(&mut self, entry: &fs::walk::WalkEntry) {
+        let name = Path::new(entry.path).file_name().unwrap_or(entry.path);
+        if entry.depth > 0 && fs::walk::wildcard_match(self.pattern, name) {
+            self.matches += 1;
+            self.shell.output_line(entry.path);
         }
     }
+    
+    fn error(&mut self, path: &str, error: KernelError) {
+        self.shell.output_line(&format!("find: {}: {:?}", path, error));
+    }
+    
+    fn stop(&mut self) -> bool {
+        self.shell.cancelled()
+    }
 }
 
-/// Global shell instance
-static mut SHELL: Option<Shell> = None;
+/// Adds up file sizes for `du`, one running total per open directory
+struct DiskUsage<'a> {
+    shell: &'a mut Shell,
+    vfs: &'a fs::vfs::VfsManager,
+    summary_only: bool,
+    totals: Vec<u64>,
+    /// Size of the whole tree, once the walk is done
+    total: u64,
+}
 
-/// Initialize the shell subsystem
-pub fn init() -> Result<(), KernelError> {
-    serial_println!("DEBUG: Initializing shell subsystem");
+impl fs::walk::Visitor for DiskUsage<'_> {
+    fn enter(&mut self, entry: &fs::walk::WalkEntry) {
+        if entry.node_type == fs::vfs::NodeType::Directory {
+            self.totals.push(0);
+            return;
+        }
+        match self.vfs.metadata(entry.path) {
+            Ok(metadata) => match self.totals.last_mut() {
+                Some(total) => *total += metadata.size,
+                None => {
+                    // The start is a file
+                    self.total = metadata.size;
+                    if !self.summary_only {
+                        self.shell.output_line(&format!("{:>10}  {}", metadata.size, entry.path));
+                    }
+                }
+            },
+            Err(e) => self.error(entry.path, e),
+        }
+    }
     
-    // Create a new shell instance
-    unsafe {
-        serial_println!("DEBUG: Creating new Shell instance");
-        SHELL = Some(Shell::new());
-        
-        if let Some(shell) = SHELL.as_mut() {
-            serial_println!("DEBUG: Calling shell.init()");
-            shell.init();
-            serial_println!("DEBUG: Shell instance initialized successfully");
-        } else {
-            serial_println!("ERROR: Failed to access shell instance after creation");
-            return Err(KernelError::InitializationFailed);
+    fn leave(&mut self, entry: &fs::walk::WalkEntry) {
+        let size = self.totals.pop().unwrap_or(0);
+        match self.totals.last_mut() {
+            Some(parent) => *parent += size,
+            None => self.total = size,
+        }
+        if !self.summary_only {
+            self.shell.output_line(&format!("{:>10}  {}", size, entry.path));
         }
     }
     
-    serial_println!("DEBUG: Shell subsystem initialization complete");
-    Ok(())
+    fn error(&mut self, path: &str, error: KernelError) {
+        self.shell.output_line(&format!("du: {}: {:?}", path, error));
+    }
+    
+    fn stop(&mut self) -> bool {
+        self.shell.cancelled()
+    }
 }
 
-/// Get a reference to the shell, if initialized
-pub fn get_shell() -> Option<&'static mut Shell> {
-    unsafe { SHELL.as_mut() }
+/// Set while `run` has the screen, so a second session can't start over it
+static CONSOLE_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Run `shell` full screen until Esc or a switch to the desktop (blocking).
+/// `policy` says whether Esc returns or offers to reboot. The screen is
+/// left blank on the way out for whoever takes it next. Starting while the
+/// desktop has the screen, or while another run is going, is refused.
+pub fn run(shell: &mut Shell, policy: ExitPolicy) -> Result<ShellExit, KernelError> {
+    if shell.captured.is_some() || crate::vt::current() != crate::vt::Mode::Console
+        || CONSOLE_ACTIVE.swap(true, Ordering::Acquire) {
+        serial_println!("SHELL: {}", NESTED_RUN);
+        return Err(KernelError::GenericError(NESTED_RUN));
+    }
+    shell.exit_policy = policy;
+    let exit = run_session(shell);
+    shell.leave_screen();
+    CONSOLE_ACTIVE.store(false, Ordering::Release);
+    serial_println!("DEBUG: Shell exited: {:?}", exit);
+    Ok(exit)
 }
 
-/// Run the shell (blocking)
-pub fn run() -> Result<(), KernelError> {
+/// The body of `run`, once the screen is the shell's
+fn run_session(shell: &mut Shell) -> ShellExit {
     serial_println!("DEBUG: Starting shell main loop");
     
-    // Get shell instance
-    let shell = unsafe {
-        match SHELL.as_mut() {
-            Some(s) => {
-                serial_println!("DEBUG: Got shell instance");
-                s
-            },
-            None => {
-                serial_println!("ERROR: Shell not initialized, cannot run");
-                return Err(KernelError::NotInitialized);
-            }
-        }
-    };
-    
-    // Draw initial screen
+    // Draw initial screen; keys typed before a switch from the desktop
+    // were meant for it
     serial_println!("DEBUG: Drawing initial shell screen");
+    shell.typeahead.clear();
     shell.clear_screen();
-    shell.display_welcome();
+    if shell.ran_before {
+        shell.output_line("Back at the console. startx or Ctrl+Alt+F2 returns to the desktop.");
+    } else {
+        shell.display_welcome();
+        shell.show_motd();
+    }
+    shell.ran_before = true;
     shell.draw_prompt();
+    let headless = !vga_enhanced::display_available();
+    if headless {
+        serial_println!("UniverseK OS shell on the serial console; type 'help' for commands");
+        crate::serial_print!("{}", shell.prompt_text());
+    }
     
     // Indicate we're ready for input
     {
@@ -666,8 +2909,11 @@ pub fn run() -> Result<(), KernelError> {
     serial_println!("DEBUG: Entering shell input loop");
     let mut loop_count = 0;
     let mut last_key_time = 0;
+    let heartbeat = crate::task::watchdog::register("shell");
     
     loop {
+        heartbeat.kick();
+        
         // Safety check - ensure we don't process too many key events too quickly
         // This prevents potential event queue overflow
         let now = loop_count;
@@ -675,7 +2921,7 @@ pub fn run() -> Result<(), KernelError> {
         
         if key_interval > 100 { // Only check for keys after some cycles
             // Poll for keyboard input
-            if let Some(key_event) = crate::drivers::ps2_keyboard::get_event() {
+            if let Some(key_event) = shell.next_key() {
                 last_key_time = now;
                 
                 // Log key event
@@ -690,13 +2936,33 @@ pub fn run() -> Result<(), KernelError> {
                 serial_println!("DEBUG: Shell received key event: code={:?}, state={:?}", 
                     key_event.code, key_event.state);
                 
-                if shell.handle_key(key_event) {
+                if let Some(result) = crate::vt::handle_hotkey(&key_event) {
+                    if result.is_err() {
+                        shell.output_line(NO_DESKTOP);
+                    }
+                } else if shell.handle_key(key_event) {
                     // Exit code (ESC key pressed)
                     serial_println!("DEBUG: Shell exit requested (ESC key)");
-                    break;
+                    return ShellExit::Escaped;
                 }
             }
         }
+        if crate::vt::switch_pending() {
+            serial_println!("DEBUG: Shell handing over to the desktop");
+            return ShellExit::Switched;
+        }
+        if headless {
+            shell.poll_serial();
+        }
+        // Nothing here reads the mouse; its events would only pile up
+        while crate::drivers::ps2_mouse::get_event().is_some() {}
+        shell.poll_watch();
+        
+        // Process network traffic and other deferred work
+        crate::net::poll();
+        crate::drivers::rtc::poll();
+        crate::drivers::pit::poll();
+        crate::task::deferred::run_pending();
         
         // Output periodic heartbeat to show we're still running
         loop_count += 1;
@@ -733,12 +2999,287 @@ pub fn run() -> Result<(), KernelError> {
         }
         
         // Use CPU's HLT instruction to pause until the next interrupt
-        // This saves power and CPU cycles
-        if loop_count % 1000 == 0 {
+        // This saves power and CPU cycles. With interrupts off nothing
+        // would wake it, so it only halts when they're on.
+        if loop_count % 1000 == 0 && x86_64::instructions::interrupts::are_enabled() {
             x86_64::instructions::hlt();
         }
     }
+}
+
+/// Drive the line editor with synthetic key events and check the buffer,
+/// cursor and horizontal scroll after each step
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SHELL: Running line editor self-test");
+
+    let press = |shell: &mut Shell, code: KeyCode, ctrl: bool, alt: bool| {
+        shell.handle_key(KeyEvent { code, state: KeyState::Pressed, shift: false, ctrl, alt, time_ms: 0 });
+    };
+    let check = |shell: &Shell, buffer: &str, cursor: usize| -> Result<(), KernelError> {
+        if shell.input_buffer != buffer || shell.cursor_position != cursor {
+            serial_println!("SHELL: Got '{}' at {}, expected '{}' at {}",
+                shell.input_buffer, shell.cursor_position, buffer, cursor);
+            return Err(KernelError::ValidationError("Line editor produced the wrong input"));
+        }
+        Ok(())
+    };
+
+    // Redrawing moves the VGA cursor; put it back afterwards
+    let saved_cursor = vga_enhanced::get_cursor_position();
+    let mut shell = Shell::new();
+    let result = (|| {
+        shell.insert_text("echo hello world");
+        press(&mut shell, KeyCode::W, true, false);
+        check(&shell, "echo hello ", 11)?;
+        press(&mut shell, KeyCode::A, true, false);
+        press(&mut shell, KeyCode::ArrowRight, true, false);
+        check(&shell, "echo hello ", 4)?;
+        press(&mut shell, KeyCode::K, true, false);
+        check(&shell, "echo", 4)?;
+        press(&mut shell, KeyCode::Y, true, false);
+        check(&shell, "echo hello ", 11)?;
+        press(&mut shell, KeyCode::B, false, true);
+        check(&shell, "echo hello ", 5)?;
+        press(&mut shell, KeyCode::E, true, false);
+        press(&mut shell, KeyCode::U, true, false);
+        check(&shell, "", 0)?;
+        press(&mut shell, KeyCode::Y, true, false);
+        check(&shell, "echo hello ", 11)?;
+        if shell.kill_ring.len() != 3 {
+            return Err(KernelError::ValidationError("Kill ring lost entries"));
+        }
+
+        // A line wider than the window scrolls to keep the cursor in view
+        shell.insert_text(&"x".repeat(200));
+        shell.redraw_input_line();
+        let width = shell.input_width();
+        if shell.cursor_position - shell.input_scroll != width - 1 {
+            return Err(KernelError::ValidationError("Long input line not scrolled to the cursor"));
+        }
+        press(&mut shell, KeyCode::Home, false, false);
+        if shell.input_scroll != 0 {
+            return Err(KernelError::ValidationError("Home did not scroll back to the start"));
+        }
+
+        // Injected keys reach the shell as a typed and entered line
+        press(&mut shell, KeyCode::E, true, false);
+        press(&mut shell, KeyCode::U, true, false);
+        let scancodes = input::text_scancodes("echo injected\n").ok_or(KernelError::InvalidData)?;
+        input::queue_keys(&scancodes)?;
+        while let Some(event) = ps2_keyboard::get_event() {
+            shell.handle_key(event);
+        }
+        if shell.history.last() != Some("echo injected") || !shell.input_buffer.is_empty() {
+            return Err(KernelError::ValidationError("Injected keys did not run the command"));
+        }
+        Ok(())
+    })();
+    input::clear();
+    ps2_keyboard::reset_state();
+    vga_enhanced::set_cursor_position(saved_cursor.0, saved_cursor.1);
+
+    result?;
+    serial_println!("SHELL: Line editor self-test passed");
+    cancel_self_test()?;
+    dir_stack_self_test()?;
+    detached_self_test()
+}
+
+/// Stop a `cp` with an injected Ctrl+C and check the partial copy is gone,
+/// other keys are kept, and the command's task was reaped; then check a
+/// trailing & is refused rather than run
+fn cancel_self_test() -> Result<(), KernelError> {
+    serial_println!("SHELL: Running Ctrl+C self-test");
+    let Some(vfs) = fs::vfs::get_vfs_manager() else {
+        serial_println!("SHELL: Ctrl+C self-test skipped (no file system)");
+        return Ok(());
+    };
+
+    // Ctrl down, C down and up, Ctrl up, after an x typed ahead
+    const CTRL_C: [u8; 6] = [0x2D, 0xAD, 0x1D, 0x2E, 0xAE, 0x9D];
+    let (source, target) = ("/tmp/cp-selftest", "/tmp/cp-selftest.copy");
+    let saved_cursor = vga_enhanced::get_cursor_position();
+    let mut shell = Shell::new();
+    let tasks = scheduler::task_list().len();
+    let result = (|| {
+        vfs.create_file(source)?;
+        fs::direct_write_file(source, &[0x5A; COPY_BLOCK_SIZE * 4])?;
+
+        input::queue_keys(&CTRL_C)?;
+        shell.process_command("cp /tmp/cp-selftest /tmp/cp-selftest.copy")?;
+        if !shell.interrupted || vfs.metadata(target).is_ok() {
+            return Err(KernelError::ValidationError("Ctrl+C did not stop cp and remove its copy"));
+        }
+        if shell.typeahead.iter().filter(|event| event.state == KeyState::Pressed).count() != 1
+            || !shell.prompt_text().starts_with("^C") {
+            return Err(KernelError::ValidationError("Keys typed during a command were mishandled"));
+        }
+        if scheduler::task_list().len() != tasks {
+            return Err(KernelError::ValidationError("Cancelled command's task was not reaped"));
+        }
+
+        // A full disk fails the command, and the failed copy isn't left behind
+        if !matches!(shell.process_command("cp /tmp/cp-selftest /dev/full"), Err(KernelError::NoSpace)) {
+            return Err(KernelError::ValidationError("Write to /dev/full did not fail the command"));
+        }
+        // So does a disk failing after the first block went through
+        if faultinject::enabled() {
+            faultinject::arm(faultinject::TEMPFS_WRITE.name(), faultinject::Arming { after: 1, ..Default::default() })?;
+            let copied = shell.process_command("cp /tmp/cp-selftest /tmp/cp-selftest.copy");
+            faultinject::TEMPFS_WRITE.disarm();
+            if !matches!(copied, Err(KernelError::IoError)) || vfs.metadata(target).is_ok() {
+                return Err(KernelError::ValidationError("cp left a partial copy after a write error"));
+            }
+        }
+        if timing_line(532_000_000, Some(401), 1000, Some(1_000_000)) != "real 0.532s  cpu 0.401s"
+            || timing_line(1_500_000_000, None, 1000, Some(1_000_000)) != "real 1.500s  cpu n/a"
+            || timing_line(0, Some(1), 18, Some(54_945_054)) != "real 0.000s  cpu 0.055s  (clock steps 54ms)" {
+            return Err(KernelError::ValidationError("time output formatted wrongly"));
+        }
+        if [parse_speed("2"), parse_speed("0.5"), parse_speed(".25"), parse_speed("1.5")] != [Some(200), Some(50), Some(25), Some(150)]
+            || [parse_speed("0"), parse_speed("1.234"), parse_speed("-1"), parse_speed("x")].iter().any(Option::is_some) {
+            return Err(KernelError::ValidationError("guirec speed parsed wrongly"));
+        }
+        // Timed, even twice over, it still fails the same way
+        if !matches!(shell.process_command("time time cp /tmp/cp-selftest /dev/full"), Err(KernelError::NoSpace)) {
+            return Err(KernelError::ValidationError("time changed the result of the command it ran"));
+        }
+
+        // Nothing can run it alongside the prompt, so a trailing & runs nothing
+        let background = shell.process_command("cp /tmp/cp-selftest /tmp/cp-selftest.copy &");
+        if !matches!(background, Err(KernelError::UnsupportedFeature)) || vfs.metadata(target).is_ok() {
+            return Err(KernelError::ValidationError("Background command not refused"));
+        }
+        shell.process_command("cp /tmp/cp-selftest /tmp/cp-selftest.copy")?;
+        if shell.interrupted || vfs.metadata(target)?.size != (COPY_BLOCK_SIZE * 4) as u64 {
+            return Err(KernelError::ValidationError("cp copied the wrong amount"));
+        }
+        if scheduler::task_list().len() != tasks {
+            return Err(KernelError::ValidationError("Finished command's task was not reaped"));
+        }
+        Ok(())
+    })();
+    input::clear();
+    ps2_keyboard::reset_state();
+    vga_enhanced::set_cursor_position(saved_cursor.0, saved_cursor.1);
+    let _ = vfs.remove(target);
+    let _ = vfs.remove(source);
+
+    result?;
+    serial_println!("SHELL: Ctrl+C self-test passed");
+    Ok(())
+}
+
+/// Walk around a scratch tree with pushd, popd and cd -, relative paths
+/// included, and check the current directory and stack after each step,
+/// failed steps leaving both alone
+fn dir_stack_self_test() -> Result<(), KernelError> {
+    serial_println!("SHELL: Running directory stack self-test");
+    let Some(vfs) = fs::vfs::get_vfs_manager() else {
+        serial_println!("SHELL: Directory stack self-test skipped (no file system)");
+        return Ok(());
+    };
+
+    let (base, deep) = ("/tmp/pushd-selftest", "/tmp/pushd-selftest/a/b");
+    let saved_cursor = vga_enhanced::get_cursor_position();
+    let mut shell = Shell::new();
+    let check = |shell: &Shell, step: &str, dir: &str, stack: &[&str]| {
+        if shell.current_dir != dir || !shell.dirs.iter().eq(stack.iter().copied()) {
+            serial_println!("SHELL: After '{}' in {} with {:?}, expected {} with {:?}",
+                step, shell.current_dir, shell.dirs.iter().collect::<Vec<_>>(), dir, stack);
+            return Err(KernelError::ValidationError("Directory stack in the wrong state"));
+        }
+        Ok(())
+    };
+    let result = (|| {
+        for dir in [base, "/tmp/pushd-selftest/a", deep] {
+            vfs.create_directory(dir)?;
+        }
+
+        shell.process_command("cd /tmp/pushd-selftest")?;
+        shell.process_command("pushd a/./b/")?;
+        check(&shell, "pushd a/./b/", deep, &[base])?;
+        shell.process_command("pushd ../..")?;
+        check(&shell, "pushd ../..", base, &[deep, base])?;
+        if shell.process_command("pushd missing").is_ok() || shell.process_command("pushd a/b/../../..").is_err() {
+            return Err(KernelError::ValidationError("pushd succeeded or failed wrongly"));
+        }
+        check(&shell, "pushd a/b/../../..", "/tmp", &[base, deep, base])?;
+        shell.process_command("popd")?;
+        check(&shell, "popd", base, &[deep, base])?;
+
+        shell.process_command("popd")?;
+        check(&shell, "popd", deep, &[base])?;
+        shell.process_command("cd -")?;
+        check(&shell, "cd -", base, &[base])?;
+        shell.process_command("cd -")?;
+        check(&shell, "cd -", deep, &[base])?;
+
+        // A stack entry that has gone away stays on the stack
+        shell.process_command("pushd /tmp/pushd-selftest/a")?;
+        shell.process_command("cd /")?;
+        vfs.remove(deep)?;
+        if shell.process_command("popd").is_ok() {
+            return Err(KernelError::ValidationError("popd into a removed directory succeeded"));
+        }
+        check(&shell, "popd", "/", &[deep, base])?;
+
+        shell.dirs = DirStack::new(2);
+        for _ in 0..3 {
+            shell.process_command("pushd /tmp")?;
+        }
+        check(&shell, "pushd /tmp", "/tmp", &["/tmp", "/tmp"])?;
+        shell.process_command("popd")?;
+        shell.process_command("popd")?;
+        if shell.process_command("popd").is_ok() {
+            return Err(KernelError::ValidationError("popd on an empty stack succeeded"));
+        }
+        check(&shell, "popd", "/tmp", &[])
+    })();
+    vga_enhanced::set_cursor_position(saved_cursor.0, saved_cursor.1);
+    let _ = fs::trash::remove_tree(vfs, base);
+
+    result?;
+    serial_println!("SHELL: Directory stack self-test passed");
+    Ok(())
+}
+
+/// Run lines in a detached shell and check their output is kept rather
+/// than drawn, `clear` and console-only commands are handled, and `run`
+/// refuses a detached shell and a second session
+fn detached_self_test() -> Result<(), KernelError> {
+    serial_println!("SHELL: Running detached shell self-test");
+    let mut shell = Shell::detached("/");
+    
+    let output = shell.run_line("echo hello");
+    if output.clear || output.lines != [(String::from("hello"), Color::White)] {
+        serial_println!("SHELL: echo gave {:?}", output);
+        return Err(KernelError::ValidationError("Detached shell output not captured"));
+    }
+    let output = shell.run_line("clear");
+    if !output.clear || !output.lines.is_empty() {
+        return Err(KernelError::ValidationError("clear in a detached shell not passed on"));
+    }
+    let output = shell.run_line("lock");
+    if !output.lines.first().is_some_and(|(line, _)| line.starts_with("lock: only in the full-screen shell")) {
+        return Err(KernelError::ValidationError("Detached shell ran a console-only command"));
+    }
+    if shell.history.last() != Some("lock") || !shell.take_output().lines.is_empty() {
+        return Err(KernelError::ValidationError("Detached shell history or output wrong"));
+    }
     
-    serial_println!("DEBUG: Shell exited normally");
+    // Neither attempt may touch the screen or the guard
+    let was_active = CONSOLE_ACTIVE.load(Ordering::Acquire);
+    if run(&mut shell, ExitPolicy::ReturnToCaller).is_ok() {
+        return Err(KernelError::ValidationError("A detached shell took the screen"));
+    }
+    CONSOLE_ACTIVE.store(true, Ordering::Release);
+    let nested = run(&mut Shell::new(), ExitPolicy::ReturnToCaller);
+    CONSOLE_ACTIVE.store(was_active, Ordering::Release);
+    if nested.is_ok() {
+        return Err(KernelError::ValidationError("A second shell session started"));
+    }
+    
+    serial_println!("SHELL: Detached shell self-test passed");
     Ok(())
-} 
\ No newline at end of file
+}
@@ -0,0 +1,195 @@
+//! Command-line tokenizer for the shell
+//! Splits a line into arguments the way sh does: whitespace separates words,
+//! single quotes keep everything literal, double quotes keep whitespace but
+//! still honour \" \\ and \$, and a backslash outside quotes escapes any
+//! character. Quoted empty strings stay as empty arguments.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// Why a line couldn't be split; the command isn't run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxError {
+    /// A quote of this kind was never closed
+    UnterminatedQuote(char),
+    /// The line ends in a backslash with nothing to escape
+    TrailingBackslash,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyntaxError::UnterminatedQuote(quote) => write!(f, "unterminated {} quote", quote),
+            SyntaxError::TrailingBackslash => write!(f, "backslash at end of line"),
+        }
+    }
+}
+
+/// Split `line` into arguments
+pub fn tokenize(line: &str) -> Result<Vec<String>, SyntaxError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // Set once the current word has started, so "" still makes a word
+    let mut in_word = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(core::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\\' => {
+                word.push(chars.next().ok_or(SyntaxError::TrailingBackslash)?);
+                in_word = true;
+            }
+            '\'' => {
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(SyntaxError::UnterminatedQuote('\'')),
+                    }
+                }
+                in_word = true;
+            }
+            '"' => {
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(SyntaxError::UnterminatedQuote('"')),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(SyntaxError::UnterminatedQuote('"')),
+                    }
+                }
+                in_word = true;
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// `word` written so `tokenize` reads it back as one argument: as it is
+/// when that's safe, otherwise in single quotes
+pub fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%^*".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return String::from(word);
+    }
+    // A single quote can't appear inside single quotes: close, escape, reopen
+    let mut quoted = String::from("'");
+    quoted.push_str(&word.replace('\'', "'\\''"));
+    quoted.push('\'');
+    quoted
+}
+
+/// Arguments joined back into a line that tokenizes to the same words
+pub fn join(words: &[&str]) -> String {
+    words.iter().map(|word| quote(word)).collect::<Vec<_>>().join(" ")
+}
+
+/// The command before a trailing `&`, when the line ends in one that isn't
+/// quoted or escaped, asking for the line to run in the background
+pub fn strip_background(line: &str) -> Option<&str> {
+    let before = line.trim_end().strip_suffix('&')?;
+    // The & is quoted or escaped if the line before it leaves a quote open
+    // or ends in a backslash
+    let mut quote = None;
+    let mut escaped = false;
+    for c in before.chars() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (None | Some('"'), '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
+        }
+    }
+    let body = before.trim_end();
+    if quote.is_some() || escaped || body.is_empty() {
+        return None;
+    }
+    Some(body)
+}
+
+/// Check tokenize against lines with each kind of quoting
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SHELL: Running tokenizer self-test");
+
+    let cases: [(&str, Result<&[&str], SyntaxError>); 12] = [
+        ("", Ok(&[])),
+        ("  ls   -l  /bin ", Ok(&["ls", "-l", "/bin"])),
+        ("echo \"hello world\"", Ok(&["echo", "hello world"])),
+        ("mkdir 'My Documents'", Ok(&["mkdir", "My Documents"])),
+        ("cd Application\\ Support", Ok(&["cd", "Application Support"])),
+        ("echo \"it's\" 'say \"hi\"'", Ok(&["echo", "it's", "say \"hi\""])),
+        ("echo \"a \\\"b\\\" \\n\"", Ok(&["echo", "a \"b\" \\n"])),
+        ("echo 'a\\b' x\"y z\"w", Ok(&["echo", "a\\b", "xy zw"])),
+        ("touch \"\" '' x", Ok(&["touch", "", "", "x"])),
+        ("echo '>' \\|", Ok(&["echo", ">", "|"])),
+        ("echo trailing\\", Err(SyntaxError::TrailingBackslash)),
+        ("echo \"open 'inner'", Err(SyntaxError::UnterminatedQuote('"'))),
+    ];
+    for (line, expected) in cases.iter() {
+        let got = tokenize(line);
+        let matches = match (&got, expected) {
+            (Ok(words), Ok(expected)) => words.iter().map(String::as_str).eq(expected.iter().copied()),
+            (Err(error), Err(expected)) => error == expected,
+            _ => false,
+        };
+        if !matches {
+            serial_println!("SHELL: '{}' split as {:?}, expected {:?}", line, got, expected);
+            return Err(KernelError::ValidationError("Command line split wrongly"));
+        }
+    }
+    if tokenize("echo 'open") != Err(SyntaxError::UnterminatedQuote('\'')) {
+        return Err(KernelError::ValidationError("Unterminated single quote accepted"));
+    }
+
+    let background = [
+        ("cp big /tmp/x &", Some("cp big /tmp/x")),
+        ("find / *.txt&  ", Some("find / *.txt")),
+        ("echo '&'", None),
+        ("echo 'a &", None),
+        ("echo \\&", None),
+        ("echo \\\\ &", Some("echo \\\\")),
+        ("&", None),
+    ];
+    for (line, expected) in background {
+        if strip_background(line) != expected {
+            serial_println!("SHELL: '{}' background part {:?}, expected {:?}", line, strip_background(line), expected);
+            return Err(KernelError::ValidationError("Trailing & recognised wrongly"));
+        }
+    }
+
+    // Joining quotes what needs it, so the words come back unchanged
+    let words = ["echo", "two  spaces", "it's", "", "a\\b", "*.txt", "&"];
+    let joined = join(&words);
+    if tokenize(&joined).map_or(true, |again| !again.iter().map(String::as_str).eq(words.iter().copied()))
+        || join(&["ls", "-l", "/bin"]) != "ls -l /bin" {
+        serial_println!("SHELL: {:?} joined as {}", words, joined);
+        return Err(KernelError::ValidationError("Joined words tokenize differently"));
+    }
+
+    serial_println!("SHELL: Tokenizer self-test passed");
+    Ok(())
+}
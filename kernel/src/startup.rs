@@ -0,0 +1,294 @@
+//! Init steps run in dependency order
+//!
+//! Each part of kernel start-up is an `InitStep` naming the steps it needs
+//! first. `run` puts them in an order that satisfies every dependency,
+//! keeping the listed order wherever dependencies allow. It then runs the
+//! steps one at a time, timing each with the PIT once that is running. A
+//! failed step that isn't critical is retried once when
+//! `boot.retry_failed_steps` is set, and boot goes on without it; a
+//! critical failure stops the run.
+//!
+//! The early steps run before the heap exists, so ordering and results use
+//! fixed-size arrays.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::errors::KernelError;
+use crate::{boot, serial_println};
+
+/// Most steps one run can hold
+pub const MAX_STEPS: usize = 12;
+
+/// One part of start-up
+pub struct InitStep<C> {
+    pub name: &'static str,
+    /// Steps that must have run first
+    pub depends_on: &'static [&'static str],
+    /// Whether boot stops if this step fails
+    pub critical: bool,
+    pub run: fn(&mut C) -> Result<(), KernelError>,
+}
+
+/// How a step ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// A critical step failed earlier, so this one never ran
+    NotRun,
+    Succeeded,
+    Failed(&'static str),
+}
+
+/// What happened to one step
+#[derive(Debug, Clone, Copy)]
+pub struct StepRecord {
+    pub name: &'static str,
+    pub critical: bool,
+    pub outcome: StepOutcome,
+    pub attempts: u8,
+    /// None until the PIT is running
+    pub duration_us: Option<u64>,
+}
+
+impl StepRecord {
+    const EMPTY: StepRecord = StepRecord {
+        name: "",
+        critical: false,
+        outcome: StepOutcome::NotRun,
+        attempts: 0,
+        duration_us: None,
+    };
+}
+
+/// The records of a run, in the order the steps ran
+pub struct Report {
+    records: [StepRecord; MAX_STEPS],
+    count: usize,
+}
+
+impl Report {
+    pub fn records(&self) -> &[StepRecord] {
+        &self.records[..self.count]
+    }
+
+    /// Whether the named step ran and succeeded
+    pub fn succeeded(&self, name: &str) -> bool {
+        self.records().iter().any(|record| record.name == name && record.outcome == StepOutcome::Succeeded)
+    }
+}
+
+lazy_static! {
+    /// Records of the boot run, for /proc/boot
+    static ref BOOT_RECORDS: Mutex<Vec<StepRecord>> = Mutex::new(Vec::new());
+}
+
+fn retry_enabled() -> bool {
+    crate::config::get("boot.retry_failed_steps")
+        .and_then(|value| value.try_as_boolean())
+        .unwrap_or(false)
+}
+
+/// Microseconds since boot, if the PIT is counting
+fn now_us() -> Option<u64> {
+    if crate::drivers::pit::frequency() == 0 {
+        None
+    } else {
+        Some(crate::time::monotonic_ns() / 1000)
+    }
+}
+
+/// Indices of `steps` in an order that runs every step after the steps it
+/// depends on, and the number of steps. Ties go to the earlier-listed step.
+pub fn order<C>(steps: &[InitStep<C>]) -> Result<([usize; MAX_STEPS], usize), KernelError> {
+    if steps.len() > MAX_STEPS {
+        return Err(KernelError::ValidationError("Too many init steps"));
+    }
+    let index_of = |name: &str| steps.iter().position(|step| step.name == name);
+    for (index, step) in steps.iter().enumerate() {
+        if index_of(step.name) != Some(index) {
+            serial_println!("STARTUP: Init step '{}' is listed twice", step.name);
+            return Err(KernelError::ValidationError("Init step names must be unique"));
+        }
+        if let Some(missing) = step.depends_on.iter().find(|name| index_of(name).is_none()) {
+            serial_println!("STARTUP: Init step '{}' depends on unknown step '{}'", step.name, missing);
+            return Err(KernelError::ValidationError("Init step depends on an unknown step"));
+        }
+    }
+
+    let mut placed = [false; MAX_STEPS];
+    let mut order = [0; MAX_STEPS];
+    for slot in 0..steps.len() {
+        let ready = (0..steps.len()).find(|&index| {
+            !placed[index] && steps[index].depends_on.iter()
+                .all(|name| index_of(name).map_or(false, |dependency| placed[dependency]))
+        });
+        let Some(next) = ready else {
+            // Whatever is left waits on something else that's left
+            for (index, step) in steps.iter().enumerate().filter(|(index, _)| !placed[*index]) {
+                serial_println!("STARTUP: Init step '{}' (#{}) is part of or behind a cycle", step.name, index);
+            }
+            return Err(KernelError::ValidationError("Init steps depend on each other in a cycle"));
+        };
+        placed[next] = true;
+        order[slot] = next;
+    }
+    Ok((order, steps.len()))
+}
+
+/// Put `steps` in dependency order and run them with `context`. With
+/// `show_progress` each step is a phase on the boot splash. Fails, with
+/// the records so far, when a critical step fails.
+pub fn run<C>(steps: &[InitStep<C>], context: &mut C, show_progress: bool) -> Result<Report, (Report, KernelError)> {
+    let empty = || Report { records: [StepRecord::EMPTY; MAX_STEPS], count: 0 };
+    let (order, count) = order(steps).map_err(|e| (empty(), e))?;
+
+    let mut report = empty();
+    report.count = count;
+    let mut names = [""; MAX_STEPS];
+    for (slot, &index) in order[..count].iter().enumerate() {
+        names[slot] = steps[index].name;
+        report.records[slot] = StepRecord { name: steps[index].name, critical: steps[index].critical, ..StepRecord::EMPTY };
+    }
+    if show_progress {
+        boot::begin(&names[..count]);
+    }
+
+    for (slot, &index) in order[..count].iter().enumerate() {
+        let step = &steps[index];
+        if show_progress {
+            boot::start_phase(slot);
+        }
+        let started = now_us();
+        let mut result = (step.run)(context);
+        let mut attempts = 1;
+        if let Err(e) = &result {
+            if !step.critical && retry_enabled() {
+                serial_println!("STARTUP: Init step '{}' failed ({:?}); retrying", step.name, e);
+                attempts += 1;
+                result = (step.run)(context);
+            }
+        }
+        let finished = now_us();
+
+        let record = &mut report.records[slot];
+        record.attempts = attempts;
+        record.duration_us = started.zip(finished).map(|(start, end)| end.saturating_sub(start));
+        record.outcome = match &result {
+            Ok(()) => StepOutcome::Succeeded,
+            Err(e) => StepOutcome::Failed(e.to_str()),
+        };
+        if show_progress {
+            if let Err(e) = &result {
+                boot::fail(&format!("{} failed: {:?}", step.name, e));
+            }
+            boot::end_phase();
+        }
+        if let Err(e) = result {
+            if step.critical {
+                return Err((report, e));
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn format_record(record: &StepRecord) -> String {
+    let outcome = match record.outcome {
+        StepOutcome::NotRun => "not run",
+        StepOutcome::Succeeded => "ok",
+        StepOutcome::Failed(reason) => reason,
+    };
+    let duration = match record.duration_us {
+        Some(us) => format!("{}.{:03} ms", us / 1000, us % 1000),
+        None => String::from("-"),
+    };
+    format!("{:<20} {:<8} {:<8} {:<20} {}",
+        record.name, if record.critical { "yes" } else { "no" }, record.attempts, outcome, duration)
+}
+
+/// Log the boot run's records and keep them for /proc/boot
+pub fn publish(report: &Report) {
+    serial_println!("STARTUP: Init steps:");
+    for record in report.records() {
+        let line = format_record(record);
+        serial_println!("STARTUP:   {}", line);
+        crate::logger::record(crate::logger::LogLevel::Info, "boot", &line);
+    }
+    *BOOT_RECORDS.lock() = report.records().to_vec();
+}
+
+/// The boot run as a table, one step per line, for /proc/boot
+pub fn boot_text() -> String {
+    let mut text = format!("{:<20} {:<8} {:<8} {:<20} {}\n", "STEP", "CRITICAL", "ATTEMPTS", "RESULT", "TIME");
+    for record in BOOT_RECORDS.lock().iter() {
+        text.push_str(&format_record(record));
+        text.push('\n');
+    }
+    text
+}
+
+/// Check ordering, cycle and unknown-dependency detection, retries and
+/// that only critical failures stop a run
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("STARTUP: Running self-test");
+
+    fn step(name: &'static str, depends_on: &'static [&'static str], critical: bool) -> InitStep<Vec<&'static str>> {
+        InitStep { name, depends_on, critical, run: |ran| { ran.push("?"); Ok(()) } }
+    }
+    fn names(steps: &[InitStep<Vec<&'static str>>]) -> Result<Vec<&'static str>, KernelError> {
+        let (order, count) = order(steps)?;
+        Ok(order[..count].iter().map(|&index| steps[index].name).collect())
+    }
+
+    // Listed order is kept where dependencies allow
+    let steps = [step("c", &["b"], false), step("a", &[], false), step("b", &["a"], false), step("d", &[], false)];
+    if names(&steps)? != ["a", "b", "c", "d"] {
+        return Err(KernelError::ValidationError("Init steps ordered wrongly"));
+    }
+
+    let cycle = [step("a", &[], false), step("b", &["d"], false), step("c", &["b"], false), step("d", &["c"], false)];
+    if order(&cycle).is_ok() {
+        return Err(KernelError::ValidationError("Dependency cycle not detected"));
+    }
+    let itself = [step("a", &["a"], false)];
+    if order(&itself).is_ok() {
+        return Err(KernelError::ValidationError("Step depending on itself not detected"));
+    }
+    let unknown = [step("a", &["missing"], false)];
+    if order(&unknown).is_ok() {
+        return Err(KernelError::ValidationError("Unknown dependency not detected"));
+    }
+
+    // A non-critical failure is recorded and passed over; a critical one stops the run
+    let failing = InitStep::<Vec<&'static str>> {
+        name: "flaky",
+        depends_on: &[],
+        critical: false,
+        run: |ran| { ran.push("flaky"); Err(KernelError::DeviceNotFound) },
+    };
+    let mut ran = Vec::new();
+    let steps = [failing, step("after", &["flaky"], false)];
+    let report = run(&steps, &mut ran, false).map_err(|(_, e)| e)?;
+    if report.succeeded("flaky") || !report.succeeded("after") || ran.len() != 1 + report.records()[0].attempts as usize {
+        return Err(KernelError::ValidationError("Non-critical step failure handled wrongly"));
+    }
+
+    let fatal = InitStep::<Vec<&'static str>> {
+        name: "fatal",
+        depends_on: &[],
+        critical: true,
+        run: |_| Err(KernelError::DeviceNotFound),
+    };
+    let mut ran = Vec::new();
+    let steps = [fatal, step("after", &[], false)];
+    match run(&steps, &mut ran, false) {
+        Err((report, _)) if report.records()[0].attempts == 1 && report.records()[1].outcome == StepOutcome::NotRun
+            && ran.is_empty() => {}
+        _ => return Err(KernelError::ValidationError("Critical step failure did not stop the run")),
+    }
+
+    serial_println!("STARTUP: Self-test passed");
+    Ok(())
+}
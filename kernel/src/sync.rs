@@ -0,0 +1,344 @@
+//! Lock diagnostics for UniverseK OS
+//! DiagMutex wraps spin::Mutex for the kernel's busiest global locks. It
+//! records who holds the lock and since which tick, and a lock() that spins
+//! too long reports the holder, and what the holder is itself waiting for,
+//! on the serial port. In strict mode it panics with both parties instead.
+//! An uncontended lock() or try_lock() costs one extra atomic store.
+
+use core::fmt::{self, Write};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use crate::drivers::pit;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// Spins on a held lock before it is reported
+pub const SPIN_REPORT_LIMIT: u64 = 50_000_000;
+
+/// Waits that can be recorded at once, for following chains of waiters
+const WAIT_SLOTS: usize = 16;
+
+/// Holders followed when looking for a cycle
+const MAX_CHAIN: usize = 8;
+
+/// Panic rather than warn on a lock that spins too long
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAITER: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LOCK: AtomicUsize = AtomicUsize::new(0);
+/// Who is waiting in each slot, encoded as for `LockInfo::holder`
+static WAITERS: [AtomicU64; WAIT_SLOTS] = [NO_WAITER; WAIT_SLOTS];
+/// The `LockInfo` each waiter is waiting for
+static WAITED_FOR: [AtomicUsize; WAIT_SLOTS] = [NO_LOCK; WAIT_SLOTS];
+
+/// Who holds or wants a lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holder {
+    /// No current task is known: during boot, or while the scheduler's own
+    /// lock is held
+    Boot,
+    /// An interrupt handler
+    Interrupt,
+    Task(u64),
+}
+
+impl Holder {
+    /// The code running now
+    pub fn current() -> Self {
+        if crate::interrupts::in_handler() {
+            Holder::Interrupt
+        } else {
+            crate::task::scheduler::try_current_task_id().map_or(Holder::Boot, Holder::Task)
+        }
+    }
+
+    fn encode(self) -> u64 {
+        match self {
+            Holder::Boot => 1,
+            Holder::Interrupt => 2,
+            Holder::Task(id) => id + 3,
+        }
+    }
+
+    fn decode(code: u64) -> Option<Self> {
+        match code {
+            0 => None,
+            1 => Some(Holder::Boot),
+            2 => Some(Holder::Interrupt),
+            id => Some(Holder::Task(id - 3)),
+        }
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Holder::Boot => write!(f, "boot"),
+            Holder::Interrupt => write!(f, "interrupt"),
+            Holder::Task(id) => write!(f, "task {}", id),
+        }
+    }
+}
+
+/// The part of a DiagMutex that doesn't depend on what it protects
+pub struct LockInfo {
+    name: &'static str,
+    /// Encoded holder, 0 while free
+    holder: AtomicU64,
+    /// Tick the holder took the lock
+    since: AtomicU64,
+}
+
+impl LockInfo {
+    fn holder(&self) -> Option<Holder> {
+        Holder::decode(self.holder.load(Ordering::Relaxed))
+    }
+
+    /// Describe `waiter` waiting for this lock, following the holder to
+    /// whatever it waits for in turn, and say so if the chain comes back
+    /// round to `waiter`
+    pub fn describe(&self, waiter: Holder, out: &mut dyn Write) -> fmt::Result {
+        let mut lock = self;
+        let mut wanted_by = waiter;
+        for _ in 0..MAX_CHAIN {
+            let holder = match lock.holder() {
+                Some(holder) => holder,
+                None => return writeln!(out, "LOCK: '{}' wanted by {} is free now", lock.name, wanted_by),
+            };
+            writeln!(out, "LOCK: '{}' wanted by {}, held by {} since tick {}",
+                lock.name, wanted_by, holder, lock.since.load(Ordering::Relaxed))?;
+            if holder == waiter {
+                return writeln!(out, "LOCK: deadlock, {} is waiting for a lock it holds itself", waiter);
+            }
+            match waiting_for(holder) {
+                Some(next) => {
+                    lock = next;
+                    wanted_by = holder;
+                },
+                None => return Ok(()),
+            }
+        }
+        writeln!(out, "LOCK: chain of waiters too long to follow")
+    }
+}
+
+/// The lock `holder` is recorded as waiting for
+fn waiting_for(holder: Holder) -> Option<&'static LockInfo> {
+    let code = holder.encode();
+    let slot = WAITERS.iter().position(|waiter| waiter.load(Ordering::Relaxed) == code)?;
+    let lock = WAITED_FOR[slot].load(Ordering::Relaxed) as *const LockInfo;
+    // Safety: a waiter clears its slot before it stops borrowing the lock
+    unsafe { lock.as_ref() }
+}
+
+/// A recorded wait, cleared on drop
+struct Waiting {
+    slot: Option<usize>,
+}
+
+impl Waiting {
+    /// Record that `waiter` waits for `lock`. Too many waiters at once just
+    /// go unrecorded.
+    fn record(waiter: Holder, lock: &LockInfo) -> Self {
+        let code = waiter.encode();
+        let slot = WAITERS.iter().position(|slot| {
+            slot.compare_exchange(0, code, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        });
+        if let Some(slot) = slot {
+            WAITED_FOR[slot].store(lock as *const LockInfo as usize, Ordering::SeqCst);
+        }
+        Self { slot }
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            WAITED_FOR[slot].store(0, Ordering::SeqCst);
+            WAITERS[slot].store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Panic instead of warning when a lock spins too long; read from the
+/// "debug.strict_locks" setting
+pub fn init() {
+    let strict = crate::config::get("debug.strict_locks")
+        .and_then(|value| value.try_as_boolean())
+        .unwrap_or(false);
+    STRICT.store(strict, Ordering::SeqCst);
+}
+
+/// A spin lock that knows who holds it
+pub struct DiagMutex<T: ?Sized> {
+    info: LockInfo,
+    inner: Mutex<T>,
+}
+
+/// Holds a DiagMutex until dropped
+pub struct DiagMutexGuard<'a, T: ?Sized> {
+    info: &'a LockInfo,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> DiagMutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            info: LockInfo { name, holder: AtomicU64::new(0), since: AtomicU64::new(0) },
+            inner: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> DiagMutex<T> {
+    pub fn name(&self) -> &'static str {
+        self.info.name
+    }
+
+    /// Who holds the lock now, if anyone
+    pub fn holder(&self) -> Option<Holder> {
+        self.info.holder()
+    }
+
+    pub fn lock(&self) -> DiagMutexGuard<'_, T> {
+        match self.inner.try_lock() {
+            Some(guard) => self.acquired(guard, Holder::current()),
+            None => self.lock_contended(Holder::current()),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<DiagMutexGuard<'_, T>> {
+        self.inner.try_lock().map(|guard| self.acquired(guard, Holder::current()))
+    }
+
+    #[cold]
+    fn lock_contended(&self, me: Holder) -> DiagMutexGuard<'_, T> {
+        if let Some(guard) = self.wait(me, SPIN_REPORT_LIMIT) {
+            return self.acquired(guard, me);
+        }
+
+        // The serial port's own lock is taken without waiting, in case the
+        // stuck holder has it
+        if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+            let _ = self.info.describe(me, &mut *serial);
+        }
+        if STRICT.load(Ordering::Relaxed) {
+            panic!("Lock '{}' stuck: {} waiting, held by {:?}", self.info.name, me, self.info.holder());
+        }
+
+        let _waiting = Waiting::record(me, &self.info);
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                return self.acquired(guard, me);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Spin up to `spins` times for the lock, recorded as waiting for it
+    fn wait(&self, me: Holder, spins: u64) -> Option<MutexGuard<'_, T>> {
+        let _waiting = Waiting::record(me, &self.info);
+        for _ in 0..spins {
+            if let Some(guard) = self.inner.try_lock() {
+                return Some(guard);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    fn acquired<'a>(&'a self, guard: MutexGuard<'a, T>, me: Holder) -> DiagMutexGuard<'a, T> {
+        self.info.since.store(pit::ticks(), Ordering::Relaxed);
+        self.info.holder.store(me.encode(), Ordering::Relaxed);
+        DiagMutexGuard { info: &self.info, guard }
+    }
+
+    /// Release a lock whose guard can't be reached, for panic paths
+    ///
+    /// # Safety
+    /// Whoever held the lock must never touch it again.
+    pub unsafe fn force_unlock(&self) {
+        self.info.holder.store(0, Ordering::Relaxed);
+        self.inner.force_unlock();
+    }
+}
+
+impl<T: ?Sized> Drop for DiagMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Cleared first; the inner guard unlocks after this
+        self.info.holder.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<T: ?Sized> Deref for DiagMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for DiagMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for DiagMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DiagMutex {{ name: {}, holder: {:?} }}", self.info.name, self.info.holder())
+    }
+}
+
+impl DiagMutex<()> {
+    /// Take the lock as `holder`, whoever is really running
+    fn lock_as(&self, holder: Holder) -> Option<DiagMutexGuard<'_, ()>> {
+        self.inner.try_lock().map(|guard| self.acquired(guard, holder))
+    }
+}
+
+/// Stage two tasks taking two locks in opposite orders and check the report
+/// on the stuck one names both locks and calls it a deadlock. Tasks aren't
+/// preempted, so the second task's half is recorded rather than run.
+pub fn self_test() -> Result<(), KernelError> {
+    use alloc::string::String;
+
+    serial_println!("LOCK: Running self-test");
+    let first_lock = DiagMutex::new("selftest-a", ());
+    let second_lock = DiagMutex::new("selftest-b", ());
+    let (first, second) = (Holder::Task(1001), Holder::Task(1002));
+
+    let result = (|| {
+        // Each task takes one lock, then wants the other's
+        let _first_holds = first_lock.lock_as(first).ok_or(KernelError::ValidationError("Free lock busy"))?;
+        let second_holds = second_lock.lock_as(second).ok_or(KernelError::ValidationError("Free lock busy"))?;
+        let second_waits = Waiting::record(second, &first_lock.info);
+
+        if second_lock.wait(first, 1000).is_some() || second_lock.holder() != Some(second) {
+            return Err(KernelError::ValidationError("Held lock was taken"));
+        }
+        let mut report = String::new();
+        let _ = second_lock.info.describe(first, &mut report);
+        let named = ["'selftest-a'", "'selftest-b'", "task 1001", "task 1002", "deadlock"]
+            .iter()
+            .all(|part| report.contains(part));
+        if !named {
+            serial_println!("{}", report);
+            return Err(KernelError::ValidationError("Deadlock report is missing a lock or a task"));
+        }
+
+        drop(second_waits);
+        drop(second_holds);
+        if second_lock.holder().is_some() || waiting_for(second).is_some() || second_lock.try_lock().is_none() {
+            return Err(KernelError::ValidationError("Released lock still looks held"));
+        }
+        Ok(())
+    })();
+
+    result?;
+    serial_println!("LOCK: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Error numbers returned (negated) by system calls
+
+use crate::errors::KernelError;
+
+pub const EPERM: i64 = 1;
+pub const ENOENT: i64 = 2;
+pub const EINTR: i64 = 4;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const ENOMEM: i64 = 12;
+pub const EFAULT: i64 = 14;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const ENODEV: i64 = 19;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const ENOSPC: i64 = 28;
+pub const EROFS: i64 = 30;
+pub const EPIPE: i64 = 32;
+pub const ERANGE: i64 = 34;
+pub const ENOSYS: i64 = 38;
+pub const ENOTEMPTY: i64 = 39;
+pub const ETIMEDOUT: i64 = 110;
+
+/// Map a kernel error onto the closest errno value
+pub fn from_kernel_error(error: &KernelError) -> i64 {
+    match error {
+        KernelError::NotFound | KernelError::FilesystemError(crate::errors::FilesystemError::FileNotFound) => ENOENT,
+        KernelError::FilesystemError(crate::errors::FilesystemError::PermissionDenied) => EPERM,
+        KernelError::InvalidHandle => EBADF,
+        KernelError::InvalidParameter | KernelError::ValidationError(_) | KernelError::InvalidData => EINVAL,
+        KernelError::AlreadyExists => EEXIST,
+        KernelError::NotADirectory => ENOTDIR,
+        KernelError::IsADirectory | KernelError::NotAFile => EISDIR,
+        KernelError::DirectoryNotEmpty => ENOTEMPTY,
+        KernelError::DirectoryFull | KernelError::NoSpace => ENOSPC,
+        KernelError::TooManyOpenFiles => EMFILE,
+        KernelError::BufferTooSmall => ERANGE,
+        KernelError::OutOfMemory | KernelError::MemoryError(_) => ENOMEM,
+        KernelError::NotImplemented | KernelError::UnsupportedFeature => ENOSYS,
+        KernelError::DeviceNotFound | KernelError::DeviceNotInitialized => ENODEV,
+        KernelError::DeviceTimeout => ETIMEDOUT,
+        KernelError::InvalidOperation => EPERM,
+        KernelError::BrokenPipe => EPIPE,
+        KernelError::Interrupted => EINTR,
+        KernelError::ReadOnlyFilesystem => EROFS,
+        _ => EIO,
+    }
+}
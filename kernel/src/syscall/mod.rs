@@ -0,0 +1,424 @@
+// kernel/src/syscall/mod.rs
+//! System call interface
+//! User code enters the kernel with `int 0x80`: the call number goes in rax,
+//! arguments in rdi, rsi, rdx, r10 and r8, and the result comes back in rax.
+//! Failures are returned as negative errno values.
+
+pub mod errno;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::errors::KernelError;
+use crate::fs::{self, vfs::NodeType};
+use crate::task::{scheduler, user_mode};
+use crate::{print, serial_print, serial_println};
+
+/// Interrupt vector used for system calls
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+// System call numbers
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_OPEN: u64 = 2;
+pub const SYS_CLOSE: u64 = 3;
+pub const SYS_SEEK: u64 = 4;
+pub const SYS_GETDENTS: u64 = 5;
+pub const SYS_EXIT: u64 = 6;
+pub const SYS_SLEEP_MS: u64 = 7;
+pub const SYS_GETPID: u64 = 8;
+
+// Standard descriptors handled by the console rather than the FD table
+const STDIN: u64 = 0;
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+/// Lower-half (user) addresses end here
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+// getdents record: node type, name length, name bytes
+const DIRENT_FILE: u8 = 1;
+const DIRENT_DIRECTORY: u8 = 2;
+const DIRENT_OTHER: u8 = 0;
+
+lazy_static! {
+    /// Console output captured from system calls, when capturing is on
+    static ref CAPTURE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Registers saved by the entry stub, lowest address first
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// Entry stub: save every general purpose register, hand the frame to Rust,
+// restore (with rax now holding the result) and return to the caller.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call syscall_dispatch",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Address of the assembly entry point, for the IDT gate
+pub fn entry_address() -> VirtAddr {
+    VirtAddr::new(syscall_entry as usize as u64)
+}
+
+type SyscallResult = Result<u64, i64>;
+
+fn to_errno(error: KernelError) -> i64 {
+    errno::from_kernel_error(&error)
+}
+
+/// Called from the entry stub with interrupts disabled
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    crate::interrupts::record_interrupt(SYSCALL_VECTOR);
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8];
+    let result = match dispatch(frame.rax, args, frame.rflags) {
+        Ok(value) => value as i64,
+        Err(code) => -code,
+    };
+    frame.rax = result as u64;
+}
+
+fn dispatch(number: u64, args: [u64; 5], rflags: u64) -> SyscallResult {
+    match number {
+        SYS_READ => sys_read(args[0], args[1], args[2]),
+        SYS_WRITE => sys_write(args[0], args[1], args[2]),
+        SYS_OPEN => sys_open(args[0], args[1], args[2]),
+        SYS_CLOSE => sys_close(args[0]),
+        SYS_SEEK => sys_seek(args[0], args[1]),
+        SYS_GETDENTS => sys_getdents(args[0], args[1], args[2], args[3], args[4]),
+        SYS_EXIT => sys_exit(args[0]),
+        SYS_SLEEP_MS => sys_sleep_ms(args[0], rflags),
+        SYS_GETPID => sys_getpid(),
+        _ => {
+            serial_println!("SYSCALL: Unknown system call {}", number);
+            Err(errno::ENOSYS)
+        }
+    }
+}
+
+/// Check that [ptr, ptr + len) lies in the caller's address range and is mapped
+fn validate_user_range(ptr: u64, len: u64) -> Result<(), i64> {
+    if len == 0 {
+        return Ok(());
+    }
+    if ptr == 0 {
+        return Err(errno::EFAULT);
+    }
+    let end = ptr.checked_add(len).ok_or(errno::EFAULT)?;
+    if end > USER_SPACE_END {
+        return Err(errno::EFAULT);
+    }
+
+    let region = scheduler::current_user_region();
+    if let Some((start, limit)) = region {
+        if ptr < start.as_u64() || end > limit.as_u64() {
+            return Err(errno::EFAULT);
+        }
+    }
+
+    // User tasks may only pass pages they can access themselves
+    let user = region.is_some();
+    let mut page = ptr & !(PAGE_SIZE - 1);
+    while page < end {
+        if crate::memory::translate_active(VirtAddr::new(page), user).is_none() {
+            return Err(errno::EFAULT);
+        }
+        page += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], i64> {
+    validate_user_range(ptr, len)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+fn user_slice_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], i64> {
+    validate_user_range(ptr, len)?;
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+fn user_str<'a>(ptr: u64, len: u64) -> Result<&'a str, i64> {
+    core::str::from_utf8(user_slice(ptr, len)?).map_err(|_| errno::EINVAL)
+}
+
+fn to_fd(fd: u64) -> Result<u32, i64> {
+    u32::try_from(fd).map_err(|_| errno::EBADF)
+}
+
+/// read(fd, buf, len) -> bytes read
+fn sys_read(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let buffer = user_slice_mut(buf, len)?;
+    match fd {
+        // No console input yet: stdin is always at end of file
+        STDIN => Ok(0),
+        STDOUT | STDERR => Err(errno::EBADF),
+        _ => fs::fd::read(to_fd(fd)?, buffer).map(|n| n as u64).map_err(to_errno),
+    }
+}
+
+/// write(fd, buf, len) -> bytes written
+fn sys_write(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let buffer = user_slice(buf, len)?;
+    match fd {
+        STDIN => Err(errno::EBADF),
+        STDOUT | STDERR => {
+            let text = core::str::from_utf8(buffer).map_err(|_| errno::EINVAL)?;
+            serial_print!("{}", text);
+            match CAPTURE.lock().as_mut() {
+                Some(captured) => captured.push_str(text),
+                None => print!("{}", text),
+            }
+            Ok(len)
+        }
+        _ => fs::fd::write(to_fd(fd)?, buffer).map(|n| n as u64).map_err(to_errno),
+    }
+}
+
+/// open(path, path_len, flags) -> fd
+fn sys_open(path: u64, path_len: u64, flags: u64) -> SyscallResult {
+    let path = user_str(path, path_len)?;
+    let flags = u8::try_from(flags).map_err(|_| errno::EINVAL)?;
+    fs::fd::open_tagged("sys_open", path, flags).map(|fd| fd as u64).map_err(to_errno)
+}
+
+/// close(fd)
+fn sys_close(fd: u64) -> SyscallResult {
+    if fd <= STDERR {
+        return Err(errno::EBADF);
+    }
+    fs::fd::close(to_fd(fd)?).map(|_| 0).map_err(to_errno)
+}
+
+/// seek(fd, position) -> new position
+fn sys_seek(fd: u64, position: u64) -> SyscallResult {
+    if fd <= STDERR {
+        return Err(errno::EBADF);
+    }
+    let fd = to_fd(fd)?;
+    fs::fd::seek(fd, position).map_err(to_errno)?;
+    fs::fd::tell(fd).map_err(to_errno)
+}
+
+/// getdents(path, path_len, buf, len, start) -> bytes written
+///
+/// Fills `buf` with records of (type: u8, name_len: u8, name) for the
+/// directory entries from index `start` onwards that fit.
+fn sys_getdents(path: u64, path_len: u64, buf: u64, len: u64, start: u64) -> SyscallResult {
+    let path = user_str(path, path_len)?;
+    let buffer = user_slice_mut(buf, len)?;
+    let vfs = fs::vfs::get_vfs_manager().ok_or(errno::EIO)?;
+    let entries = vfs.read_dir(path).map_err(to_errno)?;
+
+    let mut written = 0;
+    for entry in entries.iter().skip(start as usize) {
+        let name = entry.name.as_bytes();
+        let name_len = name.len().min(u8::MAX as usize);
+        let record_len = 2 + name_len;
+        if written + record_len > buffer.len() {
+            if written == 0 {
+                return Err(errno::ERANGE);
+            }
+            break;
+        }
+
+        buffer[written] = match entry.node_type {
+            NodeType::File => DIRENT_FILE,
+            NodeType::Directory => DIRENT_DIRECTORY,
+            _ => DIRENT_OTHER,
+        };
+        buffer[written + 1] = name_len as u8;
+        buffer[written + 2..written + record_len].copy_from_slice(&name[..name_len]);
+        written += record_len;
+    }
+
+    Ok(written as u64)
+}
+
+/// exit(code)
+fn sys_exit(code: u64) -> SyscallResult {
+    // Before the scheduler runs, the caller is the kernel itself
+    let id = scheduler::current_task_id().unwrap_or(0);
+    if id == 0 {
+        // The kernel task can't be terminated from under the kernel
+        return Err(errno::EPERM);
+    }
+    serial_println!("SYSCALL: Task {} exited with code {}", id, code as i64);
+    if user_mode::is_active() {
+        user_mode::exit_current(code as i64);
+    }
+    crate::task::exit(code as i64).map(|_| 0).map_err(to_errno)
+}
+
+/// sleep_ms(ms)
+fn sys_sleep_ms(ms: u64, rflags: u64) -> SyscallResult {
+    const RFLAGS_IF: u64 = 1 << 9;
+    let ms = u32::try_from(ms).map_err(|_| errno::EINVAL)?;
+
+    // Only wait on the timer if the caller had interrupts on; otherwise spin
+    if rflags & RFLAGS_IF != 0 {
+        x86_64::instructions::interrupts::enable();
+        crate::drivers::pit::sleep_ms(ms);
+        x86_64::instructions::interrupts::disable();
+    } else {
+        crate::drivers::pit::busy_sleep_us(ms as u64 * 1000);
+    }
+    Ok(0)
+}
+
+/// getpid() -> task id
+fn sys_getpid() -> SyscallResult {
+    Ok(scheduler::current_task_id().unwrap_or(0))
+}
+
+/// Collect console writes made through system calls instead of printing them,
+/// so callers like the shell can display a program's output themselves
+pub fn start_capture() {
+    *CAPTURE.lock() = Some(String::new());
+}
+
+/// Stop capturing and return everything written since `start_capture`
+pub fn take_capture() -> String {
+    CAPTURE.lock().take().unwrap_or_default()
+}
+
+/// Issue a system call from kernel code (used by the self-test)
+pub unsafe fn invoke(number: u64, args: [u64; 5]) -> i64 {
+    let result: i64;
+    core::arch::asm!(
+        "int 0x80",
+        inlateout("rax") number as i64 => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+    );
+    result
+}
+
+/// Exercise the syscall path from ring 0, checking argument passing and errno mapping
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SYSCALL: Running self-test");
+
+    let check = |name: &'static str, got: i64, ok: bool| -> Result<(), KernelError> {
+        if ok {
+            serial_println!("SYSCALL:   {} -> {} ok", name, got);
+            Ok(())
+        } else {
+            serial_println!("SYSCALL:   {} -> {} FAILED", name, got);
+            Err(KernelError::ValidationError(name))
+        }
+    };
+
+    unsafe {
+        let message = b"syscall: hello from int 0x80\n";
+        let ret = invoke(SYS_WRITE, [STDOUT, message.as_ptr() as u64, message.len() as u64, 0, 0]);
+        check("write(stdout)", ret, ret == message.len() as i64)?;
+
+        let ret = invoke(SYS_GETPID, [0; 5]);
+        check("getpid", ret, ret as u64 == scheduler::current_task_id().unwrap_or(0))?;
+
+        let ret = invoke(SYS_CLOSE, [9999, 0, 0, 0, 0]);
+        check("close(bad fd)", ret, ret == -errno::EBADF)?;
+
+        let ret = invoke(SYS_WRITE, [STDOUT, 0xFFFF_8000_0000_0000, 16, 0, 0]);
+        check("write(kernel pointer)", ret, ret == -errno::EFAULT)?;
+
+        let ret = invoke(SYS_WRITE, [STDOUT, 0, 16, 0, 0]);
+        check("write(null)", ret, ret == -errno::EFAULT)?;
+
+        let ret = invoke(0xFFFF, [0; 5]);
+        check("unknown syscall", ret, ret == -errno::ENOSYS)?;
+
+        let missing = "/no/such/file";
+        let ret = invoke(SYS_OPEN, [missing.as_ptr() as u64, missing.len() as u64, fs::vfs::file_flags::READ as u64, 0, 0]);
+        check("open(missing)", ret, ret < 0)?;
+
+        let root = "/";
+        let mut dirents: Vec<u8> = alloc::vec![0; 256];
+        let ret = invoke(SYS_GETDENTS, [root.as_ptr() as u64, root.len() as u64,
+            dirents.as_mut_ptr() as u64, dirents.len() as u64, 0]);
+        check("getdents(/)", ret, ret >= 0)?;
+
+        let ret = invoke(SYS_EXIT, [0; 5]);
+        check("exit(kernel task)", ret, ret == -errno::EPERM)?;
+    }
+
+    serial_println!("SYSCALL: Self-test passed");
+    Ok(())
+}
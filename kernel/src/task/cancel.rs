@@ -0,0 +1,41 @@
+//! Cooperative cancellation
+//!
+//! A long-running job holds a `CancellationToken` and checks it between
+//! units of work (a directory entry, a block copied, a packet sent). Whoever
+//! started the job keeps a clone and calls `cancel`; the job notices at its
+//! next check, cleans up whatever it left half done, and returns
+//! `KernelError::Interrupted`.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::errors::KernelError;
+
+/// Shared flag asking a job to stop; clones share the flag
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the job to stop at its next check
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Err(Interrupted) once cancelled, for `?` at a loop boundary
+    pub fn check(&self) -> Result<(), KernelError> {
+        if self.is_cancelled() {
+            Err(KernelError::Interrupted)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// kernel/src/task/deferred.rs
+//! Deferred work: interrupt handlers raise a work item, and the main loop
+//! runs it later outside interrupt context.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use lazy_static::lazy_static;
+use crate::errors::KernelError;
+
+/// Maximum number of registered work items
+const MAX_WORK_ITEMS: usize = 32;
+
+/// Identifies a registered work item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkId(u8);
+
+/// Work function run outside interrupt context
+pub type WorkFn = fn();
+
+/// One bit per work item that has been raised but not yet run
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    static ref WORK_ITEMS: Mutex<[Option<WorkFn>; MAX_WORK_ITEMS]> = Mutex::new([None; MAX_WORK_ITEMS]);
+}
+
+/// Register a work function, returning the id used to raise it
+pub fn register(work: WorkFn) -> Result<WorkId, KernelError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut items = WORK_ITEMS.lock();
+        for (i, slot) in items.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(work);
+                return Ok(WorkId(i as u8));
+            }
+        }
+        Err(KernelError::GenericError("No free deferred work slots"))
+    })
+}
+
+/// Mark a work item as pending; safe to call from interrupt handlers
+pub fn raise(id: WorkId) {
+    PENDING.fetch_or(1 << id.0, Ordering::SeqCst);
+}
+
+/// Check whether any work is pending
+pub fn has_pending() -> bool {
+    PENDING.load(Ordering::SeqCst) != 0
+}
+
+/// Run all pending work items; call from the main loop, never from an interrupt
+pub fn run_pending() {
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    if pending == 0 {
+        return;
+    }
+
+    // Copy the functions out so work items may register or raise others
+    let items = x86_64::instructions::interrupts::without_interrupts(|| *WORK_ITEMS.lock());
+    for (i, item) in items.iter().enumerate() {
+        if pending & (1 << i) != 0 {
+            if let Some(work) = item {
+                work();
+            }
+        }
+    }
+}
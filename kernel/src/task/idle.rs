@@ -0,0 +1,218 @@
+// kernel/src/task/idle.rs
+//! The idle path: what the kernel does when nothing is ready to run.
+//! It halts until the next interrupt and keeps the books on idle time, so
+//! the timer can tell idle ticks from busy ones and report a load figure.
+//! Every `idle.heartbeat_secs` it logs one health line; `idle.verbosity`
+//! ("quiet", "normal" or "verbose") decides how much it says.
+
+use alloc::format;
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use crate::drivers::pit;
+use crate::errors::KernelError;
+use crate::serial_println;
+use super::TaskState;
+
+/// Seconds between health lines when the configuration doesn't say
+const DEFAULT_HEARTBEAT_SECS: u64 = 60;
+
+/// Set while halted in `idle_once`, so the timer can tell idle ticks apart
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+/// Ticks that arrived while halted, since boot
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Ticks counted in the current load window, and how many of them were idle
+static WINDOW_TICKS: AtomicU64 = AtomicU64::new(0);
+static WINDOW_IDLE: AtomicU64 = AtomicU64::new(0);
+
+/// Busy share of the last complete window, in percent
+static LOAD_PERCENT: AtomicU8 = AtomicU8::new(0);
+
+// Settings, cached so the idle path doesn't take the configuration lock
+static HEARTBEAT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_HEARTBEAT_SECS);
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+static LAST_HEARTBEAT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// How much the idle path logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Verbosity {
+    /// No health lines
+    Quiet = 0,
+    Normal = 1,
+    /// Health lines with idle time and interrupt totals
+    Verbose = 2,
+}
+
+impl Verbosity {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "quiet" => Some(Verbosity::Quiet),
+            "normal" => Some(Verbosity::Normal),
+            "verbose" => Some(Verbosity::Verbose),
+            _ => None,
+        }
+    }
+
+    fn current() -> Self {
+        match VERBOSITY.load(Ordering::Relaxed) {
+            0 => Verbosity::Quiet,
+            2 => Verbosity::Verbose,
+            _ => Verbosity::Normal,
+        }
+    }
+}
+
+/// Read the heartbeat settings, and read them again whenever they change
+pub fn init() {
+    reload("idle.");
+    crate::config::subscribe("idle.", reload);
+}
+
+fn reload(_key: &str) {
+    let secs = crate::config::get("idle.heartbeat_secs")
+        .and_then(|value| value.try_as_integer())
+        .filter(|secs| *secs >= 0)
+        .map_or(DEFAULT_HEARTBEAT_SECS, |secs| secs as u64);
+    let verbosity = crate::config::get("idle.verbosity")
+        .and_then(|value| value.try_as_string().and_then(|name| Verbosity::parse(name)))
+        .unwrap_or(Verbosity::Normal);
+    HEARTBEAT_SECS.store(secs, Ordering::Relaxed);
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Busy share of `total` ticks of which `idle` were idle, in percent
+fn load_of(idle: u64, total: u64) -> u8 {
+    if total == 0 {
+        return 0;
+    }
+    ((total - idle.min(total)) * 100 / total) as u8
+}
+
+/// Count a timer tick. Called from the timer interrupt.
+pub fn account_tick() {
+    let idle = IDLE.load(Ordering::Relaxed);
+    if idle {
+        IDLE_TICKS.fetch_add(1, Ordering::Relaxed);
+        WINDOW_IDLE.fetch_add(1, Ordering::Relaxed);
+    }
+    // One window is a second of ticks
+    let window = u64::from(pit::frequency()).max(1);
+    if WINDOW_TICKS.fetch_add(1, Ordering::Relaxed) + 1 >= window {
+        let idle = WINDOW_IDLE.swap(0, Ordering::Relaxed);
+        let total = WINDOW_TICKS.swap(0, Ordering::Relaxed);
+        LOAD_PERCENT.store(load_of(idle, total), Ordering::Relaxed);
+    }
+}
+
+/// Percentage of the ticks in the last second that were not idle
+pub fn load_percent() -> u8 {
+    LOAD_PERCENT.load(Ordering::Relaxed)
+}
+
+/// Ticks spent halted since boot
+pub fn idle_ticks() -> u64 {
+    IDLE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Halt until the next interrupt, counting the wait as idle time, then do
+/// the periodic chores: resync the clock, write back block caches and log
+/// a health line if due.
+/// Call with interrupts enabled.
+pub fn idle_once() {
+    IDLE.store(true, Ordering::Relaxed);
+    x86_64::instructions::hlt();
+    IDLE.store(false, Ordering::Relaxed);
+    crate::time::poll();
+    crate::fs::block_adapter::idle_flush();
+    heartbeat();
+}
+
+fn heartbeat() {
+    let secs = HEARTBEAT_SECS.load(Ordering::Relaxed);
+    let verbosity = Verbosity::current();
+    if secs == 0 || verbosity == Verbosity::Quiet {
+        return;
+    }
+    let now = pit::uptime_ms();
+    let last = LAST_HEARTBEAT_MS.load(Ordering::Relaxed);
+    if now.saturating_sub(last) < secs * 1000 {
+        return;
+    }
+    LAST_HEARTBEAT_MS.store(now, Ordering::Relaxed);
+    serial_println!("{}", health_line(verbosity));
+}
+
+/// Task counts: (ready to run, blocked, total)
+fn task_counts() -> (usize, usize, usize) {
+    let tasks = super::scheduler::task_list();
+    let ready = tasks.iter().filter(|task| matches!(task.state, TaskState::Runnable | TaskState::Running)).count();
+    let blocked = tasks.iter().filter(|task| task.state == TaskState::Blocked).count();
+    (ready, blocked, tasks.len())
+}
+
+/// One-line summary of the system's health
+pub fn health_line(verbosity: Verbosity) -> String {
+    let secs = pit::uptime_ms() / 1000;
+    let (ready, blocked, _) = task_counts();
+    let heap = crate::allocator::heap_stats();
+    let dropped = crate::drivers::ps2_keyboard::dropped_events() + crate::drivers::ps2_mouse::dropped_events();
+    let mut line = format!(
+        "health: up {:02}:{:02}:{:02} load {}% tasks {} ready {} sleeping heap {}/{} KiB dropped input {}",
+        secs / 3600, (secs / 60) % 60, secs % 60, load_percent(), ready, blocked,
+        heap.used / 1024, heap.size / 1024, dropped);
+    if verbosity == Verbosity::Verbose {
+        let interrupts: u64 = crate::interrupts::interrupt_counts().iter().map(|(_, count)| count).sum();
+        line.push_str(&format!(" idle ticks {} of {} interrupts {}", idle_ticks(), pit::ticks(), interrupts));
+    }
+    line
+}
+
+/// Load in the style of /proc/loadavg: "<load%> <ready>/<total> <idle
+/// ticks> <ticks>", for a future /proc/loadavg
+pub fn loadavg_text() -> String {
+    let (ready, _, total) = task_counts();
+    format!("{}% {}/{} {} {}\n", load_percent(), ready, total, idle_ticks(), pit::ticks())
+}
+
+/// The kernel's main loop once initialization is over: run deferred work
+/// and network polling, then idle until the next interrupt
+pub fn run() -> ! {
+    serial_println!("DEBUG: Entering idle loop");
+    loop {
+        crate::net::poll();
+        crate::drivers::rtc::poll();
+        super::deferred::run_pending();
+        idle_once();
+    }
+}
+
+/// Check the load arithmetic and the health line's fields
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("IDLE: Running self-test");
+
+    if load_of(0, 100) != 100 || load_of(100, 100) != 0 || load_of(25, 100) != 75
+        || load_of(0, 0) != 0 || load_of(7, 5) != 0 {
+        return Err(KernelError::ValidationError("Load percentage is computed wrongly"));
+    }
+    if Verbosity::parse("verbose") != Some(Verbosity::Verbose) || Verbosity::parse("loud").is_some() {
+        return Err(KernelError::ValidationError("Verbosity names don't parse"));
+    }
+    if load_percent() > 100 {
+        return Err(KernelError::ValidationError("Load is over 100%"));
+    }
+
+    let normal = health_line(Verbosity::Normal);
+    let verbose = health_line(Verbosity::Verbose);
+    if !normal.contains("load ") || !normal.contains("heap ") || !normal.contains("dropped input ")
+        || normal.contains("idle ticks ") || !verbose.contains("idle ticks ") {
+        return Err(KernelError::ValidationError("Health line is missing fields"));
+    }
+    if loadavg_text().split_whitespace().count() != 4 {
+        return Err(KernelError::ValidationError("loadavg text has the wrong number of fields"));
+    }
+
+    serial_println!("IDLE: Self-test passed");
+    Ok(())
+}
@@ -1,13 +1,21 @@
 pub mod task;
 pub mod scheduler;
+pub mod cancel; // Asking a long-running job to stop
 pub mod task_structs; // For Task, TaskContext, TaskState, etc.
 pub mod context_switch; // Add context switching module
+pub mod deferred; // Work raised by interrupt handlers, run from the main loop
+pub mod idle; // Halting when there's nothing to do, and the load figure
+pub mod trace; // Recent task switches and their reasons, for debugging
+pub mod user_mode; // Ring 3 programs in their own address space
+pub mod wait_queue; // Blocking until another task signals
+pub mod watchdog; // Reports main loops that stop running
 // Potentially later: pub mod context_switch; (for asm routines)
 
 // Re-export key structures for convenience
 pub use task_structs::{Task, TaskState, TaskContext};
 // pub use scheduler::Scheduler; // This doesn't exist, so remove it
 pub use context_switch::{save_context, restore_context, switch_context};
+pub use scheduler::{exit, wait};
 
 // Re-export key structures if needed later
 // pub use task::Task;
@@ -1,10 +1,17 @@
 // kernel/src/task/scheduler.rs
 use crate::{serial_println, println};
+use crate::errors::{FilesystemError, KernelError, TaskError};
+use super::deferred::{self, WorkId};
+use super::trace::{self, Reason};
 use super::task_structs::{Task, TaskState};
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use alloc::boxed::Box;
+use core::fmt::Write;
 
 // Define the TaskId type
 pub type TaskId = u64;
@@ -14,6 +21,24 @@ lazy_static! {
     static ref TASK_QUEUE: Mutex<VecDeque<Box<Task>>> = Mutex::new(VecDeque::new());
     // Currently running task
     static ref CURRENT_TASK: Mutex<Option<Box<Task>>> = Mutex::new(None);
+    // Exited tasks whose stacks and files have not been released yet
+    static ref EXITED: Mutex<Vec<Box<Task>>> = Mutex::new(Vec::new());
+    // Exit codes of reaped tasks that nobody has waited for yet
+    static ref ZOMBIES: Mutex<BTreeMap<TaskId, i64>> = Mutex::new(BTreeMap::new());
+    // Deferred work item that runs the reaper
+    static ref REAPER_WORK: Mutex<Option<WorkId>> = Mutex::new(None);
+}
+
+/// Summary of a task for listings such as `ps`
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub state: TaskState,
+    pub exit_code: Option<i64>,
+    pub user: bool,
+    pub cpu_ticks: u64,
+    /// Directory the task is jailed in; None once it has been reaped
+    pub root: Option<String>,
 }
 
 // Example task functions for testing
@@ -55,6 +80,12 @@ pub fn init() {
         }
     }
     
+    // Exited tasks are cleaned up from the main loop, off their own stacks
+    match deferred::register(reap) {
+        Ok(work) => *REAPER_WORK.lock() = Some(work),
+        Err(e) => serial_println!("Scheduler: Failed to register reaper: {:?}", e),
+    }
+    
     // During boot we'll avoid creating additional tasks yet
     // This simplifies the initialization process
     serial_println!("Scheduler: Skipping example task creation during initial boot");
@@ -91,9 +122,12 @@ pub fn init() {
 }
 
 /// Spawns a new task with the given entry point function.
+/// The task starts with the spawner's root. It is only queued: `schedule`
+/// doesn't switch tasks yet, so a spawned task never runs.
 pub fn spawn(entry: fn()) -> Result<TaskId, &'static str> {
     match Task::new(entry) {
-        Ok(task) => {
+        Ok(mut task) => {
+            task.set_root(current_root());
             let id = task.id();
             TASK_QUEUE.lock().push_back(Box::new(task));
             Ok(id)
@@ -102,19 +136,236 @@ pub fn spawn(entry: fn()) -> Result<TaskId, &'static str> {
     }
 }
 
-/// Terminates the currently running task.
+/// Terminates the currently running task with exit code 0.
 pub fn terminate_current() {
-    // Set the current task state to terminated
-    if let Some(ref mut task) = *CURRENT_TASK.lock() {
-        task.set_state(TaskState::Terminated);
+    if let Err(e) = exit(0) {
+        serial_println!("Scheduler: terminate_current failed: {:?}", e);
+    }
+}
+
+/// First code run by a spawned task: calls its entry function and exits the
+/// task if the function returns.
+pub(super) extern "C" fn task_trampoline() -> ! {
+    let entry = CURRENT_TASK.lock().as_ref().map(|task| task.entry_point());
+    if let Some(entry) = entry {
+        entry();
     }
     
-    // Then force a reschedule, which will not put this task back in the queue
+    terminate_current();
+    // A zombie is never scheduled again
+    crate::hlt_loop();
+}
+
+/// Ends the current task, keeping `code` for a later `wait`.
+/// Exiting twice, or exiting the kernel task, is an error. `schedule`
+/// doesn't switch tasks yet, so this returns to the exited task, and the
+/// caller must stop running it.
+pub fn exit(code: i64) -> Result<(), KernelError> {
+    let id = {
+        let mut current = CURRENT_TASK.lock();
+        let task = current.as_mut().ok_or(KernelError::NotInitialized)?;
+        if task.id() == 0 {
+            return Err(KernelError::InvalidOperation);
+        }
+        if task.state() == TaskState::Zombie {
+            return Err(KernelError::TaskError(TaskError::InvalidTaskState));
+        }
+        task.set_exit_code(code);
+        task.set_state(TaskState::Zombie);
+        task.id()
+    };
+    trace::record(id, trace::NO_TASK, Reason::Exit);
+    
+    serial_println!("Scheduler: Task {} exited with code {}", id, code);
+    if let Some(work) = *REAPER_WORK.lock() {
+        deferred::raise(work);
+    }
     schedule();
+    Ok(())
+}
+
+/// Frees the stacks and open files of exited tasks, keeping only their exit codes.
+/// Runs as deferred work, so never on the stack of a task being freed.
+fn reap() {
+    // Move zombies out of the run queue first
+    let mut exited: Vec<Box<Task>> = core::mem::take(&mut *EXITED.lock());
+    {
+        let mut queue = TASK_QUEUE.lock();
+        let mut index = 0;
+        while index < queue.len() {
+            if queue[index].state() == TaskState::Zombie {
+                if let Some(task) = queue.remove(index) {
+                    exited.push(task);
+                }
+            } else {
+                index += 1;
+            }
+        }
+    }
+    
+    for task in exited {
+        let id = task.id();
+        let closed = crate::fs::fd::close_all_owned_by(id);
+        ZOMBIES.lock().insert(id, task.exit_code().unwrap_or(0));
+        serial_println!("Scheduler: Reaped task {} ({} files closed)", id, closed);
+        // Dropping the task frees its kernel stack
+        drop(task);
+    }
+}
+
+/// Whether `id` names a task that has not exited yet
+fn is_alive(id: TaskId) -> bool {
+    let is_live = |task: &Task| task.id() == id && task.state() != TaskState::Zombie;
+    CURRENT_TASK.lock().as_deref().map_or(false, is_live)
+        || TASK_QUEUE.lock().iter().any(|task| is_live(task))
+}
+
+/// Whether `id` has exited but hasn't been waited for
+fn is_zombie(id: TaskId) -> bool {
+    ZOMBIES.lock().contains_key(&id)
+        || EXITED.lock().iter().any(|task| task.id() == id)
+        || TASK_QUEUE.lock().iter().any(|task| task.id() == id && task.state() == TaskState::Zombie)
+        || CURRENT_TASK.lock().as_ref().map_or(false, |task| task.id() == id && task.state() == TaskState::Zombie)
+}
+
+/// Collects and returns the exit code of task `id`, which must have exited.
+/// Waiting for yourself or for a task that doesn't exist is an error. So is
+/// waiting for a queued task: nothing switches to it yet, so it would
+/// never exit.
+pub fn wait(id: TaskId) -> Result<i64, KernelError> {
+    if current_task_id() == Some(id) {
+        return Err(KernelError::InvalidOperation);
+    }
+    if is_alive(id) {
+        return Err(KernelError::TaskError(TaskError::DeadlockDetected));
+    }
+    if !is_zombie(id) {
+        return Err(KernelError::NotFound);
+    }
+    
+    // We run on our own stack, so the exited task can be reaped right away
+    reap();
+    ZOMBIES.lock().remove(&id).ok_or(KernelError::NotFound)
+}
+
+/// Runs `body` as a new kernel task and returns the task's ID and the exit
+/// code `body` returned, once the task is reaped. The task runs on the
+/// caller's stack in place of the caller, which gets the CPU back when
+/// `body` returns; files opened meanwhile belong to the task and are closed
+/// when it is reaped. This is how the shell runs commands until `schedule`
+/// can switch tasks.
+pub fn run_as_task(body: &mut dyn FnMut() -> i64) -> Result<(TaskId, i64), KernelError> {
+    run_as_task_in(current_root(), body)
+}
+
+/// `run_as_task` with the task jailed in `root`, a canonical path, from
+/// before it starts; `run_as_task` gives it the caller's root
+pub fn run_as_task_in(root: String, body: &mut dyn FnMut() -> i64) -> Result<(TaskId, i64), KernelError> {
+    let mut task = Task::inline();
+    task.set_root(root);
+    let id = task.id();
+    let previous = replace_current(Some(Box::new(task)), Reason::Start);
+    let code = body();
+    if let Some(mut task) = replace_current(previous, Reason::Exit) {
+        task.set_exit_code(code);
+        task.set_state(TaskState::Zombie);
+        EXITED.lock().push(task);
+    }
+    Ok((id, wait(id)?))
+}
+
+/// Marks the current task as blocked; it won't run again until `unblock`
+pub fn block_current() {
+    if let Some(ref mut task) = *CURRENT_TASK.lock() {
+        if task.state() == TaskState::Running {
+            task.set_state(TaskState::Blocked);
+            trace::record(task.id(), trace::NO_TASK, Reason::Block);
+        }
+    }
+}
+
+/// Makes a blocked task runnable again
+pub fn unblock(id: TaskId) {
+    let waker = {
+        let mut current = CURRENT_TASK.lock();
+        if let Some(ref mut task) = *current {
+            if task.id() == id && task.state() == TaskState::Blocked {
+                task.set_state(TaskState::Running);
+                trace::record(trace::NO_TASK, id, Reason::Wake);
+                return;
+            }
+        }
+        current.as_ref().map_or(trace::NO_TASK, |task| task.id())
+    };
+    
+    if let Some(task) = TASK_QUEUE.lock().iter_mut().find(|task| task.id() == id) {
+        if task.state() == TaskState::Blocked {
+            task.set_state(TaskState::Runnable);
+            trace::record(waker, id, Reason::Wake);
+        }
+    }
+}
+
+/// Lists every task the scheduler knows about, including unreaped zombies
+pub fn task_list() -> Vec<TaskInfo> {
+    let info = |task: &Task| TaskInfo {
+        id: task.id(),
+        state: task.state(),
+        exit_code: task.exit_code(),
+        user: task.page_table().is_some(),
+        cpu_ticks: task.cpu_ticks(),
+        root: Some(task.root().to_string()),
+    };
+    
+    let mut tasks: Vec<TaskInfo> = Vec::new();
+    if let Some(task) = CURRENT_TASK.lock().as_deref() {
+        tasks.push(info(task));
+    }
+    tasks.extend(TASK_QUEUE.lock().iter().map(|task| info(task)));
+    tasks.extend(EXITED.lock().iter().map(|task| info(task)));
+    tasks.extend(ZOMBIES.lock().iter().map(|(&id, &code)| TaskInfo {
+        id,
+        state: TaskState::Zombie,
+        exit_code: Some(code),
+        user: false,
+        cpu_ticks: 0,
+        root: None,
+    }));
+    tasks.sort_by_key(|task| task.id);
+    tasks
+}
+
+/// /proc/tasks: one line per task, `id state ticks root`, with "-" as
+/// the root of a reaped task
+pub fn tasks_text() -> String {
+    let mut text = String::new();
+    for task in task_list() {
+        text.push_str(&format!("{:>3} {:<10} {:>8} {}\n", task.id, format!("{:?}", task.state).to_lowercase(),
+            task.cpu_ticks, task.root.as_deref().unwrap_or("-")));
+    }
+    text
+}
+
+/// Write one line per task to `out` without waiting for any lock, for
+/// reports made from interrupt handlers. Returns false, having written
+/// nothing, if a task list is locked.
+pub fn try_dump_tasks(out: &mut dyn core::fmt::Write) -> bool {
+    let (current, queue, exited) = match (CURRENT_TASK.try_lock(), TASK_QUEUE.try_lock(), EXITED.try_lock()) {
+        (Some(current), Some(queue), Some(exited)) => (current, queue, exited),
+        _ => return false,
+    };
+    let tasks = current.as_deref().into_iter()
+        .chain(queue.iter().map(|task| &**task))
+        .chain(exited.iter().map(|task| &**task));
+    for task in tasks {
+        let _ = writeln!(out, "  task {:>3}  {:?}  {} ticks", task.id(), task.state(), task.cpu_ticks());
+    }
+    true
 }
 
 /// Switches to the next ready task.
-/// This is the heart of the preemptive scheduler.
+/// This is the heart of the preemptive scheduler. The switch itself is
+/// still disabled, so for now this always returns to the current task.
 pub fn schedule() {
     // First check if scheduling is already in progress to prevent reentrancy issues
     static SCHEDULE_IN_PROGRESS: Mutex<bool> = Mutex::new(false);
@@ -129,11 +380,17 @@ pub fn schedule() {
     // Set the in_progress flag - fixed by directly using the unwrapped MutexGuard
     *in_progress_guard.unwrap() = true;
     
+    // Blocking and exiting were traced already, with their reasons. No
+    // other task gets the CPU yet, so the yielding task is also the next.
+    if let Some(task) = CURRENT_TASK.lock().as_ref().filter(|task| task.state() == TaskState::Running) {
+        trace::record(task.id(), task.id(), Reason::Yield);
+    }
+    
     // Special case: during early boot, just return without switching tasks
     // This is safer until the system is fully initialized
     if TASK_QUEUE.lock().is_empty() {
         if let Some(ref mut current) = *CURRENT_TASK.lock() {
-            if current.state() == TaskState::Terminated {
+            if matches!(current.state(), TaskState::Terminated | TaskState::Zombie) {
                 serial_println!("Scheduler: No tasks to run, but won't halt during boot");
                 // Don't halt during boot - just return to kernel instead
                 return;
@@ -162,6 +419,7 @@ pub fn schedule() {
     
     // Mark the next task as running
     next_task.set_state(TaskState::Running);
+    load_kernel_stack(&next_task);
     
     // Then perform the context switch
     {
@@ -216,7 +474,74 @@ pub fn schedule() {
     */
 }
 
+/// Charge a timer tick to the running task. Called from the timer
+/// interrupt, so it skips the tick rather than wait for the lock.
+pub fn account_tick() {
+    super::idle::account_tick();
+    if let Some(mut current) = CURRENT_TASK.try_lock() {
+        if let Some(task) = current.as_mut() {
+            task.add_cpu_tick();
+        }
+    }
+}
+
+/// ID of the running task without waiting for the lock; None if there is
+/// no task yet or the lock is held
+pub fn try_current_task_id() -> Option<TaskId> {
+    CURRENT_TASK.try_lock()?.as_ref().map(|task| task.id())
+}
+
 /// Gets the ID of the currently running task, if any.
 pub fn current_task_id() -> Option<TaskId> {
     CURRENT_TASK.lock().as_ref().map(|task| task.id())
-} 
\ No newline at end of file
+}
+
+/// Timer ticks charged to the running task so far
+pub fn current_cpu_ticks() -> Option<u64> {
+    CURRENT_TASK.lock().as_ref().map(|task| task.cpu_ticks())
+}
+
+/// Directory the running task is jailed in; "/" before there are tasks
+pub fn current_root() -> String {
+    CURRENT_TASK.lock().as_ref().map_or_else(|| String::from("/"), |task| task.root().to_string())
+}
+
+/// Move the running task's root to `root`, a canonical path. Once a task
+/// runs only uid 0 may do this; others get their jail from whoever
+/// starts them (see `run_as_task_in`).
+pub fn set_current_root(root: &str) -> Result<(), KernelError> {
+    if crate::user::current_credentials().0 != 0 {
+        return Err(KernelError::FilesystemError(FilesystemError::PermissionDenied));
+    }
+    let mut current = CURRENT_TASK.lock();
+    let task = current.as_mut().ok_or(KernelError::NotInitialized)?;
+    task.set_root(root.to_string());
+    Ok(())
+}
+
+/// Gets the syscall address range of the currently running task, if restricted.
+pub fn current_user_region() -> Option<(x86_64::VirtAddr, x86_64::VirtAddr)> {
+    CURRENT_TASK.lock().as_ref().and_then(|task| task.user_region())
+}
+
+/// Point TSS RSP0 at `task`'s kernel stack if it runs in ring 3, so
+/// interrupts and system calls from it land there. Call before switching to it.
+fn load_kernel_stack(task: &Task) {
+    if task.user_region().is_some() {
+        crate::gdt::set_kernel_stack(task.kernel_stack_top());
+    }
+}
+
+/// Installs `task` as the running task, returning the one it replaced,
+/// and traces the switch as happening for `reason`. Used to run user
+/// programs synchronously on behalf of the kernel task.
+pub fn replace_current(task: Option<Box<Task>>, reason: Reason) -> Option<Box<Task>> {
+    if let Some(task) = &task {
+        load_kernel_stack(task);
+    }
+    let id = |task: &Option<Box<Task>>| task.as_ref().map_or(trace::NO_TASK, |task| task.id());
+    let to = id(&task);
+    let previous = core::mem::replace(&mut *CURRENT_TASK.lock(), task);
+    trace::record(id(&previous), to, reason);
+    previous
+}
@@ -1,7 +1,9 @@
 use core::sync::atomic::{AtomicU64, Ordering};
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
 use x86_64::VirtAddr;
+use x86_64::structures::paging::PhysFrame;
 
 /// Represents the state of a task.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -10,6 +12,7 @@ pub enum TaskState {
     Running,   // Currently executing
     Blocked,   // Waiting for an event (e.g., I/O, semaphore)
     Terminated, // Task has finished execution
+    Zombie,    // Exited; exit code kept until another task waits for it
 }
 
 /// Represents the CPU context of a task.
@@ -77,6 +80,16 @@ pub struct Task {
     kernel_stack: Box<[u8]>, // Each task has its own kernel stack
     // The actual entry function for the task
     entry_point: fn(),
+    // Addresses the task may pass to system calls; None allows the whole lower half
+    user_region: Option<(VirtAddr, VirtAddr)>,
+    // Level 4 page table for user tasks; kernel tasks use the kernel's
+    page_table: Option<PhysFrame>,
+    // Set by exit; read back by wait
+    exit_code: Option<i64>,
+    // Timer ticks that arrived while this task was running
+    cpu_ticks: u64,
+    // Directory the task's paths are resolved under; "/" unless it's jailed
+    root: String,
 }
 
 // For generating unique task IDs
@@ -105,6 +118,11 @@ impl Task {
             context,
             kernel_stack,
             entry_point: || {}, // Dummy fn pointer, never used
+            user_region: None,
+            page_table: None,
+            exit_code: None,
+            cpu_ticks: 0,
+            root: String::from("/"),
         })
     }
 
@@ -127,10 +145,11 @@ impl Task {
         // The `rsp` should point to the highest address of the allocated stack memory.
         let stack_top_addr = VirtAddr::from_ptr(kernel_stack.as_ptr()) + kernel_stack.len();
         
-        // The initial instruction pointer will point to a wrapper that calls the entry function.
-        // For now, let's point directly to the entry function for simplicity.
-        // Later, we might introduce `task_entry_wrapper(entry_fn: fn())`
-        let entry_point_addr = VirtAddr::new(entry as u64);
+        // Tasks start in the trampoline, which calls the entry function and
+        // exits the task if it returns. The context is entered with `ret`, so
+        // leave room for a return address to keep the ABI's stack alignment.
+        let entry_point_addr = VirtAddr::new(super::scheduler::task_trampoline as usize as u64);
+        let stack_top_addr = stack_top_addr.align_down(16u64) - 8u64;
 
         Ok(Task {
             id,
@@ -138,6 +157,60 @@ impl Task {
             context: TaskContext::new(entry_point_addr, stack_top_addr),
             kernel_stack,
             entry_point: entry,
+            user_region: None,
+            page_table: None,
+            exit_code: None,
+            cpu_ticks: 0,
+            root: String::from("/"),
+        })
+    }
+
+    /// Creates a task that runs on its caller's stack in place of the caller
+    /// (see `scheduler::run_as_task`), so it gets no stack or context of its own.
+    pub fn inline() -> Self {
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+        Task {
+            id,
+            state: TaskState::Running,
+            context: TaskContext::new(VirtAddr::zero(), VirtAddr::zero()),
+            kernel_stack: Box::new([]),
+            entry_point: || {}, // Never entered; the caller runs its body
+            user_region: None,
+            page_table: None,
+            exit_code: None,
+            cpu_ticks: 0,
+            root: String::from("/"),
+        }
+    }
+
+    /// Creates a ring 3 task running in its own address space.
+    /// `entry` and `user_stack` are user virtual addresses inside `page_table`;
+    /// the task still gets a kernel stack for system calls and interrupts.
+    pub fn new_user(
+        entry: VirtAddr,
+        user_stack: VirtAddr,
+        page_table: PhysFrame,
+        user_region: (VirtAddr, VirtAddr),
+    ) -> Result<Self, &'static str> {
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+
+        let mut stack_mem = Vec::new();
+        if stack_mem.try_reserve_exact(USER_TASK_KERNEL_STACK_SIZE).is_err() {
+            return Err("Failed to reserve memory for kernel stack");
+        }
+        stack_mem.resize(USER_TASK_KERNEL_STACK_SIZE, 0);
+
+        Ok(Task {
+            id,
+            state: TaskState::Runnable,
+            context: TaskContext::new(entry, user_stack),
+            kernel_stack: stack_mem.into_boxed_slice(),
+            entry_point: || {}, // Entered via iretq, not called
+            user_region: Some(user_region),
+            page_table: Some(page_table),
+            exit_code: None,
+            cpu_ticks: 0,
+            root: String::from("/"),
         })
     }
 
@@ -153,6 +226,61 @@ impl Task {
         self.state = new_state;
     }
     
+    /// Address range (start, end) the task's syscall pointers must fall within
+    pub fn user_region(&self) -> Option<(VirtAddr, VirtAddr)> {
+        self.user_region
+    }
+    
+    pub fn set_user_region(&mut self, region: Option<(VirtAddr, VirtAddr)>) {
+        self.user_region = region;
+    }
+    
+    /// Function the task was spawned with
+    pub fn entry_point(&self) -> fn() {
+        self.entry_point
+    }
+    
+    /// Exit code, once the task has exited
+    pub fn exit_code(&self) -> Option<i64> {
+        self.exit_code
+    }
+    
+    pub fn set_exit_code(&mut self, code: i64) {
+        self.exit_code = Some(code);
+    }
+    
+    pub fn cpu_ticks(&self) -> u64 {
+        self.cpu_ticks
+    }
+    
+    /// Charge one timer tick to this task
+    pub fn add_cpu_tick(&mut self) {
+        self.cpu_ticks += 1;
+    }
+    
+    /// Directory the task sees as "/"
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+    
+    /// Jail the task in `root`, a canonical path. The scheduler only lets
+    /// root move a task that's running (see `scheduler::set_current_root`).
+    pub fn set_root(&mut self, root: String) {
+        self.root = root;
+    }
+    
+    /// Level 4 page table of a user task
+    pub fn page_table(&self) -> Option<PhysFrame> {
+        self.page_table
+    }
+    
+    /// Top of the task's kernel stack (loaded into TSS RSP0 for user tasks)
+    pub fn kernel_stack_top(&self) -> VirtAddr {
+        // Keep the top 16-byte aligned
+        let top = VirtAddr::from_ptr(self.kernel_stack.as_ptr()) + self.kernel_stack.len();
+        top.align_down(16u64)
+    }
+    
     // Getter for context (immutable)
     pub fn context(&self) -> &TaskContext {
         &self.context
@@ -168,4 +296,7 @@ impl Task {
 // For kernel tasks, they can be allocated from the kernel heap.
 // A typical stack size might be 4KiB or 8KiB.
 
-pub const DEFAULT_KERNEL_STACK_SIZE: usize = 4096 * 2; // 8 KiB stack 
\ No newline at end of file
+pub const DEFAULT_KERNEL_STACK_SIZE: usize = 4096 * 2; // 8 KiB stack
+
+/// Kernel stack for user tasks; system calls run on it
+pub const USER_TASK_KERNEL_STACK_SIZE: usize = 4096 * 4; // 16 KiB
@@ -0,0 +1,213 @@
+//! Scheduler trace: a ring of recent task switches and why they happened
+//!
+//! While tracing is on, the scheduler records each switch, and each time a
+//! task blocks, sleeps or is woken, as {tick, from, to, reason} in a fixed
+//! array. Recording is a few stores with interrupts off: nothing is
+//! allocated or formatted, and once the ring is full the oldest events are
+//! overwritten. An event that finds the ring busy (an interrupt landing
+//! while it's being read) is counted as dropped instead of waited for.
+//!
+//! `dump_text` formats the ring one event per line, with ticks counted
+//! from the first event, so dumps of two runs can be diffed.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::scheduler::TaskId;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// Events kept; older ones are overwritten
+pub const CAPACITY: usize = 512;
+/// `from` or `to` when no task is involved: the CPU idles, or nothing had
+/// been running
+pub const NO_TASK: TaskId = TaskId::MAX;
+
+/// Why the CPU went from one task to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The timer took the CPU from a task that had used up its time
+    Preempt,
+    /// The task gave up the CPU while still runnable
+    Yield,
+    /// The task waits for time to pass
+    Sleep,
+    /// The task waits on a lock or queue
+    Block,
+    /// A blocked or sleeping task became runnable again
+    Wake,
+    /// A task was given the CPU to start running
+    Start,
+    /// The task exited
+    Exit,
+}
+
+impl Reason {
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::Preempt => "preempt",
+            Reason::Yield => "yield",
+            Reason::Sleep => "sleep",
+            Reason::Block => "block",
+            Reason::Wake => "wake",
+            Reason::Start => "start",
+            Reason::Exit => "exit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// Timer ticks since boot
+    pub tick: u64,
+    pub from: TaskId,
+    pub to: TaskId,
+    pub reason: Reason,
+}
+
+const EMPTY: Event = Event { tick: 0, from: NO_TASK, to: NO_TASK, reason: Reason::Yield };
+
+struct Ring {
+    events: [Event; CAPACITY],
+    /// Slot the next event goes in
+    next: usize,
+    /// Events recorded since the ring was cleared, overwritten ones too
+    recorded: u64,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring { events: [EMPTY; CAPACITY], next: 0, recorded: 0 });
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Record a switch from `from` to `to`, if tracing is on
+#[inline]
+pub fn record(from: TaskId, to: TaskId, reason: Reason) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let tick = crate::drivers::pit::ticks();
+    interrupts::without_interrupts(|| match RING.try_lock() {
+        Some(mut ring) => {
+            let slot = ring.next;
+            ring.events[slot] = Event { tick, from, to, reason };
+            ring.next = (slot + 1) % CAPACITY;
+            ring.recorded += 1;
+        }
+        None => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Empty the ring and start recording
+pub fn start() {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        ring.next = 0;
+        ring.recorded = 0;
+    });
+    DROPPED.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stop recording, keeping what's in the ring
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Pass the events in the ring to `visit`, oldest first. Returns the
+/// events recorded since `start`, overwritten ones included.
+pub fn for_each(mut visit: impl FnMut(&Event)) -> u64 {
+    // Copied out so the timer isn't held off while the caller works
+    let (events, next, recorded) = interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        (ring.events.to_vec(), ring.next, ring.recorded)
+    });
+    let kept = recorded.min(CAPACITY as u64) as usize;
+    let first = (next + CAPACITY - kept) % CAPACITY;
+    for index in 0..kept {
+        visit(&events[(first + index) % CAPACITY]);
+    }
+    recorded
+}
+
+fn task_name(id: TaskId) -> String {
+    if id == NO_TASK { String::from("-") } else { format!("{}", id) }
+}
+
+/// The ring as text, one event per line: ticks since the first event, from,
+/// to and reason, then a count of lost events
+pub fn dump_text() -> String {
+    let mut text = String::from("    TICK   FROM     TO  REASON\n");
+    let mut first_tick = None;
+    let recorded = for_each(|event| {
+        let start = *first_tick.get_or_insert(event.tick);
+        let _ = writeln!(text, "{:>8} {:>6} {:>6}  {}", event.tick - start,
+            task_name(event.from), task_name(event.to), event.reason.name());
+    });
+    let overwritten = recorded.saturating_sub(CAPACITY as u64);
+    let _ = write!(text, "# {} events, {} overwritten, {} dropped{}", recorded, overwritten,
+        DROPPED.load(Ordering::Relaxed), if is_enabled() { ", still recording" } else { "" });
+    text
+}
+
+/// Check events come out oldest first with ticks from the first, the
+/// ring wrapping, and nothing recorded while stopped. Leaves tracing on
+/// or off as it was, with the ring empty.
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("TRACE: Running self-test");
+
+    let was_enabled = is_enabled();
+    start();
+    record(1, 2, Reason::Yield);
+    record(2, NO_TASK, Reason::Block);
+    record(NO_TASK, 2, Reason::Wake);
+    stop();
+    record(3, 4, Reason::Preempt);
+    let mut seen = [EMPTY; 3];
+    let mut count = 0;
+    let recorded = for_each(|event| {
+        if count < seen.len() {
+            seen[count] = *event;
+        }
+        count += 1;
+    });
+    let reasons = seen.map(|event| (event.from, event.to, event.reason));
+    if recorded != 3 || count != 3
+        || reasons != [(1, 2, Reason::Yield), (2, NO_TASK, Reason::Block), (NO_TASK, 2, Reason::Wake)] {
+        return Err(KernelError::ValidationError("Trace recorded events wrongly or while stopped"));
+    }
+    let dump = dump_text();
+    let lines: Vec<&str> = dump.lines().collect();
+    if lines.len() != 5 || !lines[1].starts_with("       0      1      2  yield") || !lines[2].ends_with("-  block") {
+        return Err(KernelError::ValidationError("Trace dump format changed"));
+    }
+
+    start();
+    for index in 0..CAPACITY as u64 + 10 {
+        record(index, index + 1, Reason::Start);
+    }
+    stop();
+    let mut oldest = None;
+    let recorded = for_each(|event| {
+        oldest.get_or_insert(event.from);
+    });
+    if recorded != CAPACITY as u64 + 10 || oldest != Some(10) {
+        return Err(KernelError::ValidationError("Trace ring didn't keep the newest events"));
+    }
+
+    start();
+    if !was_enabled {
+        stop();
+    }
+    serial_println!("TRACE: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,587 @@
+// kernel/src/task/user_mode.rs
+//! Ring 3 execution
+//! User programs get their own level 4 page table that shares the kernel's
+//! (supervisor-only) mappings and adds a user code and stack region. A program
+//! runs synchronously: `run_program` enters ring 3 with iretq and returns once
+//! the program exits through the exit system call or is killed by a fault.
+//!
+//! `AddressSpace::fork` copies an address space lazily: writable user pages
+//! become read-only and copy-on-write in both copies, and the first write
+//! to one takes a page fault that gives the writer a private copy. There is
+//! no fork system call: programs run synchronously, and the scheduler
+//! can't switch to a child task yet.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::errors::{KernelError, MemoryError};
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::serial_println;
+use super::scheduler;
+use super::trace::Reason;
+use super::task_structs::Task;
+
+/// Level 4 slot reserved for user mappings (0x1000_0000_0000..0x1080_0000_0000)
+const USER_L4_INDEX: usize = 32;
+/// Where program code is loaded
+pub const USER_CODE_BASE: u64 = (USER_L4_INDEX as u64) << 39;
+/// Initial user stack pointer; the stack grows down from here
+pub const USER_STACK_TOP: u64 = USER_CODE_BASE + 0x80_0000;
+/// Pages mapped for the user stack
+const USER_STACK_PAGES: u64 = 4;
+/// Lowest stack address; program images must end below it
+pub const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+/// Largest program image accepted
+pub const MAX_PROGRAM_SIZE: usize = 64 * 1024;
+
+/// Exit code reported for programs killed by a fault (128 + SIGSEGV, as shells report it)
+pub const FAULT_EXIT_CODE: i64 = 139;
+
+const PAGE_SIZE: u64 = 4096;
+const RFLAGS_RESERVED: u64 = 1 << 1;
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Page table bit (free for OS use) marking a page shared copy-on-write
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+lazy_static! {
+    /// Mappings of each copy-on-write frame, by physical address. The last
+    /// mapping to fault gets the frame back writable instead of a copy.
+    static ref COW_SHARES: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+}
+
+// Kernel state to return to when the running program exits; zero when no
+// program is running
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+static KERNEL_CR3: AtomicU64 = AtomicU64::new(0);
+
+// user_mode_enter(saved_rsp: *mut u64, entry, stack, cs, ss, rflags) -> exit code
+//   Saves the callee-saved registers and the kernel stack pointer, then builds
+//   an interrupt frame and irets into ring 3. Returns when user_mode_return
+//   switches back to the saved stack.
+// user_mode_return(saved_rsp, exit_code) -> !
+global_asm!(
+    ".global user_mode_enter",
+    "user_mode_enter:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "push r8",  // ss
+    "push rdx", // rsp
+    "push r9",  // rflags
+    "push rcx", // cs
+    "push rsi", // rip
+    // Don't leak kernel values into user registers
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    "",
+    ".global user_mode_return",
+    "user_mode_return:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+extern "C" {
+    fn user_mode_enter(saved_rsp: *mut u64, entry: u64, stack: u64, cs: u64, ss: u64, rflags: u64) -> i64;
+    fn user_mode_return(saved_rsp: u64, exit_code: i64) -> !;
+}
+
+/// A user address space: a private level 4 table sharing the kernel mappings
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Create an address space containing the kernel mappings and an empty user slot
+    pub fn new() -> Result<Self, KernelError> {
+        let level_4_frame = memory::allocate_frame().ok_or(KernelError::OutOfMemory)?;
+        let (kernel_frame, _) = Cr3::read();
+
+        unsafe {
+            let kernel_table: &PageTable = &*memory::phys_to_virt(kernel_frame.start_address()).as_ptr();
+            let table: &mut PageTable = &mut *memory::phys_to_virt(level_4_frame.start_address()).as_mut_ptr();
+            table.zero();
+
+            if !kernel_table[USER_L4_INDEX].is_unused() {
+                return Err(KernelError::GenericError("User address range overlaps kernel mappings"));
+            }
+
+            // Kernel entries are supervisor-only, so ring 3 can't touch them
+            for (i, entry) in kernel_table.iter().enumerate() {
+                if i != USER_L4_INDEX {
+                    table[i] = entry.clone();
+                }
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    /// Physical frame of the level 4 table (the CR3 value)
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = memory::phys_to_virt(PhysAddr::new(0));
+        unsafe {
+            let table: &mut PageTable = &mut *memory::phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr();
+            OffsetPageTable::new(table, offset)
+        }
+    }
+
+    /// Map fresh zeroed user pages over [start, start + len).
+    ///
+    /// Pages that are already mapped are kept and get the union of the old
+    /// and new permissions, so segments may share a page.
+    pub fn map_user(&mut self, start: u64, len: u64, writable: bool, executable: bool) -> Result<(), KernelError> {
+        use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+
+        if start < USER_CODE_BASE || start.checked_add(len).map_or(true, |end| end > USER_STACK_TOP) {
+            return Err(KernelError::InvalidParameter);
+        }
+
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !executable && no_execute_supported() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(start + len.max(1) - 1));
+        let mut mapper = self.mapper();
+        for page in Page::range_inclusive(first, last) {
+            if let TranslateResult::Mapped { flags: existing, .. } = mapper.translate(page.start_address()) {
+                let mut merged = existing | flags;
+                if !(existing.contains(PageTableFlags::NO_EXECUTE) && flags.contains(PageTableFlags::NO_EXECUTE)) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe {
+                    mapper
+                        .update_flags(page, merged)
+                        .map_err(|_| KernelError::MemoryError(MemoryError::PageMappingFailed))?
+                        .ignore();
+                }
+                continue;
+            }
+
+            let frame = GlobalFrameAllocator.allocate_frame().ok_or(KernelError::OutOfMemory)?;
+            unsafe {
+                core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+                // Not the active table, so no TLB flush is needed
+                mapper
+                    .map_to_with_table_flags(page, frame, flags, table_flags, &mut GlobalFrameAllocator)
+                    .map_err(|_| KernelError::MemoryError(MemoryError::PageMappingFailed))?
+                    .ignore();
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the user stack below USER_STACK_TOP
+    pub fn map_stack(&mut self) -> Result<(), KernelError> {
+        self.map_user(USER_STACK_BOTTOM, USER_STACK_TOP - USER_STACK_BOTTOM, true, false)
+    }
+
+    /// Copy `data` into already mapped user memory at `addr`
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), KernelError> {
+        use x86_64::structures::paging::mapper::Translate;
+
+        let mapper = self.mapper();
+        for (i, byte) in data.iter().enumerate() {
+            let phys = mapper
+                .translate_addr(VirtAddr::new(addr + i as u64))
+                .ok_or(KernelError::InvalidParameter)?;
+            unsafe {
+                *memory::phys_to_virt(phys).as_mut_ptr::<u8>() = *byte;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AddressSpace {
+    /// Read already mapped user memory at `addr` into `buffer`
+    pub fn read(&mut self, addr: u64, buffer: &mut [u8]) -> Result<(), KernelError> {
+        use x86_64::structures::paging::mapper::Translate;
+
+        let mapper = self.mapper();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            let phys = mapper
+                .translate_addr(VirtAddr::new(addr + i as u64))
+                .ok_or(KernelError::InvalidParameter)?;
+            *byte = unsafe { *memory::phys_to_virt(phys).as_ptr::<u8>() };
+        }
+        Ok(())
+    }
+
+    /// Copy this address space. Kernel mappings are shared as they are; the
+    /// user page tables are duplicated, with writable pages turned read-only
+    /// and copy-on-write in both spaces.
+    pub fn fork(&mut self) -> Result<AddressSpace, KernelError> {
+        use x86_64::registers::control::{Cr0, Cr0Flags};
+
+        let child = AddressSpace::new()?;
+        unsafe {
+            // Without WP, kernel writes into user buffers would skip the fault
+            // and land on the shared frame
+            Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+            let parent_entry = &table_at(self.level_4_frame)[USER_L4_INDEX];
+            if !parent_entry.is_unused() {
+                let flags = parent_entry.flags();
+                let level_3 = fork_table(PhysFrame::containing_address(parent_entry.addr()), 3, &mut COW_SHARES.lock())?;
+                table_at(child.level_4_frame)[USER_L4_INDEX].set_frame(level_3, flags);
+            }
+        }
+        // The parent may be running; its writable pages just became read-only
+        if Cr3::read().0 == self.level_4_frame {
+            x86_64::instructions::tlb::flush_all();
+        }
+        Ok(child)
+    }
+}
+
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Copy the user page table `parent` at `level` (1 holds the pages),
+/// marking writable pages copy-on-write in both copies
+unsafe fn fork_table(parent: PhysFrame, level: u8, shares: &mut BTreeMap<u64, usize>) -> Result<PhysFrame, KernelError> {
+    let frame = memory::allocate_frame().ok_or(KernelError::OutOfMemory)?;
+    let child = table_at(frame);
+    child.zero();
+
+    for (index, entry) in table_at(parent).iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let mut flags = entry.flags();
+        if level > 1 {
+            // map_user only creates 4 KiB pages here
+            let copy = fork_table(PhysFrame::containing_address(entry.addr()), level - 1, shares)?;
+            child[index].set_frame(copy, flags);
+            continue;
+        }
+        if flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+            entry.set_flags(flags);
+            *shares.entry(entry.addr().as_u64()).or_insert(1) += 1;
+        }
+        child[index].set_addr(entry.addr(), flags);
+    }
+    Ok(frame)
+}
+
+/// Give the space rooted at `level_4_frame` a private, writable copy of the
+/// copy-on-write page holding `addr`. Returns false if the page isn't
+/// copy-on-write, so the fault is a real one.
+fn resolve_copy_on_write(level_4_frame: PhysFrame, addr: VirtAddr) -> Result<bool, KernelError> {
+    if usize::from(addr.p4_index()) != USER_L4_INDEX {
+        return Ok(false);
+    }
+
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    let mut table = unsafe { table_at(level_4_frame) };
+    for index in indices {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(false);
+        }
+        table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+    }
+    let entry = &mut table[addr.p1_index()];
+    let mut flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT | COPY_ON_WRITE) {
+        return Ok(false);
+    }
+
+    let shared = entry.addr();
+    let mut shares = COW_SHARES.lock();
+    let mappings = shares.get(&shared.as_u64()).copied().unwrap_or(1);
+    if mappings <= 1 {
+        // Every other mapping has its own copy by now
+        shares.remove(&shared.as_u64());
+    } else {
+        let copy = memory::allocate_frame().ok_or(KernelError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                memory::phys_to_virt(shared).as_ptr::<u8>(),
+                memory::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            );
+        }
+        shares.insert(shared.as_u64(), mappings - 1);
+        entry.set_addr(copy.start_address(), flags);
+    }
+    flags.remove(COPY_ON_WRITE);
+    flags.insert(PageTableFlags::WRITABLE);
+    entry.set_flags(flags);
+    Ok(true)
+}
+
+/// Resolve a write fault on a copy-on-write page of the active address
+/// space. Returns true if the faulting write can be retried.
+pub fn handle_copy_on_write_fault(addr: VirtAddr) -> bool {
+    match resolve_copy_on_write(Cr3::read().0, addr) {
+        Ok(true) => {
+            x86_64::instructions::tlb::flush(addr);
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            serial_println!("USER: Copy-on-write at {:?} failed: {:?}", addr, e);
+            false
+        }
+    }
+}
+
+/// Whether the CPU honours the no-execute page bit
+fn no_execute_supported() -> bool {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Enter ring 3 at `entry` with stack pointer `stack` in the current address space.
+/// Returns the program's exit code once it exits or is killed.
+///
+/// # Safety
+/// A user task with a valid kernel stack must be current, its address space
+/// must be loaded, and `entry`/`stack` must be mapped user accessible.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> i64 {
+    let (code_selector, stack_selector) = crate::gdt::user_selectors();
+    let mut rflags = RFLAGS_RESERVED;
+    if x86_64::instructions::interrupts::are_enabled() {
+        rflags |= RFLAGS_IF;
+    }
+
+    // KERNEL_RSP is written by the stub just before the iretq
+    user_mode_enter(
+        KERNEL_RSP.as_ptr(),
+        entry.as_u64(),
+        stack.as_u64(),
+        code_selector.0 as u64,
+        stack_selector.0 as u64,
+        rflags,
+    )
+}
+
+/// Whether a user program is currently running
+pub fn is_active() -> bool {
+    KERNEL_RSP.load(Ordering::SeqCst) != 0
+}
+
+/// Leave the running program and resume the kernel in `run_program`.
+/// Called from the exit system call and from fault handlers.
+pub fn exit_current(exit_code: i64) -> ! {
+    let rsp = KERNEL_RSP.load(Ordering::SeqCst);
+    let cr3 = KERNEL_CR3.load(Ordering::SeqCst);
+    assert!(rsp != 0, "exit_current called with no user program running");
+
+    unsafe {
+        let (_, flags) = Cr3::read();
+        Cr3::write(PhysFrame::containing_address(PhysAddr::new(cr3)), flags);
+        user_mode_return(rsp, exit_code);
+    }
+}
+
+/// Kill the running program after a fault raised in ring 3
+pub fn handle_fault(description: &str, address: Option<VirtAddr>, rip: VirtAddr) -> ! {
+    let id = scheduler::current_task_id().unwrap_or(0);
+    match address {
+        Some(address) => serial_println!(
+            "USER: Task {} killed: {} at {:?} (rip {:?})", id, description, address, rip),
+        None => serial_println!("USER: Task {} killed: {} (rip {:?})", id, description, rip),
+    }
+    exit_current(FAULT_EXIT_CODE);
+}
+
+/// Load a flat binary at USER_CODE_BASE and run it in ring 3, returning its exit code
+pub fn run_program(image: &[u8]) -> Result<i64, KernelError> {
+    if image.is_empty() || image.len() > MAX_PROGRAM_SIZE {
+        return Err(KernelError::InvalidParameter);
+    }
+    if is_active() {
+        return Err(KernelError::InvalidOperation);
+    }
+
+    let mut space = AddressSpace::new()?;
+    space.map_user(USER_CODE_BASE, image.len() as u64, false, true)?;
+    space.write(USER_CODE_BASE, image)?;
+    space.map_stack()?;
+
+    run_in(&space, VirtAddr::new(USER_CODE_BASE), VirtAddr::new(USER_STACK_TOP))
+}
+
+/// Run a prepared address space from `entry` until the program exits
+pub fn run_in(space: &AddressSpace, entry: VirtAddr, stack: VirtAddr) -> Result<i64, KernelError> {
+    let region = (VirtAddr::new(USER_CODE_BASE), VirtAddr::new(USER_STACK_TOP));
+    let mut task = Task::new_user(entry, stack, space.level_4_frame(), region)
+        .map_err(KernelError::GenericError)?;
+    task.set_root(scheduler::current_root());
+    let task_id = task.id();
+
+    // The exit path returns with interrupts disabled (int 0x80 is an interrupt gate)
+    let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
+
+    let previous = scheduler::replace_current(Some(Box::new(task)), Reason::Start);
+    serial_println!("USER: Starting task {} at {:?}", task_id, entry);
+
+    let (kernel_frame, flags) = Cr3::read();
+    KERNEL_CR3.store(kernel_frame.start_address().as_u64(), Ordering::SeqCst);
+    let exit_code = unsafe {
+        Cr3::write(space.level_4_frame(), flags);
+        enter_user_mode(entry, stack)
+    };
+    // exit_current has already switched back to the kernel page table
+    KERNEL_RSP.store(0, Ordering::SeqCst);
+
+    scheduler::replace_current(previous, Reason::Exit);
+    if interrupts_enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+
+    // Frames are not reclaimed: the frame allocator can't free yet
+    serial_println!("USER: Task {} exited with code {}", task_id, exit_code);
+    Ok(exit_code)
+}
+
+/// Built-in test program:
+///     lea rsi, [rip + msg]
+///     mov edi, 1          ; stdout
+///     mov edx, 19         ; length of msg
+///     mov eax, 1          ; SYS_WRITE
+///     int 0x80
+///     xor edi, edi
+///     mov eax, 6          ; SYS_EXIT
+///     int 0x80
+///     jmp $
+/// msg: "Hello from ring 3!\n"
+const TEST_PROGRAM: &[u8] = b"\x48\x8d\x35\x1c\x00\x00\x00\
+\xbf\x01\x00\x00\x00\
+\xba\x13\x00\x00\x00\
+\xb8\x01\x00\x00\x00\
+\xcd\x80\
+\x31\xff\
+\xb8\x06\x00\x00\x00\
+\xcd\x80\
+\xeb\xfe\
+Hello from ring 3!\n";
+
+/// Test program for fork, writing 'B' over the data page it shares with
+/// its parent:
+///     mov rax, USER_CODE_BASE + 0x1000
+///     mov byte [rax], 'B'
+///     xor edi, edi
+///     mov eax, 6          ; SYS_EXIT
+///     int 0x80
+///     jmp $
+const FORK_TEST_PROGRAM: &[u8] = b"\x48\xb8\x00\x10\x00\x00\x00\x10\x00\x00\
+\xc6\x00\x42\
+\x31\xff\
+\xb8\x06\x00\x00\x00\
+\xcd\x80\
+\xeb\xfe";
+const FORK_TEST_DATA: u64 = USER_CODE_BASE + 0x1000;
+
+/// Fork an address space, let the child write a shared page from ring 3,
+/// and check the parent's copy is untouched
+fn fork_self_test() -> Result<(), KernelError> {
+    use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+
+    let frame_of = |space: &mut AddressSpace, addr: u64| match space.mapper().translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { frame, flags, .. } => Some((frame.start_address(), flags)),
+        _ => None,
+    };
+
+    let mut parent = AddressSpace::new()?;
+    parent.map_user(USER_CODE_BASE, FORK_TEST_PROGRAM.len() as u64, false, true)?;
+    parent.write(USER_CODE_BASE, FORK_TEST_PROGRAM)?;
+    parent.map_user(FORK_TEST_DATA, PAGE_SIZE, true, false)?;
+    parent.write(FORK_TEST_DATA, b"A")?;
+    parent.map_stack()?;
+
+    let mut child = parent.fork()?;
+    let (shared, parent_flags) = frame_of(&mut parent, FORK_TEST_DATA).ok_or(KernelError::NotFound)?;
+    let (code, _) = frame_of(&mut parent, USER_CODE_BASE).ok_or(KernelError::NotFound)?;
+    if frame_of(&mut child, FORK_TEST_DATA) != Some((shared, parent_flags))
+        || frame_of(&mut child, USER_CODE_BASE).map(|(frame, _)| frame) != Some(code)
+        || parent_flags.contains(PageTableFlags::WRITABLE) || !parent_flags.contains(COPY_ON_WRITE) {
+        return Err(KernelError::ValidationError("Fork didn't share pages copy-on-write"));
+    }
+
+    if run_in(&child, VirtAddr::new(USER_CODE_BASE), VirtAddr::new(USER_STACK_TOP))? != 0 {
+        return Err(KernelError::ValidationError("Forked test program failed"));
+    }
+    let (mut parent_byte, mut child_byte) = ([0u8], [0u8]);
+    parent.read(FORK_TEST_DATA, &mut parent_byte)?;
+    child.read(FORK_TEST_DATA, &mut child_byte)?;
+    if &parent_byte != b"A" || &child_byte != b"B" {
+        return Err(KernelError::ValidationError("Child's write reached the parent's page"));
+    }
+    let (child_frame, child_flags) = frame_of(&mut child, FORK_TEST_DATA).ok_or(KernelError::NotFound)?;
+    if child_frame == shared || !child_flags.contains(PageTableFlags::WRITABLE) {
+        return Err(KernelError::ValidationError("Child didn't get a private writable copy"));
+    }
+
+    // The parent is now the only mapping left, so it gets the frame back
+    if !resolve_copy_on_write(parent.level_4_frame(), VirtAddr::new(FORK_TEST_DATA))?
+        || frame_of(&mut parent, FORK_TEST_DATA) != Some((shared, (parent_flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE)) {
+        return Err(KernelError::ValidationError("Last mapping of a copy-on-write page was copied"));
+    }
+    Ok(())
+}
+
+/// Run the built-in test programs, checking that they exit cleanly and
+/// that fork keeps parent and child memory apart
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("USER: Running ring 3 test program");
+    match run_program(TEST_PROGRAM)? {
+        0 => serial_println!("USER: Test program passed"),
+        code => {
+            serial_println!("USER: Test program exited with code {}", code);
+            return Err(KernelError::ValidationError("User mode test program failed"));
+        }
+    }
+
+    fork_self_test()?;
+    serial_println!("USER: Fork test passed");
+    Ok(())
+}
@@ -0,0 +1,57 @@
+// kernel/src/task/wait_queue.rs
+//! Wait queues: tasks block on a queue until another task wakes them
+
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use super::scheduler::{self, TaskId};
+
+/// A list of tasks blocked waiting for some event
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the current task until `condition` returns true.
+    /// The condition is rechecked every time the queue is woken.
+    pub fn wait_until<F: Fn() -> bool>(&self, condition: F) {
+        loop {
+            if condition() {
+                if let Some(id) = scheduler::current_task_id() {
+                    self.waiters.lock().retain(|&waiter| waiter != id);
+                    scheduler::unblock(id);
+                }
+                return;
+            }
+
+            if let Some(id) = scheduler::current_task_id() {
+                let mut waiters = self.waiters.lock();
+                if !waiters.contains(&id) {
+                    waiters.push_back(id);
+                }
+            }
+            scheduler::block_current();
+            scheduler::schedule();
+
+            // Nothing else was runnable; idle until something changes
+            if x86_64::instructions::interrupts::are_enabled() {
+                x86_64::instructions::hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Make every waiting task runnable again
+    pub fn wake_all(&self) {
+        let woken: VecDeque<TaskId> = core::mem::take(&mut *self.waiters.lock());
+        for id in woken {
+            scheduler::unblock(id);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Watchdog for UniverseK OS
+//! Main loops register a heartbeat and kick it every time round. The timer
+//! interrupt checks once a second for a heartbeat that has gone quiet, which
+//! usually means a loop is stuck waiting for a lock, and reports it. The
+//! report runs with the rest of the kernel possibly stuck holding any lock,
+//! so nothing in it waits for one.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use lazy_static::lazy_static;
+use crate::drivers::pit;
+use crate::errors::KernelError;
+use crate::logger::LogLevel;
+use crate::serial_println;
+
+/// Seconds a heartbeat may go unkicked when none is configured
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+/// Seconds without a kick before a heartbeat counts as stale
+static TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_SECS);
+/// Reboot once a stale heartbeat has been reported
+static REBOOT_ON_STALE: AtomicBool = AtomicBool::new(false);
+
+/// A subsystem's liveness, kept for the life of the kernel
+pub struct Heartbeat {
+    name: &'static str,
+    /// Tick of the last kick
+    last_kick: AtomicU64,
+    /// Reported as stale and not kicked since
+    stale: AtomicBool,
+    /// Held by a live handle
+    active: AtomicBool,
+}
+
+/// Kept by the subsystem; dropping it stops the watchdog watching
+pub struct HeartbeatHandle {
+    beat: &'static Heartbeat,
+}
+
+impl HeartbeatHandle {
+    /// Record that the subsystem is still making progress
+    pub fn kick(&self) {
+        self.beat.last_kick.store(pit::ticks(), Ordering::Relaxed);
+        if self.beat.stale.load(Ordering::Relaxed) {
+            self.beat.stale.store(false, Ordering::Relaxed);
+            serial_println!("WATCHDOG: '{}' is running again", self.beat.name);
+        }
+    }
+}
+
+impl Drop for HeartbeatHandle {
+    fn drop(&mut self) {
+        self.beat.active.store(false, Ordering::SeqCst);
+    }
+}
+
+lazy_static! {
+    /// Every heartbeat ever registered; inactive ones are reused by name
+    static ref HEARTBEATS: Mutex<Vec<&'static Heartbeat>> = Mutex::new(Vec::new());
+}
+
+/// Read the timeout and action from the configuration. The timer interrupt
+/// can't read the configuration itself, since that takes a lock.
+pub fn init() {
+    let timeout = crate::config::get("watchdog.timeout_secs")
+        .and_then(|value| value.try_as_integer())
+        .filter(|secs| *secs > 0)
+        .map_or(DEFAULT_TIMEOUT_SECS, |secs| secs as u64);
+    let action = crate::config::get("watchdog.action")
+        .and_then(|value| value.try_as_string().cloned());
+    let reboot = action.as_deref() == Some("reboot");
+
+    TIMEOUT_SECS.store(timeout, Ordering::SeqCst);
+    REBOOT_ON_STALE.store(reboot, Ordering::SeqCst);
+    serial_println!("DEBUG: Watchdog: {} s timeout, {} when stale", timeout, if reboot { "reboot" } else { "log" });
+}
+
+/// Start watching a subsystem. It must kick the handle at least once every
+/// timeout from now on.
+pub fn register(name: &'static str) -> HeartbeatHandle {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut heartbeats = HEARTBEATS.lock();
+        let reusable = heartbeats.iter()
+            .find(|beat| beat.name == name && !beat.active.load(Ordering::SeqCst))
+            .copied();
+        let beat = match reusable {
+            Some(beat) => beat,
+            None => {
+                let beat: &'static Heartbeat = Box::leak(Box::new(Heartbeat {
+                    name,
+                    last_kick: AtomicU64::new(0),
+                    stale: AtomicBool::new(false),
+                    active: AtomicBool::new(false),
+                }));
+                heartbeats.push(beat);
+                beat
+            }
+        };
+
+        beat.last_kick.store(pit::ticks(), Ordering::SeqCst);
+        beat.stale.store(false, Ordering::SeqCst);
+        beat.active.store(true, Ordering::SeqCst);
+        HeartbeatHandle { beat }
+    })
+}
+
+/// Called from the timer interrupt with the tick count
+pub fn timer_tick(ticks: u64) {
+    let frequency = pit::frequency() as u64;
+    if frequency == 0 || ticks % frequency != 0 {
+        return;
+    }
+    let limit = TIMEOUT_SECS.load(Ordering::Relaxed) * frequency;
+    let mut any_stale = false;
+    check(ticks, limit, &mut |beat, quiet| {
+        report(beat.name, quiet / frequency);
+        any_stale = true;
+    });
+    if any_stale && REBOOT_ON_STALE.load(Ordering::Relaxed) {
+        crate::reboot();
+    }
+}
+
+/// Hand each active heartbeat quiet for more than `limit` ticks to `stale`,
+/// with how long it has been quiet, once per silence
+fn check(now: u64, limit: u64, stale: &mut dyn FnMut(&Heartbeat, u64)) {
+    // Checked again next second if a registration holds the list
+    let heartbeats = match HEARTBEATS.try_lock() {
+        Some(heartbeats) => heartbeats,
+        None => return,
+    };
+    for beat in heartbeats.iter() {
+        let quiet = now.saturating_sub(beat.last_kick.load(Ordering::Relaxed));
+        if beat.active.load(Ordering::Relaxed) && quiet > limit && !beat.stale.swap(true, Ordering::Relaxed) {
+            stale(beat, quiet);
+        }
+    }
+}
+
+/// Report a stale heartbeat on the serial port, with the task list, and add
+/// a critical entry to the log buffer. Each part is skipped if its lock is
+/// taken.
+fn report(name: &str, quiet_secs: u64) {
+    if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+        let _ = writeln!(serial, "WATCHDOG: '{}' has not run for {} s, probably stuck on a lock", name, quiet_secs);
+        if !crate::task::scheduler::try_dump_tasks(&mut *serial) {
+            let _ = writeln!(serial, "WATCHDOG: task list locked, not dumped");
+        }
+    }
+
+    // Log entries are allocated, so only log if the heap is free
+    crate::allocator::with_idle_heap(|| {
+        let message = format!("'{}' has not run for {} s", name, quiet_secs);
+        crate::logger::try_record(LogLevel::Critical, "watchdog", &message);
+    });
+}
+
+/// Check a quiet heartbeat is reported once, and not again until it has
+/// been kicked and gone quiet again
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("WATCHDOG: Running self-test");
+
+    let handle = register("watchdog-selftest");
+    let count_reports = |now: u64| {
+        let mut reports = 0;
+        check(now, 100, &mut |beat, _| {
+            if beat.name == "watchdog-selftest" {
+                reports += 1;
+            }
+        });
+        reports
+    };
+
+    handle.beat.last_kick.store(1000, Ordering::SeqCst);
+    let quiet = count_reports(1050);
+    let first = count_reports(1200);
+    let again = count_reports(1300);
+    handle.kick();
+    handle.beat.last_kick.store(1000, Ordering::SeqCst);
+    let after_kick = count_reports(1200);
+    drop(handle);
+    if quiet != 0 || first != 1 || again != 0 || after_kick != 1 {
+        serial_println!("WATCHDOG: reports {} {} {} {}", quiet, first, again, after_kick);
+        return Err(KernelError::ValidationError("Stale heartbeat reported wrongly"));
+    }
+
+    // A dropped handle is no longer watched
+    let beat = HEARTBEATS.lock().iter().copied().find(|beat| beat.name == "watchdog-selftest");
+    if let Some(beat) = beat {
+        beat.stale.store(false, Ordering::SeqCst);
+        if count_reports(5000) != 0 {
+            return Err(KernelError::ValidationError("Dropped heartbeat still watched"));
+        }
+    }
+
+    serial_println!("WATCHDOG: Self-test passed");
+    Ok(())
+}
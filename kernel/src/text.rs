@@ -0,0 +1,80 @@
+//! Width of strings on the text screen
+//!
+//! The screen draws CP437, one cell per char: characters with a CP437 glyph
+//! show as that glyph and the rest as '?' (see `drivers::cp437`). So a
+//! string's width in columns is its number of chars, not bytes, and it can
+//! only be cut between chars. Slicing by byte count both miscounts names
+//! like "café" and panics when the cut lands inside a character.
+
+use alloc::string::String;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// What `ellipsize` puts in place of the cut-off text
+pub const ELLIPSIS: &str = "...";
+
+/// Columns `s` takes on screen
+pub fn display_width(s: &str) -> usize {
+    s.chars().count()
+}
+
+/// The longest start of `s` that fits in `cols` columns
+pub fn truncate_to_width(s: &str, cols: usize) -> &str {
+    match s.char_indices().nth(cols) {
+        Some((end, _)) => &s[..end],
+        None => s,
+    }
+}
+
+/// The longest end of `s` that fits in `cols` columns, for showing the
+/// newest part of a line that has scrolled
+pub fn tail_to_width(s: &str, cols: usize) -> &str {
+    let skip = display_width(s).saturating_sub(cols);
+    match s.char_indices().nth(skip) {
+        Some((start, _)) => &s[start..],
+        None => "",
+    }
+}
+
+/// `s` if it fits in `cols` columns, otherwise as much as fits followed by
+/// "..."
+pub fn ellipsize(s: &str, cols: usize) -> String {
+    if display_width(s) <= cols {
+        return String::from(s);
+    }
+    let ellipsis = truncate_to_width(ELLIPSIS, cols);
+    let mut short = String::from(truncate_to_width(s, cols - display_width(ellipsis)));
+    short.push_str(ellipsis);
+    short
+}
+
+/// Check widths and cuts with multi-byte chars right at the boundary, where
+/// byte slicing used to panic
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("TEXT: Running self-test");
+
+    // 'é' is two bytes and '═' three, but each is one cell
+    if display_width("café") != 4 || display_width("╔═╗") != 3 || display_width("") != 0 {
+        return Err(KernelError::ValidationError("Display width counts bytes"));
+    }
+
+    // Cuts ending just before, on and just after a multi-byte char
+    if truncate_to_width("café", 3) != "caf" || truncate_to_width("café", 4) != "café"
+        || truncate_to_width("café", 9) != "café" || truncate_to_width("═══", 2) != "══"
+        || !truncate_to_width("é", 0).is_empty() {
+        return Err(KernelError::ValidationError("Truncation cut wrongly"));
+    }
+    if tail_to_width("écrit", 4) != "crit" || tail_to_width("écrit", 5) != "écrit"
+        || tail_to_width("ab═", 1) != "═" || !tail_to_width("ab", 0).is_empty() {
+        return Err(KernelError::ValidationError("Tail cut wrongly"));
+    }
+
+    // "Résumé.txt" is 12 bytes but 10 columns, so it fits exactly
+    if ellipsize("Résumé.txt", 10) != "Résumé.txt" || ellipsize("Résumé.txt", 9) != "Résumé..."
+        || ellipsize("ééééé", 4) != "é..." || ellipsize("abc", 2) != ".." {
+        return Err(KernelError::ValidationError("Ellipsized text wrong"));
+    }
+
+    serial_println!("TEXT: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,310 @@
+// kernel/src/time.rs
+//! Kernel timekeeping, the one place the rest of the kernel asks for time.
+//!
+//! The monotonic clock counts nanoseconds since boot from the PIT tick
+//! count and never goes backwards. The wall clock is seeded from the RTC at
+//! boot and then advances with the monotonic clock. Every
+//! `RESYNC_INTERVAL_SECS` it is compared with the RTC again. Small
+//! differences are slewed in by running the clock up to a tenth fast or
+//! slow, so a resync never moves it backwards. Only `set_wall_clock` (as
+//! used by `hwclock --set`) may step it back.
+//!
+//! The RTC's periodic interrupt can drive the monotonic clock instead of
+//! the PIT. Switching source carries the time over, so the clock runs on
+//! from where it was.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::drivers::rtc::{self, DateTime};
+use crate::errors::KernelError;
+use crate::serial_println;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Seconds between comparisons with the RTC
+pub const RESYNC_INTERVAL_SECS: u64 = 600;
+
+/// Differences up to this are RTC read granularity (it counts whole
+/// seconds), not drift, and are left alone
+const RESYNC_TOLERANCE_NS: i64 = NANOS_PER_SEC as i64;
+
+/// Wall time behind the RTC by more than this is stepped forward at once
+/// rather than slewed
+const STEP_THRESHOLD_NS: i64 = 60 * NANOS_PER_SEC as i64;
+
+/// A correction is slewed in at no more than 1/SLEW_DIVISOR of elapsed time
+const SLEW_DIVISOR: u64 = 10;
+
+/// Highest monotonic value handed out, so callers never see it go back
+static LAST_MONOTONIC_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Monotonic time of the last RTC comparison
+static LAST_RESYNC_NS: AtomicU64 = AtomicU64::new(0);
+
+/// RTC periodic interrupts counted so far, in 1/32768 s
+static RTC_UNITS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the RTC rather than the PIT drives the monotonic clock
+static RTC_SOURCE: AtomicBool = AtomicBool::new(false);
+
+/// Monotonic time when the source last changed, and the source's own
+/// count of nanoseconds then
+static SOURCE_BASE_NS: AtomicU64 = AtomicU64::new(0);
+static SOURCE_BASE_RAW_NS: AtomicU64 = AtomicU64::new(0);
+
+/// What drives the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    Rtc,
+}
+
+/// Wall time as a function of monotonic time: `wall` at `base` plus the
+/// time elapsed since, plus as much of `pending` as has been slewed in
+#[derive(Debug, Clone, Copy)]
+struct WallClock {
+    base: u64,
+    wall: u64,
+    pending: i64,
+}
+
+impl WallClock {
+    const fn new() -> Self {
+        WallClock { base: 0, wall: 0, pending: 0 }
+    }
+
+    /// Correction applied by monotonic time `now`
+    fn applied(&self, now: u64) -> i64 {
+        let limit = (now.saturating_sub(self.base) / SLEW_DIVISOR) as i64;
+        self.pending.clamp(-limit, limit)
+    }
+
+    /// Wall time in nanoseconds at monotonic time `now`
+    fn at(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.base) as i64;
+        (self.wall as i64 + elapsed + self.applied(now)).max(0) as u64
+    }
+
+    /// Move the base up to `now`, keeping the wall time continuous
+    fn rebase(&mut self, now: u64) {
+        let applied = self.applied(now);
+        self.wall = self.at(now);
+        self.pending -= applied;
+        self.base = now;
+    }
+
+    /// Steer towards `reference` (the RTC's wall time at `now`)
+    fn resync(&mut self, now: u64, reference: u64) {
+        self.rebase(now);
+        let offset = reference as i64 - self.wall as i64;
+        if offset > STEP_THRESHOLD_NS {
+            self.wall = reference;
+            self.pending = 0;
+        } else if offset.abs() > RESYNC_TOLERANCE_NS {
+            self.pending = offset;
+        } else {
+            self.pending = 0;
+        }
+    }
+
+    /// Make `wall` the wall time at `now`, dropping any correction
+    fn set(&mut self, now: u64, wall: u64) {
+        *self = WallClock { base: now, wall, pending: 0 };
+    }
+}
+
+lazy_static! {
+    static ref WALL_CLOCK: Mutex<WallClock> = Mutex::new(WallClock::new());
+}
+
+/// Nanoseconds in `units` of 1/32768 s, without overflowing for long uptimes
+fn rtc_units_to_ns(units: u64) -> u64 {
+    let base = u64::from(rtc::BASE_FREQUENCY);
+    units / base * NANOS_PER_SEC + units % base * NANOS_PER_SEC / base
+}
+
+/// Count an RTC periodic interrupt, `units` of 1/32768 s after the last.
+/// Called from the RTC interrupt handler.
+pub fn rtc_tick(units: u64) {
+    RTC_UNITS.fetch_add(units, Ordering::SeqCst);
+}
+
+/// Nanoseconds counted by `source` since boot
+fn source_ns(source: TickSource) -> u64 {
+    match source {
+        TickSource::Pit => crate::drivers::pit::uptime_us() * 1000,
+        TickSource::Rtc => rtc_units_to_ns(RTC_UNITS.load(Ordering::SeqCst)),
+    }
+}
+
+/// What drives the monotonic clock now
+pub fn tick_source() -> TickSource {
+    if RTC_SOURCE.load(Ordering::SeqCst) { TickSource::Rtc } else { TickSource::Pit }
+}
+
+/// Drive the monotonic clock from `source`. The RTC's periodic interrupt
+/// must be running first (see `rtc::enable_periodic`).
+pub fn set_tick_source(source: TickSource) -> Result<(), KernelError> {
+    if source == TickSource::Rtc && rtc::periodic_frequency() == 0 {
+        return Err(KernelError::NotInitialized);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let now = monotonic_ns();
+        SOURCE_BASE_NS.store(now, Ordering::SeqCst);
+        SOURCE_BASE_RAW_NS.store(source_ns(source), Ordering::SeqCst);
+        RTC_SOURCE.store(source == TickSource::Rtc, Ordering::SeqCst);
+    });
+    serial_println!("TIME: Monotonic clock now driven by the {:?}", source);
+    Ok(())
+}
+
+/// Nanoseconds since boot. Never decreases, and is safe in interrupt handlers.
+pub fn monotonic_ns() -> u64 {
+    let elapsed = source_ns(tick_source()).saturating_sub(SOURCE_BASE_RAW_NS.load(Ordering::SeqCst));
+    let now = SOURCE_BASE_NS.load(Ordering::SeqCst) + elapsed;
+    let last = LAST_MONOTONIC_NS.fetch_max(now, Ordering::SeqCst);
+    now.max(last)
+}
+
+/// Smallest step the monotonic clock takes: one tick of whatever drives
+/// it. None before that source is ticking.
+pub fn resolution_ns() -> Option<u64> {
+    let frequency = match tick_source() {
+        TickSource::Pit => crate::drivers::pit::frequency(),
+        TickSource::Rtc => rtc::periodic_frequency(),
+    };
+    (frequency > 0).then(|| NANOS_PER_SEC / u64::from(frequency))
+}
+
+/// Milliseconds since boot
+pub fn monotonic_ms() -> u64 {
+    monotonic_ns() / 1_000_000
+}
+
+fn with_wall_clock<R>(f: impl FnOnce(&mut WallClock) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut WALL_CLOCK.lock()))
+}
+
+/// Wall time in nanoseconds since the Unix epoch
+pub fn wall_clock_ns() -> u64 {
+    let now = monotonic_ns();
+    with_wall_clock(|clock| clock.at(now))
+}
+
+/// Wall time in whole seconds since the Unix epoch, as kept in file metadata
+pub fn wall_clock() -> u64 {
+    wall_clock_ns() / NANOS_PER_SEC
+}
+
+/// Wall time broken down into a date and time (UTC)
+pub fn now() -> DateTime {
+    DateTime::from_unix_seconds(wall_clock())
+}
+
+/// Set the wall clock to `unix_seconds`, and the RTC with it so the next
+/// resync agrees. This is the one way wall time can move backwards.
+pub fn set_wall_clock(unix_seconds: u64) -> Result<(), KernelError> {
+    rtc::set_datetime(&DateTime::from_unix_seconds(unix_seconds))?;
+    let now = monotonic_ns();
+    with_wall_clock(|clock| clock.set(now, unix_seconds * NANOS_PER_SEC));
+    LAST_RESYNC_NS.store(now, Ordering::SeqCst);
+    serial_println!("TIME: Wall clock set to {}", DateTime::from_unix_seconds(unix_seconds).format());
+    Ok(())
+}
+
+/// Seed the wall clock from the RTC
+pub fn init() {
+    let rtc_time = rtc::get_datetime();
+    let now = monotonic_ns();
+    with_wall_clock(|clock| clock.set(now, rtc_time.to_unix_seconds() * NANOS_PER_SEC));
+    LAST_RESYNC_NS.store(now, Ordering::SeqCst);
+    serial_println!("TIME: Wall clock seeded from the RTC: {}", rtc_time.format());
+}
+
+/// Compare the wall clock with the RTC if a resync is due. Reading the RTC
+/// can wait for its update cycle, so this runs from the idle path.
+pub fn poll() {
+    let now = monotonic_ns();
+    if now.saturating_sub(LAST_RESYNC_NS.load(Ordering::SeqCst)) < RESYNC_INTERVAL_SECS * NANOS_PER_SEC {
+        return;
+    }
+    let reference = rtc::get_datetime().to_unix_seconds() * NANOS_PER_SEC;
+    let now = monotonic_ns();
+    let pending = with_wall_clock(|clock| {
+        clock.resync(now, reference);
+        clock.pending
+    });
+    LAST_RESYNC_NS.store(now, Ordering::SeqCst);
+    if pending != 0 {
+        serial_println!("TIME: Wall clock is {} ms off the RTC, slewing", -pending / 1_000_000);
+    }
+}
+
+/// Check that a resync moving wall time backwards or forwards never makes
+/// the clock run backwards, that it converges, and the date conversions
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("TIME: Running self-test");
+
+    const SEC: u64 = NANOS_PER_SEC;
+    let mut clock = WallClock::new();
+    clock.set(5 * SEC, 1_000_000 * SEC);
+
+    // The RTC says we're 10 s fast: wall time must slow down, not jump back
+    let mut last = clock.at(10 * SEC);
+    clock.resync(10 * SEC, last - 10 * SEC);
+    if clock.at(10 * SEC) != last {
+        return Err(KernelError::ValidationError("Resync moved the wall clock at the resync instant"));
+    }
+    let mut now = 10 * SEC;
+    while now < 200 * SEC {
+        now += SEC / 4;
+        let wall = clock.at(now);
+        if wall < last {
+            return Err(KernelError::ValidationError("Wall clock went backwards after a resync"));
+        }
+        last = wall;
+        // Keep rebasing, as further resyncs would
+        if now % (7 * SEC) == 0 {
+            clock.rebase(now);
+        }
+    }
+    // 10 s of correction at a tenth of the rate takes 100 s
+    if clock.at(now) != 1_000_000 * SEC + (now - 5 * SEC) - 10 * SEC {
+        return Err(KernelError::ValidationError("Slewed wall clock didn't converge on the RTC"));
+    }
+
+    // Within the tolerance nothing changes; far behind steps forward
+    let before = clock.at(now);
+    clock.resync(now, before + SEC / 2);
+    if clock.pending != 0 || clock.at(now + SEC) != before + SEC {
+        return Err(KernelError::ValidationError("Resync reacted to RTC granularity"));
+    }
+    clock.resync(now, before + 3600 * SEC);
+    if clock.at(now) != before + 3600 * SEC {
+        return Err(KernelError::ValidationError("Wall clock far behind the RTC wasn't stepped"));
+    }
+
+    let first = monotonic_ns();
+    if monotonic_ns() < first {
+        return Err(KernelError::ValidationError("Monotonic clock went backwards"));
+    }
+
+    // 1024 Hz ticks are 32 units each; a day of them must not overflow
+    if rtc_units_to_ns(32 * 1024) != SEC || rtc_units_to_ns(16_384) != SEC / 2
+        || rtc_units_to_ns(86_400 * 32_768) != 86_400 * SEC {
+        return Err(KernelError::ValidationError("RTC ticks converted to the wrong time"));
+    }
+    if rtc::periodic_frequency() == 0 && set_tick_source(TickSource::Rtc).is_ok() {
+        return Err(KernelError::ValidationError("RTC tick source chosen with its interrupt off"));
+    }
+
+    let date = DateTime { second: 7, minute: 6, hour: 5, day: 29, month: 2, year: 2024 };
+    let round_trip = DateTime::from_unix_seconds(date.to_unix_seconds());
+    if round_trip.format() != date.format() || DateTime::from_unix_seconds(0).format() != "1970-01-01 00:00:00" {
+        return Err(KernelError::ValidationError("Unix time conversion doesn't round-trip"));
+    }
+
+    serial_println!("TIME: Self-test passed");
+    Ok(())
+}
@@ -1,6 +1,9 @@
 //! User management for the kernel.
 //! Handles user accounts, home directories, and permissions.
 
+pub mod motd;
+pub mod password;
+pub mod skel;
 pub mod welcome; // Welcome screen module
 
 use alloc::format;
@@ -8,6 +11,7 @@ use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::config;
 use crate::errors::KernelError;
 use crate::fs;
 use crate::fs::vfs::get_vfs_manager;
@@ -73,7 +77,13 @@ impl UserManager {
         user.full_name = full_name.to_string();
         
         // Create user directories
-        self.create_user_directories(&user)?;
+        match self.create_user_directories(&user) {
+            Ok(()) => {}
+            // The account still works, just without a home to write to
+            Err(KernelError::ReadOnlyFilesystem) => crate::logger::warning("user",
+                &format!("{}'s home {} is on a read-only file system; skipping its folders", username, user.home_dir)),
+            Err(e) => return Err(e),
+        }
         
         // Add to our list
         self.users.push(user);
@@ -81,40 +91,19 @@ impl UserManager {
         Ok(self.users.last().unwrap())
     }
     
-    /// Create the standard directories for a user
+    /// Create a user's home directory, filled from the skeleton (see `skel`)
     fn create_user_directories(&self, user: &User) -> Result<(), KernelError> {
         // Get the VFS
         let vfs = get_vfs_manager().ok_or(KernelError::NotInitialized)?;
         
-        // Create home directory
         serial_println!("Creating home directory: {}", user.home_dir);
-        vfs.create_directory(&user.home_dir)?;
-        
-        // Create standard subdirectories
-        let dirs = [
-            "Documents",
-            "Downloads",
-            "Desktop",
-            "Pictures",
-            "Music",
-            "Movies",
-            "Library",
-            "Library/Preferences",
-            "Library/Application Support",
-        ];
-        
-        for dir in dirs.iter() {
-            let path = format!("{}/{}", user.home_dir, dir);
-            serial_println!("Creating directory: {}", path);
-            vfs.create_directory(&path)?;
-        }
-        
-        Ok(())
+        skel::populate(vfs, &user.home_dir, user.uid, user.gid)
     }
     
     /// Set the current active user
     pub fn set_current_user(&mut self, uid: u32) -> Result<(), KernelError> {
-        if self.users.iter().any(|u| u.uid == uid) {
+        if let Some(user) = self.users.iter().find(|u| u.uid == uid) {
+            *CURRENT_CREDENTIALS.lock() = (user.uid, user.gid);
             self.current_user = Some(uid);
             Ok(())
         } else {
@@ -122,6 +111,12 @@ impl UserManager {
         }
     }
     
+    /// Leave nobody logged in
+    pub fn clear_current_user(&mut self) {
+        *CURRENT_CREDENTIALS.lock() = (0, 0);
+        self.current_user = None;
+    }
+    
     /// Get the current active user
     pub fn get_current_user(&self) -> Option<&User> {
         if let Some(uid) = self.current_user {
@@ -142,6 +137,26 @@ impl UserManager {
     }
 }
 
+/// uid and gid of the current user, kept apart from USER_MANAGER so the
+/// VFS can check access while the user table is locked, as it is while
+/// `add_user` fills a new home
+static CURRENT_CREDENTIALS: Mutex<(u32, u32)> = Mutex::new((0, 0));
+
+/// The current user's uid and gid; root's when nobody is logged in
+pub fn current_credentials() -> (u32, u32) {
+    *CURRENT_CREDENTIALS.lock()
+}
+
+/// Run `f` with root's file access, for the few things any user may do to
+/// files only root can touch, such as checking their own password. Nothing
+/// else runs meanwhile, as tasks don't switch.
+pub fn as_root<T>(f: impl FnOnce() -> T) -> T {
+    let saved = core::mem::replace(&mut *CURRENT_CREDENTIALS.lock(), (0, 0));
+    let result = f();
+    *CURRENT_CREDENTIALS.lock() = saved;
+    result
+}
+
 lazy_static! {
     pub static ref USER_MANAGER: Mutex<UserManager> = Mutex::new(UserManager::new());
 }
@@ -176,6 +191,9 @@ pub fn init() -> Result<(), KernelError> {
     Ok(())
 }
 
+/// Test program for the ELF loader (source in kernel/assets/hello.S)
+const HELLO_BINARY: &[u8] = include_bytes!("../../assets/hello.elf");
+
 /// Set up initial file system for a new system
 pub fn setup_filesystem() -> Result<(), KernelError> {
     serial_println!("Setting up initial file system structure");
@@ -191,7 +209,9 @@ pub fn setup_filesystem() -> Result<(), KernelError> {
         "/Applications",
         "/Users",
         "/root",
-        "/tmp"
+        "/tmp",
+        "/bin",
+        "/etc"
     ];
 
     // Create only top-level directories to avoid the problematic paths
@@ -210,32 +230,98 @@ pub fn setup_filesystem() -> Result<(), KernelError> {
         for _ in 0..5000 { core::hint::spin_loop(); }
     }
 
+    // Wallpaper images are looked up here by bare file name
+    match vfs.create_directory(crate::gui::wallpaper::WALLPAPER_DIR) {
+        Ok(_) | Err(KernelError::AlreadyExists) => {}
+        Err(e) => serial_println!("ERROR creating {}: {:?}", crate::gui::wallpaper::WALLPAPER_DIR, e),
+    }
+
+    // Skeleton new homes are copied from, unless one was left by an earlier boot
+    match skel::install_default() {
+        Ok(true) => serial_println!("Created {}", skel::SKEL_DIR),
+        Ok(false) => serial_println!("Keeping the existing {}", skel::SKEL_DIR),
+        Err(e) => serial_println!("ERROR creating {}: {:?}", skel::SKEL_DIR, e),
+    }
+
     // AVOID creating System/Library/Frameworks which causes the hang
     serial_println!("IMPORTANT: Skipping creation of /System/Library/Frameworks and other deep paths");
     serial_println!("Those paths will be created on demand if needed");
 
-    // Create welcome message in root directory
-    serial_println!("Creating welcome.txt file");
-    let welcome_message = b"Welcome to UniverseK OS!\nThis is a basic Unix-like operating system.\n";
-    match vfs.create_file("/welcome.txt") {
-        Ok(_) => {
-            // Write to the file
-            serial_println!("Writing welcome message to welcome.txt");
-            match fs::direct_write_file("/welcome.txt", welcome_message) {
-                Ok(bytes) => serial_println!("Successfully wrote {} bytes to welcome.txt", bytes),
-                Err(e) => serial_println!("ERROR writing to welcome.txt: {:?}", e),
-            }
+    // Message of the day, unless one was left by an earlier boot
+    match motd::install_default() {
+        Ok(true) => serial_println!("Wrote the default {}", motd::MOTD_PATH),
+        Ok(false) => serial_println!("Keeping the existing {}", motd::MOTD_PATH),
+        Err(e) => serial_println!("ERROR writing {}: {:?}", motd::MOTD_PATH, e),
+    }
+    
+    // Install the bundled test program
+    match vfs.create_file("/bin/hello") {
+        Ok(_) | Err(KernelError::AlreadyExists) => match fs::direct_write_file("/bin/hello", HELLO_BINARY) {
+            Ok(bytes) => serial_println!("Installed /bin/hello ({} bytes)", bytes),
+            Err(e) => serial_println!("ERROR writing /bin/hello: {:?}", e),
         },
-        Err(KernelError::AlreadyExists) => serial_println!("File already exists: /welcome.txt"),
-        Err(e) => serial_println!("ERROR creating welcome.txt: {:?}", e),
+        Err(e) => serial_println!("ERROR creating /bin/hello: {:?}", e),
     }
     
     serial_println!("Filesystem setup completed - skipped problematic paths");
     Ok(())
 }
 
+/// Home directory of the current user ($HOME), or root's when nobody is
+/// logged in
+pub fn home_dir() -> String {
+    USER_MANAGER.lock().get_current_user()
+        .map(|user| user.home_dir.clone())
+        .unwrap_or_else(|| "/root".to_string())
+}
+
+/// Make `username` the current user and load their preferences over the
+/// last user's (see `config::load_user`)
+pub fn log_in(username: &str) -> Result<(), KernelError> {
+    let home = {
+        let mut manager = USER_MANAGER.lock();
+        let uid = manager.users.iter().find(|user| user.username == username)
+            .map(|user| user.uid).ok_or(KernelError::NotFound)?;
+        manager.set_current_user(uid)?;
+        manager.get_current_user().map(|user| user.home_dir.clone())
+    };
+    serial_println!("USER: {} logged in", username);
+    config::load_user(home.as_deref())
+}
+
+/// Log in `user.default` at boot when `user.auto_login` is set
+pub fn auto_login() -> Result<(), KernelError> {
+    if !config::get("user.auto_login").and_then(|value| value.try_as_boolean()).unwrap_or(false) {
+        return Ok(());
+    }
+    let name = config::get("user.default").map(|value| value.as_string()).ok_or(KernelError::NotFound)?;
+    log_in(&name)
+}
+
+/// Login name of the current user, if anyone is logged in
+pub fn current_username() -> Option<String> {
+    USER_MANAGER.lock().get_current_user().map(|user| user.username.clone())
+}
+
+/// Every account, root and system included, in the order they were made
+pub fn list_users() -> Vec<User> {
+    USER_MANAGER.lock().users.clone()
+}
+
+/// Whether `name` can be a login name: a lowercase letter, then up to 31
+/// lowercase letters, digits, '-' or '_'
+pub fn valid_username(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && name.len() <= 32
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
 /// Create a new user with default settings
 pub fn create_user(username: &str, full_name: &str) -> Result<(), KernelError> {
+    if !valid_username(username) {
+        return Err(KernelError::InvalidParameter);
+    }
     USER_MANAGER.lock().add_user(username, full_name)?;
     Ok(())
 }
@@ -253,8 +339,8 @@ pub fn run_new_user_setup() -> Result<(), KernelError> {
     match create_user(username, full_name) {
         Ok(_) => {
             serial_println!("Created default user: {} ({})", full_name, username);
-            // Set as current user
-            USER_MANAGER.lock().set_current_user(1000)?;
+            // Log them in
+            log_in(username)?;
         },
         Err(KernelError::AlreadyExists) => {
             serial_println!("Default user already exists");
@@ -0,0 +1,109 @@
+//! Message of the day: /etc/motd, shown when a shell or terminal starts
+//!
+//! `setup_filesystem` writes a default one, with the version and build
+//! date, if there's none yet. Showing it never fails: a missing or
+//! unreadable file just shows nothing, and a long one is cut short.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::errors::KernelError;
+use crate::fs;
+use crate::serial_println;
+use crate::version;
+
+pub const MOTD_PATH: &str = "/etc/motd";
+
+/// Lines shown at most; more end with TRUNCATED
+pub const MAX_LINES: usize = 10;
+
+/// Last line shown in place of the rest of a long message
+pub const TRUNCATED: &str = "(truncated)";
+
+/// Largest message read
+const MAX_FILE_SIZE: usize = 4096;
+
+/// The message written at first boot
+pub fn default_text() -> String {
+    format!("Welcome to {} (built {})!\nThis is a basic Unix-like operating system.\n\
+        Type 'help' for a list of commands; 'motd set' changes this message.\n",
+        version::name_and_version(), version::BUILD_DATE)
+}
+
+/// Write the default message unless there is one already; returns whether
+/// it was written
+pub fn install_default() -> Result<bool, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    match vfs.metadata(MOTD_PATH) {
+        Ok(_) => Ok(false),
+        Err(KernelError::NotFound) => {
+            vfs.write_file_atomic(MOTD_PATH, default_text().as_bytes())?;
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace the message with `text`, which gets a final newline if it has
+/// none
+pub fn set(text: &str) -> Result<(), KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let mut text = text.to_string();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    vfs.write_file_atomic(MOTD_PATH, text.as_bytes())
+}
+
+/// The first `max` lines of `content`, with TRUNCATED in place of the rest
+/// if there's more
+pub fn capped(content: &str, max: usize) -> Vec<String> {
+    let mut lines: Vec<String> = content.lines().take(max + 1).map(|line| line.to_string()).collect();
+    if lines.len() > max {
+        lines.truncate(max);
+        lines.push(String::from(TRUNCATED));
+    }
+    lines
+}
+
+/// The message to show, up to MAX_LINES lines; none if the file is
+/// missing or can't be read
+pub fn lines() -> Vec<String> {
+    let read = || -> Result<String, KernelError> {
+        let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+        let size = (vfs.metadata(MOTD_PATH)?.size as usize).min(MAX_FILE_SIZE);
+        let mut data = alloc::vec![0u8; size];
+        let read = vfs.find_fs(MOTD_PATH)?.lock().read_at(MOTD_PATH, 0, &mut data)?;
+        data.truncate(read);
+        // A file cut off at MAX_FILE_SIZE may end mid-character
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    };
+    match read() {
+        Ok(content) => capped(&content, MAX_LINES),
+        Err(KernelError::NotFound) => Vec::new(),
+        Err(e) => {
+            serial_println!("DEBUG: motd - can't read {}: {:?}", MOTD_PATH, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Check the default message and how long messages are cut short
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("MOTD: Running self-test");
+
+    let text = default_text();
+    if !text.contains(version::VERSION) || !text.contains(version::BUILD_DATE) {
+        return Err(KernelError::ValidationError("Default motd is missing the version or build date"));
+    }
+
+    let long: String = (1..=12).map(|n| format!("line {}\n", n)).collect();
+    let lines = capped(&long, MAX_LINES);
+    if lines.len() != MAX_LINES + 1 || lines[MAX_LINES - 1] != "line 10" || lines[MAX_LINES] != TRUNCATED
+        || capped("one\ntwo\n", 2) != ["one", "two"] || !capped("", MAX_LINES).is_empty() {
+        return Err(KernelError::ValidationError("motd capped wrongly"));
+    }
+
+    serial_println!("MOTD: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Passwords, kept in /etc/shadow as `name:salt:hash` lines
+//!
+//! The hash is many rounds of FNV-1a over a per-user salt and the password.
+//! That keeps passwords out of plain sight on disk and makes every user's
+//! hash different, but it is not a cryptographic hash: anyone who can read
+//! the file can guess short passwords quickly.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::errors::KernelError;
+use crate::fs;
+use crate::fs::vfs::{file_flags, permissions, MetadataUpdate};
+use crate::serial_println;
+use crate::user;
+
+pub const SHADOW_PATH: &str = "/etc/shadow";
+
+/// Largest password file read
+const MAX_FILE_SIZE: usize = 16 * 1024;
+
+/// Times the hash goes over the salt and password
+const ROUNDS: usize = 4096;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn hash(salt: u64, password: &str) -> u64 {
+    let mut state = FNV_OFFSET;
+    for _ in 0..ROUNDS {
+        for &byte in salt.to_le_bytes().iter().chain(password.as_bytes()).chain(&state.to_le_bytes()) {
+            state = (state ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+    }
+    state
+}
+
+/// A salt that differs from call to call
+fn new_salt() -> u64 {
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    (tsc ^ crate::time::wall_clock().rotate_left(32)).wrapping_mul(FNV_PRIME)
+}
+
+/// (name, salt, hash) from a line of the password file
+fn parse_line(line: &str) -> Option<(&str, u64, u64)> {
+    let mut fields = line.split(':');
+    let name = fields.next()?;
+    let salt = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let hash = u64::from_str_radix(fields.next()?, 16).ok()?;
+    Some((name, salt, hash))
+}
+
+/// The password file, empty if there's none. Only root may read it, so
+/// this reads as root for whoever is logged in, as the file is theirs to
+/// check their own password against.
+fn read_file() -> Result<String, KernelError> {
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    let mut handle = match user::as_root(|| vfs.open(SHADOW_PATH, file_flags::READ)) {
+        Ok(handle) => handle,
+        Err(KernelError::NotFound) => return Ok(String::new()),
+        Err(e) => return Err(e),
+    };
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 512];
+    let result = loop {
+        match handle.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(count) if data.len() + count > MAX_FILE_SIZE => break Err(KernelError::InvalidData),
+            Ok(count) => data.extend_from_slice(&buffer[..count]),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = handle.close();
+    result?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// `content` with `name`'s line replaced by `entry`, or dropped for None
+fn replace_entry(content: &str, name: &str, entry: Option<(u64, u64)>) -> String {
+    let mut lines: Vec<String> = content.lines()
+        .filter(|line| !parse_line(line).is_some_and(|(other, _, _)| other == name))
+        .map(String::from)
+        .collect();
+    if let Some((salt, hash)) = entry {
+        lines.push(format!("{}:{:016x}:{:016x}", name, salt, hash));
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Whether `password` matches `name`'s line in `content`
+fn check(content: &str, name: &str, password: &str) -> bool {
+    content.lines().filter_map(parse_line)
+        .find(|(other, _, _)| *other == name)
+        .is_some_and(|(_, salt, expected)| hash(salt, password) == expected)
+}
+
+/// Give `name` the password `password`, or none if it's empty
+pub fn set(name: &str, password: &str) -> Result<(), KernelError> {
+    if name.is_empty() || name.contains(':') {
+        return Err(KernelError::InvalidParameter);
+    }
+    let entry = (!password.is_empty()).then(|| {
+        let salt = new_salt();
+        (salt, hash(salt, password))
+    });
+    let content = replace_entry(&read_file()?, name, entry);
+    let vfs = fs::vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    // The replacement is a new file, so it gets root's ownership and mode
+    // again each time
+    user::as_root(|| {
+        vfs.write_file_atomic(SHADOW_PATH, content.as_bytes())?;
+        vfs.set_metadata(SHADOW_PATH, MetadataUpdate {
+            permissions: Some(permissions::READ | permissions::WRITE),
+            uid: Some(0),
+            gid: Some(0),
+            ..Default::default()
+        })
+    })
+}
+
+/// Whether `name` has a password; false if the file can't be read
+pub fn has_password(name: &str) -> bool {
+    read_file().is_ok_and(|content| content.lines().filter_map(parse_line).any(|(other, _, _)| other == name))
+}
+
+/// Whether `password` is `name`'s. Someone with no password has no
+/// password to match.
+pub fn verify(name: &str, password: &str) -> bool {
+    match read_file() {
+        Ok(content) => check(&content, name, password),
+        Err(e) => {
+            serial_println!("PASSWORD: Can't read {}: {:?}", SHADOW_PATH, e);
+            false
+        }
+    }
+}
+
+/// Check hashing, matching and replacing lines, without the file
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("PASSWORD: Running self-test");
+
+    if hash(1, "secret") != hash(1, "secret") || hash(1, "secret") == hash(2, "secret")
+        || hash(1, "secret") == hash(1, "secreT") {
+        return Err(KernelError::ValidationError("Password hash isn't salted or stable"));
+    }
+
+    let content = replace_entry("", "alice", Some((7, hash(7, "apple"))));
+    let content = replace_entry(&content, "bob", Some((9, hash(9, "pear"))));
+    if !check(&content, "alice", "apple") || check(&content, "alice", "pear") || check(&content, "carol", "")
+        || !check(&content, "bob", "pear") {
+        return Err(KernelError::ValidationError("Password matched wrongly"));
+    }
+    let content = replace_entry(&content, "alice", Some((8, hash(8, "plum"))));
+    let content = replace_entry(&content, "bob", None);
+    if content.lines().count() != 1 || !check(&content, "alice", "plum") || check(&content, "alice", "apple") {
+        return Err(KernelError::ValidationError("Password line replaced wrongly"));
+    }
+
+    serial_println!("PASSWORD: Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Home directory skeleton: /etc/skel
+//!
+//! A new user's home starts as a copy of /etc/skel, handed over to them, so
+//! what new users get is changed by editing it rather than the kernel.
+//! `setup_filesystem` creates it with the standard folders and a few
+//! starter files if it's missing; without one, homes get the standard
+//! folders only.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use crate::errors::KernelError;
+use crate::fs::path::Path;
+use crate::fs::tempfs::TempFs;
+use crate::fs::trash;
+use crate::fs::vfs::{self, MetadataUpdate, MountFlags, NodeType, VfsManager};
+use crate::fs::walk::{self, Visitor, WalkEntry};
+use crate::serial_println;
+use crate::sync::DiagMutex;
+use super::UserManager;
+
+pub const SKEL_DIR: &str = "/etc/skel";
+
+/// Folders every home gets without a skeleton, parents first
+pub const STANDARD_DIRS: &[&str] = &[
+    "Documents",
+    "Downloads",
+    "Desktop",
+    "Pictures",
+    "Music",
+    "Movies",
+    "Library",
+    "Library/Preferences",
+    "Library/Application Support",
+];
+
+/// Files a new skeleton starts with, as (name, contents)
+fn starter_files() -> [(&'static str, String); 2] {
+    [
+        (".aliases", String::from("# Shell aliases, one per line as name=command\n")),
+        ("README", format!("Welcome to your home directory on {}.\n\n\
+            Everything here was copied from {} when your account was made,\n\
+            and it's all yours to change. 'help' in the shell lists what you can do.\n",
+            crate::version::name_and_version(), SKEL_DIR)),
+    ]
+}
+
+/// Create /etc/skel with the standard folders and starter files unless it
+/// exists already; returns whether it was created
+pub fn install_default() -> Result<bool, KernelError> {
+    let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    match vfs.metadata(SKEL_DIR) {
+        Ok(_) => return Ok(false),
+        Err(KernelError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    vfs.create_directory(SKEL_DIR)?;
+    create_standard_dirs(vfs, SKEL_DIR)?;
+    for (name, text) in starter_files() {
+        vfs.write_file_atomic(Path::new(SKEL_DIR).join(name).as_str(), text.as_bytes())?;
+    }
+    Ok(true)
+}
+
+fn create_standard_dirs(vfs: &VfsManager, base: &str) -> Result<(), KernelError> {
+    for dir in STANDARD_DIRS {
+        match vfs.create_directory(Path::new(base).join(dir).as_str()) {
+            Ok(()) | Err(KernelError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Make the home directory `home` and fill it from /etc/skel, or with the
+/// standard folders if there's no skeleton, then give all of it to
+/// `uid`/`gid`. Anything the home has already is left alone.
+pub fn populate(vfs: &VfsManager, home: &str, uid: u32, gid: u32) -> Result<(), KernelError> {
+    match vfs.create_directory(home) {
+        Ok(()) | Err(KernelError::AlreadyExists) => {}
+        Err(e) => return Err(e),
+    }
+
+    match vfs.metadata(SKEL_DIR) {
+        Ok(metadata) if metadata.node_type == NodeType::Directory => {
+            for entry in vfs.read_dir(SKEL_DIR)? {
+                let to = Path::new(home).join(&entry.name);
+                if entry.name == "." || entry.name == ".." || vfs.metadata(to.as_str()).is_ok() {
+                    continue;
+                }
+                vfs.copy_tree(Path::new(SKEL_DIR).join(&entry.name).as_str(), to.as_str())?;
+            }
+        }
+        _ => {
+            serial_println!("USER: No {}, giving {} the standard folders", SKEL_DIR, home);
+            create_standard_dirs(vfs, home)?;
+        }
+    }
+
+    let mut owner = GiveTo {
+        vfs,
+        update: MetadataUpdate { uid: Some(uid), gid: Some(gid), ..Default::default() },
+        failed: None,
+    };
+    walk::walk(home, &mut owner)?;
+    owner.failed.map_or(Ok(()), Err)
+}
+
+/// Changes the owner of everything walked
+struct GiveTo<'a> {
+    vfs: &'a VfsManager,
+    update: MetadataUpdate,
+    failed: Option<KernelError>,
+}
+
+impl Visitor for GiveTo<'_> {
+    fn enter(&mut self, entry: &WalkEntry) {
+        match self.vfs.set_metadata(entry.path, self.update) {
+            // File systems that don't record owners are left as they are
+            Ok(()) | Err(KernelError::UnsupportedFeature) | Err(KernelError::NotImplemented) => {}
+            Err(e) => {
+                self.failed.get_or_insert(e);
+            }
+        }
+    }
+
+    fn error(&mut self, _path: &str, error: KernelError) {
+        self.failed.get_or_insert(error);
+    }
+}
+
+/// Add a file to the skeleton and check a new user's home gets it, owned
+/// by them, and that a user whose home is on a read-only mount is still
+/// created
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("SKEL: Running self-test");
+    let vfs = vfs::get_vfs_manager().ok_or(KernelError::NotInitialized)?;
+    if vfs.metadata(SKEL_DIR).is_err() || vfs.is_read_only(SKEL_DIR) {
+        serial_println!("SKEL: No writable {}, skipping the self-test", SKEL_DIR);
+        return Ok(());
+    }
+
+    let marker = Path::new(SKEL_DIR).join(".skel-selftest");
+    vfs.write_file_atomic(marker.as_str(), b"copied\n")?;
+    let mut manager = UserManager::new();
+    let result = (|| {
+        let user = manager.add_user("skeltest", "Skeleton Test")?;
+        let home = vfs.metadata(&user.home_dir)?;
+        let copied = vfs.metadata(Path::new(&user.home_dir).join(".skel-selftest").as_str())?;
+        let folder = vfs.metadata(Path::new(&user.home_dir).join("Documents").as_str())?;
+        if copied.size != 7 || folder.node_type != NodeType::Directory {
+            return Err(KernelError::ValidationError("New home is missing the skeleton"));
+        }
+        // A file system that doesn't record owners leaves everything root's
+        let owned = |metadata: &vfs::Metadata| (metadata.uid, metadata.gid) == (user.uid, user.gid);
+        if home.uid != 0 && !(owned(&home) && owned(&copied) && owned(&folder)) {
+            return Err(KernelError::ValidationError("New home isn't owned by its user"));
+        }
+        Ok(())
+    })();
+    let _ = trash::remove_tree(vfs, "/Users/skeltest");
+    let _ = vfs.remove_permanently(marker.as_str());
+    result?;
+
+    let home = "/Users/skelro";
+    let _ = vfs.create_directory(home);
+    let tempfs = TempFs::new("skel-selftest");
+    vfs.mount(home, Arc::new(DiagMutex::new("fs:skel-selftest", tempfs)), MountFlags::READ_ONLY)?;
+    let created = UserManager::new().add_user("skelro", "Read-only Test").map(|_| ());
+    vfs.unmount(home)?;
+    let _ = vfs.remove_permanently(home);
+    if created.is_err() {
+        return Err(KernelError::ValidationError("User with a read-only home wasn't created"));
+    }
+
+    serial_println!("SKEL: Self-test passed");
+    Ok(())
+}
@@ -63,7 +63,7 @@ fn draw_system_info() -> Result<(), KernelError> {
         Color::White, Color::Black);
     
     // Get date/time
-    let now = crate::drivers::rtc::get_datetime();
+    let now = crate::time::now();
     vga_enhanced::write_at(6, 10, &format!("Date/Time: {}", now.format()),
         Color::White, Color::Black);
     
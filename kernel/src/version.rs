@@ -0,0 +1,88 @@
+//! What this kernel is and how it was built
+//!
+//! Everything here is fixed at compile time: the version from Cargo.toml,
+//! and the commit, build time and compiler from build.rs, which puts
+//! "unknown" for any it can't find out. Whatever reports the version reads
+//! it from here.
+
+use alloc::format;
+use alloc::string::String;
+use crate::errors::KernelError;
+use crate::serial_println;
+
+/// Name of the system, as in the About window and the motd
+pub const NAME: &str = "UniverseK OS";
+/// Kernel name, the first word `uname` prints
+pub const SYSNAME: &str = "UniverseK";
+/// Version from Cargo.toml, as "major.minor.patch"
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const MAJOR: u32 = parse_number(env!("CARGO_PKG_VERSION_MAJOR"));
+pub const MINOR: u32 = parse_number(env!("CARGO_PKG_VERSION_MINOR"));
+pub const PATCH: u32 = parse_number(env!("CARGO_PKG_VERSION_PATCH"));
+/// Short hash of the commit built, "-dirty" if it had changes, or "unknown"
+pub const GIT_HASH: &str = env!("UNIVERSEK_GIT_HASH");
+/// Build day, YYYY-MM-DD (UTC)
+pub const BUILD_DATE: &str = env!("UNIVERSEK_BUILD_DATE");
+/// Build moment, YYYY-MM-DD HH:MM:SS (UTC)
+pub const BUILD_TIME: &str = env!("UNIVERSEK_BUILD_TIME");
+/// What `rustc --version` said, or "unknown"
+pub const RUSTC_VERSION: &str = env!("UNIVERSEK_RUSTC_VERSION");
+/// Architecture the kernel runs on
+pub const MACHINE: &str = "x86_64";
+
+/// A decimal number from Cargo's version parts; 0 for anything else
+const fn parse_number(text: &str) -> u32 {
+    let bytes = text.as_bytes();
+    let mut value: u32 = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        if !bytes[index].is_ascii_digit() {
+            return 0;
+        }
+        value = value * 10 + (bytes[index] - b'0') as u32;
+        index += 1;
+    }
+    value
+}
+
+/// "UniverseK OS 0.1.0", for titles and greetings
+pub fn name_and_version() -> String {
+    format!("{} {}", NAME, VERSION)
+}
+
+/// The line `uname -a` prints: kernel name, version, commit, build time
+/// and machine
+pub fn uname_line() -> String {
+    format!("{} {} #{} {} UTC {}", SYSNAME, VERSION, GIT_HASH, BUILD_TIME, MACHINE)
+}
+
+/// /proc/version
+pub fn proc_text() -> String {
+    format!("{} version {} (git {}) ({}) built {} UTC\n", SYSNAME, VERSION, GIT_HASH, RUSTC_VERSION, BUILD_TIME)
+}
+
+/// Check the version parts agree with the version string and the build
+/// details are all filled in
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("VERSION: Running self-test");
+
+    if format!("{}.{}.{}", MAJOR, MINOR, PATCH) != VERSION {
+        serial_println!("VERSION: {}.{}.{} doesn't match {}", MAJOR, MINOR, PATCH, VERSION);
+        return Err(KernelError::ValidationError("Version parts disagree with the version"));
+    }
+    if parse_number("42") != 42 || parse_number("") != 0 || parse_number("1-rc") != 0 {
+        return Err(KernelError::ValidationError("Version part parsed wrongly"));
+    }
+    if [GIT_HASH, BUILD_DATE, BUILD_TIME, RUSTC_VERSION].iter().any(|value| value.is_empty())
+        || !BUILD_TIME.starts_with(BUILD_DATE) {
+        return Err(KernelError::ValidationError("Build details missing"));
+    }
+    let line = uname_line();
+    if !line.starts_with(SYSNAME) || !line.contains(VERSION) || !line.ends_with(MACHINE)
+        || !proc_text().contains(GIT_HASH) {
+        return Err(KernelError::ValidationError("Version lines left something out"));
+    }
+
+    serial_println!("VERSION: Self-test passed");
+    Ok(())
+}
@@ -2,7 +2,7 @@
 use volatile::Volatile;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::DiagMutex;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -55,6 +55,8 @@ pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    /// A BEL was written; rung once the writer is unlocked
+    bell_pending: bool,
 }
 
 impl Writer {
@@ -80,12 +82,14 @@ impl Writer {
     }
 
     fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe), // Print ■
+        for c in s.chars() {
+            match c {
+                '\n' => self.write_byte(b'\n'),
+                '\x07' => self.bell_pending = true,
+                // control characters have no glyph
+                c if c.is_control() => self.write_byte(0xfe), // Print ■
+                // printable text, converted to CP437
+                c => self.write_byte(crate::drivers::cp437::encode(c)),
             }
         }
     }
@@ -120,10 +124,11 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: DiagMutex<Writer> = DiagMutex::new("vga_buffer::WRITER", Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        bell_pending: false,
     });
 }
 
@@ -145,7 +150,13 @@ pub fn _print(args: fmt::Arguments) {
 
     // Disable interrupts to prevent deadlock if an interrupt handler
     // also tries to print
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+    let bell = interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_fmt(args).unwrap();
+        core::mem::take(&mut writer.bell_pending)
     });
+    // The bell reads the settings, so it rings outside the writer lock
+    if bell {
+        crate::gui::notify::bell(None);
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,216 @@
+//! Switching between the desktop and the full-screen console
+//!
+//! The machine is either in GUI mode, running `gui::run`, or in console
+//! mode, running the full-screen shell. `run` drives whichever is current;
+//! Ctrl+Alt+F1/F2 (or Ctrl+Alt+Enter, for hosts that keep those keys for
+//! themselves), `startx` and `exitgui` ask for a switch, and the running
+//! loop returns so the other can start.
+//!
+//! Leaving the desktop saves the session and stops its loop: the windows
+//! stay open, but nothing redraws them or runs their frame hooks until it's
+//! back, and then the screen is copied back from the compositor's back
+//! buffer. `run` owns the console's shell, which keeps its history and
+//! directory between visits; the desktop's terminals have shells of their
+//! own. With no desktop to go back to, Esc at the console offers a reboot.
+//! Input queued at a switch is dropped, so keys meant for one side never
+//! reach the other.
+
+use alloc::format;
+use spin::Mutex;
+use crate::config;
+use crate::drivers::ps2_keyboard::{self, KeyCode, KeyEvent, KeyState};
+use crate::drivers::ps2_mouse;
+use crate::errors::KernelError;
+use crate::{gui, safe_mode, serial_println, shell};
+use crate::shell::{ExitPolicy, Shell};
+
+/// What has the screen and keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Gui,
+    Console,
+}
+
+struct State {
+    current: Mode,
+    /// Switch asked for but not yet made
+    requested: Option<Mode>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State { current: Mode::Console, requested: None });
+
+/// Whether `mode` can be switched to: the desktop needs a display and a
+/// GUI that came up, which safe mode doesn't start
+pub fn available(mode: Mode) -> bool {
+    match mode {
+        Mode::Gui => gui::is_initialized() && !safe_mode::is_active(),
+        Mode::Console => true,
+    }
+}
+
+/// The mode to boot into: the desktop if `boot.start_gui` is on (the
+/// default) and it's available, otherwise the console
+pub fn initial_mode() -> Mode {
+    let start_gui = config::get("boot.start_gui").and_then(|value| value.try_as_boolean()).unwrap_or(true);
+    if start_gui && available(Mode::Gui) { Mode::Gui } else { Mode::Console }
+}
+
+/// Pick the mode `run` starts in
+pub fn init() {
+    let mode = initial_mode();
+    *STATE.lock() = State { current: mode, requested: None };
+    serial_println!("VT: Starting in {:?} mode", mode);
+}
+
+pub fn current() -> Mode {
+    STATE.lock().current
+}
+
+/// Ask the running mode to hand over to `mode`. Asking for the current
+/// mode does nothing.
+pub fn request(mode: Mode) -> Result<(), KernelError> {
+    if !available(mode) {
+        return Err(KernelError::NotInitialized);
+    }
+    let mut state = STATE.lock();
+    state.requested = (mode != state.current).then_some(mode);
+    Ok(())
+}
+
+/// Whether the running mode should return so `run` can switch
+pub fn switch_pending() -> bool {
+    STATE.lock().requested.is_some()
+}
+
+/// The mode a key press switches to: Ctrl+Alt+F1 the console, Ctrl+Alt+F2
+/// the desktop, and Ctrl+Alt+Enter whichever isn't current
+pub fn hotkey(event: &KeyEvent, current: Mode) -> Option<Mode> {
+    if event.state != KeyState::Pressed || !(event.ctrl && event.alt) {
+        return None;
+    }
+    match event.code {
+        KeyCode::F1 => Some(Mode::Console),
+        KeyCode::F2 => Some(Mode::Gui),
+        KeyCode::Enter => Some(match current {
+            Mode::Gui => Mode::Console,
+            Mode::Console => Mode::Gui,
+        }),
+        _ => None,
+    }
+}
+
+/// Act on a switch hotkey; returns whether `event` was one. `Err` means
+/// the mode it asks for isn't available.
+pub fn handle_hotkey(event: &KeyEvent) -> Option<Result<(), KernelError>> {
+    hotkey(event, current()).map(request)
+}
+
+/// What follows when the loop for `left` returns: the mode asked for, or
+/// if it ended by itself (Ctrl+Alt+Q, Esc in the shell) the other one if
+/// it's available. None leaves nothing running.
+fn next_mode(left: Mode, requested: Option<Mode>, gui_available: bool) -> Option<Mode> {
+    match (requested, left) {
+        (Some(mode), _) => Some(mode),
+        (None, Mode::Gui) => Some(Mode::Console),
+        (None, Mode::Console) => gui_available.then_some(Mode::Gui),
+    }
+}
+
+/// Drop queued keys and mouse movement, so none meant for the mode being
+/// left reach the next one
+fn drain_input() {
+    while ps2_keyboard::get_event().is_some() {}
+    while ps2_mouse::get_event().is_some() {}
+}
+
+/// Run `mode` until it ends; the console runs `console`, made on its first
+/// visit
+fn run_mode(mode: Mode, console: &mut Option<Shell>) -> Result<(), KernelError> {
+    match mode {
+        Mode::Gui => gui::run(),
+        Mode::Console => {
+            let session = console.get_or_insert_with(|| {
+                let mut shell = Shell::new();
+                shell.init();
+                shell
+            });
+            let policy = if available(Mode::Gui) { ExitPolicy::ReturnToCaller } else { ExitPolicy::RebootPrompt };
+            let exit = shell::run(session, policy)?;
+            serial_println!("VT: Console ended: {:?}", exit);
+            Ok(())
+        }
+    }
+}
+
+/// Run the desktop or the console, switching between them as asked, until
+/// one ends with nothing to go back to
+pub fn run() {
+    let mut console = None;
+    loop {
+        let mode = current();
+        drain_input();
+        match run_mode(mode, &mut console) {
+            Ok(()) => serial_println!("VT: Left {:?} mode", mode),
+            Err(e) => serial_println!("ERROR: {:?} mode failed: {:?}", mode, e),
+        }
+
+        let next = {
+            let mut state = STATE.lock();
+            let next = next_mode(mode, state.requested.take(), available(Mode::Gui));
+            if let Some(next) = next {
+                state.current = next;
+            }
+            next
+        };
+        match next {
+            Some(next) => crate::logger::info("vt", &format!("Switching to {:?} mode", next)),
+            None => return,
+        }
+    }
+}
+
+/// Check the hotkeys and what follows each way a mode can end
+pub fn self_test() -> Result<(), KernelError> {
+    serial_println!("VT: Running self-test");
+
+    let key = |code: KeyCode, ctrl: bool, alt: bool, state: KeyState| {
+        KeyEvent { code, state, shift: false, ctrl, alt, time_ms: 0 }
+    };
+    let cases = [
+        (key(KeyCode::F1, true, true, KeyState::Pressed), Mode::Gui, Some(Mode::Console)),
+        (key(KeyCode::F2, true, true, KeyState::Pressed), Mode::Console, Some(Mode::Gui)),
+        (key(KeyCode::Enter, true, true, KeyState::Pressed), Mode::Gui, Some(Mode::Console)),
+        (key(KeyCode::Enter, true, true, KeyState::Pressed), Mode::Console, Some(Mode::Gui)),
+        (key(KeyCode::F1, true, true, KeyState::Released), Mode::Gui, None),
+        (key(KeyCode::F1, false, true, KeyState::Pressed), Mode::Gui, None),
+        (key(KeyCode::Enter, true, false, KeyState::Pressed), Mode::Console, None),
+    ];
+    for (event, current, expected) in cases {
+        if hotkey(&event, current) != expected {
+            serial_println!("VT: {:?} in {:?} mode gave {:?}", event.code, current, hotkey(&event, current));
+            return Err(KernelError::ValidationError("Mode switch hotkey misread"));
+        }
+    }
+
+    if next_mode(Mode::Gui, Some(Mode::Console), true) != Some(Mode::Console)
+        || next_mode(Mode::Console, Some(Mode::Gui), true) != Some(Mode::Gui)
+        || next_mode(Mode::Gui, None, true) != Some(Mode::Console)
+        || next_mode(Mode::Console, None, true) != Some(Mode::Gui)
+        || next_mode(Mode::Console, None, false).is_some() {
+        return Err(KernelError::ValidationError("Wrong mode after a switch"));
+    }
+
+    // Nothing runs yet, so a request can be made and withdrawn
+    let saved = STATE.lock().requested;
+    request(current())?;
+    if switch_pending() {
+        return Err(KernelError::ValidationError("Asking for the current mode started a switch"));
+    }
+    if !available(Mode::Gui) && request(Mode::Gui).is_ok() {
+        return Err(KernelError::ValidationError("Switched to a desktop that isn't there"));
+    }
+    STATE.lock().requested = saved;
+
+    serial_println!("VT: Self-test passed");
+    Ok(())
+}